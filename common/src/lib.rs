@@ -63,6 +63,73 @@ pub struct Logger {
     file: std::fs::File,
 }
 
+thread_local! {
+    static REQUEST_ID: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
+}
+
+// Tags every log line written on this thread with a request ID, until dropped
+// Threads spawned for a request need their own span--thread locals don't carry over
+pub struct RequestSpan {
+    previous: Option<String>,
+}
+
+impl RequestSpan {
+    pub fn enter(request_id: &str) -> RequestSpan {
+        let previous = REQUEST_ID.with(|id| id.replace(Some(request_id.to_string())));
+        RequestSpan { previous }
+    }
+}
+
+impl Drop for RequestSpan {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        REQUEST_ID.with(|id| *id.borrow_mut() = previous);
+    }
+}
+
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+// An error along with what was being done when it happened, e.g. the SQL statement that failed
+// `kind` is a short category for the frontend--`Database`, `Io`, etc.
+#[derive(Debug)]
+pub struct ChamberError {
+    pub kind: &'static str,
+    pub context: String,
+    pub message: String,
+}
+
+impl ChamberError {
+    pub fn new(kind: &'static str, context: impl Into<String>, e: impl std::fmt::Display) -> Self {
+        ChamberError {
+            kind,
+            context: context.into(),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ChamberError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} error: {} ({})",
+            self.kind, self.message, self.context
+        )
+    }
+}
+
+impl std::error::Error for ChamberError {}
+
+// e.g. `INFO` -> `[INFO]` or `[INFO] [<request ID>]`
+fn log_tag(level: &str) -> String {
+    match current_request_id() {
+        Some(id) => format!("[{}] [{}]", level, id),
+        None => format!("[{}]", level),
+    }
+}
+
 static mut INSTANCE: Option<Logger> = None;
 static INIT: Once = Once::new();
 
@@ -108,7 +175,7 @@ impl Logger {
                 .try_clone()
                 .expect("Failed to clone file");
 
-            let message = format!("{} [INFO]: {}", chrono::Local::now(), message);
+            let message = format!("{} {}: {}", chrono::Local::now(), log_tag("INFO"), message);
             writeln!(file, "{}", message).expect("Failed to write to log file");
         }
     }
@@ -126,7 +193,7 @@ impl Logger {
                 .try_clone()
                 .expect("Failed to clone file");
 
-            let message = format!("{} [ERROR]: {}", chrono::Local::now(), message);
+            let message = format!("{} {}: {}", chrono::Local::now(), log_tag("ERROR"), message);
             writeln!(file, "{}", message).expect("Failed to write to log file");
         }
     }
@@ -37,16 +37,16 @@ impl<T: Clone> LinkedList<T> {
             let mut front_lock = front_node.lock().unwrap();
             front_lock.back = back.clone();
         } else {
-            // This was the back node
-            self.back = back.clone();
+            // This was the front node
+            self.front = back.clone();
         }
 
         if let Some(back_node) = back.as_ref() {
             let mut back_lock = back_node.lock().unwrap();
             back_lock.front = front.clone();
         } else {
-            // This was the front node
-            self.front = front.clone();
+            // This was the back node
+            self.back = front.clone();
         }
 
         self.len -= 1;
@@ -72,17 +72,12 @@ impl<T: Clone> LinkedList<T> {
         }));
 
         if let Some(old) = self.front.take() {
-            let mut old_lock = old.lock().unwrap();
-            old_lock.front = Some(Arc::clone(&new));
+            old.lock().unwrap().front = Some(Arc::clone(&new));
+            new.lock().unwrap().back = Some(old);
         } else {
             self.back = Some(Arc::clone(&new));
         }
 
-        if let Some(front) = &self.front {
-            let mut front_lock = front.lock().unwrap();
-            front_lock.back = self.back.clone();
-        }
-
         self.front = Some(new.clone());
         self.len += 1;
 
@@ -177,6 +172,11 @@ impl EmbeddingCache {
             }
         };
 
+        self.load_block(block_number)
+    }
+
+    /// Load every embedding in the given block into the cache
+    fn load_block(&mut self, block_number: u64) -> Result<(), std::io::Error> {
         let embeddings = read_embedding_block(block_number)?.embeddings;
         for e in embeddings.iter() {
             if self.lru.len >= self.max_size as usize {
@@ -258,6 +258,22 @@ impl EmbeddingCache {
         Ok(Box::new(embedding))
     }
 
+    /// Warm the cache with the given blocks
+    ///
+    /// Only as many blocks as the cache can hold are loaded--
+    /// anything past that would just evict the blocks loaded before it
+    pub fn prefetch(&mut self, blocks: &HashSet<u64>) -> Result<usize, std::io::Error> {
+        let capacity = self.max_size as usize / BLOCK_SIZE;
+
+        let mut count = 0;
+        for block_number in blocks.iter().take(capacity) {
+            self.load_block(*block_number)?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
     pub fn refresh_directory(&mut self) -> Result<(), std::io::Error> {
         self.directory = match get_directory() {
             Ok(d) => d.id_map,
@@ -35,19 +35,50 @@ fn touch_file(path: &std::path::PathBuf) {
 //
 // `chamber_common::Workspace` _must_ be setup before this function is run
 // otherwise the `get_*_dir` functions won't be correctly mapped
+//
+// The embedding provider and model are settled here for the rest of the process,
+// from `embedder` in the config directory if it names them (see `configured_embedding_provider`)
 pub fn setup() -> Result<(), Box<dyn std::error::Error>> {
-    match std::env::var("OPENAI_API_KEY") {
-        Ok(_) => (),
+    if !crate::openai::has_embedding_provider() {
+        match crate::configured_embedding_provider() {
+            Ok(provider) => crate::set_embedding_provider(provider),
+            Err(e) => {
+                lprint!(error, "Dewey: invalid embedder: {}", e);
+                return Err(Box::new(e));
+            }
+        };
+    }
+
+    let provider = crate::get_embedding_provider();
+    match crate::configured_embedding_model(provider) {
+        Ok(model) => {
+            lprint!(info, "Dewey: Embedding with {} {}", provider.name(), model);
+            crate::openai::set_embedding_model(model);
+        }
         Err(e) => {
-            lprint!(error, "Dewey OPENAI_API_KEY environment variable not set");
+            lprint!(error, "Dewey: invalid embedding model: {}", e);
             return Err(Box::new(e));
         }
+    };
+
+    if provider == crate::EmbeddingProvider::OpenAI {
+        match std::env::var("OPENAI_API_KEY") {
+            Ok(_) => (),
+            Err(e) => {
+                lprint!(
+                    error,
+                    "Dewey OPENAI_API_KEY environment variable not set--set it, or name another provider in `embedder`"
+                );
+                return Err(Box::new(e));
+            }
+        }
     }
 
     let config_path = chamber_common::get_config_dir();
     let local_path = chamber_common::get_local_dir();
     let data_path = chamber_common::get_data_dir();
 
+    create_if_nonexistent(&config_path);
     create_if_nonexistent(&local_path);
     create_if_nonexistent(&data_path);
 
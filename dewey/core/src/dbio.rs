@@ -7,28 +7,85 @@ use serialize_macros::Serialize;
 
 use crate::cache::EmbeddingCache;
 use crate::hnsw::{normalize, HNSW};
-use crate::openai::{embed_bulk, Embedding, EmbeddingSource};
+use crate::openai::{embed_bulk, get_embedding_model, Embedding, EmbeddingModel, EmbeddingSource};
 use crate::serialization::Serialize;
 
 // TODO: this could probably be a config parameter
 pub const BLOCK_SIZE: usize = 1024;
 
+// Block files start with this, followed by the block itself
+// Blocks from before models were recorded start right in with the block number instead,
+// and are taken to be from `EmbeddingModel::legacy`
+const BLOCK_MAGIC: &[u8; 8] = b"DEWEYBLK";
+
 #[derive(Serialize)]
 pub struct EmbeddingBlock {
     block: u64,
+    // What every embedding in the block was made with
+    pub model: EmbeddingModel,
     pub embeddings: Vec<Embedding>,
 }
 
+#[derive(Serialize)]
+struct LegacyEmbeddingBlock {
+    block: u64,
+    embeddings: Vec<Embedding>,
+}
+
 impl EmbeddingBlock {
+    fn new(block: u64, embeddings: Vec<Embedding>) -> Self {
+        Self {
+            block,
+            model: get_embedding_model(),
+            embeddings,
+        }
+    }
+
+    // Written to the side and renamed into place, so a background index rebuild
+    // reading the block never sees it half written
     fn to_file(&self, filename: &str) -> Result<(), std::io::Error> {
+        let staging = format!("{}.tmp", filename);
         let mut file = std::fs::OpenOptions::new()
             .create(true)
             .write(true)
-            .open(filename)?;
+            .truncate(true)
+            .open(&staging)?;
 
-        let bytes = self.to_bytes();
+        let mut bytes = BLOCK_MAGIC.to_vec();
+        bytes.extend(self.to_bytes());
         info!("Writing {} bytes to {}", bytes.len(), filename);
         file.write_all(&bytes)?;
+        std::fs::rename(&staging, filename)?;
+
+        Ok(())
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self, std::io::Error> {
+        if bytes.starts_with(BLOCK_MAGIC) {
+            return Ok(Self::from_bytes(bytes, BLOCK_MAGIC.len())?.0);
+        }
+
+        let (legacy, _) = LegacyEmbeddingBlock::from_bytes(bytes, 0)?;
+        Ok(Self {
+            block: legacy.block,
+            model: EmbeddingModel::legacy(),
+            embeddings: legacy.embeddings,
+        })
+    }
+
+    // Embeddings from different models (or the same one at a different size) can't be compared,
+    // so they're never mixed in one store
+    fn check_model(&self) -> Result<(), std::io::Error> {
+        let configured = get_embedding_model();
+        if self.model != configured && !self.embeddings.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Block {} has embeddings from {}, but {} is configured--reindex with the new model, or switch back",
+                    self.block, self.model, configured
+                ),
+            ));
+        }
 
         Ok(())
     }
@@ -167,10 +224,7 @@ pub fn sync_index(full_embed: bool) -> Result<(), std::io::Error> {
     let blocks = embeddings.chunks(BLOCK_SIZE);
     for (i, block) in blocks.enumerate() {
         let filename = format!("{}/{}", get_data_dir().to_str().unwrap(), i);
-        let embedding_block = EmbeddingBlock {
-            block: i as u64,
-            embeddings: block.to_vec(),
-        };
+        let embedding_block = EmbeddingBlock::new(i as u64, block.to_vec());
 
         embedding_block.to_file(&filename)?;
 
@@ -202,6 +256,8 @@ pub fn sync_index(full_embed: bool) -> Result<(), std::io::Error> {
 //
 // TODO: needs refactored to fit william integration
 pub fn reblock() -> Result<(), std::io::Error> {
+    check_models()?;
+
     let index = match HNSW::new(false) {
         Ok(index) => index,
         Err(e) => {
@@ -212,7 +268,7 @@ pub fn reblock() -> Result<(), std::io::Error> {
         }
     };
 
-    let full_graph = match index.get_last_layer() {
+    let full_graph = match index.get_full_layer() {
         Some(g) => g,
         None => {
             info!("index is empty; nothing to do.");
@@ -300,10 +356,7 @@ pub fn reblock() -> Result<(), std::io::Error> {
             embeddings.push(*embedding);
         }
 
-        let embedding_block = EmbeddingBlock {
-            block: i as u64,
-            embeddings,
-        };
+        let embedding_block = EmbeddingBlock::new(i as u64, embeddings);
 
         embedding_block.to_file(&filename)?;
     }
@@ -367,7 +420,7 @@ pub fn read_embedding_block(block_number: u64) -> Result<EmbeddingBlock, std::io
         }
     };
 
-    let (block, _) = match EmbeddingBlock::from_bytes(&bytes, 0) {
+    let block = match EmbeddingBlock::parse(&bytes) {
         Ok(b) => b,
         Err(e) => {
             error!("error parsing block file {}: {}", block_number, e);
@@ -378,14 +431,16 @@ pub fn read_embedding_block(block_number: u64) -> Result<EmbeddingBlock, std::io
     Ok(block)
 }
 
-pub struct BlockEmbedding {
-    pub block_number: u64,
-    pub embedding: Box<Embedding>,
-    pub source_file: String,
+// Errors on the first block with embeddings from a model other than the configured one
+pub fn check_models() -> Result<(), std::io::Error> {
+    for block_number in block_numbers()? {
+        read_embedding_block(block_number)?.check_model()?;
+    }
+
+    Ok(())
 }
 
-// returns boxes of the embeddings and the block files from which they were read
-pub fn get_all_blocks() -> Result<Vec<BlockEmbedding>, std::io::Error> {
+fn block_numbers() -> Result<Vec<u64>, std::io::Error> {
     let mut block_numbers = Vec::new();
     for entry in std::fs::read_dir(get_data_dir().clone())? {
         let entry = entry?;
@@ -393,18 +448,30 @@ pub fn get_all_blocks() -> Result<Vec<BlockEmbedding>, std::io::Error> {
         if path.is_file() {
             if let Some(filename) = path.file_name() {
                 if let Some(filename) = filename.to_str() {
-                    if filename.parse::<u64>().is_ok() {
-                        block_numbers.push(filename.parse::<u64>().unwrap());
+                    if let Ok(block_number) = filename.parse::<u64>() {
+                        block_numbers.push(block_number);
                     }
                 }
             }
         }
     }
 
+    Ok(block_numbers)
+}
+
+pub struct BlockEmbedding {
+    pub block_number: u64,
+    pub embedding: Box<Embedding>,
+    pub source_file: String,
+}
+
+// returns boxes of the embeddings and the block files from which they were read
+pub fn get_all_blocks() -> Result<Vec<BlockEmbedding>, std::io::Error> {
     let mut block_embeddings = Vec::new();
-    for block_number in block_numbers {
+    for block_number in block_numbers()? {
         let filename = format!("{}/{}", get_data_dir().to_str().unwrap(), block_number);
         let block = read_embedding_block(block_number)?;
+        block.check_model()?;
 
         for be in block
             .embeddings
@@ -478,68 +545,91 @@ pub fn get_directory() -> Result<Directory, std::io::Error> {
     })
 }
 
-// TODO: how does this affect indexing?
-//       i think things need reindexed + reblocked after updates here
-//
-// updates the embeddings for the given file
-// requires the file to have already been indexed
-pub fn update_file_embeddings(filepath: &str, index: &mut HNSW) -> Result<(), std::io::Error> {
-    let directory = match get_directory() {
-        Ok(d) => d,
-        Err(e) => {
-            error!("error reading directory: {}", e);
-            return Err(e);
-        }
+// the meta tags of the given file's embeddings, or `None` if it hasn't been embedded
+pub fn get_file_meta(filepath: &str) -> Result<Option<HashSet<String>>, std::io::Error> {
+    let directory = get_directory()?;
+    let target_block = match directory.file_map.get(filepath) {
+        Some(b) => *b,
+        None => return Ok(None),
     };
 
-    let target_block = match directory.file_map.get(filepath) {
-        Some(b) => b,
-        None => {
-            error!(
-                "filepath {} not catalogued in Directory, aborting update",
-                filepath
-            );
-            return Ok(());
+    let block = read_embedding_block(target_block)?;
+
+    Ok(block
+        .embeddings
+        .iter()
+        .find(|e| e.source_file.filepath == filepath)
+        .map(|e| e.source_file.meta.clone()))
+}
+
+/// this removes every embedding of the given source files from the embedding store,
+/// along with their directory entries
+///
+/// like `add_new_embedding`, this _does not_ affect the HNSW index
+///
+/// returns the IDs of the embeddings that were removed
+pub fn remove_file_embeddings(filepaths: &HashSet<String>) -> Result<Vec<u64>, std::io::Error> {
+    // directory lines are `<id> <filepath> <block>`
+    // `Directory` only keeps one block per file, and a file's chunks can be spread across blocks,
+    // so the lines are gone through here instead
+    let directory_path = get_data_dir().join("directory");
+    let contents = match std::fs::read_to_string(&directory_path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            error!("error reading directory file: {}", e);
+            return Err(e);
         }
     };
 
-    let mut block = read_embedding_block(*target_block)?;
+    let mut blocks = HashSet::new();
+    let mut kept = Vec::new();
+    for line in contents.lines().filter(|l| !l.is_empty()) {
+        let (start, end) = match (line.find(' '), line.rfind(' ')) {
+            (Some(start), Some(end)) if start < end => (start, end),
+            _ => {
+                kept.push(line);
+                continue;
+            }
+        };
 
-    let mut meta = HashSet::new();
-    let mut to_delete = Vec::new();
-    for e in block.embeddings.iter() {
-        if e.source_file.filepath == filepath {
-            meta = e.source_file.meta.clone();
-            to_delete.push(e.id);
+        match line[end + 1..].parse::<u64>() {
+            Ok(block) if filepaths.contains(&line[start + 1..end]) => {
+                blocks.insert(block);
+            }
+            _ => kept.push(line),
         }
     }
 
-    block
-        .embeddings
-        .retain(|e| e.source_file.filepath != filepath);
+    let mut removed = Vec::new();
+    for block_number in blocks {
+        let mut block = read_embedding_block(block_number)?;
+        let before = block.embeddings.len();
 
-    let mut new_embeddings = embed_bulk(&vec![EmbeddingSource {
-        filepath: filepath.to_string(),
-        meta,
-        subset: None,
-    }])?;
+        block.embeddings.retain(|e| {
+            if filepaths.contains(&e.source_file.filepath) {
+                removed.push(e.id);
+                false
+            } else {
+                true
+            }
+        });
 
-    for e in new_embeddings.iter_mut() {
-        e.id = get_next_id()?;
+        if block.embeddings.len() != before {
+            let block_path = format!("{}/{}", get_data_dir().to_str().unwrap(), block_number);
+            block.to_file(&block_path)?;
+        }
     }
 
-    block.embeddings.extend(new_embeddings);
+    std::fs::write(&directory_path, kept.join("\n"))?;
 
-    let block_path = format!("{}/{}", get_data_dir().to_str().unwrap(), target_block);
-    block.to_file(&block_path)?;
+    info!(
+        "Removed {} embeddings of {} files from the store",
+        removed.len(),
+        filepaths.len()
+    );
 
-    for node in to_delete {
-        index.remove_node(node);
-    }
-
-    index.serialize(&get_data_dir().join("index").to_str().unwrap().to_string())?;
-
-    Ok(())
+    Ok(removed)
 }
 
 /// this adds a new embedding to the embedding store
@@ -571,15 +661,16 @@ pub fn add_new_embedding(embedding: &mut Embedding) -> Result<(), std::io::Error
 
     let mut block = match read_embedding_block(last_block_number) {
         Ok(b) => b,
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => EmbeddingBlock {
-            block: 0,
-            embeddings: Vec::new(),
-        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => EmbeddingBlock::new(0, Vec::new()),
         Err(e) => {
             return Err(e);
         }
     };
 
+    block.check_model()?;
+    // An emptied block is free to take on a new model
+    block.model = get_embedding_model();
+
     embedding.id = get_next_id()?;
     block.embeddings.push(embedding.clone());
 
@@ -9,7 +9,7 @@ use chamber_common::{error, get_data_dir, info, lprint};
 use serialize_macros::Serialize;
 
 use crate::cache::EmbeddingCache;
-use crate::dbio::{get_directory, BLOCK_SIZE};
+use crate::dbio::{get_directory, Directory, BLOCK_SIZE};
 use crate::openai::{Embedding, EMBED_DIM};
 use crate::serialization::Serialize;
 
@@ -42,17 +42,60 @@ pub fn normalize(embedding: &mut Embedding) {
 // embedding id -> (neighbor ids, distances)
 type Graph = HashMap<u64, Vec<(u64, f32)>>;
 
-pub enum FilterComparator {
-    Equal,
-    NotEqual,
+// How many inserts go into the log before the whole index is written out again
+const CHECKPOINT_INTERVAL: usize = 256;
+
+// The share of removed nodes the graph can carry before it's compacted
+const COMPACTION_RATIO: f32 = 0.25;
+
+pub fn index_path() -> String {
+    get_data_dir().join("index").to_string_lossy().to_string()
+}
+
+// Inserts since the index was last written out in full are appended here,
+// each record being a length-prefixed `IndexDelta`
+fn log_path(filepath: &str) -> String {
+    format!("{}.log", filepath)
+}
+
+// A node given its neighbors in a layer, each of which links back to it
+#[derive(Serialize)]
+struct LayerLink {
+    layer: u32,
+    node: u64,
+    neighbors: Vec<(u64, f32)>,
+}
+
+// Everything a single insert changed, enough to replay it on top of the index as it was before
+#[derive(Serialize)]
+struct IndexDelta {
+    size: u32,
+    entry_id: Option<u64>,
+    layers: u32,
+    thresholds: Vec<f32>,
+    links: Vec<LayerLink>,
 }
 
-pub struct Filter {
-    pub comparator: FilterComparator,
-    pub value: String,
+// How many nodes failing the filters a query can search through, as a multiple of `ef`
+// These don't count against `ef`, but a filter almost nothing passes shouldn't mean
+// searching the whole graph
+const REJECTED_BUDGET: usize = 10;
+
+// A condition on an embedding's meta tags
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    // Tagged with exactly this
+    Equals(String),
+    // Not tagged with this--embeddings without any tags always pass
+    NotEquals(String),
+    // Tagged with anything starting with this, e.g. `conversation:` for every conversation
+    Prefix(String),
+    // Tagged with at least one of these
+    AnyOf(HashSet<String>),
 }
 
 impl Filter {
+    // `eq <tag>`, `ne <tag>`, `prefix <tag>`, or `in <tag>,<tag>,...`
     pub fn from_string(input: &String) -> Result<Self, std::io::Error> {
         let parts: Vec<&str> = input.split_whitespace().collect();
         if parts.len() != 2 {
@@ -62,27 +105,31 @@ impl Filter {
             ));
         }
 
-        let comparator = match parts[0] {
-            "eq" => FilterComparator::Equal,
-            "ne" => FilterComparator::NotEqual,
-            _ => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    "Invalid comparator",
-                ))
-            }
-        };
-
-        Ok(Filter {
-            comparator,
-            value: parts[1].to_string(),
-        })
+        let value = parts[1].to_string();
+        match parts[0] {
+            "eq" => Ok(Filter::Equals(value)),
+            "ne" => Ok(Filter::NotEquals(value)),
+            "prefix" => Ok(Filter::Prefix(value)),
+            "in" => Ok(Filter::AnyOf(
+                value
+                    .split(',')
+                    .filter(|t| !t.is_empty())
+                    .map(|t| t.to_string())
+                    .collect(),
+            )),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Invalid comparator",
+            )),
+        }
     }
 
-    pub fn compare(self: &Self, query: &str) -> bool {
-        match self.comparator {
-            FilterComparator::Equal => query == self.value,
-            FilterComparator::NotEqual => query != self.value,
+    pub fn matches(&self, meta: &HashSet<String>) -> bool {
+        match self {
+            Filter::Equals(tag) => meta.contains(tag),
+            Filter::NotEquals(tag) => !meta.contains(tag),
+            Filter::Prefix(prefix) => meta.iter().any(|m| m.starts_with(prefix.as_str())),
+            Filter::AnyOf(tags) => meta.iter().any(|m| tags.contains(m)),
         }
     }
 }
@@ -95,10 +142,10 @@ pub struct Query {
 // basic in-memory nearest neighbor index
 // TODO: should we handle huge datasets, beyond what memory can hold?
 //
-// NOTE: "top" layers (where nodes are most sparse) are the lower indices
-//       (e.g., 0, 1, 2, ...)
-//       whereas "bottom" layers (where nodes are most abundant are the upper indices
+// NOTE: "top" layers (where nodes are most sparse) are the upper indices
 //       (e.g., ..., n - 2, n - 1, n)
+//       whereas the "bottom" layer (where every node is) is index 0
+//       Searches start from the top
 #[derive(Serialize)]
 #[allow(unused_attributes)]
 pub struct HNSW {
@@ -106,6 +153,17 @@ pub struct HNSW {
     pub layers: Vec<Graph>,
     entry_id: Option<u64>,
     thresholds: Vec<f32>,
+    // Inserts not yet written anywhere
+    #[ignore]
+    pending: Vec<IndexDelta>,
+    // Inserts in the log since the last full write
+    #[ignore]
+    logged: usize,
+    // Nodes whose embeddings have been removed--they're left in the graph, unsearched,
+    // until it's compacted
+    // These aren't written out; they're whatever the graph has that the directory doesn't
+    #[ignore]
+    tombstones: HashSet<u64>,
 }
 
 // NOTE: A few guarantees to note:
@@ -116,17 +174,12 @@ impl HNSW {
     pub fn new(reindex: bool) -> Result<Self, std::io::Error> {
         if !reindex {
             lprint!(info, "Dewey: HNSW: loading index from disk");
-            let hnsw =
+            let mut hnsw =
                 match Self::deserialize(get_data_dir().join("index").to_string_lossy().to_string())
                 {
                     Ok(h) => h,
                     Err(e) => match e.kind() {
-                        std::io::ErrorKind::NotFound => Self {
-                            size: 0,
-                            layers: Vec::new(),
-                            entry_id: None,
-                            thresholds: Vec::new(),
-                        },
+                        std::io::ErrorKind::NotFound => Self::empty(),
                         _ => {
                             error!("Error reading index: {}", e);
                             return Err(e);
@@ -134,6 +187,8 @@ impl HNSW {
                     },
                 };
 
+            hnsw.replay(&index_path())?;
+
             return Ok(hnsw);
         }
 
@@ -142,12 +197,7 @@ impl HNSW {
         let directory = get_directory()?;
         let n = directory.len();
         if n == 0 {
-            return Ok(Self {
-                size: 0,
-                layers: Vec::new(),
-                entry_id: None,
-                thresholds: Vec::new(),
-            });
+            return Ok(Self::empty());
         }
 
         let m = n.ilog2();
@@ -173,6 +223,7 @@ impl HNSW {
 
         // TODO: config param?
         let mut cache = EmbeddingCache::new(20 * BLOCK_SIZE as u32)?;
+        let tombstones = HashSet::new();
 
         let mut entry_id: Option<u64> = None;
         let mut rng = thread_rng();
@@ -204,6 +255,7 @@ impl HNSW {
                         &mut cache,
                         eid.unwrap(),
                         layer,
+                        &tombstones,
                         &eid_embedding,
                         200, // TODO: ????
                     )?;
@@ -214,6 +266,7 @@ impl HNSW {
                         &mut cache,
                         eid.unwrap(),
                         layer,
+                        &tombstones,
                         &new_embedding,
                         200, // TODO: ????
                     )?;
@@ -222,6 +275,7 @@ impl HNSW {
                         &mut cache,
                         eid.unwrap(),
                         layer,
+                        &tombstones,
                         &new_embedding,
                         200, // TODO: ????
                     )?;
@@ -247,9 +301,106 @@ impl HNSW {
             layers,
             entry_id,
             thresholds,
+            pending: Vec::new(),
+            logged: 0,
+            tombstones: HashSet::new(),
         })
     }
 
+    fn empty() -> Self {
+        Self {
+            size: 0,
+            layers: Vec::new(),
+            entry_id: None,
+            thresholds: Vec::new(),
+            pending: Vec::new(),
+            logged: 0,
+            tombstones: HashSet::new(),
+        }
+    }
+
+    // The index as it was last written out, with whatever the directory no longer has tombstoned,
+    // along with whether it's stale--missing embeddings, or due for compaction--and should be rebuilt
+    // An index that can't be loaded (or searched) comes back empty
+    pub fn load() -> Result<(Self, bool), std::io::Error> {
+        let directory = get_directory()?;
+        let mut index = match Self::new(false) {
+            Ok(index) => index,
+            Err(e) => {
+                lprint!(
+                    error,
+                    "Dewey: HNSW: error loading index: {}; starting empty",
+                    e
+                );
+                return Ok((Self::empty(), directory.len() > 0));
+            }
+        };
+
+        let missing = index.reconcile(&directory);
+        if index.entry_removed() {
+            lprint!(
+                info,
+                "Dewey: HNSW: index on disk lost its entry point; starting empty"
+            );
+            return Ok((Self::empty(), true));
+        }
+
+        let stale = !missing.is_empty() || index.needs_compaction();
+        if stale {
+            lprint!(
+                info,
+                "Dewey: HNSW: index on disk is missing {} embeddings and has {} removed",
+                missing.len(),
+                index.tombstones.len()
+            );
+        }
+
+        Ok((index, stale))
+    }
+
+    // Tombstones whatever `directory` no longer has, returning what the index is missing
+    fn reconcile(&mut self, directory: &Directory) -> Vec<u64> {
+        let nodes = self.nodes();
+        self.tombstones = nodes
+            .iter()
+            .filter(|id| !directory.id_map.contains_key(&(**id as u32)))
+            .copied()
+            .collect();
+
+        let mut missing = directory
+            .id_map
+            .keys()
+            .map(|id| *id as u64)
+            .filter(|id| !nodes.contains(id))
+            .collect::<Vec<_>>();
+        missing.sort();
+
+        missing
+    }
+
+    fn entry_removed(&self) -> bool {
+        self.entry_id
+            .is_some_and(|id| self.tombstones.contains(&id))
+    }
+
+    // Brings an index built in the background up to date with whatever was added to
+    // or removed from the directory since, returning how many embeddings were added
+    pub fn catch_up(&mut self, cache: &mut EmbeddingCache) -> Result<usize, std::io::Error> {
+        let missing = self.reconcile(&get_directory()?);
+        if self.entry_removed() {
+            return Err(std::io::Error::other(
+                "the index's entry point was removed while it was being built",
+            ));
+        }
+
+        for id in missing.iter() {
+            let embedding = cache.get(*id as u32)?;
+            self.insert(cache, &embedding)?;
+        }
+
+        Ok(missing.len())
+    }
+
     // NOTE: the directory _needs_ to have been updated
     //       through dbio.rs
     //
@@ -259,21 +410,32 @@ impl HNSW {
         cache: &mut EmbeddingCache,
         embedding: &Embedding,
     ) -> Result<(), std::io::Error> {
-        // Recalculations for creating a new layer
+        let mut links = Vec::new();
+
+        // Recalculations for creating a new layer--same as building the index in `new`,
+        // the new layer is the sparsest, and layer 0 (threshold 1.0) holds every node
         // `l` is otherwise the number of layers present in the index
-        if self.layers.is_empty() || self.size.ilog2() > self.layers.len() as u32 {
-            let n = std::cmp::max(1, self.size);
-            let log = std::cmp::max(1, n.ilog2());
-            let p = 1.0 / log as f32;
+        let log = std::cmp::max(1, (self.size + 1).ilog2());
+        if self.layers.len() < log as usize || self.thresholds.len() != self.layers.len() {
+            while self.layers.len() < log as usize {
+                let mut new_layer = Graph::new();
+                if let Some(entry) = self.entry_id {
+                    new_layer.insert(entry, Vec::new());
+                    links.push(LayerLink {
+                        layer: self.layers.len() as u32,
+                        node: entry,
+                        neighbors: Vec::new(),
+                    });
+                }
 
-            let mut new_layer = Graph::new();
-            if self.entry_id.is_some() {
-                new_layer.insert(self.entry_id.unwrap(), Vec::new());
+                self.layers.push(new_layer);
             }
 
-            self.layers.push(new_layer);
-            self.thresholds = (0..log)
-                .map(|j| p * (1.0 - p).powi((j as i32 - log as i32 + 1).abs()))
+            let l = self.layers.len() as i32;
+            let p = 1.0 / log as f32;
+            self.thresholds = (1..l)
+                .map(|j| p * (1.0 - p).powi((j - l + 1).abs()))
+                .chain(vec![1.0])
                 .rev()
                 .collect::<Vec<_>>();
         }
@@ -283,46 +445,28 @@ impl HNSW {
 
         // If the node is lucky enough to be inserted in one of the upper layers, it is guaranteed
         // a spot in the lower layers through this variable
-        let mut carryover = None;
-        for (j, layer) in self.layers.iter_mut().enumerate() {
-            let eid = if self.entry_id.is_none() {
-                Some(embedding.id)
-            } else {
-                self.entry_id
-            };
-
-            // The index entry id _must_ be present in the index
-            if layer.get(&eid.unwrap()).is_none() {
-                HNSW::insert_into_layer(
+        //
+        // The entry node is in every layer, so it's where each layer's search starts
+        let entry = self.entry_id.unwrap_or(embedding.id);
+        let mut carryover = false;
+        for (j, layer) in self.layers.iter_mut().enumerate().rev() {
+            if carryover || prob < self.thresholds[j] || self.entry_id.is_none() {
+                let neighbors = HNSW::insert_into_layer(
                     cache,
-                    eid.unwrap(),
+                    entry,
                     layer,
-                    &embedding,
+                    &self.tombstones,
+                    embedding,
                     200, // TODO: ????
                 )?;
-            }
 
-            // Standard probability check to see if a node is lucky enough to hit the upper layers
-            if prob < self.thresholds[j] || self.entry_id.is_none() {
-                HNSW::insert_into_layer(
-                    cache,
-                    eid.unwrap(),
-                    layer,
-                    &embedding,
-                    200, // TODO: ????
-                )?;
+                links.push(LayerLink {
+                    layer: j as u32,
+                    node: embedding.id,
+                    neighbors,
+                });
 
-                carryover = eid;
-            }
-            // Otherwise, go ahead and insert if the node has already been inserted above
-            else if carryover.is_some() {
-                HNSW::insert_into_layer(
-                    cache,
-                    carryover.unwrap(),
-                    layer,
-                    &embedding,
-                    200, // TODO: ????
-                )?;
+                carryover = true;
             }
         }
 
@@ -332,6 +476,81 @@ impl HNSW {
 
         self.size += 1;
 
+        self.pending.push(IndexDelta {
+            size: self.size,
+            entry_id: self.entry_id,
+            layers: self.layers.len() as u32,
+            thresholds: self.thresholds.clone(),
+            links,
+        });
+
+        Ok(())
+    }
+
+    // Redoes a logged insert--the same edges in the same order as `insert_into_layer`
+    fn apply(&mut self, delta: IndexDelta) {
+        while self.layers.len() < delta.layers as usize {
+            self.layers.push(Graph::new());
+        }
+
+        for link in delta.links {
+            let layer = &mut self.layers[link.layer as usize];
+            for (id, distance) in link.neighbors.iter() {
+                layer.entry(*id).or_default().push((link.node, *distance));
+            }
+
+            layer.insert(link.node, link.neighbors);
+        }
+
+        self.size = delta.size;
+        self.entry_id = delta.entry_id;
+        self.thresholds = delta.thresholds;
+    }
+
+    // Catches the index up on the inserts logged since it was written out
+    //
+    // A record cut off partway through (e.g. by a crash) ends the log,
+    // and records already covered by the index are skipped--
+    // the index can be written out before the log is cleared
+    fn replay(&mut self, filepath: &str) -> Result<(), std::io::Error> {
+        let bytes = match std::fs::read(log_path(filepath)) {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let mut cursor = 0;
+        let mut replayed = 0;
+        while cursor + 4 <= bytes.len() {
+            let (len, count) = u32::from_bytes(&bytes, cursor)?;
+            let start = cursor + count;
+            let end = start + len as usize;
+            if end > bytes.len() {
+                lprint!(
+                    error,
+                    "Dewey: HNSW: index log ends partway through a record; ignoring the rest"
+                );
+                break;
+            }
+
+            let (delta, _) = IndexDelta::from_bytes(&bytes[..end], start)?;
+            cursor = end;
+
+            self.logged += 1;
+            if delta.size <= self.size {
+                continue;
+            }
+
+            self.apply(delta);
+            replayed += 1;
+        }
+
+        lprint!(
+            info,
+            "Dewey: HNSW: replayed {} inserts from the index log",
+            replayed
+        );
+
         Ok(())
     }
 
@@ -339,12 +558,13 @@ impl HNSW {
         cache: &mut EmbeddingCache,
         entry_id: u64,
         layer: &mut Graph,
+        tombstones: &HashSet<u64>,
         query: &Embedding,
         ef: usize,
-    ) -> Result<(), std::io::Error> {
+    ) -> Result<Vec<(u64, f32)>, std::io::Error> {
         if layer.is_empty() {
             layer.insert(query.id, Vec::new());
-            return Ok(());
+            return Ok(Vec::new());
         }
 
         let mut visited = HashSet::new();
@@ -371,6 +591,14 @@ impl HNSW {
                     }
 
                     visited.insert(neighbor_id);
+
+                    // Removed embeddings aren't there to compare against, and can't be linked to,
+                    // but whatever's past them is searched as if they were as close as this node
+                    if tombstones.contains(&neighbor_id) {
+                        candidates.push(Reverse((curr_dist, neighbor_id)));
+                        continue;
+                    }
+
                     let neighbor = cache.get(neighbor_id as u32)?;
                     let dist = 1.0 - dot(query, &*neighbor);
 
@@ -390,14 +618,14 @@ impl HNSW {
         for (d, id) in results.into_sorted_vec().iter() {
             new_neighbors.push((*id, d.0));
 
-            let other_neighbors = layer.entry(*id).or_insert(Vec::new());
+            let other_neighbors = layer.entry(*id).or_default();
             other_neighbors.push((query.id, d.0));
         }
 
-        let new_node = layer.entry(query.id).or_insert(Vec::new());
+        let new_node = layer.entry(query.id).or_default();
         *new_node = new_neighbors.clone();
 
-        Ok(())
+        Ok(new_neighbors)
     }
 
     // TODO: please god optimize this
@@ -424,15 +652,27 @@ impl HNSW {
             panic!("ef must be greater than k");
         }
 
-        // there's gotta be a better way to blacklist
-        let mut visited = vec![false; self.size as usize + 1];
-        let mut blacklist = vec![false; self.size as usize + 1];
+        // IDs aren't dense once embeddings have been removed,
+        // so these are sized by the largest one rather than the index size
+        let capacity = self
+            .layers
+            .iter()
+            .flat_map(|l| l.keys())
+            .max()
+            .map_or(0, |id| *id as usize)
+            + 1;
+
+        // Nodes already in the results--each layer is searched on its own, since whatever
+        // an upper layer passed through still has to be searched through below,
+        // but nothing's counted twice
+        let mut counted = vec![false; capacity];
 
         // frankly just a stupid way of using this instead of a min heap
         // but rust f32 doesn't have Eq so i don't know how to work with it
         let mut top_k: Vec<(u64, f32)> = Vec::new();
 
         let mut count = 0;
+        let mut rejected = 0;
         for layer in self.layers.iter().rev() {
             let mut current = match top_k.first() {
                 Some(k) => k.0,
@@ -443,6 +683,23 @@ impl HNSW {
                 continue;
             }
 
+            let mut visited = vec![false; capacity];
+            visited[current as usize] = true;
+
+            // Where the search starts might be the closest of all
+            if !counted[current as usize] && !self.tombstones.contains(&current) && count < ef {
+                let e_c = cache.get(current as u32).unwrap();
+                if query
+                    .filters
+                    .iter()
+                    .all(|f| f.matches(&e_c.source_file.meta))
+                {
+                    top_k.push((current, 1.0 - dot(&query.embedding, &e_c)));
+                    counted[current as usize] = true;
+                    count += 1;
+                }
+            }
+
             let mut stack = Vec::new();
             stack.push(current);
 
@@ -453,38 +710,54 @@ impl HNSW {
                         .clone()
                         .into_iter()
                         .filter_map(|(n, _)| {
-                            // TODO: the fact that we need to increment/decrement
-                            //       the IDs is obscenely stupid
-                            if blacklist[n as usize] {
+                            if visited[n as usize] {
                                 return None;
                             }
 
-                            let e_n = cache.get(n as u32).unwrap();
-                            let mut filter_pass = true;
-                            for filter in query.filters.iter() {
-                                for meta in e_n.source_file.meta.iter() {
-                                    filter_pass &= filter.compare(meta);
-                                }
+                            // Removed embeddings are searched through like filtered ones,
+                            // but there's nothing left to compare against
+                            if self.tombstones.contains(&n) {
+                                return Some((n, f32::MAX, false));
                             }
 
-                            if !visited[n as usize] && filter_pass {
-                                Some((n, 1.0 - dot(&query.embedding, &e_n)))
-                            } else {
-                                blacklist[n as usize] = true;
-                                None
-                            }
+                            let e_n = cache.get(n as u32).unwrap();
+                            let filter_pass = query
+                                .filters
+                                .iter()
+                                .all(|f| f.matches(&e_n.source_file.meta));
+
+                            Some((n, 1.0 - dot(&query.embedding, &e_n), filter_pass))
                         })
                         .collect::<Vec<_>>();
 
+                    // Nodes that don't pass the filters are still searched through--
+                    // they're just left out of the results
+                    // Otherwise, whatever's only reachable through them would never be found
+                    //
+                    // Only nodes that pass count against `ef`, so a filtered query gets as many
+                    // candidates as an unfiltered one; the rest have a budget of their own
                     neighbors.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-                    for (neighbor, distance) in neighbors {
+                    for (neighbor, distance, filter_pass) in neighbors {
                         let neighbor = neighbor as usize;
-                        if !visited[neighbor] && !blacklist[neighbor] && count < ef {
-                            top_k.push((neighbor as u64, distance));
+                        if !visited[neighbor] {
+                            if filter_pass {
+                                if !counted[neighbor] {
+                                    if count >= ef {
+                                        continue;
+                                    }
+
+                                    top_k.push((neighbor as u64, distance));
+                                    counted[neighbor] = true;
+                                    count += 1;
+                                }
+                            } else if rejected >= ef * REJECTED_BUDGET {
+                                continue;
+                            } else {
+                                rejected += 1;
+                            }
 
                             stack.push(neighbor as u64);
                             visited[neighbor] = true;
-                            count += 1;
                         }
 
                         if top_k.len() > k {
@@ -497,9 +770,10 @@ impl HNSW {
                         if count >= ef {
                             lprint!(
                                 info,
-                                "Dewey: .query(): returning {} results after {} comparisons",
+                                "Dewey: .query(): returning {} results after {} comparisons ({} filtered out)",
                                 top_k.len(),
-                                count
+                                count,
+                                rejected
                             );
                             return top_k
                                 .into_iter()
@@ -517,9 +791,10 @@ impl HNSW {
 
         lprint!(
             info,
-            "Dewey: .query(): returning {} results after {} comparisons",
+            "Dewey: .query(): returning {} results after {} comparisons ({} filtered out)",
             top_k.len(),
-            count
+            count,
+            rejected
         );
         top_k.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
         top_k
@@ -528,56 +803,105 @@ impl HNSW {
             .collect::<Vec<_>>()
     }
 
-    // not the most efficient
-    // need to find a workaround the borrow checker
-    pub fn remove_node(&mut self, target_id: u64) {
-        let layer_targets = self
-            .layers
-            .iter()
-            .enumerate()
-            .filter_map(|(i, l)| {
-                let t = l.get(&target_id);
-                if t.is_some() {
-                    Some((i, t.unwrap().clone()))
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
+    // Every node in the graph, removed or not
+    fn nodes(&self) -> HashSet<u64> {
+        self.layers.iter().flat_map(|l| l.keys().copied()).collect()
+    }
 
-        // removing all outgoing edges from neighbors to target_id
-        // i.e., removing all edges (neighbor -> target_id)
-        for lt in layer_targets {
-            for target in lt.1 {
-                let neighbors = self.layers[lt.0].get_mut(&target.0).unwrap();
-                neighbors.retain(|n| n.0 != target_id);
-            }
+    // Marks a node as removed--its edges stay put so the rest of the graph stays connected,
+    // and searches still pass through it, but it's never returned or linked to
+    //
+    // Returns whether the node was in the index
+    pub fn remove(&mut self, id: u64) -> bool {
+        if self.tombstones.contains(&id) || !self.layers.iter().any(|l| l.contains_key(&id)) {
+            return false;
         }
 
-        // removing all outgoing edges from target_id
-        // i.e., removing all edges (target_id -> neighbor)
-        for layer in self.layers.iter_mut() {
-            layer.retain(|k, _| *k != target_id);
-        }
+        self.tombstones.insert(id);
 
-        self.size -= 1;
+        true
     }
 
-    pub fn serialize(&self, filepath: &String) -> Result<(), std::io::Error> {
+    // Nodes that haven't been removed
+    pub fn live(&self) -> usize {
+        (self.size as usize).saturating_sub(self.tombstones.len())
+    }
+
+    // Whether enough of the graph is dead weight that it's worth rebuilding
+    // Losing the entry point means searches have nowhere to start, so that always does
+    pub fn needs_compaction(&self) -> bool {
+        self.entry_removed() || self.tombstones.len() as f32 > self.size as f32 * COMPACTION_RATIO
+    }
+
+    // Writes out the whole index, which makes the log redundant
+    //
+    // The index is written to the side, synced, and renamed into place before the log goes,
+    // so a crash or a full disk partway through leaves the old index and its log intact
+    pub fn serialize(&mut self, filepath: &String) -> Result<(), std::io::Error> {
         lprint!(info, "Dewey: HNSW: serializing index to {}", filepath);
+        let staging = format!("{}.tmp", filepath);
         let mut file = std::fs::OpenOptions::new()
             .create(true)
             .write(true)
-            .open(filepath)?;
+            .truncate(true)
+            .open(&staging)?;
 
         let bytes = self.to_bytes();
         file.write_all(&bytes)?;
+        file.sync_all()?;
+        std::fs::rename(&staging, filepath)?;
+
+        match std::fs::remove_file(log_path(filepath)) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        };
+
+        self.pending.clear();
+        self.logged = 0;
 
         lprint!(info, "Dewey: HNSW: finished serializing index");
 
         Ok(())
     }
 
+    // Writes out the inserts since the last call, appending them to the log
+    // Every `CHECKPOINT_INTERVAL` inserts, the whole index is written out instead
+    pub fn persist(&mut self, filepath: &String) -> Result<(), std::io::Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        if self.logged + self.pending.len() >= CHECKPOINT_INTERVAL {
+            return self.serialize(filepath);
+        }
+
+        let mut bytes = Vec::new();
+        for delta in self.pending.iter() {
+            let record = delta.to_bytes();
+            bytes.extend((record.len() as u32).to_bytes());
+            bytes.extend(record);
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path(filepath))?;
+        file.write_all(&bytes)?;
+
+        lprint!(
+            info,
+            "Dewey: HNSW: logged {} inserts ({} bytes)",
+            self.pending.len(),
+            bytes.len()
+        );
+
+        self.logged += self.pending.len();
+        self.pending.clear();
+
+        Ok(())
+    }
+
     pub fn deserialize(filepath: String) -> Result<Self, std::io::Error> {
         lprint!(info, "Dewey: HNSW: deserializing index from {}", filepath);
 
@@ -599,8 +923,9 @@ impl HNSW {
         Ok(hnsw)
     }
 
-    pub fn get_last_layer(&self) -> Option<&Graph> {
-        self.layers.last()
+    // The bottom layer, with every node
+    pub fn get_full_layer(&self) -> Option<&Graph> {
+        self.layers.first()
     }
 
     pub fn print_graph(&self) {
@@ -174,6 +174,13 @@ pub fn get_indexing_rules() -> Result<HashMap<String, Vec<IndexRule>>, std::io::
         for part in parts.iter().skip(1) {
             if part.starts_with("--") {
                 match part.to_lowercase().as_str() {
+                    // `--naive` doesn't take a value
+                    "--naive" => {
+                        rules.push(IndexRule {
+                            rule_type: IndexRuleType::Naive,
+                            value: "".to_string(),
+                        });
+                    }
                     "--code" => rule.rule_type = IndexRuleType::Code,
                     "--split" => rule.rule_type = IndexRuleType::Split,
                     "--maxlength" => rule.rule_type = IndexRuleType::MaxLength,
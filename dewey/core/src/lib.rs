@@ -1,95 +1,323 @@
 use chamber_common::Logger;
-use chamber_common::{error, get_data_dir, info, lprint};
+use chamber_common::{error, info, lprint};
 
 use crate::cache::EmbeddingCache;
 use crate::dbio::BLOCK_SIZE;
-use crate::hnsw::{Filter, Query, HNSW};
-pub use crate::openai::{embed, EmbeddingSource};
+pub use crate::hnsw::Filter;
+use crate::hnsw::{Query, HNSW};
+use crate::openai::block_on;
+pub use crate::openai::{
+    configured_embedding_model, configured_embedding_provider, embed, embed_batch,
+    get_embedding_model, get_embedding_provider, local_embeddings_available,
+    set_embedding_provider, Embedding, EmbeddingModel, EmbeddingProvider, EmbeddingSource,
+    EMBEDDING_BATCH_SIZE,
+};
+use crate::scoring::StatsStore;
+pub use crate::scoring::{QueryOptions, QueryResult};
 
 mod cache;
 pub mod config;
 pub mod dbio;
 pub mod hnsw;
 pub mod ledger;
+#[cfg(feature = "local")]
+mod local;
 mod openai;
 mod parsing;
+mod scoring;
 pub mod serialization;
 pub mod test_common;
 
 pub struct Dewey {
     index: hnsw::HNSW,
     cache: EmbeddingCache,
+    stats: StatsStore,
+    // A fresh index being built from the blocks--`index` keeps serving until it's swapped in
+    rebuild: Option<std::thread::JoinHandle<Result<HNSW, std::io::Error>>>,
 }
 
 impl Dewey {
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
         crate::config::setup()?;
 
-        lprint!(info, "Dewey: Verifying OpenAI API key...");
-        let key = std::env::var("OPENAI_API_KEY").map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                format!("OpenAI API key not found: {}", e),
-            )
-        })?;
-
-        if key.is_empty() {
+        let provider = get_embedding_provider();
+        if provider == EmbeddingProvider::Local && !local_embeddings_available() {
             return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "OpenAI API key is empty",
+                std::io::ErrorKind::Unsupported,
+                "Dewey was built without local embeddings (the `local` feature)",
             )));
         }
 
-        // We're rebuilding the index from the blocks for now because it's assumed that the number
-        // of messages will be small enough to warrant this
-        // More than a few blocks, however. will probably warrant some sort of process for building
-        // these in the background
-        //
-        // TODO: Figure something out to keep the index fresh without compromising performance
-        Ok(Self {
-            index: HNSW::new(true)?,
+        if provider == EmbeddingProvider::OpenAI {
+            lprint!(info, "Dewey: Verifying OpenAI API key...");
+            let key = std::env::var("OPENAI_API_KEY").map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("OpenAI API key not found: {}", e),
+                )
+            })?;
+
+            if key.is_empty() {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "OpenAI API key is empty",
+                )));
+            }
+        }
+
+        // The index is loaded from disk (plus whatever's been logged since it was last written out)
+        // If it's fallen out of sync with the blocks, it's rebuilt in the background rather than
+        // holding up start up--queries get what the old one has in the meantime
+        dbio::check_models()?;
+        let (index, stale) = HNSW::load()?;
+        let mut dewey = Self {
+            index,
             cache: EmbeddingCache::new((20 * BLOCK_SIZE) as u32)?,
-        })
+            stats: StatsStore::load()?,
+            rebuild: None,
+        };
+
+        if stale {
+            dewey.rebuild();
+        }
+
+        Ok(dewey)
+    }
+
+    /// Build a fresh index from the blocks on a background thread
+    ///
+    /// Queries are served from the current index in the meantime
+    /// The first call after the new one's done swaps it in, caught up on whatever was added
+    /// or removed while it was being built
+    ///
+    /// Does nothing if a rebuild is already running
+    pub fn rebuild(&mut self) {
+        if self.rebuild.is_some() {
+            return;
+        }
+
+        lprint!(info, "Dewey: rebuilding the index in the background");
+        self.rebuild = Some(std::thread::spawn(|| HNSW::new(true)));
+    }
+
+    /// Whether a rebuild is still running
+    pub fn rebuilding(&self) -> bool {
+        self.rebuild.as_ref().is_some_and(|r| !r.is_finished())
+    }
+
+    /// Block until a running rebuild is done, then swap it in
+    pub fn wait_for_rebuild(&mut self) -> Result<(), std::io::Error> {
+        match self.rebuild.take() {
+            Some(rebuild) => self.swap_index(rebuild),
+            None => Ok(()),
+        }
+    }
+
+    // Swaps in the rebuilt index if it's ready--failures keep the old one
+    fn poll_rebuild(&mut self) {
+        if !self.rebuild.as_ref().is_some_and(|r| r.is_finished()) {
+            return;
+        }
+
+        let rebuild = self.rebuild.take().unwrap();
+        if let Err(e) = self.swap_index(rebuild) {
+            lprint!(
+                error,
+                "Dewey: error swapping in the rebuilt index: {}; keeping the old one",
+                e
+            );
+        }
     }
 
-    // TODO: better define how filters should be passed
+    fn swap_index(
+        &mut self,
+        rebuild: std::thread::JoinHandle<Result<HNSW, std::io::Error>>,
+    ) -> Result<(), std::io::Error> {
+        let mut index = rebuild
+            .join()
+            .map_err(|_| std::io::Error::other("index rebuild panicked"))??;
+
+        self.cache.refresh_directory()?;
+        let added = index.catch_up(&mut self.cache)?;
+        index.serialize(&hnsw::index_path())?;
+
+        self.index = index;
+
+        lprint!(
+            info,
+            "Dewey: swapped in the rebuilt index, with {} embeddings added while it was built",
+            added
+        );
+
+        Ok(())
+    }
+
+    // Only embeddings passing every filter are returned--see `Filter`
+    // These are checked as the index is searched, so filtering doesn't cut into the results
+    //
+    // Results come best first, each with its distance from the query--see `QueryResult`
     pub fn query(
         &mut self,
         query_filepath: &str,
-        filters: Vec<String>,
+        filters: Vec<Filter>,
         k: usize,
-    ) -> Result<Vec<EmbeddingSource>, std::io::Error> {
-        let embedding = match embed(&EmbeddingSource {
+    ) -> Result<Vec<QueryResult>, std::io::Error> {
+        self.query_with_options(query_filepath, filters, k, QueryOptions::default())
+    }
+
+    /// Query with results ranked by `options`, blending similarity with each embedding's age and
+    /// retrieval count
+    ///
+    /// Each returned embedding has its retrieval count incremented
+    pub fn query_with_options(
+        &mut self,
+        query_filepath: &str,
+        filters: Vec<Filter>,
+        k: usize,
+        options: QueryOptions,
+    ) -> Result<Vec<QueryResult>, std::io::Error> {
+        let embedding = match block_on(embed(&EmbeddingSource {
             filepath: query_filepath.to_string(),
             meta: std::collections::HashSet::new(),
             subset: None,
-        }) {
+        })) {
             Ok(e) => e,
             Err(e) => {
                 error!("Failed to create embedding: {}", e);
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
+                return Err(std::io::Error::other(e));
             }
         };
 
-        let filters = filters
-            .iter()
-            .map(|f| Filter::from_string(&f.to_string()).unwrap())
-            .collect::<Vec<Filter>>();
+        self.query_embedding(embedding, filters, k, options)
+    }
+
+    /// Same as `query_with_options`, for a query that's already been embedded--see `embed`
+    ///
+    /// Nothing here goes over the network, so whoever shares a `Dewey` can embed without holding it
+    pub fn query_embedding(
+        &mut self,
+        embedding: Embedding,
+        filters: Vec<Filter>,
+        k: usize,
+        options: QueryOptions,
+    ) -> Result<Vec<QueryResult>, std::io::Error> {
+        self.poll_rebuild();
 
+        let start = std::time::Instant::now();
         let query = Query { embedding, filters };
 
-        Ok(self
+        // Extra candidates to rerank when the other signals are in play
+        // The search is capped at `ef` = 200 comparisons anyways
+        let candidate_count = if options.similarity_only() {
+            k
+        } else {
+            std::cmp::min(k * 4, 200)
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        let mut results = self
             .index
-            .query(&mut self.cache, &query, k, 200)
-            .iter()
-            .map(|p| p.0.source_file.clone())
+            .query(&mut self.cache, &query, candidate_count, 200)
+            .into_iter()
+            .map(|(e, distance)| {
+                let score = options.score(1.0 - distance, self.stats.get(e.id), now);
+                (e, distance, score)
+            })
+            .collect::<Vec<_>>();
+
+        results.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
+        results.truncate(k);
+
+        for (e, _, _) in results.iter() {
+            self.stats.record_access(e.id);
+        }
+
+        match self.stats.save() {
+            Ok(_) => {}
+            Err(e) => {
+                lprint!(error, "Error saving embedding stats: {}; ignoring", e);
+            }
+        };
+
+        info!(
+            "Dewey query returned {} of {} results in {}ms",
+            results.len(),
+            k,
+            start.elapsed().as_millis()
+        );
+
+        Ok(results
+            .into_iter()
+            .enumerate()
+            .map(|(rank, (e, distance, score))| {
+                let mut source = e.source_file.clone();
+                if !options.subsets {
+                    source.subset = None;
+                }
+
+                QueryResult {
+                    source,
+                    distance,
+                    rank,
+                    score,
+                }
+            })
             .collect())
     }
 
-    // This returns an empty json object {} on success
-    // or an object with just an `error` key on error
+    /// Load the blocks holding embeddings for the given source files into the cache,
+    /// so the next query touching them doesn't start cold
+    ///
+    /// `filters` are embedding source filepaths--files without embeddings are skipped
+    pub fn prefetch(&mut self, filters: Vec<String>) -> Result<(), std::io::Error> {
+        self.poll_rebuild();
+
+        let now = std::time::Instant::now();
+        let directory = dbio::get_directory()?;
+
+        let blocks = filters
+            .iter()
+            .filter_map(|f| directory.file_map.get(f).copied())
+            .collect::<std::collections::HashSet<u64>>();
+
+        let count = self.cache.prefetch(&blocks)?;
+
+        lprint!(
+            info,
+            "Dewey: prefetched {} blocks for {} files in {}ms",
+            count,
+            filters.len(),
+            now.elapsed().as_millis()
+        );
+
+        Ok(())
+    }
+
+    /// Re-embed a file that's already been added, keeping its meta tags
+    ///
+    /// The old embeddings are removed from the index and the new one takes their place
+    /// Files that haven't been embedded are left alone
     pub fn reindex(&mut self, filepath: String) -> Result<(), std::io::Error> {
-        crate::dbio::update_file_embeddings(&filepath, &mut self.index)
+        let meta = match dbio::get_file_meta(&filepath)? {
+            Some(m) => m,
+            None => {
+                error!(
+                    "filepath {} not catalogued in Directory, aborting update",
+                    filepath
+                );
+                return Ok(());
+            }
+        };
+
+        // Embedded first, so a failure leaves the old embeddings where they were
+        let embedding = block_on(embed(&EmbeddingSource {
+            filepath: filepath.clone(),
+            subset: None,
+            meta,
+        }))?;
+
+        self.remove_embeddings(vec![filepath])?;
+        self.store(vec![embedding])
     }
 
     /// Add a new embedding to the system from the given file
@@ -102,12 +330,151 @@ impl Dewey {
     /// - Embedding store directory
     /// - HNSW index file
     pub fn add_embedding(&mut self, filepath: String) -> Result<(), std::io::Error> {
-        let mut embedding = embed(&EmbeddingSource {
+        self.add_embedding_with_meta(filepath, std::collections::HashSet::new())
+    }
+
+    /// Same as `add_embedding`, but tagging the embedding with `meta`
+    /// (e.g., where the file's contents originally came from)
+    ///
+    /// The tags are kept through `reindex`
+    pub fn add_embedding_with_meta(
+        &mut self,
+        filepath: String,
+        meta: std::collections::HashSet<String>,
+    ) -> Result<(), std::io::Error> {
+        let embedding = block_on(embed(&EmbeddingSource {
             filepath,
             subset: None,
-            meta: std::collections::HashSet::new(),
-        })?;
+            meta,
+        }))?;
+
+        self.store(vec![embedding])
+    }
+
+    /// Same as `add_embedding`, but for many files at once--these are embedded with up to
+    /// `EMBEDDING_BATCH_SIZE` in each API request, and the index is only written out once
+    ///
+    /// Returns the files that couldn't be embedded, which are left out of the index
+    pub fn add_embeddings(
+        &mut self,
+        filepaths: Vec<String>,
+    ) -> Result<Vec<(String, std::io::Error)>, std::io::Error> {
+        self.add_embeddings_with_meta(
+            filepaths
+                .into_iter()
+                .map(|filepath| (filepath, std::collections::HashSet::new()))
+                .collect(),
+        )
+    }
+
+    /// Same as `add_embeddings`, but tagging each file's embedding with its own meta
+    pub fn add_embeddings_with_meta(
+        &mut self,
+        files: Vec<(String, std::collections::HashSet<String>)>,
+    ) -> Result<Vec<(String, std::io::Error)>, std::io::Error> {
+        let sources = files
+            .into_iter()
+            .map(|(filepath, meta)| EmbeddingSource {
+                filepath,
+                subset: None,
+                meta,
+            })
+            .collect::<Vec<_>>();
+
+        let (embeddings, failed) = block_on(embed_batch(&sources));
+        lprint!(
+            info,
+            "Dewey: embedded {} of {} files in {} requests",
+            embeddings.len(),
+            sources.len(),
+            sources.len().div_ceil(EMBEDDING_BATCH_SIZE)
+        );
+
+        if !embeddings.is_empty() {
+            self.store(embeddings)?;
+        }
+
+        Ok(failed
+            .into_iter()
+            .map(|(source, e)| (source.filepath, e))
+            .collect())
+    }
+
+    /// Remove every embedding of the given file from the system--see `remove_embeddings`
+    pub fn remove_embedding(&mut self, filepath: String) -> Result<usize, std::io::Error> {
+        self.remove_embeddings(vec![filepath])
+    }
+
+    /// Remove every embedding of the given files from the system--the inverse of `add_embedding`
+    ///
+    /// The embedding store, the directory, and each embedding's stats are updated,
+    /// and their nodes in the HNSW index are tombstoned--once enough of those pile up,
+    /// the index is compacted
+    /// The files themselves are left alone
+    ///
+    /// Returns the number of embeddings removed
+    pub fn remove_embeddings(&mut self, filepaths: Vec<String>) -> Result<usize, std::io::Error> {
+        let filepaths = filepaths
+            .into_iter()
+            .collect::<std::collections::HashSet<String>>();
+
+        self.poll_rebuild();
+
+        let removed = dbio::remove_file_embeddings(&filepaths)?;
+        if removed.is_empty() {
+            return Ok(0);
+        }
+
+        for id in removed.iter() {
+            self.stats.remove(*id);
+        }
 
+        self.stats.save()?;
+
+        // Pulling nodes out of the graph can strand their neighbors, so they're only marked here
+        // Nothing needs writing--loading the index tombstones whatever the directory no longer has
+        for id in removed.iter() {
+            self.index.remove(*id);
+        }
+
+        self.cache.refresh_directory()?;
+
+        if self.index.needs_compaction() {
+            self.compact()?;
+        }
+
+        lprint!(
+            info,
+            "Dewey: removed {} embeddings of {} files",
+            removed.len(),
+            filepaths.len()
+        );
+
+        Ok(removed.len())
+    }
+
+    /// Rebuild the HNSW index from the blocks, dropping removed embeddings for good
+    pub fn compact(&mut self) -> Result<(), std::io::Error> {
+        let removed = self.index.size as usize - self.index.live();
+
+        self.index = HNSW::new(true)?;
+        self.cache = EmbeddingCache::new((20 * BLOCK_SIZE) as u32)?;
+        self.index.serialize(&hnsw::index_path())?;
+
+        lprint!(
+            info,
+            "Dewey: compacted the index, dropping {} removed embeddings",
+            removed
+        );
+
+        Ok(())
+    }
+
+    /// Add embeddings that were already made (see `embed` and `embed_batch`) to the system,
+    /// same as `add_embeddings` would
+    ///
+    /// Nothing here goes over the network, so whoever shares a `Dewey` can embed without holding it
+    pub fn store(&mut self, mut embeddings: Vec<Embedding>) -> Result<(), std::io::Error> {
         // TODO: ledger integration here at some point
         //       from what I understand the ledger is only for syncing
         //       between the local file system and the embedding store
@@ -116,41 +483,312 @@ impl Dewey {
         //       but it would be nice to have file/embedding syncing
         //       and tracking all taking place in one spot (the ledger)
 
-        match dbio::add_new_embedding(&mut embedding) {
-            Ok(_) => {}
-            Err(e) => {
-                error!("error adding embedding to store: {}", e);
-                return Err(e);
-            }
-        };
+        self.poll_rebuild();
+
+        for embedding in embeddings.iter_mut() {
+            match dbio::add_new_embedding(embedding) {
+                Ok(_) => {}
+                Err(e) => {
+                    error!("error adding embedding to store: {}", e);
+                    return Err(e);
+                }
+            };
+
+            lprint!(info, "Created embedding with id: {}", embedding.id);
+            self.stats.record_creation(embedding.id);
+        }
 
-        lprint!(info, "Created embedding with id: {}", embedding.id);
-        lprint!(info, "Finished writing embedding to file system");
+        self.stats.save()?;
+        lprint!(info, "Finished writing embeddings to file system");
 
         self.cache.refresh_directory()?;
         lprint!(info, "Refreshed cache directory");
 
-        match self.index.insert(&mut self.cache, &embedding) {
-            Ok(_) => {}
-            Err(e) => {
-                error!("Error adding embedding to index: {}", e);
-                return Err(e);
-            }
-        };
+        for embedding in embeddings.iter() {
+            match self.index.insert(&mut self.cache, embedding) {
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Error adding embedding to index: {}", e);
+                    return Err(e);
+                }
+            };
+        }
 
-        match self
-            .index
-            .serialize(&get_data_dir().join("index").to_str().unwrap().to_string())
-        {
+        // Only the new nodes go to disk, appended to the index log
+        match self.index.persist(&hnsw::index_path()) {
             Ok(_) => {}
             Err(e) => {
-                error!("error serializing index: {}", e);
+                error!("error persisting index: {}", e);
                 return Err(e);
             }
         };
 
-        lprint!(info, "Updated index with new embedding");
+        lprint!(
+            info,
+            "Updated index with {} new embeddings",
+            embeddings.len()
+        );
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_embeddings_leave_the_index() {
+        let _cleanup = crate::test_common::Cleanup;
+        assert!(crate::test_common::setup().is_ok());
+
+        let dir = chamber_common::get_root_dir().join("memories");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let filepaths = (0..4)
+            .map(|i| {
+                let filepath = dir.join(format!("{}.txt", i));
+                std::fs::write(&filepath, format!("memory number {}", i)).unwrap();
+                filepath.to_string_lossy().to_string()
+            })
+            .collect::<Vec<_>>();
+
+        let mut dewey = Dewey::new().unwrap();
+        assert!(dewey.add_embeddings(filepaths.clone()).unwrap().is_empty());
+
+        assert_eq!(
+            dewey.remove_embeddings(vec![filepaths[0].clone()]).unwrap(),
+            1
+        );
+        // Already gone
+        assert_eq!(
+            dewey.remove_embeddings(vec![filepaths[0].clone()]).unwrap(),
+            0
+        );
+
+        let directory = dbio::get_directory().unwrap();
+        assert_eq!(directory.len(), 3);
+        assert!(!directory.file_map.contains_key(&filepaths[0]));
+
+        let results = dewey.query(&filepaths[0], Vec::new(), 3).unwrap();
+        assert!(results.iter().all(|r| r.source.filepath != filepaths[0]));
+
+        // The file itself is left alone
+        assert!(std::path::Path::new(&filepaths[0]).exists());
+    }
+
+    #[test]
+    fn reindexed_embeddings_replace_their_nodes() {
+        let _cleanup = crate::test_common::Cleanup;
+        assert!(crate::test_common::setup().is_ok());
+
+        let dir = chamber_common::get_root_dir().join("reindexed");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let filepaths = (0..8)
+            .map(|i| {
+                let filepath = dir.join(format!("{}.txt", i));
+                std::fs::write(&filepath, format!("memory number {}", i)).unwrap();
+                filepath.to_string_lossy().to_string()
+            })
+            .collect::<Vec<_>>();
+
+        let mut dewey = Dewey::new().unwrap();
+        assert!(dewey.add_embeddings(filepaths.clone()).unwrap().is_empty());
+
+        std::fs::write(&filepaths[1], "something else entirely").unwrap();
+        dewey.reindex(filepaths[1].clone()).unwrap();
+
+        // The old node is still in the graph, just never returned
+        assert_eq!(dewey.index.live(), 8);
+        let results = dewey.query(&filepaths[1], Vec::new(), 8).unwrap();
+        assert_eq!(
+            results
+                .iter()
+                .filter(|r| r.source.filepath == filepaths[1])
+                .count(),
+            1
+        );
+
+        // Enough removals and the graph is rebuilt without them
+        for filepath in filepaths[2..5].iter() {
+            assert_eq!(dewey.remove_embedding(filepath.clone()).unwrap(), 1);
+        }
+
+        // (the last removal may have been tombstoned since)
+        assert!(!dewey.index.needs_compaction());
+        assert_eq!(dewey.index.live(), 5);
+        assert!(dewey.index.size <= 6);
+    }
+
+    #[test]
+    fn stale_indexes_are_rebuilt_in_the_background() {
+        let _cleanup = crate::test_common::Cleanup;
+        assert!(crate::test_common::setup().is_ok());
+
+        let dir = chamber_common::get_root_dir().join("rebuilt");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let filepaths = (0..5)
+            .map(|i| {
+                let filepath = dir.join(format!("{}.txt", i));
+                std::fs::write(&filepath, format!("rebuilt memory {}", i)).unwrap();
+                filepath.to_string_lossy().to_string()
+            })
+            .collect::<Vec<_>>();
+
+        let mut dewey = Dewey::new().unwrap();
+        assert!(dewey
+            .add_embeddings(filepaths[..4].to_vec())
+            .unwrap()
+            .is_empty());
+        drop(dewey);
+
+        // Nothing on disk to load
+        let index = hnsw::index_path();
+        let _ = std::fs::remove_file(&index);
+        std::fs::remove_file(format!("{}.log", index)).unwrap();
+
+        let mut dewey = Dewey::new().unwrap();
+        assert!(dewey.rebuild.is_some());
+
+        // Added while the rebuild runs
+        dewey.add_embedding(filepaths[4].clone()).unwrap();
+
+        dewey.wait_for_rebuild().unwrap();
+        assert!(!dewey.rebuilding());
+        assert_eq!(dewey.index.live(), 5);
+
+        let results = dewey.query(&filepaths[4], Vec::new(), 5).unwrap();
+        assert_eq!(results[0].source.filepath, filepaths[4]);
+
+        // Written out in full once it's swapped in
+        assert!(std::path::Path::new(&index).exists());
+    }
+
+    #[test]
+    fn logged_inserts_are_replayed() {
+        let _cleanup = crate::test_common::Cleanup;
+        assert!(crate::test_common::setup().is_ok());
+
+        let dir = chamber_common::get_root_dir().join("logged");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut dewey = Dewey::new().unwrap();
+        for i in 0..5 {
+            let filepath = dir.join(format!("{}.txt", i));
+            std::fs::write(&filepath, format!("logged memory {}", i)).unwrap();
+            dewey
+                .add_embedding(filepath.to_string_lossy().to_string())
+                .unwrap();
+        }
+
+        // Nothing's been written out in full
+        let index = hnsw::index_path();
+        assert!(!std::path::Path::new(&index).exists());
+        assert!(std::path::Path::new(&format!("{}.log", index)).exists());
+
+        let reloaded = Dewey::new().unwrap();
+        assert_eq!(reloaded.index.size, 5);
+        assert!(reloaded.index.layers == dewey.index.layers);
+    }
+
+    #[test]
+    fn filters_scope_queries() {
+        let _cleanup = crate::test_common::Cleanup;
+        assert!(crate::test_common::setup().is_ok());
+
+        let dir = chamber_common::get_root_dir().join("scoped");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let files = (0..6)
+            .map(|i| {
+                let filepath = dir.join(format!("{}.txt", i));
+                std::fs::write(&filepath, format!("scoped memory {}", i)).unwrap();
+
+                let tag = format!("conversation:{}", i % 2);
+                (
+                    filepath.to_string_lossy().to_string(),
+                    std::collections::HashSet::from([tag]),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut dewey = Dewey::new().unwrap();
+        assert!(dewey
+            .add_embeddings_with_meta(files.clone())
+            .unwrap()
+            .is_empty());
+
+        let results = dewey
+            .query(
+                &files[0].0,
+                vec![Filter::Equals("conversation:1".to_string())],
+                6,
+            )
+            .unwrap();
+        assert!(!results.is_empty());
+        assert!(results
+            .iter()
+            .all(|r| r.source.meta.contains("conversation:1")));
+
+        let results = dewey
+            .query(
+                &files[0].0,
+                vec![Filter::NotEquals("conversation:1".to_string())],
+                6,
+            )
+            .unwrap();
+        assert!(results
+            .iter()
+            .all(|r| !r.source.meta.contains("conversation:1")));
+
+        // The index is built at random, so which of the rest are found isn't fixed--
+        // but the query's own file is as close as it gets wherever it turns up
+        assert!(results.len() <= 3);
+        assert!(results
+            .iter()
+            .filter(|r| r.source.filepath == files[0].0)
+            .all(|r| r.distance.abs() < 1e-4 && (r.similarity() - 1.0).abs() < 1e-4));
+        assert!(results.iter().enumerate().all(|(i, r)| r.rank == i));
+        assert!(results.windows(2).all(|w| w[0].distance <= w[1].distance));
+
+        let results = dewey
+            .query(
+                &files[0].0,
+                vec![Filter::Prefix("conversation:".to_string())],
+                6,
+            )
+            .unwrap();
+        assert!(!results.is_empty() && results.len() <= 6);
+        assert!(results.iter().all(|r| r
+            .source
+            .meta
+            .iter()
+            .any(|m| m.starts_with("conversation:"))));
+
+        let results = dewey
+            .query(
+                &files[0].0,
+                vec![Filter::AnyOf(std::collections::HashSet::from([
+                    "conversation:1".to_string(),
+                    "conversation:2".to_string(),
+                ]))],
+                6,
+            )
+            .unwrap();
+        assert!(results.len() <= 3);
+        assert!(results
+            .iter()
+            .all(|r| r.source.meta.contains("conversation:1")));
+
+        assert_eq!(
+            Filter::from_string(&"in conversation:1,conversation:2".to_string()).unwrap(),
+            Filter::AnyOf(std::collections::HashSet::from([
+                "conversation:1".to_string(),
+                "conversation:2".to_string(),
+            ]))
+        );
+        assert!(Filter::from_string(&"conversation:1".to_string()).is_err());
+    }
+}
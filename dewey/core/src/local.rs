@@ -0,0 +1,61 @@
+use std::sync::Mutex;
+
+use chamber_common::Logger;
+use chamber_common::{error, info};
+use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
+
+use crate::openai::{to_embedding, EmbedFuture, Embedder, Embedding, EmbeddingSource};
+
+// Embeddings from a small model run on this machine, for when there's no OpenAI key
+//
+// The model is all-MiniLM-L6-v2 (384 dimensions, padded out to `EMBED_DIM`)
+// It's downloaded into the local directory the first time it's loaded--after that,
+// nothing leaves the machine
+
+// Loaded on first use and kept around--loading takes a while
+// Calls take turns on it, bulk embedding threads included
+static MODEL: Mutex<Option<TextEmbedding>> = Mutex::new(None);
+
+fn load() -> Result<TextEmbedding, std::io::Error> {
+    let cache_dir = chamber_common::get_local_dir().join("models");
+    info!("Loading local embedding model from {:?}", cache_dir);
+
+    TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::AllMiniLML6V2)
+            .with_cache_dir(cache_dir)
+            .with_show_download_progress(false),
+    )
+    .map_err(|e| {
+        error!("Failed to load local embedding model: {}", e);
+        std::io::Error::other(e.to_string())
+    })
+}
+
+pub struct LocalEmbedder;
+impl Embedder for LocalEmbedder {
+    // The model runs right here--there's nothing to wait on
+    fn embed<'a>(&'a self, batch: &'a [(EmbeddingSource, String)]) -> EmbedFuture<'a> {
+        Box::pin(async move { embed_locally(batch) })
+    }
+}
+
+fn embed_locally(batch: &[(EmbeddingSource, String)]) -> Result<Vec<Embedding>, std::io::Error> {
+    let mut model = MODEL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if model.is_none() {
+        *model = Some(load()?);
+    }
+
+    let texts = batch.iter().map(|b| b.1.as_str()).collect::<Vec<_>>();
+    let values = model.as_ref().unwrap().embed(texts, None).map_err(|e| {
+        error!("Failed to embed batch of {} locally: {}", batch.len(), e);
+        std::io::Error::other(e.to_string())
+    })?;
+
+    Ok(batch
+        .iter()
+        .zip(values.iter())
+        .map(|(b, v)| to_embedding(&b.0, v))
+        .collect())
+}
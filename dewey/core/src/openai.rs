@@ -1,10 +1,10 @@
 use std::env;
-use std::io::{Read, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use sha2::{Digest, Sha256};
 
 use chamber_common::Logger;
 use chamber_common::{error, info};
@@ -15,25 +15,271 @@ use crate::serialization::Serialize;
 
 pub const EMBED_DIM: usize = 1536;
 
+// Where embeddings are sourced from
+// `Local` runs a small model on this machine (see `local.rs`), so memory works without a key
+// `Mock` generates deterministic pseudo-embeddings from a hash of the input text,
+// so everything can run without an API key or network access (tests, regression, etc.)
+//
+// Embeddings from different providers aren't comparable--switching means reindexing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmbeddingProvider {
+    OpenAI,
+    Local,
+    Mock,
+}
+
+impl EmbeddingProvider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EmbeddingProvider::OpenAI => "openai",
+            EmbeddingProvider::Local => "local",
+            EmbeddingProvider::Mock => "mock",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "openai" => Some(EmbeddingProvider::OpenAI),
+            "local" => Some(EmbeddingProvider::Local),
+            "mock" => Some(EmbeddingProvider::Mock),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            EmbeddingProvider::OpenAI => 1,
+            EmbeddingProvider::Local => 2,
+            EmbeddingProvider::Mock => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(EmbeddingProvider::OpenAI),
+            2 => Some(EmbeddingProvider::Local),
+            3 => Some(EmbeddingProvider::Mock),
+            _ => None,
+        }
+    }
+}
+
+// 0 is unset--`config::setup` settles it from the config the first time through
+static PROVIDER: AtomicU8 = AtomicU8::new(if cfg!(feature = "regression") { 3 } else { 0 });
+
+// This is process-wide--set it once before any embeddings are made
+pub fn set_embedding_provider(provider: EmbeddingProvider) {
+    PROVIDER.store(provider.id(), Ordering::SeqCst);
+    *EMBEDDING_MODEL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+}
+
+pub fn get_embedding_provider() -> EmbeddingProvider {
+    EmbeddingProvider::from_id(PROVIDER.load(Ordering::SeqCst)).unwrap_or_else(|| {
+        configured_embedding_provider().unwrap_or_else(|e| {
+            error!("{}; using OpenAI", e);
+            EmbeddingProvider::OpenAI
+        })
+    })
+}
+
+pub(crate) fn has_embedding_provider() -> bool {
+    EmbeddingProvider::from_id(PROVIDER.load(Ordering::SeqCst)).is_some()
+}
+
+pub fn local_embeddings_available() -> bool {
+    cfg!(feature = "local")
+}
+
+// The config's `embedder` file is one line: `<provider> [model] [dimensions]`,
+// e.g. `local`, or `openai text-embedding-3-large 1024`
+fn read_embedder_config() -> Option<Vec<String>> {
+    let path = chamber_common::get_config_dir().join("embedder");
+    std::fs::read_to_string(&path)
+        .unwrap_or_default()
+        .lines()
+        .map(|l| l.trim())
+        .find(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.split_whitespace().map(|p| p.to_string()).collect())
+}
+
+// The provider named in the config's `embedder` file (`openai`, `local`, or `mock`),
+// and OpenAI if it doesn't name one
+pub fn configured_embedding_provider() -> Result<EmbeddingProvider, std::io::Error> {
+    provider_from_config(read_embedder_config())
+}
+
+// Never guessed from which keys happen to be set--embeddings from one provider can't be searched
+// with another's, so a provider that changes on its own would strand everything already indexed
+fn provider_from_config(config: Option<Vec<String>>) -> Result<EmbeddingProvider, std::io::Error> {
+    match config {
+        Some(config) => EmbeddingProvider::from_name(&config[0]).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown embedder: {}", config[0]),
+            )
+        }),
+        None => Ok(EmbeddingProvider::OpenAI),
+    }
+}
+
+// (model, dimensions it natively has) for each OpenAI model
+// Both can be shortened with the `dimensions` parameter, though only up to `EMBED_DIM` fits
+const OPENAI_MODELS: [(&str, u32); 2] = [
+    ("text-embedding-3-small", 1536),
+    ("text-embedding-3-large", 3072),
+];
+
+// See `local.rs`
+pub(crate) const LOCAL_MODEL: (&str, u32) = ("all-MiniLM-L6-v2", 384);
+
+// The model embeddings are made with, and how many dimensions they have
+// Embeddings are only comparable with others from the same model at the same size,
+// so this is recorded with each block of them--see `dbio::EmbeddingBlock`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EmbeddingModel {
+    pub name: String,
+    pub dimensions: u32,
+}
+
+impl std::fmt::Display for EmbeddingModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({} dimensions)", self.name, self.dimensions)
+    }
+}
+
+impl EmbeddingModel {
+    // Anything left out is the provider's default
+    pub fn new(
+        provider: EmbeddingProvider,
+        name: Option<&str>,
+        dimensions: Option<u32>,
+    ) -> Result<Self, std::io::Error> {
+        let invalid =
+            |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+
+        let (name, native) = match provider {
+            EmbeddingProvider::OpenAI => {
+                let name = name.unwrap_or(OPENAI_MODELS[0].0);
+                match OPENAI_MODELS.iter().find(|(n, _)| *n == name) {
+                    Some(model) => *model,
+                    None => {
+                        return Err(invalid(format!("Unknown OpenAI embedding model: {}", name)))
+                    }
+                }
+            }
+            EmbeddingProvider::Local => match name {
+                None => LOCAL_MODEL,
+                Some(name) if name == LOCAL_MODEL.0 => LOCAL_MODEL,
+                Some(name) => {
+                    return Err(invalid(format!("Unknown local embedding model: {}", name)))
+                }
+            },
+            EmbeddingProvider::Mock => ("mock", EMBED_DIM as u32),
+        };
+
+        let dimensions = dimensions.unwrap_or(native.min(EMBED_DIM as u32));
+        if dimensions == 0 || dimensions > native.min(EMBED_DIM as u32) {
+            return Err(invalid(format!(
+                "{} can't be embedded with {} dimensions--it takes up to {}",
+                name,
+                dimensions,
+                native.min(EMBED_DIM as u32)
+            )));
+        }
+
+        // Only OpenAI's models can be shortened
+        if provider != EmbeddingProvider::OpenAI && dimensions != native {
+            return Err(invalid(format!("{} only has {} dimensions", name, native)));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            dimensions,
+        })
+    }
+
+    // What blocks were embedded with before models were recorded
+    pub fn legacy() -> Self {
+        Self {
+            name: OPENAI_MODELS[0].0.to_string(),
+            dimensions: OPENAI_MODELS[0].1,
+        }
+    }
+}
+
+// Settled along with the provider in `config::setup`
+static EMBEDDING_MODEL: Mutex<Option<EmbeddingModel>> = Mutex::new(None);
+
+// The model and dimensions from the config's `embedder` file,
+// as long as it names `provider`--otherwise they're the provider's defaults
+pub fn configured_embedding_model(
+    provider: EmbeddingProvider,
+) -> Result<EmbeddingModel, std::io::Error> {
+    let config = read_embedder_config()
+        .filter(|c| EmbeddingProvider::from_name(&c[0]) == Some(provider))
+        .unwrap_or_default();
+
+    let dimensions = match config.get(2) {
+        Some(d) => Some(d.parse::<u32>().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid embedding dimensions {}: {}", d, e),
+            )
+        })?),
+        None => None,
+    };
+
+    EmbeddingModel::new(provider, config.get(1).map(|m| m.as_str()), dimensions)
+}
+
+pub(crate) fn set_embedding_model(model: EmbeddingModel) {
+    *EMBEDDING_MODEL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(model);
+}
+
+pub fn get_embedding_model() -> EmbeddingModel {
+    if let Some(model) = EMBEDDING_MODEL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+    {
+        return model;
+    }
+
+    let provider = get_embedding_provider();
+    configured_embedding_model(provider).unwrap_or_else(|e| {
+        error!("{}; using the default model", e);
+        EmbeddingModel::new(provider, None, None).unwrap()
+    })
+}
+
 #[derive(Debug, Clone)]
 struct RequestParams {
-    host: String,
-    path: String,
-    port: u16,
+    url: String,
     model: String,
+    dimensions: u32,
     authorization_token: String,
 }
 
 impl RequestParams {
-    fn new() -> Self {
-        Self {
-            host: "api.openai.com".to_string(),
-            path: "/v1/embeddings".to_string(),
-            port: 443,
-            model: "text-embedding-3-small".to_string(),
-            authorization_token: env::var("OPENAI_API_KEY")
-                .expect("OPENAI_API_KEY environment variable not set"),
-        }
+    fn new() -> Result<Self, std::io::Error> {
+        let model = get_embedding_model();
+        let authorization_token = env::var("OPENAI_API_KEY").map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("OpenAI API key not found: {}", e),
+            )
+        })?;
+
+        Ok(Self {
+            url: "https://api.openai.com/v1/embeddings".to_string(),
+            model: model.name,
+            dimensions: model.dimensions,
+            authorization_token,
+        })
     }
 }
 
@@ -51,219 +297,197 @@ pub struct Embedding {
     pub data: [f32; EMBED_DIM],
 }
 
-trait EmbeddingApiClient {
-    fn embedding_api_call(
-        params: &RequestParams,
-        batch: &Vec<(EmbeddingSource, String)>,
-    ) -> Result<Vec<Embedding>, std::io::Error>;
-}
-
-struct ApiClient;
-impl EmbeddingApiClient for ApiClient {
-    fn embedding_api_call(
-        params: &RequestParams,
-        batch: &Vec<(EmbeddingSource, String)>,
-    ) -> Result<Vec<Embedding>, std::io::Error> {
-        let duration = std::time::Duration::from_secs(30);
-        let address = (params.host.clone(), params.port)
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| {
-                error!(
-                    "Failed to resolve address {:?}",
-                    (params.host.clone(), params.port)
-                );
-                std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    "Failed to resolve address",
-                )
-            })?;
-
-        let stream = match TcpStream::connect_timeout(&address, duration) {
-            Ok(stream) => stream,
-            Err(e) => {
-                error!("Failed to connect to OpenAI API: {:?}", e);
-                return Err(e);
-            }
-        };
+pub(crate) type EmbedFuture<'a> = std::pin::Pin<
+    Box<dyn std::future::Future<Output = Result<Vec<Embedding>, std::io::Error>> + Send + 'a>,
+>;
 
-        match stream.set_read_timeout(Some(duration)) {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Failed to set read timeout: {:?}", e);
-                return Err(e);
-            }
-        }
+// Turns a batch of (source, contents) into one embedding per input, in the same order
+// This is async since it's usually a network call--see `block_on` for callers that aren't
+pub(crate) trait Embedder: Send + Sync {
+    fn embed<'a>(&'a self, batch: &'a [(EmbeddingSource, String)]) -> EmbedFuture<'a>;
+}
 
-        match stream.set_write_timeout(Some(duration)) {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Failed to set write timeout: {:?}", e);
-                return Err(e);
-            }
-        }
+// Runs `future` to completion for callers that aren't async themselves
+//
+// Inside a runtime (e.g. William's `spawn_blocking` tasks), the runtime's used, with the worker's
+// other tasks handed off first if it's on one--otherwise, a runtime is made just for this
+pub(crate) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => tokio::task::block_in_place(|| handle.block_on(future)),
+        Err(_) => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to create runtime")
+            .block_on(future),
+    }
+}
 
-        let connector = native_tls::TlsConnector::new().expect("Failed to create TLS connector");
-        let mut stream = connector
-            .connect(&params.host, stream)
-            .expect("Failed to establish TLS connection");
+// Models with fewer dimensions than `EMBED_DIM` are padded out with zeros,
+// which leaves their cosine similarities as they were
+pub(crate) fn to_embedding(source: &EmbeddingSource, values: &[f32]) -> Embedding {
+    let mut embedding = Embedding {
+        id: 0,
+        data: [0.0; EMBED_DIM],
+        source_file: source.clone(),
+    };
 
-        let body = serde_json::json!({
-            "model": params.model,
-            "input": batch.iter().map(|pair| pair.1.clone()).collect::<Vec<String>>(),
-        });
-        let json = serde_json::json!(body);
-        let json_string = serde_json::to_string(&json)?;
-
-        let auth_string = "Authorization: Bearer ".to_string() + &params.authorization_token;
-
-        let request = format!(
-            "POST {} HTTP/1.1\r\n\
-        Host: {}\r\n\
-        Content-Type: application/json\r\n\
-        Content-Length: {}\r\n\
-        Accept: */*\r\n\
-        {}\r\n\r\n\
-        {}",
-            params.path,
-            params.host,
-            json_string.len(),
-            auth_string,
-            json_string.trim()
+    if values.len() > EMBED_DIM {
+        error!(
+            "Embedding has {} dimensions, more than the {} that fit; truncating",
+            values.len(),
+            EMBED_DIM
         );
+    }
 
-        match stream.write_all(request.as_bytes()) {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Failed to write to OpenAI stream: {:?}", e);
-                return Err(e);
-            }
-        }
+    for (d, v) in embedding.data.iter_mut().zip(values.iter()) {
+        *d = *v;
+    }
 
-        match stream.flush() {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Failed to flush OpenAI stream: {:?}", e);
-                return Err(e);
-            }
-        }
+    crate::hnsw::normalize(&mut embedding);
+    embedding
+}
 
-        let mut reader = std::io::BufReader::new(&mut stream);
+struct OpenAIEmbedder {
+    params: RequestParams,
+}
 
-        let mut buffer = String::new();
-        // read 2 characters at a time to check for CRLF
-        while !buffer.ends_with("\r\n\r\n") {
-            let mut chunk = [0; 1];
-            match reader.read(&mut chunk) {
-                Ok(0) => {
-                    error!("Failed to read from OpenAI stream: EOF");
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::UnexpectedEof,
-                        "Failed to read from OpenAI stream",
-                    ));
-                }
-                Ok(_) => {
-                    buffer.push_str(&String::from_utf8_lossy(&chunk));
+impl Embedder for OpenAIEmbedder {
+    fn embed<'a>(&'a self, batch: &'a [(EmbeddingSource, String)]) -> EmbedFuture<'a> {
+        Box::pin(async move {
+            let params = &self.params;
+            let request = serde_json::json!({
+                "model": params.model,
+                "dimensions": params.dimensions,
+                "input": batch.iter().map(|pair| pair.1.clone()).collect::<Vec<String>>(),
+            });
+
+            // A client per request--a client's connections belong to the runtime they were made on,
+            // and callers without one of their own get a fresh runtime each time (see `block_on`)
+            let client = reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(30))
+                .build()
+                .map_err(|e| {
+                    error!("Failed to create OpenAI client: {:?}", e);
+                    std::io::Error::other(e)
+                })?;
+
+            let response = match client
+                .post(&params.url)
+                .bearer_auth(&params.authorization_token)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_string(&request)?)
+                .send()
+                .await
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("Failed to reach the OpenAI API: {:?}", e);
+                    return Err(std::io::Error::other(e));
                 }
+            };
+
+            let status = response.status();
+            let body = match response.text().await {
+                Ok(body) => body,
                 Err(e) => {
                     error!("Failed to read from OpenAI stream: {:?}", e);
-                    return Err(e);
+                    return Err(std::io::Error::other(e));
                 }
-            }
-        }
-
-        let headers = buffer.split("\r\n").collect::<Vec<&str>>();
-        let content_length = headers
-            .iter()
-            .find(|header| header.starts_with("Content-Length"))
-            .ok_or_else(|| {
-                error!("Failed to find Content-Length header: {:?}", headers);
-                std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Failed to find Content-Length header",
-                )
-            })?;
-
-        let content_length = content_length.split(": ").collect::<Vec<&str>>()[1]
-            .parse::<usize>()
-            .unwrap();
-
-        let mut body = vec![0; content_length];
-        reader.read_exact(&mut body)?;
-
-        let body = String::from_utf8_lossy(&body).to_string();
-        let response_json = serde_json::from_str(&body);
-
-        if response_json.is_err() {
-            error!("request: {}", request);
-            error!("Failed to parse JSON: {}", body);
-            error!("Headers: {}", headers.join("\n"));
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Failed to parse JSON",
-            ));
-        }
+            };
 
-        let response_json: serde_json::Value = response_json.unwrap();
-        let data = match response_json["data"].as_array() {
-            Some(data) => data,
-            _ => {
-                error!("batch: {:?}", batch);
-                error!("Failed to parse data from JSON: {:?}", response_json);
-                error!("Request: {}", request);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Failed to parse data from JSON",
-                ));
-            }
-        };
+            let response_json: serde_json::Value = match serde_json::from_str(&body) {
+                Ok(json) => json,
+                Err(_) => {
+                    error!("request: {}", request);
+                    error!("Failed to parse JSON ({}): {}", status, body);
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Failed to parse JSON",
+                    ));
+                }
+            };
 
-        let mut embeddings = Vec::new();
-        for (i, datum) in data.iter().enumerate() {
-            let mut embedding = Embedding {
-                id: 0,
-                data: [0.0; 1536],
-                source_file: batch[i].0.clone(),
+            let data = match response_json["data"].as_array() {
+                Some(data) => data,
+                _ => {
+                    error!("batch: {:?}", batch);
+                    error!(
+                        "Failed to parse data from JSON ({}): {:?}",
+                        status, response_json
+                    );
+                    error!("Request: {}", request);
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Failed to parse data from JSON",
+                    ));
+                }
             };
 
-            for (i, value) in datum["embedding"].as_array().unwrap().iter().enumerate() {
-                embedding.data[i] = value.as_f64().unwrap() as f32;
+            let mut embeddings = Vec::new();
+            for (i, datum) in data.iter().enumerate() {
+                let values = datum["embedding"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|value| value.as_f64().unwrap() as f32)
+                    .collect::<Vec<_>>();
+
+                embeddings.push(to_embedding(&batch[i].0, &values));
             }
 
-            embeddings.push(embedding);
-        }
+            Ok(embeddings)
+        })
+    }
+}
 
-        Ok(embeddings)
+// Same text in, same embedding out
+// The hash of the text seeds the generator, and the result is normalized like OpenAI's
+struct MockEmbedder;
+impl Embedder for MockEmbedder {
+    fn embed<'a>(&'a self, batch: &'a [(EmbeddingSource, String)]) -> EmbedFuture<'a> {
+        Box::pin(async move { Ok(mock_embeddings(batch)) })
     }
 }
 
-struct TestApiCall;
-impl EmbeddingApiClient for TestApiCall {
-    fn embedding_api_call(
-        _params: &RequestParams,
-        batch: &Vec<(EmbeddingSource, String)>,
-    ) -> Result<Vec<Embedding>, std::io::Error> {
-        let mut embeddings = Vec::new();
-        let mut rng = rand::thread_rng();
-
-        for (i, b) in batch.iter().enumerate() {
-            let embedding = Embedding {
-                id: i as u64,
-                data: [0.0; 1536].map(|_| rng.gen()),
-                source_file: b.0.clone(),
-            };
+fn mock_embeddings(batch: &[(EmbeddingSource, String)]) -> Vec<Embedding> {
+    let mut embeddings = Vec::new();
 
-            embeddings.push(embedding);
-        }
+    for b in batch.iter() {
+        let seed: [u8; 32] = Sha256::digest(b.1.as_bytes()).into();
+        let mut rng = rand::rngs::StdRng::from_seed(seed);
+
+        let mut embedding = Embedding {
+            id: 0,
+            data: [0.0; EMBED_DIM].map(|_| rng.gen_range(-1.0..1.0)),
+            source_file: b.0.clone(),
+        };
+
+        crate::hnsw::normalize(&mut embedding);
+        embeddings.push(embedding);
+    }
 
-        Ok(embeddings)
+    embeddings
+}
+
+fn get_embedder() -> Result<Arc<dyn Embedder>, std::io::Error> {
+    match get_embedding_provider() {
+        EmbeddingProvider::OpenAI => Ok(Arc::new(OpenAIEmbedder {
+            params: RequestParams::new()?,
+        })),
+        #[cfg(feature = "local")]
+        EmbeddingProvider::Local => Ok(Arc::new(crate::local::LocalEmbedder)),
+        #[cfg(not(feature = "local"))]
+        EmbeddingProvider::Local => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Dewey was built without local embeddings (the `local` feature)",
+        )),
+        EmbeddingProvider::Mock => Ok(Arc::new(MockEmbedder)),
     }
 }
 
 // multithreaded wrapper over the actual bulk API call
 pub fn embed_bulk(sources: &Vec<EmbeddingSource>) -> Result<Vec<Embedding>, std::io::Error> {
     println!("embedding bulk");
-    let params = RequestParams::new();
 
     // TODO: there's probably a better programmatic way of determining this
     const NUM_THREADS: usize = 8;
@@ -271,11 +495,7 @@ pub fn embed_bulk(sources: &Vec<EmbeddingSource>) -> Result<Vec<Embedding>, std:
     let (tx, rx) = std::sync::mpsc::channel::<Vec<(EmbeddingSource, String)>>();
     let rx = Arc::new(Mutex::new(rx));
 
-    let api_call = if cfg!(feature = "regression") {
-        TestApiCall::embedding_api_call
-    } else {
-        ApiClient::embedding_api_call
-    };
+    let embedder = get_embedder()?;
 
     // API requests need batched up to keep from exceeding token limits
     let batches = batch_sources(&sources)?;
@@ -285,14 +505,14 @@ pub fn embed_bulk(sources: &Vec<EmbeddingSource>) -> Result<Vec<Embedding>, std:
     let count = Arc::new(Mutex::new(0));
     for i in 0..std::cmp::min(NUM_THREADS, batches.len()) {
         let thread_rx = Arc::clone(&rx);
-        let params = params.clone();
+        let embedder = Arc::clone(&embedder);
         let embeddings = Arc::clone(&embeddings);
         let count = Arc::clone(&count);
         let thread = thread::spawn(move || loop {
             let batch = thread_rx.lock().unwrap().recv();
             match batch {
                 Ok(batch) => {
-                    match api_call(&params, &batch) {
+                    match block_on(embedder.embed(&batch)) {
                         Ok(new_embeddings) => {
                             let mut embeddings = embeddings.lock().unwrap();
                             embeddings.extend(new_embeddings);
@@ -347,7 +567,13 @@ pub fn embed_bulk(sources: &Vec<EmbeddingSource>) -> Result<Vec<Embedding>, std:
     Ok(embeddings)
 }
 
-pub fn embed(source: &EmbeddingSource) -> Result<Embedding, std::io::Error> {
+// Inputs per request for `embed_batch`
+// OpenAI takes up to 2048, but every input can be up to ~8k tokens and a request is capped at
+// 300k tokens total
+pub const EMBEDDING_BATCH_SIZE: usize = 32;
+
+// The source's contents, trimmed to what the embeddings API will take
+fn read_query(source: &EmbeddingSource) -> Result<String, std::io::Error> {
     let query = read_source(source)?;
     if query.len() == 0 {
         error!("Invalid query size: {}", query.len());
@@ -368,16 +594,14 @@ pub fn embed(source: &EmbeddingSource) -> Result<Embedding, std::io::Error> {
         query
     };
 
-    let api_call = if cfg!(feature = "regression") {
-        TestApiCall::embedding_api_call
-    } else {
-        ApiClient::embedding_api_call
-    };
+    Ok(query)
+}
+
+pub async fn embed(source: &EmbeddingSource) -> Result<Embedding, std::io::Error> {
+    let query = read_query(source)?;
+    let embedder = get_embedder()?;
 
-    match api_call(
-        &RequestParams::new(),
-        &vec![(source.clone(), query.clone())],
-    ) {
+    match embedder.embed(&[(source.clone(), query.clone())]).await {
         Ok(embeddings) => Ok(embeddings[0].clone()),
         Err(e) => {
             error!("Failed to embed query \"{}\": {:?}", query, e);
@@ -385,3 +609,208 @@ pub fn embed(source: &EmbeddingSource) -> Result<Embedding, std::io::Error> {
         }
     }
 }
+
+/// Embeds `sources` with up to `EMBEDDING_BATCH_SIZE` in each API request
+///
+/// Sources that can't be read, or whose request fails, are returned with the error
+/// instead of holding up the rest
+pub async fn embed_batch(
+    sources: &[EmbeddingSource],
+) -> (Vec<Embedding>, Vec<(EmbeddingSource, std::io::Error)>) {
+    let mut embeddings = Vec::new();
+    let mut failed = Vec::new();
+
+    let mut inputs = Vec::new();
+    for source in sources {
+        match read_query(source) {
+            Ok(query) => inputs.push((source.clone(), query)),
+            Err(e) => failed.push((source.clone(), e)),
+        }
+    }
+
+    let embedder = match get_embedder() {
+        Ok(e) => e,
+        Err(e) => {
+            error!("Failed to set up embeddings: {:?}", e);
+            for (source, _) in inputs {
+                failed.push((source, std::io::Error::new(e.kind(), e.to_string())));
+            }
+
+            return (embeddings, failed);
+        }
+    };
+
+    for batch in inputs.chunks(EMBEDDING_BATCH_SIZE) {
+        let batch = batch.to_vec();
+        match embedder.embed(&batch).await {
+            Ok(new_embeddings) => embeddings.extend(new_embeddings),
+            Err(e) => {
+                error!("Failed to embed batch of {}: {:?}", batch.len(), e);
+                for (source, _) in batch {
+                    failed.push((source, std::io::Error::new(e.kind(), e.to_string())));
+                }
+            }
+        }
+    }
+
+    (embeddings, failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hnsw::dot;
+
+    fn mock_embedding(text: &str) -> Embedding {
+        let source = EmbeddingSource {
+            filepath: String::new(),
+            meta: std::collections::HashSet::new(),
+            subset: None,
+        };
+
+        mock_embeddings(&[(source, text.to_string())]).remove(0)
+    }
+
+    #[test]
+    fn mock_embeddings_are_deterministic() {
+        set_embedding_provider(EmbeddingProvider::Mock);
+
+        let a = mock_embedding("hello");
+        let b = mock_embedding("hello");
+        let c = mock_embedding("goodbye");
+
+        assert_eq!(a.data, b.data);
+        assert_ne!(a.data, c.data);
+
+        assert!((dot(&a, &a) - 1.0).abs() < 1e-4);
+        assert!(dot(&a, &c) < 0.5);
+    }
+
+    #[test]
+    fn smaller_embeddings_are_padded() {
+        let source = EmbeddingSource {
+            filepath: String::new(),
+            meta: std::collections::HashSet::new(),
+            subset: None,
+        };
+
+        let a = to_embedding(&source, &[3.0, 4.0]);
+        let b = to_embedding(&source, &[4.0, 3.0]);
+
+        assert!((a.data[0] - 0.6).abs() < 1e-6);
+        assert!((a.data[1] - 0.8).abs() < 1e-6);
+        assert!(a.data[2..].iter().all(|d| *d == 0.0));
+        assert!((dot(&a, &b) - 0.96).abs() < 1e-4);
+    }
+
+    #[test]
+    fn embedding_models() {
+        let model = EmbeddingModel::new(EmbeddingProvider::OpenAI, None, None).unwrap();
+        assert_eq!(model, EmbeddingModel::legacy());
+
+        // Large is cut down to what fits unless it's told otherwise
+        let model = EmbeddingModel::new(
+            EmbeddingProvider::OpenAI,
+            Some("text-embedding-3-large"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(model.dimensions, EMBED_DIM as u32);
+
+        let model = EmbeddingModel::new(
+            EmbeddingProvider::OpenAI,
+            Some("text-embedding-3-large"),
+            Some(256),
+        )
+        .unwrap();
+        assert_eq!(model.dimensions, 256);
+        assert_ne!(
+            model,
+            EmbeddingModel::new(EmbeddingProvider::OpenAI, None, Some(256)).unwrap()
+        );
+
+        assert!(EmbeddingModel::new(EmbeddingProvider::OpenAI, None, Some(3072)).is_err());
+        assert!(EmbeddingModel::new(EmbeddingProvider::OpenAI, None, Some(0)).is_err());
+        assert!(EmbeddingModel::new(EmbeddingProvider::OpenAI, Some("ada"), None).is_err());
+
+        let model = EmbeddingModel::new(EmbeddingProvider::Local, None, None).unwrap();
+        assert_eq!(model.dimensions, LOCAL_MODEL.1);
+        assert!(EmbeddingModel::new(EmbeddingProvider::Local, None, Some(128)).is_err());
+    }
+
+    #[test]
+    fn provider_names() {
+        for provider in [
+            EmbeddingProvider::OpenAI,
+            EmbeddingProvider::Local,
+            EmbeddingProvider::Mock,
+        ] {
+            assert_eq!(
+                EmbeddingProvider::from_name(provider.name()),
+                Some(provider)
+            );
+            assert_eq!(EmbeddingProvider::from_id(provider.id()), Some(provider));
+        }
+
+        assert_eq!(
+            EmbeddingProvider::from_name(" Local\n"),
+            Some(EmbeddingProvider::Local)
+        );
+        assert_eq!(EmbeddingProvider::from_name("cohere"), None);
+    }
+
+    #[test]
+    fn configured_providers() {
+        let config = |line: &str| Some(line.split_whitespace().map(|p| p.to_string()).collect());
+
+        assert_eq!(
+            provider_from_config(None).unwrap(),
+            EmbeddingProvider::OpenAI
+        );
+        assert_eq!(
+            provider_from_config(config("local")).unwrap(),
+            EmbeddingProvider::Local
+        );
+        assert_eq!(
+            provider_from_config(config("openai text-embedding-3-large 1024")).unwrap(),
+            EmbeddingProvider::OpenAI
+        );
+        assert!(provider_from_config(config("cohere")).is_err());
+    }
+
+    #[test]
+    fn batched_embeddings_match_single() {
+        let _cleanup = crate::test_common::Cleanup;
+        assert!(crate::test_common::setup().is_ok());
+
+        let dir = chamber_common::get_root_dir().join("batch");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut sources = Vec::new();
+        for i in 0..EMBEDDING_BATCH_SIZE + 3 {
+            let filepath = dir.join(format!("{}.txt", i));
+            std::fs::write(&filepath, format!("message {}", i)).unwrap();
+            sources.push(EmbeddingSource {
+                filepath: filepath.to_string_lossy().to_string(),
+                meta: std::collections::HashSet::new(),
+                subset: None,
+            });
+        }
+
+        sources.push(EmbeddingSource {
+            filepath: dir.join("missing.txt").to_string_lossy().to_string(),
+            meta: std::collections::HashSet::new(),
+            subset: None,
+        });
+
+        let (embeddings, failed) = block_on(embed_batch(&sources));
+        assert_eq!(embeddings.len(), EMBEDDING_BATCH_SIZE + 3);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0.filepath, sources.last().unwrap().filepath);
+
+        for (embedding, source) in embeddings.iter().zip(sources.iter()) {
+            assert_eq!(embedding.source_file.filepath, source.filepath);
+            assert_eq!(embedding.data, block_on(embed(source)).unwrap().data);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use chamber_common::Logger;
+use chamber_common::{error, get_data_dir};
+
+use crate::openai::EmbeddingSource;
+
+// How query results are ranked
+// Similarity is the usual 1 - cosine distance, recency decays exponentially with the age of the
+// embedding, and frequency grows with the number of times the embedding has been retrieved
+//
+// The defaults are pure similarity, i.e., the same as a plain HNSW query
+#[derive(Debug, Clone)]
+pub struct QueryOptions {
+    pub similarity_weight: f32,
+    pub recency_weight: f32,
+    pub frequency_weight: f32,
+    // Age (in days) at which the recency score is halved
+    pub half_life_days: f32,
+    // Whether results keep the range of their file that was embedded (`subset`)
+    // Without it, results only say which file matched
+    pub subsets: bool,
+}
+
+// One of a query's results, best first
+#[derive(Debug, Clone)]
+pub struct QueryResult {
+    pub source: EmbeddingSource,
+    // Cosine distance from the query, 0.0 being identical
+    pub distance: f32,
+    // Position in the results, starting at 0
+    pub rank: usize,
+    // What the results are ordered by--the similarity, unless `QueryOptions` blends in more
+    pub score: f32,
+}
+
+impl QueryResult {
+    pub fn similarity(&self) -> f32 {
+        1.0 - self.distance
+    }
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        Self {
+            similarity_weight: 1.0,
+            recency_weight: 0.0,
+            frequency_weight: 0.0,
+            half_life_days: 30.0,
+            subsets: false,
+        }
+    }
+}
+
+impl QueryOptions {
+    pub fn similarity_only(&self) -> bool {
+        self.recency_weight == 0.0 && self.frequency_weight == 0.0
+    }
+
+    // `now` and `stats.created` are unix timestamps in seconds
+    pub fn score(&self, similarity: f32, stats: Option<&EmbeddingStats>, now: i64) -> f32 {
+        let (recency, frequency) = match stats {
+            Some(stats) => {
+                // Embeddings from before stats were tracked don't have a creation time
+                // They're treated as new rather than penalized
+                let recency = match stats.created {
+                    Some(created) => {
+                        let age_days = (now - created).max(0) as f32 / 86400.0;
+                        (-age_days * std::f32::consts::LN_2 / self.half_life_days.max(f32::EPSILON))
+                            .exp()
+                    }
+                    None => 1.0,
+                };
+
+                // In [0, 1), with diminishing returns on each retrieval
+                let frequency = 1.0 - 1.0 / (1.0 + stats.accesses as f32);
+
+                (recency, frequency)
+            }
+            None => (1.0, 0.0),
+        };
+
+        self.similarity_weight * similarity
+            + self.recency_weight * recency
+            + self.frequency_weight * frequency
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddingStats {
+    pub created: Option<i64>,
+    pub accesses: u64,
+}
+
+// Metadata signals for each embedding, keyed by embedding ID
+//
+// Stored in the data directory as lines of `<id> <created> <accesses>`,
+// with `-` for an unknown creation time
+pub struct StatsStore {
+    entries: HashMap<u64, EmbeddingStats>,
+}
+
+impl StatsStore {
+    pub fn load() -> Result<Self, std::io::Error> {
+        let contents = match std::fs::read_to_string(get_data_dir().join("stats")) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                error!("error reading stats file: {}", e);
+                return Err(e);
+            }
+        };
+
+        let mut entries = HashMap::new();
+        for line in contents.lines().filter(|l| !l.is_empty()) {
+            let parts = line.split(" ").collect::<Vec<&str>>();
+            if parts.len() != 3 {
+                error!("Ignoring malformed stats line: {}", line);
+                continue;
+            }
+
+            let id = match parts[0].parse::<u64>() {
+                Ok(id) => id,
+                Err(_) => {
+                    error!("Ignoring malformed stats line: {}", line);
+                    continue;
+                }
+            };
+
+            entries.insert(
+                id,
+                EmbeddingStats {
+                    created: parts[1].parse::<i64>().ok(),
+                    accesses: parts[2].parse::<u64>().unwrap_or(0),
+                },
+            );
+        }
+
+        Ok(Self { entries })
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        let mut file = std::fs::File::create(get_data_dir().join("stats"))?;
+        for (id, stats) in self.entries.iter() {
+            let created = match stats.created {
+                Some(c) => c.to_string(),
+                None => "-".to_string(),
+            };
+
+            writeln!(file, "{} {} {}", id, created, stats.accesses)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, id: u64) -> Option<&EmbeddingStats> {
+        self.entries.get(&id)
+    }
+
+    pub fn record_creation(&mut self, id: u64) {
+        self.entries.entry(id).or_default().created = Some(chrono::Utc::now().timestamp());
+    }
+
+    pub fn record_access(&mut self, id: u64) {
+        self.entries.entry(id).or_default().accesses += 1;
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.entries.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_options_rank_by_similarity() {
+        let options = QueryOptions::default();
+        let now = 100 * 86400;
+        let old = EmbeddingStats {
+            created: Some(0),
+            accesses: 50,
+        };
+
+        assert!(options.score(0.9, Some(&old), now) > options.score(0.8, None, now));
+    }
+
+    #[test]
+    fn blended_scores_favor_recent_and_frequent() {
+        let options = QueryOptions {
+            similarity_weight: 1.0,
+            recency_weight: 0.5,
+            frequency_weight: 0.5,
+            half_life_days: 30.0,
+            subsets: false,
+        };
+
+        let now = 100 * 86400;
+        let old = EmbeddingStats {
+            created: Some(0),
+            accesses: 0,
+        };
+        let recent = EmbeddingStats {
+            created: Some(now),
+            accesses: 0,
+        };
+        let frequent = EmbeddingStats {
+            created: Some(0),
+            accesses: 20,
+        };
+
+        // Slightly less similar, but much newer
+        assert!(options.score(0.8, Some(&recent), now) > options.score(0.85, Some(&old), now));
+        // Same age, but retrieved often
+        assert!(options.score(0.8, Some(&frequent), now) > options.score(0.85, Some(&old), now));
+
+        // Half life
+        let half = EmbeddingStats {
+            created: Some(now - 30 * 86400),
+            accesses: 0,
+        };
+        let recency_only = QueryOptions {
+            similarity_weight: 0.0,
+            recency_weight: 1.0,
+            frequency_weight: 0.0,
+            half_life_days: 30.0,
+            subsets: false,
+        };
+        assert!((recency_only.score(0.0, Some(&half), now) - 0.5).abs() < 1e-4);
+    }
+}
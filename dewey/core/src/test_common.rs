@@ -30,10 +30,20 @@ macro_rules! write_file {
     };
 }
 
+// Every test shares the same workspace directory, so they can't overlap
+// `setup` takes the lock and `Cleanup` releases it
+static TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+thread_local! {
+    static TEST_GUARD: std::cell::RefCell<Option<std::sync::MutexGuard<'static, ()>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
 pub struct Cleanup;
 impl Drop for Cleanup {
     fn drop(&mut self) {
-        std::fs::remove_dir_all(chamber_common::get_root_dir()).unwrap()
+        let _ = std::fs::remove_dir_all(chamber_common::get_root_dir());
+        TEST_GUARD.with(|guard| guard.borrow_mut().take());
     }
 }
 
@@ -72,8 +82,28 @@ macro_rules! test_print {
 }
 
 pub fn setup() -> Result<(), std::io::Error> {
+    TEST_GUARD.with(|guard| {
+        if guard.borrow().is_none() {
+            *guard.borrow_mut() = Some(TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner()));
+        }
+    });
+
     test_print!("===BEGIN SETUP===");
     chamber_common::Workspace::new("/tmp/dewey_testing");
+
+    // Dewey logs through the client's logger, so tests need one too
+    // The logger's panic hook is dropped so test failures still print
+    chamber_common::Logger::init(
+        std::env::temp_dir()
+            .join("dewey_testing.log")
+            .to_str()
+            .unwrap(),
+    );
+    let _ = std::panic::take_hook();
+
+    // No API calls in tests
+    crate::set_embedding_provider(crate::EmbeddingProvider::Mock);
+
     crate::config::setup();
     let root = chamber_common::get_root_dir();
     let config = chamber_common::get_config_dir();
@@ -0,0 +1,655 @@
+use std::io::{BufRead, Read, Write};
+
+// A stand-in LLM provider for development and demos--it speaks the OpenAI and Anthropic APIs,
+// streamed or not, with no model (or API credits) behind it
+//
+// - OpenAI chat completions are served at `/v1/chat/completions`,
+//   so William's `local` provider can be pointed here with
+//   `WILLIAM_LOCAL_ENDPOINT=http://127.0.0.1:8089/v1`
+// - Anthropic messages are served at `/v1/messages`, for anything with a custom Anthropic base URL
+// - `/v1/models` lists `mock-model`, plus every model a request has asked for
+//
+// Everything's configured from the environment:
+// - `MOCKLLM_PORT`: 8089 by default
+// - `MOCKLLM_LATENCY_MS`: how long to wait before responding, 0 by default
+// - `MOCKLLM_TOKENS_PER_SECOND`: how fast streams go, 20 by default--0 for as fast as possible
+// - `MOCKLLM_RESPONSE`: what every response says--by default, the last user message is echoed back
+// - `MOCKLLM_ERROR_RATE`: the fraction of requests (0 to 1) that fail outright
+//   with `MOCKLLM_ERROR_STATUS` (500 by default--429s come with a `retry-after`)
+// - `MOCKLLM_STREAM_ERROR_RATE`: the fraction of streams that send an error event partway through
+
+const DEFAULT_MODEL: &str = "mock-model";
+
+#[derive(Clone, Debug)]
+struct Config {
+    port: u16,
+    latency: std::time::Duration,
+    tokens_per_second: u64,
+    response: Option<String>,
+    error_rate: f64,
+    error_status: u16,
+    stream_error_rate: f64,
+}
+
+fn env_or<T: std::str::FromStr>(var: &str, default: T) -> T {
+    match std::env::var(var) {
+        Ok(v) => match v.trim().parse::<T>() {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                eprintln!("Ignoring invalid {}: {}", var, v);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+impl Config {
+    fn from_env() -> Self {
+        Self {
+            port: env_or("MOCKLLM_PORT", 8089),
+            latency: std::time::Duration::from_millis(env_or("MOCKLLM_LATENCY_MS", 0)),
+            tokens_per_second: env_or("MOCKLLM_TOKENS_PER_SECOND", 20),
+            response: std::env::var("MOCKLLM_RESPONSE").ok(),
+            error_rate: env_or("MOCKLLM_ERROR_RATE", 0.0),
+            error_status: env_or("MOCKLLM_ERROR_STATUS", 500),
+            stream_error_rate: env_or("MOCKLLM_STREAM_ERROR_RATE", 0.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    OpenAI,
+    Anthropic,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: serde_json::Value,
+}
+
+// xorshift, seeded from the clock--the errors only need to look random
+static RNG: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+fn random() -> f64 {
+    let mut x = RNG.load(std::sync::atomic::Ordering::Relaxed);
+    if x == 0 {
+        x = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(1, |d| d.as_nanos() as u64)
+            | 1;
+    }
+
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    RNG.store(x, std::sync::atomic::Ordering::Relaxed);
+
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn chance(rate: f64) -> bool {
+    rate > 0.0 && random() < rate
+}
+
+fn read_request(stream: &std::net::TcpStream) -> std::io::Result<Request> {
+    let mut reader = std::io::BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Request {
+        method,
+        path,
+        body: serde_json::from_slice(&body).unwrap_or_default(),
+    })
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        529 => "Overloaded",
+        _ => "Error",
+    }
+}
+
+fn write_response(
+    stream: &mut std::net::TcpStream,
+    status: u16,
+    body: &serde_json::Value,
+) -> std::io::Result<()> {
+    let body = body.to_string();
+    let retry_after = if status == 429 {
+        "retry-after: 1\r\n"
+    } else {
+        ""
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        retry_after,
+        body
+    )
+}
+
+// Without a length, the stream runs until the connection closes
+fn write_stream_head(stream: &mut std::net::TcpStream) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    )?;
+    stream.flush()
+}
+
+fn write_event(
+    stream: &mut std::net::TcpStream,
+    event: Option<&str>,
+    data: &str,
+) -> std::io::Result<()> {
+    if let Some(event) = event {
+        writeln!(stream, "event: {}", event)?;
+    }
+
+    write!(stream, "data: {}\n\n", data)?;
+    stream.flush()
+}
+
+// The text of the last user message, whether its content is a string or a list of parts
+fn last_user_message(body: &serde_json::Value) -> String {
+    let message = body["messages"]
+        .as_array()
+        .and_then(|messages| messages.iter().rev().find(|m| m["role"] == "user"));
+
+    let content = match message {
+        Some(m) => &m["content"],
+        None => return String::new(),
+    };
+
+    match content {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Array(parts) => parts
+            .iter()
+            .filter_map(|p| p["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+fn response_text(config: &Config, body: &serde_json::Value) -> String {
+    if let Some(response) = config.response.as_ref() {
+        return response.clone();
+    }
+
+    match last_user_message(body) {
+        m if m.trim().is_empty() => "Hello from mockllm!".to_string(),
+        m => format!("You said: {}", m),
+    }
+}
+
+// Roughly how a tokenizer would split it--words, each with the whitespace after it
+fn tokens(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    for c in text.chars() {
+        if !c.is_whitespace() && current.ends_with(char::is_whitespace) {
+            tokens.push(std::mem::take(&mut current));
+        }
+
+        current.push(c);
+    }
+
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    tokens
+}
+
+// ~4 characters a token--close enough for usage numbers
+fn prompt_tokens(body: &serde_json::Value) -> usize {
+    (body["messages"].to_string().len() + body["system"].to_string().len()) / 4
+}
+
+fn error_body(format: Format, status: u16, message: &str) -> serde_json::Value {
+    match format {
+        Format::OpenAI => serde_json::json!({
+            "error": {
+                "message": message,
+                "type": if status == 429 { "rate_limit_exceeded" } else { "server_error" },
+                "code": null,
+            }
+        }),
+        Format::Anthropic => serde_json::json!({
+            "type": "error",
+            "error": {
+                "type": match status {
+                    429 => "rate_limit_error",
+                    529 => "overloaded_error",
+                    _ => "api_error",
+                },
+                "message": message,
+            }
+        }),
+    }
+}
+
+fn model(body: &serde_json::Value) -> String {
+    body["model"].as_str().unwrap_or(DEFAULT_MODEL).to_string()
+}
+
+fn complete(
+    stream: &mut std::net::TcpStream,
+    config: &Config,
+    format: Format,
+    request: &Request,
+    id: &str,
+) -> std::io::Result<()> {
+    let body = &request.body;
+    let text = response_text(config, body);
+    let input_tokens = prompt_tokens(body);
+    let output_tokens = tokens(&text).len();
+
+    let response = match format {
+        Format::OpenAI => serde_json::json!({
+            "id": id,
+            "object": "chat.completion",
+            "model": model(body),
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": text },
+                "finish_reason": "stop",
+            }],
+            "usage": {
+                "prompt_tokens": input_tokens,
+                "completion_tokens": output_tokens,
+                "total_tokens": input_tokens + output_tokens,
+            },
+        }),
+        Format::Anthropic => serde_json::json!({
+            "id": id,
+            "type": "message",
+            "role": "assistant",
+            "model": model(body),
+            "content": [{ "type": "text", "text": text }],
+            "stop_reason": "end_turn",
+            "stop_sequence": null,
+            "usage": { "input_tokens": input_tokens, "output_tokens": output_tokens },
+        }),
+    };
+
+    write_response(stream, 200, &response)
+}
+
+fn openai_chunk(
+    id: &str,
+    model: &str,
+    delta: serde_json::Value,
+    finish_reason: Option<&str>,
+) -> String {
+    serde_json::json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "model": model,
+        "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+    })
+    .to_string()
+}
+
+fn stream_completion(
+    stream: &mut std::net::TcpStream,
+    config: &Config,
+    format: Format,
+    request: &Request,
+    id: &str,
+) -> std::io::Result<()> {
+    let body = &request.body;
+    let model = model(body);
+    let text = tokens(&response_text(config, body));
+    let input_tokens = prompt_tokens(body);
+
+    // Somewhere in the middle, so there's a partial response to deal with
+    let fail_at = if chance(config.stream_error_rate) {
+        Some(text.len() / 2)
+    } else {
+        None
+    };
+
+    let delay = match config.tokens_per_second {
+        0 => std::time::Duration::ZERO,
+        rate => std::time::Duration::from_millis(1000 / rate),
+    };
+
+    write_stream_head(stream)?;
+
+    match format {
+        Format::OpenAI => {
+            write_event(
+                stream,
+                None,
+                &openai_chunk(
+                    id,
+                    &model,
+                    serde_json::json!({ "role": "assistant", "content": "" }),
+                    None,
+                ),
+            )?;
+        }
+        Format::Anthropic => {
+            write_event(
+                stream,
+                Some("message_start"),
+                &serde_json::json!({
+                    "type": "message_start",
+                    "message": {
+                        "id": id,
+                        "type": "message",
+                        "role": "assistant",
+                        "model": model,
+                        "content": [],
+                        "stop_reason": null,
+                        "usage": { "input_tokens": input_tokens, "output_tokens": 1 },
+                    },
+                })
+                .to_string(),
+            )?;
+            write_event(
+                stream,
+                Some("content_block_start"),
+                &serde_json::json!({
+                    "type": "content_block_start",
+                    "index": 0,
+                    "content_block": { "type": "text", "text": "" },
+                })
+                .to_string(),
+            )?;
+        }
+    }
+
+    for (i, token) in text.iter().enumerate() {
+        if fail_at == Some(i) {
+            let error = error_body(format, 500, "mockllm: injected stream error").to_string();
+            let event = match format {
+                Format::OpenAI => None,
+                Format::Anthropic => Some("error"),
+            };
+
+            return write_event(stream, event, &error);
+        }
+
+        std::thread::sleep(delay);
+        match format {
+            Format::OpenAI => write_event(
+                stream,
+                None,
+                &openai_chunk(id, &model, serde_json::json!({ "content": token }), None),
+            )?,
+            Format::Anthropic => write_event(
+                stream,
+                Some("content_block_delta"),
+                &serde_json::json!({
+                    "type": "content_block_delta",
+                    "index": 0,
+                    "delta": { "type": "text_delta", "text": token },
+                })
+                .to_string(),
+            )?,
+        }
+    }
+
+    match format {
+        Format::OpenAI => {
+            write_event(
+                stream,
+                None,
+                &openai_chunk(id, &model, serde_json::json!({}), Some("stop")),
+            )?;
+
+            // Usage only comes at the end if it's asked for, like the real thing
+            if body["stream_options"]["include_usage"] == true {
+                write_event(
+                    stream,
+                    None,
+                    &serde_json::json!({
+                        "id": id,
+                        "object": "chat.completion.chunk",
+                        "model": model,
+                        "choices": [],
+                        "usage": {
+                            "prompt_tokens": input_tokens,
+                            "completion_tokens": text.len(),
+                            "total_tokens": input_tokens + text.len(),
+                        },
+                    })
+                    .to_string(),
+                )?;
+            }
+
+            write_event(stream, None, "[DONE]")
+        }
+        Format::Anthropic => {
+            write_event(
+                stream,
+                Some("content_block_stop"),
+                &serde_json::json!({ "type": "content_block_stop", "index": 0 }).to_string(),
+            )?;
+            write_event(
+                stream,
+                Some("message_delta"),
+                &serde_json::json!({
+                    "type": "message_delta",
+                    "delta": { "stop_reason": "end_turn", "stop_sequence": null },
+                    "usage": { "output_tokens": text.len() },
+                })
+                .to_string(),
+            )?;
+            write_event(
+                stream,
+                Some("message_stop"),
+                &serde_json::json!({ "type": "message_stop" }).to_string(),
+            )
+        }
+    }
+}
+
+// Models that have been asked for, so a model picker fed from `/v1/models` shows them
+static MODELS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+fn models() -> serde_json::Value {
+    let mut models = vec![DEFAULT_MODEL.to_string()];
+    for m in MODELS.lock().unwrap_or_else(|p| p.into_inner()).iter() {
+        if !models.contains(m) {
+            models.push(m.clone());
+        }
+    }
+
+    // Both providers' listings, in one
+    serde_json::json!({
+        "object": "list",
+        "data": models
+            .iter()
+            .map(|m| serde_json::json!({
+                "id": m,
+                "object": "model",
+                "type": "model",
+                "owned_by": "mockllm",
+                "display_name": m,
+            }))
+            .collect::<Vec<_>>(),
+        "has_more": false,
+    })
+}
+
+fn handle(mut stream: std::net::TcpStream, config: &Config, id: &str) -> std::io::Result<()> {
+    let request = read_request(&stream)?;
+    let path = request.path.split('?').next().unwrap_or_default();
+
+    let format = if request.method == "POST" && path.ends_with("/chat/completions") {
+        Format::OpenAI
+    } else if request.method == "POST" && path.ends_with("/messages") {
+        Format::Anthropic
+    } else if request.method == "GET" && path.ends_with("/models") {
+        println!("{} {} -> 200", request.method, request.path);
+        return write_response(&mut stream, 200, &models());
+    } else {
+        println!("{} {} -> 404", request.method, request.path);
+        return write_response(
+            &mut stream,
+            404,
+            &serde_json::json!({ "error": { "message": "Not found", "type": "not_found" } }),
+        );
+    };
+
+    {
+        let mut models = MODELS.lock().unwrap_or_else(|p| p.into_inner());
+        let model = model(&request.body);
+        if !models.contains(&model) {
+            models.push(model);
+        }
+    }
+
+    std::thread::sleep(config.latency);
+
+    if chance(config.error_rate) {
+        println!(
+            "{} {} -> {} (injected)",
+            request.method, request.path, config.error_status
+        );
+        return write_response(
+            &mut stream,
+            config.error_status,
+            &error_body(format, config.error_status, "mockllm: injected error"),
+        );
+    }
+
+    let streamed = request.body["stream"] == true;
+    println!(
+        "{} {} -> 200 ({:?}, {})",
+        request.method,
+        request.path,
+        format,
+        if streamed { "streamed" } else { "not streamed" }
+    );
+
+    if streamed {
+        stream_completion(&mut stream, config, format, &request, id)
+    } else {
+        complete(&mut stream, config, format, &request, id)
+    }
+}
+
+fn main() {
+    let config = Config::from_env();
+    let listener = match std::net::TcpListener::bind(("127.0.0.1", config.port)) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Error binding to port {}: {}", config.port, e);
+            std::process::exit(1);
+        }
+    };
+
+    println!("mockllm listening on http://127.0.0.1:{}", config.port);
+    println!("{:?}", config);
+
+    for (i, stream) in listener.incoming().enumerate() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
+
+        let config = config.clone();
+        std::thread::spawn(move || {
+            // Clients hanging up partway through a stream are expected
+            if let Err(e) = handle(stream, &config, &format!("mock-{}", i)) {
+                eprintln!("Connection closed: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(
+            tokens("Hello there,  world\n!"),
+            vec!["Hello ", "there,  ", "world\n", "!"]
+        );
+        assert_eq!(tokens("Hello there").concat(), "Hello there");
+        assert!(tokens("").is_empty());
+    }
+
+    #[test]
+    fn test_last_user_message() {
+        let body = serde_json::json!({
+            "messages": [
+                { "role": "user", "content": "first" },
+                { "role": "assistant", "content": "reply" },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "second" },
+                    { "type": "image_url", "image_url": { "url": "data:..." } },
+                    { "type": "text", "text": "part" },
+                ] },
+            ]
+        });
+        assert_eq!(last_user_message(&body), "second\npart");
+        assert_eq!(last_user_message(&serde_json::json!({})), "");
+    }
+
+    #[test]
+    fn test_chance() {
+        assert!((0..100).all(|_| !chance(0.0)));
+        assert!((0..100).all(|_| chance(1.0)));
+        assert!((0..100).all(|_| (0.0..1.0).contains(&random())));
+    }
+
+    #[test]
+    fn test_error_bodies() {
+        assert_eq!(
+            error_body(Format::Anthropic, 529, "busy")["error"]["type"],
+            "overloaded_error"
+        );
+        assert_eq!(
+            error_body(Format::OpenAI, 429, "slow down")["error"]["type"],
+            "rate_limit_exceeded"
+        );
+    }
+}
@@ -0,0 +1,499 @@
+use base64::Engine;
+use rusqlite::params;
+
+use chamber_common::{lprint, Logger};
+
+use crate::types::*;
+
+// Attachments are stored once per distinct content, at `<dir>/<first 2 hex chars>/<sha256>`
+// Messages reference them by hash, with `attachments.ref_count` tracking how many do
+// so deleting a conversation only removes the blobs nothing else is using
+
+pub fn hash(data: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn blob_path(dir: &std::path::Path, hash: &str) -> std::path::PathBuf {
+    dir.join(&hash[..2]).join(hash)
+}
+
+// Writes `data` under its hash, unless it's already there
+// Returns the hash
+pub fn write_blob(dir: &std::path::Path, data: &[u8]) -> Result<String, std::io::Error> {
+    let hash = hash(data);
+    let path = blob_path(dir, &hash);
+    if path.exists() {
+        return Ok(hash);
+    }
+
+    std::fs::create_dir_all(path.parent().unwrap())?;
+
+    // Written to the side first so a crash can't leave a partial blob under a valid hash
+    let partial = path.with_extension("partial");
+    std::fs::write(&partial, data)?;
+    std::fs::rename(&partial, &path)?;
+
+    Ok(hash)
+}
+
+// Stores an upload from the frontend
+// Nothing references it until a message containing it is saved
+pub fn store(
+    dir: &std::path::Path,
+    upload: &AttachmentUpload,
+    db: &rusqlite::Connection,
+) -> Result<Attachment, std::io::Error> {
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(&upload.data)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    store_bytes(dir, &upload.name, &upload.mime_type, &data, db)
+}
+
+fn store_bytes(
+    dir: &std::path::Path,
+    name: &str,
+    mime_type: &str,
+    data: &[u8],
+    db: &rusqlite::Connection,
+) -> Result<Attachment, std::io::Error> {
+    let hash = write_blob(dir, data)?;
+    db.execute(
+        "INSERT OR IGNORE INTO attachments (hash, size, mime_type, ref_count, date_created)
+         VALUES (?1, ?2, ?3, 0, CURRENT_TIMESTAMP)",
+        params![hash, data.len() as i64, mime_type],
+    )
+    .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    lprint!(
+        info,
+        "Stored attachment {} ({}, {} bytes)",
+        hash,
+        name,
+        data.len()
+    );
+
+    Ok(Attachment {
+        hash,
+        name: name.to_string(),
+        mime_type: mime_type.to_string(),
+        size: data.len() as u64,
+        data: None,
+        path: None,
+    })
+}
+
+// Best guess from the extension, for files attached by path
+pub fn guess_mime_type(path: &std::path::Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "pdf" => "application/pdf",
+        e if crate::extract::TEXT_EXTENSIONS.contains(&e) => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+// Attachments can also come in with a message directly, as base64 `data` or a `path` on this machine
+// These are stored like any other upload, leaving the message with just their hashes
+pub fn store_inline(
+    dir: &std::path::Path,
+    message: &mut Message,
+    db: &rusqlite::Connection,
+) -> Result<(), std::io::Error> {
+    for attachment in message.attachments.iter_mut() {
+        if !attachment.hash.is_empty() {
+            continue;
+        }
+
+        let stored = match (&attachment.data, &attachment.path) {
+            (Some(data), _) => store(
+                dir,
+                &AttachmentUpload {
+                    name: attachment.name.clone(),
+                    mime_type: attachment.mime_type.clone(),
+                    data: data.clone(),
+                },
+                db,
+            )?,
+            (None, Some(path)) => {
+                let path = std::path::Path::new(path);
+                let data = std::fs::read(path)?;
+                let name = if attachment.name.is_empty() {
+                    path.file_name()
+                        .map(|n| n.to_string_lossy().to_string())
+                        .unwrap_or_default()
+                } else {
+                    attachment.name.clone()
+                };
+                let mime_type = if attachment.mime_type.is_empty() {
+                    guess_mime_type(path).to_string()
+                } else {
+                    attachment.mime_type.clone()
+                };
+
+                store_bytes(dir, &name, &mime_type, &data, db)?
+            }
+            (None, None) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Attachment {} has no hash, data, or path", attachment.name),
+                ));
+            }
+        };
+
+        *attachment = stored;
+    }
+
+    Ok(())
+}
+
+// Characters kept from each end of a message whose text is moved into an attachment
+const OFFLOADED_EXCERPT_CHARS: usize = 1000;
+
+// The beginning and end of `text`, `keep` characters each, with a note in place of the middle
+fn excerpt(text: &str, keep: usize, name: &str) -> String {
+    let chars = text.chars().collect::<Vec<char>>();
+    if chars.len() <= keep * 2 {
+        return text.to_string();
+    }
+
+    format!(
+        "{}\n\n[... {} characters cut--the full text is attached as {} ...]\n\n{}",
+        chars[..keep].iter().collect::<String>(),
+        chars.len() - keep * 2,
+        name,
+        chars[chars.len() - keep..].iter().collect::<String>()
+    )
+}
+
+// Messages longer than `max_chars` have their text stored as a text attachment,
+// leaving only an excerpt in the message itself
+// The rest reaches the model like any other document--its chunks closest to the message, as references
+//
+// Returns whether the message was cut down
+pub fn offload_content(
+    dir: &std::path::Path,
+    message: &mut Message,
+    max_chars: usize,
+    db: &rusqlite::Connection,
+) -> Result<bool, std::io::Error> {
+    if max_chars == 0 || message.content.chars().count() <= max_chars {
+        return Ok(false);
+    }
+
+    let name = format!("message-{}.txt", &hash(message.content.as_bytes())[..8]);
+    let attachment = store_bytes(dir, &name, "text/plain", message.content.as_bytes(), db)?;
+
+    message.content = excerpt(
+        &message.content,
+        std::cmp::min(OFFLOADED_EXCERPT_CHARS, max_chars / 2),
+        &name,
+    );
+    if !message
+        .attachments
+        .iter()
+        .any(|a| a.hash == attachment.hash)
+    {
+        message.attachments.push(attachment);
+    }
+
+    Ok(true)
+}
+
+// Reads in the contents of every image attachment, for sending to the provider
+// Images whose blobs have gone missing are left out
+pub fn load_images(dir: &std::path::Path, messages: &mut [Message]) {
+    for attachment in messages
+        .iter_mut()
+        .flat_map(|m| m.attachments.iter_mut())
+        .filter(|a| a.is_image() && a.data.is_none())
+    {
+        match std::fs::read(blob_path(dir, &attachment.hash)) {
+            Ok(data) => {
+                attachment.data = Some(base64::engine::general_purpose::STANDARD.encode(data));
+            }
+            Err(e) => {
+                lprint!(
+                    error,
+                    "Error reading attachment {}: {}; leaving it out",
+                    attachment.hash,
+                    e
+                );
+            }
+        };
+    }
+}
+
+// Brings `message_attachments` in line with `message.attachments`,
+// adjusting reference counts for whatever was added or dropped
+pub fn sync_references(message: &Message, db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let message_id = match message.id {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+
+    let existing = {
+        let mut query = db.prepare("SELECT hash FROM message_attachments WHERE message_id = ?1")?;
+        let existing = query
+            .query_map(params![message_id], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        existing
+    };
+
+    for attachment in message.attachments.iter() {
+        if existing.contains(&attachment.hash) {
+            continue;
+        }
+
+        // Only attachments that were actually uploaded get referenced
+        let added = db.execute(
+            "INSERT INTO message_attachments (message_id, hash, name)
+             SELECT ?1, hash, ?3 FROM attachments WHERE hash = ?2",
+            params![message_id, attachment.hash, attachment.name],
+        )?;
+
+        if added == 0 {
+            lprint!(
+                error,
+                "Message {} references unknown attachment {}; ignoring",
+                message_id,
+                attachment.hash
+            );
+            continue;
+        }
+
+        db.execute(
+            "UPDATE attachments SET ref_count = ref_count + 1 WHERE hash = ?1",
+            params![attachment.hash],
+        )?;
+    }
+
+    for hash in existing
+        .iter()
+        .filter(|h| !message.attachments.iter().any(|a| &a.hash == *h))
+    {
+        db.execute(
+            "DELETE FROM message_attachments WHERE message_id = ?1 AND hash = ?2",
+            params![message_id, hash],
+        )?;
+        db.execute(
+            "UPDATE attachments SET ref_count = ref_count - 1 WHERE hash = ?1",
+            params![hash],
+        )?;
+    }
+
+    Ok(())
+}
+
+// Attachments of every message in a conversation, by message ID
+pub fn get_attachments(
+    conversation_id: i64,
+    db: &rusqlite::Connection,
+) -> rusqlite::Result<std::collections::HashMap<i64, Vec<Attachment>>> {
+    let mut query = db.prepare(
+        "
+        SELECT DISTINCT ma.message_id, a.hash, ma.name, a.mime_type, a.size, ma.id
+        FROM message_attachments ma
+        JOIN attachments a ON a.hash = ma.hash
+        JOIN paths l ON l.message_id = ma.message_id
+        WHERE l.conversation_id = ?1
+        ORDER BY ma.id
+        ",
+    )?;
+
+    let mut attachments: std::collections::HashMap<i64, Vec<Attachment>> =
+        std::collections::HashMap::new();
+    for row in query.query_map(params![conversation_id], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            Attachment {
+                hash: row.get(1)?,
+                name: row.get(2)?,
+                mime_type: row.get(3)?,
+                size: row.get::<_, i64>(4)? as u64,
+                data: None,
+                path: None,
+            },
+        ))
+    })? {
+        let (message_id, attachment) = row?;
+        attachments.entry(message_id).or_default().push(attachment);
+    }
+
+    Ok(attachments)
+}
+
+// Drops references held by messages that are about to be deleted
+// Expects to be run inside the deleting transaction
+pub fn release_orphaned(db: &rusqlite::Connection) -> rusqlite::Result<usize> {
+    db.execute(
+        "
+        UPDATE attachments
+        SET ref_count = ref_count - (
+            SELECT COUNT(*)
+            FROM message_attachments ma
+            WHERE ma.hash = attachments.hash
+            AND ma.message_id NOT IN (SELECT message_id FROM paths)
+            AND ma.message_id NOT IN (SELECT message_id FROM snapshot_messages)
+        )
+        ",
+        params![],
+    )?;
+
+    db.execute(
+        "DELETE FROM message_attachments
+         WHERE message_id NOT IN (SELECT message_id FROM paths)
+         AND message_id NOT IN (SELECT message_id FROM snapshot_messages)",
+        params![],
+    )
+}
+
+// Deletes the rows of attachments nothing references anymore, returning their hashes
+// Fresh uploads get a day to be sent before they count as abandoned
+pub fn delete_unreferenced(db: &rusqlite::Connection) -> rusqlite::Result<Vec<String>> {
+    let hashes = {
+        let mut query = db.prepare(
+            "SELECT hash FROM attachments WHERE ref_count <= 0 AND date_created <= datetime('now', '-1 day')",
+        )?;
+        let hashes = query
+            .query_map(params![], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        hashes
+    };
+
+    for hash in hashes.iter() {
+        db.execute("DELETE FROM document_chunks WHERE hash = ?1", params![hash])?;
+        db.execute("DELETE FROM attachments WHERE hash = ?1", params![hash])?;
+    }
+
+    Ok(hashes)
+}
+
+pub fn remove_blobs(dir: &std::path::Path, hashes: &[String]) {
+    for hash in hashes.iter() {
+        match std::fs::remove_file(blob_path(dir, hash)) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                lprint!(error, "Error removing attachment {}: {}; ignoring", hash, e);
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_blob() {
+        let dir = std::env::temp_dir().join(format!("william-attachments-{}", std::process::id()));
+
+        let first = write_blob(&dir, b"screenshot").unwrap();
+        let second = write_blob(&dir, b"screenshot").unwrap();
+        let other = write_blob(&dir, b"another screenshot").unwrap();
+
+        // The same content lands in the same place
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        assert_eq!(
+            hash(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(blob_path(&dir, &first).ends_with(format!("{}/{}", &first[..2], first)));
+        assert_eq!(
+            std::fs::read(blob_path(&dir, &first)).unwrap(),
+            b"screenshot"
+        );
+
+        // Images are read back as base64 for the provider
+        let mut messages = vec![Message {
+            attachments: vec![Attachment {
+                hash: first.clone(),
+                name: "screenshot.png".to_string(),
+                mime_type: guess_mime_type(std::path::Path::new("screenshot.PNG")).to_string(),
+                size: 10,
+                data: None,
+                path: None,
+            }],
+            ..Message::test(
+                MessageType::User,
+                "What's in this?",
+                API::Local("llava".to_string()),
+            )
+        }];
+        load_images(&dir, &mut messages);
+        assert_eq!(
+            messages[0].attachments[0].data.as_deref(),
+            Some(
+                base64::engine::general_purpose::STANDARD
+                    .encode(b"screenshot")
+                    .as_str()
+            )
+        );
+
+        remove_blobs(&dir, &[first.clone(), other]);
+        assert!(!blob_path(&dir, &first).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_offload_content() {
+        crate::init_test_logger();
+
+        let dir = std::env::temp_dir().join(format!("william-offload-{}", std::process::id()));
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE TABLE attachments (
+                hash TEXT PRIMARY KEY,
+                size INTEGER NOT NULL,
+                mime_type TEXT NOT NULL,
+                ref_count INTEGER NOT NULL DEFAULT 0,
+                date_created TIMESTAMP NOT NULL
+            );",
+        )
+        .unwrap();
+
+        let pasted = format!("Why does this fail?\n{}\nThanks", "log line\n".repeat(500));
+        let mut message = Message::test(
+            MessageType::User,
+            &pasted,
+            API::Local("llama3.2".to_string()),
+        );
+
+        // Under the limit, or without one, nothing changes
+        assert!(!offload_content(&dir, &mut message, 0, &db).unwrap());
+        assert!(!offload_content(&dir, &mut message, pasted.len(), &db).unwrap());
+        assert_eq!(message.content, pasted);
+
+        assert!(offload_content(&dir, &mut message, 100, &db).unwrap());
+        assert!(message.content.starts_with("Why does this fail?"));
+        assert!(message.content.ends_with("Thanks"));
+        assert!(message.content.chars().count() < 200);
+
+        let attachment = &message.attachments[0];
+        assert_eq!(attachment.mime_type, "text/plain");
+        assert_eq!(attachment.size, pasted.len() as u64);
+        assert_eq!(
+            std::fs::read_to_string(blob_path(&dir, &attachment.hash)).unwrap(),
+            pasted
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
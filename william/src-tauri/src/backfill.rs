@@ -0,0 +1,131 @@
+use rusqlite::params;
+
+use crate::types::*;
+
+// Embeddings for messages that never got them--e.g., ones sent while Dewey was unavailable,
+// or before there was an OpenAI key to embed them with
+//
+// Only messages are backfilled--exchanges (see `conversation_chunks` in lib.rs) aren't,
+// and neither are messages whose memories were forgotten (see memory.rs)
+// A pass runs at start up, and again whenever `ReindexMemory` asks for one
+// There's only ever one pass running, and its progress is kept here for `ReindexMemory`
+
+static PROGRESS: std::sync::OnceLock<std::sync::Mutex<Option<BackfillProgress>>> =
+    std::sync::OnceLock::new();
+
+fn progress_lock() -> std::sync::MutexGuard<'static, Option<BackfillProgress>> {
+    PROGRESS
+        .get_or_init(|| std::sync::Mutex::new(None))
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Whether the caller gets to run a pass--false if one's already running
+pub fn start() -> bool {
+    let mut progress = progress_lock();
+    if progress.as_ref().is_some_and(|p| p.running) {
+        return false;
+    }
+
+    *progress = Some(BackfillProgress {
+        running: true,
+        total: 0,
+        embedded: 0,
+        failed: 0,
+    });
+
+    true
+}
+
+pub fn set_total(total: usize) {
+    if let Some(p) = progress_lock().as_mut() {
+        p.total = total;
+    }
+}
+
+pub fn advance(embedded: usize, failed: usize) {
+    if let Some(p) = progress_lock().as_mut() {
+        p.embedded += embedded;
+        p.failed += failed;
+    }
+}
+
+// The finished pass's numbers stick around until the next one starts
+pub fn finish() {
+    if let Some(p) = progress_lock().as_mut() {
+        p.running = false;
+    }
+}
+
+// The running pass, or the last one--`None` if there hasn't been one
+pub fn progress() -> Option<BackfillProgress> {
+    progress_lock().clone()
+}
+
+// User and assistant messages in live conversations that haven't been embedded, oldest first
+pub fn unembedded(db: &rusqlite::Connection) -> rusqlite::Result<Vec<i64>> {
+    let mut query = db.prepare(
+        "
+        SELECT m.id
+        FROM messages m
+        JOIN message_types mt ON mt.id = m.message_type_id
+        WHERE mt.name IN ('user', 'assistant')
+        AND m.content != ''
+        AND NOT EXISTS (
+            SELECT 1 FROM message_embeddings me WHERE me.message_id = m.id AND me.kind = 'message'
+        )
+        AND NOT EXISTS (SELECT 1 FROM forgotten_messages f WHERE f.message_id = m.id)
+        AND EXISTS (
+            SELECT 1
+            FROM paths l
+            JOIN conversations c ON c.id = l.conversation_id
+            WHERE l.message_id = m.id
+            AND c.deleted_at IS NULL
+        )
+        ORDER BY m.id
+        ",
+    )?;
+    let ids = query
+        .query_map(params![], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+    Ok(ids)
+}
+
+pub fn content(message_id: i64, db: &rusqlite::Connection) -> rusqlite::Result<String> {
+    db.query_row(
+        "SELECT content FROM messages WHERE id = ?1",
+        params![message_id],
+        |row| row.get(0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        assert!(start());
+        // Only one pass at a time
+        assert!(!start());
+
+        set_total(5);
+        advance(3, 1);
+        advance(1, 0);
+
+        let p = progress().unwrap();
+        assert!(p.running);
+        assert_eq!((p.total, p.embedded, p.failed), (5, 4, 1));
+
+        finish();
+        let p = progress().unwrap();
+        assert!(!p.running);
+        assert_eq!(p.embedded, 4);
+
+        // A new pass starts from scratch
+        assert!(start());
+        assert_eq!(progress().unwrap().embedded, 0);
+        finish();
+    }
+}
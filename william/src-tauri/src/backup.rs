@@ -0,0 +1,475 @@
+use std::io::{Read, Write};
+
+use crate::migrations;
+
+// Backups are a tar of the whole state directory--the database, Dewey's index and ledger,
+// and the files both of them point at--with a manifest of every file's size and SHA-256
+//
+// The database is copied with `VACUUM INTO` rather than read off disk,
+// so the archive never catches it halfway through a write
+// Older backups (the `backups` directory) aren't included
+//
+// Restoring can't swap anything out from under the running app, so it happens in two steps:
+// the archive is unpacked and checked into a staging directory, and the next start up
+// moves the current state directory aside and puts the staged one in its place
+
+pub const ARCHIVE_VERSION: u32 = 1;
+pub const MANIFEST_NAME: &str = "manifest.json";
+pub const DATABASE_NAME: &str = "william.sqlite";
+
+// Directories under the state directory left out of backups
+const EXCLUDED_DIRS: &[&str] = &["backups"];
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ManifestFile {
+    // Relative to the state directory, with `/` separators
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: usize,
+    // Seconds since the epoch
+    pub created: u64,
+    pub files: Vec<ManifestFile>,
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Default filename when the backup path is a directory
+pub fn filename() -> String {
+    format!("william-backup-{}.tar", now())
+}
+
+// Hashes whatever's read through it
+struct HashingReader<R> {
+    inner: R,
+    context: ring::digest::Context,
+    size: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            context: ring::digest::Context::new(&ring::digest::SHA256),
+            size: 0,
+        }
+    }
+
+    fn finish(self) -> (u64, String) {
+        let digest = self
+            .context
+            .finish()
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        (self.size, digest)
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.context.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
+fn relative_name(path: &std::path::Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Every file under `dir` that goes in a backup, relative to `dir`
+// The live database (and its WAL) is left out, since its snapshot goes in instead
+fn backup_files(dir: &std::path::Path) -> Result<Vec<std::path::PathBuf>, std::io::Error> {
+    let mut files = Vec::new();
+    let mut pending = vec![std::path::PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in std::fs::read_dir(dir.join(&relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                if !(relative.as_os_str().is_empty()
+                    && EXCLUDED_DIRS.contains(&entry.file_name().to_string_lossy().as_ref()))
+                {
+                    pending.push(path);
+                }
+
+                continue;
+            }
+
+            if !file_type.is_file() {
+                continue;
+            }
+
+            if relative.as_os_str().is_empty()
+                && entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(DATABASE_NAME)
+            {
+                continue;
+            }
+
+            files.push(path);
+        }
+    }
+
+    files.sort();
+
+    Ok(files)
+}
+
+fn append_file<W: Write>(
+    builder: &mut tar::Builder<W>,
+    source: &std::path::Path,
+    name: &str,
+) -> Result<ManifestFile, std::io::Error> {
+    let file = std::fs::File::open(source)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(&file.metadata()?);
+
+    // Anything written to the file past this point isn't part of the backup
+    let size = header.size()?;
+    let mut reader = HashingReader::new(file.take(size));
+    builder.append_data(&mut header, name, &mut reader)?;
+
+    let (read, sha256) = reader.finish();
+    if read != size {
+        return Err(invalid(format!(
+            "{} changed size while it was being backed up",
+            source.display()
+        )));
+    }
+
+    Ok(ManifestFile {
+        path: name.to_string(),
+        size,
+        sha256,
+    })
+}
+
+// Writes a backup of `dir` and the database to `path`
+// The archive is written next to `path` and moved into place once it's complete
+pub fn create(
+    db: &rusqlite::Connection,
+    dir: &std::path::Path,
+    path: &std::path::Path,
+) -> Result<Manifest, Box<dyn std::error::Error>> {
+    let partial = path.with_extension("partial");
+    let snapshot = path.with_extension("sqlite.partial");
+    let _ = std::fs::remove_file(&snapshot);
+
+    db.execute(
+        "VACUUM INTO ?1",
+        rusqlite::params![snapshot.to_string_lossy().to_string()],
+    )?;
+
+    let result = (|| -> Result<Manifest, Box<dyn std::error::Error>> {
+        let mut builder = tar::Builder::new(std::fs::File::create(&partial)?);
+
+        let mut files = vec![append_file(&mut builder, &snapshot, DATABASE_NAME)?];
+        for relative in backup_files(dir)? {
+            files.push(append_file(
+                &mut builder,
+                &dir.join(&relative),
+                &relative_name(&relative),
+            )?);
+        }
+
+        let manifest = Manifest {
+            version: ARCHIVE_VERSION,
+            schema_version: migrations::version(db)?,
+            created: now(),
+            files,
+        };
+
+        let contents = serde_json::to_vec_pretty(&manifest)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(manifest.created);
+        builder.append_data(&mut header, MANIFEST_NAME, contents.as_slice())?;
+
+        builder.into_inner()?.sync_all()?;
+        std::fs::rename(&partial, path)?;
+
+        Ok(manifest)
+    })();
+
+    let _ = std::fs::remove_file(&snapshot);
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+
+    result
+}
+
+// Unpacks the backup at `path` into `staged`, checking it against its manifest
+// and the database's integrity along the way
+// `staged` is only replaced once everything checks out
+pub fn stage(
+    path: &std::path::Path,
+    staged: &std::path::Path,
+    latest_schema: usize,
+) -> Result<Manifest, Box<dyn std::error::Error>> {
+    let partial = staged.with_extension("partial");
+    let _ = std::fs::remove_dir_all(&partial);
+    std::fs::create_dir_all(&partial)?;
+
+    let result = (|| -> Result<Manifest, Box<dyn std::error::Error>> {
+        let mut manifest: Option<Manifest> = None;
+        let mut unpacked = std::collections::HashMap::new();
+
+        let mut archive = tar::Archive::new(std::fs::File::open(path)?);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let relative = entry.path()?.to_path_buf();
+            if !relative
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)))
+            {
+                return Err(invalid(format!(
+                    "backup has a file outside of it: {}",
+                    relative.display()
+                ))
+                .into());
+            }
+
+            let name = relative_name(&relative);
+            let mut reader = HashingReader::new(entry);
+            if name == MANIFEST_NAME {
+                let mut contents = String::new();
+                reader.read_to_string(&mut contents)?;
+                manifest = Some(serde_json::from_str(&contents)?);
+                continue;
+            }
+
+            let destination = partial.join(&relative);
+            if let Some(parent) = destination.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            std::io::copy(&mut reader, &mut std::fs::File::create(&destination)?)?;
+            unpacked.insert(name, reader.finish());
+        }
+
+        let manifest = manifest.ok_or_else(|| invalid("backup has no manifest".to_string()))?;
+        verify(&manifest, &unpacked, latest_schema)?;
+
+        let db = rusqlite::Connection::open(partial.join(DATABASE_NAME))?;
+        let integrity: String = db.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+        if integrity != "ok" {
+            return Err(invalid(format!("backed up database is corrupt: {}", integrity)).into());
+        }
+
+        if migrations::version(&db)? != manifest.schema_version {
+            return Err(
+                invalid("backed up database doesn't match its manifest".to_string()).into(),
+            );
+        }
+
+        drop(db);
+
+        let _ = std::fs::remove_dir_all(staged);
+        std::fs::rename(&partial, staged)?;
+
+        Ok(manifest)
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(&partial);
+    }
+
+    result
+}
+
+// Whether the files unpacked from a backup, name -> (size, SHA-256), are the ones in its manifest
+fn verify(
+    manifest: &Manifest,
+    unpacked: &std::collections::HashMap<String, (u64, String)>,
+    latest_schema: usize,
+) -> Result<(), std::io::Error> {
+    if manifest.version > ARCHIVE_VERSION {
+        return Err(invalid(format!(
+            "backup format {} is newer than this build supports",
+            manifest.version
+        )));
+    }
+
+    // Migrations only go forward
+    if manifest.schema_version > latest_schema {
+        return Err(invalid(format!(
+            "backup is from a newer build (schema version {}, this build knows up to {})",
+            manifest.schema_version, latest_schema
+        )));
+    }
+
+    if !manifest.files.iter().any(|f| f.path == DATABASE_NAME) {
+        return Err(invalid("backup has no database".to_string()));
+    }
+
+    for file in manifest.files.iter() {
+        match unpacked.get(&file.path) {
+            Some((size, sha256)) if *size == file.size && *sha256 == file.sha256 => {}
+            Some(_) => {
+                return Err(invalid(format!("{} is corrupt", file.path)));
+            }
+            None => {
+                return Err(invalid(format!("{} is missing", file.path)));
+            }
+        }
+    }
+
+    if unpacked.len() != manifest.files.len() {
+        return Err(invalid(
+            "backup has files that aren't in its manifest".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// Swaps a staged restore in for `dir`, returning where the old `dir` was moved to
+// Does nothing if there's no staged restore
+pub fn apply_staged(
+    staged: &std::path::Path,
+    dir: &std::path::Path,
+) -> Result<Option<std::path::PathBuf>, std::io::Error> {
+    if !staged.is_dir() {
+        return Ok(None);
+    }
+
+    let file_name = dir
+        .file_name()
+        .map_or("state".to_string(), |n| n.to_string_lossy().to_string());
+    let previous = dir.with_file_name(format!("{}-before-restore-{}", file_name, now()));
+
+    if dir.exists() {
+        std::fs::rename(dir, &previous)?;
+    }
+
+    std::fs::rename(staged, dir)?;
+
+    Ok(Some(previous))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("william-backup-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn state(root: &std::path::Path) -> (std::path::PathBuf, rusqlite::Connection) {
+        let dir = root.join("state");
+        std::fs::create_dir_all(dir.join("messages")).unwrap();
+        std::fs::create_dir_all(dir.join("backups")).unwrap();
+        std::fs::write(dir.join("messages").join("1.txt"), "hello").unwrap();
+        std::fs::write(dir.join("ledger"), "ledger").unwrap();
+        std::fs::write(dir.join("backups").join("old.sqlite"), "old").unwrap();
+
+        let db = rusqlite::Connection::open(dir.join(DATABASE_NAME)).unwrap();
+        db.execute_batch(
+            "CREATE TABLE notes (body TEXT); INSERT INTO notes VALUES ('hi'); PRAGMA user_version = 3;",
+        )
+        .unwrap();
+
+        (dir, db)
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let root = temp_dir("roundtrip");
+        let (dir, db) = state(&root);
+
+        let archive = root.join("backup.tar");
+        let manifest = create(&db, &dir, &archive).unwrap();
+        let names = manifest
+            .files
+            .iter()
+            .map(|f| f.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![DATABASE_NAME, "ledger", "messages/1.txt"]);
+        assert_eq!(manifest.schema_version, 3);
+
+        // Too new for a build that only knows two migrations
+        let staged = root.join("restore");
+        assert!(stage(&archive, &staged, 2).is_err());
+        assert!(!staged.exists());
+
+        assert_eq!(stage(&archive, &staged, 3).unwrap(), manifest);
+        assert_eq!(
+            std::fs::read_to_string(staged.join("messages").join("1.txt")).unwrap(),
+            "hello"
+        );
+
+        drop(db);
+        let previous = apply_staged(&staged, &dir).unwrap().unwrap();
+        assert!(!staged.exists());
+        assert!(previous.join("backups").exists());
+
+        let restored = rusqlite::Connection::open(dir.join(DATABASE_NAME)).unwrap();
+        let body: String = restored
+            .query_row("SELECT body FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(body, "hi");
+
+        // Nothing staged
+        assert_eq!(apply_staged(&staged, &dir).unwrap(), None);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_verify() {
+        let manifest = Manifest {
+            version: ARCHIVE_VERSION,
+            schema_version: 2,
+            created: 0,
+            files: vec![ManifestFile {
+                path: DATABASE_NAME.to_string(),
+                size: 5,
+                sha256: "abc".to_string(),
+            }],
+        };
+
+        let mut unpacked = std::collections::HashMap::new();
+        unpacked.insert(DATABASE_NAME.to_string(), (5, "abc".to_string()));
+        assert!(verify(&manifest, &unpacked, 2).is_ok());
+
+        // Tampered with
+        unpacked.insert(DATABASE_NAME.to_string(), (5, "abd".to_string()));
+        assert!(verify(&manifest, &unpacked, 2).is_err());
+
+        // Extra files
+        unpacked.insert(DATABASE_NAME.to_string(), (5, "abc".to_string()));
+        unpacked.insert("extra".to_string(), (1, "def".to_string()));
+        assert!(verify(&manifest, &unpacked, 2).is_err());
+    }
+}
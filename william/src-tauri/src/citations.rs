@@ -0,0 +1,161 @@
+use crate::types::*;
+
+// References go into the system prompt numbered from 1, and the model is asked to cite them
+// with markers like [1] or [1, 3]
+// Once the response is in, its markers are matched back up with the references they point to
+
+pub const CITATION_INSTRUCTIONS: &str = r#"
+    <citations>
+        Each reference has an id. When part of your response relies on a reference, cite it right after with its id in square brackets, like [1], or [1, 3] for several.
+        Only cite references you actually used, and don't cite anything else this way.
+    </citations>
+"#;
+
+// Longest excerpt of a reference kept with its citation
+const EXCERPT_CHARS: usize = 200;
+
+// The reference numbers cited in `content`, in order of first appearance
+//
+// Code is skipped since brackets there are usually indexing, and so are markdown links
+pub fn markers(content: &str) -> Vec<usize> {
+    let mut markers = Vec::new();
+    let mut in_fence = false;
+    for line in content.lines() {
+        if line.trim_start().starts_with("```") {
+            in_fence = !in_fence;
+            continue;
+        }
+
+        if in_fence {
+            continue;
+        }
+
+        // Odd-numbered pieces between backticks are inline code
+        for (i, text) in line.split('`').enumerate() {
+            if i % 2 == 0 {
+                line_markers(text, &mut markers);
+            }
+        }
+    }
+
+    markers
+}
+
+fn line_markers(text: &str, markers: &mut Vec<usize>) {
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        rest = &rest[start + 1..];
+        let end = match rest.find(']') {
+            Some(e) => e,
+            None => return,
+        };
+
+        let inner = &rest[..end];
+        rest = &rest[end + 1..];
+        if rest.starts_with('(') {
+            continue;
+        }
+
+        let numbers = inner
+            .split(',')
+            .map(|n| n.trim().parse::<usize>())
+            .collect::<Result<Vec<usize>, _>>();
+
+        if let Ok(numbers) = numbers {
+            for n in numbers {
+                if !markers.contains(&n) {
+                    markers.push(n);
+                }
+            }
+        }
+    }
+}
+
+// Wraps a reference's contents for the system prompt
+pub fn reference_tag(marker: usize, contents: &str) -> String {
+    format!("<reference id=\"{}\">{}</reference>", marker, contents)
+}
+
+pub fn excerpt(contents: &str) -> String {
+    let contents = contents.trim();
+    if contents.chars().count() <= EXCERPT_CHARS {
+        return contents.to_string();
+    }
+
+    format!(
+        "{}...",
+        contents
+            .chars()
+            .take(EXCERPT_CHARS)
+            .collect::<String>()
+            .trim_end()
+    )
+}
+
+// Document, watched-file, and repository chunks start with where they came from
+pub fn chunk_source(contents: &str) -> Option<String> {
+    let first = contents.lines().next()?;
+    first
+        .strip_prefix("From ")
+        .and_then(|s| s.strip_suffix(':'))
+        .map(|s| s.to_string())
+}
+
+// The references cited in `content`, in the order they're first cited
+// Markers that don't match any reference are dropped
+pub fn cite(content: &str, references: &[Citation]) -> Vec<Citation> {
+    markers(content)
+        .into_iter()
+        .filter_map(|marker| references.iter().find(|r| r.marker == marker).cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference(marker: usize) -> Citation {
+        Citation {
+            marker,
+            source: format!("reference {}", marker),
+            conversation_id: None,
+            message_id: None,
+            excerpt: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_markers() {
+        assert_eq!(
+            markers("Paris is the capital [2]. It's also large [1, 2] [3]."),
+            vec![2, 1, 3]
+        );
+        assert!(markers("No citations here.").is_empty());
+
+        // Code, links, and non-numbers don't count
+        assert!(markers("Use `xs[0]` or [the docs](https://example.com) [x]").is_empty());
+        assert_eq!(
+            markers("Before [1]\n```rust\nlet y = xs[2];\n```\nAfter [3]"),
+            vec![1, 3]
+        );
+    }
+
+    #[test]
+    fn test_cite() {
+        let references = vec![reference(1), reference(2)];
+        let citations = cite("Something [2], and something made up [7].", &references);
+        assert_eq!(citations.len(), 1);
+        assert_eq!(citations[0].marker, 2);
+
+        assert_eq!(
+            chunk_source("From notes.md:\nbuy milk"),
+            Some("notes.md".to_string())
+        );
+        assert_eq!(chunk_source("just a message"), None);
+
+        let long = "word ".repeat(100);
+        assert!(excerpt(&long).ends_with("..."));
+        assert!(excerpt(&long).chars().count() <= EXCERPT_CHARS + 3);
+        assert_eq!(excerpt("  short  "), "short");
+    }
+}
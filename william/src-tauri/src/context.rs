@@ -0,0 +1,157 @@
+use crate::tiktoken::Tokenizer;
+use crate::types::*;
+
+// How the next prompt for a conversation would spend the model's context window,
+// for the frontend's context meter
+//
+// References are retrieved against whatever the user sends next, so there's no knowing them
+// ahead of time--the last prompt's references stand in for them
+
+// Without a tokenizer, ~4 characters per token is close enough
+pub fn count(text: &str, tokenizer: Option<&Tokenizer>) -> usize {
+    match tokenizer {
+        Some(tok) => tok.count(text),
+        None => text.len().div_ceil(4),
+    }
+}
+
+// The contents of each `<reference>` in a system prompt, in order
+pub fn references(system_prompt: &str) -> Vec<&str> {
+    let mut references = Vec::new();
+    let mut rest = system_prompt;
+    while let Some(start) = rest.find("<reference id=") {
+        let tag = &rest[start..];
+        let (open, close) = match (tag.find('>'), tag.find("</reference>")) {
+            (Some(open), Some(close)) if open < close => (open, close),
+            _ => break,
+        };
+
+        references.push(&tag[open + 1..close]);
+        rest = &tag[close + "</reference>".len()..];
+    }
+
+    references
+}
+
+// `system_prompt` is the prompt as it'd be built, minus references
+// `included` is how many of the conversation's messages (from the end) would be sent
+pub fn breakdown(
+    conversation: &Conversation,
+    api: &API,
+    system_prompt: &str,
+    last_prompt: Option<&str>,
+    included: usize,
+    tokenizer: Option<&Tokenizer>,
+) -> ContextBreakdown {
+    let first_included = conversation.messages.len().saturating_sub(included);
+    let messages = conversation
+        .messages
+        .iter()
+        .enumerate()
+        .map(|(i, m)| MessageTokens {
+            message_id: m.id,
+            message_type: m.message_type.clone(),
+            tokens: count(&m.content, tokenizer),
+            included: i >= first_included,
+        })
+        .collect::<Vec<_>>();
+
+    let references = last_prompt
+        .map(|p| {
+            references(p)
+                .into_iter()
+                .map(|r| count(r, tokenizer))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let system_prompt = count(system_prompt, tokenizer);
+    let used = system_prompt
+        + references.iter().sum::<usize>()
+        + messages
+            .iter()
+            .filter(|m| m.included)
+            .map(|m| m.tokens)
+            .sum::<usize>();
+
+    let context_window = api.context_window();
+
+    ContextBreakdown {
+        conversation_id: conversation.id.unwrap_or_default(),
+        model: api.clone(),
+        context_window,
+        system_prompt,
+        references,
+        messages,
+        used,
+        remaining: context_window.saturating_sub(used),
+        estimator: tokenizer
+            .map_or("character", |t| t.estimator().name())
+            .to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_references() {
+        let prompt = format!(
+            "<systemPrompt><references>{}{}</references></systemPrompt>",
+            crate::citations::reference_tag(1, "first"),
+            crate::citations::reference_tag(2, "second one")
+        );
+        assert_eq!(references(&prompt), vec!["first", "second one"]);
+        assert!(references("<systemPrompt></systemPrompt>").is_empty());
+        assert!(references("<reference id=\"1\">cut off").is_empty());
+    }
+
+    #[test]
+    fn test_breakdown() {
+        let api = API::Local("llama3".to_string());
+        let conversation = Conversation {
+            id: Some(1),
+            name: String::new(),
+            messages: vec![
+                Message::test(MessageType::User, &"a".repeat(400), api.clone()),
+                Message::test(MessageType::Assistant, &"b".repeat(40), api.clone()),
+                Message::test(MessageType::User, &"c".repeat(8), api.clone()),
+            ],
+            tools: Vec::new(),
+            overrides: Default::default(),
+            branch_id: None,
+            settings: Default::default(),
+            pinned: false,
+            archived: false,
+            unread: 0,
+            template: None,
+            memory_scope: MemoryScope::All,
+            related: Vec::new(),
+        };
+
+        let last_prompt = crate::citations::reference_tag(1, &"r".repeat(80));
+        let breakdown = breakdown(
+            &conversation,
+            &api,
+            &"s".repeat(20),
+            Some(&last_prompt),
+            2,
+            None,
+        );
+
+        assert_eq!(breakdown.system_prompt, 5);
+        assert_eq!(breakdown.references, vec![20]);
+        assert_eq!(
+            breakdown
+                .messages
+                .iter()
+                .map(|m| (m.tokens, m.included))
+                .collect::<Vec<_>>(),
+            vec![(100, false), (10, true), (2, true)]
+        );
+        assert_eq!(breakdown.used, 5 + 20 + 10 + 2);
+        assert_eq!(breakdown.remaining, api.context_window() - breakdown.used);
+        assert_eq!(breakdown.estimator, "character");
+    }
+}
@@ -0,0 +1,122 @@
+use chamber_common::{lprint, ChamberError, Logger};
+
+// Statement helpers that log what failed instead of panicking
+//
+// A failed statement is logged along with a summary of what was bound to it--types and sizes,
+// never the text itself, since that's usually someone's conversation--and comes back
+// as a `ChamberError` for the caller to send on to the frontend
+
+// Statements are logged on one line, however they're written in the source
+fn compact(statement: &str) -> String {
+    statement.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn describe(value: rusqlite::types::ValueRef) -> String {
+    match value {
+        rusqlite::types::ValueRef::Null => "null".to_string(),
+        rusqlite::types::ValueRef::Integer(i) => i.to_string(),
+        rusqlite::types::ValueRef::Real(f) => f.to_string(),
+        rusqlite::types::ValueRef::Text(t) => format!("text({} bytes)", t.len()),
+        rusqlite::types::ValueRef::Blob(b) => format!("blob({} bytes)", b.len()),
+    }
+}
+
+// e.g. `?1 = 12, ?2 = text(48 bytes), ?3 = null`
+pub fn summarize(params: &[&dyn rusqlite::ToSql]) -> String {
+    params
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let value = match p.to_sql() {
+                Ok(rusqlite::types::ToSqlOutput::Borrowed(v)) => describe(v),
+                Ok(rusqlite::types::ToSqlOutput::Owned(v)) => describe((&v).into()),
+                Ok(_) => "?".to_string(),
+                Err(e) => format!("unconvertible({})", e),
+            };
+
+            format!("?{} = {}", i + 1, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn failed(statement: &str, params: &[&dyn rusqlite::ToSql], e: rusqlite::Error) -> ChamberError {
+    let statement = compact(statement);
+    lprint!(
+        error,
+        "SQL error: {}; statement: `{}`; params: [{}]",
+        e,
+        statement,
+        summarize(params)
+    );
+
+    ChamberError::new("Database", statement, e)
+}
+
+pub fn execute(
+    db: &rusqlite::Connection,
+    statement: &str,
+    params: &[&dyn rusqlite::ToSql],
+) -> Result<usize, ChamberError> {
+    db.execute(statement, params)
+        .map_err(|e| failed(statement, params, e))
+}
+
+// `Ok(None)` when there's no row
+pub fn query_row<T, F>(
+    db: &rusqlite::Connection,
+    statement: &str,
+    params: &[&dyn rusqlite::ToSql],
+    f: F,
+) -> Result<Option<T>, ChamberError>
+where
+    F: FnOnce(&rusqlite::Row<'_>) -> rusqlite::Result<T>,
+{
+    match db.query_row(statement, params, f) {
+        Ok(row) => Ok(Some(row)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(failed(statement, params, e)),
+    }
+}
+
+pub fn query_map<T, F>(
+    db: &rusqlite::Connection,
+    statement: &str,
+    params: &[&dyn rusqlite::ToSql],
+    f: F,
+) -> Result<Vec<T>, ChamberError>
+where
+    F: FnMut(&rusqlite::Row<'_>) -> rusqlite::Result<T>,
+{
+    let rows = db.prepare(statement).and_then(|mut query| {
+        query
+            .query_map(params, f)?
+            .collect::<rusqlite::Result<Vec<T>>>()
+    });
+
+    rows.map_err(|e| failed(statement, params, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        let content = "someone's conversation".to_string();
+        let none: Option<i64> = None;
+        assert_eq!(
+            summarize(rusqlite::params![12, content, none, 0.5]),
+            "?1 = 12, ?2 = text(22 bytes), ?3 = null, ?4 = 0.5"
+        );
+        assert_eq!(summarize(rusqlite::params![]), "");
+    }
+
+    #[test]
+    fn test_compact() {
+        assert_eq!(
+            compact("\n        SELECT id\n        FROM messages\n        WHERE id = ?1\n        "),
+            "SELECT id FROM messages WHERE id = ?1"
+        );
+    }
+}
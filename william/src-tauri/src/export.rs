@@ -0,0 +1,382 @@
+use crate::types::*;
+
+// Renders a conversation for export
+//
+// Markdown is meant for reading--each message gets a heading with its role, model, and timestamp,
+// with the system prompt it was generated under tucked into a collapsible block
+//
+// JSON is the conversation exactly as William has it, messages + metadata and all
+//
+// PDF is the Markdown laid out for printing, for archiving--see pdf.rs
+pub fn render(
+    conversation: &Conversation,
+    format: ExportFormat,
+) -> Result<Vec<u8>, std::io::Error> {
+    match format {
+        ExportFormat::Markdown => Ok(render_markdown(conversation).into_bytes()),
+        ExportFormat::Json => serde_json::to_vec_pretty(conversation)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        ExportFormat::Pdf => Ok(crate::pdf::render(
+            &conversation.name,
+            &date(conversation),
+            &render_markdown(conversation),
+        )),
+    }
+}
+
+// The day the conversation started, for the PDF's page headers
+fn date(conversation: &Conversation) -> String {
+    conversation
+        .messages
+        .first()
+        .map(|m| {
+            m.date_created
+                .split(' ')
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+// Default filename when the export path is a directory
+pub fn filename(conversation: &Conversation, format: ExportFormat) -> String {
+    format!("{}.{}", stem(conversation), format.extension())
+}
+
+// The conversation's name, made safe for a filename
+pub fn stem(conversation: &Conversation) -> String {
+    let stem = conversation
+        .name
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .split('-')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+        .to_lowercase();
+
+    if stem.is_empty() {
+        format!("conversation-{}", conversation.id.unwrap_or_default())
+    } else {
+        stem
+    }
+}
+
+fn render_markdown(conversation: &Conversation) -> String {
+    let mut output = format!("# {}\n", conversation.name);
+
+    // Consecutive messages usually share a system prompt--no need to repeat it
+    let mut last_system_prompt = "";
+    for message in conversation.messages.iter() {
+        let (provider, model) = message.api.to_strings();
+        let role = match message.message_type {
+            MessageType::System => "System",
+            MessageType::User => "User",
+            MessageType::Assistant => "Assistant",
+            MessageType::Developer => "Developer",
+            MessageType::Tool => "Tool",
+        };
+
+        output.push_str(&format!(
+            "\n## {} ({}/{}) - {}\n\n",
+            role, provider, model, message.date_created
+        ));
+
+        if !message.system_prompt.is_empty() && message.system_prompt != last_system_prompt {
+            output.push_str(&format!(
+                "<details>\n<summary>System prompt</summary>\n\n{}\n\n</details>\n\n",
+                message.system_prompt.trim()
+            ));
+
+            last_system_prompt = &message.system_prompt;
+        }
+
+        if let Some(call_id) = &message.tool_call_id {
+            output.push_str(&format!("Result of tool call `{}`:\n\n", call_id));
+        }
+
+        // Models don't always say what language their code is in,
+        // which leaves exported code blocks without highlighting
+        if message.message_type == MessageType::Assistant {
+            output.push_str(tag_code_fences(message.content.trim()).as_str());
+        } else {
+            output.push_str(message.content.trim());
+        }
+        output.push('\n');
+
+        for call in message.tool_calls.iter() {
+            output.push_str(&format!(
+                "\nTool call `{}` (`{}`):\n\n```json\n{}\n```\n",
+                call.name, call.id, call.arguments
+            ));
+        }
+    }
+
+    output
+}
+
+// Adds a language to each untagged code fence, where one can be guessed
+// Fences that are already tagged, or never closed, are left alone
+pub fn tag_code_fences(content: &str) -> String {
+    let lines = content.lines().collect::<Vec<&str>>();
+    let mut output = Vec::with_capacity(lines.len());
+
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim_start();
+        let fence = if trimmed.starts_with("```") {
+            "```"
+        } else if trimmed.starts_with("~~~") {
+            "~~~"
+        } else {
+            output.push(line.to_string());
+            i += 1;
+            continue;
+        };
+
+        let close = lines[i + 1..]
+            .iter()
+            .position(|l| l.trim() == fence)
+            .map(|p| i + 1 + p);
+
+        match close {
+            Some(close) => {
+                let mut opener = line.to_string();
+                if trimmed[fence.len()..].trim().is_empty() {
+                    if let Some(language) = guess_language(&lines[i + 1..close].join("\n")) {
+                        let indent = &line[..line.len() - trimmed.len()];
+                        opener = format!("{}{}{}", indent, fence, language);
+                    }
+                }
+
+                output.push(opener);
+                output.extend(lines[i + 1..=close].iter().map(|l| l.to_string()));
+                i = close + 1;
+            }
+            None => {
+                output.extend(lines[i..].iter().map(|l| l.to_string()));
+                break;
+            }
+        }
+    }
+
+    output.join("\n")
+}
+
+// Tell-tale signs of each language, and how much they count for
+// The best scoring language wins, so long as it gets past a couple weak hints
+const LANGUAGE_HINTS: &[(&str, &[(&str, u32)])] = &[
+    (
+        "rust",
+        &[
+            ("fn ", 2),
+            ("let mut ", 3),
+            ("impl ", 2),
+            ("pub fn ", 3),
+            ("println!", 3),
+            ("use std::", 3),
+            ("-> ", 1),
+            ("&mut ", 2),
+            ("::", 1),
+        ],
+    ),
+    (
+        "python",
+        &[
+            ("def ", 3),
+            ("elif ", 3),
+            ("self.", 2),
+            ("print(", 2),
+            ("import ", 1),
+            ("from ", 1),
+            ("None", 1),
+            ("__init__", 3),
+        ],
+    ),
+    (
+        "typescript",
+        &[
+            ("interface ", 2),
+            (": string", 3),
+            (": number", 3),
+            ("export type ", 3),
+        ],
+    ),
+    (
+        "javascript",
+        &[
+            ("const ", 2),
+            ("function ", 2),
+            ("=> ", 1),
+            ("console.log", 3),
+            ("require(", 3),
+            ("===", 2),
+        ],
+    ),
+    (
+        "go",
+        &[("package ", 3), ("func ", 3), (":= ", 2), ("fmt.", 3)],
+    ),
+    (
+        "cpp",
+        &[
+            ("#include", 3),
+            ("std::", 2),
+            ("int main(", 2),
+            ("cout <<", 3),
+        ],
+    ),
+    (
+        "java",
+        &[
+            ("public class ", 3),
+            ("System.out.", 3),
+            ("public static void ", 3),
+            ("private ", 1),
+        ],
+    ),
+    (
+        "sql",
+        &[
+            ("SELECT ", 2),
+            (" FROM ", 2),
+            ("WHERE ", 1),
+            ("INSERT INTO ", 3),
+            ("CREATE TABLE ", 3),
+        ],
+    ),
+    (
+        "bash",
+        &[
+            ("#!/bin/", 4),
+            ("$ ", 1),
+            ("sudo ", 2),
+            ("cargo ", 2),
+            ("npm ", 2),
+            ("git ", 2),
+            ("cd ", 1),
+            ("echo ", 2),
+        ],
+    ),
+    (
+        "html",
+        &[("<div", 2), ("</", 1), ("<html", 3), ("<!DOCTYPE", 4)],
+    ),
+    (
+        "toml",
+        &[("[package]", 4), ("[dependencies]", 4), ("version = \"", 2)],
+    ),
+];
+
+pub fn guess_language(code: &str) -> Option<&'static str> {
+    let trimmed = code.trim();
+    if trimmed.is_empty() {
+        return None;
+    }
+
+    // Anything that parses is JSON, whatever else it looks like
+    if (trimmed.starts_with('{') || trimmed.starts_with('['))
+        && serde_json::from_str::<serde_json::Value>(trimmed).is_ok()
+    {
+        return Some("json");
+    }
+
+    // Ties go to whichever comes first in `LANGUAGE_HINTS`
+    let mut best: Option<(&'static str, u32)> = None;
+    for (language, hints) in LANGUAGE_HINTS {
+        let score = hints
+            .iter()
+            .filter(|(hint, _)| code.contains(hint))
+            .map(|(_, weight)| weight)
+            .sum::<u32>();
+
+        if score >= 3 && best.is_none_or(|(_, s)| score > s) {
+            best = Some((language, score));
+        }
+    }
+
+    best.map(|(language, _)| language)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markdown_export() {
+        let conversation = Conversation {
+            id: Some(1),
+            name: "Rust Lifetimes?".to_string(),
+            messages: vec![
+                (MessageType::User, "What's a lifetime?"),
+                (MessageType::Assistant, "A scope for borrows."),
+            ]
+            .into_iter()
+            .map(|(message_type, content)| Message {
+                system_prompt: "be nice".to_string(),
+                date_created: "2025-01-01 12:00:00".to_string(),
+                ..Message::test(
+                    message_type,
+                    content,
+                    API::Anthropic(AnthropicModel::Claude35Sonnet),
+                )
+            })
+            .collect(),
+            tools: Vec::new(),
+            overrides: ConversationOverrides::default(),
+            branch_id: None,
+            settings: GenerationSettings::default(),
+            pinned: false,
+            archived: false,
+            unread: 0,
+            template: None,
+            memory_scope: MemoryScope::All,
+            related: Vec::new(),
+        };
+
+        let markdown =
+            String::from_utf8(render(&conversation, ExportFormat::Markdown).unwrap()).unwrap();
+        assert!(markdown.starts_with("# Rust Lifetimes?\n"));
+        assert!(markdown.contains(
+            "## User (anthropic/claude-3-5-sonnet-latest) - 2025-01-01 12:00:00\n\n<details>"
+        ));
+        assert!(markdown.contains("## Assistant"));
+        assert_eq!(
+            markdown.matches("<summary>System prompt</summary>").count(),
+            1
+        );
+
+        assert_eq!(
+            filename(&conversation, ExportFormat::Markdown),
+            "rust-lifetimes.md"
+        );
+
+        let pdf = render(&conversation, ExportFormat::Pdf).unwrap();
+        assert!(pdf.starts_with(b"%PDF-"));
+        assert_eq!(
+            filename(&conversation, ExportFormat::Pdf),
+            "rust-lifetimes.pdf"
+        );
+        assert_eq!(date(&conversation), "2025-01-01");
+    }
+
+    #[test]
+    fn test_code_fence_tagging() {
+        let content = "Try this:\n```\nfn main() {\n    let mut x = 1;\n    println!(\"{}\", x);\n}\n```\nOr in Python:\n```\ndef main():\n    print(1)\n```\n```toml\nkeep = \"as is\"\n```";
+        let tagged = tag_code_fences(content);
+
+        assert!(tagged.contains("```rust\nfn main()"));
+        assert!(tagged.contains("```python\ndef main()"));
+        assert!(tagged.contains("```toml\nkeep"));
+        assert_eq!(tagged.lines().count(), content.lines().count());
+
+        assert_eq!(guess_language("{\"a\": [1, 2]}"), Some("json"));
+        assert_eq!(guess_language("just some words"), None);
+
+        // Nothing to close it, so nothing changes
+        assert_eq!(tag_code_fences("```\nfn main() {}"), "```\nfn main() {}");
+    }
+}
@@ -0,0 +1,140 @@
+// Text extraction for document attachments, so they can be chunked and embedded like messages
+//
+// Backends are picked by mime type, falling back to the file's extension:
+// - PDFs go through `pdf-extract`
+// - Plain text, markdown, and source code are read as-is
+
+// Extensions read as plain text, whatever mime type they came in with
+pub const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "markdown", "rst", "csv", "log", "json", "toml", "yaml", "yml", "xml", "html",
+    "css", "rs", "py", "js", "jsx", "ts", "tsx", "go", "c", "h", "cpp", "hpp", "java", "kt",
+    "swift", "rb", "php", "sh", "sql", "lua", "zig",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Pdf,
+    PlainText,
+}
+
+impl Backend {
+    pub fn for_attachment(name: &str, mime_type: &str) -> Option<Self> {
+        if mime_type == "application/pdf" {
+            return Some(Backend::Pdf);
+        }
+
+        if mime_type.starts_with("text/") {
+            return Some(Backend::PlainText);
+        }
+
+        let extension = std::path::Path::new(name)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        match extension.as_str() {
+            "pdf" => Some(Backend::Pdf),
+            e if TEXT_EXTENSIONS.contains(&e) => Some(Backend::PlainText),
+            _ => None,
+        }
+    }
+}
+
+pub fn extract_text(backend: Backend, data: &[u8]) -> Result<String, std::io::Error> {
+    let text = match backend {
+        Backend::Pdf => pdf_extract::extract_text_from_mem(data)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?,
+        Backend::PlainText => String::from_utf8_lossy(data).to_string(),
+    };
+
+    Ok(text.replace("\r\n", "\n"))
+}
+
+// Splits `text` into chunks of at most `max_chars`, each overlapping the last by about `overlap`
+//
+// Chunks end on paragraph breaks where they can, then lines, then spaces,
+// so an embedding doesn't start or stop mid-sentence unless it has to
+pub fn chunk(text: &str, max_chars: usize, overlap: usize) -> Vec<String> {
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut chunks = Vec::new();
+
+    let mut start = 0;
+    while start < chars.len() {
+        let mut end = std::cmp::min(start + max_chars, chars.len());
+
+        if end < chars.len() {
+            let window = chars[start..end].iter().collect::<String>();
+            // Not so early that the chunk is mostly overlap
+            let min_end = max_chars / 2;
+            let split = ["\n\n", "\n", " "].iter().find_map(|separator| {
+                window
+                    .rfind(separator)
+                    .map(|i| window[..i].chars().count() + separator.len())
+                    .filter(|&i| i > min_end)
+            });
+
+            if let Some(split) = split {
+                end = start + split;
+            }
+        }
+
+        let chunk = chars[start..end].iter().collect::<String>();
+        if !chunk.trim().is_empty() {
+            chunks.push(chunk.trim().to_string());
+        }
+
+        if end == chars.len() {
+            break;
+        }
+
+        start = std::cmp::max(end.saturating_sub(overlap), start + 1);
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backends() {
+        assert_eq!(
+            Backend::for_attachment("paper.pdf", "application/octet-stream"),
+            Some(Backend::Pdf)
+        );
+        assert_eq!(
+            Backend::for_attachment("notes", "text/markdown"),
+            Some(Backend::PlainText)
+        );
+        assert_eq!(
+            Backend::for_attachment("main.RS", "application/octet-stream"),
+            Some(Backend::PlainText)
+        );
+        assert_eq!(Backend::for_attachment("cat.png", "image/png"), None);
+
+        assert_eq!(
+            extract_text(Backend::PlainText, b"line one\r\nline two").unwrap(),
+            "line one\nline two"
+        );
+    }
+
+    #[test]
+    fn test_chunking() {
+        assert!(chunk("", 100, 10).is_empty());
+        assert_eq!(chunk("short", 100, 10), vec!["short".to_string()]);
+
+        let text = format!("{}\n\n{}", "a ".repeat(40), "b ".repeat(40));
+        let chunks = chunk(&text, 100, 10);
+        assert_eq!(chunks.len(), 2);
+        // The first chunk stops at the paragraph break
+        assert!(!chunks[0].contains('b'));
+        assert!(chunks[1].ends_with('b'));
+
+        // No good places to split still terminates, with every chunk in bounds
+        let text = "x".repeat(1000);
+        let chunks = chunk(&text, 100, 20);
+        assert!(chunks.iter().all(|c| c.chars().count() <= 100));
+        assert_eq!(chunks.len(), 13);
+    }
+}
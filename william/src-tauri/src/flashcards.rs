@@ -0,0 +1,177 @@
+use chamber_common::{lprint, Logger};
+
+use crate::network;
+use crate::queue;
+use crate::types::*;
+
+// Question/answer pairs drawn out of a conversation, written as a tab-separated file Anki can import
+//
+// The file carries Anki's header lines, so importing it needs no setup:
+// fields are tab-separated, rendered as HTML, and every card is tagged with the conversation
+
+const FLASHCARD_PROMPT: &str = r#"
+    You will be given a conversation someone had while studying.
+    Write flashcards covering what they learned from it.
+    Guidelines:
+    - One fact, definition, or idea per card
+    - Questions should make sense without the conversation in front of them
+    - Keep answers short--a sentence or two, or a small code snippet
+    - Skip small talk and anything the conversation got wrong and later corrected
+    Respond with _only_ a JSON array of objects with "question" and "answer" string fields
+"#;
+
+fn transcript(conversation: &Conversation) -> String {
+    let mut output = String::from("<conversation>");
+    for message in conversation.messages.iter().filter(|m| {
+        matches!(m.message_type, MessageType::User | MessageType::Assistant)
+            && !m.content.is_empty()
+    }) {
+        let role = message.message_type.to_string();
+        output.push_str(&format!(
+            "<{}>{}</{}>",
+            role,
+            network::strip_reasoning(&message.content),
+            role
+        ));
+    }
+    output.push_str("</conversation>");
+
+    output
+}
+
+// Cards from the model's response, which is expected to be a JSON array
+// but sometimes comes wrapped in a code fence or a sentence of preamble
+pub fn parse_cards(response: &str) -> Result<Vec<Flashcard>, std::io::Error> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+
+    let start = response.find('[');
+    let end = response.rfind(']');
+    let array = match (start, end) {
+        (Some(start), Some(end)) if start < end => &response[start..=end],
+        _ => return Err(invalid("No flashcards in the response".to_string())),
+    };
+
+    let cards = serde_json::from_str::<Vec<Flashcard>>(array)
+        .map_err(|e| invalid(format!("Unreadable flashcards: {}", e)))?
+        .into_iter()
+        .filter(|c| !c.question.trim().is_empty() && !c.answer.trim().is_empty())
+        .collect::<Vec<Flashcard>>();
+
+    if cards.is_empty() {
+        return Err(invalid("No flashcards in the response".to_string()));
+    }
+
+    Ok(cards)
+}
+
+// Fields are read as HTML, so `Vec<T>` has to be escaped to survive
+// Tabs would split the field, and newlines the card
+fn field(text: &str) -> String {
+    text.trim()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\t', "    ")
+        .replace("\r\n", "\n")
+        .replace('\n', "<br>")
+}
+
+// Tags can't have spaces in them
+fn tag(name: &str) -> String {
+    let tag = name.split_whitespace().collect::<Vec<&str>>().join("_");
+
+    if tag.is_empty() {
+        "william".to_string()
+    } else {
+        format!("william::{}", tag)
+    }
+}
+
+pub fn render_tsv(cards: &[Flashcard], conversation_name: &str) -> String {
+    let mut output = String::from("#separator:tab\n#html:true\n#tags column:3\n");
+    let tag = tag(conversation_name);
+    for card in cards {
+        output.push_str(&format!(
+            "{}\t{}\t{}\n",
+            field(&card.question),
+            field(&card.answer),
+            tag
+        ));
+    }
+
+    output
+}
+
+pub async fn generate(
+    conversation: &Conversation,
+    api: API,
+) -> Result<Vec<Flashcard>, std::io::Error> {
+    let message = Message {
+        id: None,
+        message_type: MessageType::User,
+        content: transcript(conversation),
+        api: api.clone(),
+        system_prompt: String::new(),
+        sequence: -1,
+        date_created: String::new(),
+        tool_calls: Vec::new(),
+        tool_call_id: None,
+        attachments: Vec::new(),
+        interrupted: false,
+        language: None,
+        citations: Vec::new(),
+        moderation: None,
+    };
+
+    let (response, _) = network::prompt_deterministic(
+        api,
+        FLASHCARD_PROMPT,
+        &vec![message],
+        &[],
+        queue::Priority::Background,
+    )
+    .await
+    .map_err(network::into_io_error)?;
+
+    let cards = parse_cards(&network::strip_reasoning(&response.content))?;
+    lprint!(
+        info,
+        "Generated {} flashcards for conversation {}",
+        cards.len(),
+        conversation.id.unwrap_or_default()
+    );
+
+    Ok(cards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cards() {
+        let response = "Here you go:\n```json\n[{\"question\": \"What is a lifetime?\", \"answer\": \"A scope for borrows.\"}, {\"question\": \"\", \"answer\": \"dropped\"}]\n```";
+        let cards = parse_cards(response).unwrap();
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].question, "What is a lifetime?");
+
+        assert!(parse_cards("I couldn't find anything to study.").is_err());
+        assert!(parse_cards("[]").is_err());
+        assert!(parse_cards("[{\"q\": \"missing fields\"}]").is_err());
+    }
+
+    #[test]
+    fn test_render_tsv() {
+        let cards = vec![Flashcard {
+            question: "What does `&mut Vec<T>` give you?".to_string(),
+            answer: "Exclusive access\n\tto the value".to_string(),
+        }];
+
+        assert_eq!(
+            render_tsv(&cards, "Rust Lifetimes"),
+            "#separator:tab\n#html:true\n#tags column:3\n\
+             What does `&amp;mut Vec&lt;T&gt;` give you?\tExclusive access<br>    to the value\twilliam::Rust_Lifetimes\n"
+        );
+        assert_eq!(tag("  "), "william");
+    }
+}
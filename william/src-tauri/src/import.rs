@@ -0,0 +1,354 @@
+use crate::types::*;
+
+// Conversations read out of another app's export
+// Timestamps are already in SQLite's `YYYY-MM-DD HH:MM:SS` (UTC) form
+pub struct ImportedConversation {
+    // The conversation's ID in the source app, for skipping re-imports
+    pub external_id: String,
+    pub conversation: Conversation,
+    pub date_created: Option<String>,
+}
+
+// `path` is either the `conversations.json` from the export,
+// or the directory it was unzipped to
+pub fn read(
+    path: &std::path::Path,
+    format: Option<ImportFormat>,
+) -> Result<(ImportFormat, Vec<ImportedConversation>), std::io::Error> {
+    let path = if path.is_dir() {
+        path.join("conversations.json")
+    } else {
+        path.to_path_buf()
+    };
+
+    let contents = std::fs::read_to_string(&path)?;
+    let json: serde_json::Value = serde_json::from_str(&contents)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    let format = match format {
+        Some(f) => f,
+        None => detect_format(&json)?,
+    };
+
+    let conversations = json
+        .as_array()
+        .ok_or(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "expected an array of conversations",
+        ))?
+        .iter()
+        .filter_map(|c| match format {
+            ImportFormat::ChatGPT => read_chatgpt_conversation(c),
+            ImportFormat::Claude => read_claude_conversation(c),
+        })
+        // Nothing to do with empty conversations
+        .filter(|c| !c.conversation.messages.is_empty())
+        .collect();
+
+    Ok((format, conversations))
+}
+
+fn detect_format(json: &serde_json::Value) -> Result<ImportFormat, std::io::Error> {
+    let first = &json[0];
+    if first["mapping"].is_object() {
+        Ok(ImportFormat::ChatGPT)
+    } else if first["chat_messages"].is_array() {
+        Ok(ImportFormat::Claude)
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "unrecognized export format",
+        ))
+    }
+}
+
+fn imported_message(message_type: MessageType, content: String, api: API, date: String) -> Message {
+    Message {
+        id: None,
+        message_type,
+        content,
+        api,
+        system_prompt: String::new(),
+        sequence: -1,
+        date_created: date,
+        tool_calls: Vec::new(),
+        tool_call_id: None,
+        attachments: Vec::new(),
+        interrupted: false,
+        language: None,
+        citations: Vec::new(),
+        moderation: None,
+    }
+}
+
+// ChatGPT conversations are trees of messages (edits + regenerations branch off),
+// so only the branch ending at `current_node`--the one the user last saw--is imported
+fn read_chatgpt_conversation(json: &serde_json::Value) -> Option<ImportedConversation> {
+    let mapping = json["mapping"].as_object()?;
+
+    let mut nodes = Vec::new();
+    let mut current = json["current_node"].as_str();
+    while let Some(id) = current {
+        let node = mapping.get(id)?;
+        nodes.push(node);
+        current = node["parent"].as_str();
+
+        // Malformed exports shouldn't send us around in circles
+        if nodes.len() > mapping.len() {
+            return None;
+        }
+    }
+
+    nodes.reverse();
+
+    let mut messages = Vec::new();
+    for node in nodes {
+        let message = &node["message"];
+        let message_type = match message["author"]["role"].as_str() {
+            Some("user") => MessageType::User,
+            Some("assistant") => MessageType::Assistant,
+            // System + tool messages are ChatGPT internals
+            _ => continue,
+        };
+
+        // Text parts only--images and the like are dropped
+        let content = message["content"]["parts"]
+            .as_array()
+            .map(|parts| {
+                parts
+                    .iter()
+                    .filter_map(|p| p.as_str())
+                    .collect::<Vec<&str>>()
+                    .join("\n")
+            })
+            .unwrap_or_default();
+
+        if content.trim().is_empty() {
+            continue;
+        }
+
+        let api = message["metadata"]["model_slug"]
+            .as_str()
+            .and_then(|slug| API::from_strings("openai", slug).ok())
+            .unwrap_or(API::OpenAI(OpenAIModel::GPT4o));
+
+        let date = message["create_time"]
+            .as_f64()
+            .map(|t| from_unix_timestamp(t as i64))
+            .unwrap_or_default();
+
+        messages.push(imported_message(message_type, content, api, date));
+    }
+
+    Some(ImportedConversation {
+        external_id: json["id"]
+            .as_str()
+            .or(json["conversation_id"].as_str())?
+            .to_string(),
+        conversation: Conversation {
+            id: None,
+            name: json["title"].as_str().unwrap_or("Imported").to_string(),
+            messages,
+            tools: Vec::new(),
+            overrides: ConversationOverrides::default(),
+            branch_id: None,
+            settings: GenerationSettings::default(),
+            pinned: false,
+            archived: false,
+            unread: 0,
+            template: None,
+            memory_scope: MemoryScope::All,
+            related: Vec::new(),
+        },
+        date_created: json["create_time"]
+            .as_f64()
+            .map(|t| from_unix_timestamp(t as i64)),
+    })
+}
+
+// Claude's export doesn't say which model wrote what
+fn read_claude_conversation(json: &serde_json::Value) -> Option<ImportedConversation> {
+    let messages = json["chat_messages"]
+        .as_array()?
+        .iter()
+        .filter_map(|m| {
+            let message_type = match m["sender"].as_str()? {
+                "human" => MessageType::User,
+                "assistant" => MessageType::Assistant,
+                _ => return None,
+            };
+
+            // Newer exports split messages into content blocks, older ones just have `text`
+            let content = match m["content"].as_array() {
+                Some(blocks) if !blocks.is_empty() => blocks
+                    .iter()
+                    .filter(|b| b["type"] == "text")
+                    .filter_map(|b| b["text"].as_str())
+                    .collect::<Vec<&str>>()
+                    .join("\n"),
+                _ => m["text"].as_str().unwrap_or_default().to_string(),
+            };
+
+            if content.trim().is_empty() {
+                return None;
+            }
+
+            Some(imported_message(
+                message_type,
+                content,
+                API::Anthropic(AnthropicModel::Claude35Sonnet),
+                m["created_at"]
+                    .as_str()
+                    .map(from_iso_timestamp)
+                    .unwrap_or_default(),
+            ))
+        })
+        .collect();
+
+    Some(ImportedConversation {
+        external_id: json["uuid"].as_str()?.to_string(),
+        conversation: Conversation {
+            id: None,
+            name: json["name"]
+                .as_str()
+                .filter(|n| !n.is_empty())
+                .unwrap_or("Imported")
+                .to_string(),
+            messages,
+            tools: Vec::new(),
+            overrides: ConversationOverrides::default(),
+            branch_id: None,
+            settings: GenerationSettings::default(),
+            pinned: false,
+            archived: false,
+            unread: 0,
+            template: None,
+            memory_scope: MemoryScope::All,
+            related: Vec::new(),
+        },
+        date_created: json["created_at"].as_str().map(from_iso_timestamp),
+    })
+}
+
+// e.g. `2024-06-01T12:34:56.789Z` -> `2024-06-01 12:34:56`
+// The exports are all in UTC
+fn from_iso_timestamp(timestamp: &str) -> String {
+    timestamp
+        .chars()
+        .take(19)
+        .collect::<String>()
+        .replace('T', " ")
+}
+
+// Unix seconds -> `YYYY-MM-DD HH:MM:SS`
+// Days -> civil date conversion from http://howardhinnant.github.io/date_algorithms.html
+fn from_unix_timestamp(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let seconds = timestamp.rem_euclid(86400);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chatgpt_follows_current_branch() {
+        let json = serde_json::json!([{
+            "id": "abc",
+            "title": "Lifetimes",
+            "create_time": 1700000000.5,
+            "current_node": "c",
+            "mapping": {
+                "root": { "id": "root", "message": null, "parent": null },
+                "a": {
+                    "id": "a",
+                    "parent": "root",
+                    "message": {
+                        "author": { "role": "user" },
+                        "content": { "content_type": "text", "parts": ["What's a lifetime?"] },
+                        "create_time": 1700000000.5
+                    }
+                },
+                "b": {
+                    "id": "b",
+                    "parent": "a",
+                    "message": {
+                        "author": { "role": "assistant" },
+                        "content": { "content_type": "text", "parts": ["An abandoned answer"] },
+                        "metadata": { "model_slug": "gpt-4o" }
+                    }
+                },
+                "c": {
+                    "id": "c",
+                    "parent": "a",
+                    "message": {
+                        "author": { "role": "assistant" },
+                        "content": { "content_type": "text", "parts": ["A scope for borrows."] },
+                        "metadata": { "model_slug": "gpt-4o-mini" }
+                    }
+                }
+            }
+        }]);
+
+        assert_eq!(detect_format(&json).unwrap(), ImportFormat::ChatGPT);
+
+        let imported = read_chatgpt_conversation(&json[0]).unwrap();
+        assert_eq!(imported.external_id, "abc");
+        assert_eq!(imported.date_created.unwrap(), "2023-11-14 22:13:20");
+
+        let messages = &imported.conversation.messages;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].message_type, MessageType::User);
+        assert_eq!(messages[1].content, "A scope for borrows.");
+        assert_eq!(messages[1].api, API::OpenAI(OpenAIModel::GPT4oMini));
+    }
+
+    #[test]
+    fn test_claude_export() {
+        let json = serde_json::json!([{
+            "uuid": "def",
+            "name": "",
+            "created_at": "2024-06-01T12:34:56.789Z",
+            "chat_messages": [
+                { "sender": "human", "text": "Hello", "content": [], "created_at": "2024-06-01T12:34:56.789Z" },
+                {
+                    "sender": "assistant",
+                    "text": "",
+                    "content": [{ "type": "text", "text": "Hi!" }],
+                    "created_at": "2024-06-01T12:35:00.000Z"
+                }
+            ]
+        }]);
+
+        assert_eq!(detect_format(&json).unwrap(), ImportFormat::Claude);
+
+        let imported = read_claude_conversation(&json[0]).unwrap();
+        assert_eq!(imported.conversation.name, "Imported");
+        assert_eq!(imported.conversation.messages.len(), 2);
+        assert_eq!(imported.conversation.messages[1].content, "Hi!");
+        assert_eq!(
+            imported.conversation.messages[1].date_created,
+            "2024-06-01 12:35:00"
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use rusqlite::params;
+
+use chamber_common::{lprint, Logger};
+
+use crate::network;
+use crate::queue;
+use crate::types::*;
+
+// Language detection for user messages, and translated copies of messages on request
+//
+// Languages are stored as ISO 639-3 codes (`eng`, `spa`, ...), as `whatlang` reports them
+
+const TRANSLATION_PROMPT: &str = r#"
+    Translate the user's message into the language given below.
+    Guidelines:
+    - Keep the formatting: markdown, lists, and line breaks stay where they are
+    - Leave code, commands, URLs, and proper nouns untranslated
+    - Respond with _only_ the translation
+    Target language:
+"#;
+
+// Fenced code says little about what language the prose around it is in
+fn prose(text: &str) -> String {
+    text.split("```")
+        .step_by(2)
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+// The language `text` is written in, if it can be told with any confidence
+// Short or mixed messages usually can't, and are left undetected
+pub fn detect(text: &str) -> Option<String> {
+    let info = whatlang::detect(&prose(text))?;
+    if !info.is_reliable() {
+        return None;
+    }
+
+    Some(info.lang().code().to_string())
+}
+
+// The model translations go through--`WILLIAM_TRANSLATION_MODEL` as `provider/model`,
+// otherwise gpt-4o-mini
+pub fn translation_model() -> API {
+    network::model_from_env("WILLIAM_TRANSLATION_MODEL")
+        .unwrap_or(API::OpenAI(OpenAIModel::GPT4oMini))
+}
+
+pub async fn translate(
+    content: &str,
+    target_language: &str,
+    api: API,
+) -> Result<String, std::io::Error> {
+    let message = Message {
+        id: None,
+        message_type: MessageType::User,
+        content: content.to_string(),
+        api: api.clone(),
+        system_prompt: String::new(),
+        sequence: -1,
+        date_created: String::new(),
+        tool_calls: Vec::new(),
+        tool_call_id: None,
+        attachments: Vec::new(),
+        interrupted: false,
+        language: None,
+        citations: Vec::new(),
+        moderation: None,
+    };
+
+    let system_prompt = format!("{}{}", TRANSLATION_PROMPT, target_language);
+    let (response, _) = network::prompt(
+        api,
+        &system_prompt,
+        &vec![message],
+        &[],
+        queue::Priority::Interactive,
+    )
+    .await
+    .map_err(network::into_io_error)?;
+
+    Ok(network::strip_reasoning(&response.content))
+}
+
+// Records the language of each of the given user messages that doesn't have one yet
+pub fn record_languages(messages: &[Message], db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    for message in messages
+        .iter()
+        .filter(|m| m.message_type == MessageType::User)
+    {
+        let (id, language) = match (message.id, detect(&message.content)) {
+            (Some(id), Some(language)) => (id, language),
+            _ => continue,
+        };
+
+        db.execute(
+            "UPDATE messages SET language = ?2 WHERE id = ?1 AND language IS NULL",
+            params![id, language],
+        )?;
+    }
+
+    Ok(())
+}
+
+// Translations are kept per message and target language, so asking again costs nothing
+pub fn get_translation(
+    request: &TranslateRequest,
+    db: &rusqlite::Connection,
+) -> Result<Translation, std::io::Error> {
+    let target_language = request.target_language.trim().to_string();
+    if target_language.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "No target language given",
+        ));
+    }
+
+    let (original, source_language) = match db.query_row(
+        "SELECT content, language FROM messages WHERE id = ?1",
+        params![request.message_id],
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)),
+    ) {
+        Ok(message) => message,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No message with ID {}", request.message_id),
+            ));
+        }
+        Err(e) => return Err(std::io::Error::other(e.to_string())),
+    };
+
+    let cached = db.query_row(
+        "SELECT content FROM translations WHERE message_id = ?1 AND target_language = ?2 COLLATE NOCASE",
+        params![request.message_id, target_language],
+        |row| row.get::<_, String>(0),
+    );
+
+    let content = match cached {
+        Ok(translated) => translated,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            let api = request.api.clone().unwrap_or_else(translation_model);
+            let translated =
+                network::block_on(translate(&original, &target_language, api.clone()))?;
+
+            let model_id =
+                get_model_id(&api, db).map_err(|e| std::io::Error::other(e.to_string()))?;
+            db.execute(
+                "INSERT OR REPLACE INTO translations (message_id, target_language, content, api_config_id, date_created)
+                 VALUES (?1, ?2, ?3, ?4, CURRENT_TIMESTAMP)",
+                params![request.message_id, target_language, translated, model_id],
+            )
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+            lprint!(
+                info,
+                "Translated message {} into {}",
+                request.message_id,
+                target_language
+            );
+
+            translated
+        }
+        Err(e) => return Err(std::io::Error::other(e.to_string())),
+    };
+
+    Ok(Translation {
+        message_id: request.message_id,
+        source_language: source_language.or_else(|| detect(&original)),
+        target_language,
+        content,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            detect("The quick brown fox jumps over the lazy dog, and then it runs away into the forest."),
+            Some("eng".to_string())
+        );
+        assert_eq!(
+            detect("¿Dónde está la biblioteca? Necesito encontrar un libro sobre la historia de España."),
+            Some("spa".to_string())
+        );
+        assert_eq!(detect(""), None);
+    }
+
+    #[test]
+    fn test_code_is_ignored() {
+        assert_eq!(
+            prose("Bonjour\n```rust\nfn main() {}\n```\nau revoir"),
+            "Bonjour\n \nau revoir"
+        );
+    }
+}
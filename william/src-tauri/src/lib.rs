@@ -1,3 +1,5 @@
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
 use rusqlite::params;
 use tauri::async_runtime::spawn;
 use tauri::{TitleBarStyle, WebviewUrl, WebviewWindowBuilder};
@@ -7,23 +9,184 @@ use dewey_lib::Dewey;
 
 use crate::types::*;
 
+mod attachments;
+mod backfill;
+mod backup;
+mod citations;
+mod context;
+mod db;
+mod export;
+mod extract;
+mod flashcards;
+mod import;
+mod language;
+mod memory;
+mod migrations;
+mod moderation;
 mod network;
+mod pdf;
+mod personas;
+mod preprocess;
+#[cfg(test)]
+mod protocol_tests;
+mod queue;
+mod related;
+mod repos;
+mod secrets;
+mod session;
+mod settings;
+mod snapshots;
+mod spans;
+mod stats;
+mod suggestions;
+mod summary;
+mod sync;
+mod templates;
 mod tiktoken;
 mod types;
+mod usage;
+mod validation;
+mod watch;
+#[cfg(test)]
+mod wire_tests;
 
-// TODO: Quit being stupid and properly secure API keys
-
+// Responses are numbered for the connection's session as they go out--see session.rs
 macro_rules! ws_send {
     ($ws:expr, $msg:expr) => {
-        match $ws.write(tungstenite::Message::text($msg)) {
-            Ok(_) => {
-                $ws.flush().unwrap();
-            }
-            Err(e) => {
+        $ws.send(String::from($msg))
+    };
+}
+
+// Safe lock for arc-mutexed elements.
+// This macro exists and is used under the assumption that EVERYTHING it is being used on is
+// _always_ safe from mutex poisoning issues in case of panic
+macro_rules! safe_lock {
+    ($mutex:expr) => {
+        $mutex
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    };
+}
+
+// A websocket connection, as its handlers--and whatever they leave running--see it
+//
+// Clones share the connection, so a completion streaming on a task of its own has its responses
+// numbered (and kept for replay) like everything else in the session
+// Responses are queued on the outbox for the connection's writer task to send,
+// so nothing handling a request waits on the socket
+#[derive(Clone)]
+struct Connection {
+    state: std::sync::Arc<std::sync::Mutex<ConnectionState>>,
+}
+
+struct ConnectionState {
+    // Unset while the session's detached--see `detach`
+    outbox: Option<tokio::sync::mpsc::UnboundedSender<tungstenite::Message>>,
+    session: session::Session,
+    // Completions in flight--see `Claim`
+    completions: Vec<CompletionHandle>,
+    next_key: u64,
+}
+
+impl ConnectionState {
+    // False if the writer's gone, along with the connection, or the session's detached
+    fn write(&self, message: tungstenite::Message) -> bool {
+        match self.outbox.as_ref().map(|outbox| outbox.send(message)) {
+            Some(Ok(_)) => true,
+            Some(Err(e)) => {
                 error!("error writing to websocket: {}", e);
+                false
             }
+            None => false,
         }
-    };
+    }
+}
+
+impl Connection {
+    fn new(outbox: tokio::sync::mpsc::UnboundedSender<tungstenite::Message>) -> Self {
+        Self {
+            state: std::sync::Arc::new(std::sync::Mutex::new(ConnectionState {
+                outbox: Some(outbox),
+                session: session::Session::new(),
+                completions: Vec::new(),
+                next_key: 0,
+            })),
+        }
+    }
+
+    // Stamped with the session's next `seq`
+    // A detached session keeps it for replay all the same
+    fn send(&self, response: String) {
+        let mut state = safe_lock!(self.state);
+        let response = state.session.stamp(response);
+        state.write(tungstenite::Message::text(response));
+    }
+
+    // Goes out as-is, outside of the session's history
+    fn write(&self, message: tungstenite::Message) -> bool {
+        safe_lock!(self.state).write(message)
+    }
+
+    fn session_id(&self) -> String {
+        safe_lock!(self.state).session.id.clone()
+    }
+
+    // Takes over a detached session: whatever it missed goes out, then `ack`,
+    // and from there on, everything the session sends comes through this connection
+    // Returns how many responses were replayed
+    //
+    // The session this connection started with never gets used
+    fn resume<F>(&mut self, detached: Connection, last_seen: u64, ack: F) -> usize
+    where
+        F: FnOnce(usize, bool) -> String,
+    {
+        let outbox = safe_lock!(self.state).outbox.clone();
+        let replayed = {
+            // Held throughout, so nothing the session's completions send can cut in line
+            let mut state = safe_lock!(detached.state);
+            state.outbox = outbox;
+
+            let (missed, complete) = state.session.replay(last_seen);
+            for response in missed.iter() {
+                if !state.write(tungstenite::Message::text(response.clone())) {
+                    break;
+                }
+            }
+
+            state.write(tungstenite::Message::text(ack(missed.len(), complete)));
+            missed.len()
+        };
+
+        *self = detached;
+        replayed
+    }
+}
+
+// Connections are pinged after `HEARTBEAT_INTERVAL` without hearing anything,
+// and dropped once they've been silent for `IDLE_TIMEOUT`
+// Otherwise a frontend that disappeared without closing leaves its task waiting on a read forever
+const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+const IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(45);
+
+// How long a dropped session's completions keep going, waiting on a `Resume`
+const RESUME_GRACE: std::time::Duration = std::time::Duration::from_secs(60);
+
+static CONNECTIONS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+// Counts a connection in `CONNECTIONS` for as long as its task lives, panics included
+struct ConnectionGuard;
+
+impl ConnectionGuard {
+    fn new() -> Self {
+        CONNECTIONS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
 }
 
 // Shorthand for handling an error, and sending back a response
@@ -32,19 +195,27 @@ macro_rules! ws_send {
 //       else
 macro_rules! ws_error {
     ($ws:expr, $error_type:expr, $error_message:expr, $e:expr, $request_id:expr) => {
+        let response = error_response!($error_type, $error_message, $e, $request_id);
+        ws_send!($ws, response);
+    };
+}
+
+// `ws_error!` without the sending, for requests handled off the connection's task
+macro_rules! error_response {
+    ($error_type:expr, $error_message:expr, $e:expr, $request_id:expr) => {{
         let message = format!("{}: {:?}", $error_message, $e);
         lprint!(error, "{}", message);
-        let response = serialize_response!(
+        serialize_response!(
             WilliamError,
             WilliamError {
                 error_type: format!("{}", $error_type), // TODO: what do we put here?
-                message
+                message,
+                details: Vec::new(),
+                provider: None,
             },
             $request_id
-        );
-
-        ws_send!($ws, response);
-    }
+        )
+    }};
 }
 
 // Shorthand for serializing an ArrakisResponse for the websocket
@@ -64,17 +235,6 @@ macro_rules! serialize_response {
     };
 }
 
-// Safe lock for arc-mutexed elements.
-// This macro exists and is used under the assumption that EVERYTHING it is being used on is
-// _always_ safe from mutex poisoning issues in case of panic
-macro_rules! safe_lock {
-    ($mutex:expr) => {
-        $mutex
-            .lock()
-            .unwrap_or_else(|poisoned| poisoned.into_inner())
-    };
-}
-
 // Check if a directory exists, and create if needed
 // Mainly just used in initialization
 fn create_if_nonexistent(path: &std::path::PathBuf) {
@@ -90,6 +250,41 @@ fn get_embeddings_dir() -> std::path::PathBuf {
     get_local_dir().join("messages")
 }
 
+fn get_attachments_dir() -> std::path::PathBuf {
+    get_local_dir().join("attachments")
+}
+
+// Text chunks of files in watched folders--see `watch.rs`
+fn get_watched_dir() -> std::path::PathBuf {
+    get_local_dir().join("watched")
+}
+
+// Text chunks of indexed git repositories--see `repos.rs`
+fn get_repositories_dir() -> std::path::PathBuf {
+    get_local_dir().join("repositories")
+}
+
+// Generated files with nowhere else to go, e.g. flashcard decks
+fn get_exports_dir() -> std::path::PathBuf {
+    get_local_dir().join("exports")
+}
+
+// Copies of the database taken before migrating it--see `migrations.rs`
+fn get_backups_dir() -> std::path::PathBuf {
+    get_local_dir().join("backups")
+}
+
+// A checked backup waiting to replace the state directory on the next start up--see `backup.rs`
+// This sits outside the state directory, since the whole thing gets swapped out
+fn get_restore_dir() -> std::path::PathBuf {
+    get_root_dir().join("restore")
+}
+
+// Text chunks of document attachments, at `<dir>/<attachment hash>/<chunk index>.txt`
+fn get_documents_dir() -> std::path::PathBuf {
+    get_local_dir().join("documents")
+}
+
 fn get_home() -> Option<String> {
     if cfg!(target_os = "windows") {
         // TODO: windows
@@ -102,6 +297,19 @@ fn get_home() -> Option<String> {
     }
 }
 
+// Anything that logs needs the logger, tests included
+// The logger's panic hook is dropped so test failures still print
+#[cfg(test)]
+fn init_test_logger() {
+    chamber_common::Logger::init(
+        std::env::temp_dir()
+            .join(format!("william-tests-{}.log", std::process::id()))
+            .to_str()
+            .unwrap(),
+    );
+    let _ = std::panic::take_hook();
+}
+
 // TODO: a lot of this setup code needs abstracted to a common module
 //
 // Sets up necessary config/local directories and touches required files to keep things from
@@ -121,13 +329,6 @@ fn setup() {
         format!("{}/.local/william", home_dir)
     };
 
-    chamber_common::Workspace::new(&root);
-
-    create_if_nonexistent(&get_local_dir());
-    create_if_nonexistent(&get_embeddings_dir());
-    create_if_nonexistent(&get_config_dir());
-    create_if_nonexistent(&get_root_dir().join("logs"));
-
     let log_name = if cfg!(dev) {
         "debug".to_string()
     } else {
@@ -140,6 +341,27 @@ fn setup() {
         )
     };
 
+    init_workspace(&root, &log_name);
+}
+
+// Everything `setup` does once it knows where the workspace is--
+// the protocol tests point this at a temp dir
+fn init_workspace(root: &str, log_name: &str) {
+    chamber_common::Workspace::new(root);
+
+    // Has to happen before anything opens the database or Dewey's files
+    let restored = backup::apply_staged(&get_restore_dir(), &get_local_dir());
+
+    create_if_nonexistent(&get_local_dir());
+    create_if_nonexistent(&get_embeddings_dir());
+    create_if_nonexistent(&get_attachments_dir());
+    create_if_nonexistent(&get_documents_dir());
+    create_if_nonexistent(&get_exports_dir());
+    create_if_nonexistent(&get_watched_dir());
+    create_if_nonexistent(&get_repositories_dir());
+    create_if_nonexistent(&get_config_dir());
+    create_if_nonexistent(&get_root_dir().join("logs"));
+
     // TODO: proper logging, obviously
     chamber_common::Logger::init(
         get_root_dir()
@@ -148,6 +370,20 @@ fn setup() {
             .to_str()
             .unwrap(),
     );
+
+    match restored {
+        Ok(Some(previous)) => {
+            lprint!(
+                info,
+                "Restored backup; the previous state was moved to {}",
+                previous.display()
+            );
+        }
+        Ok(None) => {}
+        Err(e) => {
+            lprint!(error, "Error restoring backup: {}; ignoring", e);
+        }
+    };
 }
 
 fn is_valid_guid(guid: &str) -> bool {
@@ -173,7 +409,8 @@ fn is_valid_guid(guid: &str) -> bool {
 }
 
 // DB initialization statement
-// Creates the necessary tables and whatnot and is executed at start up each time
+// Creates the necessary tables and whatnot as of the baseline migration
+// Later schema changes go in `DB_MIGRATIONS` instead of here
 const DB_SETUP_STATEMENTS: &str = r#"
 CREATE TABLE IF NOT EXISTS message_types (
     id INTEGER PRIMARY KEY,
@@ -192,6 +429,10 @@ INSERT INTO message_types (name)
 SELECT 'assistant'
 WHERE NOT EXISTS (SELECT 1 FROM message_types WHERE name = 'assistant');
 
+INSERT INTO message_types (name)
+SELECT 'tool'
+WHERE NOT EXISTS (SELECT 1 FROM message_types WHERE name = 'tool');
+
 CREATE TABLE IF NOT EXISTS providers (
     name TEXT PRIMARY KEY
 );
@@ -208,6 +449,22 @@ INSERT INTO providers (name)
 SELECT 'anthropic'
 WHERE NOT EXISTS (SELECT 1 FROM providers WHERE name = 'anthropic');
 
+INSERT INTO providers (name)
+SELECT 'deepseek'
+WHERE NOT EXISTS (SELECT 1 FROM providers WHERE name = 'deepseek');
+
+INSERT INTO providers (name)
+SELECT 'together'
+WHERE NOT EXISTS (SELECT 1 FROM providers WHERE name = 'together');
+
+INSERT INTO providers (name)
+SELECT 'fireworks'
+WHERE NOT EXISTS (SELECT 1 FROM providers WHERE name = 'fireworks');
+
+INSERT INTO providers (name)
+SELECT 'local'
+WHERE NOT EXISTS (SELECT 1 FROM providers WHERE name = 'local');
+
 CREATE TABLE IF NOT EXISTS models (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     name TEXT,
@@ -235,6 +492,22 @@ INSERT INTO models (name, provider)
 SELECT 'llama3-70b-8192', 'groq'
 WHERE NOT EXISTS (SELECT 1 FROM models WHERE name = 'llama3-70b-8192' AND provider = 'groq');
 
+INSERT INTO models (name, provider)
+SELECT 'llama-3.3-70b-versatile', 'groq'
+WHERE NOT EXISTS (SELECT 1 FROM models WHERE name = 'llama-3.3-70b-versatile' AND provider = 'groq');
+
+INSERT INTO models (name, provider)
+SELECT 'llama-3.1-8b-instant', 'groq'
+WHERE NOT EXISTS (SELECT 1 FROM models WHERE name = 'llama-3.1-8b-instant' AND provider = 'groq');
+
+INSERT INTO models (name, provider)
+SELECT 'mixtral-8x7b-32768', 'groq'
+WHERE NOT EXISTS (SELECT 1 FROM models WHERE name = 'mixtral-8x7b-32768' AND provider = 'groq');
+
+INSERT INTO models (name, provider)
+SELECT 'gemma2-9b-it', 'groq'
+WHERE NOT EXISTS (SELECT 1 FROM models WHERE name = 'gemma2-9b-it' AND provider = 'groq');
+
 INSERT INTO models (name, provider)
 SELECT 'claude-3-opus-20240229', 'anthropic'
 WHERE NOT EXISTS (SELECT 1 FROM models WHERE name = 'claude-3-opus-20240229' AND provider = 'anthropic');
@@ -255,6 +528,14 @@ INSERT INTO models (name, provider)
 SELECT 'claude-3-5-haiku-latest', 'anthropic'
 WHERE NOT EXISTS (SELECT 1 FROM models WHERE name = 'claude-3-5-haiku-latest' AND provider = 'anthropic');
 
+INSERT INTO models (name, provider)
+SELECT 'deepseek-chat', 'deepseek'
+WHERE NOT EXISTS (SELECT 1 FROM models WHERE name = 'deepseek-chat' AND provider = 'deepseek');
+
+INSERT INTO models (name, provider)
+SELECT 'deepseek-reasoner', 'deepseek'
+WHERE NOT EXISTS (SELECT 1 FROM models WHERE name = 'deepseek-reasoner' AND provider = 'deepseek');
+
 CREATE TABLE IF NOT EXISTS conversations (
     id INTEGER PRIMARY KEY,
     name TEXT NOT NULL,
@@ -288,6 +569,19 @@ CREATE TABLE IF NOT EXISTS paths (
     FOREIGN KEY (message_id) REFERENCES messages(id)
 );
 
+-- Each branch is its own path through a conversation,
+-- sharing message rows with its parent up to the point it forked off
+CREATE TABLE IF NOT EXISTS branches (
+    id INTEGER PRIMARY KEY,
+    conversation_id INTEGER NOT NULL,
+    parent_branch_id INTEGER,
+    fork_sequence INTEGER,
+    date_created TIMESTAMP NOT NULL,
+    FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE,
+    FOREIGN KEY (parent_branch_id) REFERENCES branches(id) ON DELETE CASCADE
+);
+
+-- Forks from before branches, which copied the conversation into a new one
 CREATE TABLE IF NOT EXISTS forks (
     id INTEGER PRIMARY KEY,
     from_id INTEGER NOT NULL,
@@ -296,6 +590,140 @@ CREATE TABLE IF NOT EXISTS forks (
     FOREIGN KEY (to_id) REFERENCES conversations(id) ON DELETE CASCADE
 );
 
+-- Token usage reported by the provider for each assistant message
+CREATE TABLE IF NOT EXISTS usage (
+    id INTEGER PRIMARY KEY,
+    message_id INTEGER NOT NULL UNIQUE,
+    input_tokens INTEGER NOT NULL,
+    output_tokens INTEGER NOT NULL,
+    -- 'provider' when reported by the provider, otherwise the tokenizer that estimated it
+    estimator TEXT NOT NULL DEFAULT 'provider',
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
+
+-- Sampling parameters a response was generated with
+-- Only stored for responses where something was set
+CREATE TABLE IF NOT EXISTS generation_settings (
+    id INTEGER PRIMARY KEY,
+    message_id INTEGER NOT NULL UNIQUE,
+    temperature REAL,
+    top_p REAL,
+    max_tokens INTEGER,
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
+
+-- Saved system prompts, with the model and settings to use them with
+CREATE TABLE IF NOT EXISTS personas (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    system_prompt TEXT NOT NULL,
+    default_api_config_id INTEGER,
+    temperature REAL,
+    top_p REAL,
+    max_tokens INTEGER,
+    date_created TIMESTAMP NOT NULL,
+    FOREIGN KEY (default_api_config_id) REFERENCES models(id)
+);
+
+-- Translated copies of messages, one per target language
+CREATE TABLE IF NOT EXISTS translations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_id INTEGER NOT NULL,
+    target_language TEXT NOT NULL COLLATE NOCASE,
+    content TEXT NOT NULL,
+    api_config_id INTEGER NOT NULL,
+    date_created TIMESTAMP NOT NULL,
+    UNIQUE(message_id, target_language),
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY (api_config_id) REFERENCES models(id)
+);
+
+-- Reusable prompts with `{{variable}}` placeholders
+CREATE TABLE IF NOT EXISTS prompt_templates (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    body TEXT NOT NULL,
+    date_created TIMESTAMP NOT NULL
+);
+
+-- Attachment contents live on disk under their SHA-256, shared by every message that references them
+CREATE TABLE IF NOT EXISTS attachments (
+    hash TEXT PRIMARY KEY,
+    size INTEGER NOT NULL,
+    mime_type TEXT NOT NULL,
+    ref_count INTEGER NOT NULL DEFAULT 0,
+    date_created TIMESTAMP NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS message_attachments (
+    id INTEGER PRIMARY KEY,
+    message_id INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    name TEXT NOT NULL,
+    UNIQUE (message_id, hash),
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY (hash) REFERENCES attachments(hash)
+);
+
+-- Embedded pieces of document attachments, shared by every message attaching the same file
+CREATE TABLE IF NOT EXISTS document_chunks (
+    id INTEGER PRIMARY KEY,
+    hash TEXT NOT NULL,
+    chunk_index INTEGER NOT NULL,
+    filepath TEXT NOT NULL,
+    UNIQUE (hash, chunk_index),
+    FOREIGN KEY (hash) REFERENCES attachments(hash)
+);
+
+-- Conversations brought in from other apps' exports, so re-importing doesn't duplicate them
+CREATE TABLE IF NOT EXISTS imports (
+    id INTEGER PRIMARY KEY,
+    source TEXT NOT NULL,
+    external_id TEXT NOT NULL,
+    conversation_id INTEGER NOT NULL,
+    UNIQUE (source, external_id),
+    FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE
+);
+
+-- Directories kept indexed as references, and the ledger of what's been embedded from them
+CREATE TABLE IF NOT EXISTS watched_folders (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    last_scanned TIMESTAMP,
+    date_created TIMESTAMP NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS watched_files (
+    id INTEGER PRIMARY KEY,
+    folder_id INTEGER NOT NULL,
+    path TEXT NOT NULL UNIQUE,
+    size INTEGER NOT NULL,
+    modified INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    chunks INTEGER NOT NULL,
+    date_indexed TIMESTAMP NOT NULL,
+    FOREIGN KEY (folder_id) REFERENCES watched_folders(id)
+);
+
+-- Git repositories indexed with `IndexRepo`, and what's been embedded from each
+CREATE TABLE IF NOT EXISTS repositories (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    path TEXT NOT NULL UNIQUE,
+    last_indexed TIMESTAMP,
+    date_created TIMESTAMP NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS repository_files (
+    id INTEGER PRIMARY KEY,
+    repository_id INTEGER NOT NULL,
+    relative_path TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    chunks INTEGER NOT NULL,
+    UNIQUE (repository_id, relative_path),
+    FOREIGN KEY (repository_id) REFERENCES repositories(id)
+);
+
 CREATE TABLE IF NOT EXISTS user_config (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     system_prompt TEXT,
@@ -307,711 +735,7723 @@ CREATE TABLE IF NOT EXISTS user_config (
 );
 "#;
 
-// TODO: optimize this
-//       this should be done in batch
-//
-// TODO: there should probably be some decoupling
-//       between Dewey and the SQLite db
+// Columns added to the tables above after their initial creation
+// `CREATE TABLE IF NOT EXISTS` never touches existing tables, so each of these is checked against
+// the table's schema and added if it's missing
+// Part of the baseline migration--new columns get their own migration
 //
-// Embeds a message if it's not already embedded through Dewey
-// TODO: What's the case in which it's already embedded?
-fn add_message_embedding(
-    // This is really gross
-    // The Option<> gymnastics here are really just remarkably stupid
-    dewey: &mut Option<&mut Dewey>,
-    db: &rusqlite::Connection,
-    message: &Message,
-    filepath: &str,
-) -> Result<(), std::io::Error> {
-    if dewey.is_none() {
-        lprint!(info, "Dewey unavailable, ignoring embedding request");
-        return Ok(());
-    }
-
-    let dewey = dewey.as_mut();
+// (table, column, column definition)
+const DB_COLUMN_ADDITIONS: &[(&str, &str, &str)] = &[
+    ("conversations", "tools", "TEXT NOT NULL DEFAULT '[]'"),
+    (
+        "conversations",
+        "repositories",
+        "TEXT NOT NULL DEFAULT '[]'",
+    ),
+    ("messages", "tool_calls", "TEXT NOT NULL DEFAULT '[]'"),
+    ("messages", "tool_call_id", "TEXT"),
+    ("messages", "citations", "TEXT NOT NULL DEFAULT '[]'"),
+    ("user_config", "max_retries", "INTEGER NOT NULL DEFAULT 3"),
+    (
+        "user_config",
+        "search_fusion",
+        "TEXT NOT NULL DEFAULT 'rrf'",
+    ),
+    ("user_config", "deepseek_key", "TEXT NOT NULL DEFAULT ''"),
+    ("user_config", "together_key", "TEXT NOT NULL DEFAULT ''"),
+    ("user_config", "fireworks_key", "TEXT NOT NULL DEFAULT ''"),
+    (
+        "conversations",
+        "default_api_config_id",
+        "INTEGER REFERENCES models(id)",
+    ),
+    ("conversations", "temperature", "REAL"),
+    ("conversations", "system_prompt", "TEXT"),
+    (
+        "paths",
+        "branch_id",
+        "INTEGER REFERENCES branches(id) ON DELETE CASCADE",
+    ),
+    ("conversations", "active_branch_id", "INTEGER"),
+    ("usage", "estimator", "TEXT NOT NULL DEFAULT 'provider'"),
+    ("conversations", "deleted_at", "TIMESTAMP"),
+    ("conversations", "pinned", "INTEGER NOT NULL DEFAULT 0"),
+    ("conversations", "archived", "INTEGER NOT NULL DEFAULT 0"),
+    (
+        "conversations",
+        "last_read_message_id",
+        "INTEGER REFERENCES messages(id)",
+    ),
+    ("conversations", "summary", "TEXT"),
+    ("branches", "name", "TEXT"),
+    (
+        "conversations",
+        "summary_message_id",
+        "INTEGER REFERENCES messages(id)",
+    ),
+    ("user_config", "local_endpoint", "TEXT NOT NULL DEFAULT ''"),
+    ("messages", "finish_reason", "TEXT DEFAULT 'stop'"),
+    (
+        "user_config",
+        "trash_retention_days",
+        "INTEGER NOT NULL DEFAULT 30",
+    ),
+    ("models", "available", "INTEGER NOT NULL DEFAULT 1"),
+    (
+        "conversations",
+        "persona_id",
+        "INTEGER REFERENCES personas(id)",
+    ),
+    ("messages", "time_to_first_token_ms", "INTEGER"),
+    ("messages", "generation_ms", "INTEGER"),
+    ("messages", "language", "TEXT"),
+];
+
+// Conversations from before branches get a main branch holding their existing path
+const BRANCH_MIGRATION_STATEMENTS: &str = r#"
+INSERT INTO branches (conversation_id, date_created)
+SELECT c.id, c.date_created
+FROM conversations c
+WHERE NOT EXISTS (SELECT 1 FROM branches b WHERE b.conversation_id = c.id);
+
+UPDATE paths
+SET branch_id = (SELECT MIN(b.id) FROM branches b WHERE b.conversation_id = paths.conversation_id)
+WHERE branch_id IS NULL;
+
+UPDATE conversations
+SET active_branch_id = (SELECT MIN(b.id) FROM branches b WHERE b.conversation_id = conversations.id)
+WHERE active_branch_id IS NULL;
+"#;
 
-    let exists: bool = db
-        .query_row(
-            "SELECT 1 FROM message_embeddings WHERE message_id = ?1 LIMIT 1",
-            params![message.id],
-            |_row| Ok(true),
-        )
-        .unwrap_or(false);
+// Full-text search index over message content
+// This is an external content table--the text itself lives in `messages`,
+// and the triggers keep the index in sync with it
+const FTS_SETUP_STATEMENTS: &str = r#"
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+    content,
+    content='messages',
+    content_rowid='id'
+);
 
-    if exists {
-        return Ok(());
-    }
+CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+    INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+END;
 
-    std::fs::write(filepath, message.content.clone())?;
+CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+    INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+END;
 
-    db.execute(
-        "INSERT INTO message_embeddings (message_id, filepath) VALUES (?1, ?2)",
-        params![message.id, filepath],
-    )
-    .unwrap();
+CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+    INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+    INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+END;
+"#;
 
-    // TODO: conversations need cleaned before being processed
-    match dewey.unwrap().add_embedding(filepath.to_string()) {
-        Ok(_) => {}
-        Err(e) => {
-            lprint!(error, "Error processing message {}: {}", filepath, e);
-            if let Err(cleanup_err) = std::fs::remove_file(&filepath) {
-                lprint!(
-                    error,
-                    "Failed to remove file after embedding error: {}",
-                    cleanup_err
-                );
-            }
-        }
-    };
+fn setup_search_index(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let exists = db
+        .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'messages_fts'")?
+        .exists(params![])?;
 
-    Ok(())
-}
+    db.execute_batch(FTS_SETUP_STATEMENTS)?;
 
-// Basic prompt builder. Uses embedding memory and XML to structure prompts.
-// TODO: This could probably be abstracted out to a more general prompt builder, but I can't see
-//       the metastructure at the moment
-fn build_system_prompt(
-    conversation_len: usize,
-    dewey_sources: &Vec<dewey_lib::EmbeddingSource>,
-    tokenizer: Option<&tiktoken::Tokenizer>,
-) -> String {
-    let mut prompt = "<systemPrompt>".to_string();
-    prompt.push_str(r#"
-        <objective>
-            Determine whether to use the following references to inform your response, and do so without explicitly acknowledging it.
-            Incorporate into your judgment whether this moves the conversation forward, in the same direction as the user.
-            If you decide to use it, do so in a friendly, familiar manner--leave what should stay unsaid, but implicitly acknowledge the history.
-            If reasonable, try and use the references to fill in contextual gaps.
-        </objective>
-    "#);
+    // Messages from before the index existed
+    if !exists {
+        db.execute(
+            "INSERT INTO messages_fts (messages_fts) VALUES ('rebuild')",
+            params![],
+        )?;
 
-    prompt.push_str("<references>");
-    for source in dewey_sources {
-        let prompt_len = if let Some(tok) = tokenizer {
-            tok.encode(&prompt).len()
-        } else {
-            prompt.len()
-        };
+        lprint!(info, "Built full-text search index");
+    }
 
-        if conversation_len + prompt_len > 128000 {
-            break;
-        }
+    Ok(())
+}
 
-        // TODO: error handling
-        let contents = std::fs::read_to_string(&source.filepath).unwrap();
+// Everything the schema setup did before migrations were versioned
+// Each step is idempotent, so it's safe on installs from any point before this
+fn baseline_schema(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(DB_SETUP_STATEMENTS)?;
+    add_missing_columns(db)?;
+    db.execute_batch(BRANCH_MIGRATION_STATEMENTS)?;
+    setup_search_index(db)
+}
 
-        let contents = contents.chars().take(512).collect::<String>();
-        prompt.push_str(&format!("<reference>{}</reference>", contents));
-    }
+// `paths` and `message_embeddings` predate cascading deletes, so deleting a message
+// would fail while anything still pointed at it now that foreign keys are enforced
+// SQLite can't change a column's constraints in place, so both tables are rebuilt
+//
+// Rows pointing at things deleted back when foreign keys weren't enforced are dropped,
+// since they'd fail the new table's constraints
+const CASCADE_MESSAGE_DELETES_STATEMENTS: &str = r#"
+CREATE TABLE paths_new (
+    id INTEGER PRIMARY KEY,
+    conversation_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    sequence INTEGER NOT NULL,
+    branch_id INTEGER REFERENCES branches(id) ON DELETE CASCADE,
+    FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE,
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
 
-    prompt.push_str("</references>");
-    prompt.push_str("</systemPrompt>");
+INSERT INTO paths_new (id, conversation_id, message_id, sequence, branch_id)
+SELECT id, conversation_id, message_id, sequence, branch_id
+FROM paths
+WHERE conversation_id IN (SELECT id FROM conversations)
+AND message_id IN (SELECT id FROM messages)
+AND (branch_id IS NULL OR branch_id IN (SELECT id FROM branches));
+
+DROP TABLE paths;
+ALTER TABLE paths_new RENAME TO paths;
+
+CREATE TABLE message_embeddings_new (
+    id INTEGER PRIMARY KEY,
+    message_id INTEGER NOT NULL,
+    filepath TEXT NOT NULL,
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
+
+INSERT INTO message_embeddings_new (id, message_id, filepath)
+SELECT id, message_id, filepath
+FROM message_embeddings
+WHERE message_id IN (SELECT id FROM messages);
 
-    prompt
+DROP TABLE message_embeddings;
+ALTER TABLE message_embeddings_new RENAME TO message_embeddings;
+"#;
+
+fn cascade_message_deletes(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(CASCADE_MESSAGE_DELETES_STATEMENTS)
 }
 
-// TODO: this needs to be accommodated for the high context windows
+// Sync IDs are shared across devices, since row IDs only mean anything locally
+// They're handed out on the first sync, so they stay `NULL` for anyone who never syncs
 //
-// Function to keep the conversation within context window limits. Returns the correct conversation
-// history to use for the prompt.
-fn cutoff_messages(
-    messages: &Vec<Message>,
-    tokenizer: Option<&tiktoken::Tokenizer>,
-) -> (usize, Vec<Message>) {
-    let mut cutoff = messages.len() - 1;
-    let mut total_len = 0;
-    for m in messages.iter().rev() {
-        if m.content.is_empty() {
-            continue;
-        }
+// `sync_state` only ever has the one row--the token and key are encrypted like API keys
+// `sync_cursors` is how many of each other device's deltas have been applied here
+const SYNC_STATEMENTS: &str = r#"
+ALTER TABLE conversations ADD COLUMN sync_id TEXT;
+ALTER TABLE messages ADD COLUMN sync_id TEXT;
+CREATE UNIQUE INDEX conversations_sync_id ON conversations(sync_id) WHERE sync_id IS NOT NULL;
+CREATE UNIQUE INDEX messages_sync_id ON messages(sync_id) WHERE sync_id IS NOT NULL;
+
+CREATE TABLE sync_state (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    endpoint TEXT NOT NULL,
+    token TEXT,
+    key TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    last_push TIMESTAMP,
+    last_sync TIMESTAMP
+);
 
-        total_len += if let Some(tok) = tokenizer {
-            tok.encode(&m.content).len()
-        } else {
-            m.content.len()
-        };
+CREATE TABLE sync_cursors (
+    device_id TEXT PRIMARY KEY,
+    applied INTEGER NOT NULL
+);
+"#;
 
-        // TODO: centralize context window limits for each model
-        if total_len < 128000 {
-            cutoff = std::cmp::max(0, cutoff - 1);
+fn add_sync(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(SYNC_STATEMENTS)
+}
+
+const MODERATION_STATEMENTS: &str = r#"
+ALTER TABLE messages ADD COLUMN moderation TEXT;
+ALTER TABLE user_config ADD COLUMN moderation TEXT NOT NULL DEFAULT 'off';
+ALTER TABLE user_config ADD COLUMN moderation_block INTEGER NOT NULL DEFAULT 0;
+"#;
+
+fn add_moderation(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(MODERATION_STATEMENTS)
+}
+
+const WEBSOCKET_ADDRESS_STATEMENTS: &str = r#"
+ALTER TABLE user_config ADD COLUMN bind_address TEXT NOT NULL DEFAULT '127.0.0.1';
+ALTER TABLE user_config ADD COLUMN port INTEGER NOT NULL DEFAULT 9001;
+"#;
+
+fn add_websocket_address(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(WEBSOCKET_ADDRESS_STATEMENTS)
+}
+
+fn add_suggestions(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch("ALTER TABLE conversations ADD COLUMN suggestions INTEGER NOT NULL DEFAULT 0;")
+}
+
+const SNAPSHOT_STATEMENTS: &str = r#"
+CREATE TABLE snapshots (
+    id INTEGER PRIMARY KEY,
+    conversation_id INTEGER NOT NULL,
+    branch_id INTEGER,
+    label TEXT NOT NULL,
+    date_created TIMESTAMP NOT NULL,
+    FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE,
+    FOREIGN KEY (branch_id) REFERENCES branches(id) ON DELETE SET NULL
+);
+
+CREATE TABLE snapshot_messages (
+    snapshot_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    sequence INTEGER NOT NULL,
+    PRIMARY KEY (snapshot_id, sequence),
+    FOREIGN KEY (snapshot_id) REFERENCES snapshots(id) ON DELETE CASCADE,
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
+"#;
+
+fn add_snapshots(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(SNAPSHOT_STATEMENTS)
+}
+
+fn add_empty_conversation_hours(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "ALTER TABLE user_config ADD COLUMN empty_conversation_hours INTEGER NOT NULL DEFAULT 24;",
+    )
+}
+
+fn add_max_message_chars(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "ALTER TABLE user_config ADD COLUMN max_message_chars INTEGER NOT NULL DEFAULT 20000;",
+    )
+}
+
+fn add_reference_threshold(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "ALTER TABLE user_config ADD COLUMN reference_threshold REAL NOT NULL DEFAULT 0.25;",
+    )
+}
+
+// Responses used to be written over their prompt's embedding file,
+// so the prompt's row ended up pointing at the response--those rows are dropped,
+// for the backfill to embed the prompts again on their own
+const EMBEDDING_KINDS_STATEMENTS: &str = r#"
+ALTER TABLE message_embeddings ADD COLUMN kind TEXT NOT NULL DEFAULT 'message';
+
+DELETE FROM message_embeddings
+WHERE id IN (
+    SELECT me.id
+    FROM message_embeddings me
+    JOIN messages m ON m.id = me.message_id
+    JOIN message_types mt ON mt.id = m.message_type_id
+    WHERE mt.name = 'user'
+    AND EXISTS (
+        SELECT 1 FROM message_embeddings o WHERE o.filepath = me.filepath AND o.id != me.id
+    )
+);
+"#;
+
+fn add_embedding_kinds(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(EMBEDDING_KINDS_STATEMENTS)
+}
+
+// Hashes of the cleaned text each row embeds--rows embedded before cleaning have none
+fn add_embedding_hashes(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "
+        ALTER TABLE message_embeddings ADD COLUMN content_hash TEXT;
+        CREATE INDEX IF NOT EXISTS idx_message_embeddings_hash ON message_embeddings(content_hash);
+        ",
+    )
+}
+
+// Messages whose memories were forgotten, which the backfill leaves alone--see memory.rs
+fn add_forgotten_messages(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS forgotten_messages (
+            message_id INTEGER PRIMARY KEY,
+            date_forgotten TIMESTAMP NOT NULL,
+            FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+        );
+        ",
+    )
+}
+
+// `message_types` was seeded in insertion order, leaving its IDs off by one from `MessageType::id`--
+// which is what messages are stored with--so joining on it by name found the wrong messages
+const MESSAGE_TYPE_IDS_STATEMENTS: &str = r#"
+UPDATE message_types SET name = 'old_' || name;
+
+INSERT OR IGNORE INTO message_types (id, name)
+VALUES (0, 'system'), (1, 'user'), (2, 'assistant'), (3, 'developer'), (4, 'tool');
+
+UPDATE message_types
+SET name = CASE id
+    WHEN 0 THEN 'system'
+    WHEN 1 THEN 'user'
+    WHEN 2 THEN 'assistant'
+    WHEN 3 THEN 'developer'
+    WHEN 4 THEN 'tool'
+END
+WHERE id BETWEEN 0 AND 4;
+"#;
+
+fn fix_message_type_ids(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(MESSAGE_TYPE_IDS_STATEMENTS)
+}
+
+// Follow-up conversations, linked by `related::link`
+fn add_related_conversations(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS related_conversations (
+            conversation_id INTEGER NOT NULL,
+            related_id INTEGER NOT NULL,
+            similarity REAL NOT NULL,
+            date_created TIMESTAMP NOT NULL,
+            PRIMARY KEY (conversation_id, related_id),
+            FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE,
+            FOREIGN KEY (related_id) REFERENCES conversations(id) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_related_conversations_related ON related_conversations(related_id);
+        ",
+    )
+}
+
+// Schema changes in the order they're applied--only ever append to this
+const DB_MIGRATIONS: &[migrations::Migration] = &[
+    migrations::Migration {
+        description: "Baseline schema",
+        apply: baseline_schema,
+    },
+    migrations::Migration {
+        description: "Cascade message deletes",
+        apply: cascade_message_deletes,
+    },
+    migrations::Migration {
+        description: "Sync",
+        apply: add_sync,
+    },
+    migrations::Migration {
+        description: "Moderation",
+        apply: add_moderation,
+    },
+    migrations::Migration {
+        description: "Websocket address",
+        apply: add_websocket_address,
+    },
+    migrations::Migration {
+        description: "Follow-up suggestions",
+        apply: add_suggestions,
+    },
+    migrations::Migration {
+        description: "Snapshots",
+        apply: add_snapshots,
+    },
+    migrations::Migration {
+        description: "Empty conversation cleanup",
+        apply: add_empty_conversation_hours,
+    },
+    migrations::Migration {
+        description: "Embedding kinds",
+        apply: add_embedding_kinds,
+    },
+    migrations::Migration {
+        description: "Embedding deduplication",
+        apply: add_embedding_hashes,
+    },
+    migrations::Migration {
+        description: "Forgotten memories",
+        apply: add_forgotten_messages,
+    },
+    migrations::Migration {
+        description: "Message type IDs",
+        apply: fix_message_type_ids,
+    },
+    migrations::Migration {
+        description: "Message size limit",
+        apply: add_max_message_chars,
+    },
+    migrations::Migration {
+        description: "Reference threshold",
+        apply: add_reference_threshold,
+    },
+    migrations::Migration {
+        description: "Related conversations",
+        apply: add_related_conversations,
+    },
+];
+
+fn add_missing_columns(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    for (table, column, definition) in DB_COLUMN_ADDITIONS {
+        let exists = db
+            .prepare(&format!(
+                "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+                table
+            ))?
+            .exists(params![column])?;
+
+        if !exists {
+            db.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+                params![],
+            )?;
+
+            lprint!(info, "Added column {}.{}", table, column);
         }
     }
 
-    (total_len, messages[cutoff..].to_vec())
+    Ok(())
 }
 
-// Get a simple name of the conversation from GPT4oMini
-// based on the initial message in the conversation
+// What a `message_embeddings` row embeds:
+// - `message`: just the message
+// - `exchange`: a response along with the prompt it answered, so references can bring in
+//   the whole exchange--see `conversation_chunks`
 //
-// If the user doesn't have an OpenAI API key registered,
-// just use the first 20 characters of the conversation
-fn generate_name(conversation: &mut Conversation) {
-    // TODO: this needs to be async
-    if is_valid_guid(&conversation.name) {
-        let first_message = conversation.messages[0].clone();
-        let new_name = if let Ok(_) = std::env::var("OPENAI_API_KEY") {
-            network::prompt(
-                API::OpenAI(OpenAIModel::GPT4oMini),
-                &r#"
-            You will be given the start of a conversation.
-            Give it a name.
-            Guidelines:
-            - No markdown
-            - Respond with _only_ the name.
-            "#
-                .to_string(),
-                &vec![first_message],
-            )
-            .unwrap()
-            .content
-        } else {
-            first_message.content[..std::cmp::min(20, first_message.content.len())].to_string()
-        };
+// Every row gets a file of its own, from `new_embedding_path`
+const MESSAGE_EMBEDDING: &str = "message";
+const EXCHANGE_EMBEDDING: &str = "exchange";
 
-        conversation.name = new_name
-            .chars()
-            .map(|c| match c {
-                '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
-                c if c.is_alphanumeric() || c == '.' || c == '-' || c == ' ' => c,
-                _ => '_',
-            })
-            .collect();
-    }
+// Dewey meta tag for embeddings of messages from the conversation, e.g. `conversation:12`
+fn conversation_tag(conversation_id: i64) -> String {
+    format!("conversation:{}", conversation_id)
+}
+
+fn new_embedding_path() -> String {
+    get_embeddings_dir()
+        .join(uuid::Uuid::new_v4().to_string())
+        .to_string_lossy()
+        .to_string()
+}
+
+// Whether exchanges are embedded along with their messages, from `WILLIAM_CONVERSATION_CHUNKS`
+fn conversation_chunks() -> bool {
+    std::env::var("WILLIAM_CONVERSATION_CHUNKS").is_ok_and(|v| v == "true")
 }
 
-// TODO: error handling for the results here
+// TODO: there should probably be some decoupling
+//       between Dewey and the SQLite db
 //
-// NOTE: this _does not_ create a new message for the response
-//       the last message in the conversation is expected to be
-//       a placeholder to be filled here for the Assistant
-fn completion(
-    websocket: &mut tungstenite::WebSocket<std::net::TcpStream>,
-    request_id: &str,
-    mut conversation: Conversation,
-    tokenizer: Option<&tiktoken::Tokenizer>,
+// Embeds a message if it's not already embedded through Dewey
+// TODO: What's the case in which it's already embedded?
+fn add_message_embedding(
+    dewey: &std::sync::Mutex<Option<Dewey>>,
     db: &rusqlite::Connection,
-    mut dewey: Option<&mut Dewey>,
-) {
-    generate_name(&mut conversation);
+    message: &Message,
+    filepath: &str,
+) -> Result<(), std::io::Error> {
+    if safe_lock!(dewey).is_none() {
+        lprint!(info, "Dewey unavailable, ignoring embedding request");
+        return Ok(());
+    }
 
-    // the conversation needs to be set with a db ID at this point
-    conversation.upsert(db).unwrap();
+    let mut queue = EmbeddingQueue::default();
+    queue.push(
+        db,
+        message.id,
+        MESSAGE_EMBEDDING,
+        &message.content,
+        filepath,
+    )?;
+    queue.flush(db, dewey);
 
-    let (total_len, messages_payload) = cutoff_messages(&conversation.messages, tokenizer);
+    Ok(())
+}
 
-    // The conversation has to have at least one message from the user
-    // TODO: This might change later
-    let last_user_message = messages_payload
+// Embeds a finished response, plus its exchange if `conversation_chunks` is on
+fn add_response_embeddings(
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+    db: &rusqlite::Connection,
+    conversation: &Conversation,
+) -> Result<(), std::io::Error> {
+    if safe_lock!(dewey).is_none() {
+        lprint!(info, "Dewey unavailable, ignoring embedding request");
+        return Ok(());
+    }
+
+    let response = conversation.messages.last().unwrap();
+
+    let mut queue = EmbeddingQueue::default();
+    queue.push(
+        db,
+        response.id,
+        MESSAGE_EMBEDDING,
+        &response.content,
+        &new_embedding_path(),
+    )?;
+
+    let prompt = conversation
+        .messages
         .iter()
         .rev()
-        .find(|m| m.message_type == MessageType::User)
-        .unwrap();
+        .find(|m| m.message_type == MessageType::User);
+    if let Some(prompt) = prompt.filter(|_| conversation_chunks()) {
+        // Labeled like document chunks, so citations can say where it came from
+        let exchange = format!(
+            "From {}:\nUser: {}\nAssistant: {}",
+            conversation.name, prompt.content, response.content
+        );
 
-    let api = last_user_message.api.clone();
+        queue.push(
+            db,
+            response.id,
+            EXCHANGE_EMBEDDING,
+            &exchange,
+            &new_embedding_path(),
+        )?;
+    }
 
-    let filepath = get_embeddings_dir()
-        .join(uuid::Uuid::new_v4().to_string())
-        .to_string_lossy()
-        .to_string();
+    queue.flush(db, dewey);
 
-    // TODO: system prompt building needs to be more fleshed out
-    //       like, minimum sized system prompts?
-    //       System prompt details should also be configurable
-    std::fs::write(&filepath, last_user_message.content.clone()).unwrap();
-    let dewey_sources = {
-        let now = std::time::Instant::now();
+    Ok(())
+}
 
-        // TODO: Better stats from Dewey
-        let sources = if let Some(d) = dewey.as_mut() {
-            match d.query(&filepath, Vec::new(), 10) {
-                Ok(ds) => ds,
-                Err(e) => {
-                    lprint!(
-                        error,
-                        "Error fetching references from Dewey: {}; ignoring",
-                        e
-                    );
-                    Vec::new()
-                }
-            }
-        } else {
-            Vec::new()
-        };
+// Messages waiting to be embedded, so they can go to Dewey together--
+// it sends them off in batches instead of making a request for each one
+#[derive(Default)]
+struct EmbeddingQueue {
+    // Each file with its Dewey meta tags
+    files: Vec<(String, std::collections::HashSet<String>)>,
+}
 
-        lprint!(
-            info,
-            "Retrieved {} sources from Dewey in {}ms",
-            sources.len(),
-            now.elapsed().as_millis()
-        );
+impl EmbeddingQueue {
+    // Writes out the message to be embedded as `kind`, unless it already has been
+    //
+    // The message is cleaned up first (see preprocess.rs), and if the cleaned text has already
+    // been embedded, the message shares that file instead of adding it to Dewey again
+    // Nothing's left to embed for messages that are all code
+    //
+    // New files are tagged with the conversation the message started out in--see `MemoryScope`
+    fn push(
+        &mut self,
+        db: &rusqlite::Connection,
+        message_id: Option<i64>,
+        kind: &str,
+        content: &str,
+        filepath: &str,
+    ) -> Result<(), std::io::Error> {
+        let exists: bool = db
+            .query_row(
+                "SELECT 1 FROM message_embeddings WHERE message_id = ?1 AND kind = ?2 LIMIT 1",
+                params![message_id, kind],
+                |_row| Ok(true),
+            )
+            .unwrap_or(false);
 
-        sources
-    };
+        if exists {
+            return Ok(());
+        }
 
-    let system_prompt = build_system_prompt(total_len, &dewey_sources, tokenizer);
+        let content = preprocess::clean(content);
+        let content_hash = attachments::hash(content.as_bytes());
+        let duplicate = if content.is_empty() {
+            None
+        } else {
+            db::query_row(
+                db,
+                "SELECT filepath FROM message_embeddings WHERE content_hash = ?1 AND kind = ?2 LIMIT 1",
+                params![content_hash, kind],
+                |row| row.get::<_, String>(0),
+            )
+            .map_err(std::io::Error::other)?
+        };
 
-    // Update dewey with our message
-    match add_message_embedding(&mut dewey, db, last_user_message, &filepath) {
-        Ok(_) => {}
-        Err(e) => {
-            lprint!(error, "Error adding user message to Dewey: {}; ignoring", e);
+        if content.is_empty() || duplicate.is_some() {
+            // `completion` writes the prompt out ahead of time to query with
+            let _ = std::fs::remove_file(filepath);
         }
-    };
 
-    // Separate thread to communicate with the LLM
-    // Message deltas are streamed back through the channel
-    // TODO: We need a better way of propagating errors back to this main thread
-    let (tx, rx) = std::sync::mpsc::channel::<String>();
-    let thread_system_prompt = system_prompt.clone();
-    std::thread::spawn(move || {
-        match network::prompt_stream(
-            api,
-            &messages_payload[..messages_payload.len() - 1].to_vec(),
-            &thread_system_prompt,
-            tx,
-        ) {
-            Ok(_) => {}
-            Err(e) => {
-                lprint!(error, "error sending message to GPT endpoint: {}", e);
-            }
+        if content.is_empty() {
+            return Ok(());
         }
-    });
 
-    // Set to true when we receive our first delta
-    // If this remains false, this will trigger an error
-    let mut message_received = false;
-    loop {
-        match rx.recv() {
-            Ok(message) => {
-                message_received = true;
+        let (filepath, new) = match duplicate {
+            Some(existing) => (existing, false),
+            None => {
+                std::fs::write(filepath, &content)?;
+                (filepath.to_string(), true)
+            }
+        };
 
-                // -2 to skip the last message, which is being filled by the active completion, and
-                // get the last user message
-                let request_message_id = conversation.messages[conversation.messages.len() - 2]
-                    .id
-                    .unwrap();
+        db::execute(
+            db,
+            "INSERT INTO message_embeddings (message_id, kind, filepath, content_hash) VALUES (?1, ?2, ?3, ?4)",
+            params![message_id, kind, filepath, content_hash],
+        )
+        .map_err(std::io::Error::other)?;
+
+        if new {
+            let meta = match message_id {
+                Some(id) => db::query_row(
+                    db,
+                    "SELECT MIN(conversation_id) FROM paths WHERE message_id = ?1",
+                    params![id],
+                    |row| row.get::<_, Option<i64>>(0),
+                )
+                .map_err(std::io::Error::other)?
+                .flatten()
+                .map(conversation_tag)
+                .into_iter()
+                .collect(),
+                None => std::collections::HashSet::new(),
+            };
 
-                // Update the last message with the delta
-                // This is primarily for accurately storing things in the DB
-                let last = conversation.messages.last_mut().unwrap();
-                last.content.push_str(&message);
+            self.files.push((filepath, meta));
+        }
 
-                if last.system_prompt.len() == 0 {
-                    last.system_prompt = system_prompt.clone();
-                }
+        Ok(())
+    }
 
-                // Make sure conversation metadata is correctly set
-                let conversation_id = conversation.id.unwrap();
-                let response_id = last.id.unwrap();
-                let conversation_name = conversation.name.clone();
+    // Messages that fail to embed lose their `message_embeddings` rows,
+    // so the next backfill picks them up again
+    //
+    // Returns how many failed
+    fn flush(
+        &mut self,
+        db: &rusqlite::Connection,
+        dewey: &std::sync::Mutex<Option<Dewey>>,
+    ) -> usize {
+        let files = std::mem::take(&mut self.files);
+        if files.is_empty() || safe_lock!(dewey).is_none() {
+            return 0;
+        }
 
-                ws_send!(
-                    websocket,
-                    serialize_response!(
-                        Completion,
-                        Completion {
-                            stream: true,
-                            delta: message,
-                            name: conversation_name,
-                            conversation_id,
-                            request_id: request_message_id,
-                            response_id,
-                        },
-                        request_id.to_string()
-                    )
+        let failed = embed_files(dewey, files)
+            .into_iter()
+            .map(|(filepath, e)| {
+                lprint!(error, "Error processing message {}: {}", filepath, e);
+                filepath
+            })
+            .collect::<Vec<_>>();
+
+        for filepath in failed.iter() {
+            if let Err(cleanup_err) = std::fs::remove_file(filepath) {
+                lprint!(
+                    error,
+                    "Failed to remove file after embedding error: {}",
+                    cleanup_err
                 );
             }
-            // TODO: this feels disgusting. There has to be a better way of telling when the stream
-            //       has ended
-            Err(e) => {
-                if message_received {
-                    lprint!(info, "Assuming stream completed... ({})", e);
 
-                    // Weird one-off response serialization
-                    ws_send!(
-                        websocket,
-                        serialize_response!(
-                            CompletionEnd,
-                            SystemPrompt {
-                                content: system_prompt,
-                            },
-                            request_id.to_string()
-                        )
-                    );
+            match db.execute(
+                "DELETE FROM message_embeddings WHERE filepath = ?1",
+                params![filepath],
+            ) {
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(error, "Error forgetting failed embedding: {}; ignoring", e);
+                }
+            };
+        }
 
-                    // Backend storage duties--SQLite + embedding generation/storage
-                    match conversation.upsert(db) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            ws_error!(
-                                websocket,
-                                "Completion",
-                                "Error upserting conversation in DB",
-                                e,
-                                request_id.to_string()
-                            );
-                        }
-                    };
+        failed.len()
+    }
+}
 
-                    if dewey.is_some() {
-                        match add_message_embedding(
-                            &mut dewey,
-                            db,
-                            conversation.messages.last().unwrap(),
-                            &filepath,
-                        ) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                lprint!(
-                                    error,
-                                    "Error adding assistant message to Dewey: {}; ignoring",
-                                    e
-                                );
-                            }
-                        };
-                    }
-                } else {
-                    lprint!(error, "Stream channel closing without receiving delta");
-                }
+// Embeds the files (each with its Dewey meta tags), then adds them to Dewey
+//
+// Embedding's the slow part--it's usually a request to OpenAI--so Dewey's only locked to store
+// the results, instead of holding up everything else waiting on it (completions included)
+//
+// Returns the files that couldn't be embedded or stored
+fn embed_files(
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+    files: Vec<(String, std::collections::HashSet<String>)>,
+) -> Vec<(String, std::io::Error)> {
+    let sources = files
+        .into_iter()
+        .map(|(filepath, meta)| dewey_lib::EmbeddingSource {
+            filepath,
+            meta,
+            subset: None,
+        })
+        .collect::<Vec<_>>();
 
-                break;
-            }
-        }
+    let (embeddings, failed) = network::block_on(dewey_lib::embed_batch(&sources));
+    let mut failed = failed
+        .into_iter()
+        .map(|(source, e)| (source.filepath, e))
+        .collect::<Vec<_>>();
+
+    if embeddings.is_empty() {
+        return failed;
     }
 
-    // TODO: This error handling needs refactored
-    if !message_received {
-        ws_error!(
-            websocket,
-            "Completion",
-            "Error receiving completion delta",
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Error receiving completion delta"
-            ),
-            request_id.to_string()
+    let embedded = embeddings
+        .iter()
+        .map(|e| e.source_file.filepath.clone())
+        .collect::<Vec<_>>();
+    let stored = match safe_lock!(dewey).as_mut() {
+        Some(d) => d.store(embeddings),
+        None => Err(std::io::Error::other("Dewey is unavailable")),
+    };
+
+    if let Err(e) = stored {
+        lprint!(error, "Error adding embeddings to Dewey: {}", e);
+        failed.extend(
+            embedded
+                .into_iter()
+                .map(|filepath| (filepath, std::io::Error::new(e.kind(), e.to_string()))),
         );
     }
+
+    failed
 }
 
-// Fetch a whole conversation from SQLite with a given ID
-fn get_conversation(conversation_id: i64, db: &rusqlite::Connection) -> Conversation {
-    let mut query = db
-        .prepare(
-            "
-            SELECT
-                c.id as conversation_id,
-                c.name as conversation_name,
-                m.id as message_id,
-                m.message_type_id,
-                m.content,
-                api.provider,
-                api.name,
-                m.system_prompt,
-                l.sequence,
-                m.date_created
-            FROM conversations c
-            JOIN paths l ON c.id = l.conversation_id
-            JOIN messages m ON l.message_id = m.id
-            JOIN models api ON m.api_config_id = api.id
-            WHERE c.id = ?1
-            ORDER BY l.sequence ASC
-            ",
-        )
-        .unwrap();
+// The prompt in `query_filepath`, embedded without Dewey locked (see `embed_files`)
+// for everything a completion queries it with
+//
+// `None` without Dewey, or if the prompt can't be embedded
+fn embed_query(
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+    query_filepath: &str,
+) -> Option<dewey_lib::Embedding> {
+    if safe_lock!(dewey).is_none() {
+        return None;
+    }
 
-    let rows = query
-        .query_map(params![conversation_id], |row| {
-            let provider = row.get::<_, String>("provider")?;
-            let model_name = row.get::<_, String>("name")?;
-            let api = API::from_strings(&provider, &model_name)
-                .map_err(|e| rusqlite::Error::InvalidParameterName(e))?;
+    let _span = spans::span("dewey.embed");
+    match network::block_on(dewey_lib::embed(&dewey_lib::EmbeddingSource {
+        filepath: query_filepath.to_string(),
+        meta: std::collections::HashSet::new(),
+        subset: None,
+    })) {
+        Ok(embedding) => Some(embedding),
+        Err(e) => {
+            lprint!(error, "Error embedding prompt: {}; ignoring", e);
+            None
+        }
+    }
+}
 
-            Ok((
-                row.get::<_, i64>("conversation_id")?,
-                row.get::<_, String>("conversation_name")?,
-                row.get::<_, i64>("message_id")?,
-                MessageType::from_id(row.get::<_, i64>("message_type_id")?).unwrap(),
-                row.get::<_, String>("content")?,
-                api,
-                row.get::<_, String>("system_prompt")?,
-                row.get::<_, i32>("sequence")?,
-                row.get::<_, String>("date_created")?,
-            ))
-        })
-        .unwrap();
+// Basic prompt builder. Uses embedding memory and XML to structure prompts.
+// TODO: This could probably be abstracted out to a more general prompt builder, but I can't see
+//       the metastructure at the moment
+fn build_system_prompt(
+    api: &API,
+    conversation_len: usize,
+    dewey_sources: &Vec<dewey_lib::EmbeddingSource>,
+    instructions: Option<&str>,
+    summary: Option<&str>,
+    tokenizer: Option<&tiktoken::Tokenizer>,
+) -> (String, Vec<(String, String)>) {
+    // Each reference gets a slice of the model's context,
+    // so smaller models aren't crowded out by references
+    let context_window = api.context_window();
+    let reference_budget = (context_window / 64).clamp(64, 2048);
 
-    let mut conversation = Conversation {
-        id: Some(conversation_id),
-        name: String::new(),
-        messages: Vec::new(),
-    };
+    let mut prompt = "<systemPrompt>".to_string();
+    if let Some(instructions) = instructions {
+        prompt.push_str(&format!("<instructions>{}</instructions>", instructions));
+    }
 
-    for row in rows {
-        let row = row.unwrap();
-        conversation.name = row.1;
-        conversation.messages.push(Message {
-            id: Some(row.2),
-            message_type: row.3,
-            content: row.4,
-            api: row.5,
-            system_prompt: row.6,
-            sequence: row.7,
-            date_created: row.8,
-        });
+    if let Some(summary) = summary {
+        prompt.push_str(&format!(
+            "<earlierConversation>The start of this conversation no longer fits, but here's a summary of it: {}</earlierConversation>",
+            summary
+        ));
     }
 
-    conversation
+    prompt.push_str(r#"
+        <objective>
+            Determine whether to use the following references to inform your response, and do so without explicitly acknowledging it beyond the citations described below.
+            Incorporate into your judgment whether this moves the conversation forward, in the same direction as the user.
+            If you decide to use it, do so in a friendly, familiar manner--leave what should stay unsaid, but implicitly acknowledge the history.
+            If reasonable, try and use the references to fill in contextual gaps.
+        </objective>
+    "#);
+    prompt.push_str(citations::CITATION_INSTRUCTIONS);
+
+    // (filepath, contents) of each reference that fit, numbered from 1 in the prompt
+    let mut included = Vec::new();
+    prompt.push_str("<references>");
+    for source in dewey_sources {
+        let prompt_len = if let Some(tok) = tokenizer {
+            tok.count(&prompt)
+        } else {
+            prompt.len().div_ceil(4)
+        };
+
+        if conversation_len + prompt_len + reference_budget > context_window {
+            break;
+        }
+
+        // Purged conversations take their embedding files with them
+        let contents = match std::fs::read_to_string(&source.filepath) {
+            Ok(c) => c,
+            Err(e) => {
+                lprint!(
+                    error,
+                    "Error reading reference {}: {}; skipping",
+                    source.filepath,
+                    e
+                );
+                continue;
+            }
+        };
+
+        // Without a tokenizer, ~4 characters per token is close enough
+        let contents = match tokenizer {
+            Some(tok) => tok.truncate(&contents, reference_budget),
+            None => contents
+                .chars()
+                .take(reference_budget * 4)
+                .collect::<String>(),
+        };
+        prompt.push_str(&citations::reference_tag(included.len() + 1, &contents));
+        included.push((source.filepath.clone(), contents));
+    }
+
+    prompt.push_str("</references>");
+    prompt.push_str("</systemPrompt>");
+
+    (prompt, included)
 }
 
-// Fetch the first message of a conversation from SQLite with a given ID
-fn get_first_message(conversation_id: i64, db: &rusqlite::Connection) -> Message {
-    let mut query = db
-        .prepare(
-            "
-            SELECT
-                m.id as message_id,
-                m.message_type_id,
-                m.content,
-                api.provider,
-                api.name,
-                m.system_prompt,
-                l.sequence,
-                m.date_created
-            FROM conversations c
-            JOIN paths l ON c.id = l.conversation_id
-            JOIN messages m ON l.message_id = m.id
-            JOIN models api ON m.api_config_id = api.id
-            WHERE c.id = ?1
-            ORDER BY l.sequence ASC
-            LIMIT 1
-            ",
-        )
-        .unwrap();
+// The message a reference file was embedded from, with its conversation's ID and name
+fn reference_message(
+    db: &rusqlite::Connection,
+    filepath: &str,
+) -> rusqlite::Result<Option<(i64, i64, String)>> {
+    match db.query_row(
+        "SELECT me.message_id, c.id, c.name
+         FROM message_embeddings me
+         JOIN paths l ON l.message_id = me.message_id
+         JOIN conversations c ON c.id = l.conversation_id
+         WHERE me.filepath = ?1
+         LIMIT 1",
+        params![filepath],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    ) {
+        Ok(message) => Ok(Some(message)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
 
-    let mut rows = query
-        .query_map([conversation_id], |row| {
-            let provider = row.get::<_, String>("provider")?;
-            let model_name = row.get::<_, String>("name")?;
-            let api = API::from_strings(&provider, &model_name)
-                .map_err(|e| rusqlite::Error::InvalidParameterName(e.to_string()))?;
+// What each numbered reference in the system prompt was, so citations can be traced back
+fn reference_citations(
+    db: &rusqlite::Connection,
+    references: &[(String, String)],
+) -> Vec<Citation> {
+    references
+        .iter()
+        .enumerate()
+        .map(|(i, (filepath, contents))| {
+            let message = reference_message(db, filepath).unwrap_or_else(|e| {
+                lprint!(
+                    error,
+                    "Error looking up reference {}: {}; ignoring",
+                    filepath,
+                    e
+                );
+                None
+            });
 
-            Ok(Message {
-                id: Some(row.get::<_, i64>("message_id")?),
-                message_type: MessageType::from_id(row.get::<_, i64>("message_type_id")?).unwrap(),
-                content: row.get::<_, String>("content")?,
-                api,
-                system_prompt: row.get::<_, String>("system_prompt")?,
-                sequence: row.get::<_, i32>("sequence")?,
-                date_created: row.get::<_, String>("date_created")?,
-            })
+            let (source, conversation_id, message_id) = match message {
+                Some((message_id, conversation_id, name)) => {
+                    (name, Some(conversation_id), Some(message_id))
+                }
+                None => (
+                    citations::chunk_source(contents).unwrap_or_else(|| filepath.clone()),
+                    None,
+                    None,
+                ),
+            };
+
+            Citation {
+                marker: i + 1,
+                source,
+                conversation_id,
+                message_id,
+                excerpt: citations::excerpt(contents),
+            }
         })
-        .unwrap();
+        .collect()
+}
 
-    // Retrieve the first (and only) message from the iterator
-    if let Some(result) = rows.next() {
-        match result {
-            Ok(message) => message,
-            Err(_) => panic!("TODO: error handling"),
-        }
-    } else {
-        panic!("TODO: error handling");
-    }
+// Characters a new message can run to, from `WILLIAM_MAX_MESSAGE_CHARS`--0 for no limit
+// `WILLIAM_MAX_MESSAGE_CHARS` is set from the user config, like the API keys
+fn max_message_chars() -> usize {
+    std::env::var("WILLIAM_MAX_MESSAGE_CHARS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(20000)
 }
 
-// Get the user config, or the prepared defaults
-// It really feels gross to insert a default every time we want to fetch the config
-fn get_config(db: &rusqlite::Connection) -> UserConfig {
-    match db.execute("INSERT OR IGNORE INTO user_config (openai_key, groq_key, grok_key, anthropic_key, gemini_key, system_prompt) 
-                      VALUES ('', '', '', '', '', '')", params![]) {
-        Ok(_) => {},
-        Err(e) => {
-            lprint!(error, "Error setting user_config defaults: {}", e);
-            panic!("Error setting user_config defaults: {}", e);
+// Least similarity to the prompt a memory needs to be used as a reference,
+// from `WILLIAM_REFERENCE_THRESHOLD`--set from the user config, like the API keys
+fn reference_threshold() -> f32 {
+    std::env::var("WILLIAM_REFERENCE_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse::<f32>().ok())
+        .unwrap_or(0.25)
+}
+
+// Documents are split into chunks of about this many characters for embedding
+const DOCUMENT_CHUNK_CHARS: usize = 2000;
+const DOCUMENT_CHUNK_OVERLAP: usize = 200;
+// Most chunks from a conversation's documents used as references for one completion
+const DOCUMENT_REFERENCE_LIMIT: usize = 5;
+// Same, for the code from a conversation's repositories
+const REPOSITORY_REFERENCE_LIMIT: usize = 5;
+
+// Extracts, chunks, and embeds the conversation's document attachments that haven't been already
+// Images and anything without an extraction backend are skipped
+//
+// Returns the chunk files of every document in the conversation
+fn ingest_documents(
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+    db: &rusqlite::Connection,
+    conversation: &Conversation,
+) -> Vec<String> {
+    let mut seen = std::collections::HashSet::new();
+    let mut chunk_files = Vec::new();
+    for attachment in conversation
+        .messages
+        .iter()
+        .flat_map(|m| m.attachments.iter())
+    {
+        if !seen.insert(attachment.hash.clone()) {
+            continue;
         }
-    };
 
-    let mut stmt = db
-        .prepare(
-            "SELECT openai_key, groq_key, grok_key, anthropic_key, gemini_key, system_prompt
-                                 FROM user_config LIMIT 1",
-        )
-        .unwrap();
+        let backend =
+            match extract::Backend::for_attachment(&attachment.name, &attachment.mime_type) {
+                Some(b) => b,
+                None => continue,
+            };
 
-    let config = stmt
-        .query_row(params![], |row| {
-            Ok(UserConfig {
-                write: false,
-                api_keys: APIKeys {
-                    openai: row.get(0)?,
-                    groq: row.get(1)?,
-                    grok: row.get(2)?,
-                    anthropic: row.get(3)?,
-                    gemini: row.get(4)?,
-                },
-                system_prompt: row.get(5)?,
-            })
-        })
-        .unwrap();
+        let existing = {
+            let mut query = match db.prepare(
+                "SELECT filepath FROM document_chunks WHERE hash = ?1 ORDER BY chunk_index",
+            ) {
+                Ok(q) => q,
+                Err(e) => {
+                    lprint!(error, "Error fetching document chunks: {}; ignoring", e);
+                    continue;
+                }
+            };
 
-    return config;
+            let existing = query
+                .query_map(params![attachment.hash], |row| row.get::<_, String>(0))
+                .and_then(|rows| rows.collect::<rusqlite::Result<Vec<String>>>())
+                .unwrap_or_default();
+
+            existing
+        };
+
+        if !existing.is_empty() {
+            chunk_files.extend(existing);
+            continue;
+        }
+
+        match ingest_document(dewey, db, attachment, backend) {
+            Ok(files) => chunk_files.extend(files),
+            Err(e) => {
+                lprint!(
+                    error,
+                    "Error ingesting document {}: {}; ignoring",
+                    attachment.name,
+                    e
+                );
+            }
+        };
+    }
+
+    chunk_files
 }
 
-fn register_env_var(env_var: &str, value: &str) {
-    std::env::set_var(env_var, value);
+// NOTE: chunks are only embedded here--if Dewey's unavailable at the time,
+//       the document only ever shows up through `document_sources`' fallback
+fn ingest_document(
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+    db: &rusqlite::Connection,
+    attachment: &Attachment,
+    backend: extract::Backend,
+) -> Result<Vec<String>, std::io::Error> {
+    let data = std::fs::read(attachments::blob_path(
+        &get_attachments_dir(),
+        &attachment.hash,
+    ))?;
+    let text = extract::extract_text(backend, &data)?;
+    let chunks = extract::chunk(&text, DOCUMENT_CHUNK_CHARS, DOCUMENT_CHUNK_OVERLAP);
+
+    let dir = get_documents_dir().join(&attachment.hash);
+    std::fs::create_dir_all(&dir)?;
+
+    let mut files = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let filepath = dir.join(format!("{}.txt", i)).to_string_lossy().to_string();
+
+        // The model sees these as references, so each one says where it came from
+        std::fs::write(&filepath, format!("From {}:\n{}", attachment.name, chunk))?;
+        db.execute(
+            "INSERT OR IGNORE INTO document_chunks (hash, chunk_index, filepath) VALUES (?1, ?2, ?3)",
+            params![attachment.hash, i as i64, filepath],
+        )
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        files.push(filepath);
+    }
+
+    if safe_lock!(dewey).is_some() {
+        let chunks = files
+            .iter()
+            .map(|f| (f.clone(), std::collections::HashSet::new()))
+            .collect();
+
+        for (filepath, e) in embed_files(dewey, chunks) {
+            lprint!(error, "Error embedding {}: {}; ignoring", filepath, e);
+        }
+    }
+
     lprint!(
         info,
-        "{}: {}",
-        env_var,
-        if value.len() > 0 { "***" } else { "" }
+        "Ingested {} ({} characters, {} chunks)",
+        attachment.name,
+        text.len(),
+        files.len()
     );
-}
 
-fn set_keys(user_config: &UserConfig) {
-    register_env_var("OPENAI_API_KEY", &user_config.api_keys.openai);
-    register_env_var("ANTHROPIC_API_KEY", &user_config.api_keys.anthropic);
-    register_env_var("GEMINI_API_KEY", &user_config.api_keys.gemini);
-    register_env_var("GROQ_API_KEY", &user_config.api_keys.groq);
+    Ok(files)
 }
 
-// TODO: there is zero error handling around here lol
-async fn websocket_server(db: rusqlite::Connection, dewey: Option<dewey_lib::Dewey>) {
-    // Tokenizer using the GPT-4o token mapping from OpenAI
-    let tokenizer_ = std::sync::Arc::new(std::sync::Mutex::new(
-        match tiktoken::Tokenizer::new().await {
-            Ok(t) => Some(t),
+// The files from `chunk_files` closest to `query`, best first
+fn closest_chunks(
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+    query: Option<&dewey_lib::Embedding>,
+    chunk_files: &[String],
+) -> Vec<dewey_lib::EmbeddingSource> {
+    // Dewey can't be limited to certain files, so this pulls extra and filters
+    let _span = spans::span("dewey.query");
+    match (query, safe_lock!(dewey).as_mut()) {
+        (Some(query), Some(d)) => match d.query_embedding(
+            query.clone(),
+            Vec::new(),
+            50,
+            dewey_lib::QueryOptions::default(),
+        ) {
+            Ok(results) => results
+                .into_iter()
+                .map(|r| r.source)
+                .filter(|s| chunk_files.contains(&s.filepath))
+                .collect::<Vec<_>>(),
             Err(e) => {
-                lprint!(error, "Error initializing tokenizer: {}; ignoring...", e);
-                None
+                lprint!(error, "Error ranking chunks: {}; ignoring", e);
+                Vec::new()
+            }
+        },
+        _ => Vec::new(),
+    }
+}
+
+// The chunks of the conversation's repositories closest to `query`
+// Unlike documents, there's no fallback--the first few files of a repository are rarely relevant
+fn repository_sources(
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+    db: &rusqlite::Connection,
+    query: Option<&dewey_lib::Embedding>,
+    conversation: &Conversation,
+) -> Vec<dewey_lib::EmbeddingSource> {
+    if conversation.overrides.repositories.is_empty() {
+        return Vec::new();
+    }
+
+    let chunk_files = match repos::chunk_files(
+        &get_repositories_dir(),
+        &conversation.overrides.repositories,
+        db,
+    ) {
+        Ok(f) => f,
+        Err(e) => {
+            lprint!(error, "Error fetching repository chunks: {}; ignoring", e);
+            return Vec::new();
+        }
+    };
+
+    let mut sources = closest_chunks(dewey, query, &chunk_files);
+    sources.truncate(REPOSITORY_REFERENCE_LIMIT);
+    lprint!(
+        info,
+        "Using {} chunks from {} repository chunks",
+        sources.len(),
+        chunk_files.len()
+    );
+
+    sources
+}
+
+// The chunks of `documents` closest to `query`, best first
+// Without Dewey (or without any hits), it's just the first few chunks
+fn document_sources(
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+    query: Option<&dewey_lib::Embedding>,
+    documents: &[String],
+) -> Vec<dewey_lib::EmbeddingSource> {
+    if documents.is_empty() {
+        return Vec::new();
+    }
+
+    let mut sources = closest_chunks(dewey, query, documents);
+    if sources.is_empty() {
+        sources = documents
+            .iter()
+            .map(|filepath| dewey_lib::EmbeddingSource {
+                filepath: filepath.clone(),
+                meta: std::collections::HashSet::new(),
+                subset: None,
+            })
+            .collect();
+    }
+
+    sources.truncate(DOCUMENT_REFERENCE_LIMIT);
+    lprint!(
+        info,
+        "Using {} chunks from {} document chunks",
+        sources.len(),
+        documents.len()
+    );
+
+    sources
+}
+
+// TODO: this needs to be accommodated for the high context windows
+//
+// Function to keep the conversation within context window limits. Returns the correct conversation
+// history to use for the prompt.
+fn cutoff_messages(
+    messages: &Vec<Message>,
+    tokenizer: Option<&tiktoken::Tokenizer>,
+) -> (usize, Vec<Message>) {
+    let mut cutoff = messages.len() - 1;
+    let mut total_len = 0;
+    for m in messages.iter().rev() {
+        if m.content.is_empty() {
+            continue;
+        }
+
+        total_len += if let Some(tok) = tokenizer {
+            tok.count(&m.content)
+        } else {
+            m.content.len().div_ceil(4)
+        };
+
+        // TODO: centralize context window limits for each model
+        if total_len < 128000 {
+            cutoff = std::cmp::max(0, cutoff - 1);
+        }
+    }
+
+    (total_len, messages[cutoff..].to_vec())
+}
+
+// Rolling summary of the history `cutoff_messages` dropped
+//
+// The stored summary covers everything up to `summary_message_id`,
+// so only what's been dropped since then needs summarizing--
+// unless the summary came from another branch, in which case it's redone
+fn history_summary(
+    conversation_id: i64,
+    dropped: &[Message],
+    tokenizer: Option<&tiktoken::Tokenizer>,
+    db: &rusqlite::Connection,
+) -> Option<String> {
+    let (summary, through) = match db.query_row(
+        "SELECT summary, summary_message_id FROM conversations WHERE id = ?1",
+        params![conversation_id],
+        |row| {
+            Ok((
+                row.get::<_, Option<String>>(0)?,
+                row.get::<_, Option<i64>>(1)?,
+            ))
+        },
+    ) {
+        Ok(s) => s,
+        Err(e) => {
+            lprint!(
+                error,
+                "Error fetching conversation summary: {}; ignoring",
+                e
+            );
+            (None, None)
+        }
+    };
+
+    let (previous, start) =
+        match through.and_then(|id| dropped.iter().position(|m| m.id == Some(id))) {
+            Some(p) => (summary, p + 1),
+            None => (None, 0),
+        };
+
+    let remaining = &dropped[start..];
+    if remaining.is_empty() {
+        return previous;
+    }
+
+    if std::env::var("OPENAI_API_KEY").is_err() {
+        lprint!(
+            info,
+            "No OpenAI key to summarize {} dropped messages with; skipping",
+            remaining.len()
+        );
+        return previous;
+    }
+
+    match network::block_on(summary::summarize(
+        previous.as_deref(),
+        remaining,
+        tokenizer,
+    )) {
+        Ok(s) => {
+            match db.execute(
+                "UPDATE conversations SET summary = ?2, summary_message_id = ?3 WHERE id = ?1",
+                params![conversation_id, s, remaining.last().unwrap().id],
+            ) {
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(error, "Error saving conversation summary: {}; ignoring", e);
+                }
+            };
+
+            Some(s)
+        }
+        Err(e) => {
+            lprint!(error, "Error summarizing dropped messages: {}; ignoring", e);
+            previous
+        }
+    }
+}
+
+const CONVERSATION_NAME_PROMPT: &str = r#"
+    You will be given the start of a conversation.
+    Give it a name.
+    Guidelines:
+    - No markdown
+    - Respond with _only_ the name.
+"#;
+
+const BRANCH_NAME_PROMPT: &str = r#"
+    You will be given the point where a conversation branched off, and the response that started the branch.
+    Give the branch a short name describing the direction it took.
+    Guidelines:
+    - No markdown
+    - Respond with _only_ the name.
+"#;
+
+#[derive(Clone, Copy, Debug)]
+enum NameTarget {
+    Conversation(i64),
+    Branch {
+        conversation_id: i64,
+        branch_id: i64,
+    },
+}
+
+// Sessions whose connections dropped, held onto until they're resumed or `RESUME_GRACE` is up
+// Their completions keep going in the meantime, with whatever they send kept for replay
+static DETACHED: std::sync::OnceLock<
+    std::sync::Mutex<std::collections::HashMap<String, Connection>>,
+> = std::sync::OnceLock::new();
+
+fn detached() -> &'static std::sync::Mutex<std::collections::HashMap<String, Connection>> {
+    DETACHED.get_or_init(|| std::sync::Mutex::new(std::collections::HashMap::new()))
+}
+
+// Anything still streaming is cancelled if nobody resumes the session in time
+fn detach(websocket: Connection) {
+    let id = {
+        let mut state = safe_lock!(websocket.state);
+        state.outbox = None;
+        state.session.id.clone()
+    };
+
+    safe_lock!(detached()).insert(id.clone(), websocket);
+
+    spawn(async move {
+        tokio::time::sleep(RESUME_GRACE).await;
+        if let Some(expired) = safe_lock!(detached()).remove(&id) {
+            let state = safe_lock!(expired.state);
+            for completion in state.completions.iter() {
+                completion
+                    .cancel
+                    .store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+
+            lprint!(
+                info,
+                "Session {} wasn't resumed; cancelled {} completions",
+                id,
+                state.completions.len()
+            );
+        }
+    });
+}
+
+fn ws_replay(websocket: &Connection, responses: &[String]) {
+    for response in responses {
+        if !websocket.write(tungstenite::Message::text(response.clone())) {
+            return;
+        }
+    }
+}
+
+// Runs `f` on the runtime's blocking pool, under the request's span and trace
+// `None` if it panicked
+async fn run_blocking<T, F>(request_id: &str, f: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let request_id = request_id.to_string();
+    let trace = spans::Context::current();
+    tauri::async_runtime::spawn_blocking(move || {
+        let _span = chamber_common::RequestSpan::enter(&request_id);
+        let _trace = spans::enter(trace);
+        f()
+    })
+    .await
+    .ok()
+}
+
+// Handles a request on the runtime's blocking pool, so it isn't holding up the connection--
+// anything slow (provider calls, file I/O, sync, the database) goes through `dispatch`
+// Its response goes out whenever it's ready, with whatever else is in flight
+//
+// `handler` returns the serialized response, error or not
+fn dispatch<F>(websocket: &Connection, request_id: String, handler: F)
+where
+    F: FnOnce() -> String + Send + 'static,
+{
+    dispatch_with(websocket, request_id, move |websocket| {
+        ws_send!(websocket, handler())
+    });
+}
+
+// `dispatch`, for handlers that send their own responses--e.g. to carry on after responding,
+// or to start a completion
+fn dispatch_with<F>(websocket: &Connection, request_id: String, handler: F)
+where
+    F: FnOnce(&Connection) + Send + 'static,
+{
+    let websocket = websocket.clone();
+    let task_request_id = request_id.clone();
+    let trace = spans::Context::current();
+    spawn(spans::instrument(Some(&request_id), trace, async move {
+        let handler_websocket = websocket.clone();
+        let handled = run_blocking(&task_request_id, move || handler(&handler_websocket)).await;
+
+        // Only if the handler panicked
+        if handled.is_none() {
+            ws_error!(
+                websocket,
+                "Internal",
+                "Error handling request",
+                "handler exited without responding",
+                task_request_id
+            );
+        }
+    }));
+}
+
+// Names something from GPT4oMini in a separate task, which saves the name and sends it along
+// Naming takes a full round trip to the provider--nothing should be waiting on it
+//
+// If the user doesn't have an OpenAI API key registered (or the request fails),
+// `fallback` is used instead
+fn spawn_naming(
+    websocket: &Connection,
+    db: &std::sync::Arc<std::sync::Mutex<rusqlite::Connection>>,
+    target: NameTarget,
+    request_id: &str,
+    prompt: &'static str,
+    content: String,
+    fallback: String,
+) -> tauri::async_runtime::JoinHandle<()> {
+    let websocket = websocket.clone();
+    let db = std::sync::Arc::clone(db);
+    let task_request_id = request_id.to_string();
+    let trace = spans::Context::current();
+    spawn(spans::instrument(Some(request_id), trace, async move {
+        let name = if std::env::var("OPENAI_API_KEY").is_ok() {
+            let message = Message {
+                id: None,
+                message_type: MessageType::User,
+                content,
+                api: API::OpenAI(OpenAIModel::GPT4oMini),
+                system_prompt: String::new(),
+                sequence: -1,
+                date_created: String::new(),
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                attachments: Vec::new(),
+                interrupted: false,
+                language: None,
+                citations: Vec::new(),
+                moderation: None,
+            };
+
+            match network::prompt_deterministic(
+                API::OpenAI(OpenAIModel::GPT4oMini),
+                prompt,
+                &vec![message],
+                &[],
+                queue::Priority::Background,
+            )
+            .await
+            {
+                // Naming isn't tied to any message, so its usage isn't recorded
+                Ok((response, _)) => response.content,
+                Err(e) => {
+                    lprint!(error, "Error generating name: {}; using fallback", e);
+                    fallback
+                }
+            }
+        } else {
+            fallback
+        };
+
+        let name = name
+            .trim()
+            .chars()
+            .map(|c| match c {
+                '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+                c if c.is_alphanumeric() || c == '.' || c == '-' || c == ' ' => c,
+                _ => '_',
+            })
+            .collect::<String>();
+
+        let _ = run_blocking(&task_request_id.clone(), move || {
+            apply_name(&websocket, &safe_lock!(db), target, &task_request_id, name)
+        })
+        .await;
+    }))
+}
+
+// New conversations are named from their first message
+fn conversation_naming(
+    websocket: &Connection,
+    db: &std::sync::Arc<std::sync::Mutex<rusqlite::Connection>>,
+    conversation: &Conversation,
+    request_id: &str,
+) -> Option<tauri::async_runtime::JoinHandle<()>> {
+    if !is_valid_guid(&conversation.name) {
+        return None;
+    }
+
+    let first_message = conversation.messages.first()?;
+    Some(spawn_naming(
+        websocket,
+        db,
+        NameTarget::Conversation(conversation.id?),
+        request_id,
+        CONVERSATION_NAME_PROMPT,
+        first_message.content.clone(),
+        first_message.content.chars().take(20).collect(),
+    ))
+}
+
+// Forked branches are named after the direction they took, once their first response is in
+fn branch_naming(
+    websocket: &Connection,
+    db: &std::sync::Arc<std::sync::Mutex<rusqlite::Connection>>,
+    conversation: &Conversation,
+    request_id: &str,
+) -> Option<tauri::async_runtime::JoinHandle<()>> {
+    let conversation_id = conversation.id?;
+    let branch_id = conversation.branch_id?;
+    let (fork_sequence, name) = safe_lock!(db)
+        .query_row(
+            "SELECT fork_sequence, name FROM branches WHERE id = ?1",
+            params![branch_id],
+            |row| {
+                Ok((
+                    row.get::<_, Option<i64>>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                ))
+            },
+        )
+        .ok()?;
+
+    // The original branch goes by the conversation's name
+    let fork_sequence = fork_sequence? as usize;
+    if name.is_some() || fork_sequence >= conversation.messages.len() {
+        return None;
+    }
+
+    // The last user message before the fork, and everything since
+    let start = conversation.messages[..fork_sequence]
+        .iter()
+        .rposition(|m| m.message_type == MessageType::User)
+        .unwrap_or(0);
+
+    let mut content = String::new();
+    for message in conversation.messages[start..].iter() {
+        let role = message.message_type.to_string();
+        content.push_str(&format!("<{}>{}</{}>", role, message.content, role));
+    }
+
+    Some(spawn_naming(
+        websocket,
+        db,
+        NameTarget::Branch {
+            conversation_id,
+            branch_id,
+        },
+        request_id,
+        BRANCH_NAME_PROMPT,
+        content,
+        conversation.messages[fork_sequence]
+            .content
+            .chars()
+            .take(20)
+            .collect(),
+    ))
+}
+
+// Stores a finished name and lets the client know
+// Streams into the conversation pick it up too, so their deltas (and final upsert) agree
+fn apply_name(
+    websocket: &Connection,
+    db: &rusqlite::Connection,
+    target: NameTarget,
+    request_id: &str,
+    name: String,
+) {
+    let (result, conversation_id, branch_id) = match target {
+        NameTarget::Conversation(conversation_id) => {
+            websocket.rename(conversation_id, &name);
+
+            (
+                db.execute(
+                    "UPDATE conversations SET name = ?2 WHERE id = ?1",
+                    params![conversation_id, name],
+                ),
+                conversation_id,
+                None,
+            )
+        }
+        NameTarget::Branch {
+            conversation_id,
+            branch_id,
+        } => (
+            db.execute(
+                "UPDATE branches SET name = ?2 WHERE id = ?1",
+                params![branch_id, name],
+            ),
+            conversation_id,
+            Some(branch_id),
+        ),
+    };
+
+    match result {
+        Ok(_) => {
+            lprint!(info, "Named {:?}: {}", target, name);
+            ws_send!(
+                websocket,
+                serialize_response!(
+                    ConversationRenamed,
+                    ConversationRenamed {
+                        conversation_id,
+                        branch_id,
+                        name,
+                    },
+                    request_id.to_string()
+                )
+            );
+        }
+        Err(e) => {
+            lprint!(error, "Error saving name: {}; ignoring", e);
+        }
+    };
+}
+
+// Runs the messages at `indices` through moderation, annotating each with the result
+// Errors if a message can't be sent: it was flagged (or couldn't be checked) with blocking on
+fn moderate(
+    conversation: &mut Conversation,
+    indices: &[usize],
+    db: &rusqlite::Connection,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let block = moderation::block_flagged();
+    for i in indices {
+        let message = &mut conversation.messages[*i];
+        let result = match network::block_on(moderation::check(&message.content)) {
+            Ok(Some(r)) => r,
+            Ok(None) => return Ok(()),
+            Err(e) if block => {
+                return Err(format!("message couldn't be moderated: {}", e).into());
+            }
+            Err(e) => {
+                lprint!(error, "Error moderating message: {}; sending it anyway", e);
+                continue;
+            }
+        };
+
+        moderation::record(message.id.unwrap(), &result, db)?;
+        if result.flagged {
+            lprint!(
+                info,
+                "Message {} flagged by {} moderation: {}",
+                message.id.unwrap(),
+                result.provider,
+                result.categories.join(", ")
+            );
+        }
+
+        let flagged = result.flagged;
+        message.moderation = Some(result);
+        if flagged && block {
+            return Err(format!(
+                "message was flagged: {}",
+                message.moderation.as_ref().unwrap().categories.join(", ")
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+// Make sure the model can actually handle what the conversation is asking for
+// Otherwise the provider just hands back an opaque 400
+fn check_capabilities(api: &API, conversation: &Conversation) -> Result<(), String> {
+    let required = ModelCapabilities {
+        vision: conversation
+            .messages
+            .iter()
+            .any(|m| m.attachments.iter().any(|a| a.is_image())),
+        tools: !conversation.tools.is_empty()
+            || conversation
+                .messages
+                .iter()
+                .any(|m| m.message_type == MessageType::Tool || !m.tool_calls.is_empty()),
+        streaming: true,
+        ..Default::default()
+    };
+
+    let missing = api.capabilities().missing(&required);
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "model {} does not support {}",
+            api.to_strings().1,
+            missing.join(" or ")
+        ))
+    }
+}
+
+// A completion in flight, as its connection keeps track of it
+struct CompletionHandle {
+    key: u64,
+    request_id: String,
+    // Unset until the completion's been stored--see `Claim::started`
+    conversation_id: Option<i64>,
+    response_id: Option<i64>,
+    // Whatever the conversation's been named since the completion started--see `apply_name`
+    name: Option<String>,
+    cancel: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    // Closes once the completion's finished
+    done: tokio::sync::watch::Receiver<()>,
+}
+
+// A completion's place in its connection, held for as long as it's running, panics included
+// Connections have at most one of these per conversation, so a split pane can stream into
+// several at once, but never twice into the same one
+struct Claim {
+    websocket: Connection,
+    key: u64,
+    cancel: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    _done: tokio::sync::watch::Sender<()>,
+}
+
+impl Claim {
+    // Marks the claim with the conversation + response, now that they're stored
+    fn started(&self, conversation: &Conversation) {
+        let mut state = safe_lock!(self.websocket.state);
+        if let Some(handle) = state.completions.iter_mut().find(|c| c.key == self.key) {
+            handle.conversation_id = conversation.id;
+            handle.response_id = conversation.messages.last().and_then(|m| m.id);
+        }
+    }
+
+    fn name(&self) -> Option<String> {
+        safe_lock!(self.websocket.state)
+            .completions
+            .iter()
+            .find(|c| c.key == self.key)
+            .and_then(|c| c.name.clone())
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        safe_lock!(self.websocket.state)
+            .completions
+            .retain(|c| c.key != self.key);
+    }
+}
+
+impl Connection {
+    // Claims `conversation_id` for a completion, or returns the request already streaming into it
+    // New conversations (and comparisons' sibling branches) don't have anything to claim until
+    // they're stored--see `Claim::started`
+    fn claim(&self, request_id: &str, conversation_id: Option<i64>) -> Result<Claim, String> {
+        let mut state = safe_lock!(self.state);
+        if let Some(streaming) = state
+            .completions
+            .iter()
+            .find(|c| conversation_id.is_some() && c.conversation_id == conversation_id)
+        {
+            return Err(streaming.request_id.clone());
+        }
+
+        let key = state.next_key;
+        state.next_key += 1;
+
+        let cancel = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let (done_tx, done) = tokio::sync::watch::channel(());
+        state.completions.push(CompletionHandle {
+            key,
+            request_id: request_id.to_string(),
+            conversation_id,
+            response_id: None,
+            name: None,
+            cancel: std::sync::Arc::clone(&cancel),
+            done,
+        });
+
+        Ok(Claim {
+            websocket: self.clone(),
+            key,
+            cancel,
+            _done: done_tx,
+        })
+    }
+
+    // Comparisons have a completion per model under the same request
+    // False if none of them are still running
+    fn cancel(&self, request_id: &str) -> bool {
+        let mut cancelled = false;
+        for completion in safe_lock!(self.state)
+            .completions
+            .iter()
+            .filter(|c| c.request_id == request_id)
+        {
+            completion
+                .cancel
+                .store(true, std::sync::atomic::Ordering::SeqCst);
+            cancelled = true;
+        }
+
+        cancelled
+    }
+
+    // Cancels whatever's streaming `response_id`, if anything is,
+    // handing back its request and something to wait on it finishing with
+    fn cancel_response(
+        &self,
+        response_id: i64,
+    ) -> Option<(String, tokio::sync::watch::Receiver<()>)> {
+        let state = safe_lock!(self.state);
+        let streaming = state
+            .completions
+            .iter()
+            .find(|c| c.response_id == Some(response_id))?;
+
+        streaming
+            .cancel
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+
+        Some((streaming.request_id.clone(), streaming.done.clone()))
+    }
+
+    // Completions streaming into the conversation pick up its new name
+    fn rename(&self, conversation_id: i64, name: &str) {
+        for completion in safe_lock!(self.state)
+            .completions
+            .iter_mut()
+            .filter(|c| c.conversation_id == Some(conversation_id))
+        {
+            completion.name = Some(name.to_string());
+        }
+    }
+}
+
+// A completion streaming on a task of its own--see `start_completion`
+struct ActiveCompletion {
+    request_id: String,
+    claim: Claim,
+    // Set for `CompareCompletion` streams, whose deltas are told apart by model
+    compare_model: Option<API>,
+    conversation: Conversation,
+    system_prompt: String,
+    // The references in the system prompt, in the order they're numbered
+    references: Vec<Citation>,
+    settings: GenerationSettings,
+    input_estimate: usize,
+    // Set to true when we receive our first delta
+    // If this remains false, this will trigger an error
+    message_received: bool,
+    // Deltas received but not sent yet--see `DeltaCoalescing`
+    pending: String,
+    last_flush: std::time::Instant,
+    coalescing: DeltaCoalescing,
+    // How much of the response is saved so far--see `CheckpointPolicy`
+    saved_len: usize,
+    last_save: std::time::Instant,
+    checkpoints: CheckpointPolicy,
+    // How the stream ended, once it has--left unset if the stream task died before saying
+    outcome: Option<StreamEvent>,
+    rx: tokio::sync::mpsc::UnboundedReceiver<StreamEvent>,
+    retry_rx: tokio::sync::mpsc::UnboundedReceiver<RetryStatus>,
+    stream_task: tauri::async_runtime::JoinHandle<
+        Result<(Message, Option<TokenUsage>, ResponseTiming), std::io::Error>,
+    >,
+}
+
+fn env_number(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(default)
+}
+
+// Responses are saved as they stream, so a crash only loses whatever came in since the last save
+// A save happens once the response has grown for `interval`, or by `max_chars`, whichever's first
+//
+// `WILLIAM_CHECKPOINT_SECS` and `WILLIAM_CHECKPOINT_CHARS` set these,
+// with an interval of 0 only saving once the stream's done
+#[derive(Clone, Copy, Debug)]
+struct CheckpointPolicy {
+    interval: std::time::Duration,
+    max_chars: usize,
+}
+
+impl CheckpointPolicy {
+    fn from_env() -> Self {
+        Self {
+            interval: std::time::Duration::from_secs(env_number("WILLIAM_CHECKPOINT_SECS", 2)),
+            max_chars: env_number("WILLIAM_CHECKPOINT_CHARS", 4000) as usize,
+        }
+    }
+
+    fn is_due(&self, unsaved_chars: usize, since_save: std::time::Duration) -> bool {
+        !self.interval.is_zero()
+            && unsaved_chars > 0
+            && (since_save >= self.interval || unsaved_chars >= self.max_chars)
+    }
+}
+
+// Deltas are held and sent together rather than one frame per provider delta,
+// going out once they're `interval` old or `max_chars` long, whichever comes first
+//
+// `WILLIAM_DELTA_FLUSH_MS` and `WILLIAM_DELTA_FLUSH_CHARS` set these,
+// with an interval of 0 sending every delta as it comes
+#[derive(Clone, Copy, Debug)]
+struct DeltaCoalescing {
+    interval: std::time::Duration,
+    max_chars: usize,
+}
+
+impl DeltaCoalescing {
+    fn from_env() -> Self {
+        Self {
+            interval: std::time::Duration::from_millis(env_number("WILLIAM_DELTA_FLUSH_MS", 30)),
+            max_chars: env_number("WILLIAM_DELTA_FLUSH_CHARS", 512) as usize,
+        }
+    }
+
+    fn is_due(&self, pending: &str, since_flush: std::time::Duration) -> bool {
+        !pending.is_empty()
+            && (self.interval.is_zero()
+                || since_flush >= self.interval
+                || pending.len() >= self.max_chars)
+    }
+}
+
+// Everything a completion needs from its connection, shared with the completion's task
+#[derive(Clone)]
+struct CompletionResources {
+    db: std::sync::Arc<std::sync::Mutex<rusqlite::Connection>>,
+    tokenizer: std::sync::Arc<Option<tiktoken::Tokenizer>>,
+    dewey: std::sync::Arc<std::sync::Mutex<Option<Dewey>>>,
+}
+
+// Starts a completion for `conversation`, unless one is already streaming into it
+//
+// Everything after that--setup, streaming, and finishing--happens on a task of its own,
+// so the connection carries on with its other requests (and completions) in the meantime
+fn start_completion(
+    websocket: &Connection,
+    request_id: &str,
+    conversation: Conversation,
+    resources: &CompletionResources,
+) {
+    let claim = match claim_conversation(websocket, request_id, &conversation) {
+        Some(c) => c,
+        None => return,
+    };
+
+    let websocket = websocket.clone();
+    let resources = resources.clone();
+    let task_request_id = request_id.to_string();
+    let trace = spans::Context::current();
+    spawn(spans::instrument(Some(request_id), trace, async move {
+        let (active, db) = match setup_completion(
+            &websocket,
+            &task_request_id,
+            conversation,
+            claim,
+            None,
+            &resources,
+        )
+        .await
+        {
+            Some(setup) => setup,
+            None => return,
+        };
+
+        // The name comes through `ConversationRenamed` whenever it's ready
+        conversation_naming(&websocket, &db, &active.conversation, &task_request_id);
+        drive_completion(websocket, active, resources, db).await;
+    }));
+}
+
+// Claims the conversation for a completion, letting the client know if it's already streaming one
+fn claim_conversation(
+    websocket: &Connection,
+    request_id: &str,
+    conversation: &Conversation,
+) -> Option<Claim> {
+    match websocket.claim(request_id, conversation.id) {
+        Ok(claim) => Some(claim),
+        Err(streaming) => {
+            ws_send!(
+                websocket,
+                serialize_response!(
+                    WilliamError,
+                    WilliamError {
+                        error_type: "CompletionInProgress".to_string(),
+                        message: format!(
+                            "conversation {} already has a completion streaming ({})",
+                            conversation.id.unwrap_or_default(),
+                            streaming
+                        ),
+                        details: Vec::new(),
+                        provider: None,
+                    },
+                    request_id.to_string()
+                )
+            );
+
+            None
+        }
+    }
+}
+
+// Runs `completion` on the blocking pool, with a database connection of its own
+// so saving the response as it streams never waits on anyone else
+async fn setup_completion(
+    websocket: &Connection,
+    request_id: &str,
+    conversation: Conversation,
+    claim: Claim,
+    compare_model: Option<API>,
+    resources: &CompletionResources,
+) -> Option<(
+    ActiveCompletion,
+    std::sync::Arc<std::sync::Mutex<rusqlite::Connection>>,
+)> {
+    let websocket = websocket.clone();
+    let resources = resources.clone();
+    let task_request_id = request_id.to_string();
+    run_blocking(request_id, move || {
+        let db = worker_db(&resources.db);
+        let active = completion(
+            &websocket,
+            &task_request_id,
+            conversation,
+            claim,
+            resources.tokenizer.as_ref().as_ref(),
+            &safe_lock!(db),
+            &resources.dewey,
+            compare_model,
+        )?;
+
+        Some((active, db))
+    })
+    .await
+    .flatten()
+}
+
+// Sends along the completion's deltas + retries as they come in, then finishes it up
+async fn drive_completion(
+    websocket: Connection,
+    mut active: ActiveCompletion,
+    resources: CompletionResources,
+    db: std::sync::Arc<std::sync::Mutex<rusqlite::Connection>>,
+) {
+    loop {
+        let due = next_due(&active);
+        tokio::select! {
+            // Let the client know if the provider is being retried
+            Some(status) = active.retry_rx.recv() => {
+                ws_send!(
+                    websocket,
+                    serialize_response!(Retry, status, active.request_id.clone())
+                );
+            }
+            event = active.rx.recv() => match event {
+                Some(StreamEvent::Delta(message)) => {
+                    active.message_received = true;
+                    active.pending.push_str(&message);
+                }
+                Some(end) => {
+                    active.outcome = Some(end);
+                    break;
+                }
+                None => {
+                    lprint!(error, "Stream task exited without finishing the stream");
+                    break;
+                }
+            },
+            // Whatever's held back still goes out on time when the provider goes quiet
+            _ = tokio::time::sleep_until(due.unwrap_or_else(tokio::time::Instant::now)),
+                if due.is_some() => {}
+        }
+
+        if active
+            .coalescing
+            .is_due(&active.pending, active.last_flush.elapsed())
+        {
+            flush_deltas(&websocket, &mut active);
+        }
+
+        checkpoint_response(&db, &mut active);
+    }
+
+    flush_deltas(&websocket, &mut active);
+
+    let request_id = active.request_id.clone();
+    let task_request_id = request_id.clone();
+    run_blocking(&request_id, move || {
+        let conversation = finish_completion(
+            &websocket,
+            active,
+            resources.tokenizer.as_ref().as_ref(),
+            &safe_lock!(db),
+            &resources.dewey,
+        );
+
+        branch_naming(&websocket, &db, &conversation, &task_request_id);
+    })
+    .await;
+}
+
+// When the completion's next due to flush its deltas or save its response, if it has either waiting
+fn next_due(active: &ActiveCompletion) -> Option<tokio::time::Instant> {
+    let flush =
+        (!active.pending.is_empty()).then(|| active.last_flush + active.coalescing.interval);
+
+    let streamed = active
+        .conversation
+        .messages
+        .last()
+        .map_or(0, |m| m.content.len());
+    let save = (streamed > active.saved_len && !active.checkpoints.interval.is_zero())
+        .then(|| active.last_save + active.checkpoints.interval);
+
+    flush
+        .into_iter()
+        .chain(save)
+        .min()
+        .map(tokio::time::Instant::from_std)
+}
+
+// Max models a `CompareCompletion` can fan out to
+const MAX_COMPARE_MODELS: usize = 4;
+
+// Starts the same completion with each of the comparison's models at once
+//
+// The first model answers on the conversation's branch, and every other model gets a sibling
+// branch forked off right before the response--these all stream in alongside each other,
+// with their deltas tagged by model
+fn start_comparison(
+    websocket: &Connection,
+    request_id: &str,
+    comparison: CompareCompletion,
+    resources: &CompletionResources,
+) {
+    let CompareCompletion {
+        mut conversation,
+        models,
+    } = comparison;
+
+    let claim = match claim_conversation(websocket, request_id, &conversation) {
+        Some(c) => c,
+        None => return,
+    };
+
+    // Messages keep whatever model they're stored with--
+    // the model is only swapped out in memory for each completion
+    let with_model = |conversation: &mut Conversation, model: &API| {
+        if let Some(user) = conversation
+            .messages
+            .iter_mut()
+            .rev()
+            .find(|m| m.message_type == MessageType::User)
+        {
+            user.api = model.clone();
+        }
+
+        conversation.messages.last_mut().unwrap().api = model.clone();
+    };
+
+    let websocket = websocket.clone();
+    let resources = resources.clone();
+    let task_request_id = request_id.to_string();
+    let trace = spans::Context::current();
+    spawn(spans::instrument(Some(request_id), trace, async move {
+        with_model(&mut conversation, &models[0]);
+        let (first, db) = match setup_completion(
+            &websocket,
+            &task_request_id,
+            conversation,
+            claim,
+            Some(models[0].clone()),
+            &resources,
+        )
+        .await
+        {
+            Some(setup) => setup,
+            None => return,
+        };
+
+        conversation_naming(&websocket, &db, &first.conversation, &task_request_id);
+
+        // The conversation as the first completion left it, with the prompt stored
+        let base = first.conversation.clone();
+        let cancelled = first.claim.is_cancelled();
+        let trace = spans::Context::current();
+        spawn(spans::instrument(
+            Some(&task_request_id),
+            trace,
+            drive_completion(websocket.clone(), first, resources.clone(), db),
+        ));
+
+        // Cancelled before the other models got going
+        if cancelled {
+            return;
+        }
+
+        let fork_sequence = base.messages.len() as i64 - 1;
+        for model in models.iter().skip(1) {
+            let mut sibling = base.clone();
+            let branch = {
+                let db = std::sync::Arc::clone(&resources.db);
+                let (conversation_id, branch_id) = (sibling.id.unwrap(), sibling.branch_id);
+                run_blocking(&task_request_id, move || {
+                    create_branch(
+                        &safe_lock!(db),
+                        conversation_id,
+                        branch_id,
+                        Some(fork_sequence),
+                    )
+                })
+                .await
+            };
+
+            match branch {
+                Some(Ok(branch_id)) => sibling.branch_id = Some(branch_id),
+                Some(Err(e)) => {
+                    ws_error!(
+                        websocket,
+                        "CompareCompletion",
+                        "Error adding branch to DB",
+                        e,
+                        task_request_id.to_string()
+                    );
+                    continue;
+                }
+                None => continue,
+            };
+
+            let response = sibling.messages.last_mut().unwrap();
+            response.id = None;
+            response.content = String::new();
+            response.tool_calls = Vec::new();
+            response.interrupted = false;
+            with_model(&mut sibling, model);
+
+            // Siblings are claimed once they have a branch of their own--see `Claim::started`
+            let claim = match websocket.claim(&task_request_id, None) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+
+            if let Some((active, db)) = setup_completion(
+                &websocket,
+                &task_request_id,
+                sibling,
+                claim,
+                Some(model.clone()),
+                &resources,
+            )
+            .await
+            {
+                let trace = spans::Context::current();
+                spawn(spans::instrument(
+                    Some(&task_request_id),
+                    trace,
+                    drive_completion(websocket.clone(), active, resources.clone(), db),
+                ));
+            }
+        }
+    }));
+}
+
+// Cuts off the response, keeping whatever made it out,
+// and continues the conversation with the new instruction
+//
+// A response that's still streaming is cancelled and left to finish first,
+// so the conversation picks up from what it saved
+async fn redirect(
+    websocket: Connection,
+    request_id: String,
+    payload: Redirect,
+    resources: CompletionResources,
+) {
+    if let Some((streaming, mut done)) = websocket.cancel_response(payload.response_id) {
+        lprint!(info, "Redirecting completion {}", streaming);
+
+        // Nothing's ever sent--this only returns once the completion's claim is dropped
+        let _ = done.changed().await;
+    }
+
+    let conversation = {
+        let websocket = websocket.clone();
+        let db = std::sync::Arc::clone(&resources.db);
+        let request_id = request_id.clone();
+        let response_id = payload.response_id;
+        run_blocking(&request_id.clone(), move || {
+            let db = safe_lock!(db);
+            match db.query_row(
+                "SELECT conversation_id, branch_id FROM paths WHERE message_id = ?1 ORDER BY id DESC LIMIT 1",
+                params![response_id],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Option<i64>>(1)?)),
+            ) {
+                Ok((conversation_id, branch_id)) => {
+                    Some(get_conversation_branch(conversation_id, branch_id, &db))
+                }
+                Err(e) => {
+                    ws_error!(
+                        websocket,
+                        "Redirect",
+                        "No response with the given ID",
+                        e,
+                        request_id
+                    );
+                    None
+                }
+            }
+        })
+        .await
+        .flatten()
+    };
+
+    let mut conversation = match conversation {
+        Some(c) => c,
+        None => return,
+    };
+
+    let mut response = match conversation.messages.last() {
+        Some(m)
+            if m.id == Some(payload.response_id) && m.message_type == MessageType::Assistant =>
+        {
+            m.clone()
+        }
+        _ => {
+            ws_error!(
+                websocket,
+                "Redirect",
+                "Response isn't the latest message in its conversation",
+                payload.response_id,
+                request_id
+            );
+            return;
+        }
+    };
+
+    // Cut off before anything came through--there's nothing to keep
+    if response.content.is_empty() && response.tool_calls.is_empty() {
+        conversation.messages.pop();
+        response.sequence -= 1;
+    }
+
+    let instruction = Message {
+        id: None,
+        message_type: MessageType::User,
+        content: payload.new_instruction,
+        api: response.api.clone(),
+        system_prompt: String::new(),
+        sequence: response.sequence + 1,
+        date_created: String::new(),
+        tool_calls: Vec::new(),
+        tool_call_id: None,
+        attachments: Vec::new(),
+        interrupted: false,
+        language: None,
+        citations: Vec::new(),
+        moderation: None,
+    };
+
+    let mut placeholder = instruction.clone();
+    placeholder.message_type = MessageType::Assistant;
+    placeholder.content = String::new();
+    placeholder.sequence += 1;
+
+    conversation.messages.push(instruction);
+    conversation.messages.push(placeholder);
+
+    start_completion(&websocket, &request_id, conversation, &resources);
+}
+
+// Everything up to the provider request--the response itself streams in through the returned
+// `ActiveCompletion`, see `drive_completion` and `finish_completion`
+//
+// NOTE: this _does not_ create a new message for the response
+//       the last message in the conversation is expected to be
+//       a placeholder to be filled here for the Assistant
+#[allow(clippy::too_many_arguments)]
+fn completion(
+    websocket: &Connection,
+    request_id: &str,
+    mut conversation: Conversation,
+    claim: Claim,
+    tokenizer: Option<&tiktoken::Tokenizer>,
+    db: &rusqlite::Connection,
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+    // Takes precedence over the conversation and its persona, for `CompareCompletion`
+    compare_model: Option<API>,
+) -> Option<ActiveCompletion> {
+    let persona = conversation_persona(&conversation, db);
+
+    if let Some(template) = conversation.template.take() {
+        if let Err(e) = render_template(&mut conversation, &template, db) {
+            ws_error!(
+                websocket,
+                "Completion",
+                "Error rendering prompt template",
+                e,
+                request_id.to_string()
+            );
+
+            return None;
+        }
+    }
+
+    // New messages are stamped with the conversation's (or persona's) model, if it has one,
+    // so what's stored matches what generated the response
+    let model = compare_model
+        .clone()
+        .or_else(|| conversation.overrides.model.clone())
+        .or_else(|| persona.as_ref().and_then(|p| p.model.clone()));
+    if let Some(model) = &model {
+        for message in conversation.messages.iter_mut().filter(|m| m.id.is_none()) {
+            message.api = model.clone();
+        }
+    }
+
+    for message in conversation.messages.iter_mut() {
+        if let Err(e) = attachments::store_inline(&get_attachments_dir(), message, db) {
+            ws_error!(
+                websocket,
+                "Attachment",
+                "Error storing attachment",
+                e,
+                request_id.to_string()
+            );
+
+            return None;
+        }
+    }
+
+    // Anything pasted in that's too big to send as is goes in as a document instead
+    let max_chars = max_message_chars();
+    for message in conversation
+        .messages
+        .iter_mut()
+        .filter(|m| m.message_type == MessageType::User && m.id.is_none())
+    {
+        match attachments::offload_content(&get_attachments_dir(), message, max_chars, db) {
+            Ok(true) => {
+                lprint!(
+                    info,
+                    "Message is over {} characters; moved its text into an attachment",
+                    max_chars
+                );
+            }
+            Ok(false) => {}
+            Err(e) => {
+                ws_error!(
+                    websocket,
+                    "Attachment",
+                    "Error storing message text",
+                    e,
+                    request_id.to_string()
+                );
+
+                return None;
+            }
+        };
+    }
+
+    // The conversation has to have at least one message from the user
+    // TODO: This might change later
+    let api = conversation
+        .messages
+        .iter()
+        .rev()
+        .find(|m| m.message_type == MessageType::User)
+        .unwrap()
+        .api
+        .clone();
+
+    if let Err(e) = check_capabilities(&api, &conversation) {
+        lprint!(error, "Rejecting completion: {}", e);
+        ws_send!(
+            websocket,
+            serialize_response!(
+                WilliamError,
+                WilliamError {
+                    error_type: "UnsupportedCapability".to_string(),
+                    message: e,
+                    details: Vec::new(),
+                    provider: None,
+                },
+                request_id.to_string()
+            )
+        );
+
+        return None;
+    }
+
+    // Only what the user just wrote is moderated--everything before was checked when it was sent
+    let unmoderated = conversation
+        .messages
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| m.message_type == MessageType::User && m.id.is_none())
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    // the conversation needs to be set with a db ID at this point
+    let new_conversation = conversation.id.is_none();
+    if let Err(e) = conversation.upsert(db) {
+        ws_error!(
+            websocket,
+            "Completion",
+            "Error saving conversation",
+            e,
+            request_id.to_string()
+        );
+
+        return None;
+    }
+
+    match language::record_languages(&conversation.messages, db) {
+        Ok(_) => {}
+        Err(e) => {
+            lprint!(error, "Error recording message languages: {}; ignoring", e);
+        }
+    };
+
+    if let Err(e) = moderate(&mut conversation, &unmoderated, db) {
+        lprint!(error, "Refusing to send message: {}", e);
+        ws_send!(
+            websocket,
+            serialize_response!(
+                WilliamError,
+                WilliamError {
+                    error_type: "Moderation".to_string(),
+                    message: e.to_string(),
+                    details: Vec::new(),
+                    provider: None,
+                },
+                request_id.to_string()
+            )
+        );
+
+        // A conversation isn't kept around until its first message is actually sent
+        if new_conversation {
+            match purge_conversations(
+                db,
+                "refused by moderation",
+                "id = ?1",
+                conversation.id.unwrap().to_string(),
+            ) {
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(
+                        error,
+                        "Error discarding refused conversation: {}; ignoring",
+                        e
+                    );
+                }
+            };
+        }
+
+        return None;
+    }
+
+    // The response goes without a finish reason until it's done--see `recover_interrupted`
+    if let Some(response) = conversation.messages.last_mut() {
+        response.interrupted = false;
+        match set_finish_reason(db, response.id.unwrap(), None) {
+            Ok(_) => {}
+            Err(e) => {
+                lprint!(error, "Error clearing finish reason: {}; ignoring", e);
+            }
+        };
+    }
+
+    let (total_len, mut messages_payload) = cutoff_messages(&conversation.messages, tokenizer);
+    lprint!(
+        info,
+        "Conversation is ~{} tokens ({} estimate)",
+        total_len,
+        tokenizer.map_or("character", |t| t.estimator().name())
+    );
+
+    // Whatever was cut off is summarized instead of just disappearing
+    let dropped = &conversation.messages[..conversation.messages.len() - messages_payload.len()];
+    let summary = if dropped.is_empty() {
+        None
+    } else {
+        lprint!(
+            info,
+            "{} messages don't fit in the context window",
+            dropped.len()
+        );
+        history_summary(conversation.id.unwrap(), dropped, tokenizer, db)
+    };
+
+    // System messages hold for the rest of the conversation, so they're kept even once cut off
+    let kept_system = dropped
+        .iter()
+        .filter(|m| m.message_type == MessageType::System)
+        .cloned()
+        .collect::<Vec<_>>();
+    if !kept_system.is_empty() {
+        messages_payload.splice(0..0, kept_system);
+    }
+
+    // The conversation has to have at least one message from the user
+    // TODO: This might change later
+    let last_user_message = messages_payload
+        .iter()
+        .rev()
+        .find(|m| m.message_type == MessageType::User)
+        .unwrap();
+
+    let api = last_user_message.api.clone();
+
+    // Doubles as the prompt's embedding file
+    let filepath = new_embedding_path();
+
+    // TODO: system prompt building needs to be more fleshed out
+    //       like, minimum sized system prompts?
+    //       System prompt details should also be configurable
+    std::fs::write(&filepath, last_user_message.content.clone()).unwrap();
+
+    // Attached documents get their most relevant chunks in ahead of everything else
+    let query = embed_query(dewey, &filepath);
+    let documents = ingest_documents(dewey, db, &conversation);
+    let document_references = document_sources(dewey, query.as_ref(), &documents);
+    let repository_references = repository_sources(dewey, db, query.as_ref(), &conversation);
+
+    // How close each memory Dewey turned up is to the prompt, for `References`
+    let mut similarities = std::collections::HashMap::new();
+    let dewey_sources = {
+        let now = std::time::Instant::now();
+        let _span = spans::span("dewey.query");
+
+        let scope = conversation.memory_scope;
+        let filters = match scope {
+            MemoryScope::Conversation => {
+                vec![dewey_lib::Filter::Equals(conversation_tag(
+                    conversation.id.unwrap(),
+                ))]
+            }
+            _ => Vec::new(),
+        };
+
+        let sources = if scope == MemoryScope::None {
+            Vec::new()
+        } else if let (Some(query), Some(d)) = (query.as_ref(), safe_lock!(dewey).as_mut()) {
+            match d.query_embedding(
+                query.clone(),
+                filters,
+                10,
+                dewey_lib::QueryOptions::default(),
+            ) {
+                Ok(ds) => ds,
+                Err(e) => {
+                    lprint!(
+                        error,
+                        "Error fetching references from Dewey: {}; ignoring",
+                        e
+                    );
+                    Vec::new()
+                }
+            }
+        } else {
+            Vec::new()
+        };
+
+        lprint!(
+            info,
+            "Retrieved {} sources from Dewey in {}ms",
+            sources.len(),
+            now.elapsed().as_millis()
+        );
+
+        // A new conversation might be picking up where a recent one left off
+        if new_conversation {
+            if let Err(e) = related::link(conversation.id.unwrap(), &sources, db) {
+                lprint!(
+                    error,
+                    "Error linking related conversations: {}; ignoring",
+                    e
+                );
+            }
+        }
+
+        // Anything too far from the prompt only crowds out what's relevant
+        let threshold = reference_threshold();
+        let retrieved = sources.len();
+        let sources = sources
+            .into_iter()
+            .filter(|r| r.similarity() >= threshold)
+            .map(|r| {
+                similarities.insert(r.source.filepath.clone(), r.similarity());
+                r.source
+            })
+            .collect::<Vec<_>>();
+
+        if sources.len() < retrieved {
+            lprint!(
+                info,
+                "Dropped {} sources below the {} similarity threshold",
+                retrieved - sources.len(),
+                threshold
+            );
+        }
+
+        // Nothing from the trash, or from watched files that have since shrunk or gone away
+        // Repository chunks only come in through `repository_sources`
+        let watched_dir = get_watched_dir();
+        let repositories_dir = get_repositories_dir();
+        let sources = match trashed_embedding_files(db) {
+            Ok(trashed) => sources
+                .into_iter()
+                .filter(|s| !trashed.contains(&s.filepath))
+                .filter(|s| {
+                    let path = std::path::Path::new(&s.filepath);
+                    !path.starts_with(&repositories_dir)
+                        && (!path.starts_with(&watched_dir) || path.exists())
+                })
+                .collect(),
+            Err(e) => {
+                lprint!(
+                    error,
+                    "Error fetching trashed embedding files: {}; ignoring",
+                    e
+                );
+                sources
+            }
+        };
+
+        // Keyword hits are never from the conversation itself, so they only fit `All`
+        let strategy = FusionStrategy::from_env();
+        let keyword = if strategy == FusionStrategy::Semantic || scope != MemoryScope::All {
+            Vec::new()
+        } else {
+            match keyword_sources(&last_user_message.content, conversation.id, 10, db) {
+                Ok(k) => k,
+                Err(e) => {
+                    lprint!(error, "Error fetching keyword references: {}; ignoring", e);
+                    Vec::new()
+                }
+            }
+        };
+
+        let keyword_count = keyword.len();
+        let sources = fuse_sources(strategy, sources, keyword, 10);
+
+        lprint!(
+            info,
+            "Using {} references ({} keyword hits, {:?})",
+            sources.len(),
+            keyword_count,
+            strategy
+        );
+
+        let mut references = document_references;
+        for source in repository_references.into_iter().chain(sources) {
+            if !references.iter().any(|r| r.filepath == source.filepath) {
+                references.push(source);
+            }
+        }
+
+        references
+    };
+
+    let instructions = conversation_instructions(&conversation, persona.as_ref(), db);
+
+    let (system_prompt, included) = build_system_prompt(
+        &api,
+        total_len,
+        &dewey_sources,
+        instructions.as_deref(),
+        summary.as_deref(),
+        tokenizer,
+    );
+    let references = reference_citations(db, &included);
+
+    // Sent ahead of the response, so the frontend can show what went into it
+    ws_send!(
+        websocket,
+        serialize_response!(
+            References,
+            References {
+                conversation_id: conversation.id.unwrap(),
+                references: references
+                    .iter()
+                    .zip(included.iter())
+                    .map(|(citation, (filepath, _))| Reference {
+                        citation: citation.clone(),
+                        similarity: similarities.get(filepath).copied(),
+                    })
+                    .collect(),
+            },
+            request_id.to_string()
+        )
+    );
+
+    // Update dewey with our message
+    match add_message_embedding(dewey, db, last_user_message, &filepath) {
+        Ok(_) => {}
+        Err(e) => {
+            lprint!(error, "Error adding user message to Dewey: {}; ignoring", e);
+        }
+    };
+
+    // Separate task to communicate with the LLM
+    // Message deltas are streamed back through the channel, followed by how the stream ended
+    // The full response message (e.g., for tool calls) is returned through the task handle
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<StreamEvent>();
+    let (retry_tx, retry_rx) = tokio::sync::mpsc::unbounded_channel::<RetryStatus>();
+    let task_system_prompt = system_prompt.clone();
+    let input_estimate = total_len + tokenizer.map_or(0, |t| t.count(&system_prompt));
+    let task_tools = conversation.tools.clone();
+    // The request's settings win over the conversation's, which win over the persona's
+    let persona_settings = persona.map(|p| p.settings).unwrap_or_default();
+    let settings = GenerationSettings {
+        temperature: conversation
+            .settings
+            .temperature
+            .or(conversation.overrides.temperature)
+            .or(persona_settings.temperature),
+        top_p: conversation.settings.top_p.or(persona_settings.top_p),
+        max_tokens: conversation
+            .settings
+            .max_tokens
+            .or(persona_settings.max_tokens),
+    };
+    let task_cancel = std::sync::Arc::clone(&claim.cancel);
+
+    // A response being continued goes along as it was left, for the model to pick up from
+    let history_len = match messages_payload.last() {
+        Some(m) if !m.content.is_empty() => messages_payload.len(),
+        _ => messages_payload.len() - 1,
+    };
+
+    attachments::load_images(&get_attachments_dir(), &mut messages_payload[..history_len]);
+
+    let trace = spans::Context::current();
+    let stream_task = spawn(spans::instrument(Some(request_id), trace, async move {
+        match network::prompt_stream(
+            api,
+            &messages_payload[..history_len].to_vec(),
+            &task_system_prompt,
+            &task_tools,
+            settings,
+            tx,
+            retry_tx,
+            &task_cancel,
+        )
+        .await
+        {
+            Ok(m) => Ok(m),
+            Err(e) => {
+                lprint!(error, "error sending message to GPT endpoint: {}", e);
+                Err(e)
+            }
+        }
+    }));
+
+    // A response being continued is already saved as far as it got
+    let saved_len = conversation.messages.last().map_or(0, |m| m.content.len());
+
+    claim.started(&conversation);
+    Some(ActiveCompletion {
+        request_id: request_id.to_string(),
+        claim,
+        compare_model,
+        conversation,
+        system_prompt,
+        references,
+        settings,
+        input_estimate,
+        message_received: false,
+        pending: String::new(),
+        last_flush: std::time::Instant::now(),
+        coalescing: DeltaCoalescing::from_env(),
+        saved_len,
+        last_save: std::time::Instant::now(),
+        checkpoints: CheckpointPolicy::from_env(),
+        outcome: None,
+        rx,
+        retry_rx,
+        stream_task,
+    })
+}
+
+// Saves the response as it's streamed so far, if it's due for it
+//
+// Only the content is saved--the finish reason stays unset until the stream's done,
+// so if William goes down first, the response is picked up by `recover_interrupted`
+fn checkpoint_response(
+    db: &std::sync::Arc<std::sync::Mutex<rusqlite::Connection>>,
+    active: &mut ActiveCompletion,
+) {
+    let response = active.conversation.messages.last().unwrap();
+    let unsaved_chars = response.content.len().saturating_sub(active.saved_len);
+    if !active
+        .checkpoints
+        .is_due(unsaved_chars, active.last_save.elapsed())
+    {
+        return;
+    }
+
+    // Failed saves wait out the interval too, rather than retrying every delta
+    // The database connection is the completion's own, so this is only ever waiting on SQLite
+    active.last_save = std::time::Instant::now();
+    match tokio::task::block_in_place(|| {
+        safe_lock!(db).execute(
+            "UPDATE messages SET content = ?2 WHERE id = ?1 AND finish_reason IS NULL",
+            params![response.id, response.content],
+        )
+    }) {
+        Ok(_) => active.saved_len = response.content.len(),
+        Err(e) => {
+            lprint!(error, "Error saving response in progress: {}; ignoring", e);
+        }
+    };
+}
+
+// Sends the completion's held deltas as one, and adds them to the response
+fn flush_deltas(websocket: &Connection, active: &mut ActiveCompletion) {
+    active.last_flush = std::time::Instant::now();
+    if active.pending.is_empty() {
+        return;
+    }
+
+    // Deltas (and the final upsert) go by the conversation's latest name
+    if let Some(name) = active.claim.name() {
+        active.conversation.name = name;
+    }
+
+    let delta = std::mem::take(&mut active.pending);
+    let conversation = &mut active.conversation;
+
+    // -2 to skip the last message, which is being filled by the active completion, and
+    // get the last user message
+    let request_message_id = conversation.messages[conversation.messages.len() - 2]
+        .id
+        .unwrap();
+
+    // Update the last message with the delta
+    // This is primarily for accurately storing things in the DB
+    let last = conversation.messages.last_mut().unwrap();
+    last.content.push_str(&delta);
+
+    if last.system_prompt.len() == 0 {
+        last.system_prompt = active.system_prompt.clone();
+    }
+
+    // Make sure conversation metadata is correctly set
+    let conversation_id = conversation.id.unwrap();
+    let response_id = last.id.unwrap();
+    let conversation_name = conversation.name.clone();
+
+    ws_send!(
+        websocket,
+        serialize_response!(
+            Completion,
+            Completion {
+                stream: true,
+                delta,
+                name: conversation_name,
+                conversation_id,
+                request_id: request_message_id,
+                response_id,
+                model: active.compare_model.clone(),
+            },
+            active.request_id.clone()
+        )
+    );
+}
+
+// Fills in the conversation's first user message from a saved template
+// Only a message that hasn't been stored yet is rendered--history isn't rewritten
+fn render_template(
+    conversation: &mut Conversation,
+    template: &TemplateInput,
+    db: &rusqlite::Connection,
+) -> Result<(), String> {
+    let body = match templates::get(template.template_id, db) {
+        Ok(Some(t)) => t.body,
+        Ok(None) => {
+            return Err(format!(
+                "No prompt template with ID {}",
+                template.template_id
+            ))
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let message = match conversation
+        .messages
+        .iter_mut()
+        .find(|m| m.message_type == MessageType::User)
+    {
+        Some(m) if m.id.is_none() => m,
+        _ => return Err("Templates can only start a conversation".to_string()),
+    };
+
+    message.content = templates::render(&body, &template.variables)?;
+
+    Ok(())
+}
+
+// Storage + bookkeeping once a completion's stream has ended
+// Returns the conversation as it was left, response included
+fn finish_completion(
+    websocket: &Connection,
+    mut active: ActiveCompletion,
+    tokenizer: Option<&tiktoken::Tokenizer>,
+    db: &rusqlite::Connection,
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+) -> Conversation {
+    if let Some(name) = active.claim.name() {
+        active.conversation.name = name;
+    }
+
+    let ActiveCompletion {
+        request_id,
+        claim,
+        mut conversation,
+        system_prompt,
+        references,
+        settings,
+        input_estimate,
+        message_received,
+        outcome,
+        stream_task,
+        ..
+    } = active;
+    let request_id = request_id.as_str();
+    let _finish = spans::span("completion.finish");
+
+    let cancelled = claim.is_cancelled();
+
+    // The stream has ended at this point, so the task is either finished or about to be
+    let (tool_calls, timing, stream_error) = match network::block_on(stream_task) {
+        Ok(Ok((response, _, timing))) => (response.tool_calls, Some(timing), None),
+        Ok(Err(e)) => (Vec::new(), None, Some(e)),
+        Err(_) => (Vec::new(), None, None),
+    };
+
+    let (usage, failure) = match outcome {
+        Some(StreamEvent::Done(usage)) => (usage, None),
+        Some(StreamEvent::Error(e)) => (None, Some(e)),
+        _ => (
+            None,
+            Some("Completion stream ended unexpectedly".to_string()),
+        ),
+    };
+
+    // Tool calls can come without any text deltas
+    // Whatever made it out before a failure is still kept
+    let completed = message_received || !tool_calls.is_empty();
+
+    let finish_reason = if cancelled {
+        "cancelled"
+    } else if failure.is_some() {
+        "error"
+    } else if !tool_calls.is_empty() {
+        "tool_calls"
+    } else if completed {
+        "stop"
+    } else {
+        "error"
+    };
+
+    if completed {
+        {
+            let last = conversation.messages.last_mut().unwrap();
+            last.tool_calls = tool_calls.clone();
+            if last.system_prompt.len() == 0 {
+                last.system_prompt = system_prompt.clone();
+            }
+
+            last.citations = citations::cite(&last.content, &references);
+        }
+
+        // Backend storage duties--SQLite + embedding generation/storage
+        // This happens before the tool calls are sent out so the response has an ID to
+        // reference
+        match conversation.upsert(db) {
+            Ok(_) => {}
+            Err(e) => {
+                ws_error!(
+                    websocket,
+                    "Completion",
+                    "Error upserting conversation in DB",
+                    e,
+                    request_id.to_string()
+                );
+            }
+        };
+
+        // Not every provider reports usage--
+        // those responses are counted here and marked with how they were counted
+        let usage = usage.or_else(|| {
+            let tok = tokenizer?;
+            lprint!(
+                info,
+                "No usage reported for completion {}; estimating with {}",
+                request_id,
+                tok.estimator().name()
+            );
+
+            Some(TokenUsage {
+                input_tokens: input_estimate,
+                output_tokens: tok.count(&conversation.messages.last().unwrap().content),
+                estimator: Some(tok.estimator().name().to_string()),
+            })
+        });
+
+        if let Some(usage) = usage {
+            match record_usage(
+                db,
+                conversation.messages.last().unwrap().id.unwrap(),
+                &usage,
+            ) {
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(error, "Error recording token usage: {}; ignoring", e);
+                }
+            };
+        } else {
+            lprint!(info, "No usage reported for completion {}", request_id);
+        }
+
+        let response = conversation.messages.last().unwrap();
+        if !response.citations.is_empty() {
+            match record_citations(db, response.id.unwrap(), &response.citations) {
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(error, "Error recording citations: {}; ignoring", e);
+                }
+            };
+        }
+
+        if let Some(timing) = timing {
+            match record_timing(
+                db,
+                conversation.messages.last().unwrap().id.unwrap(),
+                &timing,
+            ) {
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(error, "Error recording response timing: {}; ignoring", e);
+                }
+            };
+        }
+
+        if !settings.is_empty() {
+            match record_generation_settings(
+                db,
+                conversation.messages.last().unwrap().id.unwrap(),
+                &settings,
+            ) {
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(
+                        error,
+                        "Error recording generation settings: {}; ignoring",
+                        e
+                    );
+                }
+            };
+        }
+
+        if !tool_calls.is_empty() {
+            ws_send!(
+                websocket,
+                serialize_response!(
+                    ToolCall,
+                    ToolCallResponse {
+                        conversation_id: conversation.id.unwrap(),
+                        response_id: conversation.messages.last().unwrap().id.unwrap(),
+                        tool_calls,
+                    },
+                    request_id.to_string()
+                )
+            );
+        }
+
+        // Weird one-off response serialization
+        ws_send!(
+            websocket,
+            serialize_response!(
+                CompletionEnd,
+                SystemPrompt {
+                    content: system_prompt,
+                    citations: conversation.messages.last().unwrap().citations.clone(),
+                },
+                request_id.to_string()
+            )
+        );
+
+        if conversation.overrides.suggestions && finish_reason == "stop" {
+            spawn_suggestions(websocket, request_id, &conversation);
+        }
+
+        if message_received {
+            match add_response_embeddings(dewey, db, &conversation) {
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(
+                        error,
+                        "Error adding assistant message to Dewey: {}; ignoring",
+                        e
+                    );
+                }
+            };
+        }
+    } else if cancelled {
+        // Nothing to save
+        ws_send!(
+            websocket,
+            serialize_response!(
+                CompletionEnd,
+                SystemPrompt {
+                    content: system_prompt,
+                    citations: Vec::new(),
+                },
+                request_id.to_string()
+            )
+        );
+    } else {
+        lprint!(error, "Stream channel closing without receiving delta");
+    }
+
+    if let Some(response_id) = conversation.messages.last().and_then(|m| m.id) {
+        match set_finish_reason(db, response_id, Some(finish_reason)) {
+            Ok(_) => {}
+            Err(e) => {
+                lprint!(error, "Error recording finish reason: {}; ignoring", e);
+            }
+        };
+    }
+
+    // Errors the provider told us about get passed along as something the user can act on
+    // (fix the key, wait out the rate limit, trim the conversation, ...)
+    let provider_error = stream_error.as_ref().and_then(ProviderError::from_io);
+
+    if cancelled {
+        // Stopped on purpose--nothing to report
+    } else if let Some(e) = provider_error {
+        ws_send!(
+            websocket,
+            serialize_response!(
+                WilliamError,
+                WilliamError::from_provider(e),
+                request_id.to_string()
+            )
+        );
+    } else if let Some(e) = failure {
+        ws_error!(
+            websocket,
+            "Completion",
+            "Error streaming completion",
+            e,
+            request_id.to_string()
+        );
+    } else if !completed {
+        ws_error!(
+            websocket,
+            "Completion",
+            "Error receiving completion delta",
+            "The provider finished without responding",
+            request_id.to_string()
+        );
+    }
+
+    conversation
+}
+
+// Suggests follow-ups to the conversation's response in a separate task,
+// sending them along after its `CompletionEnd`--nothing should be waiting on them
+fn spawn_suggestions(websocket: &Connection, request_id: &str, conversation: &Conversation) {
+    let websocket = websocket.clone();
+    let task_request_id = request_id.to_string();
+    let conversation_id = conversation.id.unwrap();
+    let response_id = conversation.messages.last().unwrap().id.unwrap();
+    let messages = conversation.messages.clone();
+    let trace = spans::Context::current();
+    spawn(spans::instrument(Some(request_id), trace, async move {
+        let _span = spans::span("completion.suggestions");
+        match suggestions::suggest(&messages).await {
+            Ok(suggestions) => {
+                ws_send!(
+                    websocket,
+                    serialize_response!(
+                        Suggestions,
+                        Suggestions {
+                            conversation_id,
+                            response_id,
+                            suggestions,
+                        },
+                        task_request_id
+                    )
+                );
+            }
+            Err(e) => {
+                lprint!(error, "Error suggesting follow-ups: {}; ignoring", e);
+            }
+        };
+    }));
+}
+
+// Why a response stopped--`stop`, `tool_calls`, `cancelled`, or `error`
+// Responses still streaming have none
+fn set_finish_reason(
+    db: &rusqlite::Connection,
+    message_id: i64,
+    reason: Option<&str>,
+) -> rusqlite::Result<()> {
+    db.execute(
+        "UPDATE messages SET finish_reason = ?2 WHERE id = ?1",
+        params![message_id, reason],
+    )?;
+
+    Ok(())
+}
+
+// Nothing is streaming at start up, so any response without a finish reason was cut off when
+// William last shut down
+// These are flagged on `Load`, and can be finished with `Continue`
+//
+// Messages from before finish reasons were recorded got `stop` when the column was added
+fn recover_interrupted(db: &rusqlite::Connection) -> rusqlite::Result<usize> {
+    let count = db.execute(
+        "UPDATE messages SET finish_reason = 'interrupted' WHERE finish_reason IS NULL",
+        params![],
+    )?;
+
+    if count > 0 {
+        lprint!(info, "Found {} interrupted completions", count);
+    }
+
+    Ok(count)
+}
+
+fn record_generation_settings(
+    db: &rusqlite::Connection,
+    message_id: i64,
+    settings: &GenerationSettings,
+) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT OR REPLACE INTO generation_settings (message_id, temperature, top_p, max_tokens) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            message_id,
+            settings.temperature,
+            settings.top_p,
+            settings.max_tokens
+        ],
+    )?;
+
+    Ok(())
+}
+
+// The persona only fills in what the conversation leaves unset--it's looked up at completion time
+// rather than copied into the overrides so later edits to it carry over
+fn conversation_persona(conversation: &Conversation, db: &rusqlite::Connection) -> Option<Persona> {
+    let persona_id = conversation.overrides.persona_id?;
+    match personas::get(persona_id, db) {
+        Ok(persona) => persona,
+        Err(e) => {
+            lprint!(
+                error,
+                "Error loading persona {}: {}; ignoring",
+                persona_id,
+                e
+            );
+            None
+        }
+    }
+}
+
+// The conversation's system prompt, then its persona's, then the user config's
+fn conversation_instructions(
+    conversation: &Conversation,
+    persona: Option<&Persona>,
+    db: &rusqlite::Connection,
+) -> Option<String> {
+    conversation
+        .overrides
+        .system_prompt
+        .clone()
+        .or_else(|| {
+            persona
+                .map(|p| p.system_prompt.clone())
+                .filter(|p| !p.trim().is_empty())
+        })
+        .or_else(|| global_system_prompt(db))
+        .filter(|p| !p.trim().is_empty())
+}
+
+// `completion`'s prompt building, minus anything that'd go out to a provider or to Dewey
+// The stored summary is used as-is, even if the next completion would bring it up to date
+fn context_breakdown(
+    conversation: &Conversation,
+    tokenizer: Option<&tiktoken::Tokenizer>,
+    db: &rusqlite::Connection,
+) -> Result<ContextBreakdown, String> {
+    if conversation.messages.is_empty() {
+        return Err("Conversation has no messages".to_string());
+    }
+
+    let persona = conversation_persona(conversation, db);
+    let api = conversation
+        .overrides
+        .model
+        .clone()
+        .or_else(|| persona.as_ref().and_then(|p| p.model.clone()))
+        .or_else(|| {
+            conversation
+                .messages
+                .iter()
+                .rev()
+                .find(|m| m.message_type == MessageType::User)
+                .map(|m| m.api.clone())
+        })
+        .ok_or_else(|| "Conversation has no messages from the user".to_string())?;
+
+    let (total_len, included) = cutoff_messages(&conversation.messages, tokenizer);
+    let summary = if included.len() < conversation.messages.len() {
+        db.query_row(
+            "SELECT summary FROM conversations WHERE id = ?1",
+            params![conversation.id],
+            |row| row.get::<_, Option<String>>(0),
+        )
+        .map_err(|e| e.to_string())?
+    } else {
+        None
+    };
+
+    let instructions = conversation_instructions(conversation, persona.as_ref(), db);
+    let (system_prompt, _) = build_system_prompt(
+        &api,
+        total_len,
+        &Vec::new(),
+        instructions.as_deref(),
+        summary.as_deref(),
+        tokenizer,
+    );
+
+    let last_prompt = conversation
+        .messages
+        .iter()
+        .rev()
+        .find(|m| m.message_type == MessageType::Assistant && !m.system_prompt.is_empty())
+        .map(|m| m.system_prompt.as_str());
+
+    Ok(context::breakdown(
+        conversation,
+        &api,
+        &system_prompt,
+        last_prompt,
+        included.len(),
+        tokenizer,
+    ))
+}
+
+// The system prompt from the user config, for conversations without their own
+fn global_system_prompt(db: &rusqlite::Connection) -> Option<String> {
+    match db.query_row("SELECT system_prompt FROM user_config LIMIT 1", [], |row| {
+        row.get::<_, String>(0)
+    }) {
+        Ok(p) => Some(p),
+        Err(rusqlite::Error::QueryReturnedNoRows) => None,
+        Err(e) => {
+            lprint!(error, "Error fetching system prompt: {}; ignoring", e);
+            None
+        }
+    }
+}
+
+fn record_usage(
+    db: &rusqlite::Connection,
+    message_id: i64,
+    usage: &TokenUsage,
+) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT OR REPLACE INTO usage (message_id, input_tokens, output_tokens, estimator) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            message_id,
+            usage.input_tokens,
+            usage.output_tokens,
+            usage.estimator.as_deref().unwrap_or("provider")
+        ],
+    )?;
+
+    Ok(())
+}
+
+fn record_citations(
+    db: &rusqlite::Connection,
+    message_id: i64,
+    citations: &[Citation],
+) -> rusqlite::Result<()> {
+    db.execute(
+        "UPDATE messages SET citations = ?2 WHERE id = ?1",
+        params![message_id, serde_json::to_string(citations).unwrap()],
+    )?;
+
+    Ok(())
+}
+
+fn record_timing(
+    db: &rusqlite::Connection,
+    message_id: i64,
+    timing: &ResponseTiming,
+) -> rusqlite::Result<()> {
+    db.execute(
+        "UPDATE messages SET time_to_first_token_ms = ?2, generation_ms = ?3 WHERE id = ?1",
+        params![
+            message_id,
+            timing.time_to_first_token_ms,
+            timing.generation_ms
+        ],
+    )?;
+
+    Ok(())
+}
+
+// User input as an FTS5 query
+// Each term is quoted so punctuation doesn't get parsed as query syntax,
+// and the terms are implicitly AND'd together
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// Search message content across every conversation
+// Results are grouped by conversation, ordered by each conversation's best match
+fn search_conversations(
+    query: &str,
+    limit: usize,
+    db: &rusqlite::Connection,
+) -> rusqlite::Result<Vec<SearchResult>> {
+    let mut stmt = db.prepare(
+        "
+        SELECT DISTINCT
+            c.id,
+            c.name,
+            m.id,
+            snippet(messages_fts, 0, '', '', '...', 16)
+        FROM messages_fts
+        JOIN messages m ON m.id = messages_fts.rowid
+        JOIN paths l ON l.message_id = m.id
+        JOIN conversations c ON c.id = l.conversation_id
+        WHERE messages_fts MATCH ?1
+        AND c.deleted_at IS NULL
+        ORDER BY bm25(messages_fts)
+        LIMIT ?2
+        ",
+    )?;
+
+    let rows = stmt
+        .query_map(params![fts_query(query), limit], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                SearchMatch {
+                    message_id: row.get(2)?,
+                    snippet: row.get(3)?,
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut results: Vec<SearchResult> = Vec::new();
+    for (conversation_id, name, search_match) in rows {
+        match results
+            .iter_mut()
+            .find(|r| r.conversation_id == conversation_id)
+        {
+            Some(r) => r.matches.push(search_match),
+            None => results.push(SearchResult {
+                conversation_id,
+                name,
+                matches: vec![search_match],
+            }),
+        }
+    }
+
+    Ok(results)
+}
+
+// Keyword matches to use as references
+// Unlike `fts_query`, any of the terms can match--bm25 sorts out which hits are worth anything
+//
+// Only messages with embedding files are considered, so results line up with Dewey's
+fn keyword_sources(
+    query: &str,
+    exclude_conversation: Option<i64>,
+    limit: usize,
+    db: &rusqlite::Connection,
+) -> rusqlite::Result<Vec<String>> {
+    let terms = fts_query(query)
+        .split(' ')
+        .collect::<Vec<&str>>()
+        .join(" OR ");
+    if terms.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut stmt = db.prepare(
+        "
+        SELECT DISTINCT me.filepath
+        FROM messages_fts
+        JOIN message_embeddings me ON me.message_id = messages_fts.rowid
+        WHERE messages_fts MATCH ?1
+        AND me.kind = 'message'
+        AND me.message_id NOT IN (
+            SELECT message_id FROM paths WHERE conversation_id = ?2
+        )
+        AND EXISTS (
+            SELECT 1
+            FROM paths l
+            JOIN conversations c ON c.id = l.conversation_id
+            WHERE l.message_id = me.message_id
+            AND c.deleted_at IS NULL
+        )
+        ORDER BY bm25(messages_fts)
+        LIMIT ?3
+        ",
+    )?;
+
+    let files = stmt
+        .query_map(params![terms, exclude_conversation, limit], |row| {
+            row.get::<_, String>(0)
+        })?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    Ok(files)
+}
+
+// Merges ranked lists of filepaths, scoring each file by the sum of 1 / (k + rank) across the
+// lists it shows up in
+// Files ranked well by both searches float to the top without needing comparable scores
+fn reciprocal_rank_fusion(rankings: &[Vec<String>], k: f64, limit: usize) -> Vec<String> {
+    let mut scores: Vec<(String, f64)> = Vec::new();
+    for ranking in rankings {
+        for (rank, filepath) in ranking.iter().enumerate() {
+            let score = 1.0 / (k + rank as f64 + 1.0);
+            match scores.iter_mut().find(|(f, _)| f == filepath) {
+                Some((_, s)) => *s += score,
+                None => scores.push((filepath.clone(), score)),
+            }
+        }
+    }
+
+    // Stable, so ties keep the order of the earlier rankings
+    scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    scores.truncate(limit);
+
+    scores.into_iter().map(|(f, _)| f).collect()
+}
+
+fn fuse_sources(
+    strategy: FusionStrategy,
+    semantic: Vec<dewey_lib::EmbeddingSource>,
+    keyword: Vec<String>,
+    limit: usize,
+) -> Vec<dewey_lib::EmbeddingSource> {
+    let filepaths = match strategy {
+        FusionStrategy::Semantic => return semantic,
+        FusionStrategy::Keyword => keyword,
+        FusionStrategy::ReciprocalRank => reciprocal_rank_fusion(
+            &[
+                semantic.iter().map(|s| s.filepath.clone()).collect(),
+                keyword,
+            ],
+            60.0,
+            limit,
+        ),
+    };
+
+    filepaths
+        .into_iter()
+        .map(|filepath| {
+            semantic
+                .iter()
+                .find(|s| s.filepath == filepath)
+                .cloned()
+                .unwrap_or(dewey_lib::EmbeddingSource {
+                    filepath,
+                    meta: std::collections::HashSet::new(),
+                    subset: None,
+                })
+        })
+        .collect()
+}
+
+fn export_conversation(
+    request: &ExportRequest,
+    db: &rusqlite::Connection,
+) -> Result<ExportResponse, std::io::Error> {
+    let conversation = get_conversation(request.conversation_id, db);
+    if conversation.messages.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("conversation {} not found", request.conversation_id),
+        ));
+    }
+
+    let content = export::render(&conversation, request.format)?;
+
+    match &request.path {
+        Some(path) => {
+            let mut path = std::path::PathBuf::from(path);
+            if path.is_dir() {
+                path = path.join(export::filename(&conversation, request.format));
+            }
+
+            std::fs::write(&path, content)?;
+            lprint!(
+                info,
+                "Exported conversation {} to {}",
+                request.conversation_id,
+                path.display()
+            );
+
+            Ok(ExportResponse {
+                content: None,
+                path: Some(path.to_string_lossy().to_string()),
+            })
+        }
+        None => Ok(ExportResponse {
+            content: Some(match request.format {
+                ExportFormat::Pdf => base64::engine::general_purpose::STANDARD.encode(content),
+                _ => String::from_utf8_lossy(&content).to_string(),
+            }),
+            path: None,
+        }),
+    }
+}
+
+fn export_flashcards(
+    request: &ExportFlashcardsRequest,
+    db: &rusqlite::Connection,
+) -> Result<FlashcardExport, std::io::Error> {
+    let conversation = get_conversation(request.conversation_id, db);
+    if conversation.messages.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("conversation {} not found", request.conversation_id),
+        ));
+    }
+
+    let api = request
+        .api
+        .clone()
+        .or_else(|| network::model_from_env("WILLIAM_FLASHCARD_MODEL"))
+        .unwrap_or(API::OpenAI(OpenAIModel::GPT4oMini));
+    let cards = network::block_on(flashcards::generate(&conversation, api))?;
+
+    let path = get_exports_dir().join(format!("{}-flashcards.txt", export::stem(&conversation)));
+    std::fs::write(&path, flashcards::render_tsv(&cards, &conversation.name))?;
+    lprint!(
+        info,
+        "Exported {} flashcards from conversation {} to {}",
+        cards.len(),
+        request.conversation_id,
+        path.display()
+    );
+
+    Ok(FlashcardExport {
+        path: path.to_string_lossy().to_string(),
+        cards,
+    })
+}
+
+// Saves the conversations from another app's export
+// Everything is written in one transaction--embeddings, if requested, come after
+fn import_conversations(
+    request: &ImportRequest,
+    db: &rusqlite::Connection,
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+) -> Result<ImportResponse, Box<dyn std::error::Error>> {
+    let now = std::time::Instant::now();
+    let (format, imported) = import::read(std::path::Path::new(&request.path), request.format)?;
+
+    let tx = db.unchecked_transaction()?;
+    let mut conversations = Vec::new();
+    let mut skipped = 0;
+    for item in imported {
+        let exists = tx
+            .prepare("SELECT 1 FROM imports WHERE source = ?1 AND external_id = ?2")?
+            .exists(params![format.to_str(), item.external_id])?;
+
+        if exists {
+            skipped += 1;
+            continue;
+        }
+
+        let mut conversation = item.conversation;
+        conversation.upsert(&tx)?;
+
+        // Keep the original timestamps instead of the time of import
+        for message in conversation.messages.iter() {
+            if !message.date_created.is_empty() {
+                tx.execute(
+                    "UPDATE messages SET date_created = ?2 WHERE id = ?1",
+                    params![message.id, message.date_created],
+                )?;
+            }
+        }
+
+        let last_updated = conversation
+            .messages
+            .iter()
+            .map(|m| m.date_created.as_str())
+            .filter(|d| !d.is_empty())
+            .max();
+
+        if let (Some(created), Some(updated)) = (&item.date_created, last_updated) {
+            tx.execute(
+                "UPDATE conversations SET date_created = ?2, last_updated = ?3 WHERE id = ?1",
+                params![conversation.id, created, updated],
+            )?;
+        }
+
+        tx.execute(
+            "INSERT INTO imports (source, external_id, conversation_id) VALUES (?1, ?2, ?3)",
+            params![format.to_str(), item.external_id, conversation.id],
+        )?;
+
+        conversations.push(conversation);
+    }
+
+    tx.commit()?;
+
+    lprint!(
+        info,
+        "Imported {} conversations ({} skipped) in {}ms",
+        conversations.len(),
+        skipped,
+        now.elapsed().as_millis()
+    );
+
+    if request.embed && safe_lock!(dewey).is_some() {
+        let mut queue = EmbeddingQueue::default();
+        for message in conversations.iter().flat_map(|c| c.messages.iter()) {
+            match queue.push(
+                db,
+                message.id,
+                MESSAGE_EMBEDDING,
+                &message.content,
+                &new_embedding_path(),
+            ) {
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(error, "Error embedding imported message: {}; ignoring", e);
+                }
+            };
+        }
+
+        queue.flush(db, dewey);
+    } else if request.embed {
+        lprint!(info, "Dewey unavailable, ignoring embedding request");
+    }
+
+    Ok(ImportResponse {
+        conversation_ids: conversations.iter().filter_map(|c| c.id).collect(),
+        skipped,
+    })
+}
+
+// Embedding source files for every message in a conversation
+fn get_embedding_files(conversation_id: i64, db: &rusqlite::Connection) -> Vec<String> {
+    let mut query = match db.prepare(
+        "
+        SELECT DISTINCT me.filepath
+        FROM message_embeddings me
+        JOIN paths l ON me.message_id = l.message_id
+        WHERE l.conversation_id = ?1
+        ",
+    ) {
+        Ok(q) => q,
+        Err(e) => {
+            lprint!(error, "Error preparing embedding file query: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let files = match query.query_map(params![conversation_id], |row| row.get::<_, String>(0)) {
+        Ok(rows) => rows.filter_map(|r| r.ok()).collect(),
+        Err(e) => {
+            lprint!(error, "Error fetching embedding files: {}", e);
+            Vec::new()
+        }
+    };
+
+    files
+}
+
+// Conversations outside of the trash, pinned first and then most recently updated
+// Only the IDs, names, flags, and unread counts are filled in
+//
+// Messages from the user don't count as unread,
+// and conversations that have never been marked read have nothing unread
+fn get_conversation_list(
+    archived: bool,
+    db: &rusqlite::Connection,
+) -> rusqlite::Result<Vec<Conversation>> {
+    let mut query = db.prepare(
+        "
+        SELECT
+            c.id,
+            c.name,
+            c.pinned,
+            c.archived,
+            (
+                SELECT COUNT(*)
+                FROM paths l
+                JOIN messages m ON m.id = l.message_id
+                WHERE l.conversation_id = c.id
+                AND l.branch_id = c.active_branch_id
+                AND m.id > c.last_read_message_id
+                AND m.message_type_id != ?2
+            )
+        FROM conversations c
+        WHERE c.deleted_at IS NULL
+        AND c.archived = ?1
+        ORDER BY c.pinned DESC, c.last_updated DESC
+        ",
+    )?;
+
+    let conversations = query
+        .query_map(params![archived, MessageType::User.id()], |row| {
+            Ok(Conversation {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                messages: Vec::new(),
+                tools: Vec::new(),
+                overrides: ConversationOverrides::default(),
+                branch_id: None,
+                settings: GenerationSettings::default(),
+                pinned: row.get(2)?,
+                archived: row.get(3)?,
+                unread: row.get(4)?,
+                template: None,
+                memory_scope: MemoryScope::All,
+                related: Vec::new(),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<Conversation>>>()?;
+
+    Ok(conversations)
+}
+
+// Sets one of a conversation's flags, responding with the updated conversation list
+fn set_conversation_flag(
+    websocket: &mut Connection,
+    db: &rusqlite::Connection,
+    request_id: &str,
+    conversation_id: i64,
+    flag: &str,
+    value: bool,
+) {
+    // `flag` is only ever one of the column names below
+    let statement = match flag {
+        "pinned" => "UPDATE conversations SET pinned = ?2 WHERE id = ?1",
+        "archived" => "UPDATE conversations SET archived = ?2 WHERE id = ?1",
+        _ => unreachable!("unknown conversation flag {}", flag),
+    };
+
+    match db.execute(statement, params![conversation_id, value]) {
+        Ok(0) => {
+            ws_error!(
+                websocket,
+                "ConversationFlag",
+                "Conversation not found",
+                conversation_id,
+                request_id.to_string()
+            );
+            return;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            ws_error!(
+                websocket,
+                "ConversationFlag",
+                "Error updating conversation",
+                e,
+                request_id.to_string()
+            );
+            return;
+        }
+    };
+
+    match get_conversation_list(false, db) {
+        Ok(conversations) => {
+            ws_send!(
+                websocket,
+                serialize_response!(
+                    ConversationList,
+                    ConversationList { conversations },
+                    request_id.to_string()
+                )
+            );
+        }
+        Err(e) => {
+            ws_error!(
+                websocket,
+                "ConversationList",
+                "Error fetching conversation IDs",
+                e,
+                request_id.to_string()
+            );
+        }
+    };
+}
+
+// Days deleted conversations are kept before being purged, from `WILLIAM_TRASH_RETENTION_DAYS`
+// `WILLIAM_TRASH_RETENTION_DAYS` is set from the user config, like the API keys
+fn trash_retention_days() -> u32 {
+    std::env::var("WILLIAM_TRASH_RETENTION_DAYS")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(30)
+}
+
+// Conversations in the trash, most recently deleted first
+fn get_trash(db: &rusqlite::Connection) -> rusqlite::Result<TrashList> {
+    let mut query = db.prepare(
+        "
+        SELECT id, name, deleted_at
+        FROM conversations
+        WHERE deleted_at IS NOT NULL
+        ORDER BY deleted_at DESC
+        ",
+    )?;
+
+    let conversations = query
+        .query_map(params![], |row| {
+            Ok(TrashedConversation {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                deleted_at: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<TrashedConversation>>>()?;
+
+    Ok(TrashList {
+        conversations,
+        retention_days: trash_retention_days(),
+    })
+}
+
+// Embedding files none of whose messages are in a conversation outside the trash--
+// duplicate messages share a file (see `EmbeddingQueue::push`)
+// These shouldn't be turning up as references
+fn trashed_embedding_files(
+    db: &rusqlite::Connection,
+) -> rusqlite::Result<std::collections::HashSet<String>> {
+    let mut query = db.prepare(
+        "
+        SELECT DISTINCT me.filepath
+        FROM message_embeddings me
+        WHERE NOT EXISTS (
+            SELECT 1
+            FROM message_embeddings o
+            JOIN paths l ON l.message_id = o.message_id
+            JOIN conversations c ON c.id = l.conversation_id
+            WHERE o.filepath = me.filepath
+            AND c.deleted_at IS NULL
+        )
+        ",
+    )?;
+
+    let files = query
+        .query_map(params![], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<std::collections::HashSet<String>>>()?;
+
+    Ok(files)
+}
+
+// Permanently delete conversations that have been in the trash for at least `retention_days`
+//
+// Returns the number of conversations purged
+fn purge_trash(db: &rusqlite::Connection, retention_days: u32) -> rusqlite::Result<usize> {
+    purge_conversations(
+        db,
+        "from the trash",
+        "deleted_at IS NOT NULL AND deleted_at <= datetime('now', ?1)",
+        format!("-{} days", retention_days),
+    )
+}
+
+// Hours an abandoned conversation is kept, from `WILLIAM_EMPTY_CONVERSATION_HOURS`
+// `WILLIAM_EMPTY_CONVERSATION_HOURS` is set from the user config, like the API keys
+fn empty_conversation_hours() -> u32 {
+    std::env::var("WILLIAM_EMPTY_CONVERSATION_HOURS")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(24)
+}
+
+const EMPTY_CONVERSATION_SWEEP_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(60 * 60);
+
+// Permanently delete conversations that never got a response and haven't been touched in `hours`
+// These are mostly conversations started and abandoned, still going by their placeholder names
+//
+// Returns the number of conversations deleted
+fn delete_empty_conversations(db: &rusqlite::Connection, hours: u32) -> rusqlite::Result<usize> {
+    if hours == 0 {
+        return Ok(0);
+    }
+
+    purge_conversations(
+        db,
+        "left empty",
+        "deleted_at IS NULL
+         AND pinned = 0
+         AND last_updated <= datetime('now', ?1)
+         AND NOT EXISTS (
+             SELECT 1
+             FROM paths l
+             JOIN messages m ON m.id = l.message_id
+             JOIN message_types mt ON mt.id = m.message_type_id
+             WHERE l.conversation_id = conversations.id
+             AND mt.name = 'assistant'
+             AND m.content != ''
+         )",
+        format!("-{} hours", hours),
+    )
+}
+
+// Deletes the conversations matching `condition` (with `arg` bound to `?1`),
+// along with everything left behind once they're gone:
+// - paths + branches, which cascade, plus those from conversations deleted before foreign keys
+//   were enforced
+// - messages that aren't on any path (or in any snapshot) anymore, with their usage, settings,
+//   and embedding rows
+// - the embedding source files on disk, unless a message that's still around shares one
+// - attachments no message references anymore
+//
+// TODO: the embeddings themselves stay in Dewey's index--purging runs without Dewey on hand
+//       to `remove_embeddings` from (see `forget_memories`)
+//
+// `description` says where the conversations came from, for the log
+fn purge_conversations(
+    db: &rusqlite::Connection,
+    description: &str,
+    condition: &str,
+    arg: String,
+) -> rusqlite::Result<usize> {
+    let tx = db.unchecked_transaction()?;
+
+    // Paths, branches, forks, imports, and snapshots of the conversation cascade
+    let conversations = tx.execute(
+        &format!("DELETE FROM conversations WHERE {}", condition),
+        params![arg],
+    )?;
+
+    tx.execute_batch(
+        "
+        DELETE FROM branches WHERE conversation_id NOT IN (SELECT id FROM conversations);
+        DELETE FROM forks
+        WHERE from_id NOT IN (SELECT id FROM conversations)
+        OR to_id NOT IN (SELECT id FROM conversations);
+        DELETE FROM imports WHERE conversation_id NOT IN (SELECT id FROM conversations);
+        ",
+    )?;
+
+    let files = {
+        let mut query = tx.prepare(
+            "
+            SELECT DISTINCT me.filepath
+            FROM message_embeddings me
+            WHERE NOT EXISTS (
+                SELECT 1
+                FROM message_embeddings o
+                WHERE o.filepath = me.filepath
+                AND (
+                    o.message_id IN (SELECT message_id FROM paths)
+                    OR o.message_id IN (SELECT message_id FROM snapshot_messages)
+                )
+            )
+            ",
+        )?;
+        let files = query
+            .query_map(params![], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        files
+    };
+
+    attachments::release_orphaned(&tx)?;
+    let blobs = attachments::delete_unreferenced(&tx)?;
+
+    // Live conversations can still point at messages that were edited off of every path
+    tx.execute_batch(
+        "
+        UPDATE conversations SET last_read_message_id = NULL
+        WHERE last_read_message_id NOT IN (SELECT message_id FROM paths);
+        UPDATE conversations SET summary_message_id = NULL
+        WHERE summary_message_id NOT IN (SELECT message_id FROM paths);
+        ",
+    )?;
+
+    // Usage, settings, translations, and embedding rows cascade
+    let messages = tx.execute(
+        "DELETE FROM messages
+         WHERE id NOT IN (SELECT message_id FROM paths)
+         AND id NOT IN (SELECT message_id FROM snapshot_messages)",
+        params![],
+    )?;
+
+    tx.commit()?;
+
+    attachments::remove_blobs(&get_attachments_dir(), &blobs);
+    for hash in blobs.iter() {
+        match std::fs::remove_dir_all(get_documents_dir().join(hash)) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                lprint!(
+                    error,
+                    "Error removing document chunks for {}: {}; ignoring",
+                    hash,
+                    e
+                );
+            }
+        };
+    }
+
+    for file in files.iter() {
+        match std::fs::remove_file(file) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                lprint!(
+                    error,
+                    "Error removing embedding file {}: {}; ignoring",
+                    file,
+                    e
+                );
+            }
+        };
+    }
+
+    lprint!(
+        info,
+        "Purged {} conversations {} ({} orphaned messages, {} embedding files, {} attachments)",
+        conversations,
+        description,
+        messages,
+        files.len(),
+        blobs.len()
+    );
+
+    Ok(conversations)
+}
+
+// Puts a system message at `index` of the conversation and saves the branch's new path
+// It takes the model of the message before it, since every message needs one
+fn insert_system_message(
+    conversation: &mut Conversation,
+    index: usize,
+    content: String,
+    db: &rusqlite::Connection,
+) -> Result<(), chamber_common::ChamberError> {
+    let api = match conversation.messages.get(index.saturating_sub(1)) {
+        Some(m) => m.api.clone(),
+        None => {
+            return Err(chamber_common::ChamberError::new(
+                "InsertSystemMessage",
+                "inserting a system message",
+                format!("conversation {:?} has no messages", conversation.id),
+            ));
+        }
+    };
+
+    conversation.messages.insert(
+        index,
+        Message {
+            id: None,
+            message_type: MessageType::System,
+            content,
+            api,
+            system_prompt: String::new(),
+            sequence: index as i32,
+            date_created: String::new(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            attachments: Vec::new(),
+            interrupted: false,
+            language: None,
+            citations: Vec::new(),
+            moderation: None,
+        },
+    );
+
+    // Everything after it moves down one
+    for (sequence, message) in conversation.messages.iter_mut().enumerate() {
+        message.sequence = sequence as i32;
+    }
+
+    conversation
+        .upsert(db)
+        .map_err(|e| chamber_common::ChamberError::new("Database", "saving a system message", e))?;
+
+    Ok(())
+}
+
+// Fetch a whole conversation from SQLite with a given ID
+fn get_conversation(conversation_id: i64, db: &rusqlite::Connection) -> Conversation {
+    get_conversation_branch(conversation_id, None, db)
+}
+
+// The conversation as seen from one of its branches--the active one if `branch_id` is `None`
+fn get_conversation_branch(
+    conversation_id: i64,
+    branch_id: Option<i64>,
+    db: &rusqlite::Connection,
+) -> Conversation {
+    let mut query = db
+        .prepare(
+            "
+            SELECT
+                c.id as conversation_id,
+                c.name as conversation_name,
+                m.id as message_id,
+                m.message_type_id,
+                m.content,
+                api.provider,
+                api.name,
+                m.system_prompt,
+                l.sequence,
+                m.date_created,
+                m.tool_calls,
+                m.tool_call_id,
+                c.tools,
+                dm.provider as default_provider,
+                dm.name as default_model,
+                c.temperature,
+                c.system_prompt as conversation_system_prompt,
+                l.branch_id,
+                c.pinned,
+                c.archived,
+                m.finish_reason,
+                c.persona_id,
+                m.language,
+                c.repositories,
+                c.suggestions,
+                m.citations,
+                m.moderation
+            FROM conversations c
+            JOIN paths l
+                ON c.id = l.conversation_id
+                AND l.branch_id = COALESCE(?2, c.active_branch_id)
+            JOIN messages m ON l.message_id = m.id
+            JOIN models api ON m.api_config_id = api.id
+            LEFT JOIN models dm ON c.default_api_config_id = dm.id
+            WHERE c.id = ?1
+            ORDER BY l.sequence ASC
+            ",
+        )
+        .unwrap();
+
+    let rows = query
+        .query_map(params![conversation_id, branch_id], |row| {
+            let provider = row.get::<_, String>("provider")?;
+            let model_name = row.get::<_, String>("name")?;
+            let api = API::from_strings(&provider, &model_name)
+                .map_err(|e| rusqlite::Error::InvalidParameterName(e))?;
+
+            let model = match (
+                row.get::<_, Option<String>>("default_provider")?,
+                row.get::<_, Option<String>>("default_model")?,
+            ) {
+                (Some(provider), Some(model_name)) => {
+                    API::from_strings(&provider, &model_name).ok()
+                }
+                _ => None,
+            };
+
+            Ok((
+                row.get::<_, i64>("conversation_id")?,
+                row.get::<_, String>("conversation_name")?,
+                row.get::<_, i64>("message_id")?,
+                MessageType::from_id(row.get::<_, i64>("message_type_id")?).unwrap(),
+                row.get::<_, String>("content")?,
+                api,
+                row.get::<_, String>("system_prompt")?,
+                row.get::<_, i32>("sequence")?,
+                row.get::<_, String>("date_created")?,
+                serde_json::from_str::<Vec<ToolCall>>(&row.get::<_, String>("tool_calls")?)
+                    .unwrap_or_default(),
+                row.get::<_, Option<String>>("tool_call_id")?,
+                serde_json::from_str::<Vec<Tool>>(&row.get::<_, String>("tools")?)
+                    .unwrap_or_default(),
+                ConversationOverrides {
+                    model,
+                    temperature: row.get::<_, Option<f32>>("temperature")?,
+                    system_prompt: row.get::<_, Option<String>>("conversation_system_prompt")?,
+                    persona_id: row.get::<_, Option<i64>>("persona_id")?,
+                    repositories: serde_json::from_str::<Vec<i64>>(
+                        &row.get::<_, String>("repositories")?,
+                    )
+                    .unwrap_or_default(),
+                    suggestions: row.get::<_, bool>("suggestions")?,
+                },
+                row.get::<_, Option<i64>>("branch_id")?,
+                row.get::<_, bool>("pinned")?,
+                row.get::<_, bool>("archived")?,
+                row.get::<_, Option<String>>("finish_reason")?.as_deref() == Some("interrupted"),
+                row.get::<_, Option<String>>("language")?,
+                serde_json::from_str::<Vec<Citation>>(&row.get::<_, String>("citations")?)
+                    .unwrap_or_default(),
+                row.get::<_, Option<String>>("moderation")?
+                    .and_then(|m| serde_json::from_str::<Moderation>(&m).ok()),
+            ))
+        })
+        .unwrap();
+
+    let mut conversation = Conversation {
+        id: Some(conversation_id),
+        name: String::new(),
+        messages: Vec::new(),
+        tools: Vec::new(),
+        overrides: ConversationOverrides::default(),
+        branch_id: None,
+        settings: GenerationSettings::default(),
+        pinned: false,
+        archived: false,
+        unread: 0,
+        template: None,
+        memory_scope: MemoryScope::All,
+        related: Vec::new(),
+    };
+
+    let mut attachments = match attachments::get_attachments(conversation_id, db) {
+        Ok(a) => a,
+        Err(e) => {
+            lprint!(error, "Error loading attachments: {}; ignoring", e);
+            std::collections::HashMap::new()
+        }
+    };
+
+    for row in rows {
+        let row = row.unwrap();
+        conversation.name = row.1;
+        conversation.tools = row.11;
+        conversation.overrides = row.12;
+        conversation.branch_id = row.13;
+        conversation.pinned = row.14;
+        conversation.archived = row.15;
+        conversation.messages.push(Message {
+            id: Some(row.2),
+            message_type: row.3,
+            content: row.4,
+            api: row.5,
+            system_prompt: row.6,
+            sequence: row.7,
+            date_created: row.8,
+            tool_calls: row.9,
+            tool_call_id: row.10,
+            attachments: attachments.remove(&row.2).unwrap_or_default(),
+            interrupted: row.16,
+            language: row.17,
+            citations: row.18,
+            moderation: row.19,
+        });
+    }
+
+    conversation.related = match related::list(conversation_id, db) {
+        Ok(r) => r,
+        Err(e) => {
+            lprint!(
+                error,
+                "Error loading related conversations: {}; ignoring",
+                e
+            );
+            Vec::new()
+        }
+    };
+
+    conversation
+}
+
+// Every branch of a conversation, oldest first
+fn get_branches(conversation_id: i64, db: &rusqlite::Connection) -> rusqlite::Result<BranchList> {
+    let active_branch_id = db.query_row(
+        "SELECT active_branch_id FROM conversations WHERE id = ?1",
+        params![conversation_id],
+        |row| row.get::<_, Option<i64>>(0),
+    )?;
+
+    let mut query = db.prepare(
+        "
+        SELECT
+            b.id,
+            b.parent_branch_id,
+            b.fork_sequence,
+            b.date_created,
+            COUNT(l.id) as message_count,
+            b.name
+        FROM branches b
+        LEFT JOIN paths l ON l.branch_id = b.id
+        WHERE b.conversation_id = ?1
+        GROUP BY b.id
+        ORDER BY b.id ASC
+        ",
+    )?;
+
+    let branches = query
+        .query_map(params![conversation_id], |row| {
+            Ok(Branch {
+                id: row.get(0)?,
+                parent_branch_id: row.get(1)?,
+                fork_sequence: row.get(2)?,
+                date_created: row.get(3)?,
+                message_count: row.get(4)?,
+                name: row.get(5)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<Branch>>>()?;
+
+    Ok(BranchList {
+        conversation_id,
+        active_branch_id,
+        branches,
+    })
+}
+
+// Fetch the first message of a conversation from SQLite with a given ID
+// `None` if the conversation doesn't exist or has no messages
+fn get_first_message(
+    conversation_id: i64,
+    db: &rusqlite::Connection,
+) -> Result<Option<Message>, chamber_common::ChamberError> {
+    db::query_row(
+        db,
+        "
+        SELECT
+            m.id as message_id,
+            m.message_type_id,
+            m.content,
+            api.provider,
+            api.name,
+            m.system_prompt,
+            l.sequence,
+            m.date_created
+        FROM conversations c
+        JOIN paths l ON c.id = l.conversation_id
+        JOIN messages m ON l.message_id = m.id
+        JOIN models api ON m.api_config_id = api.id
+        WHERE c.id = ?1
+        ORDER BY l.sequence ASC
+        LIMIT 1
+        ",
+        params![conversation_id],
+        |row| {
+            let provider = row.get::<_, String>("provider")?;
+            let model_name = row.get::<_, String>("name")?;
+            let api = API::from_strings(&provider, &model_name)
+                .map_err(|e| rusqlite::Error::InvalidParameterName(e.to_string()))?;
+            let message_type = MessageType::from_id(row.get::<_, i64>("message_type_id")?)
+                .map_err(rusqlite::Error::InvalidParameterName)?;
+
+            Ok(Message {
+                id: Some(row.get::<_, i64>("message_id")?),
+                message_type,
+                content: row.get::<_, String>("content")?,
+                api,
+                system_prompt: row.get::<_, String>("system_prompt")?,
+                sequence: row.get::<_, i32>("sequence")?,
+                date_created: row.get::<_, String>("date_created")?,
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                attachments: Vec::new(),
+                interrupted: false,
+                language: None,
+                citations: Vec::new(),
+                moderation: None,
+            })
+        },
+    )
+}
+
+// Get the user config, or the prepared defaults
+const API_KEY_COLUMNS: &[&str] = &[
+    "openai_key",
+    "groq_key",
+    "grok_key",
+    "anthropic_key",
+    "gemini_key",
+    "deepseek_key",
+    "together_key",
+    "fireworks_key",
+];
+
+// Encrypts any API keys still sitting in the DB as plaintext
+// Run at start up, after the columns are in place
+fn encrypt_stored_keys(db: &rusqlite::Connection) -> Result<(), Box<dyn std::error::Error>> {
+    let keystore = secrets::keystore();
+    let mut count = 0;
+    for column in API_KEY_COLUMNS {
+        let rows = db
+            .prepare(&format!(
+                "SELECT rowid, {} FROM user_config WHERE {} IS NOT NULL AND {} != ''",
+                column, column, column
+            ))?
+            .query_map(params![], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+
+        for (rowid, value) in rows {
+            if secrets::is_encrypted(&value) {
+                continue;
+            }
+
+            db.execute(
+                &format!("UPDATE user_config SET {} = ?1 WHERE rowid = ?2", column),
+                params![keystore.encrypt(&value)?, rowid],
+            )?;
+
+            count += 1;
+        }
+    }
+
+    if count > 0 {
+        lprint!(info, "Encrypted {} plaintext API keys", count);
+    }
+
+    Ok(())
+}
+
+// Stored key columns that can't be decrypted here--sealed on another machine
+// (e.g., restored from its backup) or tampered with
+fn unreadable_keys(db: &rusqlite::Connection) -> rusqlite::Result<Vec<&'static str>> {
+    let keystore = secrets::keystore();
+    let mut unreadable = Vec::new();
+    for column in API_KEY_COLUMNS {
+        let stored = db
+            .prepare(&format!("SELECT {} FROM user_config LIMIT 1", column))?
+            .query_map(params![], |row| row.get::<_, Option<String>>(0))?
+            .next()
+            .transpose()?
+            .flatten()
+            .unwrap_or_default();
+
+        if secrets::is_encrypted(&stored) && keystore.decrypt(&stored).is_err() {
+            unreadable.push(*column);
+        }
+    }
+
+    Ok(unreadable)
+}
+
+// What an API key column is called anywhere outside the DB--`openai_key` -> `openai`
+fn key_name(column: &str) -> &str {
+    column.strip_suffix("_key").unwrap_or(column)
+}
+
+fn sealed_key<'a>(keys: &'a mut APIKeys, column: &str) -> Option<&'a mut String> {
+    match column {
+        "openai_key" => Some(&mut keys.openai),
+        "groq_key" => Some(&mut keys.groq),
+        "grok_key" => Some(&mut keys.grok),
+        "anthropic_key" => Some(&mut keys.anthropic),
+        "gemini_key" => Some(&mut keys.gemini),
+        "deepseek_key" => Some(&mut keys.deepseek),
+        "together_key" => Some(&mut keys.together),
+        "fireworks_key" => Some(&mut keys.fireworks),
+        _ => None,
+    }
+}
+
+// Keys that can't be decrypted come back from `get_config` empty, so saving the config as it was read
+// would wipe them--they're kept as they are unless a new key is set in their place
+fn keep_unreadable_keys(db: &rusqlite::Connection, sealed: &mut APIKeys) -> rusqlite::Result<()> {
+    for column in unreadable_keys(db)? {
+        if let Some(key) = sealed_key(sealed, column) {
+            if key.is_empty() {
+                *key = db.query_row(
+                    &format!("SELECT {} FROM user_config LIMIT 1", column),
+                    params![],
+                    |row| row.get(0),
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Stored key -> plaintext key
+// Keys that can't be decrypted (e.g., the DB was copied from another machine) come back empty,
+// and need to be set again--until they are, `save_config` leaves what's stored alone
+fn open_key(stored: String) -> String {
+    match secrets::keystore().decrypt(&stored) {
+        Ok(k) => k,
+        Err(e) => {
+            lprint!(error, "Error decrypting API key: {}; ignoring", e);
+            String::new()
+        }
+    }
+}
+
+fn seal_keys(keys: &APIKeys) -> Result<APIKeys, std::io::Error> {
+    let keystore = secrets::keystore();
+    Ok(APIKeys {
+        openai: keystore.encrypt(&keys.openai)?,
+        groq: keystore.encrypt(&keys.groq)?,
+        grok: keystore.encrypt(&keys.grok)?,
+        anthropic: keystore.encrypt(&keys.anthropic)?,
+        gemini: keystore.encrypt(&keys.gemini)?,
+        deepseek: keystore.encrypt(&keys.deepseek)?,
+        together: keystore.encrypt(&keys.together)?,
+        fireworks: keystore.encrypt(&keys.fireworks)?,
+    })
+}
+
+// It really feels gross to insert a default every time we want to fetch the config
+fn get_config(db: &rusqlite::Connection) -> UserConfig {
+    match db.execute("INSERT OR IGNORE INTO user_config (openai_key, groq_key, grok_key, anthropic_key, gemini_key, system_prompt) 
+                      VALUES ('', '', '', '', '', '')", params![]) {
+        Ok(_) => {},
+        Err(e) => {
+            lprint!(error, "Error setting user_config defaults: {}", e);
+            panic!("Error setting user_config defaults: {}", e);
+        }
+    };
+
+    let mut stmt = db
+        .prepare(
+            "SELECT openai_key, groq_key, grok_key, anthropic_key, gemini_key, system_prompt, max_retries, search_fusion, deepseek_key, together_key, fireworks_key, local_endpoint, trash_retention_days, moderation, moderation_block, bind_address, port, empty_conversation_hours, max_message_chars, reference_threshold
+                                 FROM user_config LIMIT 1",
+        )
+        .unwrap();
+
+    let config = stmt
+        .query_row(params![], |row| {
+            Ok(UserConfig {
+                write: false,
+                api_keys: APIKeys {
+                    openai: open_key(row.get(0)?),
+                    groq: open_key(row.get(1)?),
+                    grok: open_key(row.get(2)?),
+                    anthropic: open_key(row.get(3)?),
+                    gemini: open_key(row.get(4)?),
+                    deepseek: open_key(row.get(8)?),
+                    together: open_key(row.get(9)?),
+                    fireworks: open_key(row.get(10)?),
+                },
+                system_prompt: row.get(5)?,
+                max_retries: row.get(6)?,
+                search_fusion: FusionStrategy::from_str(&row.get::<_, String>(7)?)
+                    .unwrap_or_default(),
+                local_endpoint: row.get(11)?,
+                trash_retention_days: row.get(12)?,
+                moderation: ModerationProvider::from_str(&row.get::<_, String>(13)?)
+                    .unwrap_or_default(),
+                moderation_block: row.get(14)?,
+                bind_address: row.get(15)?,
+                port: row.get(16)?,
+                empty_conversation_hours: row.get(17)?,
+                max_message_chars: row.get(18)?,
+                reference_threshold: row.get(19)?,
+            })
+        })
+        .unwrap();
+
+    return config;
+}
+
+fn register_env_var(env_var: &str, value: &str) {
+    std::env::set_var(env_var, value);
+    lprint!(
+        info,
+        "{}: {}",
+        env_var,
+        if value.len() > 0 { "***" } else { "" }
+    );
+}
+
+fn set_keys(user_config: &UserConfig) {
+    register_env_var("OPENAI_API_KEY", &user_config.api_keys.openai);
+    register_env_var("ANTHROPIC_API_KEY", &user_config.api_keys.anthropic);
+    register_env_var("GEMINI_API_KEY", &user_config.api_keys.gemini);
+    register_env_var("GROQ_API_KEY", &user_config.api_keys.groq);
+    register_env_var("DEEPSEEK_API_KEY", &user_config.api_keys.deepseek);
+    register_env_var("TOGETHER_API_KEY", &user_config.api_keys.together);
+    register_env_var("FIREWORKS_API_KEY", &user_config.api_keys.fireworks);
+    register_env_var("WILLIAM_MAX_RETRIES", &user_config.max_retries.to_string());
+    register_env_var("WILLIAM_SEARCH_FUSION", user_config.search_fusion.to_str());
+    register_env_var("WILLIAM_LOCAL_ENDPOINT", &user_config.local_endpoint);
+    register_env_var(
+        "WILLIAM_TRASH_RETENTION_DAYS",
+        &user_config.trash_retention_days.to_string(),
+    );
+    register_env_var(
+        "WILLIAM_EMPTY_CONVERSATION_HOURS",
+        &user_config.empty_conversation_hours.to_string(),
+    );
+    register_env_var(
+        "WILLIAM_MAX_MESSAGE_CHARS",
+        &user_config.max_message_chars.to_string(),
+    );
+    register_env_var(
+        "WILLIAM_REFERENCE_THRESHOLD",
+        &user_config.reference_threshold.to_string(),
+    );
+    register_env_var("WILLIAM_MODERATION", user_config.moderation.to_str());
+    register_env_var(
+        "WILLIAM_MODERATION_BLOCK",
+        &user_config.moderation_block.to_string(),
+    );
+}
+
+// Writes `user_config` to the DB and puts it into effect
+fn save_config(
+    db: &rusqlite::Connection,
+    user_config: &UserConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut sealed = seal_keys(&user_config.api_keys)?;
+    keep_unreadable_keys(db, &mut sealed)?;
+
+    db.execute(
+        "UPDATE user_config
+         SET openai_key = ?1,
+             groq_key = ?2,
+             grok_key = ?3,
+             anthropic_key = ?4,
+             gemini_key = ?5,
+             system_prompt = ?6,
+             max_retries = ?7,
+             search_fusion = ?8,
+             deepseek_key = ?9,
+             together_key = ?10,
+             fireworks_key = ?11,
+             local_endpoint = ?12,
+             trash_retention_days = ?13,
+             moderation = ?14,
+             moderation_block = ?15,
+             bind_address = ?16,
+             port = ?17,
+             empty_conversation_hours = ?18,
+             max_message_chars = ?19,
+             reference_threshold = ?20",
+        params![
+            sealed.openai,
+            sealed.groq,
+            sealed.grok,
+            sealed.anthropic,
+            sealed.gemini,
+            user_config.system_prompt,
+            user_config.max_retries,
+            user_config.search_fusion.to_str(),
+            sealed.deepseek,
+            sealed.together,
+            sealed.fireworks,
+            user_config.local_endpoint,
+            user_config.trash_retention_days,
+            user_config.moderation.to_str(),
+            user_config.moderation_block,
+            user_config.bind_address,
+            user_config.port,
+            user_config.empty_conversation_hours,
+            user_config.max_message_chars,
+            user_config.reference_threshold,
+        ],
+    )?;
+
+    set_keys(user_config);
+
+    Ok(())
+}
+
+// Settings (and optionally keys) as a JSON file for another machine to `ImportSettings`
+fn export_settings(
+    request: &ExportSettingsRequest,
+    db: &rusqlite::Connection,
+) -> Result<ExportResponse, std::io::Error> {
+    let settings = settings::SettingsFile::from_config(&get_config(db), request.include_keys);
+
+    let mut path = std::path::PathBuf::from(&request.path);
+    if path.is_dir() {
+        path = path.join(settings::SETTINGS_FILENAME);
+    }
+
+    std::fs::write(&path, settings::render(&settings)?)?;
+    lprint!(
+        info,
+        "Exported settings to {} ({})",
+        path.display(),
+        if request.include_keys {
+            "with API keys"
+        } else {
+            "without API keys"
+        }
+    );
+
+    Ok(ExportResponse {
+        content: None,
+        path: Some(path.to_string_lossy().to_string()),
+    })
+}
+
+// Dewey stays locked for the whole backup so its index and ledger can't change partway through
+fn backup_state(
+    request: &BackupRequest,
+    db: &rusqlite::Connection,
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+) -> Result<BackupResponse, Box<dyn std::error::Error>> {
+    let mut path = std::path::PathBuf::from(&request.path);
+    if path.is_dir() {
+        path = path.join(backup::filename());
+    }
+
+    let manifest = {
+        let _dewey = safe_lock!(dewey);
+        backup::create(db, &get_local_dir(), &path)?
+    };
+
+    let bytes = manifest.files.iter().map(|f| f.size).sum();
+    lprint!(
+        info,
+        "Backed up {} files ({} bytes) to {}",
+        manifest.files.len(),
+        bytes,
+        path.display()
+    );
+
+    Ok(BackupResponse {
+        path: path.to_string_lossy().to_string(),
+        files: manifest.files.len(),
+        bytes,
+    })
+}
+
+fn sync_status(
+    db: &rusqlite::Connection,
+    pushed: usize,
+    pulled: usize,
+) -> Result<SyncStatus, Box<dyn std::error::Error>> {
+    let state = sync::load(db)?;
+    Ok(SyncStatus {
+        enabled: state.is_some(),
+        endpoint: state.as_ref().map(|s| s.endpoint.clone()),
+        device_id: state.as_ref().map(|s| s.device_id.clone()),
+        last_sync: state.and_then(|s| s.last_sync),
+        pushed,
+        pulled,
+    })
+}
+
+fn enable_sync(
+    request: &EnableSyncRequest,
+    db: &rusqlite::Connection,
+) -> Result<SyncStatus, Box<dyn std::error::Error>> {
+    let key = network::block_on(sync::connect(
+        &request.endpoint,
+        request.token.clone(),
+        &request.passphrase,
+    ))?;
+    sync::enable(&request.endpoint, request.token.as_deref(), &key, db)?;
+
+    lprint!(info, "Enabled sync with {}", request.endpoint);
+
+    sync_now(db)
+}
+
+fn disable_sync(db: &rusqlite::Connection) -> Result<SyncStatus, Box<dyn std::error::Error>> {
+    sync::disable(db)?;
+    lprint!(info, "Disabled sync");
+
+    sync_status(db, 0, 0)
+}
+
+// Local conversations changed since the last push, in the form they're synced in
+// Trashed conversations are left out--deletions don't sync
+fn changed_conversations(
+    since: Option<&str>,
+    db: &rusqlite::Connection,
+) -> Result<Vec<sync::SyncConversation>, Box<dyn std::error::Error>> {
+    let changed = db
+        .prepare(
+            "SELECT id, sync_id, last_updated FROM conversations
+             WHERE deleted_at IS NULL AND (?1 IS NULL OR last_updated > ?1)",
+        )?
+        .query_map(params![since], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut conversations = Vec::new();
+    for (id, sync_id, last_updated) in changed {
+        let conversation = get_conversation(id, db);
+        let mut messages = Vec::new();
+        for message in conversation.messages.iter() {
+            let message_sync_id: String = db.query_row(
+                "SELECT sync_id FROM messages WHERE id = ?1",
+                params![message.id],
+                |row| row.get(0),
+            )?;
+
+            messages.push(sync::SyncMessage::from_message(message_sync_id, message));
+        }
+
+        conversations.push(sync::SyncConversation {
+            sync_id,
+            name: conversation.name,
+            last_updated,
+            messages,
+        });
+    }
+
+    Ok(conversations)
+}
+
+// Merges a pulled conversation into the local one with the same sync ID, or adds it if there isn't one
+// Timestamps come along from the remote, the same as with imports
+fn apply_synced_conversation(
+    synced: &sync::SyncConversation,
+    db: &rusqlite::Connection,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local = db
+        .query_row(
+            "SELECT id, last_updated FROM conversations WHERE sync_id = ?1",
+            params![synced.sync_id],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        )
+        .map(Some)
+        .or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(e),
+        })?;
+
+    let (mut conversation, merged, last_updated) = match local {
+        Some((id, local_updated)) => {
+            let conversation = get_conversation(id, db);
+            let mut local_messages = Vec::new();
+            for message in conversation.messages.iter() {
+                let sync_id: Option<String> = db.query_row(
+                    "SELECT sync_id FROM messages WHERE id = ?1",
+                    params![message.id],
+                    |row| row.get(0),
+                )?;
+
+                // Anything added since the push has no sync ID yet, and goes out next time
+                let sync_id = sync_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+                local_messages.push((sync_id, message.clone()));
+            }
+
+            let remote_newer = synced.last_updated > local_updated;
+            let merged = sync::merge(
+                &local_messages
+                    .iter()
+                    .map(|(sync_id, m)| sync::SyncMessage::from_message(sync_id.clone(), m))
+                    .collect::<Vec<_>>(),
+                &synced.messages,
+                remote_newer,
+            );
+
+            // Local messages keep their IDs and everything that doesn't sync, like attachments
+            let messages = merged
+                .iter()
+                .map(|m| {
+                    match local_messages
+                        .iter()
+                        .find(|(sync_id, _)| *sync_id == m.sync_id)
+                    {
+                        Some((_, local)) => Message {
+                            message_type: m.message_type.clone(),
+                            content: m.content.clone(),
+                            api: m.api.clone(),
+                            system_prompt: m.system_prompt.clone(),
+                            tool_calls: m.tool_calls.clone(),
+                            tool_call_id: m.tool_call_id.clone(),
+                            ..local.clone()
+                        },
+                        None => m.to_message(),
+                    }
+                })
+                .collect();
+
+            let mut conversation = Conversation {
+                messages,
+                ..conversation
+            };
+            if remote_newer {
+                conversation.name = synced.name.clone();
+            }
+
+            (
+                conversation,
+                merged,
+                std::cmp::max(synced.last_updated.clone(), local_updated),
+            )
+        }
+        None => (
+            Conversation {
+                id: None,
+                name: synced.name.clone(),
+                messages: synced.messages.iter().map(|m| m.to_message()).collect(),
+                tools: Vec::new(),
+                overrides: ConversationOverrides::default(),
+                branch_id: None,
+                settings: GenerationSettings::default(),
+                pinned: false,
+                archived: false,
+                unread: 0,
+                template: None,
+                memory_scope: MemoryScope::All,
+                related: Vec::new(),
+            },
+            synced.messages.clone(),
+            synced.last_updated.clone(),
+        ),
+    };
+
+    conversation.upsert(db)?;
+
+    for (message, merged) in conversation.messages.iter().zip(merged.iter()) {
+        db.execute(
+            "UPDATE messages SET sync_id = ?2, date_created = ?3 WHERE id = ?1",
+            params![message.id, merged.sync_id, merged.date_created],
+        )?;
+    }
+
+    let date_created = merged
+        .first()
+        .map_or(last_updated.clone(), |m| m.date_created.clone());
+    db.execute(
+        "UPDATE conversations SET sync_id = ?2, last_updated = ?3,
+         date_created = MIN(date_created, ?4) WHERE id = ?1",
+        params![conversation.id, synced.sync_id, last_updated, date_created],
+    )?;
+
+    Ok(())
+}
+
+// Pushes first so the pull can't clobber anything local that hasn't gone out yet
+fn sync_now(db: &rusqlite::Connection) -> Result<SyncStatus, Box<dyn std::error::Error>> {
+    let now = std::time::Instant::now();
+    let state = sync::load(db)?.ok_or("sync isn't enabled")?;
+    let remote = sync::Remote::new(&state.endpoint, state.token.clone(), state.key);
+    let devices = network::block_on(sync::register(&remote, &state.device_id))?;
+
+    sync::assign_ids(db)?;
+
+    // Taken before reading so anything changed partway through goes out next time
+    let pushed_at: String =
+        db.query_row("SELECT CURRENT_TIMESTAMP", params![], |row| row.get(0))?;
+    let conversations = changed_conversations(state.last_push.as_deref(), db)?;
+    let pushed = conversations.len();
+    if !conversations.is_empty() {
+        network::block_on(sync::push(
+            &remote,
+            &sync::Delta {
+                device: state.device_id.clone(),
+                conversations,
+            },
+        ))?;
+    }
+
+    let mut pulled = 0;
+    for device in devices.iter().filter(|d| **d != state.device_id) {
+        let cursor = sync::cursor(device, db)?;
+        let deltas = network::block_on(sync::pull(&remote, device, cursor))?;
+        for (i, delta) in deltas.iter().enumerate() {
+            let tx = db.unchecked_transaction()?;
+            for conversation in delta.conversations.iter() {
+                apply_synced_conversation(conversation, &tx)?;
+            }
+
+            sync::set_cursor(device, cursor + i + 1, &tx)?;
+            tx.commit()?;
+
+            pulled += delta.conversations.len();
+        }
+    }
+
+    sync::mark_pushed(&pushed_at, db)?;
+
+    lprint!(
+        info,
+        "Synced with {}: pushed {} conversations, pulled {} in {}ms",
+        state.endpoint,
+        pushed,
+        pulled,
+        now.elapsed().as_millis()
+    );
+
+    sync_status(db, pushed, pulled)
+}
+
+fn stage_restore(request: &RestoreRequest) -> Result<RestoreResponse, Box<dyn std::error::Error>> {
+    let manifest = backup::stage(
+        std::path::Path::new(&request.path),
+        &get_restore_dir(),
+        DB_MIGRATIONS.len(),
+    )?;
+
+    lprint!(
+        info,
+        "Staged restore of {} for the next start up",
+        request.path
+    );
+
+    // Keys are sealed with this machine's ID and the salt in the config directory, neither of which
+    // are in the backup--a backup from somewhere else comes with keys that can't be read here
+    let staged = rusqlite::Connection::open(get_restore_dir().join(backup::DATABASE_NAME))?;
+    let unreadable_keys = match unreadable_keys(&staged) {
+        Ok(columns) => columns.iter().map(|c| key_name(c).to_string()).collect(),
+        Err(e) => {
+            lprint!(error, "Error checking restored API keys: {}; ignoring", e);
+            Vec::new()
+        }
+    };
+
+    if !unreadable_keys.is_empty() {
+        lprint!(
+            error,
+            "Restored API keys for {} can't be decrypted on this machine and will need to be set again",
+            unreadable_keys.join(", ")
+        );
+    }
+
+    Ok(RestoreResponse {
+        files: manifest.files.len(),
+        bytes: manifest.files.iter().map(|f| f.size).sum(),
+        created: manifest.created,
+        restart_required: true,
+        unreadable_keys,
+    })
+}
+
+// Providers whose model lists are kept current, and the setting each one needs to be reachable
+const MODEL_DISCOVERY_PROVIDERS: &[(&str, &str)] = &[
+    ("openai", "OPENAI_API_KEY"),
+    ("anthropic", "ANTHROPIC_API_KEY"),
+    ("groq", "GROQ_API_KEY"),
+    ("deepseek", "DEEPSEEK_API_KEY"),
+    ("together", "TOGETHER_API_KEY"),
+    ("fireworks", "FIREWORKS_API_KEY"),
+    ("local", "WILLIAM_LOCAL_ENDPOINT"),
+];
+
+// Model lists are refreshed at startup, whenever the config changes, and this often otherwise
+const MODEL_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(6 * 60 * 60);
+
+// Marks `provider`'s models as available (or not) to match what it just reported
+//
+// Unlisted models keep their rows since old messages still point at them
+fn store_available_models(
+    db: &rusqlite::Connection,
+    provider: &str,
+    models: &[API],
+) -> rusqlite::Result<()> {
+    let tx = db.unchecked_transaction()?;
+
+    tx.execute(
+        "UPDATE models SET available = 0 WHERE provider = ?1",
+        params![provider],
+    )?;
+
+    for api in models {
+        let (_, name) = api.to_strings();
+        tx.execute(
+            "INSERT INTO models (name, provider)
+             SELECT ?2, ?1
+             WHERE NOT EXISTS (SELECT 1 FROM models WHERE provider = ?1 AND name = ?2)",
+            params![provider, name],
+        )?;
+        tx.execute(
+            "UPDATE models SET available = 1 WHERE provider = ?1 AND name = ?2",
+            params![provider, name],
+        )?;
+    }
+
+    tx.commit()
+}
+
+// Queries every configured provider's model listing and records the results
+//
+// The DB is only locked to write each provider's list, not while waiting on the network
+// Providers that can't be reached keep whatever was found last time
+async fn refresh_models(db: &std::sync::Mutex<rusqlite::Connection>) {
+    for (provider, setting) in MODEL_DISCOVERY_PROVIDERS {
+        if std::env::var(setting).unwrap_or_default().is_empty() {
+            continue;
+        }
+
+        let models = match network::list_models(provider).await {
+            Ok(models) => models,
+            Err(e) => {
+                lprint!(error, "Error listing {} models: {}; skipping", provider, e);
+                continue;
+            }
+        };
+
+        match store_available_models(&safe_lock!(db), provider, &models) {
+            Ok(_) => {
+                lprint!(info, "Found {} {} models", models.len(), provider);
+            }
+            Err(e) => {
+                lprint!(error, "Error storing {} models: {}", provider, e);
+            }
+        };
+    }
+}
+
+// Watched folders are rescanned this often, and whenever one's added
+const WATCH_SCAN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+// Writes out and embeds a file's chunks at `chunk_path(i)`, each labeled with `source`
+//
+// The first `previous_chunks` are from the file's last version, so they're re-embedded in place
+// rather than added again, and whatever the new version doesn't reach is removed
+fn embed_chunks(
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+    chunks: &[String],
+    previous_chunks: usize,
+    source: &str,
+    chunk_path: impl Fn(usize) -> std::path::PathBuf,
+    meta: std::collections::HashSet<String>,
+) -> Result<(), std::io::Error> {
+    let mut sources = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let path = chunk_path(i);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let filepath = path.to_string_lossy().to_string();
+
+        // The model sees these as references, so each one says where it came from
+        std::fs::write(&filepath, format!("From {}:\n{}", source, chunk))?;
+        sources.push(dewey_lib::EmbeddingSource {
+            filepath,
+            meta: meta.clone(),
+            subset: None,
+        });
+    }
+
+    // Embedded before Dewey's locked, like `embed_files`--the old version stays put unless
+    // every chunk of the new one makes it
+    let (embeddings, failed) = network::block_on(dewey_lib::embed_batch(&sources));
+    if let Some((source, e)) = failed.into_iter().next() {
+        return Err(std::io::Error::new(
+            e.kind(),
+            format!("{}: {}", source.filepath, e),
+        ));
+    }
+
+    let mut dewey = safe_lock!(dewey);
+    let dewey = match dewey.as_mut() {
+        Some(d) => d,
+        None => return Err(std::io::Error::other("Dewey is unavailable")),
+    };
+
+    // Leftovers from a longer version stop showing up as references once they're gone
+    let previous = (0..previous_chunks)
+        .map(|i| chunk_path(i).to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    dewey.remove_embeddings(previous)?;
+    if !embeddings.is_empty() {
+        dewey.store(embeddings)?;
+    }
+
+    for i in chunks.len()..previous_chunks {
+        let _ = std::fs::remove_file(chunk_path(i));
+    }
+
+    Ok(())
+}
+
+// Chunks and embeds `file`, reusing the chunk files (and Dewey entries) of its previous version
+//
+// Returns the new ledger entry
+fn index_watched_file(
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+    file: &watch::ScannedFile,
+    hash: String,
+    data: &[u8],
+    previous: Option<&watch::LedgerEntry>,
+) -> Result<watch::LedgerEntry, std::io::Error> {
+    let text = extract::extract_text(file.backend, data)?;
+    let chunks = extract::chunk(&text, DOCUMENT_CHUNK_CHARS, DOCUMENT_CHUNK_OVERLAP);
+    let previous_chunks = previous.map_or(0, |p| p.chunks);
+
+    let dir = get_watched_dir();
+    embed_chunks(
+        dewey,
+        &chunks,
+        previous_chunks,
+        &file.path,
+        |i| watch::chunk_path(&dir, &file.path, i),
+        std::collections::HashSet::from(["watched".to_string(), format!("source:{}", file.path)]),
+    )?;
+
+    lprint!(
+        info,
+        "Indexed {} ({} chunks, previously {})",
+        file.path,
+        chunks.len(),
+        previous_chunks
+    );
+
+    Ok(watch::LedgerEntry {
+        path: file.path.clone(),
+        size: file.size,
+        modified: file.modified,
+        hash,
+        chunks: chunks.len(),
+    })
+}
+
+fn forget_watched_file(entry: &watch::LedgerEntry) {
+    let _ = std::fs::remove_dir_all(watch::chunk_dir(&get_watched_dir(), &entry.path));
+}
+
+// Brings every watched folder's chunks up to date with the files on disk:
+// new and edited files are (re-)embedded, and deleted files have their chunks removed
+//
+// Like `refresh_models`, the locks are only held per file, not for the whole scan
+// Files that fail to index are left out of the ledger, so they're retried next time
+fn sync_watched_folders(
+    db: &std::sync::Mutex<rusqlite::Connection>,
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+) {
+    if safe_lock!(dewey).is_none() {
+        return;
+    }
+
+    let folders = match watch::list(&safe_lock!(db)) {
+        Ok(f) => f,
+        Err(e) => {
+            lprint!(error, "Error listing watched folders: {}", e);
+            return;
+        }
+    };
+
+    for folder in folders {
+        // A folder that's gone missing (e.g., an unmounted drive) keeps its chunks until it's back
+        let files = match watch::scan(std::path::Path::new(&folder.path)) {
+            Ok(f) => f,
+            Err(e) => {
+                lprint!(error, "Error scanning {}: {}; skipping", folder.path, e);
+                continue;
+            }
+        };
+
+        let ledger = match watch::ledger(folder.id, &safe_lock!(db)) {
+            Ok(l) => l,
+            Err(e) => {
+                lprint!(error, "Error reading ledger for {}: {}", folder.path, e);
+                continue;
+            }
+        };
+
+        let mut changed = 0;
+        for file in files.iter() {
+            let previous = ledger.iter().find(|e| e.path == file.path);
+            if previous.is_some_and(|p| file.matches(p)) {
+                continue;
+            }
+
+            let data = match std::fs::read(&file.path) {
+                Ok(d) => d,
+                Err(e) => {
+                    lprint!(error, "Error reading {}: {}; skipping", file.path, e);
+                    continue;
+                }
+            };
+
+            let hash = attachments::hash(&data);
+            let entry = if previous.is_some_and(|p| p.hash == hash) {
+                // Touched without changing--only the ledger needs to catch up
+                watch::LedgerEntry {
+                    size: file.size,
+                    modified: file.modified,
+                    ..previous.unwrap().clone()
+                }
+            } else {
+                if safe_lock!(dewey).is_none() {
+                    return;
+                }
+
+                match index_watched_file(dewey, file, hash, &data, previous) {
+                    Ok(entry) => {
+                        changed += 1;
+                        entry
+                    }
+                    Err(e) => {
+                        lprint!(error, "Error indexing {}: {}; skipping", file.path, e);
+                        continue;
+                    }
+                }
+            };
+
+            match watch::record(folder.id, &entry, &safe_lock!(db)) {
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(error, "Error recording {}: {}", file.path, e);
+                }
+            };
+        }
+
+        let mut removed = 0;
+        for entry in ledger.iter() {
+            if files.iter().any(|f| f.path == entry.path) {
+                continue;
+            }
+
+            match watch::forget(&entry.path, &safe_lock!(db)) {
+                Ok(_) => {
+                    forget_watched_file(entry);
+                    removed += 1;
+                }
+                Err(e) => {
+                    lprint!(error, "Error forgetting {}: {}", entry.path, e);
+                }
+            };
+        }
+
+        match watch::mark_scanned(folder.id, &safe_lock!(db)) {
+            Ok(_) => {}
+            Err(e) => {
+                lprint!(error, "Error marking {} scanned: {}", folder.path, e);
+            }
+        };
+
+        lprint!(
+            info,
+            "Scanned {}: {} files, {} indexed, {} removed",
+            folder.path,
+            files.len(),
+            changed,
+            removed
+        );
+    }
+}
+
+// Brings the repository's chunks up to date with its working tree,
+// the same way `sync_watched_folders` does for a watched folder
+fn index_repository(
+    repository: &Repository,
+    db: &std::sync::Mutex<rusqlite::Connection>,
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+) {
+    let root = std::path::Path::new(&repository.path);
+    let files = repos::walk(root);
+
+    let ledger = match repos::ledger(repository.id, &safe_lock!(db)) {
+        Ok(l) => l,
+        Err(e) => {
+            lprint!(error, "Error reading ledger for {}: {}", repository.name, e);
+            return;
+        }
+    };
+
+    let dir = get_repositories_dir();
+    let mut changed = 0;
+    for file in files.iter() {
+        let data = match std::fs::read(&file.path) {
+            Ok(d) => d,
+            Err(e) => {
+                lprint!(
+                    error,
+                    "Error reading {}: {}; skipping",
+                    file.relative_path,
+                    e
+                );
+                continue;
+            }
+        };
+
+        let hash = attachments::hash(&data);
+        let previous = ledger.get(&file.relative_path);
+        if previous.is_some_and(|(h, _)| *h == hash) {
+            continue;
+        }
+
+        let text = String::from_utf8_lossy(&data).replace("\r\n", "\n");
+        let chunks = extract::chunk(&text, DOCUMENT_CHUNK_CHARS, DOCUMENT_CHUNK_OVERLAP);
+
+        if safe_lock!(dewey).is_none() {
+            return;
+        }
+
+        match embed_chunks(
+            dewey,
+            &chunks,
+            previous.map_or(0, |(_, c)| *c),
+            &format!("{} in {}", file.relative_path, repository.name),
+            |i| repos::chunk_path(&dir, repository.id, &file.relative_path, i),
+            std::collections::HashSet::from([format!("repository:{}", repository.id)]),
+        ) {
+            Ok(_) => changed += 1,
+            Err(e) => {
+                lprint!(
+                    error,
+                    "Error indexing {}: {}; skipping",
+                    file.relative_path,
+                    e
+                );
+                continue;
+            }
+        };
+
+        match repos::record(
+            repository.id,
+            &file.relative_path,
+            &hash,
+            chunks.len(),
+            &safe_lock!(db),
+        ) {
+            Ok(_) => {}
+            Err(e) => {
+                lprint!(error, "Error recording {}: {}", file.relative_path, e);
+            }
+        };
+    }
+
+    let mut removed = 0;
+    for relative_path in ledger.keys() {
+        if files.iter().any(|f| f.relative_path == *relative_path) {
+            continue;
+        }
+
+        match repos::forget(repository.id, relative_path, &safe_lock!(db)) {
+            Ok(_) => {
+                let _ =
+                    std::fs::remove_dir_all(repos::chunk_dir(&dir, repository.id, relative_path));
+                removed += 1;
+            }
+            Err(e) => {
+                lprint!(error, "Error forgetting {}: {}", relative_path, e);
+            }
+        };
+    }
+
+    match repos::mark_indexed(repository.id, &safe_lock!(db)) {
+        Ok(_) => {}
+        Err(e) => {
+            lprint!(error, "Error marking {} indexed: {}", repository.name, e);
+        }
+    };
+
+    lprint!(
+        info,
+        "Indexed {}: {} files, {} changed, {} removed",
+        repository.name,
+        files.len(),
+        changed,
+        removed
+    );
+}
+
+// Embeds every message `backfill::unembedded` turns up, a batch at a time,
+// keeping `backfill`'s progress up to date
+//
+// Like `sync_watched_folders`, the locks are only held per batch, not for the whole pass
+fn backfill_embeddings(
+    db: &std::sync::Mutex<rusqlite::Connection>,
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+) {
+    if safe_lock!(dewey).is_none() {
+        return;
+    }
+
+    let message_ids = match backfill::unembedded(&safe_lock!(db)) {
+        Ok(ids) => ids,
+        Err(e) => {
+            lprint!(error, "Error finding messages to embed: {}", e);
+            return;
+        }
+    };
+
+    backfill::set_total(message_ids.len());
+    if message_ids.is_empty() {
+        return;
+    }
+
+    lprint!(
+        info,
+        "Backfilling embeddings for {} messages",
+        message_ids.len()
+    );
+
+    let now = std::time::Instant::now();
+    for batch in message_ids.chunks(dewey_lib::EMBEDDING_BATCH_SIZE) {
+        let db = safe_lock!(db);
+
+        let mut queue = EmbeddingQueue::default();
+        let mut skipped = 0;
+        for message_id in batch {
+            let queued = match backfill::content(*message_id, &db) {
+                Ok(content) => queue.push(
+                    &db,
+                    Some(*message_id),
+                    MESSAGE_EMBEDDING,
+                    &content,
+                    &new_embedding_path(),
+                ),
+                Err(e) => Err(std::io::Error::other(e.to_string())),
+            };
+
+            match queued {
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(
+                        error,
+                        "Error queueing message {}: {}; skipping",
+                        message_id,
+                        e
+                    );
+                    skipped += 1;
+                }
+            };
+        }
+
+        let queued = queue.files.len();
+        let failed = queue.flush(&db, dewey);
+        backfill::advance(queued - failed, skipped + failed);
+    }
+
+    let progress = backfill::progress().unwrap();
+    lprint!(
+        info,
+        "Backfilled embeddings for {} messages ({} failed) in {}ms",
+        progress.embedded,
+        progress.failed,
+        now.elapsed().as_millis()
+    );
+}
+
+// Runs a backfill pass on its own thread, unless one's already running
+fn start_backfill(
+    db: &std::sync::Arc<std::sync::Mutex<rusqlite::Connection>>,
+    dewey: &std::sync::Arc<std::sync::Mutex<Option<Dewey>>>,
+) {
+    if !backfill::start() {
+        return;
+    }
+
+    let db = worker_db(db);
+    let dewey = std::sync::Arc::clone(dewey);
+    std::thread::spawn(move || {
+        backfill_embeddings(&db, &dewey);
+        backfill::finish();
+    });
+}
+
+// Takes a memory (or with `None`, every memory) out of Dewey, the database, and then disk
+// Dewey goes first, so if it fails the memories are all still there to try again
+//
+// Dewey stays locked throughout, so nothing can be embedded with a file while it's being forgotten
+// Returns the memories left
+fn forget_memories(
+    memory_id: Option<i64>,
+    db: &rusqlite::Connection,
+    dewey: &std::sync::Mutex<Option<Dewey>>,
+) -> Result<Vec<Memory>, chamber_common::ChamberError> {
+    let mut dewey = safe_lock!(dewey);
+    let dewey = match dewey.as_mut() {
+        Some(d) => d,
+        None => {
+            return Err(chamber_common::ChamberError::new(
+                "Dewey",
+                "forgetting memories",
+                "Dewey is unavailable",
+            ))
+        }
+    };
+
+    let files = match memory_id {
+        Some(id) => match memory::file(id, db)? {
+            Some(file) => vec![file],
+            None => {
+                return Err(chamber_common::ChamberError::new(
+                    "NotFound",
+                    "forgetting memories",
+                    format!("No memory with ID {}", id),
+                ))
+            }
+        },
+        None => memory::all_files(db)?,
+    };
+
+    dewey
+        .remove_embeddings(files.clone())
+        .map_err(|e| chamber_common::ChamberError::new("Dewey", "forgetting memories", e))?;
+
+    memory::forget(&files, db)?;
+
+    for file in files.iter() {
+        match std::fs::remove_file(file) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                lprint!(
+                    error,
+                    "Error removing embedding file {}: {}; ignoring",
+                    file,
+                    e
+                );
+            }
+        };
+    }
+
+    memory::list(db)
+}
+
+// Models the UI should offer--whatever discovery last found,
+// plus anything that's never been checked (e.g., providers without a key)
+fn get_available_models(db: &rusqlite::Connection) -> rusqlite::Result<Vec<API>> {
+    let mut stmt = db
+        .prepare("SELECT provider, name FROM models WHERE available = 1 ORDER BY provider, name")?;
+
+    let rows = stmt.query_map(params![], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+
+    let mut models = Vec::new();
+    for row in rows {
+        let (provider, name) = row?;
+        // Retired models resolve to their replacements, which may already be listed
+        if let Ok(api) = API::from_strings(&provider, &name) {
+            if !models.contains(&api) {
+                models.push(api);
+            }
+        }
+    }
+
+    Ok(models)
+}
+
+// How long a connection waits on another's write before giving up with `SQLITE_BUSY`
+const DB_BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Every connection to william.sqlite goes through here
+//
+// WAL mode lets readers carry on while something else is writing,
+// so each worker (websocket connection, background thread) gets a connection of its own
+// instead of everything queueing behind one mutex
+// SQLite still serializes the writes themselves, with `DB_BUSY_TIMEOUT` covering the wait
+fn open_db() -> rusqlite::Result<rusqlite::Connection> {
+    let db = rusqlite::Connection::open(get_local_dir().join("william.sqlite"))?;
+
+    db.busy_timeout(DB_BUSY_TIMEOUT)?;
+    // `journal_mode` reports the mode it ended up in, so it has to be read like a query
+    let mode: String = db.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+    if !mode.eq_ignore_ascii_case("wal") {
+        lprint!(info, "SQLite is using journal mode {} instead of WAL", mode);
+    }
+
+    // Safe with WAL--a crash can lose the last few commits, but can't corrupt anything
+    db.execute_batch("PRAGMA synchronous = NORMAL;")?;
+    // Off by default in SQLite, which leaves every `ON DELETE CASCADE` in the schema doing nothing
+    // This is per connection, so it has to be set here rather than with the schema
+    db.execute_batch("PRAGMA foreign_keys = ON;")?;
+
+    Ok(db)
+}
+
+// A connection for one worker, or the shared one if another couldn't be opened
+fn worker_db(
+    shared: &std::sync::Arc<std::sync::Mutex<rusqlite::Connection>>,
+) -> std::sync::Arc<std::sync::Mutex<rusqlite::Connection>> {
+    match open_db() {
+        Ok(db) => std::sync::Arc::new(std::sync::Mutex::new(db)),
+        Err(e) => {
+            lprint!(
+                error,
+                "Error opening database connection: {}; sharing the main one",
+                e
+            );
+            std::sync::Arc::clone(shared)
+        }
+    }
+}
+
+// Where the websocket server listens--the environment first, then the user config
+fn websocket_address(user_config: &UserConfig) -> (String, u16) {
+    let address = std::env::var("WILLIAM_BIND_ADDRESS")
+        .ok()
+        .filter(|a| !a.trim().is_empty())
+        .unwrap_or_else(|| user_config.bind_address.clone());
+
+    let port = std::env::var("WILLIAM_PORT")
+        .ok()
+        .and_then(|p| p.trim().parse::<u16>().ok())
+        .unwrap_or(user_config.port);
+
+    (address, port)
+}
+
+// Falls back to whatever port the OS hands out if the configured one is taken,
+// e.g. by another copy of William
+fn bind_websocket(address: &str, port: u16) -> std::io::Result<std::net::TcpListener> {
+    match std::net::TcpListener::bind((address, port)) {
+        Ok(s) => Ok(s),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && port != 0 => {
+            lprint!(
+                error,
+                "Port {} is taken: {}; falling back to any open port",
+                port,
+                e
+            );
+            std::net::TcpListener::bind((address, 0))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// What the webview connects to
+// A server listening on every interface is reached through loopback
+fn websocket_url(server: &std::net::TcpListener) -> std::io::Result<String> {
+    let mut address = server.local_addr()?;
+    if address.ip().is_unspecified() {
+        address.set_ip(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST));
+    }
+
+    Ok(format!("ws://{}", address))
+}
+
+// TODO: there is zero error handling around here lol
+async fn websocket_server(
+    server: std::net::TcpListener,
+    db: rusqlite::Connection,
+    dewey: Option<dewey_lib::Dewey>,
+) {
+    // Tokenizer using the GPT-4o token mapping from OpenAI
+    // Without the mapping, token counts are estimated instead
+    let tokenizer = match tiktoken::Tokenizer::new().await {
+        Ok(t) => t,
+        Err(e) => {
+            lprint!(
+                error,
+                "Error initializing tokenizer: {}; falling back to approximate token counts",
+                e
+            );
+            tiktoken::Tokenizer::approximate()
+        }
+    };
+
+    lprint!(
+        info,
+        "Tokenizer initialized ({})",
+        tokenizer.estimator().name()
+    );
+
+    serve(server, db, dewey, tokenizer).await;
+}
+
+// The server itself, once everything it needs is ready
+async fn serve(
+    server: std::net::TcpListener,
+    db: rusqlite::Connection,
+    dewey: Option<dewey_lib::Dewey>,
+    tokenizer: tiktoken::Tokenizer,
+) {
+    let tokenizer_ = std::sync::Arc::new(Some(tokenizer));
+
+    // Shared by whatever can't get a connection of its own--see `worker_db`
+    let db_ = std::sync::Arc::new(std::sync::Mutex::new(db));
+
+    // Embeddings are retrieved from the OpenAI API and stored locally using Dewey as the index
+    let dewey_ = std::sync::Arc::new(std::sync::Mutex::new(dewey));
+
+    lprint!(info, "Dewey initialized");
+
+    let limits = validation::InputLimits::from_env();
+
+    // Model discovery runs on its own task for the life of the server
+    // Config changes send on `model_refresh` to have it run again right away
+    let (model_refresh, mut model_refresh_rx) = tokio::sync::mpsc::unbounded_channel::<()>();
+    let refresh_db = worker_db(&db_);
+    spawn(spans::instrument(None, None, async move {
+        loop {
+            refresh_models(&refresh_db).await;
+
+            match tokio::time::timeout(MODEL_REFRESH_INTERVAL, model_refresh_rx.recv()).await {
+                Ok(Some(_)) | Err(_) => {}
+                Ok(None) => break,
+            }
+        }
+    }));
+
+    // Watched folders get the same treatment on a thread of their own, since scanning is all
+    // file reads and embedding, with `watch_scan` triggering a scan
+    let (watch_scan, watch_scan_rx) = std::sync::mpsc::channel::<()>();
+    let watch_db = worker_db(&db_);
+    let watch_dewey = std::sync::Arc::clone(&dewey_);
+    std::thread::spawn(move || loop {
+        sync_watched_folders(&watch_db, &watch_dewey);
+
+        match watch_scan_rx.recv_timeout(WATCH_SCAN_INTERVAL) {
+            Ok(_) | Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    });
+
+    // Anything that missed being embedded last time (e.g., Dewey was unavailable) gets caught up
+    start_backfill(&db_, &dewey_);
+
+    // Abandoned conversations are swept up once an hour, starting now
+    let sweep_db = worker_db(&db_);
+    std::thread::spawn(move || loop {
+        match delete_empty_conversations(&safe_lock!(sweep_db), empty_conversation_hours()) {
+            Ok(_) => {}
+            Err(e) => {
+                lprint!(error, "Error deleting empty conversations: {}; ignoring", e);
+            }
+        };
+
+        std::thread::sleep(EMPTY_CONVERSATION_SWEEP_INTERVAL);
+    });
+
+    // Each connection is a task of its own on the runtime, as is whatever it's waiting on
+    let server = match server
+        .set_nonblocking(true)
+        .and_then(|_| tokio::net::TcpListener::from_std(server))
+    {
+        Ok(s) => s,
+        Err(e) => {
+            lprint!(error, "Error starting WebSocket server: {}", e);
+            return;
+        }
+    };
+
+    // Websocket server loop
+    loop {
+        let stream = match server.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                lprint!(error, "Error accepting connection: {}; ignoring", e);
+                continue;
+            }
+        };
+
+        spawn(connection(
+            stream,
+            limits,
+            worker_db(&db_),
+            std::sync::Arc::clone(&tokenizer_),
+            std::sync::Arc::clone(&dewey_),
+            model_refresh.clone(),
+            watch_scan.clone(),
+        ));
+    }
+}
+
+// A connection, from the handshake until it closes
+//
+// Requests are handled as they come in--completions, names, and dispatched requests all run on
+// tasks of their own, sending along whatever they have whenever it's ready
+async fn connection(
+    stream: tokio::net::TcpStream,
+    limits: validation::InputLimits,
+    db: std::sync::Arc<std::sync::Mutex<rusqlite::Connection>>,
+    tokenizer: std::sync::Arc<Option<tiktoken::Tokenizer>>,
+    dewey: std::sync::Arc<std::sync::Mutex<Option<Dewey>>>,
+    model_refresh: tokio::sync::mpsc::UnboundedSender<()>,
+    watch_scan: std::sync::mpsc::Sender<()>,
+) {
+    let websocket =
+        match tokio_tungstenite::accept_async_with_config(stream, Some(limits.websocket_config()))
+            .await
+        {
+            Ok(w) => w,
+            Err(e) => {
+                lprint!(error, "Error accepting websocket connection: {}", e);
+                return;
+            }
+        };
+    let _connection = ConnectionGuard::new();
+
+    // Everything sent goes through the outbox, so it all goes out in order
+    let (mut writer, mut reader) = websocket.split();
+    let (outbox, mut outgoing) = tokio::sync::mpsc::unbounded_channel();
+    spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            if let Err(e) = writer.send(message).await {
+                error!("error writing to websocket: {}", e);
+                break;
+            }
+        }
+    });
+
+    lprint!(
+        info,
+        "Connection opened ({} open)",
+        CONNECTIONS.load(std::sync::atomic::Ordering::SeqCst)
+    );
+
+    let mut websocket = Connection::new(outbox);
+    let resources = CompletionResources {
+        db: std::sync::Arc::clone(&db),
+        tokenizer: std::sync::Arc::clone(&tokenizer),
+        dewey: std::sync::Arc::clone(&dewey),
+    };
+
+    // Not stamped, since it isn't part of the session's history
+    let session_id = websocket.session_id();
+    ws_replay(
+        &websocket,
+        &[serialize_response!(
+            Session,
+            SessionInfo {
+                session_id: session_id.clone(),
+            },
+            String::new()
+        )],
+    );
+
+    let mut last_seen = std::time::Instant::now();
+    loop {
+        let msg = match tokio::time::timeout(HEARTBEAT_INTERVAL, reader.next()).await {
+            Ok(Some(Ok(m))) => {
+                last_seen = std::time::Instant::now();
+                m
+            }
+            Err(_) => {
+                if last_seen.elapsed() >= IDLE_TIMEOUT {
+                    lprint!(
+                        info,
+                        "No response from connection in {}s; closing",
+                        last_seen.elapsed().as_secs()
+                    );
+
+                    websocket.write(tungstenite::Message::Close(None));
+                    break;
+                }
+
+                // The pong comes back through `reader` like anything else
+                if !websocket.write(tungstenite::Message::Ping(Vec::new())) {
+                    break;
+                }
+
+                continue;
+            }
+            // Whatever's left of the message can't be read past, so the connection goes too
+            Ok(Some(Err(tungstenite::Error::Capacity(e)))) => {
+                ws_error!(
+                    websocket,
+                    "MessageTooLarge",
+                    "Request exceeds the websocket size limits",
+                    e,
+                    String::new()
+                );
+
+                break;
+            }
+            // Anything else means the connection is gone (or unusable)
+            Ok(Some(Err(e))) => {
+                error!("error reading from websocket: {}", e);
+                break;
+            }
+            Ok(None) => break,
+        };
+
+        let request: ArrakisRequest = match msg {
+            tungstenite::Message::Close(_) => {
+                break;
+            }
+            // Heartbeats--tungstenite answers pings on its own
+            tungstenite::Message::Ping(_) | tungstenite::Message::Pong(_) => {
+                continue;
+            }
+            tungstenite::Message::Text(t) => match serde_json::from_str(&t) {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("t: {}", t);
+                    error!("error reading Arrakis request: {}", e);
+
+                    // Bad models, missing fields, etc.--let the client know if
+                    // there's an ID to send it back to
+                    let request_id = serde_json::from_str::<serde_json::Value>(&t)
+                        .ok()
+                        .and_then(|v| v["id"].as_str().map(|id| id.to_string()));
+
+                    if let Some(request_id) = request_id {
+                        ws_error!(
+                            websocket,
+                            "InvalidRequest",
+                            "Error reading request",
+                            e,
+                            request_id
+                        );
+                    }
+
+                    continue;
+                }
+            },
+            _ => {
+                error!("unsupported message type");
+                continue;
+            }
+        };
+
+        // Everything logged while handling the request is tagged with its ID
+        let request_id = request.id().to_string();
+        let _span = chamber_common::RequestSpan::enter(&request_id);
+        let mut trace = spans::root(request.method());
+        trace.attribute("request.id", &request_id);
+
+        lprint!(info, "Request deserialized");
+
+        // Not sure if there is a better way of delineating endpoints, but this is the best
+        // we have right now.
+        //
+        // Request types are judged by their payload structure--see `types.rs` for more
+        // info.
+        match request {
+            // Triggers on a chat message submission, as well as a fork
+            // (after backend processing)
+            ArrakisRequest::Completion { id, payload } => {
+                let errors = validation::validate_completion(&payload, &limits);
+                if !errors.is_empty() {
+                    lprint!(error, "Rejecting invalid completion request: {:?}", errors);
+                    ws_send!(
+                        websocket,
+                        serialize_response!(
+                            WilliamError,
+                            WilliamError {
+                                error_type: "InvalidRequest".to_string(),
+                                message: format!(
+                                    "Invalid completion request ({} errors)",
+                                    errors.len()
+                                ),
+                                details: errors,
+                                provider: None,
+                            },
+                            id
+                        )
+                    );
+
+                    continue;
+                }
+
+                start_completion(&websocket, &id, payload, &resources);
+            }
+            ArrakisRequest::CompareCompletion { id, payload } => {
+                let errors = validation::validate_comparison(&payload, MAX_COMPARE_MODELS, &limits);
+                if !errors.is_empty() {
+                    lprint!(error, "Rejecting invalid comparison request: {:?}", errors);
+                    ws_send!(
+                        websocket,
+                        serialize_response!(
+                            WilliamError,
+                            WilliamError {
+                                error_type: "InvalidRequest".to_string(),
+                                message: format!(
+                                    "Invalid comparison request ({} errors)",
+                                    errors.len()
+                                ),
+                                details: errors,
+                                provider: None,
+                            },
+                            id
+                        )
+                    );
+
+                    continue;
+                }
+
+                start_comparison(&websocket, &id, payload, &resources);
+            }
+            ArrakisRequest::Resume { id, payload } => {
+                let resumed = safe_lock!(detached()).remove(&payload.session_id);
+                match resumed {
+                    // Like `Session`, the ack is about the connection, not the session
+                    Some(resumed) => {
+                        let replayed = websocket.resume(
+                            resumed,
+                            payload.last_seen_response_id,
+                            |replayed, complete| {
+                                serialize_response!(
+                                    Resumed,
+                                    ResumeResponse {
+                                        session_id: payload.session_id.clone(),
+                                        resumed: true,
+                                        replayed,
+                                        complete,
+                                    },
+                                    id
+                                )
+                            },
+                        );
+
+                        lprint!(
+                            info,
+                            "Resumed session {}, replaying {} responses",
+                            payload.session_id,
+                            replayed
+                        );
+                    }
+                    None => {
+                        lprint!(
+                            info,
+                            "No session {} to resume; carrying on with {}",
+                            payload.session_id,
+                            session_id
+                        );
+
+                        ws_replay(
+                            &websocket,
+                            &[serialize_response!(
+                                Resumed,
+                                ResumeResponse {
+                                    session_id: session_id.clone(),
+                                    resumed: false,
+                                    replayed: 0,
+                                    complete: false,
+                                },
+                                id
+                            )],
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::Status { id } => {
+                ws_send!(
+                    websocket,
+                    serialize_response!(
+                        Status,
+                        StatusResponse {
+                            connections: CONNECTIONS.load(std::sync::atomic::Ordering::SeqCst),
+                            queue: queue::global().status(),
+                        },
+                        id
+                    )
+                );
+            }
+            // TODO: Not sure how necessary this is
+            ArrakisRequest::Ping { id, payload: _ } => {
+                ws_send!(
+                    websocket,
+                    serialize_response!(
+                        Ping,
+                        Ping {
+                            body: "pong".to_string(),
+                        },
+                        id
+                    )
+                );
+            }
+            // Retrieve a list of saved conversation IDs
+            ArrakisRequest::ConversationList { id } => {
+                let db = safe_lock!(db);
+                let conversations = match get_conversation_list(false, &db) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "ConversationList",
+                            "Error fetching conversation IDs",
+                            e,
+                            id.to_string()
+                        );
+                        continue;
+                    }
+                };
+
+                ws_send!(
+                    websocket,
+                    serialize_response!(ConversationList, ConversationList { conversations }, id)
+                );
+            }
+            // Fetch a conversation from its ID
+            ArrakisRequest::Load { id, payload } => {
+                let db = std::sync::Arc::clone(&db);
+                let dewey = std::sync::Arc::clone(&dewey);
+                dispatch_with(&websocket, id.clone(), move |websocket| {
+                    let db = safe_lock!(db);
+                    ws_send!(
+                        websocket,
+                        serialize_response!(
+                            Load,
+                            get_conversation_branch(payload.id, payload.branch_id, &db),
+                            id
+                        )
+                    );
+
+                    // Warm up Dewey for the conversation's next completion
+                    // This happens after the response so it doesn't hold up the UI
+                    let files = get_embedding_files(payload.id, &db);
+                    drop(db);
+                    if let Some(dewey) = safe_lock!(dewey).as_mut() {
+                        match dewey.prefetch(files) {
+                            Ok(_) => {}
+                            Err(e) => {
+                                lprint!(
+                                    error,
+                                    "Error prefetching embeddings for conversation {}: {}; ignoring",
+                                    payload.id,
+                                    e
+                                );
+                            }
+                        };
+                    }
+                });
+            }
+            // Fetch the first message of a conversation from its conversation ID
+            ArrakisRequest::Preview { id, mut payload } => {
+                match get_first_message(payload.conversation_id, &safe_lock!(db)) {
+                    Ok(Some(message)) => {
+                        payload.content = message.content;
+                        ws_send!(websocket, serialize_response!(Preview, payload, id));
+                    }
+                    Ok(None) => {
+                        ws_error!(
+                            websocket,
+                            "Preview",
+                            "Error fetching preview",
+                            format!("No messages in conversation {}", payload.conversation_id),
+                            id.to_string()
+                        );
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "Preview",
+                            "Error fetching preview",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            // get the current conversation,
+            // create the fork,
+            // carry on with the completion
+            //
+            // TODO: this needs cleaned up from a UI perspective. Regenerating messages
+            //       when there's a communication failure quickly leads to a cluttering of the
+            //       conversation history. They also need renamed based on the conversation
+            //       redirection
+            ArrakisRequest::Fork { id, payload } => {
+                let db = std::sync::Arc::clone(&db);
+                let resources = resources.clone();
+                dispatch_with(&websocket, id.clone(), move |websocket| {
+                    let db = safe_lock!(db);
+
+                    // Forks branch off within the same conversation,
+                    // sharing every message before `sequence` with the current branch
+                    let mut conversation = get_conversation(payload.conversation_id, &db);
+                    conversation.messages.truncate(payload.sequence as usize);
+
+                    // The conversation should _always_ have at least one element--what would
+                    // there be to fork otherwise?
+                    //
+                    // The response is always a new message--the old one still belongs to the
+                    // parent branch
+                    let mut assistant_message = conversation.messages.last().unwrap().clone();
+                    assistant_message.id = None;
+                    assistant_message.content = String::new();
+                    assistant_message.tool_calls = Vec::new();
+                    assistant_message.tool_call_id = None;
+
+                    if assistant_message.message_type != MessageType::Assistant {
+                        assistant_message.message_type = MessageType::Assistant;
+                        assistant_message.sequence += 1;
+
+                        conversation.messages.push(assistant_message);
+                    } else {
+                        *conversation.messages.last_mut().unwrap() = assistant_message;
+                    }
+
+                    let fork_sequence = conversation.messages.len() as i64 - 1;
+                    match create_branch(
+                        &db,
+                        payload.conversation_id,
+                        conversation.branch_id,
+                        Some(fork_sequence),
+                    ) {
+                        Ok(branch_id) => conversation.branch_id = Some(branch_id),
+                        Err(e) => {
+                            ws_error!(
+                                websocket,
+                                "Fork",
+                                "Error adding branch to DB",
+                                e,
+                                id.to_string()
+                            );
+                            return;
+                        }
+                    };
+
+                    start_completion(websocket, &id, conversation, &resources);
+                });
+            }
+            // Cuts off the response, keeping whatever made it out,
+            // and continues the conversation with the new instruction--see `redirect`
+            ArrakisRequest::Redirect { id, payload } => {
+                if payload.new_instruction.len() > limits.max_content_length {
+                    ws_error!(
+                        websocket,
+                        "InvalidRequest",
+                        "Instruction is too long",
+                        payload.new_instruction.len(),
+                        id.to_string()
+                    );
+                    continue;
+                }
+
+                // Off the connection's task, since it might be waiting on the response to finish
+                let trace = spans::Context::current();
+                spawn(spans::instrument(
+                    Some(&id),
+                    trace,
+                    redirect(websocket.clone(), id.clone(), payload, resources.clone()),
+                ));
+            }
+            // Picks an interrupted response back up where it left off
+            ArrakisRequest::Continue { id, payload } => {
+                let db = std::sync::Arc::clone(&db);
+                let resources = resources.clone();
+                dispatch_with(&websocket, id.clone(), move |websocket| {
+                    let db = safe_lock!(db);
+
+                    let mut conversation = get_conversation(payload.conversation_id, &db);
+                    match conversation.messages.last_mut() {
+                        Some(m) if m.id == Some(payload.message_id) && m.interrupted => {
+                            // Anthropic rejects a partial response that ends in whitespace
+                            m.content = m.content.trim_end().to_string();
+                        }
+                        _ => {
+                            ws_error!(
+                            websocket,
+                            "Continue",
+                            "No interrupted response with the given ID at the end of the conversation",
+                            payload.message_id,
+                            id.to_string()
+                        );
+                            return;
+                        }
+                    };
+
+                    start_completion(websocket, &id, conversation, &resources);
+                });
+            }
+            ArrakisRequest::EditMessage { id, payload } => {
+                if payload.new_content.len() > limits.max_content_length {
+                    ws_error!(
+                        websocket,
+                        "InvalidRequest",
+                        "Edited message is too long",
+                        payload.new_content.len(),
+                        id.to_string()
+                    );
+                    continue;
+                }
+
+                let db = std::sync::Arc::clone(&db);
+                let resources = resources.clone();
+                dispatch_with(&websocket, id.clone(), move |websocket| {
+                    let db = safe_lock!(db);
+
+                    let mut conversation = get_conversation(payload.conversation_id, &db);
+                    let index = match conversation
+                        .messages
+                        .iter()
+                        .position(|m| m.id == Some(payload.message_id))
+                    {
+                        Some(i)
+                            if matches!(
+                                conversation.messages[i].message_type,
+                                MessageType::User | MessageType::System
+                            ) =>
+                        {
+                            i
+                        }
+                        _ => {
+                            ws_error!(
+                                websocket,
+                                "EditMessage",
+                                "No user or system message with the given ID in the conversation",
+                                payload.message_id,
+                                id.to_string()
+                            );
+                            return;
+                        }
+                    };
+
+                    // The edit goes in as a new message so any forks sharing the original keep it
+                    if conversation.messages[index].message_type == MessageType::System {
+                        if payload.regenerate {
+                            ws_error!(
+                                websocket,
+                                "InvalidRequest",
+                                "Only user messages can be regenerated",
+                                payload.message_id,
+                                id.to_string()
+                            );
+                            return;
+                        }
+
+                        // Everything after a system message stays where it is
+                        if payload.new_content.trim().is_empty() {
+                            conversation.messages.remove(index);
+                        } else {
+                            let edited = &mut conversation.messages[index];
+                            edited.id = None;
+                            edited.content = payload.new_content;
+                        }
+                    } else {
+                        conversation.messages.truncate(index + 1);
+                        let edited = conversation.messages.last_mut().unwrap();
+                        edited.id = None;
+                        edited.content = payload.new_content;
+
+                        if payload.regenerate {
+                            let mut placeholder = edited.clone();
+                            placeholder.message_type = MessageType::Assistant;
+                            placeholder.content = String::new();
+                            placeholder.system_prompt = String::new();
+                            placeholder.sequence += 1;
+                            conversation.messages.push(placeholder);
+
+                            start_completion(websocket, &id, conversation, &resources);
+                            return;
+                        }
+                    }
+
+                    match conversation.upsert(&db) {
+                        Ok(_) => {
+                            ws_send!(websocket, serialize_response!(Load, conversation, id));
+                        }
+                        Err(e) => {
+                            ws_error!(
+                                websocket,
+                                "EditMessage",
+                                "Error saving edited conversation",
+                                e,
+                                id.to_string()
+                            );
+                        }
+                    };
+                });
+            }
+            ArrakisRequest::InsertSystemMessage { id, payload } => {
+                if payload.content.len() > limits.max_content_length {
+                    ws_error!(
+                        websocket,
+                        "InvalidRequest",
+                        "System message is too long",
+                        payload.content.len(),
+                        id.to_string()
+                    );
+                    continue;
+                }
+
+                if payload.content.trim().is_empty() {
+                    ws_error!(
+                        websocket,
+                        "InvalidRequest",
+                        "System message is empty",
+                        payload.conversation_id,
+                        id.to_string()
+                    );
+                    continue;
+                }
+
+                let db = safe_lock!(db);
+
+                let mut conversation = get_conversation(payload.conversation_id, &db);
+                let index = match payload.after_message_id {
+                    Some(after) => match conversation
+                        .messages
+                        .iter()
+                        .position(|m| m.id == Some(after))
+                    {
+                        Some(i) => i + 1,
+                        None => {
+                            ws_error!(
+                                websocket,
+                                "InsertSystemMessage",
+                                "No message with the given ID in the conversation",
+                                after,
+                                id.to_string()
+                            );
+                            continue;
+                        }
+                    },
+                    None => 0,
+                };
+
+                match insert_system_message(&mut conversation, index, payload.content, &db) {
+                    Ok(_) => {
+                        ws_send!(websocket, serialize_response!(Load, conversation, id));
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "InsertSystemMessage",
+                            "Error saving system message",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::Branches { id, payload } => {
+                match get_branches(payload.conversation_id, &safe_lock!(db)) {
+                    Ok(branches) => {
+                        ws_send!(websocket, serialize_response!(Branches, branches, id));
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "Branches",
+                            "Error fetching branches",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::SwitchBranch { id, payload } => {
+                let db = safe_lock!(db);
+                match db.execute(
+                    "UPDATE conversations SET active_branch_id = ?2
+                     WHERE id = ?1
+                     AND EXISTS (SELECT 1 FROM branches WHERE id = ?2 AND conversation_id = ?1)",
+                    params![payload.conversation_id, payload.branch_id],
+                ) {
+                    Ok(1) => {
+                        ws_send!(
+                            websocket,
+                            serialize_response!(
+                                Load,
+                                get_conversation(payload.conversation_id, &db),
+                                id
+                            )
+                        );
+                    }
+                    Ok(_) => {
+                        ws_error!(
+                            websocket,
+                            "SwitchBranch",
+                            "No such branch in the conversation",
+                            payload.branch_id,
+                            id.to_string()
+                        );
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "SwitchBranch",
+                            "Error switching branches",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::SnapshotConversation { id, payload } => {
+                match snapshots::take(payload.conversation_id, &payload.label, &safe_lock!(db)) {
+                    Ok(snapshot) => {
+                        ws_send!(websocket, serialize_response!(Snapshot, snapshot, id));
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "SnapshotConversation",
+                            "Error taking snapshot",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::Snapshots { id, payload } => {
+                match snapshots::list(payload.conversation_id, &safe_lock!(db)) {
+                    Ok(snapshots) => {
+                        ws_send!(
+                            websocket,
+                            serialize_response!(Snapshots, SnapshotList { snapshots }, id)
+                        );
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "Snapshots",
+                            "Error listing snapshots",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::RestoreSnapshot { id, payload } => {
+                let db = std::sync::Arc::clone(&db);
+                dispatch(&websocket, id.clone(), move || {
+                    let db = safe_lock!(db);
+                    match snapshots::restore(payload.snapshot_id, &db) {
+                        Ok((conversation_id, _)) => {
+                            serialize_response!(Load, get_conversation(conversation_id, &db), id)
+                        }
+                        Err(e) => error_response!(
+                            "RestoreSnapshot",
+                            "Error restoring snapshot",
+                            e,
+                            id.to_string()
+                        ),
+                    }
+                });
+            }
+            // Continues a conversation whose last assistant message requested tool calls
+            // The results are appended as tool messages, followed by a new placeholder for
+            // the assistant's response
+            ArrakisRequest::ToolResult { id, payload } => {
+                let db = std::sync::Arc::clone(&db);
+                let resources = resources.clone();
+                dispatch_with(&websocket, id.clone(), move |websocket| {
+                    let db = safe_lock!(db);
+
+                    let mut conversation = get_conversation(payload.conversation_id, &db);
+                    let mut placeholder = match conversation.messages.last() {
+                        Some(m) if !m.tool_calls.is_empty() => m.clone(),
+                        _ => {
+                            ws_error!(
+                                websocket,
+                                "ToolResult",
+                                "Conversation has no pending tool calls",
+                                payload.conversation_id,
+                                id.to_string()
+                            );
+                            return;
+                        }
+                    };
+
+                    for result in payload.results {
+                        placeholder.sequence += 1;
+                        conversation.messages.push(Message {
+                            id: None,
+                            message_type: MessageType::Tool,
+                            content: result.content,
+                            api: placeholder.api.clone(),
+                            system_prompt: String::new(),
+                            sequence: placeholder.sequence,
+                            date_created: String::new(),
+                            tool_calls: Vec::new(),
+                            tool_call_id: Some(result.tool_call_id),
+                            attachments: Vec::new(),
+                            interrupted: false,
+                            language: None,
+                            citations: Vec::new(),
+                            moderation: None,
+                        });
+                    }
+
+                    placeholder.id = None;
+                    placeholder.content = String::new();
+                    placeholder.system_prompt = String::new();
+                    placeholder.tool_calls = Vec::new();
+                    placeholder.sequence += 1;
+                    conversation.messages.push(placeholder);
+
+                    start_completion(websocket, &id, conversation, &resources);
+                });
+            }
+            ArrakisRequest::Search { id, payload } => {
+                if payload.query.trim().is_empty() {
+                    ws_send!(
+                        websocket,
+                        serialize_response!(
+                            Search,
+                            SearchResponse {
+                                results: Vec::new()
+                            },
+                            id
+                        )
+                    );
+
+                    continue;
+                }
+
+                let db = std::sync::Arc::clone(&db);
+                dispatch(&websocket, id.clone(), move || {
+                    match search_conversations(
+                        &payload.query,
+                        payload.limit.unwrap_or(50),
+                        &safe_lock!(db),
+                    ) {
+                        Ok(results) => serialize_response!(Search, SearchResponse { results }, id),
+                        Err(e) => error_response!(
+                            "Search",
+                            "Error searching conversations",
+                            e,
+                            id.to_string()
+                        ),
+                    }
+                });
+            }
+            ArrakisRequest::Import { id, payload } => {
+                let db = worker_db(&db);
+                let dewey = std::sync::Arc::clone(&dewey);
+                dispatch(&websocket, id.clone(), move || {
+                    match import_conversations(&payload, &safe_lock!(db), &dewey) {
+                        Ok(response) => serialize_response!(Import, response, id),
+                        Err(e) => error_response!(
+                            "Import",
+                            "Error importing conversations",
+                            e,
+                            id.to_string()
+                        ),
+                    }
+                });
+            }
+            ArrakisRequest::ExportSettings { id, payload } => {
+                match export_settings(&payload, &safe_lock!(db)) {
+                    Ok(response) => {
+                        ws_send!(websocket, serialize_response!(ExportSettings, response, id));
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "ExportSettings",
+                            "Error exporting settings",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                }
+            }
+            // Responds with the config as it stands after the import
+            ArrakisRequest::ImportSettings { id, payload } => {
+                let db = safe_lock!(db);
+                let imported = match settings::read(std::path::Path::new(&payload.path)) {
+                    Ok(s) => s.apply(&get_config(&db)),
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "ImportSettings",
+                            "Error reading settings file",
+                            e,
+                            id.to_string()
+                        );
+                        continue;
+                    }
+                };
+
+                match save_config(&db, &imported) {
+                    Ok(_) => {
+                        lprint!(info, "Imported settings from {}", payload.path);
+                        let _ = model_refresh.send(());
+                        ws_send!(websocket, serialize_response!(Config, get_config(&db), id));
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "ImportSettings",
+                            "Error saving imported settings",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            // Identical uploads share one blob on disk
+            ArrakisRequest::UploadAttachment { id, payload } => {
+                match attachments::store(&get_attachments_dir(), &payload, &safe_lock!(db)) {
+                    Ok(attachment) => {
+                        ws_send!(websocket, serialize_response!(Attachment, attachment, id));
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "UploadAttachment",
+                            "Error storing attachment",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                }
+            }
+            // Asks the local server what it has loaded
+            ArrakisRequest::LocalModels { id } => {
+                dispatch(&websocket, id.clone(), move || {
+                    match network::block_on(network::list_models("local")) {
+                        Ok(models) => {
+                            serialize_response!(Models, ModelList { models }, id)
+                        }
+                        Err(e) => error_response!(
+                            "LocalModels",
+                            "Error listing local models",
+                            e,
+                            id.to_string()
+                        ),
+                    }
+                });
+            }
+            ArrakisRequest::Models { id } => match get_available_models(&safe_lock!(db)) {
+                Ok(models) => {
+                    ws_send!(
+                        websocket,
+                        serialize_response!(Models, ModelList { models }, id)
+                    );
+                }
+                Err(e) => {
+                    ws_error!(
+                        websocket,
+                        "Models",
+                        "Error listing models",
+                        e,
+                        id.to_string()
+                    );
+                }
+            },
+            ArrakisRequest::Personas { id } => match personas::list(&safe_lock!(db)) {
+                Ok(personas) => {
+                    ws_send!(
+                        websocket,
+                        serialize_response!(Personas, PersonaList { personas }, id)
+                    );
+                }
+                Err(e) => {
+                    ws_error!(
+                        websocket,
+                        "Personas",
+                        "Error listing personas",
+                        e,
+                        id.to_string()
+                    );
+                }
+            },
+            // Both of these send back the updated list
+            ArrakisRequest::SavePersona { id, payload } => {
+                let db = safe_lock!(db);
+                match personas::save(&payload, &db) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "SavePersona",
+                            "Error saving persona",
+                            e,
+                            id.to_string()
+                        );
+                        continue;
+                    }
+                };
+
+                match personas::list(&db) {
+                    Ok(personas) => {
+                        ws_send!(
+                            websocket,
+                            serialize_response!(Personas, PersonaList { personas }, id)
+                        );
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "SavePersona",
+                            "Error listing personas",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::DeletePersona { id, payload } => {
+                let db = safe_lock!(db);
+                match personas::delete(payload.persona_id, &db) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "DeletePersona",
+                            "Error deleting persona",
+                            e,
+                            id.to_string()
+                        );
+                        continue;
+                    }
+                };
+
+                match personas::list(&db) {
+                    Ok(personas) => {
+                        ws_send!(
+                            websocket,
+                            serialize_response!(Personas, PersonaList { personas }, id)
+                        );
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "DeletePersona",
+                            "Error listing personas",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::PromptTemplates { id } => match templates::list(&safe_lock!(db)) {
+                Ok(templates) => {
+                    ws_send!(
+                        websocket,
+                        serialize_response!(PromptTemplates, PromptTemplateList { templates }, id)
+                    );
+                }
+                Err(e) => {
+                    ws_error!(
+                        websocket,
+                        "PromptTemplates",
+                        "Error listing prompt templates",
+                        e,
+                        id.to_string()
+                    );
+                }
+            },
+            // Both of these send back the updated list
+            ArrakisRequest::SavePromptTemplate { id, payload } => {
+                let db = safe_lock!(db);
+                match templates::save(&payload, &db) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "SavePromptTemplate",
+                            "Error saving prompt template",
+                            e,
+                            id.to_string()
+                        );
+                        continue;
+                    }
+                };
+
+                match templates::list(&db) {
+                    Ok(templates) => {
+                        ws_send!(
+                            websocket,
+                            serialize_response!(
+                                PromptTemplates,
+                                PromptTemplateList { templates },
+                                id
+                            )
+                        );
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "SavePromptTemplate",
+                            "Error listing prompt templates",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::DeletePromptTemplate { id, payload } => {
+                let db = safe_lock!(db);
+                match templates::delete(payload.template_id, &db) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "DeletePromptTemplate",
+                            "Error deleting prompt template",
+                            e,
+                            id.to_string()
+                        );
+                        continue;
+                    }
+                };
+
+                match templates::list(&db) {
+                    Ok(templates) => {
+                        ws_send!(
+                            websocket,
+                            serialize_response!(
+                                PromptTemplates,
+                                PromptTemplateList { templates },
+                                id
+                            )
+                        );
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "DeletePromptTemplate",
+                            "Error listing prompt templates",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::WatchedFolders { id } => {
+                let db = safe_lock!(db);
+                match watch::list(&db) {
+                    Ok(folders) => {
+                        ws_send!(
+                            websocket,
+                            serialize_response!(WatchedFolders, WatchedFolderList { folders }, id)
+                        );
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "WatchedFolders",
+                            "Error listing watched folders",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::AddWatchedFolder { id, payload } => {
+                let db = safe_lock!(db);
+                match watch::add(&payload.path, &db) {
+                    Ok(_) => {
+                        let _ = watch_scan.send(());
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "AddWatchedFolder",
+                            "Error adding watched folder",
+                            e,
+                            id.to_string()
+                        );
+                        continue;
+                    }
+                };
+
+                match watch::list(&db) {
+                    Ok(folders) => {
+                        ws_send!(
+                            websocket,
+                            serialize_response!(WatchedFolders, WatchedFolderList { folders }, id)
+                        );
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "AddWatchedFolder",
+                            "Error listing watched folders",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::RemoveWatchedFolder { id, payload } => {
+                let db = safe_lock!(db);
+                match watch::remove(payload.folder_id, &db) {
+                    Ok(entries) => {
+                        for entry in entries.iter() {
+                            forget_watched_file(entry);
+                        }
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "RemoveWatchedFolder",
+                            "Error removing watched folder",
+                            e,
+                            id.to_string()
+                        );
+                        continue;
+                    }
+                };
+
+                match watch::list(&db) {
+                    Ok(folders) => {
+                        ws_send!(
+                            websocket,
+                            serialize_response!(WatchedFolders, WatchedFolderList { folders }, id)
+                        );
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "RemoveWatchedFolder",
+                            "Error listing watched folders",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::Repositories { id } => match repos::list(&safe_lock!(db)) {
+                Ok(repositories) => {
+                    ws_send!(
+                        websocket,
+                        serialize_response!(Repositories, RepositoryList { repositories }, id)
+                    );
+                }
+                Err(e) => {
+                    ws_error!(
+                        websocket,
+                        "Repositories",
+                        "Error listing repositories",
+                        e,
+                        id.to_string()
+                    );
+                }
+            },
+            ArrakisRequest::IndexRepo { id, payload } => {
+                if safe_lock!(dewey).is_none() {
+                    ws_error!(
+                        websocket,
+                        "IndexRepo",
+                        "Repositories can't be indexed without Dewey",
+                        "Dewey is unavailable",
+                        id.to_string()
+                    );
+                    continue;
+                }
+
+                let repository = match repos::add(&payload.path, &safe_lock!(db)) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "IndexRepo",
+                            "Error adding repository",
+                            e,
+                            id.to_string()
+                        );
+                        continue;
+                    }
+                };
+
+                if repos::start_indexing(repository.id) {
+                    let db = worker_db(&db);
+                    let dewey = std::sync::Arc::clone(&dewey);
+                    std::thread::spawn(move || {
+                        index_repository(&repository, &db, &dewey);
+                        repos::finish_indexing(repository.id);
+                    });
+                }
+
+                match repos::list(&safe_lock!(db)) {
+                    Ok(repositories) => {
+                        ws_send!(
+                            websocket,
+                            serialize_response!(Repositories, RepositoryList { repositories }, id)
+                        );
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "IndexRepo",
+                            "Error listing repositories",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::ReindexMemory { id } => {
+                if safe_lock!(dewey).is_none() {
+                    ws_error!(
+                        websocket,
+                        "ReindexMemory",
+                        "Messages can't be embedded without Dewey",
+                        "Dewey is unavailable",
+                        id.to_string()
+                    );
+                    continue;
+                }
+
+                start_backfill(&db, &dewey);
+                ws_send!(
+                    websocket,
+                    serialize_response!(ReindexMemory, backfill::progress().unwrap(), id)
+                );
+            }
+            ArrakisRequest::Memories { id } => match memory::list(&safe_lock!(db)) {
+                Ok(memories) => {
+                    ws_send!(
+                        websocket,
+                        serialize_response!(Memories, MemoryList { memories }, id)
+                    );
+                }
+                Err(e) => {
+                    ws_error!(
+                        websocket,
+                        "Memories",
+                        "Error listing memories",
+                        e,
+                        id.to_string()
+                    );
+                }
+            },
+            ArrakisRequest::ForgetMemory { id, payload } => {
+                let db = worker_db(&db);
+                let dewey = std::sync::Arc::clone(&dewey);
+                dispatch(&websocket, id.clone(), move || {
+                    match forget_memories(Some(payload.memory_id), &safe_lock!(db), &dewey) {
+                        Ok(memories) => {
+                            serialize_response!(Memories, MemoryList { memories }, id)
+                        }
+                        Err(e) => error_response!(
+                            "ForgetMemory",
+                            "Error forgetting memory",
+                            e,
+                            id.to_string()
+                        ),
+                    }
+                });
+            }
+            ArrakisRequest::ForgetAllMemories { id } => {
+                let db = worker_db(&db);
+                let dewey = std::sync::Arc::clone(&dewey);
+                dispatch(&websocket, id.clone(), move || {
+                    match forget_memories(None, &safe_lock!(db), &dewey) {
+                        Ok(memories) => {
+                            serialize_response!(Memories, MemoryList { memories }, id)
+                        }
+                        Err(e) => error_response!(
+                            "ForgetAllMemories",
+                            "Error forgetting memories",
+                            e,
+                            id.to_string()
+                        ),
+                    }
+                });
             }
-        },
-    ));
-
-    lprint!(info, "Tokenizer initialized");
-
-    let db_ = std::sync::Arc::new(std::sync::Mutex::new(db));
-
-    // Embeddings are retrieved from the OpenAI API and stored locally using Dewey as the index
-    let dewey_ = std::sync::Arc::new(std::sync::Mutex::new(dewey));
-
-    lprint!(info, "Dewey initialized");
-
-    let server = match std::net::TcpListener::bind("127.0.0.1:9001") {
-        Ok(s) => s,
-        Err(e) => {
-            lprint!(error, "Error binding websocket: {}", e);
-            return;
-        }
-    };
-
-    lprint!(info, "WebSocket server listening on ws://127.0.0.1:9001");
-
-    // Websocket server loop
-    for stream in server.incoming() {
-        let tokenizer = std::sync::Arc::clone(&tokenizer_);
-        let db = std::sync::Arc::clone(&db_);
-        let dewey = std::sync::Arc::clone(&dewey_);
-        std::thread::spawn(move || {
-            let stream = stream.unwrap();
-            let mut websocket = tungstenite::accept(stream).unwrap();
-
-            loop {
-                let msg = match websocket.read() {
-                    Ok(m) => m,
-                    Err(e) => {
-                        error!("error reading from websocket: {}", e);
-                        continue;
+            ArrakisRequest::Backup { id, payload } => {
+                let db = worker_db(&db);
+                let dewey = std::sync::Arc::clone(&dewey);
+                dispatch(&websocket, id.clone(), move || {
+                    match backup_state(&payload, &safe_lock!(db), &dewey) {
+                        Ok(response) => serialize_response!(Backup, response, id),
+                        Err(e) => error_response!("Backup", "Error backing up", e, id.to_string()),
                     }
-                };
-
-                let request: ArrakisRequest = match msg {
-                    tungstenite::Message::Close(_) => {
-                        break;
+                });
+            }
+            ArrakisRequest::Restore { id, payload } => {
+                dispatch(&websocket, id.clone(), move || {
+                    match stage_restore(&payload) {
+                        Ok(response) => serialize_response!(Restore, response, id),
+                        Err(e) => {
+                            error_response!("Restore", "Error restoring backup", e, id.to_string())
+                        }
+                    }
+                });
+            }
+            ArrakisRequest::EnableSync { id, payload } => {
+                let db = worker_db(&db);
+                dispatch(&websocket, id.clone(), move || {
+                    match enable_sync(&payload, &safe_lock!(db)) {
+                        Ok(response) => serialize_response!(Sync, response, id),
+                        Err(e) => {
+                            error_response!("EnableSync", "Error enabling sync", e, id.to_string())
+                        }
+                    }
+                });
+            }
+            ArrakisRequest::DisableSync { id } => match disable_sync(&safe_lock!(db)) {
+                Ok(response) => {
+                    ws_send!(websocket, serialize_response!(Sync, response, id));
+                }
+                Err(e) => {
+                    ws_error!(
+                        websocket,
+                        "DisableSync",
+                        "Error disabling sync",
+                        e,
+                        id.to_string()
+                    );
+                }
+            },
+            ArrakisRequest::SyncNow { id } => {
+                let db = worker_db(&db);
+                dispatch(&websocket, id.clone(), move || {
+                    match sync_now(&safe_lock!(db)) {
+                        Ok(response) => serialize_response!(Sync, response, id),
+                        Err(e) => {
+                            error_response!("SyncNow", "Error syncing", e, id.to_string())
+                        }
+                    }
+                });
+            }
+            ArrakisRequest::ExportFlashcards { id, payload } => {
+                let db = worker_db(&db);
+                dispatch(&websocket, id.clone(), move || {
+                    match export_flashcards(&payload, &safe_lock!(db)) {
+                        Ok(response) => {
+                            serialize_response!(ExportFlashcards, response, id)
+                        }
+                        Err(e) => match ProviderError::from_io(&e) {
+                            Some(provider_error) => serialize_response!(
+                                WilliamError,
+                                WilliamError::from_provider(provider_error),
+                                id
+                            ),
+                            None => error_response!(
+                                "ExportFlashcards",
+                                "Error exporting flashcards",
+                                e,
+                                id.to_string()
+                            ),
+                        },
+                    }
+                });
+            }
+            ArrakisRequest::Export { id, payload } => {
+                let db = worker_db(&db);
+                dispatch(&websocket, id.clone(), move || {
+                    match export_conversation(&payload, &safe_lock!(db)) {
+                        Ok(response) => serialize_response!(Export, response, id),
+                        Err(e) => error_response!(
+                            "Export",
+                            "Error exporting conversation",
+                            e,
+                            id.to_string()
+                        ),
                     }
-                    tungstenite::Message::Text(t) => match serde_json::from_str(&t) {
-                        Ok(r) => r,
+                });
+            }
+            // Completions check for their own cancellations while streaming,
+            // so one landing here is for a completion that's already finished
+            ArrakisRequest::CancelCompletion { id: _, payload } => {
+                if websocket.cancel(&payload.request_id) {
+                    lprint!(info, "Cancelling completion {}", payload.request_id);
+                } else {
+                    lprint!(
+                        info,
+                        "Ignoring cancellation for inactive completion {}",
+                        payload.request_id
+                    );
+                }
+            }
+            ArrakisRequest::Config { id, payload } => {
+                let db = safe_lock!(db);
+
+                let config = get_config(&db);
+
+                if payload.write {
+                    match save_config(&db, &payload) {
+                        // New keys or endpoints can change what's available
+                        Ok(_) => {
+                            let _ = model_refresh.send(());
+                        }
                         Err(e) => {
-                            error!("t: {}", t);
-                            error!("error reading Arrakis request: {}", e);
+                            ws_error!(
+                                websocket,
+                                "Config",
+                                "Error updating user config",
+                                e,
+                                id.to_string()
+                            );
                             continue;
                         }
-                    },
-                    _ => {
-                        error!("unsupported message type");
+                    };
+                } else {
+                    ws_send!(websocket, serialize_response!(Config, config, id));
+                }
+            }
+            ArrakisRequest::WilliamError { id: _, payload: _ } => {
+                // There shouldn't be any requests for this type
+            }
+            // This just deletes the conversation listing in the DB
+            // Deleted conversations go to the trash--
+            // they're only removed for good once they're purged
+            ArrakisRequest::DeleteConversation { id, payload } => {
+                let db = safe_lock!(db);
+                match db.execute(
+                    "UPDATE conversations SET deleted_at = CURRENT_TIMESTAMP WHERE id = ?1 AND deleted_at IS NULL",
+                    params![payload.conversation_id],
+                ) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "DeleteConversation",
+                            "Error deleting conversation",
+                            e,
+                            id.to_string()
+                        );
                         continue;
                     }
                 };
 
-                lprint!(info, "Request deserialized");
-
-                // Not sure if there is a better way of delineating endpoints, but this is the best
-                // we have right now.
-                //
-                // Request types are judged by their payload structure--see `types.rs` for more
-                // info.
-                match request {
-                    // Triggers on a chat message submission, as well as a fork
-                    // (after backend processing)
-                    ArrakisRequest::Completion { id, payload } => {
-                        completion(
-                            &mut websocket,
-                            &id,
-                            payload,
-                            safe_lock!(tokenizer).as_ref(),
-                            &safe_lock!(db),
-                            safe_lock!(dewey).as_mut(),
+                let conversations = match get_conversation_list(false, &db) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "ConversationList",
+                            "Error fetching conversation IDs",
+                            e,
+                            id.to_string()
                         );
+                        continue;
                     }
-                    // TODO: Not sure how necessary this is
-                    ArrakisRequest::Ping { id, payload: _ } => {
+                };
+
+                ws_send!(
+                    websocket,
+                    serialize_response!(ConversationList, ConversationList { conversations }, id)
+                );
+            }
+            // Archived conversations are left out of `ConversationList`
+            ArrakisRequest::ListArchived { id } => {
+                let db = safe_lock!(db);
+                match get_conversation_list(true, &db) {
+                    Ok(conversations) => {
                         ws_send!(
                             websocket,
                             serialize_response!(
-                                Ping,
-                                Ping {
-                                    body: "pong".to_string(),
-                                },
+                                ListArchived,
+                                ConversationList { conversations },
                                 id
                             )
                         );
                     }
-                    // Retrieve a list of saved conversation IDs
-                    ArrakisRequest::ConversationList { id } => {
-                        let db = safe_lock!(db);
-                        let mut query = db
-                            .prepare(
-                                "
-                            SELECT
-                                id,
-                                name
-                            from conversations
-                            order by last_updated desc
-                        ",
-                            )
-                            .unwrap();
-                        let conversations = match query.query_map(params![], |row| {
-                            Ok(Conversation {
-                                id: row.get(0)?,
-                                name: row.get(1)?,
-                                messages: Vec::new(),
-                            })
-                        }) {
-                            Ok(q) => q,
-                            Err(e) => {
-                                ws_error!(
-                                    websocket,
-                                    "ConversationList",
-                                    "Error fetching conversation IDs",
-                                    e,
-                                    id.to_string()
-                                );
-                                continue;
-                            }
-                        }
-                        .map(|c| c.unwrap())
-                        .collect();
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "ListArchived",
+                            "Error fetching archived conversations",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::Pin { id, payload } => {
+                let db = safe_lock!(db);
+                set_conversation_flag(
+                    &mut websocket,
+                    &db,
+                    &id,
+                    payload.conversation_id,
+                    "pinned",
+                    true,
+                );
+            }
+            ArrakisRequest::Unpin { id, payload } => {
+                let db = safe_lock!(db);
+                set_conversation_flag(
+                    &mut websocket,
+                    &db,
+                    &id,
+                    payload.conversation_id,
+                    "pinned",
+                    false,
+                );
+            }
+            ArrakisRequest::Archive { id, payload } => {
+                let db = safe_lock!(db);
+                set_conversation_flag(
+                    &mut websocket,
+                    &db,
+                    &id,
+                    payload.conversation_id,
+                    "archived",
+                    true,
+                );
+            }
+            ArrakisRequest::Unarchive { id, payload } => {
+                let db = safe_lock!(db);
+                set_conversation_flag(
+                    &mut websocket,
+                    &db,
+                    &id,
+                    payload.conversation_id,
+                    "archived",
+                    false,
+                );
+            }
+            ArrakisRequest::MarkRead { id, payload } => {
+                let db = safe_lock!(db);
+                match db.execute(
+                    "UPDATE conversations
+                    SET last_read_message_id = COALESCE(?2, (
+                        SELECT MAX(l.message_id)
+                        FROM paths l
+                        WHERE l.conversation_id = conversations.id
+                        AND l.branch_id = conversations.active_branch_id
+                    ))
+                    WHERE id = ?1",
+                    params![payload.conversation_id, payload.message_id],
+                ) {
+                    Ok(0) => {
+                        ws_error!(
+                            websocket,
+                            "MarkRead",
+                            "Conversation not found",
+                            payload.conversation_id,
+                            id.to_string()
+                        );
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "MarkRead",
+                            "Error marking conversation read",
+                            e,
+                            id.to_string()
+                        );
+                        continue;
+                    }
+                };
 
+                match get_conversation_list(false, &db) {
+                    Ok(conversations) => {
                         ws_send!(
                             websocket,
                             serialize_response!(
@@ -1021,335 +8461,200 @@ async fn websocket_server(db: rusqlite::Connection, dewey: Option<dewey_lib::Dew
                             )
                         );
                     }
-                    // Fetch a conversation from its ID
-                    ArrakisRequest::Load { id, payload } => {
-                        ws_send!(
+                    Err(e) => {
+                        ws_error!(
                             websocket,
-                            serialize_response!(
-                                Load,
-                                get_conversation(payload.id, &safe_lock!(db)).into(),
-                                id
-                            )
+                            "ConversationList",
+                            "Error fetching conversation IDs",
+                            e,
+                            id.to_string()
                         );
                     }
-                    // Fetch the first message of a conversation from its conversation ID
-                    ArrakisRequest::Preview { id, mut payload } => {
-                        let message = get_first_message(payload.conversation_id, &safe_lock!(db));
-                        payload.content = message.content;
-                        ws_send!(websocket, serialize_response!(Preview, payload, id));
+                };
+            }
+            ArrakisRequest::Trash { id } => {
+                let db = safe_lock!(db);
+                match get_trash(&db) {
+                    Ok(trash) => {
+                        ws_send!(websocket, serialize_response!(Trash, trash, id));
                     }
-                    // get the current conversation,
-                    // create the fork,
-                    // carry on with the completion
-                    //
-                    // TODO: this needs cleaned up from a UI perspective. Regenerating messages
-                    //       when there's a communication failure quickly leads to a cluttering of the
-                    //       conversation history. They also need renamed based on the conversation
-                    //       redirection
-                    ArrakisRequest::Fork { id, payload } => {
-                        let db = safe_lock!(db);
-
-                        let mut conversation = get_conversation(payload.conversation_id, &db);
-
-                        conversation.id = None;
-                        conversation.name = format!("Fork: {}", conversation.name);
-                        conversation.messages = conversation
-                            .messages
-                            .iter()
-                            .take(payload.sequence as usize)
-                            .cloned()
-                            .collect();
-
-                        // The conversation should _always_ have at least one element--what would
-                        // there be to fork otherwise?
-                        let mut assistant_message = conversation.messages.last().unwrap().clone();
-
-                        if assistant_message.message_type != MessageType::Assistant {
-                            assistant_message.id = None;
-                            assistant_message.message_type = MessageType::Assistant;
-                            assistant_message.content = String::new();
-                            assistant_message.sequence += 1;
-
-                            conversation.messages.push(assistant_message);
-                        } else {
-                            let last = conversation.messages.last_mut().unwrap();
-                            last.content = String::new();
-                        }
-
-                        let _ = conversation.upsert(&db);
-                        let new_id = db.last_insert_rowid();
-
-                        let fork_query = "INSERT INTO forks (from_id, to_id) VALUES (?, ?)";
-                        match db.execute(fork_query, params![payload.conversation_id, new_id]) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                ws_error!(
-                                    websocket,
-                                    "Fork",
-                                    "Error adding fork to DB",
-                                    e,
-                                    id.to_string()
-                                );
-                                continue;
-                            }
-                        };
-
-                        completion(
-                            &mut websocket,
-                            &id,
-                            conversation,
-                            safe_lock!(tokenizer).as_ref(),
-                            &db,
-                            safe_lock!(dewey).as_mut(),
-                        )
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "Trash",
+                            "Error fetching trash",
+                            e,
+                            id.to_string()
+                        );
                     }
-                    ArrakisRequest::Config { id, payload } => {
-                        let db = safe_lock!(db);
-
-                        let config = get_config(&db);
-
-                        if payload.write {
-                            let mut update_stmt = db
-                                .prepare(
-                                    "UPDATE user_config 
-                                     SET openai_key = ?1, 
-                                         groq_key = ?2, 
-                                         grok_key = ?3, 
-                                         anthropic_key = ?4, 
-                                         gemini_key = ?5, 
-                                         system_prompt = ?6",
-                                )
-                                .unwrap();
-
-                            match update_stmt.execute(params![
-                                payload.api_keys.openai,
-                                payload.api_keys.groq,
-                                payload.api_keys.grok,
-                                payload.api_keys.anthropic,
-                                payload.api_keys.gemini,
-                                payload.system_prompt,
-                            ]) {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    ws_error!(
-                                        websocket,
-                                        "Config",
-                                        "Error updating user config",
-                                        e,
-                                        id.to_string()
-                                    );
-                                    continue;
-                                }
-                            };
-
-                            set_keys(&payload);
-                        } else {
-                            ws_send!(websocket, serialize_response!(Config, config, id));
-                        }
+                };
+            }
+            ArrakisRequest::RestoreConversation { id, payload } => {
+                let db = safe_lock!(db);
+                match db.execute(
+                    "UPDATE conversations SET deleted_at = NULL WHERE id = ?1",
+                    params![payload.conversation_id],
+                ) {
+                    Ok(0) => {
+                        ws_error!(
+                            websocket,
+                            "RestoreConversation",
+                            "Conversation not found",
+                            payload.conversation_id,
+                            id.to_string()
+                        );
+                        continue;
                     }
-                    ArrakisRequest::WilliamError { id: _, payload: _ } => {
-                        // There shouldn't be any requests for this type
-                    }
-                    // This just deletes the conversation listing in the DB
-                    // TODO: Do we want this to delete the messages?
-                    //       What about the different message paths?
-                    ArrakisRequest::DeleteConversation { id, payload } => {
-                        let db = safe_lock!(db);
-                        // TODO: Error handling
-                        let _ = db
-                            .execute(
-                                "DELETE FROM conversations WHERE id = ?1",
-                                params![payload.conversation_id],
-                            )
-                            .unwrap();
-
-                        // Copy + pasted from the ConversationList endpoint
-                        let mut query = db
-                            .prepare(
-                                "
-                            SELECT
-                                id,
-                                name
-                            from conversations
-                            order by last_updated desc
-                        ",
-                            )
-                            .unwrap();
-                        let conversations = match query.query_map(params![], |row| {
-                            Ok(Conversation {
-                                id: row.get(0)?,
-                                name: row.get(1)?,
-                                messages: Vec::new(),
-                            })
-                        }) {
-                            Ok(q) => q,
-                            Err(e) => {
-                                ws_error!(
-                                    websocket,
-                                    "ConversationList",
-                                    "Error fetching conversation IDs",
-                                    e,
-                                    id.to_string()
-                                );
-                                continue;
-                            }
-                        }
-                        .map(|c| c.unwrap())
-                        .collect();
-
-                        ws_send!(
+                    Ok(_) => {}
+                    Err(e) => {
+                        ws_error!(
                             websocket,
-                            serialize_response!(
-                                ConversationList,
-                                ConversationList { conversations },
-                                id
-                            )
+                            "RestoreConversation",
+                            "Error restoring conversation",
+                            e,
+                            id.to_string()
                         );
+                        continue;
                     }
-                    // TODO: This will most definitely need more fleshed out
-                    ArrakisRequest::Usage { id, payload } => {
-                        let db = safe_lock!(db);
-                        let tokenizer = safe_lock!(tokenizer);
-
-                        if tokenizer.is_none() {
-                            ws_error!(
-                                websocket,
-                                "Usage",
-                                "Tokenizer isn't available to calculate usage",
-                                Err::<String, std::io::Error>(std::io::Error::new(
-                                    std::io::ErrorKind::Other,
-                                    "Error fetching tokenizer"
-                                )),
-                                id.to_string()
-                            );
+                };
 
-                            continue;
-                        }
+                let conversations = match get_conversation_list(false, &db) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "ConversationList",
+                            "Error fetching conversation IDs",
+                            e,
+                            id.to_string()
+                        );
+                        continue;
+                    }
+                };
 
-                        let tokenizer = tokenizer.as_ref().unwrap();
-
-                        let mut stmt = db
-                            .prepare(
-                                "SELECT
-                                    m.id,
-                                    m.message_type_id,
-                                    m.content,
-                                    models.provider as provider,
-                                    models.name as model,
-                                    m.system_prompt,
-                                    p.sequence,
-                                    date(m.date_created) as date_created
-                                FROM messages m
-                                JOIN models ON m.api_config_id = models.id
-                                JOIN paths p ON m.id = p.message_id
-                                WHERE m.date_created BETWEEN ?1 AND ?2
-                                ORDER BY m.date_created ASC",
-                            )
-                            .unwrap();
-
-                        let messages = match stmt.query_map(
-                            params![payload.date_from, payload.date_to],
-                            |row| {
-                                Ok(Message {
-                                    id: row.get(0)?,
-                                    message_type: MessageType::from_id(row.get(1)?).unwrap(),
-                                    content: row.get(2)?,
-                                    api: API::from_strings(
-                                        &row.get::<_, String>(3)?,
-                                        &row.get::<_, String>(4)?,
-                                    )
-                                    .unwrap(),
-                                    system_prompt: row.get(5)?,
-                                    sequence: row.get(6)?,
-                                    date_created: row.get(7)?,
-                                })
-                            },
-                        ) {
-                            Ok(m) => m,
-                            Err(e) => {
-                                ws_error!(
-                                    websocket,
-                                    "Usage",
-                                    "Error fetching messages",
-                                    e,
-                                    id.to_string()
-                                );
-                                continue;
-                            }
-                        }
-                        .map(|m| m.unwrap())
-                        .collect::<Vec<Message>>();
-
-                        let mut usages = Vec::new();
-                        let mut dates = Vec::new();
-
-                        let mut last_date = String::new();
-                        let mut token_usage = std::collections::HashMap::new();
-                        for m in messages.iter() {
-                            let api = m.api.to_strings().1;
-                            let token_count = tokenizer.encode(&m.content).len();
-                            if m.date_created != last_date {
-                                if token_usage.len() > 0 {
-                                    usages.push(token_usage);
-                                    dates.push(m.date_created.clone());
-                                }
-
-                                token_usage = std::collections::HashMap::new();
-
-                                let token_usage =
-                                    token_usage.entry(api).or_insert_with(|| TokenUsage {
-                                        input_tokens: 0,
-                                        output_tokens: 0,
-                                    });
-
-                                match m.message_type {
-                                    MessageType::Assistant => {
-                                        token_usage.output_tokens += token_count
-                                    }
-                                    MessageType::System
-                                    | MessageType::User
-                                    | MessageType::Developer => {
-                                        token_usage.input_tokens += token_count
-                                    }
-                                }
-                            } else {
-                                let token_usage =
-                                    token_usage.entry(api).or_insert_with(|| TokenUsage {
-                                        input_tokens: 0,
-                                        output_tokens: 0,
-                                    });
-
-                                match m.message_type {
-                                    MessageType::Assistant => {
-                                        token_usage.output_tokens += token_count
-                                    }
-                                    MessageType::System
-                                    | MessageType::User
-                                    | MessageType::Developer => {
-                                        token_usage.input_tokens += token_count
-                                    }
-                                }
-                            }
+                ws_send!(
+                    websocket,
+                    serialize_response!(ConversationList, ConversationList { conversations }, id)
+                );
+            }
+            // Empty the trash now, regardless of how long things have been in it
+            ArrakisRequest::PurgeTrash { id } => {
+                let db = safe_lock!(db);
+                match purge_trash(&db, 0).and_then(|_| get_trash(&db)) {
+                    Ok(trash) => {
+                        ws_send!(websocket, serialize_response!(Trash, trash, id));
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "PurgeTrash",
+                            "Error purging trash",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            // TODO: This will most definitely need more fleshed out
+            // Usage is read from what the providers reported for each response,
+            // grouped by day + model
+            ArrakisRequest::Usage { id, payload } => {
+                let timezone = match usage::UsageTimezone::parse(
+                    payload.timezone.as_deref().unwrap_or("local"),
+                ) {
+                    Ok(tz) => tz,
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "Usage",
+                            "Error fetching usage",
+                            e,
+                            id.to_string()
+                        );
+                        continue;
+                    }
+                };
 
-                            last_date = m.date_created.clone();
+                match usage::get_usage(&payload, timezone, &safe_lock!(db)) {
+                    Ok(response) => {
+                        ws_send!(websocket, serialize_response!(Usage, response, id));
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "Usage",
+                            "Error fetching usage",
+                            e,
+                            id.to_string()
+                        );
+                    }
+                };
+            }
+            ArrakisRequest::Translate { id, payload } => {
+                let db = worker_db(&db);
+                dispatch(
+                    &websocket,
+                    id.clone(),
+                    move || match language::get_translation(&payload, &safe_lock!(db)) {
+                        Ok(translation) => {
+                            serialize_response!(Translate, translation, id)
                         }
+                        Err(e) => match ProviderError::from_io(&e) {
+                            Some(provider_error) => serialize_response!(
+                                WilliamError,
+                                WilliamError::from_provider(provider_error),
+                                id
+                            ),
+                            None => error_response!(
+                                "Translate",
+                                "Error translating message",
+                                e,
+                                id.to_string()
+                            ),
+                        },
+                    },
+                );
+            }
+            ArrakisRequest::ContextBreakdown { id, payload } => {
+                let db = safe_lock!(db);
+                let conversation =
+                    get_conversation_branch(payload.conversation_id, payload.branch_id, &db);
 
+                match context_breakdown(&conversation, tokenizer.as_ref().as_ref(), &db) {
+                    Ok(breakdown) => {
                         ws_send!(
                             websocket,
-                            serialize_response!(
-                                Usage,
-                                UsageResponse {
-                                    token_usage: usages,
-                                    dates
-                                },
-                                id
-                            )
+                            serialize_response!(ContextBreakdown, breakdown, id)
+                        );
+                    }
+                    Err(e) => {
+                        ws_error!(
+                            websocket,
+                            "ContextBreakdown",
+                            "Error breaking down context",
+                            e,
+                            id.to_string()
                         );
                     }
                 };
             }
-        });
+            ArrakisRequest::Stats { id, payload } => {
+                let conversation = get_conversation_branch(
+                    payload.conversation_id,
+                    payload.branch_id,
+                    &safe_lock!(db),
+                );
+
+                ws_send!(
+                    websocket,
+                    serialize_response!(Stats, stats::conversation_stats(&conversation), id)
+                );
+            }
+        };
     }
+
+    // Whatever's still going waits for the client to come back with `Resume`
+    detach(websocket);
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -1359,16 +8664,63 @@ pub fn run() {
             setup();
             lprint!(info, "Workspace initialized");
 
+            #[cfg(feature = "otlp")]
+            spans::otlp::start_exporter();
+
             // The SQLite database is used to store conversations/messages + the like
             // Probably want a more detailed description here
-            let db = rusqlite::Connection::open(get_local_dir().join("william.sqlite"))
-                .expect("Failed to open database");
+            let db = open_db().expect("Failed to open database");
 
             lprint!(info, "SQLite connection established");
 
             // DB initialization
-            db.execute_batch(DB_SETUP_STATEMENTS)
-                .expect("Failed to initialize database");
+            let migrated = migrations::migrate(&db, DB_MIGRATIONS, &get_backups_dir())
+                .expect("Failed to migrate database");
+            if let Some(backup) = migrated.backup {
+                lprint!(info, "Backed up database to {}", backup.display());
+            }
+
+            if migrated.to > migrated.from {
+                lprint!(
+                    info,
+                    "Migrated database from version {} to {}",
+                    migrated.from,
+                    migrated.to
+                );
+            }
+            encrypt_stored_keys(&db).expect("Failed to encrypt stored API keys");
+
+            match unreadable_keys(&db) {
+                Ok(unreadable) if !unreadable.is_empty() => {
+                    lprint!(
+                        error,
+                        "API keys for {} can't be decrypted on this machine; they're left as they are until they're set again",
+                        unreadable.iter().map(|c| key_name(c)).collect::<Vec<_>>().join(", ")
+                    );
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(error, "Error checking stored API keys: {}; ignoring", e);
+                }
+            };
+
+            match recover_interrupted(&db) {
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(
+                        error,
+                        "Error checking for interrupted completions: {}; ignoring",
+                        e
+                    );
+                }
+            };
+
+            match purge_trash(&db, trash_retention_days()) {
+                Ok(_) => {}
+                Err(e) => {
+                    lprint!(error, "Error purging trash: {}; ignoring", e);
+                }
+            };
 
             lprint!(info, "SQLite database initialized");
 
@@ -1378,6 +8730,14 @@ pub fn run() {
 
             lprint!(info, "Environment variables set");
 
+            // Bound up front so the window knows where to connect before it loads
+            // Failing here at least ends things, instead of leaving the UI waiting on nothing
+            let (address, port) = websocket_address(&user_config);
+            let server = bind_websocket(&address, port)?;
+            let url = websocket_url(&server)?;
+
+            lprint!(info, "WebSocket server listening on {}", url);
+
             let dewey = match dewey_lib::Dewey::new() {
                 Ok(d) => Some(d),
                 Err(e) => {
@@ -1387,12 +8747,16 @@ pub fn run() {
             };
 
             spawn(async move {
-                websocket_server(db, dewey).await;
+                websocket_server(server, db, dewey).await;
             });
 
             let win_builder = WebviewWindowBuilder::new(app, "main", WebviewUrl::default())
                 .title("William")
-                .inner_size(800.0, 600.0);
+                .inner_size(800.0, 600.0)
+                .initialization_script(&format!(
+                    "window.__WILLIAM_WEBSOCKET_URL__ = {};",
+                    serde_json::to_string(&url)?
+                ));
 
             // set transparent title bar only when building for macOS
             #[cfg(target_os = "macos")]
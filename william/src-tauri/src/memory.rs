@@ -0,0 +1,121 @@
+use rusqlite::params;
+
+use chamber_common::{lprint, ChamberError, Logger};
+
+use crate::db;
+use crate::types::*;
+
+// What the assistant remembers: the embedding files behind `message_embeddings`,
+// listed for the frontend so they can be looked through and pruned
+//
+// Duplicate messages share a file (see `EmbeddingQueue::push` in lib.rs), so a memory is a file
+// rather than a row--forgetting it forgets it for every message sharing it
+// Forgotten messages are kept in `forgotten_messages` so the backfill doesn't embed them again
+//
+// Only the database side is here--taking the files out of Dewey and off of disk is up to
+// `forget_memories` in lib.rs
+
+const PREVIEW_CHARS: i64 = 200;
+
+fn read_memory(row: &rusqlite::Row) -> rusqlite::Result<Memory> {
+    Ok(Memory {
+        id: row.get("id")?,
+        kind: row.get("kind")?,
+        message_id: row.get("message_id")?,
+        role: row.get("role")?,
+        preview: row.get("preview")?,
+        messages: row.get::<_, i64>("messages")? as usize,
+        conversation_id: row.get("conversation_id")?,
+        conversation_name: row.get("conversation_name")?,
+        date_created: row.get("date_created")?,
+    })
+}
+
+// Newest first, each memory going by the first message embedded with it
+// The conversation is whichever live one the message is in, if any
+pub fn list(db: &rusqlite::Connection) -> Result<Vec<Memory>, ChamberError> {
+    db::query_map(
+        db,
+        "
+        SELECT me.id, me.kind, me.message_id, mt.name AS role, m.date_created,
+            substr(m.content, 1, ?1) AS preview,
+            (SELECT COUNT(*) FROM message_embeddings o WHERE o.filepath = me.filepath) AS messages,
+            c.id AS conversation_id, c.name AS conversation_name
+        FROM message_embeddings me
+        JOIN messages m ON m.id = me.message_id
+        JOIN message_types mt ON mt.id = m.message_type_id
+        LEFT JOIN conversations c ON c.id = (
+            SELECT l.conversation_id
+            FROM paths l
+            JOIN conversations lc ON lc.id = l.conversation_id
+            WHERE l.message_id = me.message_id
+            AND lc.deleted_at IS NULL
+            ORDER BY l.conversation_id
+            LIMIT 1
+        )
+        WHERE me.id = (SELECT MIN(o.id) FROM message_embeddings o WHERE o.filepath = me.filepath)
+        ORDER BY m.date_created DESC, me.id DESC
+        ",
+        params![PREVIEW_CHARS],
+        read_memory,
+    )
+}
+
+// The embedding file of the memory with the given ID--`None` if there's no such memory
+pub fn file(memory_id: i64, db: &rusqlite::Connection) -> Result<Option<String>, ChamberError> {
+    db::query_row(
+        db,
+        "SELECT filepath FROM message_embeddings WHERE id = ?1",
+        params![memory_id],
+        |row| row.get(0),
+    )
+}
+
+pub fn all_files(db: &rusqlite::Connection) -> Result<Vec<String>, ChamberError> {
+    db::query_map(
+        db,
+        "SELECT DISTINCT filepath FROM message_embeddings",
+        params![],
+        |row| row.get(0),
+    )
+}
+
+// Drops every row embedded with `files`, marking their messages as forgotten
+pub fn forget(files: &[String], db: &rusqlite::Connection) -> Result<usize, ChamberError> {
+    let tx = db
+        .unchecked_transaction()
+        .map_err(|e| ChamberError::new("Database", "forgetting memories", e))?;
+
+    let mut rows = 0;
+    for file in files.iter() {
+        // Exchanges don't count--the backfill only embeds messages on their own
+        db::execute(
+            &tx,
+            "
+            INSERT OR IGNORE INTO forgotten_messages (message_id, date_forgotten)
+            SELECT message_id, CURRENT_TIMESTAMP
+            FROM message_embeddings
+            WHERE filepath = ?1 AND kind = 'message'
+            ",
+            params![file],
+        )?;
+
+        rows += db::execute(
+            &tx,
+            "DELETE FROM message_embeddings WHERE filepath = ?1",
+            params![file],
+        )?;
+    }
+
+    tx.commit()
+        .map_err(|e| ChamberError::new("Database", "forgetting memories", e))?;
+
+    lprint!(
+        info,
+        "Forgot {} memories ({} embedding rows)",
+        files.len(),
+        rows
+    );
+
+    Ok(rows)
+}
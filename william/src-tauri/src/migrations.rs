@@ -0,0 +1,283 @@
+// Versioned schema changes for william.sqlite
+//
+// The database's `user_version` pragma is the number of migrations it's had applied,
+// so a migration's version is just its position in the list, counting from 1
+// Migrations are only ever appended--editing or reordering one that's shipped means
+// existing installs never see the change
+//
+// Each migration runs in its own transaction along with the version bump,
+// so a failure leaves the database at the last version that went through cleanly
+// Before anything runs on a database that already has data, a copy of it is written to the
+// backup directory
+
+pub struct Migration {
+    pub description: &'static str,
+    pub apply: fn(&rusqlite::Connection) -> rusqlite::Result<()>,
+}
+
+pub fn version(db: &rusqlite::Connection) -> rusqlite::Result<usize> {
+    let version: i64 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(version as usize)
+}
+
+// Whether there's anything in the database worth backing up
+fn has_tables(db: &rusqlite::Connection) -> rusqlite::Result<bool> {
+    db.prepare("SELECT 1 FROM sqlite_master WHERE type = 'table'")?
+        .exists([])
+}
+
+// Writes a consistent copy of the database to `dir`, returning its path
+pub fn backup(
+    db: &rusqlite::Connection,
+    dir: &std::path::Path,
+    version: usize,
+) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(dir)?;
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_millis();
+    let path = dir.join(format!("william-v{}-{}.sqlite", version, timestamp));
+
+    db.execute(
+        "VACUUM INTO ?1",
+        rusqlite::params![path.to_string_lossy().to_string()],
+    )?;
+
+    Ok(path)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Migrated {
+    pub from: usize,
+    pub to: usize,
+    pub backup: Option<std::path::PathBuf>,
+}
+
+// Brings the database up to date with `migrations`
+// Databases from a newer build are left alone, since there's no telling what changed
+pub fn migrate(
+    db: &rusqlite::Connection,
+    migrations: &[Migration],
+    backup_dir: &std::path::Path,
+) -> Result<Migrated, Box<dyn std::error::Error>> {
+    let current = version(db)?;
+    if current > migrations.len() {
+        return Err(format!(
+            "database is at schema version {}, but this build only knows up to {}",
+            current,
+            migrations.len()
+        )
+        .into());
+    }
+
+    let mut migrated = Migrated {
+        from: current,
+        to: current,
+        backup: None,
+    };
+
+    if current == migrations.len() {
+        return Ok(migrated);
+    }
+
+    if has_tables(db)? {
+        migrated.backup = Some(backup(db, backup_dir, current)?);
+    }
+
+    for (i, migration) in migrations.iter().enumerate().skip(current) {
+        let tx = db.unchecked_transaction()?;
+        (migration.apply)(&tx).map_err(|e| {
+            format!(
+                "migration {} ({}) failed: {}",
+                i + 1,
+                migration.description,
+                e
+            )
+        })?;
+        tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+        tx.commit()?;
+
+        migrated.to = i + 1;
+    }
+
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_notes(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+        db.execute_batch("CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT NOT NULL);")
+    }
+
+    fn add_pinned(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+        db.execute_batch("ALTER TABLE notes ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;")
+    }
+
+    fn broken(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+        db.execute_batch("CREATE TABLE tags (name TEXT); ALTER TABLE nowhere ADD COLUMN x;")
+    }
+
+    const MIGRATIONS: [Migration; 2] = [
+        Migration {
+            description: "Notes",
+            apply: create_notes,
+        },
+        Migration {
+            description: "Pinned notes",
+            apply: add_pinned,
+        },
+    ];
+
+    fn backup_dir(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "william-migrations-{}-{}",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn test_migrate() {
+        let dir = backup_dir("migrate");
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+
+        let migrated = migrate(&db, &MIGRATIONS[..1], &dir).unwrap();
+        assert_eq!((migrated.from, migrated.to), (0, 1));
+        assert_eq!(version(&db).unwrap(), 1);
+        // Nothing to back up on a fresh database
+        assert_eq!(migrated.backup, None);
+        assert!(!dir.exists());
+
+        db.execute("INSERT INTO notes (body) VALUES ('hello')", [])
+            .unwrap();
+        let migrated = migrate(&db, &MIGRATIONS, &dir).unwrap();
+        assert_eq!((migrated.from, migrated.to), (1, 2));
+        assert_eq!(version(&db).unwrap(), 2);
+        assert!(migrated.backup.unwrap().exists());
+
+        let pinned: i64 = db
+            .query_row("SELECT pinned FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(pinned, 0);
+
+        // Already up to date
+        let migrated = migrate(&db, &MIGRATIONS, &dir).unwrap();
+        assert_eq!((migrated.from, migrated.to), (2, 2));
+
+        // From a newer build
+        assert!(migrate(&db, &MIGRATIONS[..1], &dir).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_migration() {
+        let dir = backup_dir("failed");
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+
+        let migrations = [
+            Migration {
+                description: "Notes",
+                apply: create_notes,
+            },
+            Migration {
+                description: "Broken",
+                apply: broken,
+            },
+        ];
+
+        assert!(migrate(&db, &migrations, &dir).is_err());
+
+        // The first one stuck, and nothing from the broken one did
+        assert_eq!(version(&db).unwrap(), 1);
+        assert!(!db
+            .prepare("SELECT 1 FROM sqlite_master WHERE name = 'tags'")
+            .unwrap()
+            .exists([])
+            .unwrap());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // The app's own schema, minus the logging `add_missing_columns` does
+    fn app_schema() -> rusqlite::Connection {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch(crate::DB_SETUP_STATEMENTS).unwrap();
+        for (table, column, definition) in crate::DB_COLUMN_ADDITIONS {
+            let exists = db
+                .prepare(&format!(
+                    "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+                    table
+                ))
+                .unwrap()
+                .exists([column])
+                .unwrap();
+            if exists {
+                continue;
+            }
+
+            db.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+                [],
+            )
+            .unwrap();
+        }
+
+        crate::cascade_message_deletes(&db).unwrap();
+        db.execute_batch("PRAGMA foreign_keys = ON;").unwrap();
+
+        db
+    }
+
+    fn count(db: &rusqlite::Connection, table: &str) -> i64 {
+        db.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cascades() {
+        let db = app_schema();
+        db.execute_batch(
+            "
+            INSERT INTO conversations (id, name, last_updated, date_created)
+            VALUES (1, 'Chat', CURRENT_TIMESTAMP, CURRENT_TIMESTAMP);
+            INSERT INTO branches (id, conversation_id, date_created) VALUES (1, 1, CURRENT_TIMESTAMP);
+            INSERT INTO messages (id, message_type_id, content, api_config_id, system_prompt, date_created)
+            VALUES (1, 2, 'hi', 1, '', CURRENT_TIMESTAMP), (2, 3, 'hello', 1, '', CURRENT_TIMESTAMP);
+            INSERT INTO paths (conversation_id, branch_id, message_id, sequence)
+            VALUES (1, 1, 1, 0), (1, 1, 2, 1);
+            INSERT INTO usage (message_id, input_tokens, output_tokens) VALUES (2, 10, 20);
+            INSERT INTO message_embeddings (message_id, filepath) VALUES (2, '/tmp/2');
+            ",
+        )
+        .unwrap();
+
+        // Everything hanging off a message goes with it
+        db.execute("DELETE FROM messages WHERE id = 2", []).unwrap();
+        assert_eq!(count(&db, "paths"), 1);
+        assert_eq!(count(&db, "usage"), 0);
+        assert_eq!(count(&db, "message_embeddings"), 0);
+
+        // Same for conversations, except the messages themselves, which branches can share
+        db.execute("DELETE FROM conversations WHERE id = 1", [])
+            .unwrap();
+        assert_eq!(count(&db, "paths"), 0);
+        assert_eq!(count(&db, "branches"), 0);
+        assert_eq!(count(&db, "messages"), 1);
+
+        // Nothing can point at a conversation that isn't there
+        assert!(db
+            .execute(
+                "INSERT INTO paths (conversation_id, message_id, sequence) VALUES (1, 1, 0)",
+                [],
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use rusqlite::params;
+
+use crate::network;
+use crate::queue;
+use crate::types::*;
+
+// An optional pass over user messages before they're sent, for shared or workplace setups
+//
+// - `openai`: OpenAI's moderation endpoint, with the configured OpenAI key
+// - `local`: a Llama Guard-style classifier, which answers `safe` or `unsafe` followed by the
+//   codes of the categories it found--`llama-guard3` on the local server unless
+//   `WILLIAM_MODERATION_MODEL` names another (as `provider/model`)
+//
+// Every checked message is annotated with the result, flagged or not
+// With `moderationBlock` on, flagged messages aren't sent at all
+
+const OPENAI_MODERATION_URL: &str = "https://api.openai.com/v1/moderations";
+const OPENAI_MODERATION_MODEL: &str = "omni-moderation-latest";
+const LOCAL_MODERATION_MODEL: &str = "llama-guard3";
+
+// Llama Guard 3's hazard categories
+const LLAMA_GUARD_CATEGORIES: [(&str, &str); 14] = [
+    ("S1", "violent crimes"),
+    ("S2", "non-violent crimes"),
+    ("S3", "sex-related crimes"),
+    ("S4", "child sexual exploitation"),
+    ("S5", "defamation"),
+    ("S6", "specialized advice"),
+    ("S7", "privacy"),
+    ("S8", "intellectual property"),
+    ("S9", "indiscriminate weapons"),
+    ("S10", "hate"),
+    ("S11", "suicide & self-harm"),
+    ("S12", "sexual content"),
+    ("S13", "elections"),
+    ("S14", "code interpreter abuse"),
+];
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+// `None` when moderation is off
+pub async fn check(content: &str) -> Result<Option<Moderation>, Box<dyn std::error::Error>> {
+    let provider = ModerationProvider::from_env();
+    let mut span = crate::spans::span("moderation.check");
+    span.attribute("provider", provider.to_str());
+
+    let moderation = match provider {
+        ModerationProvider::Off => return Ok(None),
+        ModerationProvider::OpenAI => openai(content).await?,
+        ModerationProvider::Local => local(content).await?,
+    };
+
+    Ok(Some(moderation))
+}
+
+async fn openai(content: &str) -> Result<Moderation, Box<dyn std::error::Error>> {
+    let key = std::env::var("OPENAI_API_KEY").unwrap_or_default();
+    if key.is_empty() {
+        return Err("moderation with OpenAI needs an OpenAI API key".into());
+    }
+
+    let response = network::client("openai")
+        .post(OPENAI_MODERATION_URL)
+        .bearer_auth(key)
+        .json(&serde_json::json!({
+            "model": OPENAI_MODERATION_MODEL,
+            "input": content,
+        }))
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(format!("moderation request failed: {}", response.status()).into());
+    }
+
+    Ok(parse_openai(&response.json().await?)?)
+}
+
+fn parse_openai(response: &serde_json::Value) -> Result<Moderation, std::io::Error> {
+    let result = &response["results"][0];
+    let flagged = result["flagged"]
+        .as_bool()
+        .ok_or_else(|| invalid(format!("unexpected moderation response: {}", response)))?;
+
+    let categories = match result["categories"].as_object() {
+        Some(categories) => categories
+            .iter()
+            .filter(|(_, v)| v.as_bool() == Some(true))
+            .map(|(k, _)| k.clone())
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Ok(Moderation {
+        flagged,
+        categories,
+        provider: ModerationProvider::OpenAI.to_str().to_string(),
+    })
+}
+
+async fn local(content: &str) -> Result<Moderation, Box<dyn std::error::Error>> {
+    let api = network::model_from_env("WILLIAM_MODERATION_MODEL")
+        .unwrap_or_else(|| API::Local(LOCAL_MODERATION_MODEL.to_string()));
+
+    // The classifier's own chat template holds its policy, so it only needs the message
+    let message = Message {
+        id: None,
+        message_type: MessageType::User,
+        content: content.to_string(),
+        api: api.clone(),
+        system_prompt: String::new(),
+        sequence: -1,
+        date_created: String::new(),
+        tool_calls: Vec::new(),
+        tool_call_id: None,
+        attachments: Vec::new(),
+        interrupted: false,
+        language: None,
+        citations: Vec::new(),
+        moderation: None,
+    };
+
+    let (response, _) =
+        network::prompt_deterministic(api, "", &vec![message], &[], queue::Priority::Interactive)
+            .await?;
+    Ok(parse_llama_guard(&response.content)?)
+}
+
+// `safe`, or `unsafe` with the category codes on the next line, e.g. `unsafe\nS1,S10`
+fn parse_llama_guard(response: &str) -> Result<Moderation, std::io::Error> {
+    let mut lines = response.trim().lines().map(|l| l.trim());
+    let flagged = match lines.next() {
+        Some("safe") => false,
+        Some("unsafe") => true,
+        _ => {
+            return Err(invalid(format!(
+                "unexpected classifier response: {}",
+                response
+            )))
+        }
+    };
+
+    let categories = lines
+        .flat_map(|l| l.split(','))
+        .map(|c| c.trim())
+        .filter(|c| !c.is_empty())
+        .map(|c| {
+            LLAMA_GUARD_CATEGORIES
+                .iter()
+                .find(|(code, _)| code.eq_ignore_ascii_case(c))
+                .map_or(c.to_string(), |(_, name)| name.to_string())
+        })
+        .collect();
+
+    Ok(Moderation {
+        flagged,
+        categories,
+        provider: ModerationProvider::Local.to_str().to_string(),
+    })
+}
+
+pub fn record(
+    message_id: i64,
+    moderation: &Moderation,
+    db: &rusqlite::Connection,
+) -> rusqlite::Result<()> {
+    db.execute(
+        "UPDATE messages SET moderation = ?2 WHERE id = ?1",
+        params![message_id, serde_json::to_string(moderation).unwrap()],
+    )?;
+
+    Ok(())
+}
+
+// `WILLIAM_MODERATION_BLOCK` is set from the user config, like the API keys
+pub fn block_flagged() -> bool {
+    std::env::var("WILLIAM_MODERATION_BLOCK").is_ok_and(|v| v == "true")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_openai() {
+        let response = serde_json::json!({
+            "id": "modr-1",
+            "model": "omni-moderation-latest",
+            "results": [{
+                "flagged": true,
+                "categories": {
+                    "harassment": true,
+                    "violence": false,
+                    "harassment/threatening": true,
+                },
+                "category_scores": { "harassment": 0.9 },
+            }],
+        });
+
+        let moderation = parse_openai(&response).unwrap();
+        assert!(moderation.flagged);
+        assert_eq!(
+            moderation.categories,
+            vec!["harassment", "harassment/threatening"]
+        );
+
+        assert!(parse_openai(&serde_json::json!({ "error": "bad key" })).is_err());
+    }
+
+    #[test]
+    fn test_parse_llama_guard() {
+        let safe = parse_llama_guard("safe").unwrap();
+        assert!(!safe.flagged);
+        assert!(safe.categories.is_empty());
+
+        let flagged = parse_llama_guard("\n\nunsafe\nS1, S10,S99").unwrap();
+        assert!(flagged.flagged);
+        assert_eq!(flagged.categories, vec!["violent crimes", "hate", "S99"]);
+
+        assert!(parse_llama_guard("I can't help with that").is_err());
+    }
+}
@@ -1,59 +1,275 @@
 use std::env;
-use std::io::BufRead;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use rand::Rng;
 
 use chamber_common::{error, info, Logger};
 
+use crate::queue;
+use crate::spans;
 use crate::types::*;
 
 // TODO: there needs to be some refactoring done here
 //       to accommodate the fact that model/system prompt metadata
 //       is bundled with the messages
 
-fn build_request(
-    client: &reqwest::blocking::Client,
-    params: &RequestParams,
-) -> reqwest::blocking::RequestBuilder {
-    let body = match params.provider.as_str() {
-        "openai" => serde_json::json!({
-            "model": params.model,
-            "messages": params.messages.iter()
-                .map(|message| {
-                    serde_json::json!({
-                        "role": message.message_type.to_string(),
-                        "content": message.content
-                    })
-                }).collect::<Vec<serde_json::Value>>(),
-            "stream": params.stream,
-        }),
-        "groq" => serde_json::json!({
+// (mime type, base64 data) of the message's images
+// Only images that were loaded for the request are included--see `attachments::load_images`
+fn message_images(message: &Message) -> Vec<(&str, &str)> {
+    message
+        .attachments
+        .iter()
+        .filter(|a| a.is_image())
+        .filter_map(|a| a.data.as_deref().map(|data| (a.mime_type.as_str(), data)))
+        .collect()
+}
+
+// OpenAI-style message serialization
+// Tool calls ride on the assistant message, and tool results are their own `tool` role
+fn openai_message(message: &Message) -> serde_json::Value {
+    let mut json = serde_json::json!({
+        "role": message.message_type.to_string(),
+        "content": message.content
+    });
+
+    // Images make the content a list of parts
+    let images = message_images(message);
+    if !images.is_empty() {
+        let mut parts = vec![serde_json::json!({
+            "type": "text",
+            "text": message.content,
+        })];
+
+        for (mime_type, data) in images {
+            parts.push(serde_json::json!({
+                "type": "image_url",
+                "image_url": {
+                    "url": format!("data:{};base64,{}", mime_type, data),
+                }
+            }));
+        }
+
+        json["content"] = serde_json::json!(parts);
+    }
+
+    if !message.tool_calls.is_empty() {
+        json["tool_calls"] = serde_json::json!(message
+            .tool_calls
+            .iter()
+            .map(|call| {
+                serde_json::json!({
+                    "id": call.id,
+                    "type": "function",
+                    "function": {
+                        "name": call.name,
+                        "arguments": call.arguments,
+                    }
+                })
+            })
+            .collect::<Vec<serde_json::Value>>());
+    }
+
+    if let Some(tool_call_id) = &message.tool_call_id {
+        json["tool_call_id"] = serde_json::json!(tool_call_id);
+    }
+
+    json
+}
+
+// Anthropic-style message serialization
+// Tool calls are `tool_use` content blocks on the assistant message,
+// and tool results are `tool_result` content blocks sent back as the user
+fn anthropic_message(message: &Message) -> serde_json::Value {
+    if let Some(tool_call_id) = &message.tool_call_id {
+        return serde_json::json!({
+            "role": "user",
+            "content": [{
+                "type": "tool_result",
+                "tool_use_id": tool_call_id,
+                "content": message.content,
+            }]
+        });
+    }
+
+    let images = message_images(message);
+    if message.tool_calls.is_empty() && images.is_empty() {
+        return serde_json::json!({
+            "role": message.message_type.to_string(),
+            "content": message.content
+        });
+    }
+
+    // Images go ahead of the text that refers to them
+    let mut content = Vec::new();
+    for (mime_type, data) in images {
+        content.push(serde_json::json!({
+            "type": "image",
+            "source": {
+                "type": "base64",
+                "media_type": mime_type,
+                "data": data,
+            }
+        }));
+    }
+
+    if !message.content.is_empty() {
+        content.push(serde_json::json!({
+            "type": "text",
+            "text": message.content,
+        }));
+    }
+
+    for call in message.tool_calls.iter() {
+        content.push(serde_json::json!({
+            "type": "tool_use",
+            "id": call.id,
+            "name": call.name,
+            "input": serde_json::from_str::<serde_json::Value>(&call.arguments)
+                .unwrap_or_else(|_| serde_json::json!({})),
+        }));
+    }
+
+    serde_json::json!({
+        "role": message.message_type.to_string(),
+        "content": content
+    })
+}
+
+// Timeouts for provider connections, in seconds, with 0 meaning no limit:
+// - `WILLIAM_CONNECT_TIMEOUT_SECS`: establishing the connection (TLS included)
+// - `WILLIAM_REQUEST_TIMEOUT_SECS`: the whole request, streamed body included,
+//   so it's generous by default--long responses can take minutes to finish streaming
+// - `WILLIAM_POOL_IDLE_SECS`: how long an unused connection is kept around for the next request
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientSettings {
+    pub connect_timeout: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    pub pool_idle_timeout: Option<Duration>,
+}
+
+fn parse_seconds(value: Option<&str>, default: u64) -> Option<Duration> {
+    let seconds = value
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(default);
+
+    if seconds == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(seconds))
+    }
+}
+
+impl ClientSettings {
+    pub fn from_env() -> Self {
+        let var = |name: &str| env::var(name).ok();
+
+        Self {
+            connect_timeout: parse_seconds(var("WILLIAM_CONNECT_TIMEOUT_SECS").as_deref(), 10),
+            request_timeout: parse_seconds(var("WILLIAM_REQUEST_TIMEOUT_SECS").as_deref(), 600),
+            pool_idle_timeout: parse_seconds(var("WILLIAM_POOL_IDLE_SECS").as_deref(), 90),
+        }
+    }
+
+    fn build(&self) -> reqwest::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .pool_max_idle_per_host(4)
+            .tcp_keepalive(Duration::from_secs(60));
+
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        if let Some(timeout) = self.request_timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        builder.build()
+    }
+}
+
+// One client per provider, built the first time it's needed
+//
+// Clients hold the connection pool (and with it, TLS sessions), so reusing them means
+// follow-up messages skip the handshakes that a fresh client would redo every time
+// Cloning one is cheap--clones share the pool
+static CLIENTS: std::sync::OnceLock<
+    std::sync::Mutex<std::collections::HashMap<String, reqwest::Client>>,
+> = std::sync::OnceLock::new();
+
+pub fn client(provider: &str) -> reqwest::Client {
+    let mut clients = CLIENTS
+        .get_or_init(|| std::sync::Mutex::new(std::collections::HashMap::new()))
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    if let Some(client) = clients.get(provider) {
+        return client.clone();
+    }
+
+    let client = match ClientSettings::from_env().build() {
+        Ok(c) => c,
+        Err(e) => {
+            error!(
+                "error building {} client: {}; falling back to the defaults",
+                provider, e
+            );
+            reqwest::Client::new()
+        }
+    };
+
+    clients.insert(provider.to_string(), client.clone());
+    client
+}
+
+fn build_request(client: &reqwest::Client, params: &RequestParams) -> reqwest::RequestBuilder {
+    let mut body = match params.provider.as_str() {
+        "openai" | "deepseek" => {
+            let mut body = serde_json::json!({
+                "model": params.model,
+                "messages": params.messages.iter()
+                    .map(openai_message)
+                    .collect::<Vec<serde_json::Value>>(),
+                "stream": params.stream,
+            });
+
+            // Usage is only sent at the end of a stream if it's asked for
+            if params.stream {
+                body["stream_options"] = serde_json::json!({ "include_usage": true });
+            }
+
+            body
+        }
+        "groq" | "together" | "fireworks" | "local" => serde_json::json!({
             "model": params.model,
             "messages": params.messages.iter()
-                .map(|message| {
-                    serde_json::json!({
-                        "role": message.message_type.to_string(),
-                        "content": message.content
-                    })
-                }).collect::<Vec<serde_json::Value>>(),
+                .map(openai_message)
+                .collect::<Vec<serde_json::Value>>(),
             "stream": params.stream,
         }),
         "anthropic" => serde_json::json!({
             "model": params.model,
-            "messages": params.messages.iter().map(|message| {
-                serde_json::json!({
-                    "role": message.message_type.to_string(),
-                    "content": message.content
-                })
-            }).collect::<Vec<serde_json::Value>>(),
+            "messages": params.messages.iter()
+                .map(anthropic_message)
+                .collect::<Vec<serde_json::Value>>(),
             "stream": params.stream,
-            "max_tokens": params.max_tokens.unwrap(),
+            "max_tokens": params.settings.max_tokens.or(params.max_tokens).unwrap(),
             "system": params.system_prompt.clone().unwrap(),
         }),
         "gemini" => serde_json::json!({
             "contents": params.messages.iter().map(|m| {
                 serde_json::json!({
-                    "parts": [{
-                        "text": m.content
-                    }],
+                    "parts": std::iter::once(serde_json::json!({ "text": m.content }))
+                        .chain(message_images(m).into_iter().map(|(mime_type, data)| {
+                            serde_json::json!({
+                                "inline_data": {
+                                    "mime_type": mime_type,
+                                    "data": data,
+                                }
+                            })
+                        }))
+                        .collect::<Vec<serde_json::Value>>(),
                     "role": match m.message_type {
                         MessageType::User => "user",
                         MessageType::Assistant => "model",
@@ -65,16 +281,84 @@ fn build_request(
                 "parts": [{
                     "text": params.system_prompt,
                 }]
+            },
+            "generationConfig": {
+                "temperature": params.settings.temperature,
+                "topP": params.settings.top_p,
+                "maxOutputTokens": params.settings.max_tokens,
             }
         }),
         _ => panic!("Invalid provider for request_body: {}", params.provider),
     };
 
-    let url = format!("https://{}:{}{}", params.host, params.port, params.path);
+    if !params.tools.is_empty() {
+        match params.provider.as_str() {
+            "openai" | "groq" | "deepseek" | "together" | "fireworks" | "local" => {
+                body["tools"] = serde_json::json!(params
+                    .tools
+                    .iter()
+                    .map(|tool| {
+                        serde_json::json!({
+                            "type": "function",
+                            "function": {
+                                "name": tool.name,
+                                "description": tool.description,
+                                "parameters": tool.parameters,
+                            }
+                        })
+                    })
+                    .collect::<Vec<serde_json::Value>>());
+            }
+            "anthropic" => {
+                body["tools"] = serde_json::json!(params
+                    .tools
+                    .iter()
+                    .map(|tool| {
+                        serde_json::json!({
+                            "name": tool.name,
+                            "description": tool.description,
+                            "input_schema": tool.parameters,
+                        })
+                    })
+                    .collect::<Vec<serde_json::Value>>());
+            }
+            // TODO: gemini function declarations
+            _ => {}
+        }
+    }
+
+    // Gemini's are set in `generationConfig` above
+    if params.provider != "gemini" {
+        let settings = &params.settings;
+
+        // The o1 models only run at their default temperature/top_p
+        if !params.model.starts_with("o1") {
+            if let Some(temperature) = settings.temperature {
+                body["temperature"] = serde_json::json!(temperature);
+            }
+
+            if let Some(top_p) = settings.top_p {
+                body["top_p"] = serde_json::json!(top_p);
+            }
+        }
+
+        // OpenAI has deprecated `max_tokens`, and the o1 models reject it outright
+        if let Some(max_tokens) = settings.max_tokens {
+            match params.provider.as_str() {
+                "openai" => body["max_completion_tokens"] = serde_json::json!(max_tokens),
+                _ => body["max_tokens"] = serde_json::json!(max_tokens),
+            }
+        }
+    }
+
+    let url = format!(
+        "{}://{}:{}{}",
+        params.scheme, params.host, params.port, params.path
+    );
     let mut request = client.post(url.clone()).json(&body);
 
     match params.provider.as_str() {
-        "openai" | "groq" => {
+        "openai" | "groq" | "deepseek" | "together" | "fireworks" => {
             request = request.header(
                 "Authorization",
                 format!("Bearer {}", params.authorization_token),
@@ -90,6 +374,8 @@ fn build_request(
                 .post(format!("{}?key={}", url, params.authorization_token))
                 .json(&body);
         }
+        // Local servers don't check keys
+        "local" => {}
         _ => panic!("Invalid provider: {}", params.provider),
     }
 
@@ -105,10 +391,11 @@ fn get_openai_request_params(
     let (provider, model) = api.to_strings();
     RequestParams {
         provider,
+        scheme: "https".to_string(),
         host: "api.openai.com".to_string(),
         path: "/v1/chat/completions".to_string(),
         port: 443,
-        messages: if model.contains("o1") {
+        messages: if !api.capabilities().system_prompt {
             vec![]
         } else {
             vec![Message {
@@ -119,6 +406,13 @@ fn get_openai_request_params(
                 system_prompt,
                 sequence: -1,
                 date_created: String::new(),
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                attachments: Vec::new(),
+                interrupted: false,
+                language: None,
+                citations: Vec::new(),
+                moderation: None,
             }]
         }
         .iter()
@@ -131,6 +425,8 @@ fn get_openai_request_params(
             .expect("OPENAI_API_KEY environment variable not set"),
         max_tokens: None,
         system_prompt: None,
+        tools: Vec::new(),
+        settings: GenerationSettings::default(),
     }
 }
 
@@ -144,6 +440,7 @@ fn get_groq_request_params(
     let (provider, model) = api.to_strings();
     RequestParams {
         provider,
+        scheme: "https".to_string(),
         host: "api.groq.com".to_string(),
         path: "/openai/v1/chat/completions".to_string(),
         port: 443,
@@ -151,10 +448,17 @@ fn get_groq_request_params(
             id: None,
             message_type: MessageType::System,
             content: system_prompt.clone(),
-            api: API::Groq(GroqModel::LLaMA70B),
+            api,
             system_prompt,
             sequence: -1,
             date_created: String::new(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            attachments: Vec::new(),
+            interrupted: false,
+            language: None,
+            citations: Vec::new(),
+            moderation: None,
         }]
         .iter()
         .chain(chat_history.iter())
@@ -166,9 +470,336 @@ fn get_groq_request_params(
             .expect("GRQO_API_KEY environment variable not set"),
         max_tokens: None,
         system_prompt: None,
+        tools: Vec::new(),
+        settings: GenerationSettings::default(),
+    }
+}
+
+// DeepSeek's API is OpenAI-compatible
+fn get_deepseek_request_params(
+    system_prompt: String,
+    api: API,
+    chat_history: &[Message],
+    stream: bool,
+) -> RequestParams {
+    let (provider, model) = api.to_strings();
+    RequestParams {
+        provider,
+        scheme: "https".to_string(),
+        host: "api.deepseek.com".to_string(),
+        path: "/chat/completions".to_string(),
+        port: 443,
+        messages: [Message {
+            id: None,
+            message_type: MessageType::System,
+            content: system_prompt.clone(),
+            api,
+            system_prompt,
+            sequence: -1,
+            date_created: String::new(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            attachments: Vec::new(),
+            interrupted: false,
+            language: None,
+            citations: Vec::new(),
+            moderation: None,
+        }]
+        .iter()
+        .chain(chat_history.iter())
+        .cloned()
+        // The reasoner rejects requests that include its own reasoning
+        .map(|mut m| {
+            if m.message_type == MessageType::Assistant {
+                m.content = strip_reasoning(&m.content);
+            }
+
+            m
+        })
+        .collect::<Vec<Message>>(),
+        model,
+        stream,
+        authorization_token: env::var("DEEPSEEK_API_KEY")
+            .expect("DEEPSEEK_API_KEY environment variable not set"),
+        max_tokens: None,
+        system_prompt: None,
+        tools: Vec::new(),
+        settings: GenerationSettings::default(),
+    }
+}
+
+// Together + Fireworks both serve open models behind OpenAI-compatible endpoints
+fn get_hosted_request_params(
+    system_prompt: String,
+    api: API,
+    chat_history: &[Message],
+    stream: bool,
+) -> RequestParams {
+    let (provider, model) = api.to_strings();
+    let (host, path, key_var) = match api {
+        API::Fireworks(_) => (
+            "api.fireworks.ai",
+            "/inference/v1/chat/completions",
+            "FIREWORKS_API_KEY",
+        ),
+        _ => (
+            "api.together.xyz",
+            "/v1/chat/completions",
+            "TOGETHER_API_KEY",
+        ),
+    };
+
+    RequestParams {
+        provider,
+        scheme: "https".to_string(),
+        host: host.to_string(),
+        path: path.to_string(),
+        port: 443,
+        messages: [Message {
+            id: None,
+            message_type: MessageType::System,
+            content: system_prompt.clone(),
+            api,
+            system_prompt,
+            sequence: -1,
+            date_created: String::new(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            attachments: Vec::new(),
+            interrupted: false,
+            language: None,
+            citations: Vec::new(),
+            moderation: None,
+        }]
+        .iter()
+        .chain(chat_history.iter())
+        .cloned()
+        .collect::<Vec<Message>>(),
+        model,
+        stream,
+        authorization_token: env::var(key_var)
+            .unwrap_or_else(|_| panic!("{} environment variable not set", key_var)),
+        max_tokens: None,
+        system_prompt: None,
+        tools: Vec::new(),
+        settings: GenerationSettings::default(),
     }
 }
 
+// A model named in `var` as `provider/model`, for the background tasks that can be pointed elsewhere
+pub fn model_from_env(var: &str) -> Option<API> {
+    let model = env::var(var).ok()?;
+    let (provider, name) = model.split_once('/')?;
+    API::from_strings(provider, name).ok()
+}
+
+// Base URL of the local server, from `WILLIAM_LOCAL_ENDPOINT`
+// Servers are usually given as their OpenAI-style base (e.g. `http://localhost:11434/v1`),
+// so a trailing `/v1` is dropped to keep the paths below from doubling up
+fn local_endpoint() -> Result<reqwest::Url, std::io::Error> {
+    let endpoint = env::var("WILLIAM_LOCAL_ENDPOINT").unwrap_or_default();
+    let endpoint = endpoint.trim().trim_end_matches('/');
+    if endpoint.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "No local endpoint configured",
+        ));
+    }
+
+    let endpoint = endpoint.strip_suffix("/v1").unwrap_or(endpoint);
+    reqwest::Url::parse(endpoint).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid local endpoint {}: {}", endpoint, e),
+        )
+    })
+}
+
+// Ollama, LM Studio, vLLM, etc. all serve the OpenAI chat completions API
+fn get_local_request_params(
+    system_prompt: String,
+    api: API,
+    chat_history: &[Message],
+    stream: bool,
+) -> RequestParams {
+    let (provider, model) = api.to_strings();
+    let endpoint = local_endpoint().unwrap_or_else(|e| panic!("{}", e));
+
+    RequestParams {
+        provider,
+        scheme: endpoint.scheme().to_string(),
+        host: endpoint.host_str().unwrap_or("localhost").to_string(),
+        path: format!(
+            "{}/v1/chat/completions",
+            endpoint.path().trim_end_matches('/')
+        ),
+        port: endpoint.port_or_known_default().unwrap_or(80),
+        messages: [Message {
+            id: None,
+            message_type: MessageType::System,
+            content: system_prompt.clone(),
+            api,
+            system_prompt,
+            sequence: -1,
+            date_created: String::new(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            attachments: Vec::new(),
+            interrupted: false,
+            language: None,
+            citations: Vec::new(),
+            moderation: None,
+        }]
+        .iter()
+        .chain(chat_history.iter())
+        .cloned()
+        .collect::<Vec<Message>>(),
+        model,
+        stream,
+        authorization_token: String::new(),
+        max_tokens: None,
+        system_prompt: None,
+        tools: Vec::new(),
+        settings: GenerationSettings::default(),
+    }
+}
+
+// Each provider's model listing endpoint, authorized with the stored key
+fn models_request(
+    client: &reqwest::Client,
+    provider: &str,
+) -> Result<reqwest::RequestBuilder, std::io::Error> {
+    let bearer = |url: &str, key_var: &str| -> Result<_, std::io::Error> {
+        let key = env::var(key_var).unwrap_or_default();
+        if key.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} environment variable not set", key_var),
+            ));
+        }
+
+        Ok(client
+            .get(url)
+            .header("Authorization", format!("Bearer {}", key)))
+    };
+
+    match provider {
+        "openai" => bearer("https://api.openai.com/v1/models", "OPENAI_API_KEY"),
+        "groq" => bearer("https://api.groq.com/openai/v1/models", "GROQ_API_KEY"),
+        "deepseek" => bearer("https://api.deepseek.com/models", "DEEPSEEK_API_KEY"),
+        "together" => bearer("https://api.together.xyz/v1/models", "TOGETHER_API_KEY"),
+        "fireworks" => bearer(
+            "https://api.fireworks.ai/inference/v1/models",
+            "FIREWORKS_API_KEY",
+        ),
+        "anthropic" => {
+            let key = env::var("ANTHROPIC_API_KEY").unwrap_or_default();
+            if key.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "ANTHROPIC_API_KEY environment variable not set",
+                ));
+            }
+
+            Ok(client
+                .get("https://api.anthropic.com/v1/models?limit=1000")
+                .header("x-api-key", key)
+                .header("anthropic-version", "2023-06-01"))
+        }
+        "local" => {
+            let endpoint = local_endpoint()?;
+            Ok(client.get(format!(
+                "{}/v1/models",
+                endpoint.as_str().trim_end_matches('/')
+            )))
+        }
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("No model listing for provider {}", provider),
+        )),
+    }
+}
+
+/// Models the provider says are available to the configured key (or server, for `local`)
+///
+/// Models that can't be used for chat are left out
+pub async fn list_models(provider: &str) -> Result<Vec<API>, std::io::Error> {
+    let mut span = spans::span("provider.models");
+    span.attribute("provider", provider);
+
+    let client = client(provider);
+    let response = models_request(&client, provider)?
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    if !status.is_success() {
+        return Err(std::io::Error::other(parse_provider_error(
+            provider,
+            status.as_u16(),
+            &body,
+        )));
+    }
+
+    read_model_list(provider, &body)
+}
+
+// OpenAI lists everything the key can touch--embeddings, audio, images, etc.
+fn is_openai_chat_model(id: &str) -> bool {
+    let chat = ["gpt-", "chatgpt-", "o1", "o3", "o4"]
+        .iter()
+        .any(|prefix| id.starts_with(prefix));
+    let other = [
+        "audio",
+        "realtime",
+        "transcribe",
+        "tts",
+        "image",
+        "search",
+        "instruct",
+    ]
+    .iter()
+    .any(|kind| id.contains(kind));
+
+    chat && !other
+}
+
+// `{"object": "list", "data": [{"id": <model>, ...}, ...]}`, or just the array for Together
+//
+// Models outside a provider's fixed list (e.g., new Groq models) are dropped
+fn read_model_list(provider: &str, body: &str) -> Result<Vec<API>, std::io::Error> {
+    let json: serde_json::Value = serde_json::from_str(body)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    let models = match json.as_array() {
+        Some(models) => models,
+        None => match json["data"].as_array() {
+            Some(models) => models,
+            None => return Ok(Vec::new()),
+        },
+    };
+
+    Ok(models
+        .iter()
+        .filter(|m| {
+            !matches!(
+                m["type"].as_str(),
+                Some("embedding" | "image" | "audio" | "moderation" | "rerank")
+            )
+        })
+        .filter_map(|m| m["id"].as_str())
+        .filter(|id| provider != "openai" || is_openai_chat_model(id))
+        .filter_map(|id| API::from_strings(provider, id).ok())
+        .collect())
+}
+
 fn get_anthropic_request_params(
     system_prompt: String,
     api: API,
@@ -178,6 +809,7 @@ fn get_anthropic_request_params(
     let (provider, model) = api.to_strings();
     RequestParams {
         provider,
+        scheme: "https".to_string(),
         host: "api.anthropic.com".to_string(),
         path: "/v1/messages".to_string(),
         port: 443,
@@ -188,6 +820,8 @@ fn get_anthropic_request_params(
             .expect("ANTHROPIC_API_KEY environment variable not set"),
         max_tokens: Some(4096),
         system_prompt: Some(system_prompt),
+        tools: Vec::new(),
+        settings: GenerationSettings::default(),
     }
 }
 
@@ -201,6 +835,7 @@ fn get_gemini_request_params(
     let (provider, model) = api.to_strings();
     RequestParams {
         provider,
+        scheme: "https".to_string(),
         host: "generativelanguage.googleapis.com".to_string(),
         path: "/v1beta/models/gemini-1.5-flash-latest:generateContent".to_string(),
         port: 443,
@@ -211,15 +846,44 @@ fn get_gemini_request_params(
             .expect("GEMINI_API_KEY environment variable not set"),
         max_tokens: Some(4096),
         system_prompt: Some(system_prompt),
+        tools: Vec::new(),
+        settings: GenerationSettings::default(),
     }
 }
 
+// System messages from the conversation itself (as opposed to the system prompt built for the request)
+// go wherever each provider will take them:
+// - OpenAI-compatible APIs take them in place as `system`, or `developer` for OpenAI's own models
+// - Anthropic only takes a system prompt up front, as do models without one at all,
+//   so they're sent as the user instead, marked as coming from the system
+fn place_system_messages(api: &API, chat_history: &[Message]) -> Vec<Message> {
+    let in_place = api.capabilities().system_prompt && !matches!(api, API::Anthropic(_));
+
+    chat_history
+        .iter()
+        .cloned()
+        .map(|mut m| {
+            if m.message_type == MessageType::System {
+                if !in_place {
+                    m.message_type = MessageType::User;
+                    m.content = format!("<systemMessage>{}</systemMessage>", m.content);
+                } else if matches!(api, API::OpenAI(_)) {
+                    m.message_type = MessageType::Developer;
+                }
+            }
+
+            m
+        })
+        .collect()
+}
+
 fn get_params(
     system_prompt: &str,
     api: API,
     chat_history: &Vec<Message>,
     stream: bool,
 ) -> RequestParams {
+    let chat_history = &place_system_messages(&api, chat_history);
     match api {
         API::Anthropic(_) => get_anthropic_request_params(
             system_prompt.to_string(),
@@ -233,11 +897,20 @@ fn get_params(
         API::Groq(_) => {
             get_groq_request_params(system_prompt.to_string(), api.clone(), chat_history, stream)
         }
+        API::DeepSeek(_) => {
+            get_deepseek_request_params(system_prompt.to_string(), api, chat_history, stream)
+        }
+        API::Together(_) | API::Fireworks(_) => {
+            get_hosted_request_params(system_prompt.to_string(), api, chat_history, stream)
+        }
+        API::Local(_) => {
+            get_local_request_params(system_prompt.to_string(), api, chat_history, stream)
+        }
     }
 }
 
-fn send_delta(tx: &std::sync::mpsc::Sender<String>, delta: String) {
-    match tx.send(delta.clone()) {
+fn send_delta(tx: &tokio::sync::mpsc::UnboundedSender<StreamEvent>, delta: String) {
+    match tx.send(StreamEvent::Delta(delta)) {
         Ok(_) => {}
         Err(e) => {
             error!("error sending transmission error string: {}", e);
@@ -245,6 +918,29 @@ fn send_delta(tx: &std::sync::mpsc::Sender<String>, delta: String) {
     };
 }
 
+// Reasoning is kept in the message content between these tags,
+// so it's shown + saved alongside the response
+const REASONING_START: &str = "<think>";
+const REASONING_END: &str = "</think>";
+
+// Drops any reasoning blocks from a message
+pub fn strip_reasoning(content: &str) -> String {
+    let mut stripped = String::new();
+    let mut rest = content;
+    while let Some(start) = rest.find(REASONING_START) {
+        stripped.push_str(&rest[..start]);
+        rest = match rest[start..].find(REASONING_END) {
+            Some(end) => &rest[start + end + REASONING_END.len()..],
+            // Unterminated, e.g. a cancelled completion
+            None => "",
+        };
+    }
+
+    stripped.push_str(rest);
+
+    stripped.trim_start().to_string()
+}
+
 fn unescape(content: &str) -> String {
     content
         .replace("\\n", "\n")
@@ -254,18 +950,155 @@ fn unescape(content: &str) -> String {
         .replace("\\\\", "\\")
 }
 
+// Token counts reported by the provider, if the response has them
+//
+// - OpenAI: `usage` on non-streamed responses and the last chunk of a stream
+// - Groq: same as OpenAI, except streams report it in `x_groq.usage`
+// - Anthropic: `usage` on non-streamed responses; streams give the input tokens in `message_start`
+//   and the running output tokens in `message_delta`
+fn read_usage(api: &API, response_json: &serde_json::Value) -> Option<TokenUsage> {
+    let (usage, input_key, output_key) = match api {
+        API::OpenAI(_)
+        | API::Groq(_)
+        | API::DeepSeek(_)
+        | API::Together(_)
+        | API::Fireworks(_)
+        | API::Local(_) => {
+            let usage = if response_json["usage"].is_object() {
+                &response_json["usage"]
+            } else {
+                &response_json["x_groq"]["usage"]
+            };
+
+            (usage, "prompt_tokens", "completion_tokens")
+        }
+        API::Anthropic(_) => {
+            let usage = if response_json["message"]["usage"].is_object() {
+                &response_json["message"]["usage"]
+            } else {
+                &response_json["usage"]
+            };
+
+            (usage, "input_tokens", "output_tokens")
+        }
+    };
+
+    if !usage.is_object() {
+        return None;
+    }
+
+    Some(TokenUsage {
+        input_tokens: usage[input_key].as_u64().unwrap_or(0) as usize,
+        output_tokens: usage[output_key].as_u64().unwrap_or(0) as usize,
+        estimator: None,
+    })
+}
+
+// Times a streamed response from when the request goes out
+// The first token is the first delta carrying anything--text, reasoning, or a tool call
+pub struct StreamTimer {
+    started: std::time::Instant,
+    first_token: Option<Duration>,
+}
+
+impl StreamTimer {
+    pub fn start() -> Self {
+        Self {
+            started: std::time::Instant::now(),
+            first_token: None,
+        }
+    }
+
+    fn first_token(&mut self) {
+        if self.first_token.is_none() {
+            self.first_token = Some(self.started.elapsed());
+        }
+    }
+
+    pub fn finish(&self) -> ResponseTiming {
+        ResponseTiming {
+            time_to_first_token_ms: self.first_token.map(|d| d.as_millis() as u64),
+            generation_ms: self.started.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+// A streamed response body, read a line at a time as it comes in
+struct Lines {
+    response: reqwest::Response,
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+impl Lines {
+    fn new(response: reqwest::Response) -> Self {
+        Self {
+            response,
+            buffer: Vec::new(),
+            done: false,
+        }
+    }
+
+    // Without the line ending, like `BufRead::lines`
+    async fn next(&mut self) -> Option<Result<String, std::io::Error>> {
+        loop {
+            let line = match self.buffer.iter().position(|b| *b == b'\n') {
+                Some(end) => {
+                    let mut line = self.buffer.drain(..=end).collect::<Vec<u8>>();
+                    line.pop();
+                    if line.last() == Some(&b'\r') {
+                        line.pop();
+                    }
+
+                    line
+                }
+                None if self.done && !self.buffer.is_empty() => std::mem::take(&mut self.buffer),
+                None if self.done => return None,
+                None => {
+                    match self.response.chunk().await {
+                        Ok(Some(chunk)) => self.buffer.extend_from_slice(&chunk),
+                        Ok(None) => self.done = true,
+                        Err(e) => return Some(Err(std::io::Error::other(e.to_string()))),
+                    };
+
+                    continue;
+                }
+            };
+
+            return Some(
+                String::from_utf8(line)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+            );
+        }
+    }
+}
+
 // TODO: at some point i think the tokenizer will have to come down here
 //       as that's how we'll track usage metrics from streams
 
-fn process_openai_stream(
-    response: reqwest::blocking::Response,
-    tx: &std::sync::mpsc::Sender<String>,
-) -> Result<String, std::io::Error> {
+// Returns the full message text alongside any tool calls the model made,
+// and the usage the provider reported, if any
+async fn process_openai_stream(
+    api: &API,
+    response: reqwest::Response,
+    tx: &tokio::sync::mpsc::UnboundedSender<StreamEvent>,
+    cancel: &AtomicBool,
+    timer: &mut StreamTimer,
+) -> Result<(String, Vec<ToolCall>, Option<TokenUsage>), std::io::Error> {
     info!("processing openai stream");
-    let reader = std::io::BufReader::new(response);
+    let mut lines = Lines::new(response);
     let mut full_message = String::new();
+    let mut tool_calls: Vec<ToolCall> = Vec::new();
+    let mut usage: Option<TokenUsage> = None;
+    // Whether we're in the middle of streaming reasoning content
+    let mut reasoning = false;
+
+    while let Some(line) = lines.next().await {
+        if cancel.load(Ordering::SeqCst) {
+            info!("openai stream cancelled");
+            break;
+        }
 
-    for line in reader.lines() {
         let line = line?;
         if !line.starts_with("data: ") {
             continue;
@@ -285,28 +1118,107 @@ fn process_openai_stream(
             }
         };
 
+        if let Some(e) = read_stream_error(api, &response_json) {
+            return Err(std::io::Error::other(e));
+        }
+
+        if let Some(u) = read_usage(api, &response_json) {
+            usage = Some(u);
+        }
+
+        // DeepSeek's reasoner streams its chain of thought in a separate field ahead of the answer
+        if let Some(thought) = response_json["choices"][0]["delta"]["reasoning_content"].as_str() {
+            if !thought.is_empty() {
+                timer.first_token();
+                if !reasoning {
+                    reasoning = true;
+                    send_delta(tx, REASONING_START.to_string());
+                    full_message.push_str(REASONING_START);
+                }
+
+                send_delta(tx, thought.to_string());
+                full_message.push_str(thought);
+            }
+        }
+
         let mut delta = unescape(&response_json["choices"][0]["delta"]["content"].to_string());
+        if reasoning && delta != "null" && delta != "\"\"" {
+            reasoning = false;
+            let end = format!("{}\n\n", REASONING_END);
+            send_delta(tx, end.clone());
+            full_message.push_str(&end);
+        }
+
         if delta != "null" {
             delta = delta[1..delta.len() - 1].to_string();
+            if !delta.is_empty() {
+                timer.first_token();
+            }
+
             send_delta(tx, delta.clone());
 
             full_message.push_str(&delta);
         }
+
+        // Tool calls are streamed in pieces keyed by their index:
+        // the first piece carries the ID + name, the rest are fragments of the JSON arguments
+        if let Some(deltas) = response_json["choices"][0]["delta"]["tool_calls"].as_array() {
+            timer.first_token();
+            for call_delta in deltas {
+                let index = call_delta["index"].as_u64().unwrap_or(0) as usize;
+                while tool_calls.len() <= index {
+                    tool_calls.push(ToolCall {
+                        id: String::new(),
+                        name: String::new(),
+                        arguments: String::new(),
+                    });
+                }
+
+                let call = &mut tool_calls[index];
+                if let Some(id) = call_delta["id"].as_str() {
+                    call.id = id.to_string();
+                }
+
+                if let Some(name) = call_delta["function"]["name"].as_str() {
+                    call.name.push_str(name);
+                }
+
+                if let Some(arguments) = call_delta["function"]["arguments"].as_str() {
+                    call.arguments.push_str(arguments);
+                }
+            }
+        }
+    }
+
+    if reasoning {
+        send_delta(tx, REASONING_END.to_string());
+        full_message.push_str(REASONING_END);
     }
 
-    // TODO: actually calculate the usage, obviously
-    Ok(full_message)
+    Ok((full_message, tool_calls, usage))
 }
 
-fn process_anthropic_stream(
-    response: reqwest::blocking::Response,
-    tx: &std::sync::mpsc::Sender<String>,
-) -> Result<String, std::io::Error> {
+// Returns the full message text alongside any tool calls the model made,
+// and the usage the provider reported, if any
+async fn process_anthropic_stream(
+    api: &API,
+    response: reqwest::Response,
+    tx: &tokio::sync::mpsc::UnboundedSender<StreamEvent>,
+    cancel: &AtomicBool,
+    timer: &mut StreamTimer,
+) -> Result<(String, Vec<ToolCall>, Option<TokenUsage>), std::io::Error> {
     info!("processing anthropic stream");
-    let reader = std::io::BufReader::new(response);
+    let mut lines = Lines::new(response);
     let mut full_message = String::new();
+    let mut tool_calls: Vec<ToolCall> = Vec::new();
+    let mut usage: Option<TokenUsage> = None;
+
+    while let Some(line) = lines.next().await {
+        if cancel.load(Ordering::SeqCst) {
+            info!("anthropic stream cancelled");
+            break;
+        }
 
-    for line in reader.lines() {
         let line = line?;
 
         if line.starts_with("event: message_stop") {
@@ -331,6 +1243,56 @@ fn process_anthropic_stream(
             }
         };
 
+        if let Some(e) = read_stream_error(api, &response_json) {
+            return Err(std::io::Error::other(e));
+        }
+
+        // The input tokens come with the start of the message,
+        // and the output tokens are updated as it goes
+        if let Some(u) = read_usage(api, &response_json) {
+            usage = match (usage, response_json["type"].as_str()) {
+                (Some(existing), Some("message_delta")) => Some(TokenUsage {
+                    input_tokens: existing.input_tokens,
+                    output_tokens: u.output_tokens,
+                    estimator: None,
+                }),
+                _ => Some(u),
+            };
+        }
+
+        // `tool_use` blocks open with the ID + name,
+        // and their JSON input follows in `input_json_delta` fragments
+        if response_json["type"] == "content_block_start"
+            && response_json["content_block"]["type"] == "tool_use"
+        {
+            timer.first_token();
+            tool_calls.push(ToolCall {
+                id: response_json["content_block"]["id"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                name: response_json["content_block"]["name"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                arguments: String::new(),
+            });
+
+            continue;
+        }
+
+        if response_json["delta"]["type"] == "input_json_delta" {
+            if let Some(call) = tool_calls.last_mut() {
+                call.arguments.push_str(
+                    response_json["delta"]["partial_json"]
+                        .as_str()
+                        .unwrap_or(""),
+                );
+            }
+
+            continue;
+        }
+
         let mut delta = "null".to_string();
         if response_json["type"] == "content_block_delta" {
             delta = unescape(&response_json["delta"]["text"].to_string());
@@ -339,26 +1301,345 @@ fn process_anthropic_stream(
         }
 
         if delta != "null" {
+            if !delta.is_empty() {
+                timer.first_token();
+            }
+
             send_delta(tx, delta.clone());
             full_message.push_str(&delta);
         }
     }
 
-    Ok(full_message)
+    Ok((full_message, tool_calls, usage))
 }
 
 // TODO: error handling
 //
 /// JSON response handler for `prompt`
+/// Returns the message content alongside any tool calls in the response
 /// Ideally I think there should be more done here,
 /// maybe something like getting usage metrics out of this
-fn read_json_response(api: &API, response_json: &serde_json::Value) -> String {
+fn read_json_response(api: &API, response_json: &serde_json::Value) -> (String, Vec<ToolCall>) {
     match api {
-        API::Anthropic(_) => response_json["choices"][0]["message"]["content"].to_string(),
-        API::OpenAI(_) => response_json["choices"][0]["message"]["content"].to_string(),
-        API::Groq(_) => response_json["content"][0]["text"].to_string(),
-        // TODO: gemini
-        //_ => response_json["candidates"][0]["content"]["parts"][0]["text"].to_string(),
+        API::Anthropic(_) => {
+            let blocks = response_json["content"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default();
+
+            let content = blocks
+                .iter()
+                .filter(|b| b["type"] == "text")
+                .map(|b| b["text"].as_str().unwrap_or_default())
+                .collect::<Vec<&str>>()
+                .join("");
+
+            let tool_calls = blocks
+                .iter()
+                .filter(|b| b["type"] == "tool_use")
+                .map(|b| ToolCall {
+                    id: b["id"].as_str().unwrap_or_default().to_string(),
+                    name: b["name"].as_str().unwrap_or_default().to_string(),
+                    arguments: b["input"].to_string(),
+                })
+                .collect();
+
+            (content, tool_calls)
+        }
+        API::OpenAI(_)
+        | API::Groq(_)
+        | API::DeepSeek(_)
+        | API::Together(_)
+        | API::Fireworks(_)
+        | API::Local(_) => {
+            let message = &response_json["choices"][0]["message"];
+            let mut content = message["content"].as_str().unwrap_or_default().to_string();
+            if let Some(thought) = message["reasoning_content"].as_str() {
+                content = format!(
+                    "{}{}{}\n\n{}",
+                    REASONING_START, thought, REASONING_END, content
+                );
+            }
+
+            let tool_calls = message["tool_calls"]
+                .as_array()
+                .map(|calls| {
+                    calls
+                        .iter()
+                        .map(|c| ToolCall {
+                            id: c["id"].as_str().unwrap_or_default().to_string(),
+                            name: c["function"]["name"]
+                                .as_str()
+                                .unwrap_or_default()
+                                .to_string(),
+                            arguments: c["function"]["arguments"]
+                                .as_str()
+                                .unwrap_or_default()
+                                .to_string(),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            (content, tool_calls)
+        } // TODO: gemini
+          //_ => response_json["candidates"][0]["content"]["parts"][0]["text"].to_string(),
+    }
+}
+
+// How failed provider requests are retried
+// Connection failures, rate limits (429), and server errors (5xx) are retried with exponential
+// backoff + jitter--anything else (bad keys, bad requests, etc.) fails immediately
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // Including the first attempt
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    // `WILLIAM_MAX_RETRIES` is set from the user config, like the API keys
+    pub fn from_env() -> Self {
+        let retries = env::var("WILLIAM_MAX_RETRIES")
+            .ok()
+            .and_then(|r| r.parse::<u32>().ok())
+            .unwrap_or(3);
+
+        Self {
+            max_attempts: retries + 1,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+
+    // Exponential backoff with "equal jitter"--somewhere between half and all of the full delay
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let delay = std::cmp::min(exponential, self.max_delay);
+
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+// Only the delay-seconds form of `Retry-After` is handled--HTTP dates fall back to the backoff
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+fn is_retryable(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// OpenAI-style APIs (OpenAI, Groq, DeepSeek, Together, Fireworks) send
+// `{"error": {"message", "type", "code"}}`,
+// Anthropic sends `{"type": "error", "error": {"type", "message"}}`,
+// and Gemini sends `{"error": {"code", "message", "status"}}`
+//
+// The error code/type is preferred over the status when it says something more specific
+// (e.g., a 400 can mean the context is too long, or that the prompt was filtered)
+fn parse_provider_error(provider: &str, status: u16, body: &str) -> ProviderError {
+    let json: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+    let error = &json["error"];
+
+    let message = error["message"]
+        .as_str()
+        .or(error.as_str())
+        .unwrap_or(body)
+        .trim()
+        .to_string();
+
+    let codes = [&error["code"], &error["type"], &error["status"]]
+        .iter()
+        .filter_map(|c| c.as_str())
+        .collect::<Vec<&str>>();
+    let code = codes.join(" ").to_lowercase();
+
+    let lowercase_message = message.to_lowercase();
+    let category = if code.contains("context_length")
+        || lowercase_message.contains("context length")
+        || lowercase_message.contains("context window")
+        || lowercase_message.contains("prompt is too long")
+    {
+        ProviderErrorCategory::ContextLength
+    } else if code.contains("content_filter")
+        || code.contains("content_policy")
+        || lowercase_message.contains("content management policy")
+    {
+        ProviderErrorCategory::ContentFilter
+    } else if code.contains("invalid_api_key")
+        || code.contains("authentication")
+        || code.contains("permission")
+        || code.contains("unauthenticated")
+    {
+        ProviderErrorCategory::Auth
+    } else if code.contains("rate_limit")
+        || code.contains("insufficient_quota")
+        || code.contains("resource_exhausted")
+    {
+        ProviderErrorCategory::RateLimit
+    } else if code.contains("overloaded") || code.contains("server_error") || code == "api_error" {
+        ProviderErrorCategory::Server
+    } else {
+        match status {
+            401 | 403 => ProviderErrorCategory::Auth,
+            413 => ProviderErrorCategory::ContextLength,
+            429 => ProviderErrorCategory::RateLimit,
+            500..=599 => ProviderErrorCategory::Server,
+            _ => ProviderErrorCategory::Unknown,
+        }
+    };
+
+    ProviderError {
+        provider: provider.to_string(),
+        status,
+        code: codes.first().map(|c| c.to_string()),
+        category,
+        message,
+    }
+}
+
+// Errors can also come partway through a stream, as an event of their own,
+// which would otherwise just look like the stream ending early
+// OpenAI-style APIs send `{"error": {...}}`, and Anthropic sends an `error` event
+fn read_stream_error(api: &API, response_json: &serde_json::Value) -> Option<ProviderError> {
+    if !response_json["error"].is_object() && response_json["type"] != "error" {
+        return None;
+    }
+
+    let (provider, _) = api.to_strings();
+    Some(parse_provider_error(
+        &provider,
+        200,
+        &response_json.to_string(),
+    ))
+}
+
+// Waits on a request from synchronous code--request handlers, or anything else holding a DB lock
+//
+// Off the runtime's workers (e.g. in `spawn_blocking`) this just blocks,
+// and on one, the worker's other tasks are handed off first so they aren't held up
+// Threads outside the runtime altogether (e.g. the watch loop) get a runtime just for this
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => tokio::task::block_in_place(|| handle.block_on(future)),
+        Err(_) => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to create runtime")
+            .block_on(future),
+    }
+}
+
+// Keeps the `std::io::Error` from the network layer intact, `ProviderError` and all
+pub fn into_io_error(e: Box<dyn std::error::Error>) -> std::io::Error {
+    match e.downcast::<std::io::Error>() {
+        Ok(e) => *e,
+        Err(e) => std::io::Error::other(e.to_string()),
+    }
+}
+
+// Sends the request described by `params`, retrying according to `policy`
+// `on_retry` is called before each wait, and a set `cancel` flag stops any further attempts
+//
+// Each attempt waits on a slot from the completion queue, given up between attempts
+// The response comes with its slot, to be held for as long as the response is being read
+async fn send_with_retry(
+    client: &reqwest::Client,
+    params: &RequestParams,
+    policy: &RetryPolicy,
+    priority: queue::Priority,
+    cancel: &AtomicBool,
+    on_retry: &(dyn Fn(RetryStatus) + Sync),
+) -> Result<(reqwest::Response, queue::Slot<'static>), std::io::Error> {
+    info!(
+        "sending {} request for {} ({} messages)",
+        params.provider,
+        params.model,
+        params.messages.len()
+    );
+
+    let mut attempt = 1;
+    loop {
+        let slot = queue::global()
+            .acquire(&params.provider, priority, cancel)
+            .await?;
+        let (error, delay) = match build_request(client, params).send().await {
+            Ok(response) if response.status().is_success() => return Ok((response, slot)),
+            Ok(response) => {
+                let status = response.status();
+                let delay = retry_after(&response);
+                let error_body = response
+                    .text()
+                    .await
+                    .unwrap_or_else(|_| String::from("Could not read error response"));
+
+                let error = parse_provider_error(&params.provider, status.as_u16(), &error_body);
+                if !is_retryable(status) {
+                    return Err(std::io::Error::other(error));
+                }
+
+                (std::io::Error::other(error), delay)
+            }
+            Err(e) => (std::io::Error::other(e.to_string()), None),
+        };
+        drop(slot);
+
+        let delay = delay.unwrap_or_else(|| policy.backoff(attempt));
+
+        // Not worth waiting on a rate limit longer than we'd ever back off for
+        if attempt >= policy.max_attempts || delay > policy.max_delay {
+            error!(
+                "{} request failed after {} attempts: {}",
+                params.provider, attempt, error
+            );
+            return Err(error);
+        }
+
+        info!(
+            "{} request failed (attempt {}/{}), retrying in {}ms: {}",
+            params.provider,
+            attempt,
+            policy.max_attempts,
+            delay.as_millis(),
+            error
+        );
+
+        on_retry(RetryStatus {
+            attempt,
+            max_attempts: policy.max_attempts,
+            delay_ms: delay.as_millis() as u64,
+            error: error.to_string(),
+        });
+
+        // Sleeping in pieces so a cancellation doesn't have to wait out the whole delay
+        let start = std::time::Instant::now();
+        while start.elapsed() < delay {
+            if cancel.load(Ordering::SeqCst) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Interrupted,
+                    "Request cancelled while waiting to retry",
+                ));
+            }
+
+            tokio::time::sleep(std::cmp::min(
+                Duration::from_millis(50),
+                delay.saturating_sub(start.elapsed()),
+            ))
+            .await;
+        }
+
+        attempt += 1;
     }
 }
 
@@ -366,76 +1647,311 @@ fn read_json_response(api: &API, response_json: &serde_json::Value) -> String {
 //
 /// Function for streaming responses from the LLM.
 /// Asynchronous by default--relies on message channels.
-pub fn prompt_stream(
+///
+/// Text deltas are sent through the channel as they arrive,
+/// while tool calls are only available on the returned message once the stream finishes.
+/// The stream ends with either `Done` or `Error`
+#[allow(clippy::too_many_arguments)]
+pub async fn prompt_stream(
     api: API,
     chat_history: &Vec<Message>,
     system_prompt: &str,
-    tx: std::sync::mpsc::Sender<String>,
-) -> Result<Message, std::io::Error> {
-    let params = get_params(system_prompt, api.clone(), chat_history, true);
-    let client = reqwest::blocking::Client::new();
+    tools: &Vec<Tool>,
+    settings: GenerationSettings,
+    tx: tokio::sync::mpsc::UnboundedSender<StreamEvent>,
+    retry_tx: tokio::sync::mpsc::UnboundedSender<RetryStatus>,
+    cancel: &AtomicBool,
+) -> Result<(Message, Option<TokenUsage>, ResponseTiming), std::io::Error> {
+    let (provider, model) = api.to_strings();
+    let mut span = spans::span("provider.stream");
+    span.attribute("provider", provider);
+    span.attribute("model", model);
 
-    let request = build_request(&client, &params);
-    let response = build_request(&client, &params)
-        .send()
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    let result = stream_response(
+        api,
+        chat_history,
+        system_prompt,
+        tools,
+        settings,
+        &tx,
+        retry_tx,
+        cancel,
+    )
+    .await;
+
+    let end = match &result {
+        Ok((_, usage, _)) => StreamEvent::Done(usage.clone()),
+        Err(e) => StreamEvent::Error(e.to_string()),
+    };
 
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_body = response
-            .text()
-            .unwrap_or_else(|_| String::from("Could not read error response"));
+    match tx.send(end) {
+        Ok(_) => {}
+        Err(e) => {
+            error!("error sending end of stream: {}", e);
+        }
+    };
 
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, error_body));
-    }
+    result
+}
 
-    let content = match api {
-        API::Anthropic(_) => process_anthropic_stream(response, &tx),
-        API::OpenAI(_) => process_openai_stream(response, &tx),
-        API::Groq(_) => process_openai_stream(response, &tx),
+#[allow(clippy::too_many_arguments)]
+async fn stream_response(
+    api: API,
+    chat_history: &Vec<Message>,
+    system_prompt: &str,
+    tools: &Vec<Tool>,
+    settings: GenerationSettings,
+    tx: &tokio::sync::mpsc::UnboundedSender<StreamEvent>,
+    retry_tx: tokio::sync::mpsc::UnboundedSender<RetryStatus>,
+    cancel: &AtomicBool,
+) -> Result<(Message, Option<TokenUsage>, ResponseTiming), std::io::Error> {
+    let mut params = get_params(system_prompt, api.clone(), chat_history, true);
+    params.tools = tools.clone();
+    params.settings = settings;
+    let client = client(&params.provider);
+
+    // Started before any retries, since waiting them out is part of how responsive the model is
+    let mut timer = StreamTimer::start();
+
+    // Held until the stream's been read through
+    let (response, _slot) = send_with_retry(
+        &client,
+        &params,
+        &RetryPolicy::from_env(),
+        queue::Priority::Interactive,
+        cancel,
+        &|status| {
+            let _ = retry_tx.send(status);
+        },
+    )
+    .await?;
+
+    // Dropping the response on cancellation is what closes the connection
+    let (content, mut tool_calls, usage) = match api {
+        API::Anthropic(_) => process_anthropic_stream(&api, response, tx, cancel, &mut timer).await,
+        API::OpenAI(_) => process_openai_stream(&api, response, tx, cancel, &mut timer).await,
+        API::Groq(_) => process_openai_stream(&api, response, tx, cancel, &mut timer).await,
+        API::DeepSeek(_) => process_openai_stream(&api, response, tx, cancel, &mut timer).await,
+        API::Together(_) | API::Fireworks(_) => {
+            process_openai_stream(&api, response, tx, cancel, &mut timer).await
+        }
+        API::Local(_) => process_openai_stream(&api, response, tx, cancel, &mut timer).await,
     }?;
+    let timing = timer.finish();
 
-    Ok(Message {
-        id: None,
-        message_type: MessageType::Assistant,
-        content,
-        api,
-        system_prompt: system_prompt.to_string(),
-        sequence: -1,
-        date_created: String::new(),
-    })
+    // Tool calls cut off partway through can't be trusted
+    if cancel.load(Ordering::SeqCst) {
+        tool_calls.clear();
+    }
+
+    Ok((
+        Message {
+            id: None,
+            message_type: MessageType::Assistant,
+            content,
+            api,
+            system_prompt: system_prompt.to_string(),
+            sequence: -1,
+            date_created: String::new(),
+            tool_calls,
+            tool_call_id: None,
+            attachments: Vec::new(),
+            interrupted: false,
+            language: None,
+            citations: Vec::new(),
+            moderation: None,
+        },
+        usage,
+        timing,
+    ))
 }
 
 /// Ad-hoc prompting for an LLM
 /// Makes zero expectations about the state of the conversation
 /// and returns a tuple of (response message, usage from the prompt)
-pub fn prompt(
+async fn send_prompt(
+    api: API,
+    system_prompt: &str,
+    params: &RequestParams,
+    priority: queue::Priority,
+) -> Result<(Message, Option<TokenUsage>), Box<dyn std::error::Error>> {
+    let mut span = spans::span("provider.prompt");
+    span.attribute("provider", &params.provider);
+    span.attribute("model", &params.model);
+
+    let client = client(&params.provider);
+
+    let (response, _slot) = send_with_retry(
+        &client,
+        params,
+        &RetryPolicy::from_env(),
+        priority,
+        &AtomicBool::new(false),
+        &|_| {},
+    )
+    .await?;
+    let response_json: serde_json::Value = response.json().await?;
+
+    let (content, tool_calls) = read_json_response(&api, &response_json);
+    let usage = read_usage(&api, &response_json);
+
+    Ok((
+        Message {
+            id: None,
+            message_type: MessageType::Assistant,
+            content,
+            api,
+            system_prompt: system_prompt.to_string(),
+            sequence: -1,
+            date_created: String::new(),
+            tool_calls,
+            tool_call_id: None,
+            attachments: Vec::new(),
+            interrupted: false,
+            language: None,
+            citations: Vec::new(),
+            moderation: None,
+        },
+        usage,
+    ))
+}
+
+/// `send_prompt` at the model's usual temperature, and never cached
+/// For anything that's expected to come out differently when asked again (suggestions, translations, etc.)
+///
+/// `priority` is whether the user's waiting on it--see queue.rs
+pub async fn prompt(
     api: API,
     system_prompt: &str,
     chat_history: &Vec<Message>,
-) -> Result<Message, Box<dyn std::error::Error>> {
-    let params = get_params(system_prompt, api.clone(), chat_history, false);
-    let client = reqwest::blocking::Client::new();
+    tools: &[Tool],
+    priority: queue::Priority,
+) -> Result<(Message, Option<TokenUsage>), Box<dyn std::error::Error>> {
+    let mut params = get_params(system_prompt, api.clone(), chat_history, false);
+    params.tools = tools.to_vec();
 
-    let response = build_request(&client, &params).send()?;
-    let response_json: serde_json::Value = response.json()?;
+    send_prompt(api, system_prompt, &params, priority).await
+}
 
-    let mut content = read_json_response(&api, &response_json);
+// Responses to deterministic prompts, keyed on everything that goes into the request
+// Entries are dropped oldest first once the cache is full
+pub struct ResponseCache {
+    capacity: usize,
+    entries: std::collections::HashMap<u64, Message>,
+    order: std::collections::VecDeque<u64>,
+}
 
-    content = unescape(&content);
-    if content.starts_with("\"") && content.ends_with("\"") {
-        content = content[1..content.len() - 1].to_string();
+impl ResponseCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: std::collections::HashMap::new(),
+            order: std::collections::VecDeque::new(),
+        }
     }
 
-    Ok(Message {
-        id: None,
-        message_type: MessageType::Assistant,
-        content,
-        api,
-        system_prompt: system_prompt.to_string(),
-        sequence: -1,
-        date_created: String::new(),
-    })
+    // `WILLIAM_RESPONSE_CACHE_SIZE` is the max number of cached responses--0 turns caching off
+    pub fn from_env() -> Self {
+        Self::new(
+            env::var("WILLIAM_RESPONSE_CACHE_SIZE")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(256),
+        )
+    }
+
+    fn key(params: &RequestParams) -> u64 {
+        use std::hash::{Hash, Hasher};
+
+        let messages = params
+            .messages
+            .iter()
+            .map(|m| {
+                serde_json::json!({
+                    "type": m.message_type.to_string(),
+                    "content": m.content,
+                    "toolCalls": m.tool_calls,
+                    "toolCallId": m.tool_call_id,
+                    "attachments": m.attachments.iter().map(|a| &a.hash).collect::<Vec<_>>(),
+                })
+            })
+            .collect::<Vec<serde_json::Value>>();
+
+        let key = serde_json::json!({
+            "provider": params.provider,
+            "model": params.model,
+            "messages": messages,
+            "systemPrompt": params.system_prompt,
+            "tools": params.tools,
+            "maxTokens": params.max_tokens,
+            "settings": params.settings,
+        })
+        .to_string();
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn get(&self, params: &RequestParams) -> Option<Message> {
+        self.entries.get(&Self::key(params)).cloned()
+    }
+
+    pub fn insert(&mut self, params: &RequestParams, message: Message) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = Self::key(params);
+        if self.entries.insert(key, message).is_none() {
+            self.order.push_back(key);
+        }
+
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+static RESPONSE_CACHE: std::sync::OnceLock<std::sync::Mutex<ResponseCache>> =
+    std::sync::OnceLock::new();
+
+/// `send_prompt`, but at temperature 0 and cached
+/// For background calls (naming, summaries, etc.) that are likely to be repeated word for word
+///
+/// Cache hits cost nothing, so they come back without usage
+/// `priority` is whether the user's waiting on it--see queue.rs
+pub async fn prompt_deterministic(
+    api: API,
+    system_prompt: &str,
+    chat_history: &Vec<Message>,
+    tools: &[Tool],
+    priority: queue::Priority,
+) -> Result<(Message, Option<TokenUsage>), Box<dyn std::error::Error>> {
+    let mut params = get_params(system_prompt, api.clone(), chat_history, false);
+    params.tools = tools.to_vec();
+    params.settings.temperature = Some(0.0);
+
+    let cache = RESPONSE_CACHE.get_or_init(|| std::sync::Mutex::new(ResponseCache::from_env()));
+    let cached = cache
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(&params);
+
+    if let Some(message) = cached {
+        info!("response cache hit for {}", params.model);
+        return Ok((message, None));
+    }
+
+    let (message, usage) = send_prompt(api, system_prompt, &params, priority).await?;
+    cache
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(&params, message.clone());
+
+    Ok((message, usage))
 }
 
 #[cfg(test)]
@@ -443,30 +1959,58 @@ mod tests {
     use super::*;
     use std::env;
 
+    #[test]
+    fn test_client_settings() {
+        assert_eq!(parse_seconds(None, 10), Some(Duration::from_secs(10)));
+        assert_eq!(
+            parse_seconds(Some(" 30 "), 10),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(parse_seconds(Some("0"), 10), None);
+        assert_eq!(
+            parse_seconds(Some("soon"), 10),
+            Some(Duration::from_secs(10))
+        );
+    }
+
     fn setup_test_env() {
         env::set_var("GROQ_API_KEY", "test_groq_key");
         env::set_var("OPENAI_API_KEY", "test_openai_key");
         env::set_var("ANTHROPIC_API_KEY", "test_anthropic_key");
+        env::set_var("DEEPSEEK_API_KEY", "test_deepseek_key");
+        env::set_var("WILLIAM_LOCAL_ENDPOINT", "http://localhost:11434/v1/");
     }
 
-    fn create_test_message(message_type: MessageType, content: &str, api: API) -> Message {
-        Message {
-            id: None,
-            message_type,
-            content: content.to_string(),
-            api,
-            system_prompt: "".to_string(),
-            sequence: -1,
-            date_created: String::new(),
-        }
+    #[test]
+    fn test_retired_models_alias_to_replacements() {
+        let api = API::from_strings("groq", "llama3-70b-8192").unwrap();
+        assert_eq!(api, API::Groq(GroqModel::LLaMA3370B));
+        assert_eq!(api.to_strings().1, "llama-3.3-70b-versatile");
+
+        let api: API =
+            serde_json::from_str(r#"{"provider":"groq","model":"llama3-70b-8192"}"#).unwrap();
+        assert_eq!(api, API::Groq(GroqModel::LLaMA3370B));
+
+        assert!(API::from_strings("groq", "llama3-70b-8192-nope").is_err());
+
+        // Hosted models pass through as-is
+        let api = API::from_strings("together", "Qwen/Qwen2.5-72B-Instruct-Turbo").unwrap();
+        assert_eq!(
+            api.to_strings(),
+            (
+                "together".to_string(),
+                "Qwen/Qwen2.5-72B-Instruct-Turbo".to_string()
+            )
+        );
+        assert!(API::from_strings("fireworks", "").is_err());
     }
 
     #[test]
     fn test_groq_basic_params() {
         setup_test_env();
         let system_prompt = "test system prompt".to_string();
-        let api = API::Groq(GroqModel::LLaMA70B);
-        let chat_history = vec![create_test_message(MessageType::User, "Hello", api.clone())];
+        let api = API::Groq(GroqModel::LLaMA3370B);
+        let chat_history = vec![Message::test(MessageType::User, "Hello", api.clone())];
 
         let params = get_groq_request_params(system_prompt.clone(), api, &chat_history, false);
 
@@ -482,7 +2026,7 @@ mod tests {
         setup_test_env();
         let system_prompt = "test system prompt".to_string();
         let api = API::OpenAI(OpenAIModel::GPT4o);
-        let chat_history = vec![create_test_message(MessageType::User, "Hello", api.clone())];
+        let chat_history = vec![Message::test(MessageType::User, "Hello", api.clone())];
 
         let params = get_openai_request_params(system_prompt.clone(), api, &chat_history, false);
 
@@ -498,7 +2042,7 @@ mod tests {
         setup_test_env();
         let system_prompt = "test system prompt".to_string();
         let api = API::Anthropic(AnthropicModel::Claude35Sonnet);
-        let chat_history = vec![create_test_message(MessageType::User, "Hello", api.clone())];
+        let chat_history = vec![Message::test(MessageType::User, "Hello", api.clone())];
 
         let params = get_anthropic_request_params(system_prompt.clone(), api, &chat_history, false);
 
@@ -514,30 +2058,23 @@ mod tests {
         setup_test_env();
         let system_prompt = "test prompt".to_string();
         let chat_history = vec![
-            Message {
-                id: None,
-                message_type: MessageType::User,
-                content: "First".to_string(),
-                api: API::OpenAI(OpenAIModel::GPT4o),
-                system_prompt: "".to_string(),
-                sequence: -1,
-                date_created: String::new(),
-            },
-            Message {
-                id: None,
-                message_type: MessageType::Assistant,
-                content: "Second".to_string(),
-                api: API::OpenAI(OpenAIModel::GPT4o),
-                system_prompt: "".to_string(),
-                sequence: -1,
-                date_created: String::new(),
-            },
+            Message::test(MessageType::User, "First", API::OpenAI(OpenAIModel::GPT4o)),
+            Message::test(
+                MessageType::Assistant,
+                "Second",
+                API::OpenAI(OpenAIModel::GPT4o),
+            ),
         ];
 
         let providers = vec![
-            (API::Groq(GroqModel::LLaMA70B), "groq"),
+            (API::Groq(GroqModel::LLaMA3370B), "groq"),
             (API::OpenAI(OpenAIModel::GPT4o), "openai"),
             (API::Anthropic(AnthropicModel::Claude35Sonnet), "anthropic"),
+            (API::DeepSeek(DeepSeekModel::Chat), "deepseek"),
+            (
+                API::Together("Qwen/Qwen2.5-72B-Instruct-Turbo".to_string()),
+                "together",
+            ),
         ];
 
         for (api, provider_name) in providers {
@@ -551,6 +2088,15 @@ mod tests {
                 API::Anthropic(_) => {
                     get_anthropic_request_params(system_prompt.clone(), api, &chat_history, false)
                 }
+                API::DeepSeek(_) => {
+                    get_deepseek_request_params(system_prompt.clone(), api, &chat_history, false)
+                }
+                API::Together(_) | API::Fireworks(_) => {
+                    get_hosted_request_params(system_prompt.clone(), api, &chat_history, false)
+                }
+                API::Local(_) => {
+                    get_local_request_params(system_prompt.clone(), api, &chat_history, false)
+                }
             };
 
             match provider_name {
@@ -562,7 +2108,7 @@ mod tests {
                         provider_name
                     );
                 }
-                "groq" | "openai" => {
+                "groq" | "openai" | "deepseek" | "together" => {
                     assert_eq!(
                         params.messages.len(),
                         3,
@@ -576,15 +2122,67 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_system_messages_in_place() {
+        setup_test_env();
+        let chat_history = vec![
+            Message::test(MessageType::User, "First", API::OpenAI(OpenAIModel::GPT4o)),
+            Message::test(
+                MessageType::System,
+                "Answer in French",
+                API::OpenAI(OpenAIModel::GPT4o),
+            ),
+            Message::test(MessageType::User, "Second", API::OpenAI(OpenAIModel::GPT4o)),
+        ];
+
+        // After the built system prompt, right where they were
+        let params = get_params(
+            "prompt",
+            API::Groq(GroqModel::LLaMA3370B),
+            &chat_history,
+            false,
+        );
+        assert_eq!(params.messages.len(), 4);
+        assert_eq!(params.messages[2].message_type, MessageType::System);
+        assert_eq!(params.messages[2].content, "Answer in French");
+
+        let params = get_params(
+            "prompt",
+            API::OpenAI(OpenAIModel::GPT4o),
+            &chat_history,
+            false,
+        );
+        assert_eq!(params.messages[2].message_type, MessageType::Developer);
+
+        // Anthropic's system prompt stays as it was, with the message going as the user
+        let api = API::Anthropic(AnthropicModel::Claude35Sonnet);
+        let params = get_params("prompt", api, &chat_history, false);
+        assert_eq!(params.system_prompt, Some("prompt".to_string()));
+        assert_eq!(params.messages.len(), 3);
+        assert_eq!(params.messages[1].message_type, MessageType::User);
+        assert_eq!(
+            params.messages[1].content,
+            "<systemMessage>Answer in French</systemMessage>"
+        );
+
+        // What's stored is left alone
+        assert_eq!(chat_history[1].message_type, MessageType::System);
+    }
+
     #[test]
     fn test_api_key_handling() {
         let test_cases = vec![
-            ("GROQ_API_KEY", API::Groq(GroqModel::LLaMA70B)),
+            ("GROQ_API_KEY", API::Groq(GroqModel::LLaMA3370B)),
             ("OPENAI_API_KEY", API::OpenAI(OpenAIModel::GPT4o)),
             (
                 "ANTHROPIC_API_KEY",
                 API::Anthropic(AnthropicModel::Claude35Sonnet),
             ),
+            ("DEEPSEEK_API_KEY", API::DeepSeek(DeepSeekModel::Chat)),
+            (
+                "FIREWORKS_API_KEY",
+                API::Fireworks("accounts/fireworks/models/llama-v3p1-70b-instruct".to_string()),
+            ),
         ];
 
         for (key, api) in test_cases {
@@ -610,6 +2208,15 @@ mod tests {
                     &chat_history,
                     false,
                 ),
+                API::DeepSeek(_) => {
+                    get_deepseek_request_params(system_prompt.clone(), api, &chat_history, false)
+                }
+                API::Together(_) | API::Fireworks(_) => {
+                    get_hosted_request_params(system_prompt.clone(), api, &chat_history, false)
+                }
+                API::Local(_) => {
+                    get_local_request_params(system_prompt.clone(), api, &chat_history, false)
+                }
             });
             assert!(result.is_err(), "Should panic when {} is not set", key);
         }
@@ -622,9 +2229,10 @@ mod tests {
         let chat_history = vec![];
 
         let providers = vec![
-            API::Groq(GroqModel::LLaMA70B),
+            API::Groq(GroqModel::LLaMA3370B),
             API::OpenAI(OpenAIModel::GPT4o),
             API::Anthropic(AnthropicModel::Claude35Sonnet),
+            API::DeepSeek(DeepSeekModel::Reasoner),
         ];
 
         for api in providers {
@@ -638,8 +2246,414 @@ mod tests {
                 API::Anthropic(_) => {
                     get_anthropic_request_params(system_prompt.clone(), api, &chat_history, true)
                 }
+                API::DeepSeek(_) => {
+                    get_deepseek_request_params(system_prompt.clone(), api, &chat_history, true)
+                }
+                API::Together(_) | API::Fireworks(_) => {
+                    get_hosted_request_params(system_prompt.clone(), api, &chat_history, true)
+                }
+                API::Local(_) => {
+                    get_local_request_params(system_prompt.clone(), api, &chat_history, true)
+                }
             };
             assert!(params.stream);
         }
     }
+
+    #[test]
+    fn test_local_params() {
+        setup_test_env();
+        let api = API::Local("llama3.2".to_string());
+        let chat_history = vec![Message::test(MessageType::User, "Hello", api.clone())];
+
+        let params = get_local_request_params("test".to_string(), api, &chat_history, true);
+        assert_eq!(params.provider, "local");
+        assert_eq!(params.scheme, "http");
+        assert_eq!(params.host, "localhost");
+        assert_eq!(params.port, 11434);
+        assert_eq!(params.path, "/v1/chat/completions");
+        assert_eq!(params.messages[0].message_type, MessageType::System);
+
+        let request = build_request(&reqwest::Client::new(), &params)
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "http://localhost:11434/v1/chat/completions"
+        );
+        assert!(request.headers().get("Authorization").is_none());
+
+        let models = read_model_list(
+            "local",
+            r#"{"object": "list", "data": [{"id": "llama3.2", "object": "model"}, {"id": "qwen2.5:7b"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            models,
+            vec![
+                API::Local("llama3.2".to_string()),
+                API::Local("qwen2.5:7b".to_string())
+            ]
+        );
+        assert!(read_model_list("local", "not json").is_err());
+    }
+
+    #[test]
+    fn test_model_discovery() {
+        let openai = read_model_list(
+            "openai",
+            r#"{"object": "list", "data": [
+                {"id": "gpt-4o"},
+                {"id": "gpt-4.1"},
+                {"id": "text-embedding-3-small"},
+                {"id": "gpt-4o-realtime-preview"},
+                {"id": "whisper-1"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            openai,
+            vec![
+                API::OpenAI(OpenAIModel::GPT4o),
+                API::OpenAI(OpenAIModel::Other("gpt-4.1".to_string()))
+            ]
+        );
+
+        let anthropic = read_model_list(
+            "anthropic",
+            r#"{"data": [
+                {"id": "claude-3-7-sonnet-20250219", "type": "model"},
+                {"id": "claude-sonnet-4-20250514", "type": "model"}
+            ], "has_more": false}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            anthropic,
+            vec![
+                API::Anthropic(AnthropicModel::Claude37Sonnet),
+                API::Anthropic(AnthropicModel::Other(
+                    "claude-sonnet-4-20250514".to_string()
+                ))
+            ]
+        );
+
+        // Groq's list is fixed, so anything new is skipped
+        let groq = read_model_list(
+            "groq",
+            r#"{"data": [{"id": "llama-3.1-8b-instant"}, {"id": "some-new-model"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(groq, vec![API::Groq(GroqModel::LLaMA318B)]);
+
+        let together = read_model_list(
+            "together",
+            r#"[{"id": "Qwen/Qwen2.5-72B-Instruct-Turbo", "type": "chat"}, {"id": "BAAI/bge-large-en-v1.5", "type": "embedding"}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            together,
+            vec![API::Together("Qwen/Qwen2.5-72B-Instruct-Turbo".to_string())]
+        );
+
+        // Discovered models round trip through the DB's (provider, name) pairs
+        let (provider, model) = openai[1].to_strings();
+        assert_eq!(API::from_strings(&provider, &model).unwrap(), openai[1]);
+        assert_eq!(
+            serde_json::from_str::<API>(r#"{"provider": "openai", "model": "gpt-4.1"}"#).unwrap(),
+            openai[1]
+        );
+    }
+
+    #[test]
+    fn test_tool_message_serialization() {
+        let api = API::Anthropic(AnthropicModel::Claude35Sonnet);
+        let mut call = Message::test(MessageType::Assistant, "", api.clone());
+        call.tool_calls.push(ToolCall {
+            id: "call_1".to_string(),
+            name: "lookup".to_string(),
+            arguments: r#"{"query":"test"}"#.to_string(),
+        });
+
+        let mut result = Message::test(MessageType::Tool, "result", api);
+        result.tool_call_id = Some("call_1".to_string());
+
+        let anthropic_call = anthropic_message(&call);
+        assert_eq!(anthropic_call["content"][0]["type"], "tool_use");
+        assert_eq!(anthropic_call["content"][0]["input"]["query"], "test");
+
+        let anthropic_result = anthropic_message(&result);
+        assert_eq!(anthropic_result["role"], "user");
+        assert_eq!(anthropic_result["content"][0]["tool_use_id"], "call_1");
+
+        let openai_call = openai_message(&call);
+        assert_eq!(openai_call["tool_calls"][0]["function"]["name"], "lookup");
+
+        let openai_result = openai_message(&result);
+        assert_eq!(openai_result["role"], "tool");
+        assert_eq!(openai_result["tool_call_id"], "call_1");
+    }
+
+    #[test]
+    fn test_image_serialization() {
+        let api = API::OpenAI(OpenAIModel::GPT4o);
+        let mut message = Message::test(MessageType::User, "What's this?", api);
+        message.attachments.push(Attachment {
+            hash: "abc".to_string(),
+            name: "cat.png".to_string(),
+            mime_type: "image/png".to_string(),
+            size: 3,
+            data: Some("aGk=".to_string()),
+            path: None,
+        });
+        // Not an image, and not loaded
+        message.attachments.push(Attachment {
+            hash: "def".to_string(),
+            name: "notes.txt".to_string(),
+            mime_type: "text/plain".to_string(),
+            size: 3,
+            data: None,
+            path: None,
+        });
+
+        let openai = openai_message(&message);
+        assert_eq!(openai["content"].as_array().unwrap().len(), 2);
+        assert_eq!(openai["content"][0]["text"], "What's this?");
+        assert_eq!(
+            openai["content"][1]["image_url"]["url"],
+            "data:image/png;base64,aGk="
+        );
+
+        let anthropic = anthropic_message(&message);
+        assert_eq!(anthropic["content"][0]["type"], "image");
+        assert_eq!(anthropic["content"][0]["source"]["media_type"], "image/png");
+        assert_eq!(anthropic["content"][1]["text"], "What's this?");
+
+        // Without loaded images, the content stays a plain string
+        message.attachments[0].data = None;
+        assert_eq!(openai_message(&message)["content"], "What's this?");
+        assert_eq!(anthropic_message(&message)["content"], "What's this?");
+    }
+
+    #[test]
+    fn test_reasoning_is_stripped_from_history() {
+        setup_test_env();
+        let api = API::DeepSeek(DeepSeekModel::Reasoner);
+        let chat_history = vec![
+            Message::test(MessageType::User, "Hello", api.clone()),
+            Message::test(
+                MessageType::Assistant,
+                "<think>they said hello</think>\n\nHi!",
+                api.clone(),
+            ),
+        ];
+
+        let params = get_deepseek_request_params("test".to_string(), api, &chat_history, true);
+        assert_eq!(params.messages[2].content, "Hi!");
+
+        assert_eq!(strip_reasoning("<think>cut off"), "");
+        assert_eq!(strip_reasoning("no reasoning"), "no reasoning");
+    }
+
+    #[test]
+    fn test_response_cache() {
+        let api = API::OpenAI(OpenAIModel::GPT4oMini);
+        let chat_history = vec![Message::test(MessageType::User, "Hello", api.clone())];
+
+        let mut params = get_params("name this", api.clone(), &chat_history, false);
+        params.settings.temperature = Some(0.0);
+
+        let mut cache = ResponseCache::new(1);
+        assert!(cache.get(&params).is_none());
+
+        let response = Message::test(MessageType::Assistant, "Greetings", api.clone());
+        cache.insert(&params, response);
+        assert_eq!(cache.get(&params).unwrap().content, "Greetings");
+
+        // Any change to the request is a different entry
+        let other = get_params("name this differently", api.clone(), &chat_history, false);
+        assert!(cache.get(&other).is_none());
+
+        // Oldest entry goes once the cache is full
+        let response = Message::test(MessageType::Assistant, "Salutations", api.clone());
+        cache.insert(&other, response);
+        assert!(cache.get(&params).is_none());
+        assert!(cache.get(&other).is_some());
+
+        let mut disabled = ResponseCache::new(0);
+        let response = Message::test(MessageType::Assistant, "Greetings", api);
+        disabled.insert(&params, response);
+        assert!(disabled.get(&params).is_none());
+    }
+
+    #[test]
+    fn test_stream_errors() {
+        let openai = API::OpenAI(OpenAIModel::GPT4o);
+        let delta = serde_json::json!({ "choices": [{ "delta": { "content": "hi" } }] });
+        assert!(read_stream_error(&openai, &delta).is_none());
+
+        let error = serde_json::json!({
+            "error": { "message": "Rate limit reached", "type": "requests", "code": "rate_limit_exceeded" }
+        });
+        let error = read_stream_error(&openai, &error).unwrap();
+        assert_eq!(error.category, ProviderErrorCategory::RateLimit);
+        assert_eq!(error.status, 200);
+
+        let anthropic = API::Anthropic(AnthropicModel::Claude35Sonnet);
+        let event = serde_json::json!({
+            "type": "error",
+            "error": { "type": "overloaded_error", "message": "Overloaded" }
+        });
+        let error = read_stream_error(&anthropic, &event).unwrap();
+        assert_eq!(error.provider, "anthropic");
+        assert_eq!(error.category, ProviderErrorCategory::Server);
+        assert_eq!(error.message, "Overloaded");
+    }
+
+    #[test]
+    fn test_provider_error_categories() {
+        let openai = r#"{"error": {"message": "This model's maximum context length is 128000 tokens.", "type": "invalid_request_error", "code": "context_length_exceeded"}}"#;
+        let error = parse_provider_error("openai", 400, openai);
+        assert_eq!(error.category, ProviderErrorCategory::ContextLength);
+        assert!(error.message.starts_with("This model's maximum"));
+
+        let anthropic = r#"{"type": "error", "error": {"type": "authentication_error", "message": "invalid x-api-key"}}"#;
+        let error = parse_provider_error("anthropic", 401, anthropic);
+        assert_eq!(error.category, ProviderErrorCategory::Auth);
+        assert_eq!(error.message, "invalid x-api-key");
+        assert_eq!(error.code.as_deref(), Some("authentication_error"));
+
+        let anthropic =
+            r#"{"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}}"#;
+        assert_eq!(
+            parse_provider_error("anthropic", 529, anthropic).category,
+            ProviderErrorCategory::Server
+        );
+
+        // Falls back on the status when the body is no help
+        let error = parse_provider_error("groq", 429, "slow down");
+        assert_eq!(error.category, ProviderErrorCategory::RateLimit);
+        assert_eq!(error.message, "slow down");
+
+        let wrapped = std::io::Error::other(error);
+        let boxed: Box<dyn std::error::Error> = Box::new(wrapped);
+        let wrapped = into_io_error(boxed);
+        assert_eq!(
+            ProviderError::from_io(&wrapped)
+                .unwrap()
+                .category
+                .error_type(),
+            "RateLimited"
+        );
+    }
+
+    #[test]
+    fn test_generation_settings_in_request() {
+        setup_test_env();
+
+        let body = |api: API, settings: GenerationSettings| {
+            let chat_history = vec![Message::test(MessageType::User, "Hello", api.clone())];
+            let mut params = get_params("test", api, &chat_history, false);
+            params.settings = settings;
+
+            let request = build_request(&reqwest::Client::new(), &params)
+                .build()
+                .unwrap();
+
+            serde_json::from_slice::<serde_json::Value>(request.body().unwrap().as_bytes().unwrap())
+                .unwrap()
+        };
+
+        let settings = GenerationSettings {
+            temperature: Some(0.5),
+            top_p: Some(0.9),
+            max_tokens: Some(256),
+        };
+
+        let openai = body(API::OpenAI(OpenAIModel::GPT4o), settings);
+        assert_eq!(openai["temperature"], 0.5);
+        assert_eq!(openai["max_completion_tokens"], 256);
+
+        let anthropic = body(API::Anthropic(AnthropicModel::Claude35Sonnet), settings);
+        assert_eq!(anthropic["max_tokens"], 256);
+        assert_eq!(anthropic["top_p"].as_f64().unwrap() as f32, 0.9);
+
+        // Falls back to the provider's required default
+        let anthropic = body(
+            API::Anthropic(AnthropicModel::Claude35Sonnet),
+            GenerationSettings::default(),
+        );
+        assert_eq!(anthropic["max_tokens"], 4096);
+        assert!(anthropic.get("temperature").is_none());
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+        };
+
+        for attempt in 1..10 {
+            let full = std::cmp::min(
+                Duration::from_millis(100 * 2u64.pow(attempt - 1)),
+                Duration::from_millis(1000),
+            );
+
+            let delay = policy.backoff(attempt);
+            assert!(delay >= full / 2 && delay <= full);
+        }
+
+        assert!(is_retryable(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable(reqwest::StatusCode::BAD_GATEWAY));
+        assert!(!is_retryable(reqwest::StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn test_read_usage() {
+        let openai = serde_json::json!({
+            "choices": [],
+            "usage": { "prompt_tokens": 12, "completion_tokens": 34 }
+        });
+        let usage = read_usage(&API::OpenAI(OpenAIModel::GPT4o), &openai).unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (12, 34));
+
+        let groq = serde_json::json!({
+            "x_groq": { "usage": { "prompt_tokens": 5, "completion_tokens": 6 } }
+        });
+        let usage = read_usage(&API::Groq(GroqModel::LLaMA3370B), &groq).unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (5, 6));
+
+        let anthropic_start = serde_json::json!({
+            "type": "message_start",
+            "message": { "usage": { "input_tokens": 7, "output_tokens": 1 } }
+        });
+        let usage = read_usage(
+            &API::Anthropic(AnthropicModel::Claude35Sonnet),
+            &anthropic_start,
+        )
+        .unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (7, 1));
+
+        let delta = serde_json::json!({ "choices": [{ "delta": { "content": "hi" } }] });
+        assert!(read_usage(&API::OpenAI(OpenAIModel::GPT4o), &delta).is_none());
+    }
+
+    #[test]
+    fn test_stream_timer() {
+        let mut timer = StreamTimer::start();
+        assert_eq!(timer.finish().time_to_first_token_ms, None);
+
+        std::thread::sleep(Duration::from_millis(5));
+        timer.first_token();
+        let first = timer.finish().time_to_first_token_ms.unwrap();
+        assert!(first >= 5);
+
+        // Only the first delta counts
+        std::thread::sleep(Duration::from_millis(5));
+        timer.first_token();
+        let timing = timer.finish();
+        assert_eq!(timing.time_to_first_token_ms, Some(first));
+        assert!(timing.generation_ms >= first + 5);
+    }
 }
@@ -0,0 +1,431 @@
+// A bare-bones PDF writer for exporting conversations
+//
+// Takes the Markdown export and lays it out on A4 pages: `#`/`##` headings in bold,
+// code blocks in Courier, and everything else as wrapped Helvetica
+// Each page gets a header with the conversation's name and date, and a page number at the bottom
+//
+// Only the standard fonts are used, so nothing has to be embedded, but that limits text to
+// Windows-1252--anything outside it comes out as `?`
+
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 56.0;
+const HEADER_Y: f32 = PAGE_HEIGHT - 36.0;
+const HEADER_RULE_Y: f32 = PAGE_HEIGHT - 44.0;
+const FOOTER_Y: f32 = 30.0;
+const BODY_TOP: f32 = PAGE_HEIGHT - MARGIN - 12.0;
+const LINE_SPACING: f32 = 1.35;
+
+// Helvetica's widths for ' ' through '~', in thousandths of the font size
+const HELVETICA_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, 556, 556, 556,
+    556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, 1015, 667, 667, 722, 722, 667,
+    611, 778, 722, 278, 500, 667, 556, 833, 722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667,
+    667, 611, 278, 278, 278, 469, 556, 333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500,
+    222, 833, 556, 556, 556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Font {
+    Regular,
+    Bold,
+    Mono,
+}
+
+impl Font {
+    fn name(self) -> &'static str {
+        match self {
+            Font::Regular => "F1",
+            Font::Bold => "F2",
+            Font::Mono => "F3",
+        }
+    }
+
+    // Bold is a little wider than regular--rounding up keeps lines inside the margins
+    fn width(self, bytes: &[u8], size: f32) -> f32 {
+        let units: f32 = bytes
+            .iter()
+            .map(|b| match self {
+                Font::Mono => 600.0,
+                _ => {
+                    let width = match b {
+                        32..=126 => HELVETICA_WIDTHS[(b - 32) as usize] as f32,
+                        _ => 556.0,
+                    };
+                    if self == Font::Bold {
+                        width * 1.1
+                    } else {
+                        width
+                    }
+                }
+            })
+            .sum();
+
+        units * size / 1000.0
+    }
+}
+
+struct Line {
+    font: Font,
+    size: f32,
+    text: Vec<u8>,
+    // Extra room above the line
+    space: f32,
+}
+
+// Text as Windows-1252, which is what the standard fonts are set up for
+fn encode(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\t' => bytes.extend_from_slice(b"    "),
+            ' '..='~' => bytes.push(c as u8),
+            '\u{a0}'..='\u{ff}' => bytes.push(c as u32 as u8),
+            '\u{2018}' => bytes.push(0x91),
+            '\u{2019}' => bytes.push(0x92),
+            '\u{201c}' => bytes.push(0x93),
+            '\u{201d}' => bytes.push(0x94),
+            '\u{2022}' => bytes.push(0x95),
+            '\u{2013}' => bytes.push(0x96),
+            '\u{2014}' => bytes.push(0x97),
+            '\u{2026}' => bytes.push(0x85),
+            '\u{20ac}' => bytes.push(0x80),
+            c if c.is_control() => {}
+            _ => bytes.push(b'?'),
+        }
+    }
+
+    bytes
+}
+
+// A PDF string literal
+fn literal(bytes: &[u8]) -> String {
+    let mut literal = String::from("(");
+    for b in bytes {
+        match b {
+            b'(' | b')' | b'\\' => {
+                literal.push('\\');
+                literal.push(*b as char);
+            }
+            32..=126 => literal.push(*b as char),
+            _ => literal.push_str(&format!("\\{:03o}", b)),
+        }
+    }
+    literal.push(')');
+
+    literal
+}
+
+// Breaks `text` into lines that fit in `width`, splitting words only when they don't fit on their own
+fn wrap(text: &[u8], font: Font, size: f32, width: f32) -> Vec<Vec<u8>> {
+    let mut lines = Vec::new();
+    let mut line: Vec<u8> = Vec::new();
+    for word in text.split(|b| *b == b' ') {
+        let candidate = if line.is_empty() {
+            word.to_vec()
+        } else {
+            [line.as_slice(), &b" "[..], word].concat()
+        };
+
+        if font.width(&candidate, size) <= width {
+            line = candidate;
+            continue;
+        }
+
+        if !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+        }
+
+        for b in word {
+            if !line.is_empty() && font.width(&line, size) + font.width(&[*b], size) > width {
+                lines.push(std::mem::take(&mut line));
+            }
+            line.push(*b);
+        }
+    }
+
+    lines.push(line);
+    lines
+}
+
+fn layout(markdown: &str) -> Vec<Line> {
+    let width = PAGE_WIDTH - 2.0 * MARGIN;
+    let mut lines = Vec::new();
+    let push = |font: Font, size: f32, text: &str, space: f32, lines: &mut Vec<Line>| {
+        for (i, text) in wrap(&encode(text), font, size, width)
+            .into_iter()
+            .enumerate()
+        {
+            lines.push(Line {
+                font,
+                size,
+                text,
+                space: if i == 0 { space } else { 0.0 },
+            });
+        }
+    };
+
+    let mut fence: Option<&str> = None;
+    for raw in markdown.lines() {
+        let trimmed = raw.trim();
+        if let Some(f) = fence {
+            if trimmed == f {
+                fence = None;
+            } else {
+                push(Font::Mono, 9.0, raw, 0.0, &mut lines);
+            }
+            continue;
+        }
+
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fence = Some(&trimmed[..3]);
+            continue;
+        }
+
+        // The system prompt's collapsible block doesn't mean anything on paper
+        if trimmed == "<details>" || trimmed == "</details>" {
+            continue;
+        }
+
+        if let Some(summary) = trimmed
+            .strip_prefix("<summary>")
+            .and_then(|s| s.strip_suffix("</summary>"))
+        {
+            push(Font::Bold, 10.0, summary, 0.0, &mut lines);
+        } else if let Some(title) = raw.strip_prefix("# ") {
+            push(Font::Bold, 18.0, title, 0.0, &mut lines);
+        } else if let Some(heading) = raw.strip_prefix("## ") {
+            push(Font::Bold, 12.0, heading, 10.0, &mut lines);
+        } else if trimmed.is_empty() {
+            lines.push(Line {
+                font: Font::Regular,
+                size: 5.0,
+                text: Vec::new(),
+                space: 0.0,
+            });
+        } else {
+            push(Font::Regular, 10.0, raw, 0.0, &mut lines);
+        }
+    }
+
+    lines
+}
+
+// Header (title on the left, date on the right, a rule underneath) and the page number
+fn page_furniture(title: &[u8], date: &[u8], page: usize, pages: usize) -> String {
+    let date_width = Font::Regular.width(date, 9.0);
+
+    // Long titles are cut short of the date
+    let room = PAGE_WIDTH - 2.0 * MARGIN - date_width - 18.0;
+    let mut title = title.to_vec();
+    if Font::Regular.width(&title, 9.0) > room {
+        while !title.is_empty()
+            && Font::Regular.width(&[title.as_slice(), &b"..."[..]].concat(), 9.0) > room
+        {
+            title.pop();
+        }
+        title.extend_from_slice(b"...");
+    }
+
+    let footer = format!("Page {} of {}", page, pages);
+    let footer_width = Font::Regular.width(footer.as_bytes(), 8.0);
+
+    format!(
+        "BT /F1 9 Tf {:.2} {:.2} Td {} Tj ET\n\
+         BT /F1 9 Tf {:.2} {:.2} Td {} Tj ET\n\
+         0.5 w {:.2} {:.2} m {:.2} {:.2} l S\n\
+         BT /F1 8 Tf {:.2} {:.2} Td {} Tj ET\n",
+        MARGIN,
+        HEADER_Y,
+        literal(&title),
+        PAGE_WIDTH - MARGIN - date_width,
+        HEADER_Y,
+        literal(date),
+        MARGIN,
+        HEADER_RULE_Y,
+        PAGE_WIDTH - MARGIN,
+        HEADER_RULE_Y,
+        (PAGE_WIDTH - footer_width) / 2.0,
+        FOOTER_Y,
+        literal(footer.as_bytes()),
+    )
+}
+
+// Each page's body text as content stream operators
+fn paginate(lines: &[Line]) -> Vec<String> {
+    let mut pages = Vec::new();
+    let mut page = String::new();
+    let mut y = BODY_TOP;
+    for line in lines {
+        let height = line.size * LINE_SPACING + line.space;
+        if y - height < MARGIN && !page.is_empty() {
+            pages.push(std::mem::take(&mut page));
+            y = BODY_TOP;
+        }
+
+        y -= height;
+        if !line.text.is_empty() {
+            page.push_str(&format!(
+                "BT /{} {} Tf {:.2} {:.2} Td {} Tj ET\n",
+                line.font.name(),
+                line.size,
+                MARGIN,
+                y,
+                literal(&line.text)
+            ));
+        }
+    }
+
+    pages.push(page);
+    pages
+}
+
+pub fn render(title: &str, date: &str, markdown: &str) -> Vec<u8> {
+    let pages = paginate(&layout(markdown));
+    let (title, date) = (encode(title), encode(date));
+
+    // 1: catalog, 2: page tree, 3-5: fonts, 6: document info, then each page's contents + page
+    let page_ids = (0..pages.len()).map(|i| 8 + 2 * i).collect::<Vec<_>>();
+    let mut objects = vec![
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            page_ids
+                .iter()
+                .map(|id| format!("{} 0 R", id))
+                .collect::<Vec<_>>()
+                .join(" "),
+            pages.len()
+        ),
+    ];
+
+    for font in ["Helvetica", "Helvetica-Bold", "Courier"] {
+        objects.push(format!(
+            "<< /Type /Font /Subtype /Type1 /BaseFont /{} /Encoding /WinAnsiEncoding >>",
+            font
+        ));
+    }
+
+    objects.push(format!(
+        "<< /Title {} /Producer (William) >>",
+        literal(&title)
+    ));
+
+    for (i, body) in pages.iter().enumerate() {
+        let contents = format!(
+            "{}{}",
+            page_furniture(&title, &date, i + 1, pages.len()),
+            body
+        );
+        objects.push(format!(
+            "<< /Length {} >>\nstream\n{}endstream",
+            contents.len(),
+            contents
+        ));
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+             /Resources << /Font << /F1 3 0 R /F2 4 0 R /F3 5 0 R >> >> /Contents {} 0 R >>",
+            PAGE_WIDTH,
+            PAGE_HEIGHT,
+            page_ids[i] - 1
+        ));
+    }
+
+    // The binary comment marks the file as binary for anything sniffing it
+    let mut output = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(output.len());
+        output.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).as_bytes());
+    }
+
+    let xref = output.len();
+    output.extend_from_slice(
+        format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes(),
+    );
+    for offset in offsets {
+        output.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+
+    output.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R /Info 6 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref
+        )
+        .as_bytes(),
+    );
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(pdf: &[u8]) -> String {
+        String::from_utf8_lossy(pdf).to_string()
+    }
+
+    #[test]
+    fn test_render() {
+        let pdf = render(
+            "Lifetimes (and borrows)",
+            "2025-01-01",
+            "# Lifetimes\n\n## User\n\nWhat's a lifetime?\n\n```rust\nfn f<'a>(x: &'a str) {}\n```\n",
+        );
+        let output = text(&pdf);
+
+        assert!(output.starts_with("%PDF-1.4"));
+        assert!(output.ends_with("%%EOF\n"));
+        assert!(output.contains("/Count 1"));
+        assert!(output.contains("(Lifetimes \\(and borrows\\)) Tj"));
+        assert!(output.contains("(2025-01-01) Tj"));
+        assert!(output.contains("/F3 9 Tf"));
+        assert!(output.contains("(Page 1 of 1) Tj"));
+
+        // Every object is where the cross-reference table says it is
+        let xref = output.rfind("xref\n").unwrap();
+        for (i, entry) in output[xref..].lines().skip(3).take(8).enumerate() {
+            let offset = entry[..10].parse::<usize>().unwrap();
+            assert!(pdf[offset..].starts_with(format!("{} 0 obj", i + 1).as_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_pagination() {
+        let markdown = (0..200)
+            .map(|i| format!("Line number {}", i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let output = text(&render("Long", "2025-01-01", &markdown));
+
+        let pages = output.matches("/Type /Page ").count();
+        assert!(pages > 1);
+        assert_eq!(output.matches("(Long) Tj").count(), pages);
+        assert!(output.contains(&format!("(Page {} of {}) Tj", pages, pages)));
+    }
+
+    #[test]
+    fn test_wrap() {
+        let width = 100.0;
+        let lines = wrap(
+            &encode("the quick brown fox jumps over the lazy dog"),
+            Font::Regular,
+            10.0,
+            width,
+        );
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|l| Font::Regular.width(l, 10.0) <= width));
+
+        // Too long for a line on its own
+        let lines = wrap(&[b'x'; 100], Font::Mono, 10.0, width);
+        assert_eq!(lines.concat().len(), 100);
+        assert!(lines.iter().all(|l| Font::Mono.width(l, 10.0) <= width));
+
+        assert_eq!(
+            encode("café “quoted” 日本"),
+            b"caf\xe9 \x93quoted\x94 ??".to_vec()
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use rusqlite::params;
+
+use chamber_common::{lprint, Logger};
+
+use crate::types::*;
+
+// Personas are saved system prompts, with the model and sampling settings that go with them
+// A conversation's persona fills in whatever its own overrides leave unset,
+// and the user config covers the rest
+
+fn read_persona(row: &rusqlite::Row) -> rusqlite::Result<Persona> {
+    let model = match (
+        row.get::<_, Option<String>>("provider")?,
+        row.get::<_, Option<String>>("model")?,
+    ) {
+        (Some(provider), Some(model)) => API::from_strings(&provider, &model).ok(),
+        _ => None,
+    };
+
+    Ok(Persona {
+        id: Some(row.get("id")?),
+        name: row.get("name")?,
+        system_prompt: row.get("system_prompt")?,
+        model,
+        settings: GenerationSettings {
+            temperature: row.get("temperature")?,
+            top_p: row.get("top_p")?,
+            max_tokens: row.get("max_tokens")?,
+        },
+    })
+}
+
+const PERSONA_SELECT: &str = "
+    SELECT p.id, p.name, p.system_prompt, m.provider, m.name as model, p.temperature, p.top_p, p.max_tokens
+    FROM personas p
+    LEFT JOIN models m ON p.default_api_config_id = m.id
+";
+
+pub fn list(db: &rusqlite::Connection) -> rusqlite::Result<Vec<Persona>> {
+    let mut query = db.prepare(&format!(
+        "{} ORDER BY p.name COLLATE NOCASE",
+        PERSONA_SELECT
+    ))?;
+    let personas = query
+        .query_map(params![], read_persona)?
+        .collect::<rusqlite::Result<Vec<Persona>>>()?;
+
+    Ok(personas)
+}
+
+pub fn get(id: i64, db: &rusqlite::Connection) -> rusqlite::Result<Option<Persona>> {
+    match db.query_row(
+        &format!("{} WHERE p.id = ?1", PERSONA_SELECT),
+        params![id],
+        read_persona,
+    ) {
+        Ok(persona) => Ok(Some(persona)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Creates the persona, or updates it if it has an ID
+// Returns the persona's ID
+pub fn save(persona: &Persona, db: &rusqlite::Connection) -> Result<i64, std::io::Error> {
+    let name = persona.name.trim();
+    if name.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Personas need a name",
+        ));
+    }
+
+    let model_id = match &persona.model {
+        Some(api) => Some(get_model_id(api, db).map_err(|e| std::io::Error::other(e.to_string()))?),
+        None => None,
+    };
+
+    let result = match persona.id {
+        Some(id) => db
+            .execute(
+                "UPDATE personas
+                 SET name = ?2, system_prompt = ?3, default_api_config_id = ?4, temperature = ?5, top_p = ?6, max_tokens = ?7
+                 WHERE id = ?1",
+                params![
+                    id,
+                    name,
+                    persona.system_prompt,
+                    model_id,
+                    persona.settings.temperature,
+                    persona.settings.top_p,
+                    persona.settings.max_tokens
+                ],
+            )
+            .map(|updated| (id, updated)),
+        None => db
+            .execute(
+                "INSERT INTO personas (name, system_prompt, default_api_config_id, temperature, top_p, max_tokens, date_created)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, CURRENT_TIMESTAMP)",
+                params![
+                    name,
+                    persona.system_prompt,
+                    model_id,
+                    persona.settings.temperature,
+                    persona.settings.top_p,
+                    persona.settings.max_tokens
+                ],
+            )
+            .map(|inserted| (db.last_insert_rowid(), inserted)),
+    };
+
+    match result {
+        Ok((id, 0)) => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("No persona with ID {}", id),
+        )),
+        Ok((id, _)) => {
+            lprint!(info, "Saved persona {} ({})", id, name);
+            Ok(id)
+        }
+        Err(rusqlite::Error::SqliteFailure(e, _))
+            if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("There's already a persona named {}", name),
+            ))
+        }
+        Err(e) => Err(std::io::Error::other(e.to_string())),
+    }
+}
+
+// Conversations using the persona go back to their own overrides and the user config
+pub fn delete(id: i64, db: &rusqlite::Connection) -> rusqlite::Result<usize> {
+    let tx = db.unchecked_transaction()?;
+
+    tx.execute(
+        "UPDATE conversations SET persona_id = NULL WHERE persona_id = ?1",
+        params![id],
+    )?;
+    let deleted = tx.execute("DELETE FROM personas WHERE id = ?1", params![id])?;
+
+    tx.commit()?;
+
+    Ok(deleted)
+}
@@ -0,0 +1,106 @@
+// Cleaning up message text before it's embedded
+//
+// Code blocks and the XML the prompts are built out of mostly add noise to the embeddings--
+// what's worth remembering is the prose around them
+// Cleaned text is also what duplicates are caught by, so the same message sent twice
+// (or differing only in whitespace) only goes into the index once--see `EmbeddingQueue::push`
+
+pub fn clean(text: &str) -> String {
+    collapse_whitespace(&strip_tags(&strip_code_blocks(text)))
+}
+
+// Fenced code blocks are dropped along with their fences
+// A fence that's never closed runs to the end of the text
+fn strip_code_blocks(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut fence: Option<&str> = None;
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        match fence {
+            Some(f) => {
+                if trimmed.starts_with(f) {
+                    fence = None;
+                }
+            }
+            None if trimmed.starts_with("```") || trimmed.starts_with("~~~") => {
+                fence = Some(&trimmed[..3]);
+            }
+            None => {
+                output.push_str(line);
+                output.push('\n');
+            }
+        }
+    }
+
+    output
+}
+
+// Length of the XML tag `text` starts with, if it starts with one
+// Anything else with a `<` in it (e.g., `a < b`) is left alone
+fn tag_len(text: &str) -> Option<usize> {
+    let name = text[1..].strip_prefix('/').unwrap_or(&text[1..]);
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let end = text.find('>')?;
+    if text[1..end].contains(['<', '\n']) {
+        return None;
+    }
+
+    Some(end + 1)
+}
+
+// Tags are dropped, their contents kept
+fn strip_tags(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        output.push_str(&rest[..start]);
+
+        let tag = &rest[start..];
+        match tag_len(tag) {
+            Some(len) => {
+                // So the text on either side doesn't run together
+                output.push(' ');
+                rest = &tag[len..];
+            }
+            None => {
+                output.push('<');
+                rest = &tag[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+
+    output
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean() {
+        assert_eq!(
+            clean("Here's the fix:\n\n```rust\nfn main() {}\n```\n\nThat   should\tdo it."),
+            "Here's the fix: That should do it."
+        );
+        assert_eq!(
+            clean("<systemPrompt><reference id=\"1\">Some context</reference></systemPrompt>"),
+            "Some context"
+        );
+
+        // Not tags
+        assert_eq!(clean("if a < b && c > d"), "if a < b && c > d");
+        assert_eq!(clean("x <- 3"), "x <- 3");
+
+        // Unclosed fences run to the end
+        assert_eq!(clean("Before\n~~~\nlet x = 1;"), "Before");
+        assert_eq!(clean("```\nonly code\n```"), "");
+    }
+}
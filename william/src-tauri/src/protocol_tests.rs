@@ -0,0 +1,525 @@
+// End-to-end tests of the websocket protocol
+//
+// The real server runs against a workspace in the temp dir, with:
+// - a mock provider, serving canned OpenAI-style SSE as the `local` endpoint
+// - Dewey's mock embeddings, so nothing goes out to OpenAI
+//
+// The workspace, the database, and the server are process-wide, so there's one of each for every
+// test here--tests each open their own connection, and only look at the conversations they made
+
+use std::io::{BufRead, Read, Write};
+
+use chamber_common::get_root_dir;
+
+use crate::types::*;
+
+const MOCK_MODEL: &str = "mock-model";
+// Streamed a few characters at a time
+const MOCK_RESPONSE: &str = "Hello from the mock provider!";
+const READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+struct Harness {
+    url: String,
+}
+
+static HARNESS: std::sync::OnceLock<Harness> = std::sync::OnceLock::new();
+
+fn harness() -> &'static Harness {
+    HARNESS.get_or_init(|| {
+        let root = std::env::temp_dir().join(format!("william-protocol-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+
+        crate::init_workspace(root.to_str().unwrap(), "protocol_tests");
+        assert_eq!(
+            get_root_dir(),
+            root,
+            "the workspace was set up before the harness"
+        );
+        // The logger's panic hook is dropped so test failures still print
+        let _ = std::panic::take_hook();
+
+        dewey_lib::set_embedding_provider(dewey_lib::EmbeddingProvider::Mock);
+        // Conversations are named from their first message instead
+        std::env::remove_var("OPENAI_API_KEY");
+        std::env::set_var(
+            "WILLIAM_LOCAL_ENDPOINT",
+            format!("http://{}/v1", mock_provider()),
+        );
+
+        let db = crate::open_db().unwrap();
+        crate::migrations::migrate(&db, crate::DB_MIGRATIONS, &crate::get_backups_dir()).unwrap();
+
+        let dewey = dewey_lib::Dewey::new().ok();
+        assert!(dewey.is_some(), "Dewey couldn't start with mock embeddings");
+
+        let server = crate::bind_websocket("127.0.0.1", 0).unwrap();
+        let url = crate::websocket_url(&server).unwrap();
+        std::thread::spawn(move || {
+            tauri::async_runtime::block_on(crate::serve(
+                server,
+                db,
+                dewey,
+                crate::tiktoken::Tokenizer::approximate(),
+            ))
+        });
+
+        Harness { url }
+    })
+}
+
+// Answers chat completions with `MOCK_RESPONSE` (streamed if asked) and lists `MOCK_MODEL`,
+// returning the address it's listening on
+fn mock_provider() -> std::net::SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            std::thread::spawn(move || mock_response(stream));
+        }
+    });
+
+    address
+}
+
+fn mock_response(mut stream: std::net::TcpStream) {
+    let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).is_err() || header.trim().is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    let _ = reader.read_exact(&mut body);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (content_type, response) = if path.ends_with("/models") {
+        (
+            "application/json",
+            serde_json::json!({ "object": "list", "data": [{ "id": MOCK_MODEL }] }).to_string(),
+        )
+    } else if path.ends_with("/chat/completions") && body["stream"] == true {
+        let mut events = MOCK_RESPONSE
+            .chars()
+            .collect::<Vec<_>>()
+            .chunks(5)
+            .map(|c| {
+                let delta = serde_json::json!({
+                    "choices": [{ "index": 0, "delta": { "content": c.iter().collect::<String>() } }]
+                });
+                format!("data: {}\n\n", delta)
+            })
+            .collect::<String>();
+        events.push_str(&format!(
+            "data: {}\n\n",
+            serde_json::json!({
+                "choices": [{ "index": 0, "delta": {}, "finish_reason": "stop" }],
+                "usage": { "prompt_tokens": 10, "completion_tokens": 5 }
+            })
+        ));
+        events.push_str("data: [DONE]\n\n");
+
+        ("text/event-stream", events)
+    } else if path.ends_with("/chat/completions") {
+        (
+            "application/json",
+            serde_json::json!({
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": MOCK_RESPONSE },
+                    "finish_reason": "stop"
+                }],
+                "usage": { "prompt_tokens": 10, "completion_tokens": 5 }
+            })
+            .to_string(),
+        )
+    } else {
+        let _ = stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        return;
+    };
+
+    let _ = stream.write_all(
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            content_type,
+            response.len(),
+            response
+        )
+        .as_bytes(),
+    );
+}
+
+type Client = tungstenite::WebSocket<tungstenite::stream::MaybeTlsStream<std::net::TcpStream>>;
+
+fn connect() -> Client {
+    let (mut client, _) = tungstenite::connect(harness().url.as_str()).unwrap();
+    if let tungstenite::stream::MaybeTlsStream::Plain(stream) = client.get_mut() {
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    }
+
+    // Every connection opens with its session
+    assert_eq!(read(&mut client)["method"], "Session");
+
+    client
+}
+
+fn send(client: &mut Client, request: ArrakisRequest) {
+    client
+        .send(tungstenite::Message::Text(
+            serde_json::to_string(&request).unwrap().into(),
+        ))
+        .unwrap();
+}
+
+fn read(client: &mut Client) -> serde_json::Value {
+    loop {
+        match client.read().unwrap() {
+            tungstenite::Message::Text(t) => return serde_json::from_str(&t).unwrap(),
+            _ => continue,
+        }
+    }
+}
+
+// Every response to `request_id` up to and including the first `method` one
+// Errors fail the test rather than being waited past
+fn read_until(client: &mut Client, request_id: &str, method: &str) -> Vec<serde_json::Value> {
+    let mut responses = Vec::new();
+    loop {
+        let response = read(client);
+        if response["id"] != request_id {
+            continue;
+        }
+
+        assert_ne!(response["method"], "WilliamError", "{}", response);
+
+        let done = response["method"] == method;
+        responses.push(response);
+        if done {
+            return responses;
+        }
+    }
+}
+
+// The streamed deltas, put back together
+fn streamed(responses: &[serde_json::Value]) -> String {
+    responses
+        .iter()
+        .filter(|r| r["method"] == "Completion")
+        .map(|r| r["payload"]["delta"].as_str().unwrap_or_default())
+        .collect()
+}
+
+// A new conversation, the way the frontend sends one--named with a GUID until it's named properly,
+// with an empty assistant message for the response
+fn new_conversation(prompt: &str) -> Conversation {
+    Conversation {
+        id: None,
+        name: uuid::Uuid::new_v4().to_string(),
+        messages: vec![
+            Message::test(
+                MessageType::User,
+                prompt,
+                API::Local(MOCK_MODEL.to_string()),
+            ),
+            Message::test(
+                MessageType::Assistant,
+                "",
+                API::Local(MOCK_MODEL.to_string()),
+            ),
+        ],
+        tools: Vec::new(),
+        overrides: Default::default(),
+        branch_id: None,
+        settings: Default::default(),
+        pinned: false,
+        archived: false,
+        unread: 0,
+        template: None,
+        memory_scope: MemoryScope::All,
+        related: Vec::new(),
+    }
+}
+
+// Sends a new conversation and waits for its response, returning the conversation's ID
+fn complete(client: &mut Client, prompt: &str) -> i64 {
+    let request_id = uuid::Uuid::new_v4().to_string();
+    send(
+        client,
+        ArrakisRequest::Completion {
+            id: request_id.clone(),
+            payload: new_conversation(prompt),
+        },
+    );
+
+    let responses = read_until(client, &request_id, "CompletionEnd");
+    assert_eq!(streamed(&responses), MOCK_RESPONSE);
+
+    responses
+        .iter()
+        .find(|r| r["method"] == "Completion")
+        .and_then(|r| r["payload"]["conversationId"].as_i64())
+        .unwrap()
+}
+
+fn db() -> rusqlite::Connection {
+    harness();
+    crate::open_db().unwrap()
+}
+
+// (type, content) of each message on the conversation's active branch, in order
+fn stored_messages(conversation_id: i64) -> Vec<(String, String)> {
+    let db = db();
+    let mut query = db
+        .prepare(
+            "
+            SELECT mt.name, m.content
+            FROM conversations c
+            JOIN paths l ON l.conversation_id = c.id
+                AND l.branch_id IS c.active_branch_id
+            JOIN messages m ON m.id = l.message_id
+            JOIN message_types mt ON mt.id = m.message_type_id
+            WHERE c.id = ?1
+            ORDER BY l.sequence
+            ",
+        )
+        .unwrap();
+
+    query
+        .query_map([conversation_id], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .unwrap()
+}
+
+#[test]
+fn test_ping() {
+    let mut client = connect();
+    send(
+        &mut client,
+        ArrakisRequest::Ping {
+            id: "ping".to_string(),
+            payload: Ping {
+                body: "ping".to_string(),
+            },
+        },
+    );
+
+    let responses = read_until(&mut client, "ping", "Ping");
+    assert_eq!(responses[0]["payload"]["body"], "pong");
+}
+
+#[test]
+fn test_invalid_request() {
+    let mut client = connect();
+    client
+        .send(tungstenite::Message::Text(
+            r#"{"method": "Load", "id": "bad", "payload": {}}"#.into(),
+        ))
+        .unwrap();
+
+    let response = read(&mut client);
+    assert_eq!(response["method"], "WilliamError");
+    assert_eq!(response["id"], "bad");
+    assert_eq!(response["payload"]["error_type"], "InvalidRequest");
+}
+
+#[test]
+fn test_completion_and_load() {
+    let mut client = connect();
+    let conversation_id = complete(&mut client, "Say hello");
+
+    assert_eq!(
+        stored_messages(conversation_id),
+        vec![
+            ("user".to_string(), "Say hello".to_string()),
+            ("assistant".to_string(), MOCK_RESPONSE.to_string()),
+        ]
+    );
+
+    send(
+        &mut client,
+        ArrakisRequest::Load {
+            id: "load".to_string(),
+            payload: LoadConversation {
+                id: conversation_id,
+                branch_id: None,
+            },
+        },
+    );
+
+    let responses = read_until(&mut client, "load", "Load");
+    let loaded: Conversation =
+        serde_json::from_value(responses.last().unwrap()["payload"].clone()).unwrap();
+    assert_eq!(loaded.id, Some(conversation_id));
+    assert_eq!(loaded.messages.len(), 2);
+    assert_eq!(loaded.messages[1].content, MOCK_RESPONSE);
+}
+
+#[test]
+fn test_fork() {
+    let mut client = connect();
+    let conversation_id = complete(&mut client, "Say hello twice");
+
+    // Regenerates the response on a branch of its own
+    send(
+        &mut client,
+        ArrakisRequest::Fork {
+            id: "fork".to_string(),
+            payload: Fork {
+                conversation_id,
+                sequence: 2,
+            },
+        },
+    );
+
+    let responses = read_until(&mut client, "fork", "CompletionEnd");
+    assert_eq!(streamed(&responses), MOCK_RESPONSE);
+
+    let branches: i64 = db()
+        .query_row(
+            "SELECT COUNT(*) FROM branches WHERE conversation_id = ?1",
+            [conversation_id],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(branches, 2);
+
+    // Both branches share the prompt, but not the response
+    let responses: i64 = db()
+        .query_row(
+            "
+            SELECT COUNT(DISTINCT l.message_id)
+            FROM paths l
+            JOIN messages m ON m.id = l.message_id
+            JOIN message_types mt ON mt.id = m.message_type_id
+            WHERE l.conversation_id = ?1 AND mt.name = 'assistant'
+            ",
+            [conversation_id],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(responses, 2);
+    assert_eq!(stored_messages(conversation_id).len(), 2);
+}
+
+#[test]
+fn test_delete() {
+    let mut client = connect();
+    let conversation_id = complete(&mut client, "Say goodbye");
+
+    send(
+        &mut client,
+        ArrakisRequest::DeleteConversation {
+            id: "delete".to_string(),
+            payload: DeleteConversation { conversation_id },
+        },
+    );
+
+    let responses = read_until(&mut client, "delete", "ConversationList");
+    let listed = responses.last().unwrap()["payload"]["conversations"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|c| c["id"] == conversation_id);
+    assert!(!listed);
+
+    // Into the trash, not gone
+    let trashed: bool = db()
+        .query_row(
+            "SELECT deleted_at IS NOT NULL FROM conversations WHERE id = ?1",
+            [conversation_id],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert!(trashed);
+    assert_eq!(stored_messages(conversation_id).len(), 2);
+}
+
+#[test]
+fn test_forget_memory() {
+    let mut client = connect();
+    let prompt = format!("Remember {}", uuid::Uuid::new_v4());
+    let conversation_id = complete(&mut client, &prompt);
+
+    send(
+        &mut client,
+        ArrakisRequest::Memories {
+            id: "memories".to_string(),
+        },
+    );
+
+    let responses = read_until(&mut client, "memories", "Memories");
+    let memory = responses.last().unwrap()["payload"]["memories"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|m| m["conversationId"] == conversation_id && m["role"] == "user")
+        .cloned()
+        .unwrap();
+    assert_eq!(memory["preview"], prompt);
+
+    let memory_id = memory["id"].as_i64().unwrap();
+    let message_id = memory["messageId"].as_i64().unwrap();
+    send(
+        &mut client,
+        ArrakisRequest::ForgetMemory {
+            id: "forget".to_string(),
+            payload: ForgetMemory { memory_id },
+        },
+    );
+
+    let responses = read_until(&mut client, "forget", "Memories");
+    let listed = responses.last().unwrap()["payload"]["memories"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|m| m["id"] == memory_id);
+    assert!(!listed);
+
+    // Not embedded again by the backfill
+    let db = db();
+    let forgotten: bool = db
+        .query_row(
+            "SELECT EXISTS (SELECT 1 FROM forgotten_messages WHERE message_id = ?1)",
+            [message_id],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert!(forgotten);
+    assert!(!crate::backfill::unembedded(&db)
+        .unwrap()
+        .contains(&message_id));
+}
+
+// Messages are stored by `MessageType::id`, so joins on `message_types` by name depend on these agreeing
+#[test]
+fn test_message_type_ids() {
+    let db = db();
+    for id in 0..5 {
+        let name: String = db
+            .query_row(
+                "SELECT name FROM message_types WHERE id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(name, MessageType::from_id(id).unwrap().to_string());
+    }
+}
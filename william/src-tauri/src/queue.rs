@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::types::*;
+
+// Requests to providers wait here for a slot, with only so many running against a provider at once
+//
+// Whenever a slot opens, interactive requests (what the user is waiting on) go ahead of background
+// ones (names, suggestions, flashcards), and requests of the same priority go in the order they came in
+// A slot is held until the response is read through--for streams, until the stream ends
+// Retries give theirs up while they wait, and queue up again like anything else
+//
+// The cap is `WILLIAM_PROVIDER_CONCURRENCY`: a number for every provider, with per-provider overrides,
+// e.g. `4,local=1,anthropic=2`
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Background,
+    Interactive,
+}
+
+const DEFAULT_LIMIT: usize = 4;
+// Local servers tend to work through requests one at a time anyways
+const LOCAL_LIMIT: usize = 1;
+
+// How often waiting requests check whether they've been cancelled
+const CANCEL_CHECK: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    default: usize,
+    providers: HashMap<String, usize>,
+}
+
+impl Limits {
+    pub fn parse(value: &str) -> Self {
+        let mut limits = Limits {
+            default: DEFAULT_LIMIT,
+            providers: HashMap::from([("local".to_string(), LOCAL_LIMIT)]),
+        };
+
+        for part in value.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            match part.split_once('=') {
+                Some((provider, limit)) => {
+                    if let Ok(limit) = limit.trim().parse::<usize>() {
+                        limits
+                            .providers
+                            .insert(provider.trim().to_string(), limit.max(1));
+                    }
+                }
+                None => {
+                    if let Ok(limit) = part.parse::<usize>() {
+                        limits.default = limit.max(1);
+                    }
+                }
+            }
+        }
+
+        limits
+    }
+
+    pub fn from_env() -> Self {
+        Self::parse(&std::env::var("WILLIAM_PROVIDER_CONCURRENCY").unwrap_or_default())
+    }
+
+    fn get(&self, provider: &str) -> usize {
+        self.providers
+            .get(provider)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+#[derive(Default)]
+struct ProviderState {
+    running: usize,
+    // (priority, ticket) of each request waiting on a slot
+    waiting: Vec<(Priority, u64)>,
+}
+
+impl ProviderState {
+    // The waiting request that gets the next slot
+    fn next(&self) -> Option<u64> {
+        self.waiting
+            .iter()
+            .max_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)))
+            .map(|(_, ticket)| *ticket)
+    }
+}
+
+pub struct CompletionQueue {
+    limits: Limits,
+    providers: Mutex<HashMap<String, ProviderState>>,
+    changed: tokio::sync::Notify,
+    tickets: AtomicU64,
+}
+
+// Released when dropped
+pub struct Slot<'a> {
+    queue: &'a CompletionQueue,
+    provider: String,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut providers = self.queue.lock();
+        if let Some(state) = providers.get_mut(&self.provider) {
+            state.running = state.running.saturating_sub(1);
+        }
+
+        self.queue.changed.notify_waiters();
+    }
+}
+
+// A place in line, given up if the request stops waiting on it--cancelled, or dropped partway
+struct Ticket<'a> {
+    queue: &'a CompletionQueue,
+    provider: String,
+    ticket: u64,
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        let mut providers = self.queue.lock();
+        if let Some(state) = providers.get_mut(&self.provider) {
+            state.waiting.retain(|(_, t)| *t != self.ticket);
+        }
+
+        self.queue.changed.notify_waiters();
+    }
+}
+
+impl CompletionQueue {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            providers: Mutex::new(HashMap::new()),
+            changed: tokio::sync::Notify::new(),
+            tickets: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, ProviderState>> {
+        self.providers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Waits until a slot for `provider` is free and it's this request's turn
+    // A set `cancel` gives up the place in line
+    pub async fn acquire(
+        &self,
+        provider: &str,
+        priority: Priority,
+        cancel: &AtomicBool,
+    ) -> Result<Slot<'_>, std::io::Error> {
+        let ticket = Ticket {
+            queue: self,
+            provider: provider.to_string(),
+            ticket: self.tickets.fetch_add(1, Ordering::SeqCst),
+        };
+        let limit = self.limits.get(provider);
+
+        self.lock()
+            .entry(provider.to_string())
+            .or_default()
+            .waiting
+            .push((priority, ticket.ticket));
+
+        loop {
+            // Registered before checking, so a slot freed in between still wakes this up
+            let changed = self.changed.notified();
+
+            {
+                let mut providers = self.lock();
+                let state = providers.get_mut(provider).unwrap();
+                if state.running < limit && state.next() == Some(ticket.ticket) {
+                    state.waiting.retain(|(_, t)| *t != ticket.ticket);
+                    state.running += 1;
+                    drop(providers);
+
+                    // Anyone else with a free slot to take
+                    drop(ticket);
+
+                    return Ok(Slot {
+                        queue: self,
+                        provider: provider.to_string(),
+                    });
+                }
+            }
+
+            if cancel.load(Ordering::SeqCst) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Interrupted,
+                    "Request cancelled while queued",
+                ));
+            }
+
+            let _ = tokio::time::timeout(CANCEL_CHECK, changed).await;
+        }
+    }
+
+    // Every provider that's had a request, by name
+    pub fn status(&self) -> Vec<ProviderQueueStatus> {
+        let providers = self.lock();
+        let mut status = providers
+            .iter()
+            .map(|(provider, state)| ProviderQueueStatus {
+                provider: provider.clone(),
+                limit: self.limits.get(provider),
+                running: state.running,
+                interactive: state
+                    .waiting
+                    .iter()
+                    .filter(|(p, _)| *p == Priority::Interactive)
+                    .count(),
+                background: state
+                    .waiting
+                    .iter()
+                    .filter(|(p, _)| *p == Priority::Background)
+                    .count(),
+            })
+            .collect::<Vec<_>>();
+
+        status.sort_by(|a, b| a.provider.cmp(&b.provider));
+        status
+    }
+}
+
+static QUEUE: std::sync::OnceLock<CompletionQueue> = std::sync::OnceLock::new();
+
+pub fn global() -> &'static CompletionQueue {
+    QUEUE.get_or_init(|| CompletionQueue::new(Limits::from_env()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits::parse("");
+        assert_eq!(limits.get("openai"), DEFAULT_LIMIT);
+        assert_eq!(limits.get("local"), LOCAL_LIMIT);
+
+        let limits = Limits::parse("2, anthropic=1, local=3, groq=zero");
+        assert_eq!(limits.get("openai"), 2);
+        assert_eq!(limits.get("anthropic"), 1);
+        assert_eq!(limits.get("local"), 3);
+        assert_eq!(limits.get("groq"), 2);
+
+        // There's always at least one slot
+        assert_eq!(Limits::parse("0").get("openai"), 1);
+    }
+
+    fn openai(queue: &CompletionQueue) -> ProviderQueueStatus {
+        queue
+            .status()
+            .into_iter()
+            .find(|s| s.provider == "openai")
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_priority() {
+        let queue = std::sync::Arc::new(CompletionQueue::new(Limits::parse("1")));
+        let cancel = AtomicBool::new(false);
+        let first = queue
+            .acquire("openai", Priority::Interactive, &cancel)
+            .await
+            .unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let waiting = |priority: Priority| {
+            let queue = std::sync::Arc::clone(&queue);
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let slot = queue
+                    .acquire("openai", priority, &AtomicBool::new(false))
+                    .await
+                    .unwrap();
+                tx.send(priority).unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                drop(slot);
+            })
+        };
+
+        let waits = |count: usize| {
+            let queue = std::sync::Arc::clone(&queue);
+            async move {
+                while openai(&queue).interactive + openai(&queue).background < count {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        };
+
+        // Queued first, but it's only background work
+        let background = waiting(Priority::Background);
+        waits(1).await;
+        let interactive = waiting(Priority::Interactive);
+        waits(2).await;
+
+        let status = openai(&queue);
+        assert_eq!(status.running, 1);
+        assert_eq!(status.interactive, 1);
+        assert_eq!(status.background, 1);
+
+        // Other providers aren't held up
+        let other = queue
+            .acquire("anthropic", Priority::Background, &cancel)
+            .await;
+        assert!(other.is_ok());
+        drop(other);
+
+        drop(first);
+        assert_eq!(rx.recv().await.unwrap(), Priority::Interactive);
+        assert_eq!(rx.recv().await.unwrap(), Priority::Background);
+        background.await.unwrap();
+        interactive.await.unwrap();
+
+        // Cancelled requests leave the line
+        let _held = queue
+            .acquire("openai", Priority::Interactive, &cancel)
+            .await
+            .unwrap();
+        let cancelled = AtomicBool::new(true);
+        assert!(queue
+            .acquire("openai", Priority::Interactive, &cancelled)
+            .await
+            .is_err());
+        assert_eq!(openai(&queue).interactive, 0);
+
+        // So do requests that stop waiting on their own
+        let abandoned = tokio::time::timeout(
+            Duration::from_millis(20),
+            queue.acquire("openai", Priority::Interactive, &cancel),
+        )
+        .await;
+        assert!(abandoned.is_err());
+        assert_eq!(openai(&queue).interactive, 0);
+    }
+}
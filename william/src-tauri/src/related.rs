@@ -0,0 +1,192 @@
+use rusqlite::params;
+
+use chamber_common::{lprint, ChamberError, Logger};
+
+use crate::db;
+use crate::types::*;
+
+// Follow-ups: a new conversation whose first message is close to memories from a recent one
+// gets linked to it, so a topic spread over a few conversations can be found from any of them
+//
+// Links go both ways, and are found through the Dewey results the first completion already
+// turns up--each memory is tagged with the conversation it came from (see `conversation_tag`)
+// Purged conversations take their links with them
+
+// How close the first message has to be to one of a conversation's memories
+const RELATED_THRESHOLD: f32 = 0.7;
+// How recently the other conversation has to have been active
+const RECENT_DAYS: i64 = 14;
+// Links made per conversation, closest first
+const MAX_RELATED: usize = 3;
+
+// Which conversation a memory came from, by its tag
+fn tagged_conversation(source: &dewey_lib::EmbeddingSource) -> Option<i64> {
+    source
+        .meta
+        .iter()
+        .find_map(|tag| tag.strip_prefix("conversation:")?.parse::<i64>().ok())
+}
+
+// Links `conversation_id` to the recent conversations behind the memories in `results`,
+// returning how many were linked
+pub fn link(
+    conversation_id: i64,
+    results: &[dewey_lib::QueryResult],
+    db: &rusqlite::Connection,
+) -> Result<usize, ChamberError> {
+    // The closest memory of each conversation
+    let mut candidates: Vec<(i64, f32)> = Vec::new();
+    for result in results.iter() {
+        let similarity = result.similarity();
+        if similarity < RELATED_THRESHOLD {
+            continue;
+        }
+
+        let related_id = match tagged_conversation(&result.source) {
+            Some(id) if id != conversation_id => id,
+            _ => continue,
+        };
+
+        match candidates.iter_mut().find(|(id, _)| *id == related_id) {
+            Some(c) => c.1 = c.1.max(similarity),
+            None => candidates.push((related_id, similarity)),
+        }
+    }
+
+    candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut linked = 0;
+    for (related_id, similarity) in candidates {
+        if linked >= MAX_RELATED {
+            break;
+        }
+
+        linked += db::execute(
+            db,
+            "
+            INSERT OR IGNORE INTO related_conversations (conversation_id, related_id, similarity, date_created)
+            SELECT ?1, c.id, ?3, CURRENT_TIMESTAMP
+            FROM conversations c
+            WHERE c.id = ?2
+            AND c.deleted_at IS NULL
+            AND c.last_updated >= datetime('now', ?4)
+            ",
+            params![
+                conversation_id,
+                related_id,
+                similarity,
+                format!("-{} days", RECENT_DAYS)
+            ],
+        )?;
+    }
+
+    if linked > 0 {
+        lprint!(
+            info,
+            "Linked conversation {} to {} related conversations",
+            conversation_id,
+            linked
+        );
+    }
+
+    Ok(linked)
+}
+
+// Conversations linked to `conversation_id` either way, closest first
+// Anything in the trash is left out
+pub fn list(
+    conversation_id: i64,
+    db: &rusqlite::Connection,
+) -> Result<Vec<RelatedConversation>, ChamberError> {
+    db::query_map(
+        db,
+        "
+        SELECT c.id, c.name, r.similarity
+        FROM related_conversations r
+        JOIN conversations c
+            ON c.id = CASE WHEN r.conversation_id = ?1 THEN r.related_id ELSE r.conversation_id END
+        WHERE (r.conversation_id = ?1 OR r.related_id = ?1)
+        AND c.deleted_at IS NULL
+        ORDER BY r.similarity DESC, c.id
+        ",
+        params![conversation_id],
+        |row| {
+            Ok(RelatedConversation {
+                conversation_id: row.get(0)?,
+                name: row.get(1)?,
+                similarity: row.get(2)?,
+            })
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(tag: &str, similarity: f32) -> dewey_lib::QueryResult {
+        dewey_lib::QueryResult {
+            source: dewey_lib::EmbeddingSource {
+                filepath: String::new(),
+                meta: std::collections::HashSet::from([tag.to_string()]),
+                subset: None,
+            },
+            distance: 1.0 - similarity,
+            rank: 0,
+            score: similarity,
+        }
+    }
+
+    #[test]
+    fn test_link() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "
+            CREATE TABLE conversations (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                last_updated TIMESTAMP NOT NULL,
+                deleted_at TIMESTAMP
+            );
+            CREATE TABLE related_conversations (
+                conversation_id INTEGER NOT NULL,
+                related_id INTEGER NOT NULL,
+                similarity REAL NOT NULL,
+                date_created TIMESTAMP NOT NULL,
+                PRIMARY KEY (conversation_id, related_id)
+            );
+            INSERT INTO conversations (id, name, last_updated) VALUES
+                (1, 'new', CURRENT_TIMESTAMP),
+                (2, 'recent', CURRENT_TIMESTAMP),
+                (3, 'old', datetime('now', '-60 days')),
+                (4, 'also recent', CURRENT_TIMESTAMP);
+            UPDATE conversations SET deleted_at = CURRENT_TIMESTAMP WHERE id = 4;
+            ",
+        )
+        .unwrap();
+
+        let sources = vec![
+            source("conversation:2", 0.75),
+            source("conversation:2", 0.9),
+            source("conversation:3", 0.95),
+            source("conversation:4", 0.95),
+            source("conversation:1", 1.0),
+            source("document", 1.0),
+        ];
+
+        // Too old, in the trash, itself, or not from a conversation at all
+        assert_eq!(link(1, &sources, &db).unwrap(), 1);
+
+        let related = list(1, &db).unwrap();
+        assert_eq!(related.len(), 1);
+        assert_eq!(related[0].conversation_id, 2);
+        assert!((related[0].similarity - 0.9).abs() < 1e-6);
+
+        // Seen from the other side too
+        assert_eq!(list(2, &db).unwrap()[0].conversation_id, 1);
+
+        // Nothing close enough
+        let sources = vec![source("conversation:2", 0.5), source("conversation:2", 0.5)];
+        assert_eq!(link(4, &sources, &db).unwrap(), 0);
+    }
+}
@@ -0,0 +1,286 @@
+use rusqlite::params;
+
+use chamber_common::{lprint, Logger};
+
+use crate::attachments;
+use crate::extract;
+use crate::types::*;
+
+// Git repositories indexed so conversations can pull in code as references
+//
+// Repositories are walked with the `ignore` crate, so whatever git ignores (build output,
+// vendored dependencies, etc.) is left out here too
+// Unlike watched folders, a repository's chunks only show up in conversations that opt in
+// through `ConversationOverrides::repositories`
+//
+// `repository_files` tracks the hash each file was last chunked at, so re-indexing only
+// re-embeds what changed
+// Chunks live at `<dir>/<repository ID>/<hash of the file's relative path>/<chunk index>.txt`
+
+// Source files past this are almost always generated
+pub const MAX_FILE_BYTES: u64 = 1024 * 1024;
+
+// Repositories with an indexing pass running, so a second `IndexRepo` doesn't start another
+static INDEXING: std::sync::OnceLock<std::sync::Mutex<std::collections::HashSet<i64>>> =
+    std::sync::OnceLock::new();
+
+fn indexing() -> std::sync::MutexGuard<'static, std::collections::HashSet<i64>> {
+    INDEXING
+        .get_or_init(|| std::sync::Mutex::new(std::collections::HashSet::new()))
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Whether the caller gets to index the repository--false if it's already being indexed
+pub fn start_indexing(id: i64) -> bool {
+    indexing().insert(id)
+}
+
+pub fn finish_indexing(id: i64) {
+    indexing().remove(&id);
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceFile {
+    pub path: std::path::PathBuf,
+    // Relative to the repository root, which is how the model sees it
+    pub relative_path: String,
+}
+
+// Every text/source file in the repository that git wouldn't ignore
+// Hidden files and directories (`.git` included) are skipped
+pub fn walk(root: &std::path::Path) -> Vec<SourceFile> {
+    let mut files = Vec::new();
+    for entry in ignore::WalkBuilder::new(root).build() {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                lprint!(error, "Error walking {}: {}; skipping", root.display(), e);
+                continue;
+            }
+        };
+
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy().to_string();
+        if extract::Backend::for_attachment(&name, "") != Some(extract::Backend::PlainText) {
+            continue;
+        }
+
+        if entry.metadata().map_or(true, |m| m.len() > MAX_FILE_BYTES) {
+            continue;
+        }
+
+        let relative_path = match entry.path().strip_prefix(root) {
+            Ok(p) => p.to_string_lossy().to_string(),
+            Err(_) => continue,
+        };
+
+        files.push(SourceFile {
+            path: entry.path().to_path_buf(),
+            relative_path,
+        });
+    }
+
+    files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+
+    files
+}
+
+pub fn chunk_dir(dir: &std::path::Path, id: i64, relative_path: &str) -> std::path::PathBuf {
+    dir.join(id.to_string())
+        .join(attachments::hash(relative_path.as_bytes()))
+}
+
+pub fn chunk_path(
+    dir: &std::path::Path,
+    id: i64,
+    relative_path: &str,
+    index: usize,
+) -> std::path::PathBuf {
+    chunk_dir(dir, id, relative_path).join(format!("{}.txt", index))
+}
+
+const REPOSITORY_SELECT: &str = "
+    SELECT r.id, r.name, r.path, COUNT(f.id), r.last_indexed
+    FROM repositories r
+    LEFT JOIN repository_files f ON f.repository_id = r.id
+";
+
+fn read_repository(row: &rusqlite::Row) -> rusqlite::Result<Repository> {
+    let id: i64 = row.get(0)?;
+    Ok(Repository {
+        id,
+        name: row.get(1)?,
+        path: row.get(2)?,
+        files: row.get(3)?,
+        last_indexed: row.get(4)?,
+        indexing: indexing().contains(&id),
+    })
+}
+
+pub fn list(db: &rusqlite::Connection) -> rusqlite::Result<Vec<Repository>> {
+    let mut query = db.prepare(&format!(
+        "{} GROUP BY r.id ORDER BY r.name COLLATE NOCASE",
+        REPOSITORY_SELECT
+    ))?;
+    let repositories = query
+        .query_map(params![], read_repository)?
+        .collect::<rusqlite::Result<Vec<Repository>>>()?;
+
+    Ok(repositories)
+}
+
+// Adds the repository at `path` if it isn't already
+pub fn add(path: &str, db: &rusqlite::Connection) -> Result<Repository, std::io::Error> {
+    let path = std::path::Path::new(path.trim()).canonicalize()?;
+    if !path.join(".git").exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} isn't a git repository", path.display()),
+        ));
+    }
+
+    let name = path
+        .file_name()
+        .map_or_else(|| path.to_string_lossy(), |n| n.to_string_lossy())
+        .to_string();
+    let path = path.to_string_lossy().to_string();
+
+    let to_io = |e: rusqlite::Error| std::io::Error::other(e.to_string());
+    db.execute(
+        "INSERT OR IGNORE INTO repositories (name, path, date_created) VALUES (?1, ?2, CURRENT_TIMESTAMP)",
+        params![name, path],
+    )
+    .map_err(to_io)?;
+
+    db.query_row(
+        &format!("{} WHERE r.path = ?1 GROUP BY r.id", REPOSITORY_SELECT),
+        params![path],
+        read_repository,
+    )
+    .map_err(to_io)
+}
+
+// Relative path -> (hash, chunk count) of every file indexed from the repository
+pub fn ledger(
+    id: i64,
+    db: &rusqlite::Connection,
+) -> rusqlite::Result<std::collections::HashMap<String, (String, usize)>> {
+    let mut query = db.prepare(
+        "SELECT relative_path, hash, chunks FROM repository_files WHERE repository_id = ?1",
+    )?;
+
+    let ledger = query
+        .query_map(params![id], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                (row.get::<_, String>(1)?, row.get::<_, i64>(2)? as usize),
+            ))
+        })?
+        .collect::<rusqlite::Result<std::collections::HashMap<_, _>>>()?;
+
+    Ok(ledger)
+}
+
+pub fn record(
+    id: i64,
+    relative_path: &str,
+    hash: &str,
+    chunks: usize,
+    db: &rusqlite::Connection,
+) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT INTO repository_files (repository_id, relative_path, hash, chunks)
+         VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (repository_id, relative_path) DO UPDATE SET
+            hash = excluded.hash,
+            chunks = excluded.chunks",
+        params![id, relative_path, hash, chunks as i64],
+    )?;
+
+    Ok(())
+}
+
+pub fn forget(id: i64, relative_path: &str, db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute(
+        "DELETE FROM repository_files WHERE repository_id = ?1 AND relative_path = ?2",
+        params![id, relative_path],
+    )?;
+    Ok(())
+}
+
+pub fn mark_indexed(id: i64, db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute(
+        "UPDATE repositories SET last_indexed = CURRENT_TIMESTAMP WHERE id = ?1",
+        params![id],
+    )?;
+    Ok(())
+}
+
+// The chunk files of everything indexed from the repositories in `ids`
+pub fn chunk_files(
+    dir: &std::path::Path,
+    ids: &[i64],
+    db: &rusqlite::Connection,
+) -> rusqlite::Result<Vec<String>> {
+    let mut files = Vec::new();
+    for &id in ids {
+        for (relative_path, (_, chunks)) in ledger(id, db)? {
+            files.extend((0..chunks).map(|i| {
+                chunk_path(dir, id, &relative_path, i)
+                    .to_string_lossy()
+                    .to_string()
+            }));
+        }
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_walk() {
+        let root = std::env::temp_dir().join(format!("william-repo-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join("target")).unwrap();
+
+        std::fs::write(root.join(".gitignore"), "target/\n*.log\n").unwrap();
+        std::fs::write(root.join("README.md"), "# project").unwrap();
+        std::fs::write(root.join("src").join("main.rs"), "fn main() {}").unwrap();
+        std::fs::write(root.join("src").join("logo.png"), [0u8; 4]).unwrap();
+        std::fs::write(root.join("target").join("out.rs"), "// generated").unwrap();
+        std::fs::write(root.join("debug.log"), "noise").unwrap();
+        std::fs::write(root.join(".git").join("config.toml"), "[core]").unwrap();
+
+        let files = walk(&root)
+            .into_iter()
+            .map(|f| f.relative_path)
+            .collect::<Vec<_>>();
+        assert_eq!(files, vec!["README.md", "src/main.rs"]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_chunk_paths() {
+        let dir = std::path::Path::new("/data/repositories");
+        let path = chunk_path(dir, 3, "src/main.rs", 2);
+        assert!(path.starts_with(dir.join("3")));
+        assert!(path.starts_with(chunk_dir(dir, 3, "src/main.rs")));
+        assert!(path.ends_with("2.txt"));
+
+        // The same file in two repositories doesn't collide
+        assert_ne!(
+            chunk_dir(dir, 3, "src/main.rs"),
+            chunk_dir(dir, 4, "src/main.rs")
+        );
+    }
+}
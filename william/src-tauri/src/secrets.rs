@@ -0,0 +1,243 @@
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use chamber_common::{get_config_dir, lprint, Logger};
+
+// API keys are encrypted at rest with AES-256-GCM
+// The encryption key is derived from the machine ID + a random salt kept in the config directory,
+// so the database on its own (backups, copies on another machine, etc.) doesn't give up any keys
+//
+// TODO: OS keychain integration (Keychain, Secret Service, Credential Manager)
+
+// Stored values look like `enc:v1:<base64(nonce + ciphertext + tag)>`
+// Anything without the prefix is a plaintext key from before encryption
+const PREFIX: &str = "enc:v1:";
+const SALT_LEN: usize = 32;
+
+pub struct KeyStore {
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl KeyStore {
+    pub fn new() -> Result<Self, std::io::Error> {
+        let salt = load_or_create_salt(&get_config_dir().join("keysalt"))?;
+        let machine_id = match machine_id() {
+            Ok(id) => id,
+            Err(e) => {
+                lprint!(
+                    error,
+                    "Error reading machine ID: {}; deriving the key from the salt alone",
+                    e
+                );
+                Vec::new()
+            }
+        };
+
+        Ok(Self::from_parts(&machine_id, &salt))
+    }
+
+    fn from_parts(machine_id: &[u8], salt: &[u8]) -> Self {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(machine_id);
+        let okm = prk
+            .expand(&[b"william api keys"], &AES_256_GCM)
+            .expect("HKDF output length is fixed by the algorithm");
+
+        Self {
+            key: LessSafeKey::new(UnboundKey::from(okm)),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    // Empty keys are left as-is--there's nothing to hide,
+    // and it keeps the "is this provider configured" checks simple
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, std::io::Error> {
+        if plaintext.is_empty() {
+            return Ok(String::new());
+        }
+
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| std::io::Error::other("error generating nonce"))?;
+
+        let mut in_out = plaintext.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut in_out,
+            )
+            .map_err(|_| std::io::Error::other("error encrypting key"))?;
+
+        let mut blob = nonce.to_vec();
+        blob.extend_from_slice(&in_out);
+
+        Ok(format!(
+            "{}{}",
+            PREFIX,
+            base64::engine::general_purpose::STANDARD.encode(blob)
+        ))
+    }
+
+    pub fn decrypt(&self, stored: &str) -> Result<String, std::io::Error> {
+        let encoded = match stored.strip_prefix(PREFIX) {
+            Some(e) => e,
+            None => return Ok(stored.to_string()),
+        };
+
+        let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+
+        let mut blob = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|_| invalid("stored key isn't valid base64"))?;
+
+        if blob.len() < NONCE_LEN {
+            return Err(invalid("stored key is too short"));
+        }
+
+        let mut in_out = blob.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&blob).map_err(|_| invalid("bad nonce"))?;
+
+        // This fails if the salt or machine ID changed, or the value was tampered with
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .map_err(|_| invalid("stored key can't be decrypted on this machine"))?;
+
+        String::from_utf8(plaintext.to_vec()).map_err(|_| invalid("decrypted key isn't UTF-8"))
+    }
+}
+
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(PREFIX)
+}
+
+// Shared key store, created on first use
+pub fn keystore() -> &'static KeyStore {
+    static KEYSTORE: std::sync::OnceLock<KeyStore> = std::sync::OnceLock::new();
+    KEYSTORE.get_or_init(|| match KeyStore::new() {
+        Ok(k) => k,
+        Err(e) => {
+            lprint!(error, "Error initializing key store: {}", e);
+            panic!("William can't store API keys without a key store! Shutting down.");
+        }
+    })
+}
+
+fn load_or_create_salt(path: &std::path::PathBuf) -> Result<Vec<u8>, std::io::Error> {
+    match std::fs::read(path) {
+        Ok(salt) if salt.len() == SALT_LEN => return Ok(salt),
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("malformed key salt at {}", path.display()),
+            ));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    };
+
+    let mut salt = vec![0u8; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| std::io::Error::other("error generating salt"))?;
+
+    std::fs::write(path, &salt)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    lprint!(info, "Created key salt at {}", path.display());
+
+    Ok(salt)
+}
+
+#[cfg(target_os = "linux")]
+fn machine_id() -> Result<Vec<u8>, std::io::Error> {
+    std::fs::read_to_string("/etc/machine-id")
+        .or_else(|_| std::fs::read_to_string("/var/lib/dbus/machine-id"))
+        .map(|id| id.trim().as_bytes().to_vec())
+}
+
+#[cfg(target_os = "macos")]
+fn machine_id() -> Result<Vec<u8>, std::io::Error> {
+    let output = std::process::Command::new("ioreg")
+        .args(["-rd1", "-c", "IOPlatformExpertDevice"])
+        .output()?;
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find(|l| l.contains("IOPlatformUUID"))
+        .and_then(|l| l.split('"').nth(3))
+        .map(|id| id.as_bytes().to_vec())
+        .ok_or(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "IOPlatformUUID not found",
+        ))
+}
+
+#[cfg(target_os = "windows")]
+fn machine_id() -> Result<Vec<u8>, std::io::Error> {
+    let output = std::process::Command::new("reg")
+        .args([
+            "query",
+            r"HKLM\SOFTWARE\Microsoft\Cryptography",
+            "/v",
+            "MachineGuid",
+        ])
+        .output()?;
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find(|l| l.contains("MachineGuid"))
+        .and_then(|l| l.split_whitespace().last())
+        .map(|id| id.as_bytes().to_vec())
+        .ok_or(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "MachineGuid not found",
+        ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+fn machine_id() -> Result<Vec<u8>, std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "no machine ID on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let store = KeyStore::from_parts(b"machine", &[7u8; SALT_LEN]);
+
+        let encrypted = store.encrypt("sk-test").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.contains("sk-test"));
+        assert_eq!(store.decrypt(&encrypted).unwrap(), "sk-test");
+
+        // Fresh nonce each time
+        assert_ne!(store.encrypt("sk-test").unwrap(), encrypted);
+
+        assert_eq!(store.encrypt("").unwrap(), "");
+        assert_eq!(store.decrypt("sk-plaintext").unwrap(), "sk-plaintext");
+    }
+
+    #[test]
+    fn test_wrong_machine_fails() {
+        let store = KeyStore::from_parts(b"machine", &[7u8; SALT_LEN]);
+        let other = KeyStore::from_parts(b"other machine", &[7u8; SALT_LEN]);
+
+        let encrypted = store.encrypt("sk-test").unwrap();
+        assert!(other.decrypt(&encrypted).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+// Response sequencing, so a client that loses its connection can pick back up where it left off
+//
+// Each connection gets a session, announced with a `Session` response as soon as it opens
+// Every response after that is stamped with a `seq` one higher than the last, and kept around
+// (up to `MAX_REPLAY_BYTES`) in case it needs to be sent again
+//
+// A client that reconnects sends `Resume` with the session ID and the last `seq` it saw,
+// and gets everything it missed replayed with the original numbers--plus whatever the session's
+// completions streamed while nobody was connected, since those wait for the client
+// to come back (see `RESUME_GRACE` in lib.rs)
+//
+// Sessions live on their connection--see `Connection` in lib.rs
+
+const MAX_REPLAY_BYTES: usize = 4 * 1024 * 1024;
+
+pub struct Session {
+    pub id: String,
+    next_seq: u64,
+    // Oldest first
+    sent: std::collections::VecDeque<(u64, String)>,
+    sent_bytes: usize,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            id: uuid::Uuid::new_v4().to_string(),
+            next_seq: 1,
+            sent: std::collections::VecDeque::new(),
+            sent_bytes: 0,
+        }
+    }
+
+    // Adds the next `seq` to a serialized response, keeping a copy for replay
+    pub fn stamp(&mut self, response: String) -> String {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        // Responses are always JSON objects
+        let stamped = match response.strip_prefix('{') {
+            Some(rest) if rest.starts_with('}') => format!("{{\"seq\":{}{}", seq, rest),
+            Some(rest) => format!("{{\"seq\":{},{}", seq, rest),
+            None => response,
+        };
+
+        self.sent_bytes += stamped.len();
+        self.sent.push_back((seq, stamped.clone()));
+        while self.sent_bytes > MAX_REPLAY_BYTES {
+            match self.sent.pop_front() {
+                Some((_, r)) => self.sent_bytes -= r.len(),
+                None => break,
+            }
+        }
+
+        stamped
+    }
+
+    // Everything sent after `last_seen`, and whether that's all of it--
+    // the oldest responses are dropped once there's too much kept
+    pub fn replay(&self, last_seen: u64) -> (Vec<String>, bool) {
+        let missed = self
+            .sent
+            .iter()
+            .filter(|(seq, _)| *seq > last_seen)
+            .map(|(_, r)| r.clone())
+            .collect::<Vec<_>>();
+
+        let first_kept = self.sent.front().map_or(self.next_seq, |(seq, _)| *seq);
+        (missed, last_seen + 1 >= first_kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stamp_and_replay() {
+        let mut session = Session::new();
+        assert_eq!(
+            session.stamp("{\"method\":\"Ping\"}".to_string()),
+            "{\"seq\":1,\"method\":\"Ping\"}"
+        );
+        session.stamp("{\"method\":\"Completion\"}".to_string());
+        session.stamp("{\"method\":\"CompletionEnd\"}".to_string());
+
+        let (missed, complete) = session.replay(1);
+        assert!(complete);
+        assert_eq!(missed.len(), 2);
+        assert!(missed[0].starts_with("{\"seq\":2,"));
+
+        let (missed, complete) = session.replay(3);
+        assert!(complete);
+        assert!(missed.is_empty());
+
+        // Only so much is kept
+        let big = format!("{{\"payload\":\"{}\"}}", "x".repeat(MAX_REPLAY_BYTES / 2));
+        for _ in 0..3 {
+            session.stamp(big.clone());
+        }
+
+        let (missed, complete) = session.replay(0);
+        assert!(!complete);
+        assert!(missed.len() < 6);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::types::*;
+
+// Bumped whenever a field is renamed or removed--new fields just default
+pub const SETTINGS_VERSION: u32 = 1;
+
+// Default filename when the export path is a directory
+pub const SETTINGS_FILENAME: &str = "william-settings.json";
+
+// Everything in the user config that's worth bringing to another machine
+//
+// API keys are left out unless they're asked for, since the file is plaintext
+// Importing a file without keys keeps whatever keys are already set
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SettingsFile {
+    pub version: u32,
+    #[serde(rename = "systemPrompt")]
+    pub system_prompt: String,
+    #[serde(rename = "maxRetries")]
+    pub max_retries: u32,
+    #[serde(rename = "searchFusion")]
+    pub search_fusion: FusionStrategy,
+    #[serde(rename = "localEndpoint", default)]
+    pub local_endpoint: String,
+    #[serde(rename = "trashRetentionDays")]
+    pub trash_retention_days: u32,
+    #[serde(
+        rename = "emptyConversationHours",
+        default = "default_empty_conversation_hours"
+    )]
+    pub empty_conversation_hours: u32,
+    #[serde(rename = "maxMessageChars", default = "default_max_message_chars")]
+    pub max_message_chars: u32,
+    #[serde(rename = "referenceThreshold", default = "default_reference_threshold")]
+    pub reference_threshold: f32,
+    #[serde(default)]
+    pub moderation: ModerationProvider,
+    #[serde(rename = "moderationBlock", default)]
+    pub moderation_block: bool,
+    #[serde(rename = "apiKeys", default, skip_serializing_if = "Option::is_none")]
+    pub api_keys: Option<APIKeys>,
+}
+
+impl SettingsFile {
+    pub fn from_config(config: &UserConfig, include_keys: bool) -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            system_prompt: config.system_prompt.clone(),
+            max_retries: config.max_retries,
+            search_fusion: config.search_fusion,
+            local_endpoint: config.local_endpoint.clone(),
+            trash_retention_days: config.trash_retention_days,
+            empty_conversation_hours: config.empty_conversation_hours,
+            max_message_chars: config.max_message_chars,
+            reference_threshold: config.reference_threshold,
+            moderation: config.moderation,
+            moderation_block: config.moderation_block,
+            api_keys: if include_keys {
+                Some(config.api_keys.clone())
+            } else {
+                None
+            },
+        }
+    }
+
+    // `config` with these settings in place of its own
+    pub fn apply(self, config: &UserConfig) -> UserConfig {
+        UserConfig {
+            write: true,
+            api_keys: self.api_keys.unwrap_or_else(|| config.api_keys.clone()),
+            system_prompt: self.system_prompt,
+            max_retries: self.max_retries,
+            search_fusion: self.search_fusion,
+            local_endpoint: self.local_endpoint,
+            trash_retention_days: self.trash_retention_days,
+            empty_conversation_hours: self.empty_conversation_hours,
+            max_message_chars: self.max_message_chars,
+            reference_threshold: self.reference_threshold,
+            moderation: self.moderation,
+            moderation_block: self.moderation_block,
+            // Where this machine listens isn't worth carrying over
+            bind_address: config.bind_address.clone(),
+            port: config.port,
+        }
+    }
+}
+
+// Matches the user config's default, for files from before the setting existed
+fn default_empty_conversation_hours() -> u32 {
+    24
+}
+
+fn default_max_message_chars() -> u32 {
+    20000
+}
+
+fn default_reference_threshold() -> f32 {
+    0.25
+}
+
+pub fn render(settings: &SettingsFile) -> Result<String, std::io::Error> {
+    serde_json::to_string_pretty(settings)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+pub fn read(path: &std::path::Path) -> Result<SettingsFile, std::io::Error> {
+    let contents = std::fs::read_to_string(path)?;
+    parse(&contents)
+}
+
+fn parse(contents: &str) -> Result<SettingsFile, std::io::Error> {
+    let settings: SettingsFile = serde_json::from_str(contents)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    if settings.version > SETTINGS_VERSION {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "settings file is version {}, but only up to {} is supported",
+                settings.version, SETTINGS_VERSION
+            ),
+        ));
+    }
+
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> UserConfig {
+        UserConfig {
+            write: false,
+            api_keys: APIKeys {
+                openai: "sk-test".to_string(),
+                groq: String::new(),
+                grok: String::new(),
+                anthropic: "sk-ant-test".to_string(),
+                gemini: String::new(),
+                deepseek: String::new(),
+                together: String::new(),
+                fireworks: String::new(),
+            },
+            system_prompt: "be brief".to_string(),
+            max_retries: 5,
+            search_fusion: FusionStrategy::Keyword,
+            local_endpoint: "http://localhost:11434".to_string(),
+            trash_retention_days: 7,
+            empty_conversation_hours: 48,
+            max_message_chars: 5000,
+            reference_threshold: 0.5,
+            moderation: ModerationProvider::Local,
+            moderation_block: true,
+            bind_address: "127.0.0.1".to_string(),
+            port: 9001,
+        }
+    }
+
+    #[test]
+    fn test_keys_only_when_included() {
+        let rendered = render(&SettingsFile::from_config(&config(), false)).unwrap();
+        assert!(!rendered.contains("sk-test"));
+        assert!(!rendered.contains("apiKeys"));
+
+        // Importing without keys keeps the ones already set
+        let mut existing = config();
+        existing.api_keys.openai = "sk-existing".to_string();
+        let imported = parse(&rendered).unwrap().apply(&existing);
+        assert_eq!(imported.api_keys.openai, "sk-existing");
+        assert_eq!(imported.system_prompt, "be brief");
+        assert_eq!(imported.search_fusion, FusionStrategy::Keyword);
+        assert_eq!(imported.trash_retention_days, 7);
+        assert_eq!(imported.empty_conversation_hours, 48);
+        assert_eq!(imported.max_message_chars, 5000);
+        assert_eq!(imported.reference_threshold, 0.5);
+        assert_eq!(imported.moderation, ModerationProvider::Local);
+        assert!(imported.moderation_block);
+        assert!(imported.write);
+
+        let rendered = render(&SettingsFile::from_config(&config(), true)).unwrap();
+        let imported = parse(&rendered).unwrap().apply(&existing);
+        assert_eq!(imported.api_keys.openai, "sk-test");
+    }
+
+    #[test]
+    fn test_newer_versions_are_rejected() {
+        let mut settings = SettingsFile::from_config(&config(), false);
+        settings.version = SETTINGS_VERSION + 1;
+
+        assert!(parse(&render(&settings).unwrap()).is_err());
+        assert!(parse("{}").is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use rusqlite::params;
+
+use chamber_common::{lprint, Logger};
+
+use crate::types::*;
+
+// Named checkpoints of a conversation's current path
+//
+// A snapshot keeps the list of messages it was taken with, so it can be restored as a new branch
+// however far the conversation has moved on since--without a fork showing up in the
+// conversation list, or a branch for every checkpoint
+//
+// Messages deleted after the snapshot was taken are dropped from it--purging the trash leaves
+// snapshotted messages alone, even once they're off of every path
+
+const SNAPSHOT_SELECT: &str = "
+    SELECT s.id, s.conversation_id, s.branch_id, s.label, s.date_created,
+        (SELECT COUNT(*) FROM snapshot_messages sm WHERE sm.snapshot_id = s.id) AS messages
+    FROM snapshots s
+";
+
+fn read_snapshot(row: &rusqlite::Row) -> rusqlite::Result<Snapshot> {
+    Ok(Snapshot {
+        id: row.get("id")?,
+        conversation_id: row.get("conversation_id")?,
+        branch_id: row.get("branch_id")?,
+        label: row.get("label")?,
+        messages: row.get::<_, i64>("messages")? as usize,
+        date_created: row.get("date_created")?,
+    })
+}
+
+fn get(snapshot_id: i64, db: &rusqlite::Connection) -> rusqlite::Result<Snapshot> {
+    db.query_row(
+        &format!("{} WHERE s.id = ?1", SNAPSHOT_SELECT),
+        params![snapshot_id],
+        read_snapshot,
+    )
+}
+
+fn not_found(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, message)
+}
+
+fn db_error(e: rusqlite::Error) -> std::io::Error {
+    std::io::Error::other(e.to_string())
+}
+
+// Freezes the conversation's active branch under `label`
+pub fn take(
+    conversation_id: i64,
+    label: &str,
+    db: &rusqlite::Connection,
+) -> Result<Snapshot, std::io::Error> {
+    let label = label.trim();
+    if label.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Snapshots need a label",
+        ));
+    }
+
+    let branch_id = match db.query_row(
+        "SELECT active_branch_id FROM conversations WHERE id = ?1",
+        params![conversation_id],
+        |row| row.get::<_, Option<i64>>(0),
+    ) {
+        Ok(b) => b,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(not_found(format!(
+                "No conversation with ID {}",
+                conversation_id
+            )))
+        }
+        Err(e) => return Err(db_error(e)),
+    };
+
+    let tx = db.unchecked_transaction().map_err(db_error)?;
+    let snapshot_id = insert_snapshot(conversation_id, branch_id, label, &tx).map_err(db_error)?;
+    let snapshot = get(snapshot_id, &tx).map_err(db_error)?;
+    if snapshot.messages == 0 {
+        return Err(not_found(format!(
+            "No messages in conversation {} to snapshot",
+            conversation_id
+        )));
+    }
+
+    tx.commit().map_err(db_error)?;
+
+    lprint!(
+        info,
+        "Took snapshot {} of conversation {} ({} messages)",
+        snapshot.id,
+        conversation_id,
+        snapshot.messages
+    );
+
+    Ok(snapshot)
+}
+
+fn insert_snapshot(
+    conversation_id: i64,
+    branch_id: Option<i64>,
+    label: &str,
+    db: &rusqlite::Connection,
+) -> rusqlite::Result<i64> {
+    db.execute(
+        "INSERT INTO snapshots (conversation_id, branch_id, label, date_created) VALUES (?1, ?2, ?3, CURRENT_TIMESTAMP)",
+        params![conversation_id, branch_id, label],
+    )?;
+    let snapshot_id = db.last_insert_rowid();
+
+    db.execute(
+        "INSERT INTO snapshot_messages (snapshot_id, message_id, sequence)
+         SELECT ?1, message_id, sequence FROM paths
+         WHERE conversation_id = ?2 AND branch_id = ?3",
+        params![snapshot_id, conversation_id, branch_id],
+    )?;
+
+    Ok(snapshot_id)
+}
+
+pub fn list(conversation_id: i64, db: &rusqlite::Connection) -> rusqlite::Result<Vec<Snapshot>> {
+    let mut query = db.prepare(&format!(
+        "{} WHERE s.conversation_id = ?1 ORDER BY s.date_created DESC, s.id DESC",
+        SNAPSHOT_SELECT
+    ))?;
+    let snapshots = query
+        .query_map(params![conversation_id], read_snapshot)?
+        .collect::<rusqlite::Result<Vec<Snapshot>>>()?;
+
+    Ok(snapshots)
+}
+
+// Branches off from where the snapshot's branch was when it was taken, and switches to it
+// Returns the snapshot's conversation ID and the new branch's
+pub fn restore(snapshot_id: i64, db: &rusqlite::Connection) -> Result<(i64, i64), std::io::Error> {
+    let snapshot = match get(snapshot_id, db) {
+        Ok(s) => s,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(not_found(format!("No snapshot with ID {}", snapshot_id)))
+        }
+        Err(e) => return Err(db_error(e)),
+    };
+
+    if snapshot.messages == 0 {
+        return Err(not_found(format!(
+            "Every message in snapshot {} has since been deleted",
+            snapshot_id
+        )));
+    }
+
+    let tx = db.unchecked_transaction().map_err(db_error)?;
+    let branch_id = insert_branch(&snapshot, &tx).map_err(db_error)?;
+    tx.commit().map_err(db_error)?;
+
+    lprint!(
+        info,
+        "Restored snapshot {} of conversation {} as branch {}",
+        snapshot_id,
+        snapshot.conversation_id,
+        branch_id
+    );
+
+    Ok((snapshot.conversation_id, branch_id))
+}
+
+fn insert_branch(snapshot: &Snapshot, db: &rusqlite::Connection) -> rusqlite::Result<i64> {
+    let branch_id = create_branch(
+        db,
+        snapshot.conversation_id,
+        snapshot.branch_id,
+        Some(snapshot.messages as i64),
+    )?;
+
+    // Renumbered, in case any of the snapshot's messages have since been deleted
+    db.execute(
+        "INSERT INTO paths (conversation_id, branch_id, message_id, sequence)
+         SELECT ?1, ?2, sm.message_id,
+             (SELECT COUNT(*) FROM snapshot_messages e
+              WHERE e.snapshot_id = sm.snapshot_id AND e.sequence < sm.sequence)
+         FROM snapshot_messages sm
+         WHERE sm.snapshot_id = ?3",
+        params![snapshot.conversation_id, branch_id, snapshot.id],
+    )?;
+
+    db.execute(
+        "UPDATE conversations SET active_branch_id = ?2 WHERE id = ?1",
+        params![snapshot.conversation_id, branch_id],
+    )?;
+
+    Ok(branch_id)
+}
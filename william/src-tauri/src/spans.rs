@@ -0,0 +1,415 @@
+// Timing spans for profiling where requests spend their time
+//
+// A span covers everything between `root`/`span` and its guard being dropped
+// Spans nest per thread: one started while another is open on the same thread becomes its child
+// Threads spawned partway through a request pick the trace back up with `Context::current` + `enter`,
+// and tasks with `instrument`
+//
+// Built with the `otlp` feature, finished spans are batched and sent as OTLP/HTTP JSON
+// to `OTEL_EXPORTER_OTLP_ENDPOINT` (http://localhost:4318 by default), which Jaeger and the
+// OpenTelemetry collector both accept
+// Without it, spans only keep track of the current context and nothing is recorded
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Context {
+    pub trace_id: u128,
+    pub span_id: u64,
+}
+
+thread_local! {
+    static CURRENT: std::cell::Cell<Option<Context>> = const { std::cell::Cell::new(None) };
+}
+
+impl Context {
+    // The innermost open span on this thread
+    pub fn current() -> Option<Context> {
+        CURRENT.with(|c| c.get())
+    }
+}
+
+// Makes `context` the parent of spans started on this thread, until dropped
+pub struct ContextGuard {
+    previous: Option<Context>,
+}
+
+pub fn enter(context: Option<Context>) -> ContextGuard {
+    ContextGuard {
+        previous: CURRENT.with(|c| c.replace(context)),
+    }
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|c| c.set(self.previous));
+    }
+}
+
+// Runs `future` with the request's ID (if it's for one) and the trace entered,
+// whichever thread it's polled on
+//
+// Both are kept per thread, so a task can't just enter them once at the start--
+// it could be picked back up on another thread after waiting, leaving them behind on this one
+pub fn instrument<F: std::future::Future>(
+    request_id: Option<&str>,
+    context: Option<Context>,
+    future: F,
+) -> Instrumented<F> {
+    Instrumented {
+        request_id: request_id.map(|id| id.to_string()),
+        context,
+        future: Box::pin(future),
+    }
+}
+
+pub struct Instrumented<F> {
+    request_id: Option<String>,
+    context: Option<Context>,
+    future: std::pin::Pin<Box<F>>,
+}
+
+impl<F: std::future::Future> std::future::Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<F::Output> {
+        let this = &mut *self;
+        let _request = this
+            .request_id
+            .as_deref()
+            .map(chamber_common::RequestSpan::enter);
+        let previous = CURRENT.with(|c| c.replace(this.context));
+
+        let poll = this.future.as_mut().poll(cx);
+
+        // Spans the future still has open are picked back up on its next poll
+        this.context = CURRENT.with(|c| c.replace(previous));
+        poll
+    }
+}
+
+pub struct SpanGuard {
+    context: Context,
+    previous: Option<Context>,
+    #[cfg(feature = "otlp")]
+    record: Option<otlp::Record>,
+}
+
+impl SpanGuard {
+    fn start(name: &str, parent: Option<Context>) -> SpanGuard {
+        let context = Context {
+            trace_id: parent.map_or_else(rand::random, |p| p.trace_id),
+            span_id: rand::random(),
+        };
+
+        #[cfg(not(feature = "otlp"))]
+        let _ = name;
+
+        SpanGuard {
+            context,
+            previous: CURRENT.with(|c| c.replace(Some(context))),
+            #[cfg(feature = "otlp")]
+            record: otlp::Record::start(name, context, parent),
+        }
+    }
+
+    pub fn context(&self) -> Context {
+        self.context
+    }
+
+    pub fn attribute(&mut self, key: &'static str, value: impl ToString) {
+        #[cfg(feature = "otlp")]
+        if let Some(record) = self.record.as_mut() {
+            record.attributes.push((key, value.to_string()));
+        }
+
+        #[cfg(not(feature = "otlp"))]
+        let _ = (key, value);
+    }
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(record) = self.record.take() {
+            otlp::finish(record);
+        }
+
+        CURRENT.with(|c| c.set(self.previous));
+    }
+}
+
+// Starts a new trace, whatever's open on this thread
+pub fn root(name: &str) -> SpanGuard {
+    SpanGuard::start(name, None)
+}
+
+// A child of the innermost open span, or a new trace if there isn't one
+pub fn span(name: &str) -> SpanGuard {
+    SpanGuard::start(name, Context::current())
+}
+
+#[cfg(feature = "otlp")]
+pub mod otlp {
+    use chamber_common::{lprint, Logger};
+
+    use super::Context;
+
+    // Spans are sent once this many are waiting, or this long after the first one finished
+    const BATCH_SIZE: usize = 512;
+    const BATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+    pub struct Record {
+        pub name: String,
+        pub context: Context,
+        pub parent: Option<u64>,
+        pub start: std::time::SystemTime,
+        pub end: std::time::SystemTime,
+        pub attributes: Vec<(&'static str, String)>,
+    }
+
+    impl Record {
+        // Nothing's recorded until the exporter's running
+        pub fn start(name: &str, context: Context, parent: Option<Context>) -> Option<Record> {
+            SINK.get()?;
+
+            let now = std::time::SystemTime::now();
+            Some(Record {
+                name: name.to_string(),
+                context,
+                parent: parent.map(|p| p.span_id),
+                start: now,
+                end: now,
+                attributes: Vec::new(),
+            })
+        }
+    }
+
+    static SINK: std::sync::OnceLock<tokio::sync::mpsc::UnboundedSender<Record>> =
+        std::sync::OnceLock::new();
+
+    pub fn finish(mut record: Record) {
+        record.end = std::time::SystemTime::now();
+        if let Some(sink) = SINK.get() {
+            let _ = sink.send(record);
+        }
+    }
+
+    fn nanos(time: std::time::SystemTime) -> String {
+        time.duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos())
+            .to_string()
+    }
+
+    // An OTLP/HTTP JSON `ExportTraceServiceRequest`
+    pub fn encode(service: &str, records: &[Record]) -> serde_json::Value {
+        let spans = records
+            .iter()
+            .map(|r| {
+                let attributes = r
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| {
+                        serde_json::json!({
+                            "key": key,
+                            "value": { "stringValue": value },
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                let mut span = serde_json::json!({
+                    "traceId": format!("{:032x}", r.context.trace_id),
+                    "spanId": format!("{:016x}", r.context.span_id),
+                    "name": r.name,
+                    // SPAN_KIND_INTERNAL
+                    "kind": 1,
+                    "startTimeUnixNano": nanos(r.start),
+                    "endTimeUnixNano": nanos(r.end),
+                    "attributes": attributes,
+                });
+
+                if let Some(parent) = r.parent {
+                    span["parentSpanId"] = serde_json::json!(format!("{:016x}", parent));
+                }
+
+                span
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": service },
+                    }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "william" },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+
+    async fn export(client: &reqwest::Client, endpoint: &str, service: &str, records: &[Record]) {
+        let response = client
+            .post(endpoint)
+            .header("Content-Type", "application/json")
+            .body(encode(service, records).to_string())
+            .send()
+            .await;
+
+        match response {
+            Ok(r) if r.status().is_success() => {}
+            Ok(r) => {
+                lprint!(
+                    error,
+                    "Error exporting {} spans: {}; dropping them",
+                    records.len(),
+                    r.status()
+                );
+            }
+            Err(e) => {
+                lprint!(
+                    error,
+                    "Error exporting {} spans: {}; dropping them",
+                    records.len(),
+                    e
+                );
+            }
+        };
+    }
+
+    // Starts recording spans and sending them off in the background
+    pub fn start_exporter() {
+        let endpoint = format!(
+            "{}/v1/traces",
+            std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+                .unwrap_or_else(|_| "http://localhost:4318".to_string())
+                .trim_end_matches('/')
+        );
+        let service = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "william".to_string());
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Record>();
+        if SINK.set(tx).is_err() {
+            return;
+        }
+
+        lprint!(info, "Exporting spans to {}", endpoint);
+
+        tauri::async_runtime::spawn(async move {
+            let client = reqwest::Client::new();
+            let mut batch = Vec::new();
+            loop {
+                // Waits as long as it takes for the first span of a batch
+                let record = if batch.is_empty() {
+                    match rx.recv().await {
+                        Some(r) => Some(r),
+                        None => return,
+                    }
+                } else {
+                    tokio::time::timeout(BATCH_INTERVAL, rx.recv())
+                        .await
+                        .ok()
+                        .flatten()
+                };
+
+                let timed_out = record.is_none();
+                batch.extend(record);
+                if timed_out || batch.len() >= BATCH_SIZE {
+                    export(&client, &endpoint, &service, &batch).await;
+                    batch.clear();
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nesting() {
+        assert_eq!(Context::current(), None);
+
+        let outer = root("request");
+        assert_eq!(Context::current(), Some(outer.context()));
+
+        {
+            let inner = span("db.upsert");
+            assert_eq!(inner.context().trace_id, outer.context().trace_id);
+            assert_ne!(inner.context().span_id, outer.context().span_id);
+            assert_eq!(Context::current(), Some(inner.context()));
+
+            // Another root starts over
+            let other = root("request");
+            assert_ne!(other.context().trace_id, outer.context().trace_id);
+        }
+
+        assert_eq!(Context::current(), Some(outer.context()));
+
+        // As if on a thread spawned for the request
+        let context = Context::current();
+        drop(outer);
+        assert_eq!(Context::current(), None);
+        {
+            let _entered = enter(context);
+            assert_eq!(
+                span("provider").context().trace_id,
+                context.unwrap().trace_id
+            );
+        }
+
+        assert_eq!(Context::current(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_instrument() {
+        let trace = root("request").context();
+        let context = Some(trace);
+
+        let (inner, request_id) =
+            tauri::async_runtime::spawn(instrument(Some("request-1"), context, async {
+                let inner = span("provider");
+                tokio::task::yield_now().await;
+
+                // Still the innermost span, wherever this picked back up
+                assert_eq!(Context::current(), Some(inner.context()));
+                (inner.context(), chamber_common::current_request_id())
+            }))
+            .await
+            .unwrap();
+
+        assert_eq!(inner.trace_id, trace.trace_id);
+        assert_eq!(request_id.as_deref(), Some("request-1"));
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_encode() {
+        let start = std::time::UNIX_EPOCH + std::time::Duration::from_millis(1500);
+        let record = otlp::Record {
+            name: "provider".to_string(),
+            context: Context {
+                trace_id: 1,
+                span_id: 2,
+            },
+            parent: Some(3),
+            start,
+            end: start + std::time::Duration::from_millis(250),
+            attributes: vec![("model", "gpt-4o".to_string())],
+        };
+
+        let encoded = otlp::encode("william", &[record]);
+        let span = &encoded["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "00000000000000000000000000000001");
+        assert_eq!(span["spanId"], "0000000000000002");
+        assert_eq!(span["parentSpanId"], "0000000000000003");
+        assert_eq!(span["startTimeUnixNano"], "1500000000");
+        assert_eq!(span["endTimeUnixNano"], "1750000000");
+        assert_eq!(span["attributes"][0]["value"]["stringValue"], "gpt-4o");
+    }
+}
@@ -0,0 +1,130 @@
+use crate::network::strip_reasoning;
+use crate::types::*;
+
+// Document-style numbers for a conversation--what a draft would be measured by, not its tokens
+//
+// Only user and assistant messages count, and reasoning is left out of the assistant's:
+// tool output and chain of thought aren't part of the writing
+
+// Average silent reading speed for prose
+const WORDS_PER_MINUTE: usize = 238;
+
+pub fn word_count(text: &str) -> usize {
+    text.split_whitespace()
+        .filter(|w| w.chars().any(|c| c.is_alphanumeric()))
+        .count()
+}
+
+pub fn reading_time_seconds(words: usize) -> u64 {
+    (words * 60).div_ceil(WORDS_PER_MINUTE) as u64
+}
+
+fn author_stats(messages: &[String], total_words: usize) -> AuthorStats {
+    let words = messages.iter().map(|m| word_count(m)).sum::<usize>();
+
+    AuthorStats {
+        messages: messages.len(),
+        words,
+        characters: messages.iter().map(|m| m.chars().count()).sum(),
+        share: if total_words == 0 {
+            0.0
+        } else {
+            words as f64 / total_words as f64
+        },
+    }
+}
+
+pub fn conversation_stats(conversation: &Conversation) -> ConversationStats {
+    let mut user = Vec::new();
+    let mut assistant = Vec::new();
+    for message in conversation.messages.iter() {
+        match message.message_type {
+            MessageType::User => user.push(message.content.clone()),
+            MessageType::Assistant => assistant.push(strip_reasoning(&message.content)),
+            _ => {}
+        }
+    }
+
+    let words = user
+        .iter()
+        .chain(assistant.iter())
+        .map(|m| word_count(m))
+        .sum();
+
+    let user = author_stats(&user, words);
+    let assistant = author_stats(&assistant, words);
+
+    ConversationStats {
+        conversation_id: conversation.id.unwrap_or_default(),
+        messages: user.messages + assistant.messages,
+        words,
+        characters: user.characters + assistant.characters,
+        reading_time_seconds: reading_time_seconds(words),
+        user,
+        assistant,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_word_count() {
+        assert_eq!(word_count(""), 0);
+        assert_eq!(word_count("  one two\n\nthree  "), 3);
+        // Stray punctuation isn't a word
+        assert_eq!(word_count("well - that's it ..."), 3);
+        assert_eq!(word_count("naïve café"), 2);
+    }
+
+    #[test]
+    fn test_reading_time() {
+        assert_eq!(reading_time_seconds(0), 0);
+        assert_eq!(reading_time_seconds(238), 60);
+        // Partial seconds round up
+        assert_eq!(reading_time_seconds(1), 1);
+    }
+
+    #[test]
+    fn test_author_breakdown() {
+        let api = API::Anthropic(AnthropicModel::Claude35Sonnet);
+        let conversation = Conversation {
+            id: Some(3),
+            name: "Draft".to_string(),
+            messages: vec![
+                Message::test(MessageType::System, "ignored entirely", api.clone()),
+                Message::test(MessageType::User, "Tighten this paragraph", api.clone()),
+                Message::test(
+                    MessageType::Assistant,
+                    "<think>not part of the draft</think>The paragraph, tightened.",
+                    api.clone(),
+                ),
+                Message::test(MessageType::Tool, "{\"result\": \"ignored\"}", api),
+            ],
+            tools: Vec::new(),
+            overrides: ConversationOverrides::default(),
+            branch_id: None,
+            settings: GenerationSettings::default(),
+            pinned: false,
+            archived: false,
+            unread: 0,
+            template: None,
+            memory_scope: MemoryScope::All,
+            related: Vec::new(),
+        };
+
+        let stats = conversation_stats(&conversation);
+        assert_eq!(stats.conversation_id, 3);
+        assert_eq!(stats.messages, 2);
+        assert_eq!(stats.words, 6);
+        assert_eq!(stats.user.words, 3);
+        assert_eq!(stats.assistant.words, 3);
+        assert_eq!(
+            stats.assistant.characters,
+            "The paragraph, tightened.".len()
+        );
+        assert_eq!(stats.user.share, 0.5);
+        assert_eq!(stats.reading_time_seconds, 2);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::network;
+use crate::queue;
+use crate::types::*;
+
+// Short follow-ups the user might want to send next, offered alongside each response
+// Only for conversations that turn them on, since each one is another (small) request
+
+const SUGGESTIONS_PROMPT: &str = r#"
+    You will be given the end of a conversation between a user and an assistant.
+    Suggest up to 3 short follow-up messages the user might send next.
+    Guidelines:
+    - Write them as the user, e.g. "Can you show an example?"
+    - Keep each under 12 words
+    - Make them different from each other, and from what's already been asked
+    - Respond with _only_ the suggestions, one per line
+"#;
+
+// Messages from the end of the conversation the suggestions are based on
+const CONTEXT_MESSAGES: usize = 4;
+const MAX_SUGGESTIONS: usize = 3;
+const MAX_SUGGESTION_CHARS: usize = 120;
+
+// `WILLIAM_SUGGESTION_MODEL` as `provider/model`, otherwise gpt-4o-mini
+pub fn suggestion_model() -> API {
+    network::model_from_env("WILLIAM_SUGGESTION_MODEL")
+        .unwrap_or(API::OpenAI(OpenAIModel::GPT4oMini))
+}
+
+fn transcript(messages: &[Message]) -> String {
+    let start = messages.len().saturating_sub(CONTEXT_MESSAGES);
+    let mut output = String::from("<conversation>");
+    for message in messages[start..]
+        .iter()
+        .filter(|m| !m.content.is_empty())
+        .filter(|m| matches!(m.message_type, MessageType::User | MessageType::Assistant))
+    {
+        let role = message.message_type.to_string();
+        output.push_str(&format!("<{}>{}</{}>", role, message.content, role));
+    }
+    output.push_str("</conversation>");
+
+    output
+}
+
+pub async fn suggest(messages: &[Message]) -> Result<Vec<String>, std::io::Error> {
+    let api = suggestion_model();
+    let request = Message {
+        id: None,
+        message_type: MessageType::User,
+        content: transcript(messages),
+        api: api.clone(),
+        system_prompt: String::new(),
+        sequence: -1,
+        date_created: String::new(),
+        tool_calls: Vec::new(),
+        tool_call_id: None,
+        attachments: Vec::new(),
+        interrupted: false,
+        language: None,
+        citations: Vec::new(),
+        moderation: None,
+    };
+
+    let (response, _) = network::prompt(
+        api,
+        SUGGESTIONS_PROMPT,
+        &vec![request],
+        &[],
+        queue::Priority::Background,
+    )
+    .await
+    .map_err(network::into_io_error)?;
+
+    Ok(parse(&network::strip_reasoning(&response.content)))
+}
+
+// One suggestion per line, minus whatever list markers or quotes the model wrapped them in
+pub fn parse(response: &str) -> Vec<String> {
+    response
+        .lines()
+        .map(|line| {
+            let line = line.trim();
+            let line = match line.find(['.', ')']) {
+                Some(i) if i > 0 && line[..i].chars().all(|c| c.is_ascii_digit()) => &line[i + 1..],
+                _ => line,
+            };
+            let line = line.trim_start_matches(['-', '*', '•']).trim();
+            line.trim_matches(['"', '“', '”']).trim().to_string()
+        })
+        .filter(|s| !s.is_empty() && s.chars().count() <= MAX_SUGGESTION_CHARS)
+        .take(MAX_SUGGESTIONS)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("1. \"Can you show an example?\"\n2) What about async?\n\n- Why not use Rc?\n* One too many"),
+            vec![
+                "Can you show an example?",
+                "What about async?",
+                "Why not use Rc?"
+            ]
+        );
+
+        // Whatever doesn't look like a suggestion is dropped
+        let rambling = "x".repeat(MAX_SUGGESTION_CHARS + 1);
+        assert_eq!(
+            parse(&format!("{}\nShort one", rambling)),
+            vec!["Short one"]
+        );
+        assert!(parse("").is_empty());
+        assert_eq!(parse("3D printing instead?"), vec!["3D printing instead?"]);
+    }
+}
@@ -0,0 +1,144 @@
+use chamber_common::{lprint, Logger};
+
+use crate::network;
+use crate::queue;
+use crate::tiktoken::Tokenizer;
+use crate::types::*;
+
+// Tokens of history sent to the summarizer at a time
+// Comfortably inside gpt-4o-mini's context, with room for the running summary
+const CHUNK_TOKENS: usize = 64000;
+
+const SUMMARY_PROMPT: &str = r#"
+    You will be given the earlier part of a conversation that no longer fits in the context window,
+    possibly along with a summary of what came before it.
+    Write a single updated summary covering all of it.
+    Guidelines:
+    - Keep names, decisions, facts, code identifiers, and open questions
+    - Drop pleasantries and anything already resolved
+    - Write in the third person, e.g. "The user asked..."
+    - Respond with _only_ the summary
+"#;
+
+// Splits `messages` into consecutive runs of at most `budget` tokens
+// A message over the budget on its own gets a run to itself
+pub fn chunk_messages(
+    messages: &[Message],
+    budget: usize,
+    count: impl Fn(&str) -> usize,
+) -> Vec<&[Message]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut total = 0;
+    for (i, message) in messages.iter().enumerate() {
+        let len = count(&message.content);
+        if total + len > budget && i > start {
+            chunks.push(&messages[start..i]);
+            start = i;
+            total = 0;
+        }
+
+        total += len;
+    }
+
+    if start < messages.len() {
+        chunks.push(&messages[start..]);
+    }
+
+    chunks
+}
+
+fn transcript(previous: Option<&str>, messages: &[Message]) -> String {
+    let mut output = String::new();
+    if let Some(previous) = previous {
+        output.push_str(&format!("<summary>{}</summary>", previous));
+    }
+
+    output.push_str("<conversation>");
+    for message in messages.iter().filter(|m| !m.content.is_empty()) {
+        let role = message.message_type.to_string();
+        output.push_str(&format!("<{}>{}</{}>", role, message.content, role));
+    }
+    output.push_str("</conversation>");
+
+    output
+}
+
+// Folds `messages` into the `previous` summary with gpt-4o-mini, a chunk at a time
+pub async fn summarize(
+    previous: Option<&str>,
+    messages: &[Message],
+    tokenizer: Option<&Tokenizer>,
+) -> Result<String, std::io::Error> {
+    let api = API::OpenAI(OpenAIModel::GPT4oMini);
+    let count = |text: &str| match tokenizer {
+        Some(tok) => tok.count(text),
+        None => text.len().div_ceil(4),
+    };
+
+    let mut summary = previous.map(|s| s.to_string());
+    for chunk in chunk_messages(messages, CHUNK_TOKENS, count) {
+        let request = Message {
+            id: None,
+            message_type: MessageType::User,
+            content: transcript(summary.as_deref(), chunk),
+            api: api.clone(),
+            system_prompt: String::new(),
+            sequence: -1,
+            date_created: String::new(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            attachments: Vec::new(),
+            interrupted: false,
+            language: None,
+            citations: Vec::new(),
+            moderation: None,
+        };
+
+        let (response, _) = network::prompt_deterministic(
+            api.clone(),
+            SUMMARY_PROMPT,
+            &vec![request],
+            &[],
+            queue::Priority::Interactive,
+        )
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        summary = Some(response.content);
+    }
+
+    lprint!(
+        info,
+        "Summarized {} messages ({} chars)",
+        messages.len(),
+        summary.as_ref().map_or(0, |s| s.len())
+    );
+
+    Ok(summary.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_messages() {
+        let messages = ["aaaa", "bb", "cccccc", "dddddddddddd", "e"]
+            .into_iter()
+            .map(|c| Message::test(MessageType::User, c, API::OpenAI(OpenAIModel::GPT4oMini)))
+            .collect::<Vec<Message>>();
+
+        let chunks = chunk_messages(&messages, 8, |t| t.len());
+        let lens = chunks.iter().map(|c| c.len()).collect::<Vec<usize>>();
+
+        // The oversized message gets its own chunk instead of being dropped
+        assert_eq!(lens, vec![2, 1, 1, 1]);
+        assert_eq!(
+            chunks.iter().map(|c| c.len()).sum::<usize>(),
+            messages.len()
+        );
+
+        assert!(chunk_messages(&[], 8, |t| t.len()).is_empty());
+    }
+}
@@ -0,0 +1,578 @@
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use rusqlite::params;
+
+use crate::network;
+use crate::secrets;
+use crate::types::*;
+
+// Syncing conversations between devices through storage the user provides
+//
+// The remote is anything that answers HTTP `GET`/`PUT` on paths under a base URL--a WebDAV share,
+// an S3 bucket behind a presigning proxy, or a self-hosted server
+// Everything but the salt is encrypted before it leaves (AES-256-GCM, keyed from the passphrase),
+// so the remote only ever sees ciphertext
+//
+// Each device appends deltas--the conversations it changed since its last push--under its own
+// prefix, so devices never write to the same object (other than the device list):
+//   salt            random PBKDF2 salt, in the clear
+//   check           a known value encrypted with the key, to catch a wrong passphrase
+//   devices         every device ID that's pushed
+//   <device>/count  how many deltas the device has pushed
+//   <device>/<n>    the device's nth delta
+//
+// Pulled conversations are merged message by message--see `merge`
+// Attachments, tool definitions, and branches other than the active one don't sync
+
+const PBKDF2_ITERATIONS: u32 = 200_000;
+const SALT_LEN: usize = 16;
+const CHECK_VALUE: &[u8] = b"william sync";
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SyncMessage {
+    #[serde(rename = "syncId")]
+    pub sync_id: String,
+    #[serde(rename = "messageType")]
+    pub message_type: MessageType,
+    pub content: String,
+    pub api: API,
+    #[serde(rename = "systemPrompt")]
+    pub system_prompt: String,
+    #[serde(rename = "dateCreated")]
+    pub date_created: String,
+    #[serde(default, rename = "toolCalls")]
+    pub tool_calls: Vec<ToolCall>,
+    #[serde(default, rename = "toolCallId")]
+    pub tool_call_id: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SyncConversation {
+    #[serde(rename = "syncId")]
+    pub sync_id: String,
+    pub name: String,
+    #[serde(rename = "lastUpdated")]
+    pub last_updated: String,
+    pub messages: Vec<SyncMessage>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Delta {
+    pub device: String,
+    pub conversations: Vec<SyncConversation>,
+}
+
+impl SyncMessage {
+    pub fn from_message(sync_id: String, message: &Message) -> Self {
+        SyncMessage {
+            sync_id,
+            message_type: message.message_type.clone(),
+            content: message.content.clone(),
+            api: message.api.clone(),
+            system_prompt: message.system_prompt.clone(),
+            date_created: message.date_created.clone(),
+            tool_calls: message.tool_calls.clone(),
+            tool_call_id: message.tool_call_id.clone(),
+        }
+    }
+
+    // Without an ID--it's new to this device
+    pub fn to_message(&self) -> Message {
+        Message {
+            id: None,
+            message_type: self.message_type.clone(),
+            content: self.content.clone(),
+            api: self.api.clone(),
+            system_prompt: self.system_prompt.clone(),
+            sequence: -1,
+            date_created: self.date_created.clone(),
+            tool_calls: self.tool_calls.clone(),
+            tool_call_id: self.tool_call_id.clone(),
+            attachments: Vec::new(),
+            interrupted: false,
+            language: None,
+            citations: Vec::new(),
+            moderation: None,
+        }
+    }
+}
+
+// Messages from both sides, oldest first
+// A message on both sides is taken from whichever side was updated last--`remote_newer`--
+// and messages created at the same time keep the order they had, local ones first
+pub fn merge(
+    local: &[SyncMessage],
+    remote: &[SyncMessage],
+    remote_newer: bool,
+) -> Vec<SyncMessage> {
+    let mut merged = local
+        .iter()
+        .map(|m| match remote.iter().find(|r| r.sync_id == m.sync_id) {
+            Some(r) if remote_newer => r.clone(),
+            _ => m.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    merged.extend(
+        remote
+            .iter()
+            .filter(|r| !local.iter().any(|m| m.sync_id == r.sync_id))
+            .cloned(),
+    );
+
+    // Stable, so ties keep the order above
+    merged.sort_by(|a, b| a.date_created.cmp(&b.date_created));
+
+    merged
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub fn derive_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    ring::pbkdf2::derive(
+        ring::pbkdf2::PBKDF2_HMAC_SHA256,
+        std::num::NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+
+    key
+}
+
+fn cipher(key: &[u8; 32]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).expect("key length is fixed"))
+}
+
+// Nonce + ciphertext + tag
+pub fn seal(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| std::io::Error::other("error generating nonce"))?;
+
+    let mut in_out = plaintext.to_vec();
+    cipher(key)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut in_out,
+        )
+        .map_err(|_| std::io::Error::other("error encrypting sync data"))?;
+
+    let mut blob = nonce.to_vec();
+    blob.extend_from_slice(&in_out);
+
+    Ok(blob)
+}
+
+pub fn open(key: &[u8; 32], mut blob: Vec<u8>) -> Result<Vec<u8>, std::io::Error> {
+    if blob.len() < NONCE_LEN {
+        return Err(invalid("sync data is too short"));
+    }
+
+    let mut in_out = blob.split_off(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(&blob).map_err(|_| invalid("bad nonce"))?;
+
+    // A wrong passphrase or tampering both end up here
+    let plaintext = cipher(key)
+        .open_in_place(nonce, Aad::empty(), &mut in_out)
+        .map_err(|_| invalid("sync data can't be decrypted with this passphrase"))?;
+
+    Ok(plaintext.to_vec())
+}
+
+#[derive(Clone, Debug)]
+pub struct SyncState {
+    pub endpoint: String,
+    pub token: Option<String>,
+    pub key: [u8; 32],
+    pub device_id: String,
+    pub last_push: Option<String>,
+    pub last_sync: Option<String>,
+}
+
+// The token and key are stored encrypted, like API keys
+pub fn load(db: &rusqlite::Connection) -> Result<Option<SyncState>, Box<dyn std::error::Error>> {
+    let row = db.query_row(
+        "SELECT endpoint, token, key, device_id, last_push, last_sync FROM sync_state WHERE id = 1",
+        params![],
+        |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, Option<String>>(5)?,
+            ))
+        },
+    );
+
+    let (endpoint, token, key, device_id, last_push, last_sync) = match row {
+        Ok(r) => r,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let keystore = secrets::keystore();
+    let token = match token {
+        Some(t) => Some(keystore.decrypt(&t)?),
+        None => None,
+    };
+
+    let key = base64::engine::general_purpose::STANDARD.decode(keystore.decrypt(&key)?)?;
+    let key: [u8; 32] = key
+        .try_into()
+        .map_err(|_| invalid("stored sync key is the wrong length"))?;
+
+    Ok(Some(SyncState {
+        endpoint,
+        token,
+        key,
+        device_id,
+        last_push,
+        last_sync,
+    }))
+}
+
+// Replaces whatever sync was set up before, starting over on what's been pulled
+pub fn enable(
+    endpoint: &str,
+    token: Option<&str>,
+    key: &[u8; 32],
+    db: &rusqlite::Connection,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let keystore = secrets::keystore();
+    let token = match token {
+        Some(t) => Some(keystore.encrypt(t)?),
+        None => None,
+    };
+    let key = keystore.encrypt(&base64::engine::general_purpose::STANDARD.encode(key))?;
+
+    let tx = db.unchecked_transaction()?;
+    tx.execute("DELETE FROM sync_cursors", params![])?;
+    tx.execute(
+        "INSERT OR REPLACE INTO sync_state (id, endpoint, token, key, device_id)
+         VALUES (1, ?1, ?2, ?3, COALESCE((SELECT device_id FROM sync_state WHERE id = 1), ?4))",
+        params![endpoint, token, key, uuid::Uuid::new_v4().to_string()],
+    )?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+pub fn disable(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let tx = db.unchecked_transaction()?;
+    tx.execute("DELETE FROM sync_cursors", params![])?;
+    tx.execute("DELETE FROM sync_state", params![])?;
+    tx.commit()
+}
+
+pub fn mark_pushed(pushed_at: &str, db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute(
+        "UPDATE sync_state SET last_push = ?1, last_sync = CURRENT_TIMESTAMP WHERE id = 1",
+        params![pushed_at],
+    )?;
+    Ok(())
+}
+
+// How many of the device's deltas have been applied here
+pub fn cursor(device: &str, db: &rusqlite::Connection) -> rusqlite::Result<usize> {
+    let cursor = db
+        .query_row(
+            "SELECT applied FROM sync_cursors WHERE device_id = ?1",
+            params![device],
+            |row| row.get::<_, i64>(0),
+        )
+        .or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(0),
+            e => Err(e),
+        })?;
+
+    Ok(cursor as usize)
+}
+
+pub fn set_cursor(device: &str, applied: usize, db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT INTO sync_cursors (device_id, applied) VALUES (?1, ?2)
+         ON CONFLICT (device_id) DO UPDATE SET applied = excluded.applied",
+        params![device, applied as i64],
+    )?;
+    Ok(())
+}
+
+// Gives every conversation and message without a sync ID one
+pub fn assign_ids(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    for table in ["conversations", "messages"] {
+        let ids = db
+            .prepare(&format!("SELECT id FROM {} WHERE sync_id IS NULL", table))?
+            .query_map(params![], |row| row.get::<_, i64>(0))?
+            .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+        for id in ids {
+            db.execute(
+                &format!("UPDATE {} SET sync_id = ?2 WHERE id = ?1", table),
+                params![id, uuid::Uuid::new_v4().to_string()],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+pub struct Remote {
+    base: String,
+    token: Option<String>,
+    key: [u8; 32],
+    client: reqwest::Client,
+}
+
+impl Remote {
+    pub fn new(endpoint: &str, token: Option<String>, key: [u8; 32]) -> Self {
+        Remote {
+            base: endpoint.trim_end_matches('/').to_string(),
+            token,
+            key,
+            client: network::client("sync"),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}/{}", self.base, path));
+        match &self.token {
+            Some(t) => request.bearer_auth(t),
+            None => request,
+        }
+    }
+
+    // `None` if there's nothing at `path`
+    pub async fn get(&self, path: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let response = self
+            .request(reqwest::Method::GET, path)
+            .send()
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND => Ok(None),
+            s if s.is_success() => Ok(Some(
+                response
+                    .bytes()
+                    .await
+                    .map_err(|e| std::io::Error::other(e.to_string()))?
+                    .to_vec(),
+            )),
+            s => Err(std::io::Error::other(format!("GET {} failed: {}", path, s))),
+        }
+    }
+
+    pub async fn put(&self, path: &str, body: Vec<u8>) -> Result<(), std::io::Error> {
+        let response = self
+            .request(reqwest::Method::PUT, path)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        if !response.status().is_success() {
+            return Err(std::io::Error::other(format!(
+                "PUT {} failed: {}",
+                path,
+                response.status()
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_sealed<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+    ) -> Result<Option<T>, std::io::Error> {
+        match self.get(path).await? {
+            Some(blob) => {
+                let plaintext = open(&self.key, blob)?;
+                serde_json::from_slice(&plaintext)
+                    .map(Some)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub async fn put_sealed<T: serde::Serialize>(
+        &self,
+        path: &str,
+        value: &T,
+    ) -> Result<(), std::io::Error> {
+        let plaintext = serde_json::to_vec(value)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        self.put(path, seal(&self.key, &plaintext)?).await
+    }
+}
+
+// The key for the remote at `endpoint`, setting the remote up if this is the first device on it
+// Errors if the passphrase doesn't match the one the remote was set up with
+pub async fn connect(
+    endpoint: &str,
+    token: Option<String>,
+    passphrase: &str,
+) -> Result<[u8; 32], std::io::Error> {
+    let setup = Remote::new(endpoint, token.clone(), [0u8; 32]);
+    let (salt, new) = match setup.get("salt").await? {
+        Some(s) => (s, false),
+        None => {
+            let mut salt = vec![0u8; SALT_LEN];
+            SystemRandom::new()
+                .fill(&mut salt)
+                .map_err(|_| std::io::Error::other("error generating salt"))?;
+            (salt, true)
+        }
+    };
+
+    let key = derive_key(passphrase, &salt);
+    let remote = Remote::new(endpoint, token, key);
+    if new {
+        remote.put("salt", salt).await?;
+        remote.put("check", seal(&key, CHECK_VALUE)?).await?;
+        return Ok(key);
+    }
+
+    let check = remote
+        .get("check")
+        .await?
+        .ok_or_else(|| invalid("sync remote is missing its passphrase check"))?;
+    if open(&key, check)? != CHECK_VALUE {
+        return Err(invalid("sync data can't be decrypted with this passphrase"));
+    }
+
+    Ok(key)
+}
+
+// Adds the device to the remote's list if it isn't there yet, returning every device on it
+pub async fn register(remote: &Remote, device: &str) -> Result<Vec<String>, std::io::Error> {
+    let mut devices = remote
+        .get_sealed::<Vec<String>>("devices")
+        .await?
+        .unwrap_or_default();
+    if !devices.iter().any(|d| d == device) {
+        devices.push(device.to_string());
+        remote.put_sealed("devices", &devices).await?;
+    }
+
+    Ok(devices)
+}
+
+// Appends a delta to the device's log
+pub async fn push(remote: &Remote, delta: &Delta) -> Result<(), std::io::Error> {
+    let count = remote
+        .get_sealed::<usize>(&format!("{}/count", delta.device))
+        .await?
+        .unwrap_or(0);
+
+    remote
+        .put_sealed(&format!("{}/{}", delta.device, count), delta)
+        .await?;
+    remote
+        .put_sealed(&format!("{}/count", delta.device), &(count + 1))
+        .await
+}
+
+// The device's deltas from `cursor` on
+pub async fn pull(
+    remote: &Remote,
+    device: &str,
+    cursor: usize,
+) -> Result<Vec<Delta>, std::io::Error> {
+    let count = remote
+        .get_sealed::<usize>(&format!("{}/count", device))
+        .await?
+        .unwrap_or(0);
+
+    let mut deltas = Vec::new();
+    for n in cursor..count {
+        match remote
+            .get_sealed::<Delta>(&format!("{}/{}", device, n))
+            .await?
+        {
+            Some(d) => deltas.push(d),
+            None => {
+                return Err(invalid(&format!("delta {} from {} is missing", n, device)));
+            }
+        }
+    }
+
+    Ok(deltas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(sync_id: &str, content: &str, date_created: &str) -> SyncMessage {
+        SyncMessage {
+            sync_id: sync_id.to_string(),
+            message_type: MessageType::User,
+            content: content.to_string(),
+            api: API::Local("llama".to_string()),
+            system_prompt: String::new(),
+            date_created: date_created.to_string(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let local = vec![
+            message("a", "hi", "2025-01-01 10:00:00"),
+            message("b", "local edit", "2025-01-01 10:00:05"),
+        ];
+        let remote = vec![
+            message("a", "hi", "2025-01-01 10:00:00"),
+            message("b", "remote edit", "2025-01-01 10:00:05"),
+            message("c", "from the other device", "2025-01-01 10:00:02"),
+        ];
+
+        let contents =
+            |merged: Vec<SyncMessage>| merged.into_iter().map(|m| m.content).collect::<Vec<_>>();
+
+        assert_eq!(
+            contents(merge(&local, &remote, false)),
+            vec!["hi", "from the other device", "local edit"]
+        );
+        assert_eq!(
+            contents(merge(&local, &remote, true)),
+            vec!["hi", "from the other device", "remote edit"]
+        );
+
+        // Nothing new
+        assert_eq!(merge(&local, &local[..1], true), local);
+    }
+
+    #[test]
+    fn test_encryption() {
+        let key = derive_key("correct horse", b"saltsaltsaltsalt");
+        assert_eq!(key, derive_key("correct horse", b"saltsaltsaltsalt"));
+        assert_ne!(key, derive_key("battery staple", b"saltsaltsaltsalt"));
+
+        let blob = seal(&key, b"conversation").unwrap();
+        assert_ne!(&blob[NONCE_LEN..], b"conversation");
+        assert_eq!(open(&key, blob.clone()).unwrap(), b"conversation");
+
+        let wrong = derive_key("battery staple", b"saltsaltsaltsalt");
+        assert!(open(&wrong, blob.clone()).is_err());
+
+        let mut tampered = blob;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(open(&key, tampered).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use rusqlite::params;
+
+use chamber_common::{lprint, Logger};
+
+use crate::types::*;
+
+// Saved prompts with `{{variable}}` placeholders
+// A `Completion` naming one of these has its first user message rendered from it
+
+// Every placeholder in `body`, in the order they first appear
+pub fn variables(body: &str) -> Vec<String> {
+    let mut variables: Vec<String> = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find("{{") {
+        rest = &rest[start + 2..];
+        let end = match rest.find("}}") {
+            Some(end) => end,
+            None => break,
+        };
+
+        let name = rest[..end].trim().to_string();
+        if !name.is_empty() && !variables.contains(&name) {
+            variables.push(name);
+        }
+
+        rest = &rest[end + 2..];
+    }
+
+    variables
+}
+
+// `body` with each placeholder filled in from `values`
+// Every placeholder needs a value--a prompt with a stray `{{topic}}` in it isn't what was meant
+pub fn render(
+    body: &str,
+    values: &std::collections::HashMap<String, String>,
+) -> Result<String, String> {
+    let missing = variables(body)
+        .into_iter()
+        .filter(|v| !values.contains_key(v))
+        .collect::<Vec<String>>();
+    if !missing.is_empty() {
+        return Err(format!(
+            "Missing template variables: {}",
+            missing.join(", ")
+        ));
+    }
+
+    let mut rendered = String::new();
+    let mut rest = body;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        let name = rest[start + 2..end].trim();
+        rendered.push_str(&rest[..start]);
+        match values.get(name) {
+            Some(value) => rendered.push_str(value),
+            // Empty braces are left as they are
+            None => rendered.push_str(&rest[start..end + 2]),
+        }
+
+        rest = &rest[end + 2..];
+    }
+
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+fn read_template(row: &rusqlite::Row) -> rusqlite::Result<PromptTemplate> {
+    let body: String = row.get("body")?;
+
+    Ok(PromptTemplate {
+        id: Some(row.get("id")?),
+        name: row.get("name")?,
+        variables: variables(&body),
+        body,
+    })
+}
+
+pub fn list(db: &rusqlite::Connection) -> rusqlite::Result<Vec<PromptTemplate>> {
+    let mut query =
+        db.prepare("SELECT id, name, body FROM prompt_templates ORDER BY name COLLATE NOCASE")?;
+    let templates = query
+        .query_map(params![], read_template)?
+        .collect::<rusqlite::Result<Vec<PromptTemplate>>>()?;
+
+    Ok(templates)
+}
+
+pub fn get(id: i64, db: &rusqlite::Connection) -> rusqlite::Result<Option<PromptTemplate>> {
+    match db.query_row(
+        "SELECT id, name, body FROM prompt_templates WHERE id = ?1",
+        params![id],
+        read_template,
+    ) {
+        Ok(template) => Ok(Some(template)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Creates the template, or updates it if it has an ID
+// Returns the template's ID
+pub fn save(template: &PromptTemplate, db: &rusqlite::Connection) -> Result<i64, std::io::Error> {
+    let name = template.name.trim();
+    if name.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Prompt templates need a name",
+        ));
+    }
+
+    let result = match template.id {
+        Some(id) => db
+            .execute(
+                "UPDATE prompt_templates SET name = ?2, body = ?3 WHERE id = ?1",
+                params![id, name, template.body],
+            )
+            .map(|updated| (id, updated)),
+        None => db
+            .execute(
+                "INSERT INTO prompt_templates (name, body, date_created) VALUES (?1, ?2, CURRENT_TIMESTAMP)",
+                params![name, template.body],
+            )
+            .map(|inserted| (db.last_insert_rowid(), inserted)),
+    };
+
+    match result {
+        Ok((id, 0)) => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("No prompt template with ID {}", id),
+        )),
+        Ok((id, _)) => {
+            lprint!(info, "Saved prompt template {} ({})", id, name);
+            Ok(id)
+        }
+        Err(rusqlite::Error::SqliteFailure(e, _))
+            if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("There's already a prompt template named {}", name),
+            ))
+        }
+        Err(e) => Err(std::io::Error::other(e.to_string())),
+    }
+}
+
+pub fn delete(id: i64, db: &rusqlite::Connection) -> rusqlite::Result<usize> {
+    db.execute("DELETE FROM prompt_templates WHERE id = ?1", params![id])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pairs: &[(&str, &str)]) -> std::collections::HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(
+            variables("Review {{language}} code for {{ focus }}, in {{language}}"),
+            vec!["language".to_string(), "focus".to_string()]
+        );
+        assert!(variables("no placeholders, {{}} or {{unclosed").is_empty());
+    }
+
+    #[test]
+    fn test_render() {
+        let body = "Summarize {{ topic }} for {{audience}}.";
+        assert_eq!(
+            render(
+                body,
+                &values(&[("topic", "borrowing"), ("audience", "beginners")])
+            )
+            .unwrap(),
+            "Summarize borrowing for beginners."
+        );
+
+        let error = render(body, &values(&[("topic", "borrowing")])).unwrap_err();
+        assert!(error.contains("audience"));
+
+        // Values aren't rendered again
+        assert_eq!(
+            render("{{a}} {{", &values(&[("a", "{{a}}")])).unwrap(),
+            "{{a}} {{"
+        );
+    }
+}
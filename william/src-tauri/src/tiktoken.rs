@@ -18,11 +18,47 @@ type Rank = u32;
 
 pub struct Tokenizer {
     ranks: HashMap<Vec<u8>, Rank>,
+    // rank -> token bytes, for decoding
+    decoder: HashMap<Rank, Vec<u8>>,
+    estimator: Estimator,
+    pretokenizer: Regex,
+}
+
+// Whatever's counting the tokens
+// Counts from the approximate estimator are only ballpark figures,
+// so anything reporting them should say so
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Estimator {
+    Tiktoken,
+    Approximate,
+}
+
+impl Estimator {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Estimator::Tiktoken => "o200k_base",
+            Estimator::Approximate => "approximate",
+        }
+    }
 }
 
 const TOKEN_MAPPING_URL: &str =
     "https://openaipublic.blob.core.windows.net/encodings/o200k_base.tiktoken";
 
+// The o200k_base pre-tokenization pattern
+const O200K_PATTERN: &str = r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+// Rough tokens per pre-tokenized piece, for when there's no token mapping
+// o200k_base covers most short English words (with their leading space) in a single token
+// Anything outside of ASCII is counted a token per character--
+// overcounting is safer than undercounting when fitting a context window
+fn approximate_piece_tokens(piece: &str) -> usize {
+    let ascii = piece.chars().filter(|c| c.is_ascii()).count();
+    let other = piece.chars().count() - ascii;
+
+    (ascii.div_ceil(6) + other).max(1)
+}
+
 impl Tokenizer {
     // TODO: tokenizer file management
     //
@@ -39,12 +75,18 @@ impl Tokenizer {
             let response = match reqwest::get(TOKEN_MAPPING_URL).await {
                 Ok(r) => r,
                 Err(e) => {
-                    // TODO: william should be able to run without a tokenizer
-                    panic!("error fetching tokenizer mapping: {}", e);
+                    error!("error fetching tokenizer mapping: {}", e);
+                    return Err(std::io::Error::other(e));
                 }
             };
 
-            let bytes = response.bytes().await.unwrap();
+            let bytes = match response.error_for_status() {
+                Ok(r) => r.bytes().await.map_err(std::io::Error::other)?,
+                Err(e) => {
+                    error!("error fetching tokenizer mapping: {}", e);
+                    return Err(std::io::Error::other(e));
+                }
+            };
             println!("Token mapping downloaded");
 
             let mut file = std::fs::File::create(token_mapping_filepath)?;
@@ -69,8 +111,8 @@ impl Tokenizer {
             token_mapping_filepath.to_string_lossy().to_string()
         );
 
-        Ok(Tokenizer {
-            ranks: contents
+        Ok(Tokenizer::from_ranks(
+            contents
                 .lines()
                 .filter(|line| !line.trim().is_empty())
                 .filter_map(|line| {
@@ -87,16 +129,102 @@ impl Tokenizer {
                     None
                 })
                 .collect(),
-        })
+        ))
+    }
+
+    fn from_ranks(ranks: HashMap<Vec<u8>, Rank>) -> Self {
+        let decoder = ranks.iter().map(|(k, v)| (*v, k.clone())).collect();
+        Tokenizer {
+            ranks,
+            decoder,
+            estimator: Estimator::Tiktoken,
+            pretokenizer: Regex::new(O200K_PATTERN).unwrap(),
+        }
+    }
+
+    // Compiled-in fallback for when the token mapping can't be loaded
+    // Counts are estimated from the o200k_base pre-tokenization instead of BPE
+    pub fn approximate() -> Self {
+        Tokenizer {
+            ranks: HashMap::default(),
+            decoder: HashMap::default(),
+            estimator: Estimator::Approximate,
+            pretokenizer: Regex::new(O200K_PATTERN).unwrap(),
+        }
+    }
+
+    pub fn estimator(&self) -> Estimator {
+        self.estimator
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        match self.estimator {
+            Estimator::Tiktoken => self.encode(text).len(),
+            Estimator::Approximate => self.pieces(text).map(approximate_piece_tokens).sum(),
+        }
     }
 
-    pub fn encode(&self, piece: &str) -> Vec<Rank> {
+    fn pieces<'a>(&'a self, text: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.pretokenizer
+            .find_iter(text)
+            .filter_map(|m| m.ok())
+            .map(|m| m.as_str())
+    }
+
+    // Only meaningful with the token mapping--use `count` for anything that can fall back
+    fn encode(&self, piece: &str) -> Vec<Rank> {
         if piece.len() == 0 {
             return Vec::new();
         }
 
         byte_pair_encode(piece.as_bytes(), &self.ranks)
     }
+
+    // Tokens are byte-level, so this only returns whatever bytes the tokens map to
+    pub fn decode_bytes(&self, tokens: &[Rank]) -> Vec<u8> {
+        tokens
+            .iter()
+            .filter_map(|t| self.decoder.get(t))
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    // Cut `text` down to at most `max_tokens` tokens
+    // A token boundary can land in the middle of a multi-byte character--
+    // any trailing partial character is dropped
+    pub fn truncate(&self, text: &str, max_tokens: usize) -> String {
+        if self.estimator == Estimator::Approximate {
+            let mut total = 0;
+            let mut end = 0;
+            for m in self.pretokenizer.find_iter(text).filter_map(|m| m.ok()) {
+                total += approximate_piece_tokens(m.as_str());
+                if total > max_tokens {
+                    break;
+                }
+
+                end = m.end();
+            }
+
+            return text[..end].to_string();
+        }
+
+        let tokens = self.encode(text);
+        if tokens.len() <= max_tokens {
+            return text.to_string();
+        }
+
+        let bytes = self.decode_bytes(&tokens[..max_tokens]);
+        match String::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(e) => {
+                let valid = e.utf8_error().valid_up_to();
+                let mut bytes = e.into_bytes();
+                bytes.truncate(valid);
+                String::from_utf8(bytes).unwrap_or_default()
+            }
+        }
+    }
 }
 
 fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize, Rank)> {
@@ -670,4 +798,44 @@ mod tests {
         let res = byte_pair_split(b"abab", &ranks);
         assert_eq!(res, vec![b"ab", b"ab"]);
     }
+
+    #[test]
+    fn test_truncate() {
+        let mut ranks = HashMap::from_iter([
+            (b"a".to_vec(), 0),
+            (b"b".to_vec(), 1),
+            (b"c".to_vec(), 2),
+            (b"d".to_vec(), 3),
+            (b"ab".to_vec(), 4),
+            (b"cd".to_vec(), 5),
+        ]);
+        // The two bytes of "é" as separate tokens
+        ranks.insert(vec![0xC3], 6);
+        ranks.insert(vec![0xA9], 7);
+
+        let tokenizer = Tokenizer::from_ranks(ranks);
+
+        assert_eq!(tokenizer.truncate("abcdab", 2), "abcd");
+        assert_eq!(tokenizer.truncate("abcd", 10), "abcd");
+
+        // Cutting between the bytes of a character drops the partial character
+        assert_eq!(tokenizer.truncate("\u{e9}\u{e9}", 3), "\u{e9}");
+    }
+
+    #[test]
+    fn test_approximate() {
+        let tokenizer = Tokenizer::approximate();
+        assert_eq!(tokenizer.estimator().name(), "approximate");
+
+        // "Hello" "," " world" "!"
+        assert_eq!(tokenizer.count("Hello, world!"), 4);
+        assert_eq!(tokenizer.count(""), 0);
+
+        // Nowhere near the 1 token per byte of a character count
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(10);
+        assert!(tokenizer.count(&text) <= text.len() / 3);
+
+        assert_eq!(tokenizer.truncate("Hello, world!", 2), "Hello,");
+        assert_eq!(tokenizer.truncate("Hello, world!", 10), "Hello, world!");
+    }
 }
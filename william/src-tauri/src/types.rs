@@ -6,6 +6,7 @@ pub enum MessageType {
     User,
     Assistant,
     Developer,
+    Tool,
 }
 
 impl MessageType {
@@ -15,6 +16,7 @@ impl MessageType {
             MessageType::User => "user".to_string(),
             MessageType::Assistant => "assistant".to_string(),
             MessageType::Developer => "developer".to_string(),
+            MessageType::Tool => "tool".to_string(),
         }
     }
 
@@ -23,7 +25,8 @@ impl MessageType {
             MessageType::System => 0,
             MessageType::User => 1,
             MessageType::Assistant => 2,
-            MessageType::Developer => 2,
+            MessageType::Developer => 3,
+            MessageType::Tool => 4,
         }
     }
 
@@ -33,12 +36,13 @@ impl MessageType {
             1 => Ok(MessageType::User),
             2 => Ok(MessageType::Assistant),
             3 => Ok(MessageType::Developer),
+            4 => Ok(MessageType::Tool),
             _ => Err(format!("Invalid message type: {}", id)),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, Hash, Eq, PartialEq)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, Hash, Eq, PartialEq)]
 #[serde(tag = "provider", content = "model")]
 pub enum API {
     #[serde(rename = "openai")]
@@ -47,9 +51,23 @@ pub enum API {
     Groq(GroqModel),
     #[serde(rename = "anthropic")]
     Anthropic(AnthropicModel),
+    #[serde(rename = "deepseek")]
+    DeepSeek(DeepSeekModel),
+    // Hosts for open models take whatever model string the host does,
+    // e.g., `Qwen/Qwen2.5-72B-Instruct-Turbo` or `accounts/fireworks/models/llama-v3p1-70b-instruct`
+    #[serde(rename = "together")]
+    Together(String),
+    #[serde(rename = "fireworks")]
+    Fireworks(String),
+    // Any server speaking the OpenAI chat completions protocol at the configured endpoint,
+    // e.g., Ollama, LM Studio, or vLLM--the model is whatever the server calls it
+    #[serde(rename = "local")]
+    Local(String),
 }
 
-#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, Hash, Eq, PartialEq)]
+// The named models are the ones offered out of the box--anything else the provider
+// lists through its models endpoint comes in as `Other`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, Hash, Eq, PartialEq)]
 pub enum OpenAIModel {
     #[serde(rename = "gpt-4o")]
     GPT4o,
@@ -59,15 +77,31 @@ pub enum OpenAIModel {
     O1Preview,
     #[serde(rename = "o1-mini")]
     O1Mini,
+    #[serde(untagged)]
+    Other(String),
 }
 
 #[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, Hash, Eq, PartialEq)]
 pub enum GroqModel {
-    #[serde(rename = "llama3-70b-8192")]
-    LLaMA70B,
+    #[serde(rename = "llama-3.3-70b-versatile", alias = "llama3-70b-8192")]
+    LLaMA3370B,
+    #[serde(rename = "llama-3.1-8b-instant", alias = "llama3-8b-8192")]
+    LLaMA318B,
+    #[serde(rename = "mixtral-8x7b-32768")]
+    Mixtral8x7B,
+    #[serde(rename = "gemma2-9b-it")]
+    Gemma29B,
 }
 
 #[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, Hash, Eq, PartialEq)]
+pub enum DeepSeekModel {
+    #[serde(rename = "deepseek-chat")]
+    Chat,
+    #[serde(rename = "deepseek-reasoner")]
+    Reasoner,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, Hash, Eq, PartialEq)]
 pub enum AnthropicModel {
     #[serde(rename = "claude-3-opus-20240229")]
     Claude3Opus,
@@ -81,10 +115,28 @@ pub enum AnthropicModel {
     Claude37Sonnet,
     #[serde(rename = "claude-3-5-haiku-latest")]
     Claude35Haiku,
+    #[serde(untagged)]
+    Other(String),
 }
 
+// Models that have been retired upstream, mapped to their replacements
+// (provider, old model, new model)
+//
+// Old messages still reference the retired models in the DB,
+// so these keep them loading instead of erroring out
+pub const MODEL_ALIASES: &[(&str, &str, &str)] = &[
+    ("groq", "llama3-70b-8192", "llama-3.3-70b-versatile"),
+    ("groq", "llama3-8b-8192", "llama-3.1-8b-instant"),
+];
+
 impl API {
     pub fn from_strings(provider: &str, model: &str) -> Result<Self, String> {
+        let model = MODEL_ALIASES
+            .iter()
+            .find(|(p, old, _)| *p == provider && *old == model)
+            .map(|(_, _, new)| *new)
+            .unwrap_or(model);
+
         match provider {
             "openai" => {
                 let model = match model {
@@ -92,13 +144,17 @@ impl API {
                     "gpt-4o-mini" => OpenAIModel::GPT4oMini,
                     "o1-preview" => OpenAIModel::O1Preview,
                     "o1-mini" => OpenAIModel::O1Mini,
-                    _ => return Err(format!("Unknown OpenAI model: {}", model)),
+                    "" => return Err("Missing model for openai".to_string()),
+                    other => OpenAIModel::Other(other.to_string()),
                 };
                 Ok(API::OpenAI(model))
             }
             "groq" => {
                 let model = match model {
-                    "llama3-70b-8192" => GroqModel::LLaMA70B,
+                    "llama-3.3-70b-versatile" => GroqModel::LLaMA3370B,
+                    "llama-3.1-8b-instant" => GroqModel::LLaMA318B,
+                    "mixtral-8x7b-32768" => GroqModel::Mixtral8x7B,
+                    "gemma2-9b-it" => GroqModel::Gemma29B,
                     _ => return Err(format!("Unknown Groq model: {}", model)),
                 };
                 Ok(API::Groq(model))
@@ -110,14 +166,103 @@ impl API {
                     "claude-3-haiku-20240307" => AnthropicModel::Claude3Haiku,
                     "claude-3-5-sonnet-latest" => AnthropicModel::Claude35Sonnet,
                     "claude-3-5-haiku-latest" => AnthropicModel::Claude35Haiku,
-                    _ => return Err(format!("Unknown Anthropic model: {}", model)),
+                    "claude-3-7-sonnet-20250219" => AnthropicModel::Claude37Sonnet,
+                    "" => return Err("Missing model for anthropic".to_string()),
+                    other => AnthropicModel::Other(other.to_string()),
                 };
                 Ok(API::Anthropic(model))
             }
+            "deepseek" => {
+                let model = match model {
+                    "deepseek-chat" => DeepSeekModel::Chat,
+                    "deepseek-reasoner" => DeepSeekModel::Reasoner,
+                    _ => return Err(format!("Unknown DeepSeek model: {}", model)),
+                };
+                Ok(API::DeepSeek(model))
+            }
+            "together" | "fireworks" | "local" if model.is_empty() => {
+                Err(format!("Missing model for {}", provider))
+            }
+            "together" => Ok(API::Together(model.to_string())),
+            "fireworks" => Ok(API::Fireworks(model.to_string())),
+            "local" => Ok(API::Local(model.to_string())),
             _ => Err(format!("Unknown provider: {}", provider)),
         }
     }
 
+    /// Context window size, in tokens
+    pub fn context_window(&self) -> usize {
+        match self {
+            API::OpenAI(_) => 128000,
+            API::Groq(model) => match model {
+                GroqModel::LLaMA3370B | GroqModel::LLaMA318B => 131072,
+                GroqModel::Mixtral8x7B => 32768,
+                GroqModel::Gemma29B => 8192,
+            },
+            API::Anthropic(_) => 200000,
+            API::DeepSeek(_) => 64000,
+            // Varies by model--this is on the low end for what they host
+            API::Together(_) | API::Fireworks(_) => 32768,
+            // Local servers tend to run models with much smaller windows than they're capable of
+            API::Local(_) => 8192,
+        }
+    }
+
+    /// Features the model supports through its provider's API
+    pub fn capabilities(&self) -> ModelCapabilities {
+        let all = ModelCapabilities {
+            vision: true,
+            tools: true,
+            json_mode: true,
+            streaming: true,
+            system_prompt: true,
+        };
+
+        match self {
+            API::OpenAI(model) => match model {
+                // Newer models found through discovery are assumed to have everything
+                OpenAIModel::GPT4o | OpenAIModel::GPT4oMini | OpenAIModel::Other(_) => all,
+                // The o1 previews are text only, and don't take system/developer messages
+                OpenAIModel::O1Preview | OpenAIModel::O1Mini => ModelCapabilities {
+                    vision: false,
+                    tools: false,
+                    json_mode: false,
+                    streaming: true,
+                    system_prompt: false,
+                },
+            },
+            // None of the Groq models take images
+            API::Groq(_) => ModelCapabilities {
+                vision: false,
+                ..all
+            },
+            // Anthropic doesn't have a dedicated JSON mode
+            API::Anthropic(_) => ModelCapabilities {
+                json_mode: false,
+                ..all
+            },
+            API::DeepSeek(model) => match model {
+                DeepSeekModel::Chat => ModelCapabilities {
+                    vision: false,
+                    ..all
+                },
+                DeepSeekModel::Reasoner => ModelCapabilities {
+                    vision: false,
+                    tools: false,
+                    json_mode: false,
+                    streaming: true,
+                    system_prompt: true,
+                },
+            },
+            // There's no telling what an arbitrary model supports,
+            // so anything besides images is left to the host to reject
+            API::Together(_) | API::Fireworks(_) | API::Local(_) => ModelCapabilities {
+                vision: false,
+                ..all
+            },
+        }
+    }
+
     /// Returns a tuple of (provider, model)
     pub fn to_strings(&self) -> (String, String) {
         match self {
@@ -127,12 +272,16 @@ impl API {
                     OpenAIModel::GPT4oMini => "gpt-4o-mini",
                     OpenAIModel::O1Preview => "o1-preview",
                     OpenAIModel::O1Mini => "o1-mini",
+                    OpenAIModel::Other(model) => model,
                 };
                 ("openai".to_string(), model_str.to_string())
             }
             API::Groq(model) => {
                 let model_str = match model {
-                    GroqModel::LLaMA70B => "llama3-70b-8192",
+                    GroqModel::LLaMA3370B => "llama-3.3-70b-versatile",
+                    GroqModel::LLaMA318B => "llama-3.1-8b-instant",
+                    GroqModel::Mixtral8x7B => "mixtral-8x7b-32768",
+                    GroqModel::Gemma29B => "gemma2-9b-it",
                 };
                 ("groq".to_string(), model_str.to_string())
             }
@@ -143,14 +292,76 @@ impl API {
                     AnthropicModel::Claude3Haiku => "claude-3-haiku-20240307",
                     AnthropicModel::Claude35Sonnet => "claude-3-5-sonnet-latest",
                     AnthropicModel::Claude35Haiku => "claude-3-5-haiku-latest",
-                    AnthropicModel::Claude37Sonnet => "claude-3-5-sonnet-latest",
+                    AnthropicModel::Claude37Sonnet => "claude-3-7-sonnet-20250219",
+                    AnthropicModel::Other(model) => model,
                 };
                 ("anthropic".to_string(), model_str.to_string())
             }
+            API::DeepSeek(model) => {
+                let model_str = match model {
+                    DeepSeekModel::Chat => "deepseek-chat",
+                    DeepSeekModel::Reasoner => "deepseek-reasoner",
+                };
+                ("deepseek".to_string(), model_str.to_string())
+            }
+            API::Together(model) => ("together".to_string(), model.clone()),
+            API::Fireworks(model) => ("fireworks".to_string(), model.clone()),
+            API::Local(model) => ("local".to_string(), model.clone()),
+        }
+    }
+}
+
+// Feature flags for a model
+// Requests needing something the model doesn't have are rejected before they reach the provider
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize, PartialEq)]
+pub struct ModelCapabilities {
+    pub vision: bool,
+    pub tools: bool,
+    #[serde(rename = "jsonMode")]
+    pub json_mode: bool,
+    pub streaming: bool,
+    #[serde(rename = "systemPrompt")]
+    pub system_prompt: bool,
+}
+
+impl ModelCapabilities {
+    // Names of the features in `required` that aren't supported here
+    pub fn missing(&self, required: &ModelCapabilities) -> Vec<&'static str> {
+        let mut missing = Vec::new();
+        for (name, have, need) in [
+            ("images", self.vision, required.vision),
+            ("tools", self.tools, required.tools),
+            ("JSON mode", self.json_mode, required.json_mode),
+            ("streaming", self.streaming, required.streaming),
+            ("system prompts", self.system_prompt, required.system_prompt),
+        ] {
+            if need && !have {
+                missing.push(name);
+            }
         }
+
+        missing
     }
 }
 
+// A tool the model is allowed to call
+// `parameters` is the JSON schema of the tool's arguments
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+// A structured tool invocation returned by the model
+// `arguments` is kept as the raw JSON string the provider gave us
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message {
     pub id: Option<i64>,
@@ -160,41 +371,136 @@ pub struct Message {
     pub system_prompt: String,
     pub sequence: i32,
     pub date_created: String,
+    // Set on assistant messages where the model requested tool calls
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+    // Set on tool messages--the ID of the call this message is the result of
+    #[serde(default)]
+    pub tool_call_id: Option<String>,
+    // Uploaded beforehand with `UploadAttachment`
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    // Set on assistant messages whose stream was cut off by William shutting down
+    // These can be picked back up with `Continue`
+    #[serde(default)]
+    pub interrupted: bool,
+    // ISO 639-3 code detected for user messages, when it could be told
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    // Set on assistant messages--the references behind the response's [n] markers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub citations: Vec<Citation>,
+    // Set on user messages that went through moderation before being sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moderation: Option<Moderation>,
+}
+
+// What the moderation pass made of a message--see moderation.rs
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Moderation {
+    pub flagged: bool,
+    // As named by the provider, e.g. `harassment/threatening` or `violent crimes`
+    pub categories: Vec<String>,
+    pub provider: String,
+}
+
+// A reference the model was given, and cited with `[marker]`
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Citation {
+    pub marker: usize,
+    // The conversation a remembered message is from, or the file a document chunk is from
+    pub source: String,
+    #[serde(rename = "conversationId")]
+    pub conversation_id: Option<i64>,
+    #[serde(rename = "messageId")]
+    pub message_id: Option<i64>,
+    pub excerpt: String,
+}
+
+// A reference put into the system prompt, listed in `References` before the response streams in
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Reference {
+    #[serde(flatten)]
+    pub citation: Citation,
+    // Cosine similarity to the prompt--only memories Dewey found have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub similarity: Option<f32>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct References {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    pub references: Vec<Reference>,
+}
+
+// A message with just its type, content, and model, for tests to fill in what they care about
+#[cfg(test)]
+impl Message {
+    pub fn test(message_type: MessageType, content: &str, api: API) -> Self {
+        Message {
+            id: None,
+            message_type,
+            content: content.to_string(),
+            api,
+            system_prompt: String::new(),
+            sequence: -1,
+            date_created: String::new(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            attachments: Vec::new(),
+            interrupted: false,
+            language: None,
+            citations: Vec::new(),
+            moderation: None,
+        }
+    }
 }
 
 impl Message {
     pub fn update(&self, db: &rusqlite::Connection) -> rusqlite::Result<usize> {
-        db.execute(
-            "UPDATE messages SET content = ?2, system_prompt = ?3 WHERE id = ?1",
-            params![self.id, self.content, self.system_prompt],
-        )
+        let update_count = db.execute(
+            "UPDATE messages SET content = ?2, system_prompt = ?3, tool_calls = ?4, tool_call_id = ?5 WHERE id = ?1",
+            params![
+                self.id,
+                self.content,
+                self.system_prompt,
+                serde_json::to_string(&self.tool_calls).unwrap(),
+                self.tool_call_id
+            ],
+        )?;
+
+        if update_count > 0 {
+            crate::attachments::sync_references(self, db)?;
+        }
+
+        Ok(update_count)
     }
 
     pub fn insert(&mut self, db: &rusqlite::Connection) -> rusqlite::Result<usize> {
-        let (provider, model_name) = self.api.to_strings();
-
-        let api_config_id: i64 = db.query_row(
-            "SELECT id FROM models WHERE provider = ?1 AND name = ?2",
-            params![provider, model_name],
-            |row| row.get(0),
-        )?;
+        let api_config_id = get_model_id(&self.api, db)?;
 
         let update_count = db.execute(
-            "INSERT INTO messages (message_type_id, content, api_config_id, system_prompt, date_created) VALUES (?1, ?2, ?3, ?4, CURRENT_TIMESTAMP)",
+            "INSERT INTO messages (message_type_id, content, api_config_id, system_prompt, date_created, tool_calls, tool_call_id) VALUES (?1, ?2, ?3, ?4, CURRENT_TIMESTAMP, ?5, ?6)",
             params![
                 self.message_type.id(),
                 self.content,
                 api_config_id,
-                self.system_prompt
+                self.system_prompt,
+                serde_json::to_string(&self.tool_calls).unwrap(),
+                self.tool_call_id
             ],
         )?;
 
         self.id = Some(db.last_insert_rowid());
+        crate::attachments::sync_references(self, db)?;
 
         Ok(update_count)
     }
 
     pub fn upsert(&mut self, db: &rusqlite::Connection) -> rusqlite::Result<usize> {
+        let _span = crate::spans::span("db.upsert_message");
+
         if self.update(db)? == 0 {
             self.insert(db)
         } else {
@@ -203,11 +509,146 @@ impl Message {
     }
 }
 
+// ID of the `models` row for `api`
+pub fn get_model_id(api: &API, db: &rusqlite::Connection) -> rusqlite::Result<i64> {
+    let (provider, model_name) = api.to_strings();
+
+    // Hosted models aren't known up front, so they're added the first time they're used
+    db.execute(
+        "INSERT INTO models (name, provider)
+         SELECT ?2, ?1
+         WHERE NOT EXISTS (SELECT 1 FROM models WHERE provider = ?1 AND name = ?2)",
+        params![provider, model_name],
+    )?;
+
+    db.query_row(
+        "SELECT id FROM models WHERE provider = ?1 AND name = ?2",
+        params![provider, model_name],
+        |row| row.get(0),
+    )
+}
+
+// Sampling parameters for a completion
+// Anything left as `None` is up to the provider
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GenerationSettings {
+    pub temperature: Option<f32>,
+    #[serde(rename = "topP")]
+    pub top_p: Option<f32>,
+    #[serde(rename = "maxTokens")]
+    pub max_tokens: Option<u32>,
+}
+
+impl GenerationSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == GenerationSettings::default()
+    }
+}
+
+// Starts a new branch in a conversation, forking off of `parent_branch_id` after `fork_sequence`
+// messages (or from nothing, for a conversation's first branch)
+pub fn create_branch(
+    db: &rusqlite::Connection,
+    conversation_id: i64,
+    parent_branch_id: Option<i64>,
+    fork_sequence: Option<i64>,
+) -> rusqlite::Result<i64> {
+    db.execute(
+        "INSERT INTO branches (conversation_id, parent_branch_id, fork_sequence, date_created) VALUES (?1, ?2, ?3, CURRENT_TIMESTAMP)",
+        params![conversation_id, parent_branch_id, fork_sequence],
+    )?;
+
+    Ok(db.last_insert_rowid())
+}
+
+// Per-conversation settings, taking precedence over the global config in `completion()`
+// Anything left as `None` falls back to the usual behavior
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ConversationOverrides {
+    // Used for every completion in the conversation, regardless of the model the message came in with
+    pub model: Option<API>,
+    pub temperature: Option<f32>,
+    // Replaces the system prompt from the user config
+    #[serde(rename = "systemPrompt")]
+    pub system_prompt: Option<String>,
+    // Fills in the model, system prompt, and settings for anything above left unset
+    #[serde(rename = "personaId")]
+    pub persona_id: Option<i64>,
+    // Indexed repositories (see `IndexRepo`) whose code can be pulled in as references
+    pub repositories: Vec<i64>,
+    // Suggested follow-ups come after each response--see suggestions.rs
+    pub suggestions: bool,
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Conversation {
     pub id: Option<i64>,
     pub name: String,
     pub messages: Vec<Message>,
+    // Tools made available to the model for this conversation
+    // These are executed by the client, which sends the results back through `ToolResult`
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+    #[serde(default)]
+    pub overrides: ConversationOverrides,
+    // Which of the conversation's branches `messages` is
+    // Left unset, this is whichever branch is active
+    #[serde(default, rename = "branchId")]
+    pub branch_id: Option<i64>,
+    // Only read from `Completion` requests--these are stored with the response, not the conversation
+    #[serde(default)]
+    pub settings: GenerationSettings,
+    // Only changed through `Pin`/`Unpin` and `Archive`/`Unarchive`--`upsert` leaves these alone
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub archived: bool,
+    // Messages on the active branch since the last `MarkRead`, only filled in for `ConversationList`
+    #[serde(default)]
+    pub unread: usize,
+    // Only read from `Completion` requests--renders the first user message from a saved template
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<TemplateInput>,
+    // Only read from `Completion` requests--which memories references are drawn from
+    #[serde(default, rename = "memoryScope")]
+    pub memory_scope: MemoryScope,
+    // Only filled in for `Load`--follow-ups to or from this conversation, see related.rs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub related: Vec<RelatedConversation>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RelatedConversation {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    pub name: String,
+    // Between the first message of the newer one and the closest memory of the older
+    pub similarity: f32,
+}
+
+// Embeddings are tagged in Dewey with the conversation their message started out in,
+// so references can be kept to it
+// Messages carried into a fork keep their original conversation's tag,
+// and embeddings from before tagging only turn up with `All`
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize, PartialEq)]
+pub enum MemoryScope {
+    #[serde(rename = "conversation")]
+    Conversation,
+    #[default]
+    #[serde(rename = "all")]
+    All,
+    #[serde(rename = "none")]
+    None,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TemplateInput {
+    #[serde(rename = "templateId")]
+    pub template_id: i64,
+    #[serde(default)]
+    pub variables: std::collections::HashMap<String, String>,
 }
 
 impl Conversation {
@@ -215,19 +656,46 @@ impl Conversation {
     // basic order here is something like:
     // - upsert conversation table
     // - upsert each message item (for setting IDs + updating contents)
-    // - reset paths
+    // - reset the branch's path, and make it the active one
     pub fn upsert(&mut self, db: &rusqlite::Connection) -> rusqlite::Result<usize> {
+        let mut span = crate::spans::span("db.upsert_conversation");
+        span.attribute("messages", self.messages.len());
+
+        let default_model_id = match &self.overrides.model {
+            Some(api) => Some(get_model_id(api, db)?),
+            None => None,
+        };
+
         if self.id.is_none() {
             db.execute(
-                "INSERT INTO conversations (name, last_updated, date_created) VALUES (?1, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP)",
-                params![self.name],
+                "INSERT INTO conversations (name, last_updated, date_created, tools, default_api_config_id, temperature, system_prompt, persona_id, repositories, suggestions) VALUES (?1, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    self.name,
+                    serde_json::to_string(&self.tools).unwrap(),
+                    default_model_id,
+                    self.overrides.temperature,
+                    self.overrides.system_prompt,
+                    self.overrides.persona_id,
+                    serde_json::to_string(&self.overrides.repositories).unwrap(),
+                    self.overrides.suggestions
+                ],
             )?;
 
             self.id = Some(db.last_insert_rowid());
         } else {
             db.execute(
-                "UPDATE conversations SET name = ?2, last_updated = CURRENT_TIMESTAMP WHERE id = ?1",
-                params![self.id, self.name],
+                "UPDATE conversations SET name = ?2, last_updated = CURRENT_TIMESTAMP, tools = ?3, default_api_config_id = ?4, temperature = ?5, system_prompt = ?6, persona_id = ?7, repositories = ?8, suggestions = ?9 WHERE id = ?1",
+                params![
+                    self.id,
+                    self.name,
+                    serde_json::to_string(&self.tools).unwrap(),
+                    default_model_id,
+                    self.overrides.temperature,
+                    self.overrides.system_prompt,
+                    self.overrides.persona_id,
+                    serde_json::to_string(&self.overrides.repositories).unwrap(),
+                    self.overrides.suggestions
+                ],
             )?;
         }
 
@@ -235,22 +703,121 @@ impl Conversation {
             message.upsert(db)?;
         }
 
+        let conversation_id = self.id.unwrap();
+        if self.branch_id.is_none() {
+            let active: Option<i64> = db.query_row(
+                "SELECT active_branch_id FROM conversations WHERE id = ?1",
+                params![conversation_id],
+                |row| row.get(0),
+            )?;
+
+            self.branch_id = match active {
+                Some(b) => Some(b),
+                None => Some(create_branch(db, conversation_id, None, None)?),
+            };
+        }
+
+        // Other branches share these messages, but keep their own paths
         db.execute(
-            "DELETE FROM paths WHERE conversation_id = ?1",
-            params![self.id],
+            "DELETE FROM paths WHERE conversation_id = ?1 AND branch_id = ?2",
+            params![self.id, self.branch_id],
         )?;
 
         for (sequence, message) in self.messages.iter().enumerate() {
             db.execute(
-                "INSERT INTO paths (conversation_id, message_id, sequence) VALUES (?1, ?2, ?3)",
-                params![self.id, message.id, sequence as i64],
+                "INSERT INTO paths (conversation_id, branch_id, message_id, sequence) VALUES (?1, ?2, ?3, ?4)",
+                params![self.id, self.branch_id, message.id, sequence as i64],
             )?;
         }
 
+        db.execute(
+            "UPDATE conversations SET active_branch_id = ?2 WHERE id = ?1",
+            params![self.id, self.branch_id],
+        )?;
+
         Ok(1)
     }
 }
 
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Branch {
+    pub id: i64,
+    // `None` for the conversation's original branch
+    #[serde(rename = "parentBranchId")]
+    pub parent_branch_id: Option<i64>,
+    // Number of messages shared with the parent branch
+    #[serde(rename = "forkSequence")]
+    pub fork_sequence: Option<i64>,
+    #[serde(rename = "messageCount")]
+    pub message_count: i64,
+    #[serde(rename = "dateCreated")]
+    pub date_created: String,
+    // Named after its first response, once that's in
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct BranchesRequest {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct BranchList {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    #[serde(rename = "activeBranchId")]
+    pub active_branch_id: Option<i64>,
+    pub branches: Vec<Branch>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SwitchBranch {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    #[serde(rename = "branchId")]
+    pub branch_id: i64,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotConversation {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    pub label: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotsRequest {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RestoreSnapshot {
+    #[serde(rename = "snapshotId")]
+    pub snapshot_id: i64,
+}
+
+// A labeled checkpoint of a conversation's path--see snapshots.rs
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    pub id: i64,
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    // The branch the snapshot was taken on--restoring it branches off from here
+    #[serde(rename = "branchId")]
+    pub branch_id: Option<i64>,
+    pub label: String,
+    pub messages: usize,
+    #[serde(rename = "dateCreated")]
+    pub date_created: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotList {
+    pub snapshots: Vec<Snapshot>,
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct ConversationList {
     pub conversations: Vec<Conversation>,
@@ -259,6 +826,9 @@ pub struct ConversationList {
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct LoadConversation {
     pub id: i64,
+    // Load a branch without switching to it
+    #[serde(default, rename = "branchId")]
+    pub branch_id: Option<i64>,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -273,6 +843,51 @@ pub struct Fork {
     pub sequence: i64,
 }
 
+// Rewrites a user message in place, dropping everything after it
+// System messages are rewritten without dropping anything, and taken out if left empty
+// The conversation is needed since branches share messages--only the active branch's path changes
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct EditMessage {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    #[serde(rename = "messageId")]
+    pub message_id: i64,
+    #[serde(rename = "newContent")]
+    pub new_content: String,
+    // Run a completion for the edited message, instead of just sending back the conversation
+    #[serde(default)]
+    pub regenerate: bool,
+}
+
+// Puts a system message into the conversation after `afterMessageId`, or at the start without one
+// Unlike the system prompt, it's part of the history--every request after it is sent with it in place
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct InsertSystemMessage {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    #[serde(default, rename = "afterMessageId")]
+    pub after_message_id: Option<i64>,
+    pub content: String,
+}
+
+// Cuts off a response mid-stream and follows it up with a new instruction from the user
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Redirect {
+    #[serde(rename = "responseId")]
+    pub response_id: i64,
+    #[serde(rename = "newInstruction")]
+    pub new_instruction: String,
+}
+
+// Finishes an interrupted response, keeping whatever was written before the interruption
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ContinueCompletion {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    #[serde(rename = "messageId")]
+    pub message_id: i64,
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct APIKeys {
     pub openai: String,
@@ -280,6 +895,12 @@ pub struct APIKeys {
     pub grok: String,
     pub anthropic: String,
     pub gemini: String,
+    #[serde(default)]
+    pub deepseek: String,
+    #[serde(default)]
+    pub together: String,
+    #[serde(default)]
+    pub fireworks: String,
 }
 
 // Represents the state of the user's configured settings and secrets
@@ -291,6 +912,166 @@ pub struct UserConfig {
     pub api_keys: APIKeys,
     #[serde(rename = "systemPrompt")]
     pub system_prompt: String,
+    // How many times a failed provider request is retried
+    #[serde(rename = "maxRetries", default = "default_max_retries")]
+    pub max_retries: u32,
+    // How Dewey and keyword results are blended into references
+    #[serde(rename = "searchFusion", default)]
+    pub search_fusion: FusionStrategy,
+    // Base URL of an OpenAI-compatible server for `local` models, e.g. `http://localhost:11434`
+    #[serde(rename = "localEndpoint", default)]
+    pub local_endpoint: String,
+    // Days deleted conversations stay in the trash before they're purged
+    #[serde(
+        rename = "trashRetentionDays",
+        default = "default_trash_retention_days"
+    )]
+    pub trash_retention_days: u32,
+    // Hours a conversation can go without a response before it's deleted--0 keeps them
+    #[serde(
+        rename = "emptyConversationHours",
+        default = "default_empty_conversation_hours"
+    )]
+    pub empty_conversation_hours: u32,
+    // Characters a new message can run to before its text is moved into an attachment--0 for no limit
+    #[serde(rename = "maxMessageChars", default = "default_max_message_chars")]
+    pub max_message_chars: u32,
+    // Least cosine similarity to the prompt a memory needs to be used as a reference
+    #[serde(rename = "referenceThreshold", default = "default_reference_threshold")]
+    pub reference_threshold: f32,
+    // Checks user messages before they're sent--see moderation.rs
+    #[serde(default)]
+    pub moderation: ModerationProvider,
+    // Refuses to send flagged messages instead of only annotating them
+    #[serde(rename = "moderationBlock", default)]
+    pub moderation_block: bool,
+    // Where the websocket server listens, from the next start up on
+    // `WILLIAM_BIND_ADDRESS` and `WILLIAM_PORT` take precedence when they're set
+    #[serde(rename = "bindAddress", default = "default_bind_address")]
+    pub bind_address: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+fn default_bind_address() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_port() -> u16 {
+    9001
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_trash_retention_days() -> u32 {
+    30
+}
+
+fn default_empty_conversation_hours() -> u32 {
+    24
+}
+
+fn default_max_message_chars() -> u32 {
+    20000
+}
+
+fn default_reference_threshold() -> f32 {
+    0.25
+}
+
+// Size of the buckets usage is grouped into
+// Weeks start on Monday, and each bucket is labeled with its first day
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize, PartialEq)]
+pub enum UsageGranularity {
+    #[default]
+    #[serde(rename = "day")]
+    Day,
+    #[serde(rename = "week")]
+    Week,
+    #[serde(rename = "month")]
+    Month,
+}
+
+// How references are picked for a completion
+// - `semantic`: Dewey embedding results only
+// - `keyword`: full-text search hits only
+// - `rrf`: both, merged with reciprocal rank fusion
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize, PartialEq)]
+pub enum FusionStrategy {
+    #[serde(rename = "semantic")]
+    Semantic,
+    #[serde(rename = "keyword")]
+    Keyword,
+    #[default]
+    #[serde(rename = "rrf")]
+    ReciprocalRank,
+}
+
+impl FusionStrategy {
+    pub fn from_str(strategy: &str) -> Result<Self, String> {
+        match strategy {
+            "semantic" => Ok(FusionStrategy::Semantic),
+            "keyword" => Ok(FusionStrategy::Keyword),
+            "rrf" => Ok(FusionStrategy::ReciprocalRank),
+            _ => Err(format!("Unknown fusion strategy: {}", strategy)),
+        }
+    }
+
+    pub fn to_str(self) -> &'static str {
+        match self {
+            FusionStrategy::Semantic => "semantic",
+            FusionStrategy::Keyword => "keyword",
+            FusionStrategy::ReciprocalRank => "rrf",
+        }
+    }
+
+    // `WILLIAM_SEARCH_FUSION` is set from the user config, like the API keys
+    pub fn from_env() -> Self {
+        std::env::var("WILLIAM_SEARCH_FUSION")
+            .ok()
+            .and_then(|s| Self::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize, PartialEq)]
+pub enum ModerationProvider {
+    #[default]
+    #[serde(rename = "off")]
+    Off,
+    #[serde(rename = "openai")]
+    OpenAI,
+    #[serde(rename = "local")]
+    Local,
+}
+
+impl ModerationProvider {
+    pub fn from_str(provider: &str) -> Result<Self, String> {
+        match provider {
+            "off" => Ok(ModerationProvider::Off),
+            "openai" => Ok(ModerationProvider::OpenAI),
+            "local" => Ok(ModerationProvider::Local),
+            _ => Err(format!("Unknown moderation provider: {}", provider)),
+        }
+    }
+
+    pub fn to_str(self) -> &'static str {
+        match self {
+            ModerationProvider::Off => "off",
+            ModerationProvider::OpenAI => "openai",
+            ModerationProvider::Local => "local",
+        }
+    }
+
+    // `WILLIAM_MODERATION` is set from the user config, like the API keys
+    pub fn from_env() -> Self {
+        std::env::var("WILLIAM_MODERATION")
+            .ok()
+            .and_then(|s| Self::from_str(&s).ok())
+            .unwrap_or_default()
+    }
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -306,53 +1087,590 @@ pub struct DeleteConversation {
     pub conversation_id: i64,
 }
 
+// For `Pin`, `Unpin`, `Archive`, and `Unarchive`
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-pub struct UsageRequest {
+pub struct ConversationFlag {
     #[serde(rename = "conversationId")]
-    pub conversation_id: Option<i64>,
-    pub api: API,
-    #[serde(rename = "dateFrom")]
-    pub date_from: String,
-    #[serde(rename = "dateTo")]
-    pub date_to: String,
+    pub conversation_id: i64,
 }
 
+// Everything up to `message_id` has been seen--
+// the latest message on the active branch if it's left out
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-#[serde(untagged)]
-pub enum RequestPayload {
-    Ping(Ping),
-    Completion(Conversation),
-    ConversationList,
-    Load(LoadConversation),
-    Fork(Fork),
-    Config(UserConfig),
-    Preview(Preview),
-    DeleteConversation(DeleteConversation),
-    Usage(UsageRequest),
+pub struct MarkRead {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    #[serde(default, rename = "messageId")]
+    pub message_id: Option<i64>,
 }
 
-/// Request in JSON form looks like
-/// {
-///     "method": <request enum type>,
-///     "payload": {
-///         <request payload JSON>
-///     }
-/// }
-///
-/// e.g.,
-/// // Update configuration
-/// {
-///   "method": "Config",
-///   "payload": {
-///     "write": true,
-///     "apiKeys": {
-///       "openai": "sk-...",
-///       "groq": "gsk-...",
-///       "grok": "...",
-///       "anthropic": "sk-ant-...",
-///       "gemini": "..."
-///     },
-///     "systemPrompt": "You are a helpful assistant"
+// A saved system prompt, with the model and settings it's meant to be used with
+// Conversations pick one up through `overrides.personaId`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Persona {
+    // Left out when creating one with `SavePersona`
+    #[serde(default)]
+    pub id: Option<i64>,
+    pub name: String,
+    #[serde(rename = "systemPrompt")]
+    pub system_prompt: String,
+    #[serde(default)]
+    pub model: Option<API>,
+    #[serde(default)]
+    pub settings: GenerationSettings,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PersonaList {
+    pub personas: Vec<Persona>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct DeletePersona {
+    #[serde(rename = "personaId")]
+    pub persona_id: i64,
+}
+
+// A reusable prompt with `{{variable}}` placeholders
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PromptTemplate {
+    // Left out when creating one with `SavePromptTemplate`
+    #[serde(default)]
+    pub id: Option<i64>,
+    pub name: String,
+    pub body: String,
+    // The placeholders in `body`--filled in when read, ignored when saved
+    #[serde(default)]
+    pub variables: Vec<String>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PromptTemplateList {
+    pub templates: Vec<PromptTemplate>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct DeletePromptTemplate {
+    #[serde(rename = "templateId")]
+    pub template_id: i64,
+}
+
+// A directory whose files are kept chunked and embedded as references
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct WatchedFolder {
+    pub id: i64,
+    pub path: String,
+    // Files currently indexed from the folder
+    pub files: i64,
+    #[serde(rename = "lastScanned")]
+    pub last_scanned: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct WatchedFolderList {
+    pub folders: Vec<WatchedFolder>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct AddWatchedFolder {
+    pub path: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RemoveWatchedFolder {
+    #[serde(rename = "folderId")]
+    pub folder_id: i64,
+}
+
+// A git repository indexed for code references
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Repository {
+    pub id: i64,
+    pub name: String,
+    pub path: String,
+    // Files currently indexed from the repository
+    pub files: i64,
+    #[serde(rename = "lastIndexed")]
+    pub last_indexed: Option<String>,
+    // Whether an `IndexRepo` pass is still running
+    pub indexing: bool,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RepositoryList {
+    pub repositories: Vec<Repository>,
+}
+
+// How far the running (or last) embedding backfill got--see backfill.rs
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct BackfillProgress {
+    pub running: bool,
+    // Messages the pass found without embeddings
+    pub total: usize,
+    pub embedded: usize,
+    pub failed: usize,
+}
+
+// An embedding file the assistant pulls references from--see memory.rs
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Memory {
+    pub id: i64,
+    // `message` or `exchange`
+    pub kind: String,
+    // The first message embedded with the file, which the rest of this describes
+    #[serde(rename = "messageId")]
+    pub message_id: i64,
+    pub role: String,
+    pub preview: String,
+    // Messages sharing the file, duplicates included
+    pub messages: usize,
+    // `None` once the message isn't in any conversation outside the trash
+    #[serde(rename = "conversationId")]
+    pub conversation_id: Option<i64>,
+    #[serde(rename = "conversationName")]
+    pub conversation_name: Option<String>,
+    #[serde(rename = "dateCreated")]
+    pub date_created: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct MemoryList {
+    pub memories: Vec<Memory>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ForgetMemory {
+    #[serde(rename = "memoryId")]
+    pub memory_id: i64,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct IndexRepoRequest {
+    pub path: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RestoreConversation {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TrashedConversation {
+    pub id: i64,
+    pub name: String,
+    #[serde(rename = "deletedAt")]
+    pub deleted_at: String,
+}
+
+// Deleted conversations, kept around until they're purged
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TrashList {
+    pub conversations: Vec<TrashedConversation>,
+    // Days a conversation stays in the trash before it's purged for good
+    #[serde(rename = "retentionDays")]
+    pub retention_days: u32,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct UsageRequest {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: Option<i64>,
+    pub api: API,
+    #[serde(rename = "dateFrom")]
+    pub date_from: String,
+    #[serde(rename = "dateTo")]
+    pub date_to: String,
+    // What days are bucketed by--`local` (the default), `UTC`, or an offset like `-07:00`
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub granularity: UsageGranularity,
+}
+
+// The same completion from several models at once, each stored on its own branch
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CompareCompletion {
+    pub conversation: Conversation,
+    pub models: Vec<API>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct StatsRequest {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    // The active branch if unset
+    #[serde(default, rename = "branchId")]
+    pub branch_id: Option<i64>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct AuthorStats {
+    pub messages: usize,
+    pub words: usize,
+    pub characters: usize,
+    // Fraction of the conversation's words written by this author
+    pub share: f64,
+}
+
+// Word counts + reading time for a conversation's branch, split between the user and assistant
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ConversationStats {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    pub messages: usize,
+    pub words: usize,
+    pub characters: usize,
+    #[serde(rename = "readingTimeSeconds")]
+    pub reading_time_seconds: u64,
+    pub user: AuthorStats,
+    pub assistant: AuthorStats,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ContextBreakdownRequest {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    // The active branch if unset
+    #[serde(default, rename = "branchId")]
+    pub branch_id: Option<i64>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct MessageTokens {
+    #[serde(rename = "messageId")]
+    pub message_id: Option<i64>,
+    #[serde(rename = "messageType")]
+    pub message_type: MessageType,
+    pub tokens: usize,
+    // Whether the message still fits--the rest are left to the conversation's summary
+    pub included: bool,
+}
+
+// Token counts for what the next prompt would be made of--see context.rs
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ContextBreakdown {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    pub model: API,
+    #[serde(rename = "contextWindow")]
+    pub context_window: usize,
+    // Instructions, summary, and the boilerplate around them
+    #[serde(rename = "systemPrompt")]
+    pub system_prompt: usize,
+    // One per reference, estimated from the last prompt
+    pub references: Vec<usize>,
+    pub messages: Vec<MessageTokens>,
+    pub used: usize,
+    pub remaining: usize,
+    // How the tokens were counted, e.g. `o200k_base`
+    pub estimator: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TranslateRequest {
+    #[serde(rename = "messageId")]
+    pub message_id: i64,
+    // A language name or code, passed along to the model as-is
+    #[serde(rename = "targetLanguage")]
+    pub target_language: String,
+    // `WILLIAM_TRANSLATION_MODEL` (or gpt-4o-mini) if unset
+    #[serde(default)]
+    pub api: Option<API>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Translation {
+    #[serde(rename = "messageId")]
+    pub message_id: i64,
+    #[serde(rename = "sourceLanguage")]
+    pub source_language: Option<String>,
+    #[serde(rename = "targetLanguage")]
+    pub target_language: String,
+    pub content: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ToolResult {
+    #[serde(rename = "toolCallId")]
+    pub tool_call_id: String,
+    pub content: String,
+}
+
+// Results for every tool call in the last assistant message of the conversation
+// The completion is continued once these are appended
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ToolResultRequest {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    pub results: Vec<ToolResult>,
+}
+
+// Stops the streaming completion started by the request with the given ID
+// Whatever's been received up to that point is kept
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CancelCompletion {
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SearchRequest {
+    pub query: String,
+    // Max number of matching messages
+    pub limit: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq)]
+pub enum ExportFormat {
+    #[serde(rename = "markdown")]
+    Markdown,
+    #[serde(rename = "json")]
+    Json,
+    #[serde(rename = "pdf")]
+    Pdf,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Json => "json",
+            ExportFormat::Pdf => "pdf",
+        }
+    }
+}
+
+// Without a path, the rendered conversation is sent back in the response instead of written out--
+// base64-encoded, for PDFs
+// A path to a directory gets a file named after the conversation
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ExportRequest {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    pub format: ExportFormat,
+    pub path: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ExportFlashcardsRequest {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    // `WILLIAM_FLASHCARD_MODEL` (or gpt-4o-mini) if unset
+    #[serde(default)]
+    pub api: Option<API>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Flashcard {
+    pub question: String,
+    pub answer: String,
+}
+
+// The cards, and where in the exports directory they were written for Anki
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct FlashcardExport {
+    pub path: String,
+    pub cards: Vec<Flashcard>,
+}
+
+// Like `ExportRequest`, a path to a directory gets a file with the default name
+// API keys are only written out with `includeKeys`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ExportSettingsRequest {
+    pub path: String,
+    #[serde(default, rename = "includeKeys")]
+    pub include_keys: bool,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ImportSettingsRequest {
+    pub path: String,
+}
+
+// A path to a directory gets a file named after when the backup was taken
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct BackupRequest {
+    pub path: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RestoreRequest {
+    pub path: String,
+}
+
+// `endpoint` is the base URL of the remote, which has to take `GET`s and `PUT`s under it
+// Every device syncing with the same remote needs the same passphrase
+// `lastSeenResponseId` is the `seq` of the last response the client got from the session
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ResumeRequest {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    #[serde(rename = "lastSeenResponseId")]
+    pub last_seen_response_id: u64,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct EnableSyncRequest {
+    pub endpoint: String,
+    pub passphrase: String,
+    // Sent as a bearer token, if the remote wants one
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq)]
+pub enum ImportFormat {
+    #[serde(rename = "chatgpt")]
+    ChatGPT,
+    #[serde(rename = "claude")]
+    Claude,
+}
+
+impl ImportFormat {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            ImportFormat::ChatGPT => "chatgpt",
+            ImportFormat::Claude => "claude",
+        }
+    }
+}
+
+// `path` is an export's `conversations.json`, or the directory it was unzipped to
+// The format is detected from the file if it isn't given
+// With `embed`, imported messages are also added to Dewey--this can take a while for large exports
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ImportRequest {
+    pub path: String,
+    pub format: Option<ImportFormat>,
+    #[serde(default)]
+    pub embed: bool,
+}
+
+// A file attached to a message
+// The contents are stored separately, addressed by their SHA-256
+//
+// Messages sent to `Completion` can skip the upload and carry the file as `data` or `path`--
+// it's stored then, and only the hash is kept
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Attachment {
+    #[serde(default)]
+    pub hash: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(rename = "mimeType", default)]
+    pub mime_type: String,
+    #[serde(default)]
+    pub size: u64,
+    // Base64 contents--otherwise only filled in for provider requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    // A file on this machine
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+impl Attachment {
+    pub fn is_image(&self) -> bool {
+        self.mime_type.starts_with("image/")
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct AttachmentUpload {
+    pub name: String,
+    #[serde(rename = "mimeType")]
+    pub mime_type: String,
+    // Base64
+    pub data: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum RequestPayload {
+    Ping(Ping),
+    Completion(Conversation),
+    CompareCompletion(CompareCompletion),
+    ConversationList,
+    ListArchived,
+    Pin(ConversationFlag),
+    Unpin(ConversationFlag),
+    Archive(ConversationFlag),
+    Unarchive(ConversationFlag),
+    MarkRead(MarkRead),
+    Load(LoadConversation),
+    Fork(Fork),
+    EditMessage(EditMessage),
+    Redirect(Redirect),
+    Continue(ContinueCompletion),
+    Branches(BranchesRequest),
+    SwitchBranch(SwitchBranch),
+    SnapshotConversation(SnapshotConversation),
+    Snapshots(SnapshotsRequest),
+    RestoreSnapshot(RestoreSnapshot),
+    Config(UserConfig),
+    Preview(Preview),
+    DeleteConversation(DeleteConversation),
+    Trash,
+    RestoreConversation(RestoreConversation),
+    PurgeTrash,
+    Usage(UsageRequest),
+    Stats(StatsRequest),
+    ContextBreakdown(ContextBreakdownRequest),
+    Translate(TranslateRequest),
+    ToolResult(ToolResultRequest),
+    CancelCompletion(CancelCompletion),
+    Search(SearchRequest),
+    Export(ExportRequest),
+    ExportFlashcards(ExportFlashcardsRequest),
+    Import(ImportRequest),
+    ExportSettings(ExportSettingsRequest),
+    ImportSettings(ImportSettingsRequest),
+    UploadAttachment(AttachmentUpload),
+    LocalModels,
+    Models,
+    Personas,
+    SavePersona(Persona),
+    DeletePersona(DeletePersona),
+    PromptTemplates,
+    SavePromptTemplate(PromptTemplate),
+    DeletePromptTemplate(DeletePromptTemplate),
+    WatchedFolders,
+    AddWatchedFolder(AddWatchedFolder),
+    RemoveWatchedFolder(RemoveWatchedFolder),
+    Repositories,
+    IndexRepo(IndexRepoRequest),
+    Backup(BackupRequest),
+    Restore(RestoreRequest),
+    EnableSync(EnableSyncRequest),
+    DisableSync,
+    Resume(ResumeRequest),
+    SyncNow,
+    Status,
+}
+
+/// Request in JSON form looks like
+/// {
+///     "method": <request enum type>,
+///     "payload": {
+///         <request payload JSON>
+///     }
+/// }
+///
+/// e.g.,
+/// // Update configuration
+/// {
+///   "method": "Config",
+///   "payload": {
+///     "write": true,
+///     "apiKeys": {
+///       "openai": "sk-...",
+///       "groq": "gsk-...",
+///       "grok": "...",
+///       "anthropic": "sk-ant-...",
+///       "gemini": "..."
+///     },
+///     "systemPrompt": "You are a helpful assistant"
 ///   }
 /// }
 ///
@@ -373,16 +1691,83 @@ pub enum ArrakisRequest {
         id: String,
         payload: Conversation,
     },
+    // Streams back a response from each of 2-4 models, on sibling branches
+    CompareCompletion {
+        id: String,
+        payload: CompareCompletion,
+    },
     ConversationList {
         id: String,
     },
+    ListArchived {
+        id: String,
+    },
+    Pin {
+        id: String,
+        payload: ConversationFlag,
+    },
+    Unpin {
+        id: String,
+        payload: ConversationFlag,
+    },
+    Archive {
+        id: String,
+        payload: ConversationFlag,
+    },
+    Unarchive {
+        id: String,
+        payload: ConversationFlag,
+    },
+    MarkRead {
+        id: String,
+        payload: MarkRead,
+    },
     Load {
         id: String,
         payload: LoadConversation,
     },
-    Fork {
+    Fork {
+        id: String,
+        payload: Fork,
+    },
+    EditMessage {
+        id: String,
+        payload: EditMessage,
+    },
+    // Responds with the conversation, system message included
+    InsertSystemMessage {
+        id: String,
+        payload: InsertSystemMessage,
+    },
+    Redirect {
+        id: String,
+        payload: Redirect,
+    },
+    Continue {
+        id: String,
+        payload: ContinueCompletion,
+    },
+    Branches {
+        id: String,
+        payload: BranchesRequest,
+    },
+    SwitchBranch {
+        id: String,
+        payload: SwitchBranch,
+    },
+    // Saves the conversation's current path under a label, without forking it
+    SnapshotConversation {
+        id: String,
+        payload: SnapshotConversation,
+    },
+    Snapshots {
+        id: String,
+        payload: SnapshotsRequest,
+    },
+    // Branches off from a snapshot and switches to it, responding with `Load`
+    RestoreSnapshot {
         id: String,
-        payload: Fork,
+        payload: RestoreSnapshot,
     },
     Config {
         id: String,
@@ -400,10 +1785,461 @@ pub enum ArrakisRequest {
         id: String,
         payload: DeleteConversation,
     },
+    Trash {
+        id: String,
+    },
+    RestoreConversation {
+        id: String,
+        payload: RestoreConversation,
+    },
+    PurgeTrash {
+        id: String,
+    },
     Usage {
         id: String,
         payload: UsageRequest,
     },
+    Stats {
+        id: String,
+        payload: StatsRequest,
+    },
+    // How much of the model's context window the next prompt would use, and on what
+    ContextBreakdown {
+        id: String,
+        payload: ContextBreakdownRequest,
+    },
+    Translate {
+        id: String,
+        payload: TranslateRequest,
+    },
+    ToolResult {
+        id: String,
+        payload: ToolResultRequest,
+    },
+    CancelCompletion {
+        id: String,
+        payload: CancelCompletion,
+    },
+    Search {
+        id: String,
+        payload: SearchRequest,
+    },
+    Export {
+        id: String,
+        payload: ExportRequest,
+    },
+    // Q/A pairs drawn out of the conversation by a model, written out as an Anki import
+    ExportFlashcards {
+        id: String,
+        payload: ExportFlashcardsRequest,
+    },
+    Import {
+        id: String,
+        payload: ImportRequest,
+    },
+    ExportSettings {
+        id: String,
+        payload: ExportSettingsRequest,
+    },
+    ImportSettings {
+        id: String,
+        payload: ImportSettingsRequest,
+    },
+    UploadAttachment {
+        id: String,
+        payload: AttachmentUpload,
+    },
+    LocalModels {
+        id: String,
+    },
+    // Every model the stored keys were last found to have access to
+    Models {
+        id: String,
+    },
+    Personas {
+        id: String,
+    },
+    // Creates or updates a persona, depending on whether it has an ID
+    SavePersona {
+        id: String,
+        payload: Persona,
+    },
+    DeletePersona {
+        id: String,
+        payload: DeletePersona,
+    },
+    PromptTemplates {
+        id: String,
+    },
+    // Creates or updates a template, depending on whether it has an ID
+    SavePromptTemplate {
+        id: String,
+        payload: PromptTemplate,
+    },
+    DeletePromptTemplate {
+        id: String,
+        payload: DeletePromptTemplate,
+    },
+    WatchedFolders {
+        id: String,
+    },
+    // The folder is scanned right away rather than waiting for the next pass
+    AddWatchedFolder {
+        id: String,
+        payload: AddWatchedFolder,
+    },
+    // Forgets the folder's files and their chunks--the files themselves are left alone
+    RemoveWatchedFolder {
+        id: String,
+        payload: RemoveWatchedFolder,
+    },
+    Repositories {
+        id: String,
+    },
+    // Adds the repository if it's new, then (re-)indexes it in the background
+    // The response is the repository list as of the start--`Repositories` shows the progress
+    IndexRepo {
+        id: String,
+        payload: IndexRepoRequest,
+    },
+    // Embeds whatever messages aren't yet, in the background--see backfill.rs
+    // The response is the pass's progress as of the start; sending it again while the pass is
+    // running just reports the progress
+    ReindexMemory {
+        id: String,
+    },
+    Memories {
+        id: String,
+    },
+    // Removes the memory from Dewey, along with any duplicates sharing it
+    // Its messages aren't embedded again by `ReindexMemory`
+    ForgetMemory {
+        id: String,
+        payload: ForgetMemory,
+    },
+    // `ForgetMemory` for every memory at once
+    ForgetAllMemories {
+        id: String,
+    },
+    // The database, Dewey's index, and everything they point at, as one archive
+    Backup {
+        id: String,
+        payload: BackupRequest,
+    },
+    // Checks a backup and stages it to replace everything on the next start up
+    Restore {
+        id: String,
+        payload: RestoreRequest,
+    },
+    // Connects to the remote and syncs right away
+    EnableSync {
+        id: String,
+        payload: EnableSyncRequest,
+    },
+    // Stops syncing--nothing already synced is removed, here or on the remote
+    DisableSync {
+        id: String,
+    },
+    // Pushes local changes, then pulls everything other devices have pushed since the last sync
+    SyncNow {
+        id: String,
+    },
+    Status {
+        id: String,
+    },
+    // Picks a dropped session back up on a new connection--see session.rs
+    Resume {
+        id: String,
+        payload: ResumeRequest,
+    },
+}
+
+impl ArrakisRequest {
+    pub fn id(&self) -> &str {
+        match self {
+            ArrakisRequest::Ping { id, .. } => id,
+            ArrakisRequest::Completion { id, .. } => id,
+            ArrakisRequest::CompareCompletion { id, .. } => id,
+            ArrakisRequest::ConversationList { id, .. } => id,
+            ArrakisRequest::ListArchived { id, .. } => id,
+            ArrakisRequest::Pin { id, .. } => id,
+            ArrakisRequest::Unpin { id, .. } => id,
+            ArrakisRequest::Archive { id, .. } => id,
+            ArrakisRequest::Unarchive { id, .. } => id,
+            ArrakisRequest::MarkRead { id, .. } => id,
+            ArrakisRequest::Load { id, .. } => id,
+            ArrakisRequest::Fork { id, .. } => id,
+            ArrakisRequest::EditMessage { id, .. } => id,
+            ArrakisRequest::InsertSystemMessage { id, .. } => id,
+            ArrakisRequest::Redirect { id, .. } => id,
+            ArrakisRequest::Continue { id, .. } => id,
+            ArrakisRequest::Branches { id, .. } => id,
+            ArrakisRequest::SwitchBranch { id, .. } => id,
+            ArrakisRequest::SnapshotConversation { id, .. } => id,
+            ArrakisRequest::Snapshots { id, .. } => id,
+            ArrakisRequest::RestoreSnapshot { id, .. } => id,
+            ArrakisRequest::Config { id, .. } => id,
+            ArrakisRequest::WilliamError { id, .. } => id,
+            ArrakisRequest::Preview { id, .. } => id,
+            ArrakisRequest::DeleteConversation { id, .. } => id,
+            ArrakisRequest::Trash { id, .. } => id,
+            ArrakisRequest::RestoreConversation { id, .. } => id,
+            ArrakisRequest::PurgeTrash { id, .. } => id,
+            ArrakisRequest::Usage { id, .. } => id,
+            ArrakisRequest::Stats { id, .. } => id,
+            ArrakisRequest::ContextBreakdown { id, .. } => id,
+            ArrakisRequest::Translate { id, .. } => id,
+            ArrakisRequest::ToolResult { id, .. } => id,
+            ArrakisRequest::CancelCompletion { id, .. } => id,
+            ArrakisRequest::Search { id, .. } => id,
+            ArrakisRequest::Export { id, .. } => id,
+            ArrakisRequest::ExportFlashcards { id, .. } => id,
+            ArrakisRequest::Import { id, .. } => id,
+            ArrakisRequest::ExportSettings { id, .. } => id,
+            ArrakisRequest::ImportSettings { id, .. } => id,
+            ArrakisRequest::UploadAttachment { id, .. } => id,
+            ArrakisRequest::LocalModels { id, .. } => id,
+            ArrakisRequest::Models { id, .. } => id,
+            ArrakisRequest::Personas { id, .. } => id,
+            ArrakisRequest::SavePersona { id, .. } => id,
+            ArrakisRequest::DeletePersona { id, .. } => id,
+            ArrakisRequest::PromptTemplates { id, .. } => id,
+            ArrakisRequest::SavePromptTemplate { id, .. } => id,
+            ArrakisRequest::DeletePromptTemplate { id, .. } => id,
+            ArrakisRequest::WatchedFolders { id, .. } => id,
+            ArrakisRequest::AddWatchedFolder { id, .. } => id,
+            ArrakisRequest::RemoveWatchedFolder { id, .. } => id,
+            ArrakisRequest::Repositories { id, .. } => id,
+            ArrakisRequest::IndexRepo { id, .. } => id,
+            ArrakisRequest::ReindexMemory { id, .. } => id,
+            ArrakisRequest::Memories { id, .. } => id,
+            ArrakisRequest::ForgetMemory { id, .. } => id,
+            ArrakisRequest::ForgetAllMemories { id, .. } => id,
+            ArrakisRequest::Backup { id, .. } => id,
+            ArrakisRequest::Restore { id, .. } => id,
+            ArrakisRequest::EnableSync { id, .. } => id,
+            ArrakisRequest::DisableSync { id, .. } => id,
+            ArrakisRequest::SyncNow { id, .. } => id,
+            ArrakisRequest::Status { id, .. } => id,
+            ArrakisRequest::Resume { id, .. } => id,
+        }
+    }
+
+    // The request's `method`, e.g. for naming its span
+    pub fn method(&self) -> &'static str {
+        match self {
+            ArrakisRequest::Ping { .. } => "Ping",
+            ArrakisRequest::Completion { .. } => "Completion",
+            ArrakisRequest::CompareCompletion { .. } => "CompareCompletion",
+            ArrakisRequest::ConversationList { .. } => "ConversationList",
+            ArrakisRequest::ListArchived { .. } => "ListArchived",
+            ArrakisRequest::Pin { .. } => "Pin",
+            ArrakisRequest::Unpin { .. } => "Unpin",
+            ArrakisRequest::Archive { .. } => "Archive",
+            ArrakisRequest::Unarchive { .. } => "Unarchive",
+            ArrakisRequest::MarkRead { .. } => "MarkRead",
+            ArrakisRequest::Load { .. } => "Load",
+            ArrakisRequest::Fork { .. } => "Fork",
+            ArrakisRequest::EditMessage { .. } => "EditMessage",
+            ArrakisRequest::InsertSystemMessage { .. } => "InsertSystemMessage",
+            ArrakisRequest::Redirect { .. } => "Redirect",
+            ArrakisRequest::Continue { .. } => "Continue",
+            ArrakisRequest::Branches { .. } => "Branches",
+            ArrakisRequest::SwitchBranch { .. } => "SwitchBranch",
+            ArrakisRequest::SnapshotConversation { .. } => "SnapshotConversation",
+            ArrakisRequest::Snapshots { .. } => "Snapshots",
+            ArrakisRequest::RestoreSnapshot { .. } => "RestoreSnapshot",
+            ArrakisRequest::Config { .. } => "Config",
+            ArrakisRequest::WilliamError { .. } => "WilliamError",
+            ArrakisRequest::Preview { .. } => "Preview",
+            ArrakisRequest::DeleteConversation { .. } => "DeleteConversation",
+            ArrakisRequest::Trash { .. } => "Trash",
+            ArrakisRequest::RestoreConversation { .. } => "RestoreConversation",
+            ArrakisRequest::PurgeTrash { .. } => "PurgeTrash",
+            ArrakisRequest::Usage { .. } => "Usage",
+            ArrakisRequest::Stats { .. } => "Stats",
+            ArrakisRequest::ContextBreakdown { .. } => "ContextBreakdown",
+            ArrakisRequest::Translate { .. } => "Translate",
+            ArrakisRequest::ToolResult { .. } => "ToolResult",
+            ArrakisRequest::CancelCompletion { .. } => "CancelCompletion",
+            ArrakisRequest::Search { .. } => "Search",
+            ArrakisRequest::Export { .. } => "Export",
+            ArrakisRequest::ExportFlashcards { .. } => "ExportFlashcards",
+            ArrakisRequest::Import { .. } => "Import",
+            ArrakisRequest::ExportSettings { .. } => "ExportSettings",
+            ArrakisRequest::ImportSettings { .. } => "ImportSettings",
+            ArrakisRequest::UploadAttachment { .. } => "UploadAttachment",
+            ArrakisRequest::LocalModels { .. } => "LocalModels",
+            ArrakisRequest::Models { .. } => "Models",
+            ArrakisRequest::Personas { .. } => "Personas",
+            ArrakisRequest::SavePersona { .. } => "SavePersona",
+            ArrakisRequest::DeletePersona { .. } => "DeletePersona",
+            ArrakisRequest::PromptTemplates { .. } => "PromptTemplates",
+            ArrakisRequest::SavePromptTemplate { .. } => "SavePromptTemplate",
+            ArrakisRequest::DeletePromptTemplate { .. } => "DeletePromptTemplate",
+            ArrakisRequest::WatchedFolders { .. } => "WatchedFolders",
+            ArrakisRequest::AddWatchedFolder { .. } => "AddWatchedFolder",
+            ArrakisRequest::RemoveWatchedFolder { .. } => "RemoveWatchedFolder",
+            ArrakisRequest::Repositories { .. } => "Repositories",
+            ArrakisRequest::IndexRepo { .. } => "IndexRepo",
+            ArrakisRequest::ReindexMemory { .. } => "ReindexMemory",
+            ArrakisRequest::Memories { .. } => "Memories",
+            ArrakisRequest::ForgetMemory { .. } => "ForgetMemory",
+            ArrakisRequest::ForgetAllMemories { .. } => "ForgetAllMemories",
+            ArrakisRequest::Backup { .. } => "Backup",
+            ArrakisRequest::Restore { .. } => "Restore",
+            ArrakisRequest::EnableSync { .. } => "EnableSync",
+            ArrakisRequest::DisableSync { .. } => "DisableSync",
+            ArrakisRequest::SyncNow { .. } => "SyncNow",
+            ArrakisRequest::Status { .. } => "Status",
+            ArrakisRequest::Resume { .. } => "Resume",
+        }
+    }
+}
+
+// What a completion's stream thread sends back to the connection
+// Every stream ends with `Done` or `Error`--the channel closing without either means the thread died
+#[derive(Clone, Debug)]
+pub enum StreamEvent {
+    Delta(String),
+    // With the usage the provider reported, if it did
+    Done(Option<TokenUsage>),
+    Error(String),
+}
+
+// Sent while a provider request is being retried
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RetryStatus {
+    pub attempt: u32,
+    #[serde(rename = "maxAttempts")]
+    pub max_attempts: u32,
+    #[serde(rename = "delayMs")]
+    pub delay_ms: u64,
+    pub error: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SearchMatch {
+    #[serde(rename = "messageId")]
+    pub message_id: i64,
+    pub snippet: String,
+}
+
+// A conversation with every message in it that matched the search
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SearchResult {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    pub name: String,
+    pub matches: Vec<SearchMatch>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SearchResponse {
+    pub results: Vec<SearchResult>,
+}
+
+// Exactly one of these is set, depending on whether the request had a path
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ExportResponse {
+    pub content: Option<String>,
+    pub path: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct BackupResponse {
+    pub path: String,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+// The restore is only staged--it takes effect the next time William starts
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RestoreResponse {
+    pub files: usize,
+    pub bytes: u64,
+    // Seconds since the epoch
+    pub created: u64,
+    #[serde(rename = "restartRequired")]
+    pub restart_required: bool,
+    // Providers whose backed up keys can't be decrypted on this machine--see `secrets.rs`
+    // They're kept as they are, but need to be set again before they'll work
+    #[serde(rename = "unreadableKeys", default)]
+    pub unreadable_keys: Vec<String>,
+}
+
+// `pushed` and `pulled` count conversations, and are only nonzero right after a sync
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SyncStatus {
+    pub enabled: bool,
+    pub endpoint: Option<String>,
+    #[serde(rename = "deviceId")]
+    pub device_id: Option<String>,
+    #[serde(rename = "lastSync")]
+    pub last_sync: Option<String>,
+    pub pushed: usize,
+    pub pulled: usize,
+}
+
+// Conversations that were already imported are skipped
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ImportResponse {
+    #[serde(rename = "conversationIds")]
+    pub conversation_ids: Vec<i64>,
+    pub skipped: usize,
+}
+
+// Sent once a conversation (or a branch of one) has been named in the background
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ConversationRenamed {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    #[serde(rename = "branchId", skip_serializing_if = "Option::is_none")]
+    pub branch_id: Option<i64>,
+    pub name: String,
+}
+
+// Models a provider (or the local endpoint) reports as available
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ModelList {
+    pub models: Vec<API>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct StatusResponse {
+    // Open frontend connections, dead ones aside
+    pub connections: usize,
+    // Provider requests running and waiting their turn--see queue.rs
+    pub queue: Vec<ProviderQueueStatus>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ProviderQueueStatus {
+    pub provider: String,
+    // Most requests allowed to run at once
+    pub limit: usize,
+    pub running: usize,
+    // Waiting, by priority
+    pub interactive: usize,
+    pub background: usize,
+}
+
+// Sent first thing on every connection
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SessionInfo {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+}
+
+// `sessionId` is the session the connection carries on with--the new one, if `resumed` is false
+// `complete` is false when some of what was missed was too old to replay
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ResumeResponse {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    pub resumed: bool,
+    pub replayed: usize,
+    pub complete: bool,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -417,17 +2253,118 @@ pub enum ResponsePayload {
     Config(UserConfig),
     WilliamError(WilliamError),
     Preview(Preview),
+    ToolCall(ToolCallResponse),
+    Retry(RetryStatus),
+    Search(SearchResponse),
+    Export(ExportResponse),
+    Import(ImportResponse),
+    Status(StatusResponse),
+    Branches(BranchList),
+    Trash(TrashList),
+    ConversationRenamed(ConversationRenamed),
+    Attachment(Attachment),
+    Models(ModelList),
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct WilliamError {
     pub error_type: String,
     pub message: String,
+    // Field-by-field problems with an `InvalidRequest`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<ValidationError>,
+    // What the provider said, when it's the provider that failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<ProviderError>,
+}
+
+impl WilliamError {
+    pub fn from_provider(e: &ProviderError) -> Self {
+        Self {
+            error_type: e.category.error_type().to_string(),
+            message: e.to_string(),
+            details: Vec::new(),
+            provider: Some(e.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ValidationError {
+    // Path into the request payload, e.g. `messages[2].content`
+    pub field: String,
+    pub message: String,
+}
+
+// What went wrong on the provider's end, as far as the user is concerned
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ProviderErrorCategory {
+    Auth,
+    RateLimit,
+    ContextLength,
+    ContentFilter,
+    Server,
+    Unknown,
+}
+
+impl ProviderErrorCategory {
+    // `WilliamError.error_type` for the frontend
+    pub fn error_type(self) -> &'static str {
+        match self {
+            ProviderErrorCategory::Auth => "InvalidAPIKey",
+            ProviderErrorCategory::RateLimit => "RateLimited",
+            ProviderErrorCategory::ContextLength => "ContextLengthExceeded",
+            ProviderErrorCategory::ContentFilter => "ContentFiltered",
+            ProviderErrorCategory::Server => "ProviderUnavailable",
+            ProviderErrorCategory::Unknown => "ProviderError",
+        }
+    }
+}
+
+// A failed request, parsed out of the provider's error response
+// Carried inside the `std::io::Error`s coming out of the network layer--see `ProviderError::from_io`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ProviderError {
+    pub provider: String,
+    // 200 for errors sent partway through a stream
+    pub status: u16,
+    // The provider's own error code/type, e.g. `invalid_api_key`
+    pub code: Option<String>,
+    pub category: ProviderErrorCategory,
+    pub message: String,
+}
+
+impl ProviderError {
+    pub fn from_io(e: &std::io::Error) -> Option<&ProviderError> {
+        e.get_ref()?.downcast_ref::<ProviderError>()
+    }
 }
 
+impl std::fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}): {}", self.provider, self.status, self.message)
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct SystemPrompt {
     pub content: String,
+    // The references the response cited, if any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub citations: Vec<Citation>,
+}
+
+// Follow-ups for the user to pick from, for conversations with suggestions on
+// These are generated after the response is done, so they come after its `CompletionEnd`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Suggestions {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    #[serde(rename = "responseId")]
+    pub response_id: i64,
+    pub suggestions: Vec<String>,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -441,6 +2378,21 @@ pub struct Completion {
     pub request_id: i64,
     #[serde(rename = "responseId")]
     pub response_id: i64,
+    // Which model the delta is from, for `CompareCompletion` streams
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<API>,
+}
+
+// Sent after a completion in which the model requested tool calls
+// The client is expected to run them and respond with a `ToolResult` request
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ToolCallResponse {
+    #[serde(rename = "conversationId")]
+    pub conversation_id: i64,
+    #[serde(rename = "responseId")]
+    pub response_id: i64,
+    #[serde(rename = "toolCalls")]
+    pub tool_calls: Vec<ToolCall>,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -449,13 +2401,42 @@ pub struct TokenUsage {
     pub input_tokens: usize,
     #[serde(rename = "outputTokens")]
     pub output_tokens: usize,
+    // Set when Chamber counted the tokens itself instead of the provider
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimator: Option<String>,
+}
+
+// How long a streamed response took, from the request going out
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResponseTiming {
+    // Unset when nothing came back before the stream ended
+    pub time_to_first_token_ms: Option<u64>,
+    pub generation_ms: u64,
+}
+
+// A model's average response times over a usage range
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ModelLatency {
+    #[serde(rename = "avgTimeToFirstTokenMs")]
+    pub avg_time_to_first_token_ms: Option<f64>,
+    #[serde(rename = "avgGenerationMs")]
+    pub avg_generation_ms: f64,
+    // Number of timed responses the averages are over
+    pub responses: usize,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct UsageResponse {
     #[serde(rename = "tokenUsage")]
     pub token_usage: Vec<std::collections::HashMap<String, TokenUsage>>,
+    // `YYYY-MM-DD` for the start of each bucket, in `timezone`
     pub dates: Vec<String>,
+    pub timezone: String,
+    pub granularity: UsageGranularity,
+    // Each model's usage over the whole range
+    pub totals: std::collections::HashMap<String, TokenUsage>,
+    // Each model's response times over the whole range
+    pub latency: std::collections::HashMap<String, ModelLatency>,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -473,10 +2454,23 @@ pub enum ArrakisResponse {
         id: String,
         payload: SystemPrompt,
     },
+    Suggestions {
+        id: String,
+        payload: Suggestions,
+    },
+    // What went into the system prompt for a completion, sent before its first delta
+    References {
+        id: String,
+        payload: References,
+    },
     ConversationList {
         id: String,
         payload: ConversationList,
     },
+    ListArchived {
+        id: String,
+        payload: ConversationList,
+    },
     Load {
         id: String,
         payload: Conversation,
@@ -497,6 +2491,122 @@ pub enum ArrakisResponse {
         id: String,
         payload: UsageResponse,
     },
+    Stats {
+        id: String,
+        payload: ConversationStats,
+    },
+    ContextBreakdown {
+        id: String,
+        payload: ContextBreakdown,
+    },
+    Translate {
+        id: String,
+        payload: Translation,
+    },
+    ToolCall {
+        id: String,
+        payload: ToolCallResponse,
+    },
+    Retry {
+        id: String,
+        payload: RetryStatus,
+    },
+    Search {
+        id: String,
+        payload: SearchResponse,
+    },
+    Export {
+        id: String,
+        payload: ExportResponse,
+    },
+    Import {
+        id: String,
+        payload: ImportResponse,
+    },
+    ExportSettings {
+        id: String,
+        payload: ExportResponse,
+    },
+    ExportFlashcards {
+        id: String,
+        payload: FlashcardExport,
+    },
+    Status {
+        id: String,
+        payload: StatusResponse,
+    },
+    Session {
+        id: String,
+        payload: SessionInfo,
+    },
+    Resumed {
+        id: String,
+        payload: ResumeResponse,
+    },
+    Branches {
+        id: String,
+        payload: BranchList,
+    },
+    Snapshot {
+        id: String,
+        payload: Snapshot,
+    },
+    Snapshots {
+        id: String,
+        payload: SnapshotList,
+    },
+    Trash {
+        id: String,
+        payload: TrashList,
+    },
+    ConversationRenamed {
+        id: String,
+        payload: ConversationRenamed,
+    },
+    Attachment {
+        id: String,
+        payload: Attachment,
+    },
+    Models {
+        id: String,
+        payload: ModelList,
+    },
+    Personas {
+        id: String,
+        payload: PersonaList,
+    },
+    PromptTemplates {
+        id: String,
+        payload: PromptTemplateList,
+    },
+    WatchedFolders {
+        id: String,
+        payload: WatchedFolderList,
+    },
+    Repositories {
+        id: String,
+        payload: RepositoryList,
+    },
+    ReindexMemory {
+        id: String,
+        payload: BackfillProgress,
+    },
+    Memories {
+        id: String,
+        payload: MemoryList,
+    },
+    Backup {
+        id: String,
+        payload: BackupResponse,
+    },
+    Restore {
+        id: String,
+        payload: RestoreResponse,
+    },
+    Sync {
+        id: String,
+        payload: SyncStatus,
+    },
 }
 
 // search.rs (for Dewey-related structures)
@@ -522,6 +2632,8 @@ pub struct DeweyResponse {
 #[derive(Clone, Debug)]
 pub struct RequestParams {
     pub provider: String,
+    // `https` for everything but local servers
+    pub scheme: String,
     pub host: String,
     pub path: String,
     pub port: u16,
@@ -529,6 +2641,9 @@ pub struct RequestParams {
     pub model: String,
     pub stream: bool,
     pub authorization_token: String,
-    pub max_tokens: Option<u16>,
+    // Default for the provider, for the ones that require it
+    pub max_tokens: Option<u32>,
     pub system_prompt: Option<String>,
+    pub tools: Vec<Tool>,
+    pub settings: GenerationSettings,
 }
@@ -0,0 +1,252 @@
+use rusqlite::params;
+
+use crate::types::*;
+
+// Timestamps are stored in UTC--usage is only shifted into the user's time zone
+// when it's bucketed, so evenings don't get split at UTC midnight
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UsageTimezone {
+    // The machine's own time zone, daylight saving included
+    Local,
+    // A fixed offset from UTC, in minutes
+    Offset(i32),
+}
+
+impl UsageTimezone {
+    // `local`, `UTC`/`Z`, or an offset like `+05:30`, `-0700`, or `-7`
+    pub fn parse(timezone: &str) -> Result<Self, String> {
+        let timezone = timezone.trim();
+        match timezone.to_ascii_lowercase().as_str() {
+            "" | "local" | "localtime" => return Ok(UsageTimezone::Local),
+            "utc" | "gmt" | "z" => return Ok(UsageTimezone::Offset(0)),
+            _ => {}
+        }
+
+        let invalid = || {
+            format!(
+                "Invalid timezone {}: expected `local`, `UTC`, or an offset like `+05:30`",
+                timezone
+            )
+        };
+
+        let offset = timezone
+            .strip_prefix("UTC")
+            .or_else(|| timezone.strip_prefix("GMT"))
+            .unwrap_or(timezone);
+        let (sign, offset) = match offset.chars().next() {
+            Some('+') => (1, &offset[1..]),
+            Some('-') => (-1, &offset[1..]),
+            _ => return Err(invalid()),
+        };
+
+        let (hours, minutes) = match offset.split_once(':') {
+            Some((h, m)) => (h, m),
+            None if offset.len() == 4 => offset.split_at(2),
+            None => (offset, "0"),
+        };
+
+        let hours = hours.parse::<i32>().map_err(|_| invalid())?;
+        let minutes = minutes.parse::<i32>().map_err(|_| invalid())?;
+        if hours > 14 || minutes >= 60 {
+            return Err(invalid());
+        }
+
+        Ok(UsageTimezone::Offset(sign * (hours * 60 + minutes)))
+    }
+
+    // SQLite date modifier shifting a UTC timestamp into this time zone
+    pub fn modifier(&self) -> String {
+        match self {
+            UsageTimezone::Local => "localtime".to_string(),
+            UsageTimezone::Offset(minutes) => format!("{:+} minutes", minutes),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            UsageTimezone::Local => "local".to_string(),
+            UsageTimezone::Offset(0) => "UTC".to_string(),
+            UsageTimezone::Offset(minutes) => format!(
+                "{}{:02}:{:02}",
+                if *minutes < 0 { '-' } else { '+' },
+                minutes.abs() / 60,
+                minutes.abs() % 60
+            ),
+        }
+    }
+}
+
+// SQLite expression for the first day of the bucket a message falls in
+// `?3` is the time zone modifier
+fn bucket_expression(granularity: UsageGranularity) -> &'static str {
+    match granularity {
+        UsageGranularity::Day => "date(m.date_created, ?3)",
+        // The next Sunday (or the day itself), back to the Monday before it
+        UsageGranularity::Week => "date(m.date_created, ?3, 'weekday 0', '-6 days')",
+        UsageGranularity::Month => "date(m.date_created, ?3, 'start of month')",
+    }
+}
+
+// Token usage per model, per day/week/month in `timezone`,
+// with each model's totals and average response times over the whole range
+// `date_from` and `date_to` are in `timezone` as well
+pub fn get_usage(
+    request: &UsageRequest,
+    timezone: UsageTimezone,
+    db: &rusqlite::Connection,
+) -> rusqlite::Result<UsageResponse> {
+    let mut stmt = db.prepare(&format!(
+        "SELECT
+            {} as bucket,
+            models.name as model,
+            SUM(u.input_tokens),
+            SUM(u.output_tokens),
+            GROUP_CONCAT(DISTINCT NULLIF(u.estimator, 'provider'))
+        FROM usage u
+        JOIN messages m ON u.message_id = m.id
+        JOIN models ON m.api_config_id = models.id
+        WHERE datetime(m.date_created, ?3) BETWEEN ?1 AND ?2
+        GROUP BY bucket, models.name
+        ORDER BY bucket ASC",
+        bucket_expression(request.granularity)
+    ))?;
+
+    let read_usage = |row: &rusqlite::Row| -> rusqlite::Result<(String, String, TokenUsage)> {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            TokenUsage {
+                input_tokens: row.get(2)?,
+                output_tokens: row.get(3)?,
+                estimator: row.get(4)?,
+            },
+        ))
+    };
+
+    let modifier = timezone.modifier();
+    let rows = stmt
+        .query_map(
+            params![request.date_from, request.date_to, modifier],
+            read_usage,
+        )?
+        .filter_map(|r| r.ok())
+        .collect::<Vec<_>>();
+
+    // Same thing without the buckets
+    let mut stmt = db.prepare(
+        "SELECT
+            '' as bucket,
+            models.name as model,
+            SUM(u.input_tokens),
+            SUM(u.output_tokens),
+            GROUP_CONCAT(DISTINCT NULLIF(u.estimator, 'provider'))
+        FROM usage u
+        JOIN messages m ON u.message_id = m.id
+        JOIN models ON m.api_config_id = models.id
+        WHERE datetime(m.date_created, ?3) BETWEEN ?1 AND ?2
+        GROUP BY models.name",
+    )?;
+
+    let totals = stmt
+        .query_map(
+            params![request.date_from, request.date_to, modifier],
+            read_usage,
+        )?
+        .filter_map(|r| r.ok())
+        .map(|(_, model, token_usage)| (model, token_usage))
+        .collect::<std::collections::HashMap<String, TokenUsage>>();
+
+    // Responses from before timing was recorded are left out of the averages
+    let mut stmt = db.prepare(
+        "SELECT
+            models.name as model,
+            AVG(m.time_to_first_token_ms),
+            AVG(m.generation_ms),
+            COUNT(m.generation_ms)
+        FROM messages m
+        JOIN models ON m.api_config_id = models.id
+        WHERE m.generation_ms IS NOT NULL
+        AND datetime(m.date_created, ?3) BETWEEN ?1 AND ?2
+        GROUP BY models.name",
+    )?;
+
+    let latency = stmt
+        .query_map(
+            params![request.date_from, request.date_to, modifier],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    ModelLatency {
+                        avg_time_to_first_token_ms: row.get(1)?,
+                        avg_generation_ms: row.get(2)?,
+                        responses: row.get(3)?,
+                    },
+                ))
+            },
+        )?
+        .filter_map(|r| r.ok())
+        .collect::<std::collections::HashMap<String, ModelLatency>>();
+
+    let mut usages: Vec<std::collections::HashMap<String, TokenUsage>> = Vec::new();
+    let mut dates: Vec<String> = Vec::new();
+    for (date, model, token_usage) in rows {
+        if dates.last() != Some(&date) {
+            dates.push(date);
+            usages.push(std::collections::HashMap::new());
+        }
+
+        usages.last_mut().unwrap().insert(model, token_usage);
+    }
+
+    Ok(UsageResponse {
+        token_usage: usages,
+        dates,
+        timezone: timezone.name(),
+        granularity: request.granularity,
+        totals,
+        latency,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timezone() {
+        assert_eq!(UsageTimezone::parse("local"), Ok(UsageTimezone::Local));
+        assert_eq!(UsageTimezone::parse(""), Ok(UsageTimezone::Local));
+        assert_eq!(UsageTimezone::parse("UTC"), Ok(UsageTimezone::Offset(0)));
+        assert_eq!(UsageTimezone::parse("Z"), Ok(UsageTimezone::Offset(0)));
+        assert_eq!(
+            UsageTimezone::parse("+05:30"),
+            Ok(UsageTimezone::Offset(330))
+        );
+        assert_eq!(
+            UsageTimezone::parse("-0700"),
+            Ok(UsageTimezone::Offset(-420))
+        );
+        assert_eq!(
+            UsageTimezone::parse("UTC-8"),
+            Ok(UsageTimezone::Offset(-480))
+        );
+
+        assert!(UsageTimezone::parse("America/New_York").is_err());
+        assert!(UsageTimezone::parse("+25:00").is_err());
+        assert!(UsageTimezone::parse("+05:75").is_err());
+    }
+
+    #[test]
+    fn test_timezone_names() {
+        for timezone in ["local", "UTC", "+05:30", "-07:00"] {
+            let parsed = UsageTimezone::parse(timezone).unwrap();
+            assert_eq!(parsed.name(), timezone);
+            assert_eq!(UsageTimezone::parse(&parsed.name()), Ok(parsed));
+        }
+
+        assert_eq!(UsageTimezone::Local.modifier(), "localtime");
+        assert_eq!(UsageTimezone::Offset(-420).modifier(), "-420 minutes");
+        assert_eq!(UsageTimezone::Offset(330).modifier(), "+330 minutes");
+    }
+}
@@ -0,0 +1,242 @@
+use crate::types::*;
+
+// Limits on what the frontend can send, so a runaway client can't take the backend down with it
+// Each can be overridden through the environment:
+// - `WILLIAM_MAX_MESSAGE_SIZE`: bytes per websocket message
+// - `WILLIAM_MAX_FRAME_SIZE`: bytes per websocket frame
+// - `WILLIAM_MAX_MESSAGES`: messages in a completion request
+// - `WILLIAM_MAX_CONTENT_LENGTH`: bytes per message in a completion request
+#[derive(Clone, Copy, Debug)]
+pub struct InputLimits {
+    pub max_message_size: usize,
+    pub max_frame_size: usize,
+    pub max_messages: usize,
+    pub max_content_length: usize,
+}
+
+impl Default for InputLimits {
+    fn default() -> Self {
+        Self {
+            max_message_size: 16 << 20,
+            max_frame_size: 16 << 20,
+            max_messages: 2000,
+            max_content_length: 1 << 20,
+        }
+    }
+}
+
+impl InputLimits {
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: usize| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(default)
+        };
+
+        let default = Self::default();
+        Self {
+            max_message_size: var("WILLIAM_MAX_MESSAGE_SIZE", default.max_message_size),
+            max_frame_size: var("WILLIAM_MAX_FRAME_SIZE", default.max_frame_size),
+            max_messages: var("WILLIAM_MAX_MESSAGES", default.max_messages),
+            max_content_length: var("WILLIAM_MAX_CONTENT_LENGTH", default.max_content_length),
+        }
+    }
+
+    pub fn websocket_config(&self) -> tungstenite::protocol::WebSocketConfig {
+        tungstenite::protocol::WebSocketConfig {
+            max_message_size: Some(self.max_message_size),
+            max_frame_size: Some(self.max_frame_size),
+            ..Default::default()
+        }
+    }
+}
+
+fn invalid(field: String, message: String) -> ValidationError {
+    ValidationError { field, message }
+}
+
+// Everything wrong with a `Completion` request, if anything
+// `completion()` assumes all of this holds, and panics otherwise
+pub fn validate_completion(
+    conversation: &Conversation,
+    limits: &InputLimits,
+) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    let messages = &conversation.messages;
+
+    if messages.len() > limits.max_messages {
+        errors.push(invalid(
+            "messages".to_string(),
+            format!(
+                "too many messages ({}, max {})",
+                messages.len(),
+                limits.max_messages
+            ),
+        ));
+    }
+
+    if !messages.iter().any(|m| m.message_type == MessageType::User) {
+        errors.push(invalid(
+            "messages".to_string(),
+            "conversation has no user messages".to_string(),
+        ));
+    }
+
+    match messages.last() {
+        Some(m) if m.message_type == MessageType::Assistant => {}
+        _ => errors.push(invalid(
+            "messages".to_string(),
+            "last message must be the assistant placeholder for the response".to_string(),
+        )),
+    }
+
+    for (i, message) in messages.iter().enumerate() {
+        if message.content.len() > limits.max_content_length {
+            errors.push(invalid(
+                format!("messages[{}].content", i),
+                format!(
+                    "content is too long ({} bytes, max {})",
+                    message.content.len(),
+                    limits.max_content_length
+                ),
+            ));
+        }
+
+        // The fixed model lists are checked during deserialization,
+        // but the hosted providers and discovered models take any non-empty string
+        let (provider, model) = message.api.to_strings();
+        if let Err(e) = API::from_strings(&provider, &model) {
+            errors.push(invalid(format!("messages[{}].api", i), e));
+        }
+    }
+
+    if let Some(api) = &conversation.overrides.model {
+        let (provider, model) = api.to_strings();
+        if let Err(e) = API::from_strings(&provider, &model) {
+            errors.push(invalid("overrides.model".to_string(), e));
+        }
+    }
+
+    errors
+}
+
+// `validate_completion`, plus the models the completion is fanned out to
+pub fn validate_comparison(
+    comparison: &CompareCompletion,
+    max_models: usize,
+    limits: &InputLimits,
+) -> Vec<ValidationError> {
+    let mut errors = validate_completion(&comparison.conversation, limits);
+    let models = &comparison.models;
+
+    if models.len() < 2 || models.len() > max_models {
+        errors.push(invalid(
+            "models".to_string(),
+            format!(
+                "comparisons take 2 to {} models ({} given)",
+                max_models,
+                models.len()
+            ),
+        ));
+    }
+
+    for (i, api) in models.iter().enumerate() {
+        let (provider, model) = api.to_strings();
+        if let Err(e) = API::from_strings(&provider, &model) {
+            errors.push(invalid(format!("models[{}]", i), e));
+        }
+
+        if models[..i].contains(api) {
+            errors.push(invalid(
+                format!("models[{}]", i),
+                format!("{}/{} is already being compared", provider, model),
+            ));
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation(messages: Vec<Message>) -> Conversation {
+        Conversation {
+            id: None,
+            name: String::new(),
+            messages,
+            tools: Vec::new(),
+            overrides: ConversationOverrides::default(),
+            branch_id: None,
+            settings: GenerationSettings::default(),
+            pinned: false,
+            archived: false,
+            unread: 0,
+            template: None,
+            memory_scope: MemoryScope::All,
+            related: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_validate_completion() {
+        let api = API::OpenAI(OpenAIModel::GPT4o);
+        let limits = InputLimits {
+            max_content_length: 10,
+            ..Default::default()
+        };
+
+        let valid = conversation(vec![
+            Message::test(MessageType::User, "Hello", api.clone()),
+            Message::test(MessageType::Assistant, "", api.clone()),
+        ]);
+        assert!(validate_completion(&valid, &limits).is_empty());
+
+        let invalid = conversation(vec![
+            Message::test(MessageType::User, "Hello, world!", api.clone()),
+            Message::test(MessageType::Assistant, "", API::Together(String::new())),
+        ]);
+        let fields = validate_completion(&invalid, &limits)
+            .into_iter()
+            .map(|e| e.field)
+            .collect::<Vec<String>>();
+        assert_eq!(fields, vec!["messages[0].content", "messages[1].api"]);
+
+        let no_placeholder = conversation(vec![Message::test(MessageType::User, "Hello", api)]);
+        assert_eq!(validate_completion(&no_placeholder, &limits).len(), 1);
+    }
+
+    #[test]
+    fn test_validate_comparison() {
+        let api = API::OpenAI(OpenAIModel::GPT4o);
+        let limits = InputLimits::default();
+        let comparison = |models: Vec<API>| CompareCompletion {
+            conversation: conversation(vec![
+                Message::test(MessageType::User, "Hello", api.clone()),
+                Message::test(MessageType::Assistant, "", api.clone()),
+            ]),
+            models,
+        };
+
+        let claude = API::Anthropic(AnthropicModel::Claude35Sonnet);
+        assert!(
+            validate_comparison(&comparison(vec![api.clone(), claude.clone()]), 4, &limits)
+                .is_empty()
+        );
+
+        let fields = |models: Vec<API>| {
+            validate_comparison(&comparison(models), 4, &limits)
+                .into_iter()
+                .map(|e| e.field)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(fields(vec![api.clone()]), vec!["models"]);
+        assert_eq!(fields(vec![api.clone(); 5]).len(), 5);
+        assert_eq!(
+            fields(vec![api.clone(), claude, api.clone()]),
+            vec!["models[2]"]
+        );
+    }
+}
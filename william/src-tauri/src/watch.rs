@@ -0,0 +1,321 @@
+use rusqlite::params;
+
+use chamber_common::{lprint, Logger};
+
+use crate::attachments;
+use crate::extract;
+use crate::types::*;
+
+// Watched folders are directories whose files are chunked and embedded like document attachments,
+// so they turn up as references without having to be attached to anything
+//
+// `watched_files` is the ledger: one row per file with the size, modification time, and hash
+// of whatever was last embedded, so a scan only re-chunks files that actually changed
+// Each file's chunks live at `<dir>/<hash of the file's path>/<chunk index>.txt`
+
+// Anything bigger is skipped--it'd be hundreds of chunks for one file
+pub const MAX_FILE_BYTES: u64 = 10 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LedgerEntry {
+    pub path: String,
+    pub size: u64,
+    pub modified: i64,
+    pub hash: String,
+    pub chunks: usize,
+}
+
+// A file found in a watched folder, as of the scan
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScannedFile {
+    pub path: String,
+    pub size: u64,
+    pub modified: i64,
+    pub backend: extract::Backend,
+}
+
+impl ScannedFile {
+    // Whether the file looks untouched since `entry` was recorded
+    // Touched-but-identical files are caught later by comparing hashes
+    pub fn matches(&self, entry: &LedgerEntry) -> bool {
+        self.size == entry.size && self.modified == entry.modified
+    }
+}
+
+pub fn chunk_dir(dir: &std::path::Path, path: &str) -> std::path::PathBuf {
+    dir.join(attachments::hash(path.as_bytes()))
+}
+
+pub fn chunk_path(dir: &std::path::Path, path: &str, index: usize) -> std::path::PathBuf {
+    chunk_dir(dir, path).join(format!("{}.txt", index))
+}
+
+// Every file under `root` with an extraction backend, skipping hidden files and directories
+// Symlinks aren't followed, so a link back up the tree can't loop forever
+pub fn scan(root: &std::path::Path) -> Result<Vec<ScannedFile>, std::io::Error> {
+    let mut files = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') {
+                continue;
+            }
+
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+
+            if !file_type.is_file() {
+                continue;
+            }
+
+            let backend = match extract::Backend::for_attachment(&name, "") {
+                Some(b) => b,
+                None => continue,
+            };
+
+            let metadata = entry.metadata()?;
+            if metadata.len() > MAX_FILE_BYTES {
+                lprint!(
+                    info,
+                    "Skipping {}: {} bytes is too big to index",
+                    entry.path().display(),
+                    metadata.len()
+                );
+                continue;
+            }
+
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs() as i64);
+
+            files.push(ScannedFile {
+                path: entry.path().to_string_lossy().to_string(),
+                size: metadata.len(),
+                modified,
+                backend,
+            });
+        }
+    }
+
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(files)
+}
+
+// Folders inside other watched folders (or the other way around) would index files twice
+fn overlaps(a: &std::path::Path, b: &std::path::Path) -> bool {
+    a.starts_with(b) || b.starts_with(a)
+}
+
+pub fn list(db: &rusqlite::Connection) -> rusqlite::Result<Vec<WatchedFolder>> {
+    let mut query = db.prepare(
+        "SELECT f.id, f.path, COUNT(w.id), f.last_scanned
+         FROM watched_folders f
+         LEFT JOIN watched_files w ON w.folder_id = f.id
+         GROUP BY f.id
+         ORDER BY f.path",
+    )?;
+
+    let folders = query
+        .query_map(params![], |row| {
+            Ok(WatchedFolder {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                files: row.get(2)?,
+                last_scanned: row.get(3)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<WatchedFolder>>>()?;
+
+    Ok(folders)
+}
+
+// Returns the folder's ID
+pub fn add(path: &str, db: &rusqlite::Connection) -> Result<i64, std::io::Error> {
+    let path = std::path::Path::new(path.trim()).canonicalize()?;
+    if !path.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} isn't a directory", path.display()),
+        ));
+    }
+
+    for folder in list(db).map_err(|e| std::io::Error::other(e.to_string()))? {
+        if overlaps(&path, std::path::Path::new(&folder.path)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} is already watched as part of {}",
+                    path.display(),
+                    folder.path
+                ),
+            ));
+        }
+    }
+
+    let path = path.to_string_lossy().to_string();
+    db.execute(
+        "INSERT INTO watched_folders (path, date_created) VALUES (?1, CURRENT_TIMESTAMP)",
+        params![path],
+    )
+    .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    lprint!(info, "Watching {}", path);
+
+    Ok(db.last_insert_rowid())
+}
+
+// Returns the ledger entries of the folder's files, whose chunks are the caller's to clean up
+pub fn remove(id: i64, db: &rusqlite::Connection) -> rusqlite::Result<Vec<LedgerEntry>> {
+    let entries = ledger(id, db)?;
+
+    let tx = db.unchecked_transaction()?;
+    tx.execute(
+        "DELETE FROM watched_files WHERE folder_id = ?1",
+        params![id],
+    )?;
+    tx.execute("DELETE FROM watched_folders WHERE id = ?1", params![id])?;
+    tx.commit()?;
+
+    Ok(entries)
+}
+
+pub fn ledger(folder_id: i64, db: &rusqlite::Connection) -> rusqlite::Result<Vec<LedgerEntry>> {
+    let mut query = db.prepare(
+        "SELECT path, size, modified, hash, chunks FROM watched_files WHERE folder_id = ?1",
+    )?;
+
+    let entries = query
+        .query_map(params![folder_id], |row| {
+            Ok(LedgerEntry {
+                path: row.get(0)?,
+                size: row.get::<_, i64>(1)? as u64,
+                modified: row.get(2)?,
+                hash: row.get(3)?,
+                chunks: row.get::<_, i64>(4)? as usize,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<LedgerEntry>>>()?;
+
+    Ok(entries)
+}
+
+// Nothing's recorded if the folder was removed mid-scan
+pub fn record(
+    folder_id: i64,
+    entry: &LedgerEntry,
+    db: &rusqlite::Connection,
+) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT INTO watched_files (folder_id, path, size, modified, hash, chunks, date_indexed)
+         SELECT ?1, ?2, ?3, ?4, ?5, ?6, CURRENT_TIMESTAMP
+         WHERE EXISTS (SELECT 1 FROM watched_folders WHERE id = ?1)
+         ON CONFLICT (path) DO UPDATE SET
+            size = excluded.size,
+            modified = excluded.modified,
+            hash = excluded.hash,
+            chunks = excluded.chunks,
+            date_indexed = excluded.date_indexed",
+        params![
+            folder_id,
+            entry.path,
+            entry.size as i64,
+            entry.modified,
+            entry.hash,
+            entry.chunks as i64
+        ],
+    )?;
+
+    Ok(())
+}
+
+pub fn forget(path: &str, db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute("DELETE FROM watched_files WHERE path = ?1", params![path])?;
+    Ok(())
+}
+
+pub fn mark_scanned(folder_id: i64, db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute(
+        "UPDATE watched_folders SET last_scanned = CURRENT_TIMESTAMP WHERE id = ?1",
+        params![folder_id],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan() {
+        let root = std::env::temp_dir().join(format!("william-watch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("notes")).unwrap();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+
+        std::fs::write(root.join("todo.md"), "- groceries").unwrap();
+        std::fs::write(root.join("notes").join("paper.pdf"), "%PDF").unwrap();
+        std::fs::write(root.join("photo.png"), [0u8; 4]).unwrap();
+        std::fs::write(root.join(".hidden.md"), "secret").unwrap();
+        std::fs::write(root.join(".git").join("HEAD.txt"), "ref").unwrap();
+
+        let files = scan(&root).unwrap();
+        let names = files
+            .iter()
+            .map(|f| {
+                std::path::Path::new(&f.path)
+                    .strip_prefix(&root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["notes/paper.pdf", "todo.md"]);
+        assert_eq!(files[0].backend, extract::Backend::Pdf);
+        assert_eq!(files[1].size, 11);
+
+        let entry = LedgerEntry {
+            path: files[1].path.clone(),
+            size: files[1].size,
+            modified: files[1].modified,
+            hash: String::new(),
+            chunks: 1,
+        };
+        assert!(files[1].matches(&entry));
+        assert!(!files[1].matches(&LedgerEntry { size: 12, ..entry }));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_chunk_paths() {
+        let dir = std::path::Path::new("/data/watched");
+        let first = chunk_path(dir, "/home/me/notes.md", 0);
+        assert!(first.starts_with(chunk_dir(dir, "/home/me/notes.md")));
+        assert!(first.ends_with("0.txt"));
+        assert_ne!(
+            chunk_dir(dir, "/home/me/notes.md"),
+            chunk_dir(dir, "/home/me/todo.md")
+        );
+
+        assert!(overlaps(
+            std::path::Path::new("/home/me/notes"),
+            std::path::Path::new("/home/me")
+        ));
+        assert!(overlaps(
+            std::path::Path::new("/home/me"),
+            std::path::Path::new("/home/me/notes")
+        ));
+        assert!(!overlaps(
+            std::path::Path::new("/home/me/notes"),
+            std::path::Path::new("/home/me/notes-old")
+        ));
+    }
+}
@@ -0,0 +1,481 @@
+// Keeping the wire format from drifting out from under the frontend
+//
+// Round trips through JSON are property tested, and the messages main.tsx is written against
+// are pinned in fixtures/wire--requests as the frontend sends them, responses as William sends them
+// A fixture failing here means the schemas in main.tsx need the same change
+
+use proptest::prelude::*;
+use serde_json::Value;
+
+use crate::types::*;
+
+const REQUEST_FIXTURES: &[(&str, &str)] = &[
+    (
+        "ConversationList",
+        include_str!("../fixtures/wire/requests/ConversationList.json"),
+    ),
+    (
+        "Completion",
+        include_str!("../fixtures/wire/requests/Completion.json"),
+    ),
+    ("Load", include_str!("../fixtures/wire/requests/Load.json")),
+    (
+        "Config",
+        include_str!("../fixtures/wire/requests/Config.json"),
+    ),
+    ("Fork", include_str!("../fixtures/wire/requests/Fork.json")),
+    (
+        "Preview",
+        include_str!("../fixtures/wire/requests/Preview.json"),
+    ),
+    (
+        "DeleteConversation",
+        include_str!("../fixtures/wire/requests/DeleteConversation.json"),
+    ),
+    (
+        "Usage",
+        include_str!("../fixtures/wire/requests/Usage.json"),
+    ),
+];
+
+// Model strings as providers list them, e.g. `gpt-4o-2024-08-06` or `Qwen/Qwen2.5-72B-Instruct-Turbo`
+const MODEL: &str = "[a-zA-Z0-9][a-zA-Z0-9./:_-]{0,40}";
+const REQUEST_ID: &str = "[a-zA-Z0-9-]{0,36}";
+
+// `Other` is only ever a model without a variant of its own--
+// `from_strings` turns anything else into the named variant
+fn openai_model() -> impl Strategy<Value = OpenAIModel> {
+    prop_oneof![
+        Just(OpenAIModel::GPT4o),
+        Just(OpenAIModel::GPT4oMini),
+        Just(OpenAIModel::O1Preview),
+        Just(OpenAIModel::O1Mini),
+        MODEL.prop_filter_map("named model", |m| match API::from_strings("openai", &m) {
+            Ok(API::OpenAI(model @ OpenAIModel::Other(_))) => Some(model),
+            _ => None,
+        }),
+    ]
+}
+
+fn anthropic_model() -> impl Strategy<Value = AnthropicModel> {
+    prop_oneof![
+        Just(AnthropicModel::Claude3Opus),
+        Just(AnthropicModel::Claude3Sonnet),
+        Just(AnthropicModel::Claude3Haiku),
+        Just(AnthropicModel::Claude35Sonnet),
+        Just(AnthropicModel::Claude35Haiku),
+        Just(AnthropicModel::Claude37Sonnet),
+        MODEL.prop_filter_map("named model", |m| {
+            match API::from_strings("anthropic", &m) {
+                Ok(API::Anthropic(model @ AnthropicModel::Other(_))) => Some(model),
+                _ => None,
+            }
+        }),
+    ]
+}
+
+fn api() -> impl Strategy<Value = API> {
+    prop_oneof![
+        openai_model().prop_map(API::OpenAI),
+        prop_oneof![
+            Just(GroqModel::LLaMA3370B),
+            Just(GroqModel::LLaMA318B),
+            Just(GroqModel::Mixtral8x7B),
+            Just(GroqModel::Gemma29B),
+        ]
+        .prop_map(API::Groq),
+        anthropic_model().prop_map(API::Anthropic),
+        prop_oneof![Just(DeepSeekModel::Chat), Just(DeepSeekModel::Reasoner)]
+            .prop_map(API::DeepSeek),
+        MODEL.prop_map(API::Together),
+        MODEL.prop_map(API::Fireworks),
+        MODEL.prop_map(API::Local),
+    ]
+}
+
+fn message_type() -> impl Strategy<Value = MessageType> {
+    prop_oneof![
+        Just(MessageType::System),
+        Just(MessageType::User),
+        Just(MessageType::Assistant),
+        Just(MessageType::Developer),
+        Just(MessageType::Tool),
+    ]
+}
+
+fn message() -> impl Strategy<Value = Message> {
+    (
+        proptest::option::of(any::<i64>()),
+        message_type(),
+        any::<String>(),
+        api(),
+        any::<String>(),
+        any::<i32>(),
+        any::<bool>(),
+        proptest::option::of("[a-z]{3}"),
+    )
+        .prop_map(
+            |(id, message_type, content, api, system_prompt, sequence, interrupted, language)| {
+                Message {
+                    id,
+                    message_type,
+                    content,
+                    api,
+                    system_prompt,
+                    sequence,
+                    date_created: "2025-01-01 12:00:00".to_string(),
+                    tool_calls: Vec::new(),
+                    tool_call_id: None,
+                    attachments: Vec::new(),
+                    interrupted,
+                    language,
+                    citations: Vec::new(),
+                    moderation: None,
+                }
+            },
+        )
+}
+
+fn conversation() -> impl Strategy<Value = Conversation> {
+    (
+        proptest::option::of(any::<i64>()),
+        any::<String>(),
+        proptest::collection::vec(message(), 0..4),
+        proptest::option::of(any::<i64>()),
+        any::<bool>(),
+        any::<bool>(),
+        any::<usize>(),
+        prop_oneof![
+            Just(MemoryScope::Conversation),
+            Just(MemoryScope::All),
+            Just(MemoryScope::None),
+        ],
+    )
+        .prop_map(
+            |(id, name, messages, branch_id, pinned, archived, unread, memory_scope)| {
+                Conversation {
+                    id,
+                    name,
+                    messages,
+                    tools: Vec::new(),
+                    overrides: Default::default(),
+                    branch_id,
+                    settings: Default::default(),
+                    pinned,
+                    archived,
+                    unread,
+                    template: None,
+                    memory_scope,
+                    related: Vec::new(),
+                }
+            },
+        )
+}
+
+fn request() -> impl Strategy<Value = ArrakisRequest> {
+    prop_oneof![
+        (REQUEST_ID, any::<String>()).prop_map(|(id, body)| ArrakisRequest::Ping {
+            id,
+            payload: Ping { body },
+        }),
+        (REQUEST_ID, conversation())
+            .prop_map(|(id, payload)| ArrakisRequest::Completion { id, payload }),
+        REQUEST_ID.prop_map(|id| ArrakisRequest::ConversationList { id }),
+        (REQUEST_ID, any::<i64>(), proptest::option::of(any::<i64>())).prop_map(
+            |(id, conversation_id, branch_id)| ArrakisRequest::Load {
+                id,
+                payload: LoadConversation {
+                    id: conversation_id,
+                    branch_id,
+                },
+            }
+        ),
+        (REQUEST_ID, any::<i64>(), any::<i64>()).prop_map(|(id, conversation_id, sequence)| {
+            ArrakisRequest::Fork {
+                id,
+                payload: Fork {
+                    conversation_id,
+                    sequence,
+                },
+            }
+        }),
+        (REQUEST_ID, any::<i64>(), any::<String>()).prop_map(|(id, conversation_id, content)| {
+            ArrakisRequest::Preview {
+                id,
+                payload: Preview {
+                    conversation_id,
+                    content,
+                },
+            }
+        }),
+        (REQUEST_ID, any::<i64>()).prop_map(|(id, conversation_id)| {
+            ArrakisRequest::DeleteConversation {
+                id,
+                payload: DeleteConversation { conversation_id },
+            }
+        }),
+        (REQUEST_ID, any::<i64>()).prop_map(|(id, memory_id)| ArrakisRequest::ForgetMemory {
+            id,
+            payload: ForgetMemory { memory_id },
+        }),
+    ]
+}
+
+fn response() -> impl Strategy<Value = ArrakisResponse> {
+    prop_oneof![
+        (REQUEST_ID, any::<String>()).prop_map(|(id, body)| ArrakisResponse::Ping {
+            id,
+            payload: Ping { body },
+        }),
+        (
+            REQUEST_ID,
+            any::<String>(),
+            any::<i64>(),
+            any::<i64>(),
+            any::<i64>(),
+            proptest::option::of(api())
+        )
+            .prop_map(
+                |(id, delta, conversation_id, request_id, response_id, model)| {
+                    ArrakisResponse::Completion {
+                        id,
+                        payload: Completion {
+                            stream: true,
+                            delta,
+                            name: "Conversation".to_string(),
+                            conversation_id,
+                            request_id,
+                            response_id,
+                            model,
+                        },
+                    }
+                }
+            ),
+        (REQUEST_ID, proptest::collection::vec(conversation(), 0..3)).prop_map(
+            |(id, conversations)| ArrakisResponse::ConversationList {
+                id,
+                payload: ConversationList { conversations },
+            }
+        ),
+        (REQUEST_ID, conversation())
+            .prop_map(|(id, payload)| ArrakisResponse::Load { id, payload }),
+        (REQUEST_ID, any::<String>(), any::<String>()).prop_map(|(id, error_type, message)| {
+            ArrakisResponse::WilliamError {
+                id,
+                payload: WilliamError {
+                    error_type,
+                    message,
+                    details: Vec::new(),
+                    provider: None,
+                },
+            }
+        }),
+    ]
+}
+
+// Sent, parsed back, and sent again, nothing changes
+fn round_trip<T: serde::Serialize + serde::de::DeserializeOwned>(
+    value: &T,
+) -> Result<T, TestCaseError> {
+    let sent = serde_json::to_string(value).unwrap();
+    let parsed: T = serde_json::from_str(&sent)
+        .map_err(|e| TestCaseError::fail(format!("{} parsing {}", e, sent)))?;
+    prop_assert_eq!(serde_json::to_string(&parsed).unwrap(), sent);
+
+    Ok(parsed)
+}
+
+proptest! {
+    #[test]
+    fn test_api_strings(api in api()) {
+        let (provider, model) = api.to_strings();
+        prop_assert_eq!(API::from_strings(&provider, &model), Ok(api));
+    }
+
+    #[test]
+    fn test_api_json(api in api()) {
+        prop_assert_eq!(round_trip(&api)?, api);
+    }
+
+    #[test]
+    fn test_message_type_ids(message_type in message_type()) {
+        prop_assert_eq!(MessageType::from_id(message_type.id()), Ok(message_type));
+    }
+
+    #[test]
+    fn test_unknown_message_type_ids(id in any::<i64>().prop_filter("known ID", |id| !(0..5).contains(id))) {
+        prop_assert!(MessageType::from_id(id).is_err());
+    }
+
+    #[test]
+    fn test_request_json(request in request()) {
+        let parsed = round_trip(&request)?;
+        prop_assert_eq!(parsed.method(), request.method());
+        prop_assert_eq!(parsed.id(), request.id());
+    }
+
+    #[test]
+    fn test_response_json(response in response()) {
+        round_trip(&response)?;
+    }
+}
+
+// Retired models load as their replacements, so they don't come back out the same
+#[test]
+fn test_model_aliases() {
+    for (provider, old, new) in MODEL_ALIASES {
+        let api = API::from_strings(provider, old).unwrap();
+        assert_eq!(api.to_strings(), (provider.to_string(), new.to_string()));
+    }
+}
+
+// Whether everything in `fixture` is in `json`
+// William drops fields it doesn't know about, so a field renamed on one side shows up here as a missing one
+fn contains(json: &Value, fixture: &Value) -> bool {
+    match (json, fixture) {
+        (Value::Object(json), Value::Object(fixture)) => fixture
+            .iter()
+            .all(|(k, v)| json.get(k).is_some_and(|j| contains(j, v))),
+        (Value::Array(json), Value::Array(fixture)) => {
+            json.len() == fixture.len() && json.iter().zip(fixture).all(|(j, f)| contains(j, f))
+        }
+        _ => json == fixture,
+    }
+}
+
+#[test]
+fn test_request_fixtures() {
+    for (method, fixture) in REQUEST_FIXTURES {
+        let fixture: Value = serde_json::from_str(fixture).unwrap();
+        let request: ArrakisRequest = serde_json::from_value(fixture.clone())
+            .unwrap_or_else(|e| panic!("{} fixture didn't parse: {}", method, e));
+        assert_eq!(request.method(), *method);
+
+        let json = serde_json::to_value(&request).unwrap();
+        assert!(
+            contains(&json, &fixture),
+            "{} fixture didn't make it through: {}",
+            method,
+            json
+        );
+    }
+}
+
+fn fixture_message(
+    id: i64,
+    message_type: MessageType,
+    content: &str,
+    sequence: i32,
+    date_created: &str,
+) -> Message {
+    Message {
+        id: Some(id),
+        system_prompt: "You are a helpful assistant".to_string(),
+        sequence,
+        date_created: date_created.to_string(),
+        ..Message::test(message_type, content, API::Local("llama3.2".to_string()))
+    }
+}
+
+// Responses are compared in full--anything William starts or stops sending shows up here
+#[test]
+fn test_response_fixtures() {
+    let prompt = Message {
+        language: Some("eng".to_string()),
+        ..fixture_message(
+            40,
+            MessageType::User,
+            "What's the capital of Australia?",
+            0,
+            "2025-01-01 12:00:00",
+        )
+    };
+    let response = fixture_message(
+        41,
+        MessageType::Assistant,
+        "Canberra.",
+        1,
+        "2025-01-01 12:00:05",
+    );
+
+    let responses = [
+        (
+            ArrakisResponse::Completion {
+                id: "completion".to_string(),
+                payload: Completion {
+                    stream: true,
+                    delta: "Canberra".to_string(),
+                    name: "Capital of Australia".to_string(),
+                    conversation_id: 12,
+                    request_id: 40,
+                    response_id: 41,
+                    model: None,
+                },
+            },
+            include_str!("../fixtures/wire/responses/Completion.json"),
+        ),
+        (
+            ArrakisResponse::Load {
+                id: "load".to_string(),
+                payload: Conversation {
+                    id: Some(12),
+                    name: "Capital of Australia".to_string(),
+                    messages: vec![prompt, response],
+                    tools: Vec::new(),
+                    overrides: Default::default(),
+                    branch_id: Some(3),
+                    settings: Default::default(),
+                    pinned: true,
+                    archived: false,
+                    unread: 0,
+                    template: None,
+                    memory_scope: MemoryScope::All,
+                    related: Vec::new(),
+                },
+            },
+            include_str!("../fixtures/wire/responses/Load.json"),
+        ),
+        (
+            ArrakisResponse::Preview {
+                id: "preview".to_string(),
+                payload: Preview {
+                    conversation_id: 12,
+                    content: "Half-written prompt".to_string(),
+                },
+            },
+            include_str!("../fixtures/wire/responses/Preview.json"),
+        ),
+        (
+            ArrakisResponse::Suggestions {
+                id: "completion".to_string(),
+                payload: Suggestions {
+                    conversation_id: 12,
+                    response_id: 41,
+                    suggestions: vec!["What's the largest city?".to_string()],
+                },
+            },
+            include_str!("../fixtures/wire/responses/Suggestions.json"),
+        ),
+        (
+            ArrakisResponse::WilliamError {
+                id: "completion".to_string(),
+                payload: WilliamError {
+                    error_type: "Provider".to_string(),
+                    message: "Rate limited".to_string(),
+                    details: Vec::new(),
+                    provider: None,
+                },
+            },
+            include_str!("../fixtures/wire/responses/WilliamError.json"),
+        ),
+    ];
+
+    for (response, fixture) in responses.iter() {
+        let fixture: Value = serde_json::from_str(fixture).unwrap();
+        assert_eq!(serde_json::to_value(response).unwrap(), fixture);
+
+        // And the other way, for anything reading William's responses back in
+        let parsed: ArrakisResponse = serde_json::from_value(fixture.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), fixture);
+    }
+}
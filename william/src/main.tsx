@@ -10,6 +10,13 @@ import hljs from 'highlight.js';
 import './font.css';
 import './buttons.css';
 
+// Set by the backend before the page loads--the port moves if the configured one is taken
+declare global {
+  interface Window {
+    __WILLIAM_WEBSOCKET_URL__?: string;
+  }
+}
+
 const md = new MarkdownIt({
   html: true,
   linkify: true,
@@ -42,23 +49,39 @@ interface WebSocketHookOptions {
 // A variety of types for communicating with the backend
 // I think this is really all they're here for
 
-const OpenAIModelSchema = z.enum([
-  "gpt-4o",
-  "gpt-4o-mini",
-  "o1-preview",
-  "o1-mini",
+// OpenAI and Anthropic models found through discovery can be anything
+const OpenAIModelSchema = z.union([
+  z.enum([
+    "gpt-4o",
+    "gpt-4o-mini",
+    "o1-preview",
+    "o1-mini",
+  ]),
+  z.string(),
 ]);
 
 const GroqModelSchema = z.enum([
-  "llama3-70b-8192",
+  "llama-3.3-70b-versatile",
+  "llama-3.1-8b-instant",
+  "mixtral-8x7b-32768",
+  "gemma2-9b-it",
+]);
+
+const AnthropicModelSchema = z.union([
+  z.enum([
+    "claude-3-opus-20240229",
+    "claude-3-sonnet-20240229",
+    "claude-3-haiku-20240307",
+    "claude-3-5-sonnet-latest",
+    "claude-3-7-sonnet-20250219",
+    "claude-3-5-haiku-latest",
+  ]),
+  z.string(),
 ]);
 
-const AnthropicModelSchema = z.enum([
-  "claude-3-opus-20240229",
-  "claude-3-sonnet-20240229",
-  "claude-3-haiku-20240307",
-  "claude-3-5-sonnet-latest",
-  "claude-3-5-haiku-latest",
+const DeepSeekModelSchema = z.enum([
+  "deepseek-chat",
+  "deepseek-reasoner",
 ]);
 
 const APISchema = z.discriminatedUnion("provider", [
@@ -74,8 +97,64 @@ const APISchema = z.discriminatedUnion("provider", [
     provider: z.literal("anthropic"),
     model: AnthropicModelSchema,
   }),
+  z.object({
+    provider: z.literal("deepseek"),
+    model: DeepSeekModelSchema,
+  }),
+  // Hosted open models take the host's own model string
+  z.object({
+    provider: z.literal("together"),
+    model: z.string(),
+  }),
+  z.object({
+    provider: z.literal("fireworks"),
+    model: z.string(),
+  }),
+  // Models served from the configured local endpoint (Ollama, LM Studio, vLLM, ...)
+  z.object({
+    provider: z.literal("local"),
+    model: z.string(),
+  }),
 ]);
 
+// Files are uploaded ahead of time with `UploadAttachment`; messages only carry the hash
+// A completion can also send them inline as base64 `data` or a local `path`, in place of the hash
+const AttachmentSchema = z.object({
+  hash: z.string().optional(),
+  name: z.string(),
+  mimeType: z.string(),
+  size: z.number().optional(),
+  data: z.string().optional(),
+  path: z.string().optional(),
+});
+
+// A reference behind one of a response's [n] markers
+const CitationSchema = z.object({
+  marker: z.number(),
+  source: z.string(),
+  conversationId: z.number().nullable(),
+  messageId: z.number().nullable(),
+  excerpt: z.string(),
+});
+
+// A reference that went into the system prompt, sent in `References` ahead of the response
+// Only memories Dewey turned up have a similarity
+const ReferenceSchema = CitationSchema.extend({
+  similarity: z.number().optional(),
+});
+
+const ReferencesResponseSchema = z.object({
+  conversationId: z.number(),
+  references: z.array(ReferenceSchema),
+});
+
+// What moderation made of a user message before it was sent
+const ModerationSchema = z.object({
+  flagged: z.boolean(),
+  categories: z.array(z.string()),
+  provider: z.string(),
+});
+
 const MessageSchema = z.object({
   message_type: z.enum(["System", "User", "Assistant"]),
   id: z.number().nullable(),
@@ -84,12 +163,53 @@ const MessageSchema = z.object({
   system_prompt: z.string(),
   sequence: z.number(),
   date_created: z.string(),
+  attachments: z.array(AttachmentSchema).optional(),
+  // Cut off by a shutdown mid-stream--finished with a `Continue` request
+  interrupted: z.boolean().optional(),
+  // ISO 639-3 code, detected for user messages
+  language: z.string().optional(),
+  // Set on assistant responses that cited their references
+  citations: z.array(CitationSchema).optional(),
+  moderation: ModerationSchema.optional(),
+});
+
+// Per-conversation model/temperature/system prompt, over the persona and then the global config
+const ConversationOverridesSchema = z.object({
+  model: APISchema.nullable().optional(),
+  temperature: z.number().nullable().optional(),
+  systemPrompt: z.string().nullable().optional(),
+  personaId: z.number().nullable().optional(),
+  repositories: z.array(z.number()).optional(),
+  // Follow-ups suggested with each response, in `Suggestions` after its `CompletionEnd`
+  suggestions: z.boolean().optional(),
+});
+
+// Sampling parameters for a single completion--unset means the provider's default
+const GenerationSettingsSchema = z.object({
+  temperature: z.number().nullable().optional(),
+  topP: z.number().nullable().optional(),
+  maxTokens: z.number().nullable().optional(),
+});
+
+// A recent conversation this one seems to follow up on, or the other way around
+const RelatedConversationSchema = z.object({
+  conversationId: z.number(),
+  name: z.string(),
+  similarity: z.number(),
 });
 
 const ConversationSchema = z.object({
   id: z.number().nullable(),
   name: z.string(),
   messages: z.array(MessageSchema),
+  overrides: ConversationOverridesSchema.optional(),
+  branchId: z.number().nullable().optional(),
+  settings: GenerationSettingsSchema.optional(),
+  pinned: z.boolean().optional(),
+  archived: z.boolean().optional(),
+  unread: z.number().optional(),
+  // Only filled in on `Load`
+  related: z.array(RelatedConversationSchema).optional(),
 });
 
 const CompletionRequestSchema = ConversationSchema;
@@ -100,6 +220,9 @@ const ApiKeysSchema = z.object({
   grok: z.string(),
   groq: z.string(),
   gemini: z.string(),
+  deepseek: z.string(),
+  together: z.string(),
+  fireworks: z.string(),
 });
 
 const UserConfigRequestSchema = z.object({
@@ -137,16 +260,35 @@ const UsageRequestSchema = z.object({
   api: APISchema,
   dateFrom: z.string(),
   dateTo: z.string(),
+  // `local` by default, otherwise `UTC` or an offset like `-07:00`
+  timezone: z.string().optional(),
+  granularity: z.enum(["day", "week", "month"]).optional(),
 });
 
 const TokenUsageSchema = z.object({
   inputTokens: z.number(),
-  outputTokens: z.number()
+  outputTokens: z.number(),
+  estimator: z.string().optional()
 });
 
 const UsageResponseSchema = z.object({
   tokenUsage: z.array(z.record(z.string(), TokenUsageSchema)),
-  dates: z.array(z.string())
+  dates: z.array(z.string()),
+  timezone: z.string().optional(),
+  granularity: z.enum(["day", "week", "month"]).optional(),
+  // Each model's usage over the whole range
+  totals: z.record(z.string(), TokenUsageSchema).optional(),
+  // Each model's average response times over the whole range
+  latency: z
+    .record(
+      z.string(),
+      z.object({
+        avgTimeToFirstTokenMs: z.number().nullable(),
+        avgGenerationMs: z.number(),
+        responses: z.number(),
+      }),
+    )
+    .optional(),
 });
 
 const PreviewResponseSchema = PreviewRequestSchema;
@@ -158,11 +300,25 @@ const CompletionResponseSchema = z.object({
   conversationId: z.number(),
   requestId: z.number(),
   responseId: z.number(),
+  // Set on `CompareCompletion` deltas, to tell the models' streams apart
+  model: APISchema.optional(),
 });
 
 const ErrorResponseSchema = z.object({
   error_type: z.string(),
   message: z.string(),
+  details: z.array(z.object({
+    field: z.string(),
+    message: z.string(),
+  })).optional(),
+  // What the provider said, when it's the provider that failed
+  provider: z.object({
+    provider: z.string(),
+    status: z.number(),
+    code: z.string().nullable(),
+    category: z.string(),
+    message: z.string(),
+  }).optional(),
 });
 
 const UserConfigResponseSchema = UserConfigRequestSchema;
@@ -395,6 +551,10 @@ const useWebSocket = ({
   // TODO: There will need to be some refactoring to account for this addition
   const callbacks = useRef<{ [key: string]: ResponseCallback }>({});
 
+  // The server's session for this client, and the last numbered response seen from it
+  // Reconnecting with this resumes the session, replaying whatever was missed while disconnected
+  const session = useRef<{ id: string, lastSeen: number } | null>(null);
+
   const connect = useCallback(() => {
     const attemptConnection = () => {
       setTimeout(() => {
@@ -410,12 +570,50 @@ const useWebSocket = ({
             setConnectionStatus('connected');
             setError(null);
             retryCount.current = 0;
+
+            if (session.current) {
+              ws.send(JSON.stringify({
+                method: 'Resume',
+                id: crypto.randomUUID(),
+                payload: {
+                  sessionId: session.current.id,
+                  lastSeenResponseId: session.current.lastSeen,
+                },
+              }));
+            }
           };
 
           // TODO: There could probably be some proper error handling here
           ws.onmessage = (event) => {
             try {
               const responseJSON = JSON.parse(event.data);
+
+              // Every connection announces a new session, but we stick with the first one
+              // unless it turns out it couldn't be resumed
+              if (responseJSON.method === 'Session') {
+                if (!session.current) {
+                  session.current = { id: responseJSON.payload.sessionId, lastSeen: 0 };
+                }
+
+                return;
+              }
+              else if (responseJSON.method === 'Resumed') {
+                if (!responseJSON.payload.resumed) {
+                  session.current = { id: responseJSON.payload.sessionId, lastSeen: 0 };
+                }
+
+                return;
+              }
+
+              // Replays can overlap with what already made it through
+              if (typeof responseJSON.seq === 'number' && session.current) {
+                if (responseJSON.seq <= session.current.lastSeen) {
+                  return;
+                }
+
+                session.current.lastSeen = responseJSON.seq;
+              }
+
               // We really need a better way of handling this
               if (responseJSON.method === 'CompletionEnd') {
                 // Updating the front end with the system prompt
@@ -426,6 +624,7 @@ const useWebSocket = ({
 
                   const last = lcm[lcm.length - 1];
                   last.system_prompt = responseJSON.payload.content;
+                  last.citations = responseJSON.payload.citations;
 
                   newMessages.push(last);
 
@@ -437,6 +636,21 @@ const useWebSocket = ({
 
                 return;
               }
+              // Shown on the response while it streams in, until `CompletionEnd` narrows them to what was cited
+              else if (responseJSON.method === 'References') {
+                const payload = ReferencesResponseSchema.parse(responseJSON.payload);
+                setLoadedConversation(prev => {
+                  // New conversations don't have their ID until the first delta
+                  if ((prev.id !== null && prev.id !== payload.conversationId) || prev.messages.length === 0) {
+                    return prev;
+                  }
+
+                  const last = { ...prev.messages[prev.messages.length - 1], citations: payload.references };
+                  return { ...prev, messages: [...prev.messages.slice(0, -1), last] };
+                });
+
+                return;
+              }
               // Special case, as we'll never send a request to purposely get an error response
               else if (responseJSON.method === 'WilliamError') {
                 const payload = ErrorResponseSchema.parse(responseJSON.payload);
@@ -444,6 +658,16 @@ const useWebSocket = ({
 
                 return;
               }
+              // Names are generated in the background, so these show up whenever they're ready
+              else if (responseJSON.method === 'ConversationRenamed') {
+                const payload = responseJSON.payload;
+                if (payload.branchId === undefined) {
+                  setLoadedConversation(prev => prev.id === payload.conversationId ? { ...prev, name: payload.name } : prev);
+                  setConversations(prev => prev.map(c => c.id === payload.conversationId ? { ...c, name: payload.name } : c));
+                }
+
+                return;
+              }
 
               const response = ArrakisResponseSchema.parse(responseJSON);
 
@@ -608,12 +832,17 @@ const MODEL_PROVIDER_MAPPING: Record<string, string> = {
   // "gpt-4o-mini": "openai",
   "o1-preview": "openai",
   // "o1-mini": "openai",
-  "llama3-70b-8192": "groq",
+  "llama-3.3-70b-versatile": "groq",
+  // "llama-3.1-8b-instant": "groq",
+  // "mixtral-8x7b-32768": "groq",
+  // "gemma2-9b-it": "groq",
   // "claude-3-opus-20240229": "anthropic",
   // "claude-3-sonnet-20240229": "anthropic",
   // "claude-3-haiku-20240307": "anthropic",
   "claude-3-5-sonnet-latest": "anthropic",
   // "claude-3-5-haiku-latest": "anthropic"
+  "deepseek-chat": "deepseek",
+  "deepseek-reasoner": "deepseek",
 };
 
 // TODO: This needs to be better + more robust
@@ -623,13 +852,18 @@ const MODEL_LABEL_MAPPING: Record<string, string> = {
   // "gpt-4o-mini": "openai",
   "o1-preview": "GPT (smarter)",
   // "o1-mini": "openai",
-  "llama3-70b-8192": "LLaMA",
+  "llama-3.3-70b-versatile": "LLaMA",
+  // "llama-3.1-8b-instant": "groq",
+  // "mixtral-8x7b-32768": "groq",
+  // "gemma2-9b-it": "groq",
   // "claude-3-opus-20240229": "anthropic",
   // "claude-3-sonnet-20240229": "anthropic",
   // "claude-3-haiku-20240307": "anthropic",
   // "claude-3-5-sonnet-latest": "Claude",
   "claude-3-5-sonnet-latest": "Claude",
   // "claude-3-5-haiku-latest": "anthropic"
+  "deepseek-chat": "DeepSeek",
+  "deepseek-reasoner": "DeepSeek (reasoning)",
 };
 
 const menuButtonStyle: React.CSSProperties = {
@@ -652,7 +886,8 @@ function filterAvailableModels(userConfig: UserConfig | null) {
       const provider = MODEL_PROVIDER_MAPPING[m];
       return !((provider === 'openai' && userConfig?.apiKeys.openai === '') ||
         (provider === 'anthropic' && userConfig?.apiKeys.anthropic === '') ||
-        (provider === 'groq' && userConfig?.apiKeys.groq === ''));
+        (provider === 'groq' && userConfig?.apiKeys.groq === '') ||
+        (provider === 'deepseek' && !userConfig?.apiKeys.deepseek));
     })
     .map(m => ({ model: m, provider: MODEL_PROVIDER_MAPPING[m], }));
 }
@@ -759,7 +994,10 @@ const UserConfigModal = (props: {
     anthropic: props.oldConfig ? props.oldConfig.apiKeys.anthropic : '',
     gemini: props.oldConfig ? props.oldConfig.apiKeys.gemini : '',
     groq: props.oldConfig ? props.oldConfig.apiKeys.groq : '',
-    grok: props.oldConfig ? props.oldConfig.apiKeys.grok : ''
+    grok: props.oldConfig ? props.oldConfig.apiKeys.grok : '',
+    deepseek: props.oldConfig ? props.oldConfig.apiKeys.deepseek : '',
+    together: props.oldConfig ? props.oldConfig.apiKeys.together : '',
+    fireworks: props.oldConfig ? props.oldConfig.apiKeys.fireworks : ''
   });
 
   useEffect(() => {
@@ -768,7 +1006,10 @@ const UserConfigModal = (props: {
       anthropic: props.oldConfig ? props.oldConfig.apiKeys.anthropic : '',
       gemini: props.oldConfig ? props.oldConfig.apiKeys.gemini : '',
       groq: props.oldConfig ? props.oldConfig.apiKeys.groq : '',
-      grok: props.oldConfig ? props.oldConfig.apiKeys.grok : ''
+      grok: props.oldConfig ? props.oldConfig.apiKeys.grok : '',
+    deepseek: props.oldConfig ? props.oldConfig.apiKeys.deepseek : '',
+    together: props.oldConfig ? props.oldConfig.apiKeys.together : '',
+    fireworks: props.oldConfig ? props.oldConfig.apiKeys.fireworks : ''
     });
   }, [props.oldConfig]);
 
@@ -827,7 +1068,10 @@ const UserConfigModal = (props: {
         'OpenAI': 'openai',
         'Anthropic': 'anthropic',
         'Gemini': 'gemini',
-        'Groq': 'groq'
+        'Groq': 'groq',
+        'DeepSeek': 'deepseek',
+        'Together': 'together',
+        'Fireworks': 'fireworks'
       }).map(([label, key]) => (
         <div key={key} style={{
           display: 'flex',
@@ -1342,7 +1586,7 @@ function MainPage() {
     sendMessage,
     error,
   } = useWebSocket({
-    url: 'ws://localhost:9001',
+    url: window.__WILLIAM_WEBSOCKET_URL__ ?? 'ws://localhost:9001',
     retryInterval: 5000,
     maxRetries: 0
   });
@@ -1424,6 +1668,9 @@ function MainPage() {
             groq: '',
             gemini: '',
             anthropic: '',
+            deepseek: '',
+            together: '',
+            fireworks: '',
           }
         })
       } satisfies ArrakisRequest, (response: UserConfig) => {
@@ -1438,7 +1685,10 @@ function MainPage() {
         userConfig.apiKeys.groq ||
         userConfig.apiKeys.anthropic ||
         userConfig.apiKeys.grok ||
-        userConfig.apiKeys.gemini));
+        userConfig.apiKeys.gemini ||
+        userConfig.apiKeys.deepseek ||
+        userConfig.apiKeys.together ||
+        userConfig.apiKeys.fireworks));
   };
 
   const usagePageToggle = () => {
@@ -2237,6 +2487,8 @@ function MainPage() {
             const unescapedElements = reactElements.map(modifyElements);
 
             const isUser = m.message_type === 'User';
+            // Set mid-conversation to change how the rest of it goes
+            const isSystem = m.message_type === 'System';
 
             // Parsing the system prompt references for cleaner display
             // TODO: This streaming setup really needs to be cleaned
@@ -2254,6 +2506,9 @@ function MainPage() {
               <>
                 <div style={{
                   backgroundColor: isUser ? '#E8E9E9' : '',
+                  border: isSystem ? '1px dashed #C0C0C0' : '',
+                  color: isSystem ? '#606060' : '',
+                  fontStyle: isSystem ? 'italic' : '',
                   borderRadius: '0.5rem',
                   margin: '2rem 0.25rem 1.5rem 0.25rem',
                   padding: '0.01rem 0',
@@ -2261,6 +2516,9 @@ function MainPage() {
                   position: 'relative',
                   fontSize: '14px',
                 }}>
+                  {isSystem ? (
+                    <div style={{ fontSize: '12px', padding: '0.5rem 0.75rem 0', userSelect: 'none' }}>System</div>
+                  ) : ''}
                   {
                     /* The actual message elements */
                     i < loadedConversation.messages.length - 1 || unescapedElements.length > 0 ? unescapedElements : (
@@ -2272,7 +2530,7 @@ function MainPage() {
                       </div>
                     )
                   }
-                  {isUser ? '' : (
+                  {isUser || isSystem ? '' : (
                     <div>
                       <p
                         className="messageOptions"
@@ -2300,8 +2558,8 @@ function MainPage() {
                               }}
                               onClick={() => {
                                 // Regeneration option for a given message
-                                // This forks the existing conversation and saves the new conversation as a new entry in the listing
-                                // All conversation up to the regenerated message is kept, all conversation history after is left behind in the old conversation
+                                // This starts a new branch of the conversation from the regenerated message
+                                // All conversation up to the regenerated message is shared, all conversation history after is left behind on the old branch
 
                                 sendMessage({
                                   method: 'Fork',